@@ -0,0 +1,93 @@
+//! Cost of encoding standby status updates at a high keepalive rate
+//! Compares encoding every update into a stack buffer copied out to a `Vec`, as feedback was
+//! sent before, with encoding into one `StatusUpdate` kept by the sender. The `alloc-stats`
+//! allocator reports the heap allocations per update next to the time. Run with `cargo bench
+//! --features alloc-stats --bench feedback`; `FEEDBACK_BENCH_UPDATES` sets the number of
+//! updates (default: 1000000).
+
+use replication_checker::allocstats::{self, CountingAllocator};
+use replication_checker::buffer::BufferWriter;
+use replication_checker::errors::Result;
+use replication_checker::pipeline::StatusUpdate;
+use replication_checker::types::Lsn;
+use replication_checker::utils::system_time_to_postgres_timestamp;
+use std::hint::black_box;
+use std::time::{Duration, Instant, SystemTime};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Allocations counted so far; the benchmark runs entirely in the `other` component
+fn allocations() -> u64 {
+    allocstats::snapshot().other.count
+}
+
+/// The standby status update as it was encoded before `StatusUpdate`
+fn standby_status_update(
+    received: Lsn,
+    flushed: Lsn,
+    applied: Lsn,
+    reply_requested: bool,
+) -> Result<Vec<u8>> {
+    let timestamp = system_time_to_postgres_timestamp(SystemTime::now());
+    let mut reply_buf = [0u8; 34]; // 1 + 8 + 8 + 8 + 8 + 1
+    let mut writer = BufferWriter::new(&mut reply_buf);
+    writer.write_u8(b'r')?;
+    writer.write_u64(received.value())?; // Received LSN
+    writer.write_u64(flushed.value())?; // Flushed LSN
+    writer.write_u64(applied.value())?; // Applied LSN
+    writer.write_i64(timestamp)?; // Timestamp
+    writer.write_u8(u8::from(reply_requested))?; // Whether the server should reply
+    let bytes_written = writer.bytes_written();
+    Ok(reply_buf[..bytes_written].to_vec())
+}
+
+/// Time and allocations of `updates` calls of `send`, which gets the position to report
+fn measure(name: &str, updates: u64, mut send: impl FnMut(Lsn)) {
+    // Warm up so lazily initialised state is not counted
+    for lsn in 0..1000 {
+        send(Lsn(lsn));
+    }
+
+    let allocated = allocations();
+    let started = Instant::now();
+    for lsn in 0..updates {
+        send(Lsn(lsn));
+    }
+    let elapsed = started.elapsed();
+    let allocated = allocations() - allocated;
+
+    println!(
+        "{:<10} {:>8.1} ns/update {:>6.2} allocations/update ({} updates in {:.3}s)",
+        name,
+        per_update(elapsed, updates),
+        allocated as f64 / updates as f64,
+        updates,
+        elapsed.as_secs_f64()
+    );
+}
+
+fn per_update(elapsed: Duration, updates: u64) -> f64 {
+    elapsed.as_nanos() as f64 / updates as f64
+}
+
+fn main() {
+    let updates = std::env::var("FEEDBACK_BENCH_UPDATES")
+        .ok()
+        .and_then(|updates| updates.parse().ok())
+        .unwrap_or(1_000_000);
+
+    measure("allocating", updates, |lsn| {
+        let reply =
+            standby_status_update(lsn, lsn, lsn, false).expect("Failed to encode status update");
+        black_box(reply);
+    });
+
+    let mut status = StatusUpdate::new();
+    measure("reused", updates, |lsn| {
+        let reply = status
+            .encode(lsn, lsn, lsn, false)
+            .expect("Failed to encode status update");
+        black_box(reply);
+    });
+}
@@ -0,0 +1,15 @@
+//! Generates the `ChangeStream` service of `proto/replication_checker.proto` for the `grpc`
+//! feature, with the protoc that comes with `protoc-bin-vendored` rather than one on the PATH
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        let protoc =
+            protoc_bin_vendored::protoc_bin_path().expect("protoc is vendored for this platform");
+        let mut config = tonic_prost_build::Config::new();
+        config.protoc_executable(protoc);
+        tonic_prost_build::configure()
+            .compile_with_config(config, &["proto/replication_checker.proto"], &["proto"])
+            .expect("proto/replication_checker.proto compiles");
+    }
+}
@@ -0,0 +1,38 @@
+//! Runs a sequence of BufferReader reads over arbitrary bytes. The first byte gives how many of
+//! the following bytes are operations; the rest is the buffer they read from.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use replication_checker::buffer::BufferReader;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&count, rest)) = data.split_first() else {
+        return;
+    };
+    let (operations, input) = rest.split_at(usize::from(count).min(rest.len()));
+
+    let mut reader = BufferReader::new(input);
+    for &operation in operations {
+        let _ = match operation % 13 {
+            0 => reader.read_u8().map(drop),
+            1 => reader.read_i16().map(drop),
+            2 => reader.read_u32().map(drop),
+            3 => reader.read_i32().map(drop),
+            4 => reader.read_u64().map(drop),
+            5 => reader.read_i64().map(drop),
+            6 => reader.read_lsn().map(drop),
+            7 => reader.read_null_terminated_str().map(drop),
+            8 => reader.read_length_prefixed_bytes().map(drop),
+            9 => reader.read_length_prefixed_string().map(drop),
+            10 => reader.peek_u8().map(drop),
+            11 => reader
+                .read_i64()
+                .and_then(|count| reader.check_count(count, 1664, 1, "items"))
+                .map(drop),
+            _ => reader.set_position(usize::from(operation)),
+        };
+        assert!(reader.remaining() <= input.len());
+        assert!(reader.position() <= input.len());
+    }
+});
@@ -0,0 +1,15 @@
+//! Parses arbitrary bytes as a pgoutput message, outside and inside a streamed transaction,
+//! and copies whatever parses out of the buffer
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use replication_checker::MessageParser;
+
+fuzz_target!(|data: &[u8]| {
+    for in_streaming_txn in [false, true] {
+        if let Ok(message) = MessageParser::parse_wal_message_ref(data, in_streaming_txn) {
+            let _ = message.into_owned();
+        }
+    }
+});
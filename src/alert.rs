@@ -0,0 +1,214 @@
+//! Lag alerts
+//! Compares the WAL bytes behind the server, the time since the server was last heard from, the
+//! time since the last commit and the share of max_slot_wal_keep_size the slot retains with
+//! configured thresholds. An alert fires once when its
+//! threshold is exceeded and resolves when the value drops back; firing logs at error level and
+//! can also call a webhook or stop the process with a chosen exit code.
+
+use crate::errors::{ReplicationError, Result};
+use crate::webhook::Endpoint;
+use chrono::Utc;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// How often the thresholds are compared
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What happens when an alert fires
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlertAction {
+    /// Only log the alert
+    Log,
+    /// Log the alert and POST it, and its resolution, as JSON to this `http://` or `https://` URL
+    Webhook(String),
+    /// Log the alert and stop the process with this exit code
+    Exit(i32),
+}
+
+/// Thresholds and the action taken when one is exceeded
+#[derive(Debug, Clone)]
+pub struct AlertConfig {
+    /// Most WAL bytes the stream may fall behind the server's WAL end
+    pub max_bytes_behind: Option<u64>,
+    /// Longest the server may send no keepalive or other message
+    pub max_keepalive_silence: Option<Duration>,
+    /// Longest the stream may go without receiving a commit
+    pub max_commit_silence: Option<Duration>,
+    /// Highest share of max_slot_wal_keep_size, in percent, the slot may retain
+    pub max_wal_keep_percent: Option<f64>,
+    pub action: AlertAction,
+}
+
+/// Measured quantities that can raise an alert
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AlertKind {
+    BytesBehind,
+    KeepaliveSilence,
+    CommitSilence,
+    WalKeepUsed,
+}
+
+impl AlertKind {
+    const ALL: [AlertKind; 4] = [
+        AlertKind::BytesBehind,
+        AlertKind::KeepaliveSilence,
+        AlertKind::CommitSilence,
+        AlertKind::WalKeepUsed,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            AlertKind::BytesBehind => "bytes_behind",
+            AlertKind::KeepaliveSilence => "seconds_since_keepalive",
+            AlertKind::CommitSilence => "seconds_since_commit",
+            AlertKind::WalKeepUsed => "slot_wal_keep_percent",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Checks the thresholds and fires the configured action
+pub struct AlertMonitor {
+    config: AlertConfig,
+    endpoint: Option<Endpoint>,
+    /// Slot and source the alerts are about
+    slot_name: String,
+    source: Option<String>,
+    last_commit: Instant,
+    /// Last measured share of max_slot_wal_keep_size the slot retains
+    wal_keep_used: Option<f64>,
+    last_check: Instant,
+    firing: [bool; 4],
+}
+
+impl AlertMonitor {
+    pub fn new(config: AlertConfig, slot_name: String, source: Option<String>) -> Result<Self> {
+        let endpoint = match &config.action {
+            AlertAction::Webhook(url) => Some(Endpoint::parse(url)?),
+            _ => None,
+        };
+        Ok(Self {
+            config,
+            endpoint,
+            slot_name,
+            source,
+            last_commit: Instant::now(),
+            wal_keep_used: None,
+            last_check: Instant::now(),
+            firing: [false; 4],
+        })
+    }
+
+    /// Note that a transaction commit was received
+    pub fn record_commit(&mut self) {
+        self.last_commit = Instant::now();
+    }
+
+    /// Note the share of max_slot_wal_keep_size, in percent, the slot was found to retain
+    pub fn record_wal_keep_used(&mut self, percent: f64) {
+        self.wal_keep_used = Some(percent);
+    }
+
+    /// Compare the thresholds with the current values, at most once per check interval
+    ///
+    /// `bytes_behind` is None when the backend does not know the server's WAL end. Returns an
+    /// alert error when an alert fires with the exit action.
+    pub fn maybe_check(
+        &mut self,
+        bytes_behind: Option<u64>,
+        since_last_message: Duration,
+    ) -> Result<()> {
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return Ok(());
+        }
+        self.last_check = Instant::now();
+
+        let since_last_commit = self.last_commit.elapsed();
+        for kind in AlertKind::ALL {
+            // Values and thresholds as numbers reported in logs and webhook payloads
+            let measured = match kind {
+                AlertKind::BytesBehind => self
+                    .config
+                    .max_bytes_behind
+                    .zip(bytes_behind)
+                    .map(|(max, bytes)| (bytes as f64, max as f64)),
+                AlertKind::KeepaliveSilence => self
+                    .config
+                    .max_keepalive_silence
+                    .map(|max| (since_last_message.as_secs_f64(), max.as_secs_f64())),
+                AlertKind::CommitSilence => self
+                    .config
+                    .max_commit_silence
+                    .map(|max| (since_last_commit.as_secs_f64(), max.as_secs_f64())),
+                AlertKind::WalKeepUsed => self
+                    .config
+                    .max_wal_keep_percent
+                    .zip(self.wal_keep_used)
+                    .map(|(max, used)| (used, max)),
+            };
+            let Some((value, threshold)) = measured else {
+                continue;
+            };
+
+            let exceeded = value > threshold;
+            if exceeded == self.firing[kind.index()] {
+                continue;
+            }
+            self.firing[kind.index()] = exceeded;
+            if exceeded {
+                self.fire(kind, value, threshold)?;
+            } else {
+                info!(
+                    alert = kind.name(),
+                    value,
+                    threshold,
+                    "Alert {} resolved: {} is within {}",
+                    kind.name(),
+                    value,
+                    threshold
+                );
+                self.notify(kind, "resolved", value, threshold);
+            }
+        }
+        Ok(())
+    }
+
+    fn fire(&self, kind: AlertKind, value: f64, threshold: f64) -> Result<()> {
+        let message = format!(
+            "{} of slot {} is {}, above the threshold of {}",
+            kind.name(),
+            self.slot_name,
+            value,
+            threshold
+        );
+        error!(alert = kind.name(), value, threshold, "Alert: {}", message);
+        self.notify(kind, "firing", value, threshold);
+        match self.config.action {
+            AlertAction::Exit(code) => Err(ReplicationError::alert(message, code)),
+            _ => Ok(()),
+        }
+    }
+
+    /// POST the alert to the webhook; a failed call only warns so streaming continues
+    fn notify(&self, kind: AlertKind, state: &str, value: f64, threshold: f64) {
+        let Some(endpoint) = &self.endpoint else {
+            return;
+        };
+        let body = json!({
+            "alert": kind.name(),
+            "state": state,
+            "value": value,
+            "threshold": threshold,
+            "slot": self.slot_name,
+            "source": self.source,
+            "time": Utc::now().to_rfc3339(),
+        });
+        if let Err(e) = endpoint.post(body.to_string().as_bytes()) {
+            warn!("Failed to send the {} alert: {}", kind.name(), e);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Allocation statistics per component
+//! Built with the `alloc-stats` feature. The binaries then install `CountingAllocator` as the
+//! global allocator, which counts every allocation against the component the allocating thread
+//! is in: the parser decoding messages, the transaction assembler buffering and spilling them,
+//! or the sinks writing change events. Everything else, the receive stage and the connection
+//! among it, is counted as other. Freed memory is not attributed, since a value is often freed
+//! by another component than the one that allocated it; only the bytes in use overall are kept.
+
+use serde::Serialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tracing::info;
+
+/// Part of the pipeline allocations are counted against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    Parser,
+    Assembler,
+    Sinks,
+    Other,
+}
+
+impl Component {
+    const ALL: [Component; 4] = [
+        Component::Parser,
+        Component::Assembler,
+        Component::Sinks,
+        Component::Other,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Component::Parser => "parser",
+            Component::Assembler => "assembler",
+            Component::Sinks => "sinks",
+            Component::Other => "other",
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: Cell<Component> = const { Cell::new(Component::Other) };
+}
+
+static ALLOCATED_BYTES: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+static ALLOCATIONS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK_IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting allocations per component
+pub struct CountingAllocator;
+
+impl CountingAllocator {
+    fn allocated(size: usize) {
+        let component = CURRENT.try_with(Cell::get).unwrap_or(Component::Other);
+        ALLOCATED_BYTES[component as usize].fetch_add(size as u64, Ordering::Relaxed);
+        ALLOCATIONS[component as usize].fetch_add(1, Ordering::Relaxed);
+        let in_use = IN_USE.fetch_add(size, Ordering::Relaxed) + size;
+        PEAK_IN_USE.fetch_max(in_use, Ordering::Relaxed);
+    }
+
+    fn freed(size: usize) {
+        IN_USE.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            Self::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            Self::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        Self::freed(layout.size());
+    }
+
+    /// Growth counts as an allocation of the added bytes, shrinking as freeing the rest
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            if new_size > layout.size() {
+                Self::allocated(new_size - layout.size());
+            } else {
+                Self::freed(layout.size() - new_size);
+            }
+        }
+        new_ptr
+    }
+}
+
+/// Counts allocations of the current thread against a component until dropped
+///
+/// Only held around code that does not await, as a task can move to another thread there.
+#[must_use]
+pub struct ComponentScope {
+    previous: Component,
+}
+
+impl Drop for ComponentScope {
+    fn drop(&mut self) {
+        let _ = CURRENT.try_with(|current| current.set(self.previous));
+    }
+}
+
+/// Count the current thread's allocations against `component` until the scope is dropped
+pub fn enter(component: Component) -> ComponentScope {
+    let previous = CURRENT
+        .try_with(|current| current.replace(component))
+        .unwrap_or(Component::Other);
+    ComponentScope { previous }
+}
+
+/// Allocations of one component
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ComponentAllocations {
+    pub bytes: u64,
+    pub count: u64,
+}
+
+/// Allocations since the start of the process
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct AllocSnapshot {
+    pub parser: ComponentAllocations,
+    pub assembler: ComponentAllocations,
+    pub sinks: ComponentAllocations,
+    pub other: ComponentAllocations,
+    /// Bytes allocated and not yet freed
+    pub in_use: usize,
+    pub peak_in_use: usize,
+}
+
+impl AllocSnapshot {
+    pub fn component(&self, component: Component) -> ComponentAllocations {
+        match component {
+            Component::Parser => self.parser,
+            Component::Assembler => self.assembler,
+            Component::Sinks => self.sinks,
+            Component::Other => self.other,
+        }
+    }
+}
+
+/// Current counts; all zero when `CountingAllocator` is not the global allocator
+pub fn snapshot() -> AllocSnapshot {
+    let component = |component: Component| ComponentAllocations {
+        bytes: ALLOCATED_BYTES[component as usize].load(Ordering::Relaxed),
+        count: ALLOCATIONS[component as usize].load(Ordering::Relaxed),
+    };
+    AllocSnapshot {
+        parser: component(Component::Parser),
+        assembler: component(Component::Assembler),
+        sinks: component(Component::Sinks),
+        other: component(Component::Other),
+        in_use: IN_USE.load(Ordering::Relaxed),
+        peak_in_use: PEAK_IN_USE.load(Ordering::Relaxed),
+    }
+}
+
+/// Reports the allocations of each component since the previous report
+#[derive(Debug, Default)]
+pub struct AllocStats {
+    last: AllocSnapshot,
+}
+
+impl AllocStats {
+    /// Log the bytes each component allocated since the previous report, unless nothing is
+    /// counted because another global allocator is in use
+    pub fn report(&mut self) {
+        let current = snapshot();
+        if current.peak_in_use == 0 {
+            return;
+        }
+        let allocated = |component: Component| {
+            let now = current.component(component);
+            let last = self.last.component(component);
+            ComponentAllocations {
+                bytes: now.bytes - last.bytes,
+                count: now.count - last.count,
+            }
+        };
+        let components = Component::ALL
+            .iter()
+            .map(|&component| {
+                let allocated = allocated(component);
+                format!(
+                    "{} {} bytes ({} allocations)",
+                    component.as_str(),
+                    allocated.bytes,
+                    allocated.count
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        info!(
+            parser_bytes = allocated(Component::Parser).bytes,
+            assembler_bytes = allocated(Component::Assembler).bytes,
+            sinks_bytes = allocated(Component::Sinks).bytes,
+            other_bytes = allocated(Component::Other).bytes,
+            bytes_in_use = current.in_use,
+            peak_bytes_in_use = current.peak_in_use,
+            "Allocated: {}; {} bytes in use, peak {}",
+            components,
+            current.in_use,
+            current.peak_in_use
+        );
+        self.last = current;
+    }
+}
@@ -0,0 +1,258 @@
+//! Apply decoded changes to a target PostgreSQL database
+//! Turns the checker into a minimal logical replica: each source transaction is replayed as
+//! parameterized SQL inside one target transaction
+
+use crate::errors::{ReplicationError, Result};
+use crate::quote::{quote_ident, quote_qualified};
+use crate::types::{ColumnData, ColumnInfo, RelationInfo, TupleData};
+use crate::utils::{PGConnection, PGResult};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Connection to the database receiving the applied changes
+pub struct ApplyTarget {
+    connection: PGConnection,
+    in_transaction: bool,
+    transactions_applied: u64,
+}
+
+impl ApplyTarget {
+    pub fn connect(connection_string: &str) -> Result<Self> {
+        let connection = PGConnection::connect(connection_string)?;
+        info!("Connected to apply target database");
+
+        Ok(Self {
+            connection,
+            in_transaction: false,
+            transactions_applied: 0,
+        })
+    }
+
+    /// Open the target transaction matching a source BEGIN
+    pub fn begin(&mut self) -> Result<()> {
+        if self.in_transaction {
+            warn!("BEGIN received inside an open apply transaction, rolling it back");
+            self.execute("ROLLBACK", &[])?;
+        }
+
+        self.execute("BEGIN", &[])?;
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    /// Commit the target transaction matching a source COMMIT
+    pub fn commit(&mut self) -> Result<()> {
+        if !self.in_transaction {
+            return Ok(());
+        }
+
+        self.execute("COMMIT", &[])?;
+        self.in_transaction = false;
+        self.transactions_applied += 1;
+        debug!("Applied transaction {}", self.transactions_applied);
+        Ok(())
+    }
+
+    pub fn insert(&mut self, relation: &RelationInfo, tuple_data: &TupleData) -> Result<()> {
+        let mut columns = Vec::new();
+        let mut params = Vec::new();
+        for (column, value) in present_columns(relation, tuple_data) {
+            columns.push(quote_ident(&column.column_name));
+            params.push(param_value(column, value));
+        }
+
+        let placeholders = (1..=params.len())
+            .map(|i| format!("${}", i))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            qualified_name(relation),
+            columns.join(", "),
+            placeholders
+        );
+
+        self.execute(&sql, &params)?;
+        Ok(())
+    }
+
+    /// Update the row identified by the old key (or the new row when no old tuple was sent)
+    pub fn update(
+        &mut self,
+        relation: &RelationInfo,
+        old_tuple_data: Option<&TupleData>,
+        new_tuple_data: &TupleData,
+    ) -> Result<()> {
+        let mut assignments = Vec::new();
+        let mut params = Vec::new();
+        for (column, value) in present_columns(relation, new_tuple_data) {
+            params.push(param_value(column, value));
+            assignments.push(format!(
+                "{} = ${}",
+                quote_ident(&column.column_name),
+                params.len()
+            ));
+        }
+
+        let key_tuple = old_tuple_data.unwrap_or(new_tuple_data);
+        let conditions = key_conditions(relation, key_tuple, &mut params)?;
+        let sql = format!(
+            "UPDATE {} SET {} WHERE {}",
+            qualified_name(relation),
+            assignments.join(", "),
+            conditions
+        );
+
+        let result = self.execute(&sql, &params)?;
+        if result.cmd_tuples() == 0 {
+            warn!("UPDATE on {} matched no rows", qualified_name(relation));
+        }
+        Ok(())
+    }
+
+    pub fn delete(&mut self, relation: &RelationInfo, key_tuple: &TupleData) -> Result<()> {
+        let mut params = Vec::new();
+        let conditions = key_conditions(relation, key_tuple, &mut params)?;
+        let sql = format!(
+            "DELETE FROM {} WHERE {}",
+            qualified_name(relation),
+            conditions
+        );
+
+        let result = self.execute(&sql, &params)?;
+        if result.cmd_tuples() == 0 {
+            warn!("DELETE on {} matched no rows", qualified_name(relation));
+        }
+        Ok(())
+    }
+
+    pub fn truncate(&mut self, relations: &[&RelationInfo], flags: i8) -> Result<()> {
+        if relations.is_empty() {
+            return Ok(());
+        }
+
+        let tables = relations
+            .iter()
+            .map(|relation| qualified_name(relation))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut sql = format!("TRUNCATE ONLY {}", tables);
+        if flags & 2 != 0 {
+            sql.push_str(" RESTART IDENTITY");
+        }
+        if flags & 1 != 0 {
+            sql.push_str(" CASCADE");
+        }
+
+        self.execute(&sql, &[])?;
+        Ok(())
+    }
+
+    /// Start loading rows of the relation with COPY FROM STDIN inside the open transaction
+    pub fn start_copy(&mut self, relation: &RelationInfo) -> Result<()> {
+        let columns = relation
+            .columns
+            .iter()
+            .map(|column| quote_ident(&column.column_name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!("COPY {} ({}) FROM STDIN", qualified_name(relation), columns);
+        debug!("Applying: {}", sql);
+        let result = self.connection.exec(&sql)?;
+        if !result.is_copy_in() {
+            return Err(ReplicationError::apply(format!(
+                "{} failed: {}",
+                sql,
+                result.error_message()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Send one row in COPY text format, as read from the source with COPY TO STDOUT
+    pub fn copy_row(&mut self, line: &[u8]) -> Result<()> {
+        self.connection.put_copy_data(line)
+    }
+
+    /// End the COPY started by `start_copy`
+    pub fn finish_copy(&mut self) -> Result<()> {
+        self.connection.end_copy(Duration::from_secs(30))?;
+        self.connection
+            .finish_copy()
+            .map_err(|e| ReplicationError::apply(e.to_string()))
+    }
+
+    fn execute(&self, sql: &str, params: &[Option<String>]) -> Result<PGResult> {
+        debug!("Applying: {}", sql);
+        let params: Vec<Option<&str>> = params.iter().map(|param| param.as_deref()).collect();
+        let result = self.connection.exec_params(sql, &params)?;
+        if !result.is_ok() {
+            return Err(ReplicationError::apply(format!(
+                "{} failed: {}",
+                sql,
+                result.error_message()
+            )));
+        }
+        Ok(result)
+    }
+}
+
+/// Columns of the tuple with a value, skipping unchanged TOAST columns
+fn present_columns<'a>(
+    relation: &'a RelationInfo,
+    tuple_data: &'a TupleData,
+) -> impl Iterator<Item = (&'a ColumnInfo, &'a ColumnData)> {
+    relation
+        .columns
+        .iter()
+        .zip(tuple_data.columns.iter())
+        .filter(|(_, value)| value.data_type != 'u')
+}
+
+/// WHERE clause matching the replica identity columns of the tuple
+fn key_conditions(
+    relation: &RelationInfo,
+    key_tuple: &TupleData,
+    params: &mut Vec<Option<String>>,
+) -> Result<String> {
+    let mut conditions = Vec::new();
+    for (column, value) in relation.columns.iter().zip(key_tuple.columns.iter()) {
+        // Bit 1 of the column flags marks it as part of the replica identity
+        if column.key_flag & 1 == 0 || value.data_type == 'u' {
+            continue;
+        }
+        // = can use an index on the key, which IS NOT DISTINCT FROM cannot
+        match param_value(column, value) {
+            Some(param) => {
+                params.push(Some(param));
+                conditions.push(format!(
+                    "{} = ${}",
+                    quote_ident(&column.column_name),
+                    params.len()
+                ));
+            }
+            None => conditions.push(format!("{} IS NULL", quote_ident(&column.column_name))),
+        }
+    }
+
+    if conditions.is_empty() {
+        return Err(ReplicationError::apply(format!(
+            "Table {} has no replica identity columns to match rows on",
+            qualified_name(relation)
+        )));
+    }
+
+    Ok(conditions.join(" AND "))
+}
+
+/// Parameter for a column value, sent as text whatever format it arrived in
+fn param_value(column: &ColumnInfo, value: &ColumnData) -> Option<String> {
+    match value.data_type {
+        'n' => None,
+        _ => Some(value.to_text(column.column_type)),
+    }
+}
+
+fn qualified_name(relation: &RelationInfo) -> String {
+    quote_qualified(&relation.namespace, &relation.relation_name)
+}
@@ -0,0 +1,411 @@
+//! Per-transaction buffering of decoded messages
+//! Holds the messages of each transaction until it commits, so its changes are emitted together
+//! and aborted streamed transactions are never emitted. When the open transactions outgrow the
+//! memory limit the largest is spilled to a file, as is any single transaction over the
+//! per-transaction limit, and spilled transactions are read back on commit.
+//!
+//! Messages are kept as the raw pgoutput bytes and decoded again when the transaction is
+//! emitted. Spill files hold one record per message: the WAL position (u64), a flags byte
+//! (bit 0 streamed, bit 1 xid present), the xid (u32), the length (u32) and the message, all
+//! integers big-endian.
+
+use crate::errors::{ReplicationError, Result};
+use crate::types::Lsn;
+use crate::utils::Xid;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use tracing::{debug, info};
+
+const FLAG_IN_STREAM: u8 = 1;
+const FLAG_HAS_XID: u8 = 2;
+
+/// Memory limit and spill location of the transaction assembler
+#[derive(Debug, Clone)]
+pub struct AssemblerConfig {
+    /// Bytes of buffered messages kept in memory across all open transactions
+    pub memory_limit: usize,
+    /// Bytes one transaction may keep in memory before it is spilled, however little the
+    /// others hold
+    pub transaction_limit: Option<usize>,
+    /// Directory transactions over the memory limit are spilled to
+    pub spill_dir: PathBuf,
+    /// Emit each streamed block when it ends instead of waiting for Stream Commit, like the
+    /// server's parallel apply workers do
+    pub parallel: bool,
+}
+
+impl Default for AssemblerConfig {
+    fn default() -> Self {
+        Self {
+            memory_limit: 64 * 1024 * 1024,
+            transaction_limit: None,
+            spill_dir: env::temp_dir(),
+            parallel: false,
+        }
+    }
+}
+
+/// A logical replication message waiting for its transaction to commit
+#[derive(Debug)]
+pub struct BufferedMessage {
+    /// Start of the WAL data the message arrived in
+    pub lsn: Lsn,
+    /// Whether the message was sent inside a streamed block
+    pub in_stream: bool,
+    /// (Sub)transaction the message carries, for changes of streamed transactions
+    pub xid: Option<Xid>,
+    /// The pgoutput message as received
+    pub data: Vec<u8>,
+}
+
+/// Spill file of a transaction over the memory limit
+#[derive(Debug)]
+struct SpillFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl SpillFile {
+    fn create(path: PathBuf) -> Result<Self> {
+        let file = File::create(&path).map_err(|e| {
+            ReplicationError::spill(format!(
+                "Failed to create spill file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    fn write(&mut self, message: &BufferedMessage) -> Result<()> {
+        let mut flags = 0;
+        if message.in_stream {
+            flags |= FLAG_IN_STREAM;
+        }
+        if message.xid.is_some() {
+            flags |= FLAG_HAS_XID;
+        }
+
+        let mut header = Vec::with_capacity(17);
+        header.extend_from_slice(&message.lsn.value().to_be_bytes());
+        header.push(flags);
+        header.extend_from_slice(&message.xid.unwrap_or(0).to_be_bytes());
+        header.extend_from_slice(&(message.data.len() as u32).to_be_bytes());
+
+        self.writer
+            .write_all(&header)
+            .and_then(|_| self.writer.write_all(&message.data))
+            .map_err(|e| self.error("write", e))
+    }
+
+    fn error(&self, action: &str, e: std::io::Error) -> ReplicationError {
+        ReplicationError::spill(format!(
+            "Failed to {} spill file {}: {}",
+            action,
+            self.path.display(),
+            e
+        ))
+    }
+}
+
+/// Messages buffered for one top-level transaction
+#[derive(Debug, Default)]
+struct PendingTransaction {
+    messages: Vec<BufferedMessage>,
+    memory: usize,
+    spill: Option<SpillFile>,
+    /// Subtransactions aborted by a Stream Abort, whose changes are skipped on commit
+    aborted_subxacts: HashSet<Xid>,
+}
+
+/// Buffers messages per transaction until Commit or Stream Commit
+#[derive(Debug)]
+pub struct TransactionAssembler {
+    config: AssemblerConfig,
+    transactions: HashMap<Xid, PendingTransaction>,
+    memory: usize,
+    peak_memory: usize,
+    spilled_transactions: u64,
+    /// Bytes of messages written to spill files
+    spilled_bytes: u64,
+}
+
+impl TransactionAssembler {
+    pub fn new(config: AssemblerConfig) -> Self {
+        Self {
+            config,
+            transactions: HashMap::new(),
+            memory: 0,
+            peak_memory: 0,
+            spilled_transactions: 0,
+            spilled_bytes: 0,
+        }
+    }
+
+    /// Bytes of messages currently held in memory
+    pub fn memory_used(&self) -> usize {
+        self.memory
+    }
+
+    /// Most bytes of messages held in memory at once
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory
+    }
+
+    /// Transactions with buffered messages that have not committed or aborted yet
+    pub fn open_transactions(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Whether streamed blocks are emitted as they end rather than on Stream Commit
+    pub fn parallel(&self) -> bool {
+        self.config.parallel
+    }
+
+    /// Transactions that had to be spilled to disk so far
+    pub fn spilled_transactions(&self) -> u64 {
+        self.spilled_transactions
+    }
+
+    /// Log the peak memory held and how much was spilled, when the stream stops
+    pub fn log_summary(&self) {
+        info!(
+            peak_memory_bytes = self.peak_memory,
+            spilled_transactions = self.spilled_transactions,
+            spilled_bytes = self.spilled_bytes,
+            "Transaction buffer: peak {} bytes in memory, {} transactions spilled to disk ({} bytes)",
+            self.peak_memory,
+            self.spilled_transactions,
+            self.spilled_bytes
+        );
+    }
+
+    /// Buffer a message of the top-level transaction `xid`
+    pub fn push(&mut self, xid: Xid, message: BufferedMessage) -> Result<()> {
+        let transaction = self.transactions.entry(xid).or_default();
+        let size = message.data.len();
+        match transaction.spill.as_mut() {
+            // Once spilled, later messages follow the earlier ones into the file
+            Some(spill) => {
+                spill.write(&message)?;
+                self.spilled_bytes += size as u64;
+            }
+            None => {
+                transaction.messages.push(message);
+                transaction.memory += size;
+                self.memory += size;
+                self.peak_memory = self.peak_memory.max(self.memory);
+            }
+        }
+
+        if let Some(limit) = self.config.transaction_limit {
+            let memory = transaction.memory;
+            if memory > limit {
+                self.spill(xid, format_args!("it holds more than {} bytes", limit))?;
+            }
+        }
+        if self.memory > self.config.memory_limit {
+            self.spill_largest()?;
+        }
+        Ok(())
+    }
+
+    /// Move the transaction holding the most memory to a spill file
+    fn spill_largest(&mut self) -> Result<()> {
+        let Some(xid) = self
+            .transactions
+            .iter()
+            .filter(|(_, transaction)| transaction.spill.is_none())
+            .max_by_key(|(_, transaction)| transaction.memory)
+            .map(|(&xid, _)| xid)
+        else {
+            return Ok(());
+        };
+        let limit = self.config.memory_limit;
+        self.spill(
+            xid,
+            format_args!("buffered transactions exceed {} bytes", limit),
+        )
+    }
+
+    /// Move a transaction's messages to a spill file, which its later messages are appended to
+    fn spill(&mut self, xid: Xid, reason: fmt::Arguments) -> Result<()> {
+        let Some(transaction) = self.transactions.get_mut(&xid) else {
+            return Ok(());
+        };
+        let path = self
+            .config
+            .spill_dir
+            .join(format!("pgr-{}-{}.spill", std::process::id(), xid));
+        info!(
+            "Spilling transaction {} ({} bytes) to {}: {}",
+            xid,
+            transaction.memory,
+            path.display(),
+            reason
+        );
+
+        let mut spill = SpillFile::create(path)?;
+        for message in transaction.messages.drain(..) {
+            spill.write(&message)?;
+        }
+        self.memory -= transaction.memory;
+        self.spilled_bytes += transaction.memory as u64;
+        transaction.memory = 0;
+        transaction.spill = Some(spill);
+        self.spilled_transactions += 1;
+        Ok(())
+    }
+
+    /// Discard a transaction after a Stream Abort, or only the aborted subtransaction
+    pub fn abort(&mut self, xid: Xid, subtransaction_xid: Xid) {
+        if xid != subtransaction_xid {
+            if let Some(transaction) = self.transactions.get_mut(&xid) {
+                transaction.aborted_subxacts.insert(subtransaction_xid);
+            }
+            return;
+        }
+
+        if let Some(transaction) = self.transactions.remove(&xid) {
+            debug!(
+                "Discarding {} buffered messages of aborted transaction {}",
+                transaction.messages.len(),
+                xid
+            );
+            self.release(transaction);
+        }
+    }
+
+    /// Take the buffered messages of a committed transaction, in the order they arrived
+    pub fn take(&mut self, xid: Xid) -> Result<CommittedTransaction> {
+        let Some(mut transaction) = self.transactions.remove(&xid) else {
+            return Ok(CommittedTransaction::default());
+        };
+        self.memory -= transaction.memory;
+
+        let spilled = match transaction.spill.take() {
+            Some(mut spill) => {
+                spill.writer.flush().map_err(|e| spill.error("flush", e))?;
+                let file = File::open(&spill.path).map_err(|e| spill.error("open", e))?;
+                Some((BufReader::new(file), spill.path))
+            }
+            None => None,
+        };
+
+        Ok(CommittedTransaction {
+            spilled,
+            messages: std::mem::take(&mut transaction.messages).into_iter(),
+            aborted_subxacts: transaction.aborted_subxacts,
+        })
+    }
+
+    /// Discard every open transaction, e.g. after a reconnect resends them
+    pub fn clear(&mut self) {
+        let transactions: Vec<PendingTransaction> = self
+            .transactions
+            .drain()
+            .map(|(_, transaction)| transaction)
+            .collect();
+        for transaction in transactions {
+            self.release(transaction);
+        }
+    }
+
+    fn release(&mut self, transaction: PendingTransaction) {
+        self.memory -= transaction.memory;
+        if let Some(spill) = transaction.spill {
+            let path = spill.path.clone();
+            drop(spill);
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Drop for TransactionAssembler {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Messages of a committed transaction, read back from its spill file first
+#[derive(Default)]
+pub struct CommittedTransaction {
+    spilled: Option<(BufReader<File>, PathBuf)>,
+    messages: std::vec::IntoIter<BufferedMessage>,
+    aborted_subxacts: HashSet<Xid>,
+}
+
+impl CommittedTransaction {
+    /// The next message, skipping changes of aborted subtransactions
+    pub fn next_message(&mut self) -> Result<Option<BufferedMessage>> {
+        loop {
+            let message = match self.read_spilled()? {
+                Some(message) => message,
+                None => match self.messages.next() {
+                    Some(message) => message,
+                    None => return Ok(None),
+                },
+            };
+
+            match message.xid {
+                Some(xid) if self.aborted_subxacts.contains(&xid) => continue,
+                _ => return Ok(Some(message)),
+            }
+        }
+    }
+
+    fn read_spilled(&mut self) -> Result<Option<BufferedMessage>> {
+        let Some((reader, path)) = self.spilled.as_mut() else {
+            return Ok(None);
+        };
+        let error = |e: std::io::Error| {
+            ReplicationError::spill(format!(
+                "Failed to read spill file {}: {}",
+                path.display(),
+                e
+            ))
+        };
+
+        let mut header = [0u8; 17];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                if let Some((_, path)) = self.spilled.take() {
+                    let _ = fs::remove_file(path);
+                }
+                return Ok(None);
+            }
+            Err(e) => return Err(error(e)),
+        }
+
+        let lsn = Lsn(u64::from_be_bytes(header[..8].try_into().unwrap()));
+        let flags = header[8];
+        let xid = u32::from_be_bytes(header[9..13].try_into().unwrap());
+        let len = u32::from_be_bytes(header[13..].try_into().unwrap());
+
+        let mut data = vec![0u8; len as usize];
+        reader.read_exact(&mut data).map_err(error)?;
+
+        Ok(Some(BufferedMessage {
+            lsn,
+            in_stream: flags & FLAG_IN_STREAM != 0,
+            xid: (flags & FLAG_HAS_XID != 0).then_some(xid),
+            data,
+        }))
+    }
+}
+
+impl Drop for CommittedTransaction {
+    fn drop(&mut self) {
+        if let Some((_, path)) = self.spilled.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,579 @@
+//! Avro sink for change events with a Confluent-compatible schema registry
+//! Derives an Avro schema per table from its Relation message, registers it under the subject
+//! `<topic>-value` and writes each change in the Confluent wire format (magic byte, schema ID,
+//! Avro binary), ready to be produced to Kafka as the message value of the table's topic.
+//! Each table gets a file of length-prefixed messages under the sink directory.
+
+use crate::datetime::{self, DateOrder};
+use crate::errors::{ReplicationError, Result};
+use crate::handler::ChangeHandler;
+use crate::mask::ColumnMask;
+use crate::output::ChangeRecord;
+use crate::row::{Row, RowValue};
+use crate::types::{Lsn, RelationInfo};
+use crate::utils::{Oid, Xid};
+use crate::watch::ChangeKind;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{debug, info};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Leading byte of the Confluent wire format
+const MAGIC_BYTE: u8 = 0;
+
+/// Operation symbols of the envelope, in the order of their enum index
+const OPERATIONS: [&str; 4] = ["insert", "update", "delete", "truncate"];
+
+/// Where Avro messages are written and where their schemas are registered
+#[derive(Debug, Clone)]
+pub struct AvroConfig {
+    /// Directory holding a `<topic>.bin` file per table
+    pub directory: PathBuf,
+    /// Base `http://` URL of the schema registry
+    pub registry_url: String,
+    /// Prepended to the `schema.table` topic of each table, e.g. the source name
+    pub topic_prefix: Option<String>,
+}
+
+/// Avro type of a table column, chosen from the PostgreSQL type of the column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AvroKind {
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Date,
+    Timestamp,
+    TimestampTz,
+    Uuid,
+    String,
+}
+
+impl AvroKind {
+    fn for_type(type_oid: Oid) -> Self {
+        match type_oid {
+            16 => AvroKind::Boolean,
+            21 | 23 => AvroKind::Int,
+            20 | 26 => AvroKind::Long,
+            700 => AvroKind::Float,
+            701 => AvroKind::Double,
+            1082 => AvroKind::Date,
+            1114 => AvroKind::Timestamp,
+            1184 => AvroKind::TimestampTz,
+            2950 => AvroKind::Uuid,
+            _ => AvroKind::String,
+        }
+    }
+
+    fn schema(self) -> Value {
+        match self {
+            AvroKind::Boolean => json!("boolean"),
+            AvroKind::Int => json!("int"),
+            AvroKind::Long => json!("long"),
+            AvroKind::Float => json!("float"),
+            AvroKind::Double => json!("double"),
+            AvroKind::Date => json!({"type": "int", "logicalType": "date"}),
+            AvroKind::Timestamp => json!({"type": "long", "logicalType": "local-timestamp-micros"}),
+            AvroKind::TimestampTz => json!({"type": "long", "logicalType": "timestamp-micros"}),
+            AvroKind::Uuid => json!({"type": "string", "logicalType": "uuid"}),
+            AvroKind::String => json!("string"),
+        }
+    }
+
+    /// Encode the text form of a value, None when it does not fit the column type
+    fn encode(self, text: &str, buffer: &mut Vec<u8>) -> Option<()> {
+        match self {
+            AvroKind::Boolean => buffer.push(match text {
+                "t" | "true" => 1,
+                "f" | "false" => 0,
+                _ => return None,
+            }),
+            AvroKind::Int => write_long(buffer, text.parse::<i32>().ok()?.into()),
+            AvroKind::Long => write_long(buffer, text.parse().ok()?),
+            AvroKind::Float => buffer.extend_from_slice(&text.parse::<f32>().ok()?.to_le_bytes()),
+            AvroKind::Double => buffer.extend_from_slice(&text.parse::<f64>().ok()?.to_le_bytes()),
+            AvroKind::Date => {
+                let date = datetime::parse_date(text, DateOrder::default())?;
+                let epoch = NaiveDate::from_ymd_opt(1970, 1, 1)?;
+                write_long(buffer, (date - epoch).num_days())
+            }
+            AvroKind::Timestamp => {
+                let time = datetime::parse_timestamp(text, DateOrder::default())?;
+                write_long(buffer, time.and_utc().timestamp_micros())
+            }
+            AvroKind::TimestampTz => {
+                let time = datetime::parse_timestamptz(text, DateOrder::default())?;
+                write_long(buffer, time.timestamp_micros())
+            }
+            AvroKind::Uuid | AvroKind::String => write_bytes(buffer, text.as_bytes()),
+        }
+        Some(())
+    }
+}
+
+/// A table column and the Avro field it is written to
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Field {
+    column: String,
+    name: String,
+    kind: AvroKind,
+}
+
+/// Schema of a table's messages with its registry ID
+#[derive(Debug)]
+struct TableSchema {
+    fields: Vec<Field>,
+    schema_id: u32,
+    topic: String,
+}
+
+/// Columns of a table as Avro fields; masked values are replaced with text
+fn table_fields(relation: &RelationInfo, mask: &ColumnMask) -> Vec<Field> {
+    relation
+        .columns
+        .iter()
+        .map(|column| Field {
+            column: column.column_name.clone(),
+            name: avro_name(&column.column_name),
+            kind: if mask.matches(
+                &relation.namespace,
+                &relation.relation_name,
+                &column.column_name,
+            ) {
+                AvroKind::String
+            } else {
+                AvroKind::for_type(column.column_type)
+            },
+        })
+        .collect()
+}
+
+/// Envelope record of a table: the change metadata with the row before and after the change
+fn envelope_schema(relation: &RelationInfo, fields: &[Field]) -> Value {
+    let nullable = |schema: Value| json!(["null", schema]);
+    let columns: Vec<Value> = fields
+        .iter()
+        .map(|field| {
+            json!({"name": field.name, "type": nullable(field.kind.schema()), "default": null})
+        })
+        .collect();
+    let namespace = format!(
+        "{}.{}",
+        avro_name(&relation.namespace),
+        avro_name(&relation.relation_name)
+    );
+    let value = json!({"type": "record", "name": "Value", "fields": columns});
+    json!({
+        "type": "record",
+        "name": "Envelope",
+        "namespace": namespace,
+        "fields": [
+            {"name": "op", "type": {"type": "enum", "name": "Operation", "symbols": OPERATIONS}},
+            {"name": "id", "type": ["null", "string"], "default": null},
+            {"name": "source", "type": ["null", "string"], "default": null},
+            {"name": "lsn", "type": ["null", "string"], "default": null},
+            {"name": "xid", "type": ["null", "long"], "default": null},
+            {"name": "commit_time", "type": nullable(AvroKind::TimestampTz.schema()), "default": null},
+            {"name": "before", "type": nullable(value), "default": null},
+            {"name": "after", "type": ["null", "Value"], "default": null},
+        ],
+    })
+}
+
+/// Writes change events of every table as registry-framed Avro messages
+pub struct AvroSink {
+    config: AvroConfig,
+    mask: ColumnMask,
+    registry: Registry,
+    schemas: HashMap<(String, String), TableSchema>,
+    files: HashMap<String, BufWriter<File>>,
+    /// Commit time of the transaction being received, if it started with a Begin
+    commit_time: Option<DateTime<Utc>>,
+    messages_written: u64,
+}
+
+impl AvroSink {
+    pub fn new(config: AvroConfig, mask: ColumnMask) -> Result<Self> {
+        let registry = Registry::parse(&config.registry_url)?;
+        fs::create_dir_all(&config.directory).map_err(|e| {
+            ReplicationError::avro(format!(
+                "Failed to create {}: {}",
+                config.directory.display(),
+                e
+            ))
+        })?;
+        info!(
+            "Writing Avro change events under {} with schemas registered at {}",
+            config.directory.display(),
+            config.registry_url
+        );
+        Ok(Self {
+            config,
+            mask,
+            registry,
+            schemas: HashMap::new(),
+            files: HashMap::new(),
+            commit_time: None,
+            messages_written: 0,
+        })
+    }
+
+    /// Derive the schema of a table from its Relation message and register it when its columns
+    /// changed; the registry returns the existing ID for a schema it already knows
+    pub fn register_relation(&mut self, relation: &RelationInfo) -> Result<()> {
+        let key = (relation.namespace.clone(), relation.relation_name.clone());
+        let fields = table_fields(relation, &self.mask);
+        if self
+            .schemas
+            .get(&key)
+            .is_some_and(|schema| schema.fields == fields)
+        {
+            return Ok(());
+        }
+
+        let topic = match &self.config.topic_prefix {
+            Some(prefix) => format!("{}.{}.{}", prefix, key.0, key.1),
+            None => format!("{}.{}", key.0, key.1),
+        };
+        let schema = envelope_schema(relation, &fields);
+        let schema_id = self
+            .registry
+            .register(&format!("{}-value", topic), &schema)?;
+        info!("Registered Avro schema {} for topic {}", schema_id, topic);
+        self.schemas.insert(
+            key,
+            TableSchema {
+                fields,
+                schema_id,
+                topic,
+            },
+        );
+        Ok(())
+    }
+
+    /// Flush every file
+    pub fn close(&mut self) -> Result<()> {
+        for (topic, file) in self.files.iter_mut() {
+            file.flush().map_err(|e| {
+                ReplicationError::avro(format!("Failed to write the file of {}: {}", topic, e))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Flush every file and sync it to disk
+    pub fn sync(&mut self) -> Result<()> {
+        self.close()?;
+        for (topic, file) in self.files.iter() {
+            file.get_ref().sync_data().map_err(|e| {
+                ReplicationError::avro(format!("Failed to sync the file of {}: {}", topic, e))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Messages written so far
+    pub fn messages_written(&self) -> u64 {
+        self.messages_written
+    }
+
+    fn file_for(&mut self, topic: &str) -> Result<&mut BufWriter<File>> {
+        if !self.files.contains_key(topic) {
+            let path = self.config.directory.join(format!("{}.bin", topic));
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| {
+                    ReplicationError::avro(format!("Failed to open {}: {}", path.display(), e))
+                })?;
+            self.files.insert(topic.to_string(), BufWriter::new(file));
+        }
+        self.files
+            .get_mut(topic)
+            .ok_or_else(|| ReplicationError::avro(format!("No file open for {}", topic)))
+    }
+}
+
+impl ChangeHandler for AvroSink {
+    fn on_begin(&mut self, _xid: Xid, _final_lsn: Lsn, commit_time: DateTime<Utc>) -> Result<()> {
+        self.commit_time = Some(commit_time);
+        Ok(())
+    }
+
+    fn on_change(&mut self, change: &ChangeRecord) -> Result<()> {
+        let key = (change.schema.clone(), change.table.clone());
+        let Some(schema) = self.schemas.get(&key) else {
+            debug!(
+                "No schema known for {}.{}, change not written to Avro",
+                key.0, key.1
+            );
+            return Ok(());
+        };
+
+        let mut message = vec![MAGIC_BYTE];
+        message.extend_from_slice(&schema.schema_id.to_be_bytes());
+        write_long(&mut message, op_index(change.op));
+        write_optional(&mut message, change.id.as_deref(), |buffer, id| {
+            write_bytes(buffer, id.as_bytes())
+        });
+        write_optional(&mut message, change.source.as_deref(), |buffer, source| {
+            write_bytes(buffer, source.as_bytes())
+        });
+        write_optional(&mut message, change.lsn.as_deref(), |buffer, lsn| {
+            write_bytes(buffer, lsn.as_bytes())
+        });
+        write_optional(&mut message, change.xid, |buffer, xid| {
+            write_long(buffer, xid.into())
+        });
+        // Streamed transactions have no Begin, so their changes have no commit time
+        write_optional(&mut message, self.commit_time, |buffer, time| {
+            write_long(buffer, time.timestamp_micros())
+        });
+        for row in [change.old.as_ref(), change.new.as_ref()] {
+            write_optional(&mut message, row, |buffer, row| {
+                write_row(buffer, &schema.fields, row)
+            });
+        }
+
+        let topic = schema.topic.clone();
+        let file = self.file_for(&topic)?;
+        file.write_all(&(message.len() as u32).to_be_bytes())
+            .and_then(|_| file.write_all(&message))
+            .map_err(|e| {
+                ReplicationError::avro(format!("Failed to write the file of {}: {}", topic, e))
+            })?;
+        self.messages_written += 1;
+        Ok(())
+    }
+
+    fn on_commit(
+        &mut self,
+        _commit_lsn: Lsn,
+        _end_lsn: Lsn,
+        _commit_time: DateTime<Utc>,
+    ) -> Result<()> {
+        self.commit_time = None;
+        // Whole transactions become visible to readers of the files
+        self.close()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sync()
+    }
+}
+
+fn op_index(op: ChangeKind) -> i64 {
+    match op {
+        ChangeKind::Insert => 0,
+        ChangeKind::Update => 1,
+        ChangeKind::Delete => 2,
+        ChangeKind::Truncate => 3,
+    }
+}
+
+/// Encode the `Value` record; NULL, unchanged TOAST values and unparsable text are null
+fn write_row(buffer: &mut Vec<u8>, fields: &[Field], row: &Row) {
+    for field in fields {
+        let text = row.get(&field.column).and_then(RowValue::as_text);
+        let mut value = Vec::new();
+        let encoded = text.and_then(|text| field.kind.encode(text, &mut value));
+        if text.is_some() && encoded.is_none() {
+            debug!(
+                "Value of column {} does not fit {:?}, written as null",
+                field.column, field.kind
+            );
+        }
+        match encoded {
+            Some(()) => {
+                write_long(buffer, 1);
+                buffer.extend_from_slice(&value);
+            }
+            None => write_long(buffer, 0),
+        }
+    }
+}
+
+/// Encode a `["null", T]` union
+fn write_optional<T>(buffer: &mut Vec<u8>, value: Option<T>, write: impl FnOnce(&mut Vec<u8>, T)) {
+    match value {
+        Some(value) => {
+            write_long(buffer, 1);
+            write(buffer, value);
+        }
+        None => write_long(buffer, 0),
+    }
+}
+
+/// Zigzag varint encoding shared by Avro `int` and `long`
+fn write_long(buffer: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn write_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    write_long(buffer, bytes.len() as i64);
+    buffer.extend_from_slice(bytes);
+}
+
+/// A valid Avro name: letters, digits and underscores, not starting with a digit
+fn avro_name(name: &str) -> String {
+    let mut avro: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !avro.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        avro.insert(0, '_');
+    }
+    avro
+}
+
+/// Client of the schema registry's REST API
+#[derive(Debug)]
+struct Registry {
+    url: String,
+    host: String,
+    port: u16,
+    /// Path prefix of the API, without a trailing slash
+    path: String,
+}
+
+impl Registry {
+    fn parse(url: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            return Err(ReplicationError::config(format!(
+                "Unsupported schema registry URL {}: only http:// is supported",
+                url
+            )));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse().map_err(|_| {
+                    ReplicationError::config(format!("Invalid port in schema registry URL {}", url))
+                })?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(ReplicationError::config(format!(
+                "Schema registry URL {} has no host",
+                url
+            )));
+        }
+
+        Ok(Self {
+            url: url.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Register the schema under the subject and return its ID
+    fn register(&self, subject: &str, schema: &Value) -> Result<u32> {
+        let body = json!({ "schema": schema.to_string() }).to_string();
+        let path = format!(
+            "{}/subjects/{}/versions",
+            self.path,
+            percent_encode(subject)
+        );
+        let response = self.post(&path, body.as_bytes())?;
+        response
+            .get("id")
+            .and_then(Value::as_u64)
+            .and_then(|id| u32::try_from(id).ok())
+            .ok_or_else(|| {
+                ReplicationError::avro(format!(
+                    "Schema registry {} returned no schema ID for {}: {}",
+                    self.url, subject, response
+                ))
+            })
+    }
+
+    /// POST a JSON body and parse the JSON response of a 2xx status
+    fn post(&self, path: &str, body: &[u8]) -> Result<Value> {
+        let error = |e: std::io::Error| {
+            ReplicationError::avro(format!("Request to {} failed: {}", self.url, e))
+        };
+
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(error)?
+            .next()
+            .ok_or_else(|| ReplicationError::avro(format!("Cannot resolve {}", self.host)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT).map_err(error)?;
+        stream
+            .set_read_timeout(Some(REQUEST_TIMEOUT))
+            .map_err(error)?;
+        stream
+            .set_write_timeout(Some(REQUEST_TIMEOUT))
+            .map_err(error)?;
+
+        // HTTP/1.0 keeps the response from being chunked
+        let header = format!(
+            "POST {} HTTP/1.0\r\nHost: {}:{}\r\nContent-Type: application/vnd.schemaregistry.v1+json\r\nAccept: application/vnd.schemaregistry.v1+json, application/json\r\nContent-Length: {}\r\n\r\n",
+            path,
+            self.host,
+            self.port,
+            body.len()
+        );
+        stream.write_all(header.as_bytes()).map_err(error)?;
+        stream.write_all(body).map_err(error)?;
+        stream.flush().map_err(error)?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).map_err(error)?;
+        let (head, content) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok());
+        match status {
+            Some(200..=299) => serde_json::from_str(content).map_err(|e| {
+                ReplicationError::avro(format!("{} sent an invalid response: {}", self.url, e))
+            }),
+            Some(status) => Err(ReplicationError::avro(format!(
+                "{} responded with status {}: {}",
+                self.url,
+                status,
+                content.trim()
+            ))),
+            None => Err(ReplicationError::avro(format!(
+                "{} sent an invalid response: {}",
+                self.url,
+                head.lines().next().unwrap_or_default()
+            ))),
+        }
+    }
+}
+
+/// Escape a path segment of a URL
+fn percent_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
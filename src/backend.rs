@@ -0,0 +1,459 @@
+//! Streaming backends behind one interface
+//! The libpq backend (`ReplicationServer`) speaks the replication protocol itself and decodes
+//! pgoutput with the in-tree parser; the walstream backend leaves both to pg-walstream. Both are
+//! configured with a `ReplicationConfig` and emit the same `ChangeRecord`s to the same sinks.
+//! Several sources are streamed by one backend each, concurrently on the same thread.
+
+use crate::cli::StreamArgs;
+use crate::ctlsocket::{self, ControlSources, StreamControl};
+use crate::errors::{ReplicationError, Result};
+use crate::events::EventStream;
+use crate::handler::ChangeHandler;
+use crate::health::{self, HealthSources, HealthState};
+use crate::reload::{DisplaySettings, PendingReload};
+use crate::server::ReplicationServer;
+use crate::service::{self, PidFile};
+use crate::signals::{ReloadSignal, ShutdownSignals};
+use crate::types::ReplicationConfig;
+use crate::walstream::WalstreamBackend;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::LocalSet;
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// Which implementation streams the changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// pg-walstream decodes the stream
+    Walstream,
+    /// The in-tree libpq client and pgoutput parser
+    Libpq,
+}
+
+impl FromStr for BackendKind {
+    type Err = ReplicationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "walstream" | "pg-walstream" => Ok(BackendKind::Walstream),
+            "libpq" => Ok(BackendKind::Libpq),
+            _ => Err(ReplicationError::config(format!(
+                "Invalid backend: {}. Valid values are: walstream, libpq",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendKind::Walstream => write!(f, "walstream"),
+            BackendKind::Libpq => write!(f, "libpq"),
+        }
+    }
+}
+
+/// A replication client that streams changes to `ChangeHandler`s
+pub trait ReplicationBackend {
+    /// Register a handler that receives every decoded transaction
+    fn add_handler(&mut self, handler: Box<dyn ChangeHandler>);
+
+    /// Flag that stops streaming gracefully once set
+    fn shutdown_handle(&self) -> Arc<AtomicBool>;
+
+    /// Display, output and filter settings to switch to at the next transaction boundary
+    fn reload_handle(&self) -> Arc<PendingReload>;
+
+    /// Liveness and readiness of the stream, as served by the health endpoint
+    fn health_state(&self) -> Arc<HealthState>;
+
+    /// Pause, resume and feedback requests from the control socket
+    fn stream_control(&self) -> Arc<StreamControl>;
+
+    /// Receive committed transactions as a stream, confirming to the server only the positions
+    /// acknowledged with `EventStream::ack`. Reading pauses while `capacity` transactions are
+    /// waiting to be taken from the stream, and dropping the stream stops streaming.
+    fn event_stream(&mut self, capacity: usize) -> EventStream;
+
+    /// Prepare the slot and stream until shutdown or a stop condition, confirming the final
+    /// position before returning
+    fn run(&mut self) -> impl Future<Output = Result<()>>;
+}
+
+impl ReplicationBackend for ReplicationServer {
+    fn add_handler(&mut self, handler: Box<dyn ChangeHandler>) {
+        ReplicationServer::add_handler(self, handler);
+    }
+
+    fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        ReplicationServer::shutdown_handle(self)
+    }
+
+    fn reload_handle(&self) -> Arc<PendingReload> {
+        ReplicationServer::reload_handle(self)
+    }
+
+    fn health_state(&self) -> Arc<HealthState> {
+        ReplicationServer::health_state(self)
+    }
+
+    fn stream_control(&self) -> Arc<StreamControl> {
+        ReplicationServer::stream_control(self)
+    }
+
+    fn event_stream(&mut self, capacity: usize) -> EventStream {
+        ReplicationServer::event_stream(self, capacity)
+    }
+
+    async fn run(&mut self) -> Result<()> {
+        self.identify_system()?;
+        self.create_replication_slot_and_start().await
+    }
+}
+
+impl ReplicationBackend for WalstreamBackend {
+    fn add_handler(&mut self, handler: Box<dyn ChangeHandler>) {
+        WalstreamBackend::add_handler(self, handler);
+    }
+
+    fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        WalstreamBackend::shutdown_handle(self)
+    }
+
+    fn reload_handle(&self) -> Arc<PendingReload> {
+        WalstreamBackend::reload_handle(self)
+    }
+
+    fn health_state(&self) -> Arc<HealthState> {
+        WalstreamBackend::health_state(self)
+    }
+
+    fn stream_control(&self) -> Arc<StreamControl> {
+        WalstreamBackend::stream_control(self)
+    }
+
+    fn event_stream(&mut self, capacity: usize) -> EventStream {
+        WalstreamBackend::event_stream(self, capacity)
+    }
+
+    async fn run(&mut self) -> Result<()> {
+        self.stream().await
+    }
+}
+
+/// Run the `stream` command with the requested backend, or `default` when none was requested.
+/// In service mode a run that fails with a retryable error is started again after the reconnect
+/// backoff, until it stops without an error or a shutdown signal arrives while waiting
+pub async fn run_stream(args: StreamArgs, default: BackendKind) -> Result<()> {
+    let _pid_file = args.pid_file.as_deref().map(PidFile::create).transpose()?;
+    if !args.service {
+        return stream_once(&args, default).await;
+    }
+
+    let policy = args.reconnect_policy();
+    info!("Service mode: restarting the stream after recoverable failures");
+    let mut restarts = 0;
+    loop {
+        let started = Instant::now();
+        let e = match stream_once(&args, default).await {
+            Err(e) if e.is_retryable() => e,
+            result => return result,
+        };
+        // A run that streamed for a while starts the backoff over
+        if started.elapsed() >= policy.max_backoff {
+            restarts = 0;
+        }
+        restarts += 1;
+        let delay = policy.backoff(restarts);
+        warn!(
+            error_kind = %e.kind(),
+            error_code = e.code(),
+            restarts,
+            "Restarting the stream in {:.1}s (restart {})",
+            delay.as_secs_f64(),
+            restarts
+        );
+        if !service::wait_before_restart(delay).await? {
+            return Ok(());
+        }
+    }
+}
+
+/// Stream until every source stops or one of them fails
+async fn stream_once(args: &StreamArgs, default: BackendKind) -> Result<()> {
+    let kind = args.backend.unwrap_or(default);
+    let health_port = args.health_port;
+    let health_timeout = Duration::from_secs(args.health_timeout_secs);
+    let control_socket = args.control_socket.clone();
+    let configs = if args.sources.is_empty() {
+        info!("Slot name: {}", args.slot.slot_name);
+        info!("Publication names: {}", args.publication_names.join(", "));
+        vec![args.replication_config()?]
+    } else {
+        let configs = args.source_configs()?;
+        for config in &configs {
+            info!(
+                "Source {}: slot {}, publications {}",
+                config.source.as_deref().unwrap_or_default(),
+                config.slot_name,
+                config.publication_names.join(", ")
+            );
+        }
+        configs
+    };
+    info!("Streaming with the {} backend", kind);
+
+    let Some(config) = configs.first() else {
+        return Err(ReplicationError::config(
+            "No replication source is configured",
+        ));
+    };
+    if args.target_connection_string.is_some() && !args.no_streaming {
+        info!("Applying to a target database, disabling streaming of in-progress transactions");
+    }
+    log_selection(config);
+    if let Some(duration) = config.watch_duration {
+        info!("Watching replication for {}s", duration.as_secs());
+    }
+    if !config.stop_conditions.is_empty() {
+        info!("Stop conditions: {:?}", config.stop_conditions);
+    }
+
+    let result = match kind {
+        BackendKind::Libpq => {
+            let backends = configs
+                .into_iter()
+                .map(|config| Ok((config.source.clone(), ReplicationServer::new(config)?)))
+                .collect::<Result<Vec<_>>>()?;
+            run_all(backends, health_port, health_timeout, control_socket).await
+        }
+        BackendKind::Walstream => {
+            let backends = configs
+                .into_iter()
+                .map(|config| Ok((config.source.clone(), WalstreamBackend::new(config)?)))
+                .collect::<Result<Vec<_>>>()?;
+            run_all(backends, health_port, health_timeout, control_socket).await
+        }
+    };
+    match result {
+        Ok(()) => {
+            info!("Replication stream completed successfully");
+            Ok(())
+        }
+        Err(e) => {
+            error!(
+                error_kind = %e.kind(),
+                error_code = e.code(),
+                retryable = e.is_retryable(),
+                "Replication stream failed: {}",
+                e
+            );
+            Err(e)
+        }
+    }
+}
+
+/// Stream with the backend, stopping gracefully on Ctrl+C, SIGTERM or SIGQUIT (or the Windows
+/// console events), reloading the display, output and filter settings on SIGHUP, serving the health endpoint on `health_port` and the
+/// control socket at `control_socket` if they are given
+pub async fn run<B: ReplicationBackend + 'static>(
+    backend: B,
+    health_port: Option<u16>,
+    health_timeout: Duration,
+    control_socket: Option<PathBuf>,
+) -> Result<()> {
+    run_all(
+        vec![(None, backend)],
+        health_port,
+        health_timeout,
+        control_socket,
+    )
+    .await
+}
+
+/// Stream with every backend concurrently like `run`, each in its own task whose log lines are
+/// labelled with its source; the first source to fail stops the others
+pub async fn run_all<B: ReplicationBackend + 'static>(
+    backends: Vec<(Option<String>, B)>,
+    health_port: Option<u16>,
+    health_timeout: Duration,
+    control_socket: Option<PathBuf>,
+) -> Result<()> {
+    // Stop at the next loop iteration so final feedback is sent before exiting
+    let shutdowns: Vec<Arc<AtomicBool>> = backends
+        .iter()
+        .map(|(_, backend)| backend.shutdown_handle())
+        .collect();
+    // Signal handlers and servers end with the run, freeing the health port for a restart
+    let mut background = Vec::new();
+    let mut signals = ShutdownSignals::new()?;
+    {
+        let shutdowns = shutdowns.clone();
+        background.push(tokio::spawn(async move {
+            let signal = signals.recv().await;
+            warn!("Received {}, shutting down gracefully...", signal);
+            for shutdown in &shutdowns {
+                shutdown.store(true, Ordering::Relaxed);
+            }
+        }));
+    }
+
+    let reloads: Vec<Arc<PendingReload>> = backends
+        .iter()
+        .map(|(_, backend)| backend.reload_handle())
+        .collect();
+    let mut hangup = ReloadSignal::new()?;
+    background.push(tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading display, output and filter settings");
+            // Connection settings keep the values the stream started with
+            match DisplaySettings::load() {
+                Ok(settings) => {
+                    for reload in &reloads {
+                        reload.request(settings.clone());
+                    }
+                }
+                Err(e) => error!("Failed to reload settings, keeping the current ones: {}", e),
+            }
+        }
+    }));
+
+    if let Some(port) = health_port {
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        let sources: HealthSources = backends
+            .iter()
+            .map(|(source, backend)| (source.clone(), backend.health_state()))
+            .collect();
+        background.push(tokio::spawn(async move {
+            if let Err(e) = health::serve(addr, sources, health_timeout).await {
+                error!("Health endpoint on {} failed: {}", addr, e);
+            }
+        }));
+    }
+
+    #[cfg(feature = "grpc")]
+    let mut backends = backends;
+    // Not a background task: it ends the calls once the stream stops, rather than being aborted
+    #[cfg(feature = "grpc")]
+    let mut grpc = None;
+    #[cfg(feature = "grpc")]
+    if let Some(config) = crate::grpc::GrpcConfig::from_env()? {
+        let hub = crate::grpc::GrpcHub::new(config.subscriber_buffer);
+        for (source, backend) in &mut backends {
+            backend.add_handler(Box::new(hub.publisher(source.clone())));
+        }
+        let addr = config.listen;
+        let server = hub.clone();
+        grpc = Some((
+            hub,
+            tokio::spawn(async move {
+                if let Err(e) = crate::grpc::serve(addr, server).await {
+                    error!("gRPC server on {} failed: {}", addr, e);
+                }
+            }),
+        ));
+    }
+
+    #[cfg(feature = "otlp")]
+    crate::otlp::register_metrics(
+        backends
+            .iter()
+            .map(|(source, backend)| (source.clone(), backend.health_state()))
+            .collect(),
+    );
+
+    if let Some(path) = control_socket {
+        let sources: ControlSources = backends
+            .iter()
+            .map(|(source, backend)| {
+                (
+                    source.clone(),
+                    backend.stream_control(),
+                    backend.health_state(),
+                )
+            })
+            .collect();
+        background.push(tokio::spawn(async move {
+            if let Err(e) = ctlsocket::serve(&path, sources, health_timeout).await {
+                error!("Control socket {} failed: {}", path.display(), e);
+            }
+        }));
+    }
+
+    // The backends are not Send, so their tasks share this thread
+    let tasks = LocalSet::new();
+    let result = tasks
+        .run_until(async move {
+            let (finished, mut results) = mpsc::unbounded_channel();
+            let count = backends.len();
+            for (source, mut backend) in backends {
+                let finished = finished.clone();
+                let span = match &source {
+                    Some(name) => info_span!("source", name = %name),
+                    None => tracing::Span::none(),
+                };
+                tokio::task::spawn_local(
+                    async move {
+                        let result = backend.run().await;
+                        if let Err(e) = &result {
+                            backend.health_state().record_error(e);
+                            error!(
+                                error_kind = %e.kind(),
+                                error_code = e.code(),
+                                retryable = e.is_retryable(),
+                                "Replication stream failed: {}",
+                                e
+                            );
+                        }
+                        let _ = finished.send(result);
+                    }
+                    .instrument(span),
+                );
+            }
+
+            let mut first_error = None;
+            for _ in 0..count {
+                let Some(result) = results.recv().await else {
+                    break;
+                };
+                if let (Err(e), None) = (result, &first_error) {
+                    for shutdown in &shutdowns {
+                        shutdown.store(true, Ordering::Relaxed);
+                    }
+                    first_error = Some(e);
+                }
+            }
+            first_error.map_or(Ok(()), Err)
+        })
+        .await;
+    for task in background {
+        task.abort();
+    }
+    #[cfg(feature = "grpc")]
+    if let Some((hub, server)) = grpc {
+        hub.shutdown();
+        let _ = server.await;
+    }
+    result
+}
+
+/// Log the table and row filters and the sampling of the configuration
+pub fn log_selection(config: &ReplicationConfig) {
+    if !config.table_filter.is_empty() {
+        info!("Table filter: {:?}", config.table_filter);
+    }
+    for predicate in config.row_filter.predicates() {
+        info!("Row filter: {}", predicate);
+    }
+    for rule in config.sampling.rules() {
+        info!("Sampling: {}", rule);
+    }
+}
@@ -0,0 +1,289 @@
+//! Text rendering of column values sent in binary format
+//! With the `binary` option pgoutput sends each value in its type's binary send format; the
+//! common built-in types are decoded here into the same text the server would have sent, and
+//! anything else is shown as hex like a bytea
+
+use crate::utils::Oid;
+use std::fmt::{LowerExp, Write};
+
+const BOOLOID: Oid = 16;
+const BYTEAOID: Oid = 17;
+const CHAROID: Oid = 18;
+const NAMEOID: Oid = 19;
+const INT8OID: Oid = 20;
+const INT2OID: Oid = 21;
+const INT4OID: Oid = 23;
+const TEXTOID: Oid = 25;
+const OIDOID: Oid = 26;
+const JSONOID: Oid = 114;
+const XMLOID: Oid = 142;
+const FLOAT4OID: Oid = 700;
+const FLOAT8OID: Oid = 701;
+const BPCHAROID: Oid = 1042;
+const VARCHAROID: Oid = 1043;
+const DATEOID: Oid = 1082;
+const TIMEOID: Oid = 1083;
+const TIMESTAMPOID: Oid = 1114;
+const TIMESTAMPTZOID: Oid = 1184;
+const NUMERICOID: Oid = 1700;
+const UUIDOID: Oid = 2950;
+const JSONBOID: Oid = 3802;
+
+const NUMERIC_NEG: u16 = 0x4000;
+const NUMERIC_NAN: u16 = 0xC000;
+const NUMERIC_PINF: u16 = 0xD000;
+const NUMERIC_NINF: u16 = 0xF000;
+
+/// Largest decimal exponent float4out and float8out write without switching to scientific
+/// notation
+const FLOAT4_MAX_EXPONENT: i32 = 5;
+const FLOAT8_MAX_EXPONENT: i32 = 14;
+
+/// Days from 0000-03-01, the start of the 400 year cycle days are counted in, to 2000-01-01
+const DAYS_TO_PG_EPOCH: i64 = 730_425;
+const DAYS_PER_400_YEARS: i64 = 146_097;
+const MICROS_PER_DAY: i64 = 86_400_000_000;
+
+/// Render a binary value of the given type as text, falling back to hex for unknown types
+pub fn to_text(type_oid: Oid, data: &[u8]) -> String {
+    decode(type_oid, data).unwrap_or_else(|| hex(data))
+}
+
+fn decode(type_oid: Oid, data: &[u8]) -> Option<String> {
+    let text = match type_oid {
+        BOOLOID => match data {
+            [0] => "f".to_string(),
+            [_] => "t".to_string(),
+            _ => return None,
+        },
+        BYTEAOID => hex(data),
+        CHAROID | NAMEOID | TEXTOID | JSONOID | XMLOID | BPCHAROID | VARCHAROID => {
+            String::from_utf8_lossy(data).into_owned()
+        }
+        JSONBOID => match data.split_first() {
+            // Version 1 is the only jsonb binary format: the version byte and the JSON text
+            Some((1, json)) => String::from_utf8_lossy(json).into_owned(),
+            _ => return None,
+        },
+        INT2OID => i16::from_be_bytes(data.try_into().ok()?).to_string(),
+        INT4OID => i32::from_be_bytes(data.try_into().ok()?).to_string(),
+        INT8OID => i64::from_be_bytes(data.try_into().ok()?).to_string(),
+        OIDOID => u32::from_be_bytes(data.try_into().ok()?).to_string(),
+        FLOAT4OID => float_to_text(
+            f32::from_be_bytes(data.try_into().ok()?),
+            FLOAT4_MAX_EXPONENT,
+        )?,
+        FLOAT8OID => float_to_text(
+            f64::from_be_bytes(data.try_into().ok()?),
+            FLOAT8_MAX_EXPONENT,
+        )?,
+        NUMERICOID => numeric_to_text(data)?,
+        UUIDOID => uuid_to_text(data.try_into().ok()?),
+        DATEOID => date_to_text(i32::from_be_bytes(data.try_into().ok()?)),
+        TIMEOID => time_to_text(i64::from_be_bytes(data.try_into().ok()?)),
+        TIMESTAMPOID => timestamp_to_text(i64::from_be_bytes(data.try_into().ok()?), false),
+        TIMESTAMPTZOID => timestamp_to_text(i64::from_be_bytes(data.try_into().ok()?), true),
+        _ => return None,
+    };
+    Some(text)
+}
+
+/// bytea hex output format
+fn hex(data: &[u8]) -> String {
+    let mut text = String::with_capacity(2 + data.len() * 2);
+    text.push_str("\\x");
+    for byte in data {
+        let _ = write!(text, "{:02x}", byte);
+    }
+    text
+}
+
+/// The shortest digits that read back as the same value, as float4out and float8out write them
+/// since PostgreSQL 12: positional for decimal exponents from -4 to `max_exponent`, otherwise
+/// scientific with a signed exponent of at least two digits
+fn float_to_text<F: LowerExp + Into<f64> + Copy>(value: F, max_exponent: i32) -> Option<String> {
+    let wide: f64 = value.into();
+    if wide.is_nan() {
+        return Some("NaN".to_string());
+    } else if wide.is_infinite() {
+        return Some(if wide > 0.0 { "Infinity" } else { "-Infinity" }.to_string());
+    }
+
+    // LowerExp writes the shortest round-tripping digits of the value's own precision
+    let scientific = format!("{:e}", value);
+    let (mantissa, exponent) = scientific.split_once('e')?;
+    let exponent: i32 = exponent.parse().ok()?;
+    if !(-4..=max_exponent).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        return Some(format!("{}e{}{:02}", mantissa, sign, exponent.abs()));
+    }
+
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(mantissa) => ("-", mantissa),
+        None => ("", mantissa),
+    };
+    let digits = mantissa.replace('.', "");
+    let text = if exponent < 0 {
+        format!("0.{}{}", "0".repeat((-exponent - 1) as usize), digits)
+    } else {
+        let point = exponent as usize + 1;
+        if digits.len() <= point {
+            format!("{}{}", digits, "0".repeat(point - digits.len()))
+        } else {
+            format!("{}.{}", &digits[..point], &digits[point..])
+        }
+    };
+    Some(format!("{}{}", sign, text))
+}
+
+/// Decode the numeric send format: digit count, weight of the first digit, sign, display
+/// scale, then base 10000 digits
+fn numeric_to_text(data: &[u8]) -> Option<String> {
+    let header = |i: usize| data.get(i..i + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let ndigits = header(0)? as usize;
+    let weight = header(2)? as i16 as i64;
+    let sign = header(4)?;
+    let dscale = header(6)? as usize;
+
+    match sign {
+        NUMERIC_NAN => return Some("NaN".to_string()),
+        NUMERIC_PINF => return Some("Infinity".to_string()),
+        NUMERIC_NINF => return Some("-Infinity".to_string()),
+        _ => {}
+    }
+
+    let digits = (0..ndigits)
+        .map(|i| header(8 + i * 2))
+        .collect::<Option<Vec<u16>>>()?;
+    let digit = |index: i64| {
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| digits.get(index).copied())
+            .unwrap_or(0)
+    };
+
+    let mut text = String::new();
+    if sign == NUMERIC_NEG {
+        text.push('-');
+    }
+
+    if weight < 0 {
+        text.push('0');
+    } else {
+        for index in 0..=weight {
+            if index == 0 {
+                let _ = write!(text, "{}", digit(index));
+            } else {
+                let _ = write!(text, "{:04}", digit(index));
+            }
+        }
+    }
+
+    if dscale > 0 {
+        // Fractional digit group n (1-based) sits at index weight + n
+        let mut fraction = String::new();
+        let mut index = weight + 1;
+        while fraction.len() < dscale {
+            let _ = write!(fraction, "{:04}", digit(index));
+            index += 1;
+        }
+        fraction.truncate(dscale);
+        text.push('.');
+        text.push_str(&fraction);
+    }
+
+    Some(text)
+}
+
+fn uuid_to_text(data: [u8; 16]) -> String {
+    let hex = hex(&data);
+    let hex = &hex[2..];
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Days since 2000-01-01
+fn date_to_text(days: i32) -> String {
+    match days {
+        i32::MAX => "infinity".to_string(),
+        i32::MIN => "-infinity".to_string(),
+        _ => with_era(days as i64, ""),
+    }
+}
+
+/// Proleptic Gregorian year, month and day of a day count from 2000-01-01
+///
+/// Computed directly rather than through chrono, whose dates end in the year 262143 while
+/// PostgreSQL's run to 5874897.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    // Years are counted from March so the leap day falls at the end of each one
+    let days = days + DAYS_TO_PG_EPOCH;
+    let era = days.div_euclid(DAYS_PER_400_YEARS);
+    let day_of_era = days.rem_euclid(DAYS_PER_400_YEARS);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = era * 400 + year_of_era + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// `YYYY-MM-DD` of a day count from 2000-01-01 followed by `time`, with years before 1 AD as
+/// PostgreSQL writes them: `0044-03-15 12:00:00 BC`
+fn with_era(days: i64, time: &str) -> String {
+    let (year, month, day) = civil_from_days(days);
+    if year <= 0 {
+        // 1 BC is year 0 of the proleptic calendar
+        format!("{:04}-{:02}-{:02}{} BC", 1 - year, month, day, time)
+    } else {
+        format!("{:04}-{:02}-{:02}{}", year, month, day, time)
+    }
+}
+
+/// Microseconds since midnight
+fn time_to_text(micros: i64) -> String {
+    let secs = micros.div_euclid(1_000_000);
+    format!(
+        "{:02}:{:02}:{:02}{}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        fraction_to_text(micros.rem_euclid(1_000_000))
+    )
+}
+
+/// Microseconds since 2000-01-01, rendered in UTC for timestamptz
+fn timestamp_to_text(micros: i64, with_time_zone: bool) -> String {
+    match micros {
+        i64::MAX => return "infinity".to_string(),
+        i64::MIN => return "-infinity".to_string(),
+        _ => {}
+    }
+
+    let time = time_to_text(micros.rem_euclid(MICROS_PER_DAY));
+    let zone = if with_time_zone { "+00" } else { "" };
+    with_era(
+        micros.div_euclid(MICROS_PER_DAY),
+        &format!(" {}{}", time, zone),
+    )
+}
+
+/// Fractional seconds with trailing zeros removed, empty for whole seconds
+fn fraction_to_text(micros: i64) -> String {
+    if micros == 0 {
+        return String::new();
+    }
+    format!(".{:06}", micros).trim_end_matches('0').to_string()
+}
@@ -1,5 +1,11 @@
 use crate::errors::{ReplicationError, Result};
+use crate::types::Lsn;
 use crate::utils::{buf_recv_i16, buf_recv_i32, buf_recv_i64, buf_recv_u32, buf_recv_u64};
+use std::borrow::Cow;
+
+/// Longest null-terminated string read; the protocol only sends names, GIDs and origin names
+/// this way, all far shorter, so anything longer is corrupt data
+pub const MAX_STRING_LEN: usize = 8192;
 
 /// A buffer reader that manages position and provides meaningful parsing methods
 #[derive(Debug)]
@@ -96,6 +102,11 @@ impl<'a> BufferReader<'a> {
         Ok(value)
     }
 
+    /// Read a WAL position at current position
+    pub fn read_lsn(&mut self) -> Result<Lsn> {
+        self.read_u64().map(Lsn)
+    }
+
     /// Read a 64-bit signed integer at current position
     pub fn read_i64(&mut self) -> Result<i64> {
         if !self.has_bytes(8) {
@@ -108,45 +119,92 @@ impl<'a> BufferReader<'a> {
 
     /// Read a null-terminated string at current position
     pub fn read_null_terminated_string(&mut self) -> Result<String> {
-        let start_pos = self.position;
+        Ok(self.read_null_terminated_str()?.into_owned())
+    }
 
-        // Find the null terminator
-        while self.position < self.buffer.len() && self.buffer[self.position] != 0 {
-            self.position += 1;
-        }
+    /// Read a null-terminated string, borrowing it from the buffer when it is valid UTF-8
+    pub fn read_null_terminated_str(&mut self) -> Result<Cow<'a, str>> {
+        let start_pos = self.position.min(self.buffer.len());
+        // Only the longest allowed string and its terminator are searched
+        let end = self.buffer.len().min(start_pos + MAX_STRING_LEN + 1);
 
-        if self.position >= self.buffer.len() {
+        // Find the null terminator
+        let Some(length) = self.buffer[start_pos..end]
+            .iter()
+            .position(|&byte| byte == 0)
+        else {
+            if end < self.buffer.len() {
+                return Err(ReplicationError::parse_with_context(
+                    "String too long",
+                    format!("Longer than {} bytes", MAX_STRING_LEN),
+                ));
+            }
             return Err(ReplicationError::parse("String not null-terminated"));
-        }
+        };
 
         // Extract the string
-        let string_bytes = &self.buffer[start_pos..self.position];
-        let string_value = String::from_utf8_lossy(string_bytes).into_owned();
+        let string_bytes = &self.buffer[start_pos..start_pos + length];
+        let string_value = String::from_utf8_lossy(string_bytes);
 
-        // Skip the null terminator
-        self.position += 1;
+        // Skip the string and its null terminator
+        self.position = start_pos + length + 1;
 
         Ok(string_value)
     }
 
     /// Read a length-prefixed string (32-bit length followed by data)
     pub fn read_length_prefixed_string(&mut self) -> Result<String> {
+        let string_bytes = self.read_length_prefixed_bytes()?;
+        Ok(String::from_utf8_lossy(string_bytes).into_owned())
+    }
+
+    /// Read length-prefixed raw bytes (4-byte length + data)
+    pub fn read_length_prefixed_bytes(&mut self) -> Result<&'a [u8]> {
         let length = self.read_i32()?;
 
         if length < 0 {
-            return Err(ReplicationError::parse("Negative string length"));
+            return Err(ReplicationError::parse("Negative data length"));
         }
 
         let length = length as usize;
         if !self.has_bytes(length) {
-            return Err(ReplicationError::parse("String data truncated"));
+            return Err(ReplicationError::parse("Data truncated"));
         }
 
-        let string_bytes = &self.buffer[self.position..self.position + length];
-        let string_value = String::from_utf8_lossy(string_bytes).into_owned();
-
+        let bytes = &self.buffer[self.position..self.position + length];
         self.position += length;
-        Ok(string_value)
+        Ok(bytes)
+    }
+
+    /// Check a count of `what` read from the message against `max` and against the bytes left,
+    /// each item taking at least `min_item_bytes`, so a corrupt count cannot cause a huge
+    /// allocation before the data runs out
+    pub fn check_count(
+        &self,
+        count: i64,
+        max: usize,
+        min_item_bytes: usize,
+        what: &str,
+    ) -> Result<usize> {
+        let Ok(count) = usize::try_from(count) else {
+            return Err(ReplicationError::parse_with_context(
+                "Negative count",
+                format!("{} {}", count, what),
+            ));
+        };
+        if count > max {
+            return Err(ReplicationError::parse_with_context(
+                "Count over limit",
+                format!("{} {}, at most {} allowed", count, what, max),
+            ));
+        }
+        if !self.has_bytes(count.saturating_mul(min_item_bytes)) {
+            return Err(ReplicationError::parse_with_context(
+                "Count exceeds message",
+                format!("{} {} in {} bytes", count, what, self.remaining()),
+            ));
+        }
+        Ok(count)
     }
 
     /// Peek at the next byte without advancing position
@@ -232,4 +290,9 @@ impl<'a> BufferWriter<'a> {
     pub fn bytes_written(&self) -> usize {
         self.position
     }
+
+    /// The bytes written so far, borrowed from the underlying buffer
+    pub fn into_written(self) -> &'a [u8] {
+        &self.buffer[..self.position]
+    }
 }
@@ -0,0 +1,134 @@
+//! Reusable buffers for CopyData messages
+//! libpq hands every CopyData message out in its own allocation, which has to be copied before
+//! it is freed. The copy goes into a buffer taken from this pool; `MessageParser` parses it in
+//! place and the buffer returns to the pool once the message is processed, so steady streaming
+//! allocates only when more messages are in flight than the pool holds
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Idle buffers kept for reuse; more are freed when returned
+const MAX_IDLE_BUFFERS: usize = 256;
+
+/// Buffers that grew beyond this for a large message are freed instead of kept
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+/// Pool of byte buffers, cheap to clone and shared between the receive and processing stages
+#[derive(Debug, Clone, Default)]
+pub struct BufferPool {
+    inner: Arc<PoolInner>,
+}
+
+#[derive(Debug, Default)]
+struct PoolInner {
+    idle: Mutex<Vec<Vec<u8>>>,
+    acquired: AtomicU64,
+    reused: AtomicU64,
+    /// Returned buffers freed because the pool was full or they were too large
+    discarded: AtomicU64,
+}
+
+/// Buffer pool usage since the start of the run
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct BufferPoolStats {
+    /// Buffers handed out
+    pub acquired: u64,
+    /// Buffers handed out that were reused instead of allocated
+    pub reused: u64,
+    /// Buffers freed on return
+    pub discarded: u64,
+    /// Buffers waiting in the pool
+    pub idle: usize,
+}
+
+impl BufferPoolStats {
+    /// Share of buffers that were reused, 0 before any was handed out
+    pub fn reuse_ratio(&self) -> f64 {
+        if self.acquired == 0 {
+            return 0.0;
+        }
+        self.reused as f64 / self.acquired as f64
+    }
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A buffer holding a copy of `data`, stamped with the time it was received
+    pub fn copy_from(&self, data: &[u8]) -> PooledBuffer {
+        let inner = &self.inner;
+        inner.acquired.fetch_add(1, Ordering::Relaxed);
+        let reused = inner.idle.lock().ok().and_then(|mut idle| idle.pop());
+        let mut buffer = match reused {
+            Some(buffer) => {
+                inner.reused.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => Vec::with_capacity(data.len()),
+        };
+        buffer.extend_from_slice(data);
+
+        PooledBuffer {
+            data: buffer,
+            pool: self.clone(),
+            received_at: Utc::now(),
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        let inner = &self.inner;
+        BufferPoolStats {
+            acquired: inner.acquired.load(Ordering::Relaxed),
+            reused: inner.reused.load(Ordering::Relaxed),
+            discarded: inner.discarded.load(Ordering::Relaxed),
+            idle: inner.idle.lock().map(|idle| idle.len()).unwrap_or(0),
+        }
+    }
+
+    fn release(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() <= MAX_POOLED_CAPACITY {
+            if let Ok(mut idle) = self.inner.idle.lock() {
+                if idle.len() < MAX_IDLE_BUFFERS {
+                    buffer.clear();
+                    idle.push(buffer);
+                    return;
+                }
+            }
+        }
+        self.inner.discarded.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A message buffer that goes back to its pool when dropped
+#[derive(Debug)]
+pub struct PooledBuffer {
+    data: Vec<u8>,
+    pool: BufferPool,
+    received_at: DateTime<Utc>,
+}
+
+impl PooledBuffer {
+    /// When the message was copied out of libpq, before it waited in the receive queue
+    pub fn received_at(&self) -> DateTime<Utc> {
+        self.received_at
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.release(std::mem::take(&mut self.data));
+    }
+}
@@ -0,0 +1,151 @@
+//! Capture files of the raw replication stream
+//! Records every CopyData payload with its arrival time so a session can be replayed offline
+//!
+//! Format: the magic bytes `PGRCAP01`, followed by one record per payload made of the arrival
+//! time in microseconds since the Unix epoch (u64), the payload length (u32) and the payload,
+//! all integers big-endian like the replication protocol itself. The whole file may be gzip or
+//! zstd compressed, which the reader recognises by its first bytes.
+
+use crate::compress::{CompressedReader, CompressedWriter, Compression};
+use crate::errors::{ReplicationError, Result};
+use chrono::{DateTime, Utc};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"PGRCAP01";
+
+/// Longest payload read back: no protocol message exceeds PostgreSQL's 1 GB allocation limit,
+/// so a larger length means the file is corrupt
+pub const MAX_MESSAGE_LEN: u32 = 1 << 30;
+
+/// A CopyData payload read back from a capture file
+#[derive(Debug)]
+pub struct CapturedMessage {
+    pub received_at: DateTime<Utc>,
+    pub data: Vec<u8>,
+}
+
+/// Appends CopyData payloads to a capture file
+pub struct CaptureWriter {
+    writer: CompressedWriter<BufWriter<File>>,
+    path: PathBuf,
+    messages: u64,
+}
+
+impl CaptureWriter {
+    /// Create the capture file, named with the extension of its compression
+    pub fn create(path: &Path, compression: Compression) -> Result<Self> {
+        let path = compression.file_path(path);
+        let file = File::create(&path).map_err(|e| {
+            ReplicationError::capture(format!(
+                "Failed to create capture file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let mut writer = CompressedWriter::new(BufWriter::new(file), compression);
+        writer.write_all(MAGIC)?;
+
+        Ok(Self {
+            writer,
+            path,
+            messages: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record one payload, stamped with the current time
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let received_at = Utc::now().timestamp_micros() as u64;
+        self.writer.write_all(&received_at.to_be_bytes())?;
+        self.writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.writer.write_all(data)?;
+        self.messages += 1;
+        Ok(())
+    }
+
+    pub fn messages(&self) -> u64 {
+        self.messages
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for CaptureWriter {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+/// Reads payloads back from a capture file in the order they were recorded
+pub struct CaptureReader {
+    reader: CompressedReader<BufReader<File>>,
+}
+
+impl CaptureReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).map_err(|e| {
+            ReplicationError::capture(format!(
+                "Failed to open capture file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let mut reader = CompressedReader::new(BufReader::new(file))?;
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).map_err(|_| {
+            ReplicationError::capture(format!("{} is not a capture file", path.display()))
+        })?;
+        if &magic != MAGIC {
+            return Err(ReplicationError::capture(format!(
+                "{} is not a capture file",
+                path.display()
+            )));
+        }
+
+        Ok(Self { reader })
+    }
+
+    /// The next recorded payload, or None at the end of the file
+    pub fn next_message(&mut self) -> Result<Option<CapturedMessage>> {
+        let mut header = [0u8; 12];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let received_at = u64::from_be_bytes(header[..8].try_into().unwrap());
+        let len = u32::from_be_bytes(header[8..].try_into().unwrap());
+
+        if len > MAX_MESSAGE_LEN {
+            return Err(ReplicationError::capture(format!(
+                "Capture file message of {} bytes exceeds the {} byte limit",
+                len, MAX_MESSAGE_LEN
+            )));
+        }
+
+        // Read rather than allocated up front, so a corrupt length cannot reserve memory the
+        // file does not back
+        let mut data = Vec::new();
+        (&mut self.reader).take(len as u64).read_to_end(&mut data)?;
+        if data.len() != len as usize {
+            return Err(ReplicationError::capture(
+                "Capture file ends in the middle of a message",
+            ));
+        }
+
+        Ok(Some(CapturedMessage {
+            received_at: DateTime::from_timestamp_micros(received_at as i64).unwrap_or_default(),
+            data,
+        }))
+    }
+}
@@ -0,0 +1,377 @@
+//! Dry-run validation of the stream configuration
+//! The `check` command builds the configuration `stream` would run with, connects the way it
+//! would and inspects the server, the publications and the slot, without creating the slot or
+//! starting to stream. Every problem found is reported rather than only the first.
+
+use crate::backend::BackendKind;
+use crate::cli::StreamArgs;
+use crate::errors::{ReplicationError, Result};
+use crate::publication::published_tables;
+use crate::quote::quote_literal;
+use crate::serverinfo::ServerInfo;
+use crate::slot::{check_usable, find_slot, SlotMode};
+use crate::types::ReplicationConfig;
+use crate::utils::PGConnection;
+use crate::walstream::unsupported_options;
+use tracing::{error, info, warn};
+
+/// Verdict of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    /// Streaming would start, but probably not as intended
+    Warning,
+    /// Streaming would fail
+    Problem,
+}
+
+/// One thing checked, such as the server or the slot
+#[derive(Debug, Clone)]
+pub struct CheckItem {
+    pub subject: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+/// Checks of one replication source
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    pub source: Option<String>,
+    pub items: Vec<CheckItem>,
+}
+
+impl CheckReport {
+    fn new(source: Option<String>) -> Self {
+        Self {
+            source,
+            items: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, subject: &'static str, status: CheckStatus, detail: impl Into<String>) {
+        self.items.push(CheckItem {
+            subject,
+            status,
+            detail: detail.into(),
+        });
+    }
+
+    fn ok(&mut self, subject: &'static str, detail: impl Into<String>) {
+        self.push(subject, CheckStatus::Ok, detail);
+    }
+
+    fn warning(&mut self, subject: &'static str, detail: impl Into<String>) {
+        self.push(subject, CheckStatus::Warning, detail);
+    }
+
+    fn problem(&mut self, subject: &'static str, detail: impl Into<String>) {
+        self.push(subject, CheckStatus::Problem, detail);
+    }
+
+    pub fn problems(&self) -> usize {
+        self.count(CheckStatus::Problem)
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.count(CheckStatus::Warning)
+    }
+
+    fn count(&self, status: CheckStatus) -> usize {
+        self.items
+            .iter()
+            .filter(|item| item.status == status)
+            .count()
+    }
+
+    pub fn log(&self) {
+        let source = self
+            .source
+            .as_deref()
+            .map(|source| format!("source {}: ", source))
+            .unwrap_or_default();
+        for item in &self.items {
+            match item.status {
+                CheckStatus::Ok => info!("CHECK: {}{}: ok: {}", source, item.subject, item.detail),
+                CheckStatus::Warning => {
+                    warn!(
+                        "CHECK: {}{}: warning: {}",
+                        source, item.subject, item.detail
+                    )
+                }
+                CheckStatus::Problem => {
+                    error!(
+                        "CHECK: {}{}: problem: {}",
+                        source, item.subject, item.detail
+                    )
+                }
+            }
+        }
+    }
+}
+
+/// Check every source `stream` would start with the same arguments
+pub fn check_stream(args: &StreamArgs, default: BackendKind) -> Vec<CheckReport> {
+    let kind = args.backend.unwrap_or(default);
+    let configs = if args.sources.is_empty() {
+        args.replication_config().map(|config| vec![config])
+    } else {
+        args.source_configs()
+    };
+    match configs {
+        Ok(configs) => configs
+            .iter()
+            .map(|config| check_source(config, kind))
+            .collect(),
+        Err(e) => {
+            let mut report = CheckReport::new(None);
+            report.problem("configuration", e.to_string());
+            vec![report]
+        }
+    }
+}
+
+/// Check one source's configuration against its server, publications and slot
+pub fn check_source(config: &ReplicationConfig, kind: BackendKind) -> CheckReport {
+    let mut report = CheckReport::new(config.source.clone());
+    let unsupported = match kind {
+        BackendKind::Walstream => unsupported_options(config),
+        BackendKind::Libpq => Vec::new(),
+    };
+    if unsupported.is_empty() {
+        report.ok("configuration", format!("valid for the {} backend", kind));
+    } else {
+        report.problem(
+            "configuration",
+            format!(
+                "not supported by the walstream backend, use --backend libpq: {}",
+                unsupported.join(", ")
+            ),
+        );
+    }
+
+    let connection = match PGConnection::connect(&config.connection_string) {
+        Ok(connection) => connection,
+        Err(e) => {
+            report.problem("connection", e.to_string());
+            return report;
+        }
+    };
+    if connection.ssl_in_use() {
+        report.ok(
+            "connection",
+            format!(
+                "connected, encrypted with {}",
+                connection
+                    .ssl_attribute("protocol")
+                    .as_deref()
+                    .unwrap_or("unknown protocol")
+            ),
+        );
+    } else {
+        report.ok("connection", "connected, TLS not in use");
+    }
+
+    check_server(&connection, config, &mut report);
+    check_publications(&connection, config, &mut report);
+    check_slot(&connection, config, &mut report);
+    report
+}
+
+/// IDENTIFY_SYSTEM, the pgoutput options the server accepts and wal_level
+fn check_server(connection: &PGConnection, config: &ReplicationConfig, report: &mut CheckReport) {
+    match ServerInfo::fetch(connection) {
+        Ok(info) if info.identity.dbname.is_none() => report.problem(
+            "server",
+            "IDENTIFY_SYSTEM reports no database, the connection string must include replication=database",
+        ),
+        Ok(info) => {
+            report.ok(
+                "server",
+                format!(
+                    "PostgreSQL {}, system {}, timeline {}, WAL at {}, database {}",
+                    info.version,
+                    info.identity.system_id,
+                    info.identity.timeline,
+                    info.identity.xlogpos,
+                    info.identity.dbname.as_deref().unwrap_or_default()
+                ),
+            );
+            match info.check_options(config) {
+                Ok(()) => report.ok(
+                    "options",
+                    format!(
+                        "protocol version {} is supported",
+                        config.protocol_version
+                    ),
+                ),
+                Err(e) => report.problem("options", e.to_string()),
+            }
+        }
+        Err(e) => report.problem("server", e.to_string()),
+    }
+
+    match show(connection, "wal_level") {
+        Ok(level) if level == "logical" => report.ok("wal_level", "logical"),
+        Ok(level) => report.problem(
+            "wal_level",
+            format!(
+                "{}, logical decoding needs wal_level = logical and a server restart",
+                level
+            ),
+        ),
+        Err(e) => report.warning("wal_level", e.to_string()),
+    }
+}
+
+/// The publications exist, and the tables they publish
+fn check_publications(
+    connection: &PGConnection,
+    config: &ReplicationConfig,
+    report: &mut CheckReport,
+) {
+    let names = &config.publication_names;
+    let query = format!(
+        "SELECT pubname FROM pg_publication WHERE pubname IN ({})",
+        names
+            .iter()
+            .map(|name| quote_literal(name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let existing = match connection.exec(&query) {
+        Ok(result) if result.is_ok() => (0..result.ntuples())
+            .filter_map(|row| result.getvalue(row, 0))
+            .collect::<Vec<_>>(),
+        Ok(result) => {
+            report.problem("publications", result.error_message());
+            return;
+        }
+        Err(e) => {
+            report.problem("publications", e.to_string());
+            return;
+        }
+    };
+    let missing: Vec<&str> = names
+        .iter()
+        .filter(|name| !existing.contains(name))
+        .map(String::as_str)
+        .collect();
+    if missing.is_empty() {
+        report.ok("publications", format!("found {}", names.join(", ")));
+    } else {
+        report.problem(
+            "publications",
+            format!("not found in the database: {}", missing.join(", ")),
+        );
+    }
+
+    let tables = match published_tables(connection, names) {
+        Ok(tables) => tables,
+        Err(e) => {
+            report.warning("tables", e.to_string());
+            return;
+        }
+    };
+    if tables.is_empty() {
+        report.warning("tables", "the publications include no tables");
+        return;
+    }
+    for table in &tables {
+        let mut detail = format!(
+            "{}.{} ({})",
+            table.schema,
+            table.table,
+            table.publications.join(", ")
+        );
+        let omitted = table.omitted_columns();
+        if !omitted.is_empty() {
+            detail.push_str(&format!(", not published: [{}]", omitted.join(", ")));
+        }
+        if let Some(row_filter) = table.row_filter() {
+            detail.push_str(&format!(", row filter {}", row_filter));
+        }
+        if config.table_filter.matches(&table.schema, &table.table) {
+            report.ok("table", detail);
+        } else {
+            report.ok("table", format!("{}, excluded by the table filter", detail));
+        }
+    }
+    if !tables
+        .iter()
+        .any(|table| config.table_filter.matches(&table.schema, &table.table))
+    {
+        report.warning(
+            "tables",
+            "the table filter excludes every published table, no changes would be shown",
+        );
+    }
+}
+
+/// The slot exists or can be created as the slot mode asks, with pgoutput and unused
+fn check_slot(connection: &PGConnection, config: &ReplicationConfig, report: &mut CheckReport) {
+    let name = &config.slot_name;
+    let slot = match find_slot(connection, name) {
+        Ok(slot) => slot,
+        Err(e) => {
+            report.problem("slot", e.to_string());
+            return;
+        }
+    };
+    let Some(slot) = slot else {
+        match config.slot_mode {
+            SlotMode::UseExisting => report.problem(
+                "slot",
+                format!(
+                    "{} does not exist and --slot-mode use-existing does not create it",
+                    name
+                ),
+            ),
+            _ => report.ok(
+                "slot",
+                format!("{} does not exist yet and would be created", name),
+            ),
+        }
+        return;
+    };
+
+    let position = slot
+        .confirmed_flush_lsn
+        .map(|lsn| format!(", confirmed up to {}", lsn))
+        .unwrap_or_default();
+    match config.slot_mode {
+        SlotMode::Temporary => report.problem(
+            "slot",
+            format!(
+                "{} already exists, a temporary slot cannot be created with its name",
+                name
+            ),
+        ),
+        SlotMode::Recreate => report.warning(
+            "slot",
+            format!(
+                "{} exists{} and would be dropped and created again, discarding its position",
+                name, position
+            ),
+        ),
+        SlotMode::Create | SlotMode::UseExisting => match check_usable(name, &slot) {
+            Ok(()) => report.ok(
+                "slot",
+                format!("{} exists with plugin pgoutput{}", name, position),
+            ),
+            Err(e) => report.problem("slot", e.to_string()),
+        },
+    }
+}
+
+/// Value of a server setting
+fn show(connection: &PGConnection, setting: &str) -> Result<String> {
+    let result = connection.exec(&format!("SHOW {}", setting))?;
+    if !result.is_ok() || result.ntuples() == 0 {
+        return Err(ReplicationError::protocol(format!(
+            "SHOW {} failed: {}",
+            setting,
+            result.error_message()
+        )));
+    }
+    Ok(result.getvalue(0, 0).unwrap_or_default())
+}
@@ -0,0 +1,95 @@
+//! File-based checkpoint store for resuming replication across restarts
+//! Persists the flushed and applied LSN of a slot as JSON, replaced atomically on every save
+
+use crate::errors::{ReplicationError, Result};
+use crate::types::Lsn;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Replication position recorded for a slot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub slot_name: String,
+    pub flushed_lsn: Lsn,
+    pub applied_lsn: Lsn,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Reads and writes the checkpoint file
+#[derive(Debug)]
+pub struct CheckpointStore {
+    path: PathBuf,
+    last_saved: Option<(Lsn, Lsn)>,
+}
+
+impl CheckpointStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            last_saved: None,
+        }
+    }
+
+    /// Load the checkpoint, returning None if the file does not exist yet
+    pub fn load(&self) -> Result<Option<Checkpoint>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&self.path)?;
+        let checkpoint = serde_json::from_str(&contents).map_err(|e| {
+            ReplicationError::checkpoint(format!(
+                "Failed to parse checkpoint file {}: {}",
+                self.path.display(),
+                e
+            ))
+        })?;
+
+        Ok(Some(checkpoint))
+    }
+
+    /// Persist the position if it changed since the last save
+    pub fn save(&mut self, slot_name: &str, flushed_lsn: Lsn, applied_lsn: Lsn) -> Result<()> {
+        if self.last_saved == Some((flushed_lsn, applied_lsn)) {
+            return Ok(());
+        }
+
+        let checkpoint = Checkpoint {
+            slot_name: slot_name.to_string(),
+            flushed_lsn,
+            applied_lsn,
+            updated_at: Utc::now(),
+        };
+        let contents = serde_json::to_string_pretty(&checkpoint).map_err(|e| {
+            ReplicationError::checkpoint(format!("Failed to serialize checkpoint: {}", e))
+        })?;
+
+        // Write to a temporary file first so a crash never leaves a truncated checkpoint, and
+        // sync it so the renamed file holds the position once save returns
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        // The rename is only durable once the directory entry is
+        let directory = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::File::open(directory)
+            .and_then(|directory| directory.sync_all())
+            .map_err(|e| {
+                ReplicationError::checkpoint(format!(
+                    "Failed to sync checkpoint directory {}: {}",
+                    directory.display(),
+                    e
+                ))
+            })?;
+
+        self.last_saved = Some((flushed_lsn, applied_lsn));
+        Ok(())
+    }
+}
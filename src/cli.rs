@@ -0,0 +1,1384 @@
+//! Command line interface for the replication checker binaries
+//! Every flag falls back to the environment variable that configured it before the CLI existed
+
+use crate::alert::{AlertAction, AlertConfig};
+use crate::assembler::AssemblerConfig;
+use crate::avro::AvroConfig;
+use crate::backend::BackendKind;
+use crate::compress::Compression;
+use crate::config::{ConfigFile, SourceConfig};
+use crate::conninfo::ConnectionParams;
+use crate::datetime::DisplayTimeZone;
+use crate::envelope::EventTimestamps;
+use crate::filter::TableFilter;
+use crate::initsync::InitSyncConfig;
+use crate::mask::{ColumnMask, MaskMode};
+use crate::output::{EventSchemaVersion, OutputConfig, OutputFormat};
+#[cfg(feature = "parquet")]
+use crate::parquet::ParquetConfig;
+use crate::physical::PhysicalConfig;
+use crate::reconnect::ReconnectPolicy;
+use crate::rowfilter::{RowFilter, RowPredicate};
+#[cfg(feature = "s3")]
+use crate::s3::{ObjectFormat, S3Config};
+use crate::sample::{SampleRule, Sampling};
+use crate::sink::SinkConfig;
+use crate::slot::SlotMode;
+use crate::stop::{parse_stop_time, StopConditions};
+use crate::throttle::RateLimits;
+use crate::tls::TlsConfig;
+use crate::txlimit::{LimitAction, TransactionLimits};
+use crate::types::{FeedbackMode, Lsn, OriginFilter, ReplicationConfig, UpdateDisplayMode};
+use crate::validate::ValidationMode;
+use crate::verify::VerifyConfig;
+use crate::walkeep::WalKeepConfig;
+use crate::watch::parse_duration;
+use crate::webhook::WebhookConfig;
+use chrono::{DateTime, Utc};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// PostgreSQL logical replication checker
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+pub struct Cli {
+    /// TOML config file supplying defaults for any flag and the [logging] settings
+    #[arg(long = "config", env = "CONFIG_FILE", global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Arguments for the default `stream` command
+    #[command(flatten)]
+    pub stream: StreamArgs,
+}
+
+impl Cli {
+    /// Parse the command line, with settings from the config file as defaults
+    pub fn parse_with_config(config: &ConfigFile) -> anyhow::Result<Self> {
+        let matches = config.apply_defaults(Self::command())?.get_matches();
+        let cli = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        Ok(cli.with_sources(config))
+    }
+
+    /// Parse the command line like `parse_with_config`, returning an error instead of exiting
+    pub fn try_parse_with_config(config: &ConfigFile) -> anyhow::Result<Self> {
+        let matches = config.apply_defaults(Self::command())?.try_get_matches()?;
+        Ok(Self::from_arg_matches(&matches)?.with_sources(config))
+    }
+
+    /// Attach the config file's replication sources and event sinks to the stream arguments
+    fn with_sources(mut self, config: &ConfigFile) -> Self {
+        self.stream.sources = config.sources().to_vec();
+        self.stream.sinks = config.sinks().to_vec();
+        match self.command.as_mut() {
+            Some(Command::Stream(args) | Command::Check(args)) => {
+                args.sources = config.sources().to_vec();
+                args.sinks = config.sinks().to_vec();
+            }
+            Some(Command::ReplayDlq(args)) => args.sinks = config.sinks().to_vec(),
+            _ => {}
+        }
+        self
+    }
+
+    /// The command to run, defaulting to `stream` when no subcommand is given
+    pub fn into_command(self) -> Command {
+        self.command.unwrap_or(Command::Stream(self.stream))
+    }
+}
+
+// Parsed once at startup, so boxing the stream arguments would buy nothing
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Stream and display changes from the replication slot (default)
+    Stream(StreamArgs),
+    /// Create the logical replication slot and exit
+    CreateSlot(SlotArgs),
+    /// Drop the replication slot and exit
+    DropSlot(SlotArgs),
+    /// Move the replication slot forward to an LSN, releasing the WAL it retains before it
+    AdvanceSlot(AdvanceSlotArgs),
+    /// Decode and display a capture file written with --record, without connecting
+    Replay(ReplayArgs),
+    /// Send the events of a dead-letter file to their sinks again, keeping those that still fail
+    ReplayDlq(ReplayDlqArgs),
+    /// Show slot positions, retained WAL and the streaming backend, without starting a stream
+    Status(StatusArgs),
+    /// Validate the configuration, connection, publications and slot without streaming, failing
+    /// when any problem is found
+    Check(StreamArgs),
+    /// Compare tables between the publisher and a subscriber by ranges of their primary key
+    Verify(VerifyArgs),
+    /// Stream WAL over physical replication and report its throughput and timeline switches
+    Physical(PhysicalArgs),
+}
+
+/// Connection and slot selection shared by all commands
+#[derive(Debug, Clone, Args)]
+pub struct SlotArgs {
+    /// PostgreSQL connection string, must include replication=database
+    #[arg(
+        long = "connection",
+        env = "DB_CONNECTION_STRING",
+        hide_env_values = true
+    )]
+    pub connection_string: String,
+
+    /// Name of the replication slot
+    #[arg(long = "slot", env = "slot_name", default_value = "sub")]
+    pub slot_name: String,
+
+    /// TLS mode appended to the connection string: disable, allow, prefer, require, verify-ca or verify-full
+    #[arg(
+        long,
+        env = "SSL_MODE",
+        value_parser = ["disable", "allow", "prefer", "require", "verify-ca", "verify-full"]
+    )]
+    pub sslmode: Option<String>,
+
+    /// CA certificate file the server certificate is verified against
+    #[arg(long, env = "SSL_ROOT_CERT")]
+    pub sslrootcert: Option<PathBuf>,
+
+    /// Client certificate file, requires --sslkey
+    #[arg(long, env = "SSL_CERT")]
+    pub sslcert: Option<PathBuf>,
+
+    /// Private key file of the client certificate, requires --sslcert
+    #[arg(long, env = "SSL_KEY")]
+    pub sslkey: Option<PathBuf>,
+
+    /// Seconds to wait for the connection to be established, 0 to wait indefinitely
+    #[arg(long, env = "CONNECT_TIMEOUT_SECS")]
+    pub connect_timeout: Option<u64>,
+
+    /// Whether TCP keepalives are sent on the connection (libpq's default is on)
+    #[arg(long, env = "TCP_KEEPALIVES", value_parser = clap::builder::BoolishValueParser::new())]
+    pub keepalives: Option<bool>,
+
+    /// Seconds of inactivity before a TCP keepalive is sent
+    #[arg(long, env = "TCP_KEEPALIVES_IDLE_SECS")]
+    pub keepalives_idle: Option<u32>,
+
+    /// Seconds between unanswered TCP keepalives
+    #[arg(long, env = "TCP_KEEPALIVES_INTERVAL_SECS")]
+    pub keepalives_interval: Option<u32>,
+
+    /// Unanswered TCP keepalives before the connection is considered dead
+    #[arg(long, env = "TCP_KEEPALIVES_COUNT")]
+    pub keepalives_count: Option<u32>,
+
+    /// Name shown for the connection in pg_stat_activity and pg_stat_replication
+    #[arg(long, env = "APPLICATION_NAME")]
+    pub application_name: Option<String>,
+
+    /// Milliseconds a statement may run before the server cancels it, 0 for no limit
+    #[arg(long = "statement-timeout-ms", env = "STATEMENT_TIMEOUT_MS")]
+    pub statement_timeout_ms: Option<u64>,
+}
+
+impl SlotArgs {
+    /// TLS options requested on the command line
+    pub fn tls_config(&self) -> TlsConfig {
+        TlsConfig {
+            sslmode: self.sslmode.clone(),
+            sslrootcert: self.sslrootcert.clone(),
+            sslcert: self.sslcert.clone(),
+            sslkey: self.sslkey.clone(),
+        }
+    }
+
+    /// Timeouts, keepalives and application name requested on the command line
+    pub fn connection_params(&self) -> ConnectionParams {
+        ConnectionParams {
+            connect_timeout: self.connect_timeout,
+            keepalives: self.keepalives,
+            keepalives_idle: self.keepalives_idle,
+            keepalives_interval: self.keepalives_interval,
+            keepalives_count: self.keepalives_count,
+            application_name: self.application_name.clone(),
+            statement_timeout_ms: self.statement_timeout_ms,
+        }
+    }
+
+    /// The connection string with the TLS and connection options appended
+    pub fn conninfo(&self) -> crate::errors::Result<String> {
+        self.apply(&self.connection_string)
+    }
+
+    /// Append the TLS and connection options to another connection string of the same server
+    /// or source
+    pub fn apply(&self, conninfo: &str) -> crate::errors::Result<String> {
+        self.connection_params()
+            .apply(&self.tls_config().apply(conninfo)?)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct AdvanceSlotArgs {
+    #[command(flatten)]
+    pub slot: SlotArgs,
+
+    /// LSN (X/Y) to advance the slot to; changes before it are never streamed from the slot
+    #[arg(long = "to", env = "ADVANCE_TO_LSN", value_parser = Lsn::from_str)]
+    pub lsn: Lsn,
+
+    /// Only report how far the slot would move and how much WAL that would release
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct StatusArgs {
+    #[command(flatten)]
+    pub slot: SlotArgs,
+
+    /// Show every replication slot on the server instead of only --slot
+    #[arg(long)]
+    pub all_slots: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// Connection to the publisher; --slot is not used
+    #[command(flatten)]
+    pub source: SlotArgs,
+
+    /// Connection string of the subscriber
+    #[arg(
+        long = "target-connection",
+        env = "TARGET_DB_CONNECTION_STRING",
+        hide_env_values = true
+    )]
+    pub target_connection_string: String,
+
+    /// Tables to compare, as schema.table or table in the public schema
+    #[arg(
+        long = "table",
+        env = "VERIFY_TABLES",
+        value_delimiter = ',',
+        required = true
+    )]
+    pub tables: Vec<String>,
+
+    /// Rows of the publisher compared per range of primary key values
+    #[arg(long, env = "VERIFY_CHUNK_ROWS", default_value_t = 10000)]
+    pub chunk_rows: u64,
+
+    /// List the rows that differ in ranges that do not match
+    #[arg(long, env = "VERIFY_ROW_DIFF", value_parser = clap::builder::BoolishValueParser::new())]
+    pub row_diff: bool,
+
+    /// Most differing rows listed per table
+    #[arg(long, env = "VERIFY_MAX_ROW_DIFFS", default_value_t = 100)]
+    pub max_row_diffs: usize,
+}
+
+impl VerifyArgs {
+    pub fn verify_config(&self) -> VerifyConfig {
+        VerifyConfig {
+            tables: self.tables.clone(),
+            chunk_rows: self.chunk_rows,
+            row_diff: self.row_diff,
+            max_row_diffs: self.max_row_diffs,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct PhysicalArgs {
+    /// Connection to the server; --slot is not used and replication=database is overridden
+    #[command(flatten)]
+    pub connection: SlotArgs,
+
+    /// Physical replication slot to stream from, which then retains WAL this stream has not
+    /// received; none by default
+    #[arg(long, env = "PHYSICAL_SLOT")]
+    pub physical_slot: Option<String>,
+
+    /// Start at this LSN (X/Y) instead of the server's current end of WAL
+    #[arg(long, env = "START_LSN", value_parser = Lsn::from_str)]
+    pub start_lsn: Option<Lsn>,
+
+    /// Seconds between WAL throughput reports
+    #[arg(
+        long = "report-interval",
+        env = "PHYSICAL_REPORT_INTERVAL_SECS",
+        default_value_t = 10
+    )]
+    pub report_interval_secs: u64,
+
+    /// Seconds between standby status updates sent to the server
+    #[arg(
+        long = "status-interval",
+        env = "STATUS_INTERVAL_SECS",
+        default_value_t = 10
+    )]
+    pub status_interval_secs: u64,
+
+    /// Send hot-standby feedback with the server's oldest running xmin, holding back vacuum
+    /// like a standby running queries
+    #[arg(
+        long,
+        env = "HOT_STANDBY_FEEDBACK",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    pub hot_standby_feedback: bool,
+
+    /// Stream for a bounded duration (e.g. 30s, 5m, 1h), otherwise until Ctrl+C
+    #[arg(long = "for", env = "WATCH_DURATION", value_parser = parse_duration)]
+    pub duration: Option<Duration>,
+}
+
+impl PhysicalArgs {
+    pub fn physical_config(&self) -> crate::errors::Result<PhysicalConfig> {
+        Ok(PhysicalConfig {
+            connection_string: self.connection.conninfo()?,
+            slot_name: self.physical_slot.clone(),
+            start_lsn: self.start_lsn,
+            report_interval: Duration::from_secs(self.report_interval_secs.max(1)),
+            status_interval: Duration::from_secs(self.status_interval_secs.max(1)),
+            hot_standby_feedback: self.hot_standby_feedback,
+            duration: self.duration,
+        })
+    }
+}
+
+/// How decoded changes are displayed and emitted, shared by `stream` and `replay`
+#[derive(Debug, Args)]
+pub struct DisplayArgs {
+    /// How UPDATE events are printed: the old and new rows, or only key and changed columns
+    #[arg(
+        long,
+        env = "UPDATE_DISPLAY",
+        default_value = "diff",
+        value_parser = ["full", "changed", "diff"]
+    )]
+    pub update_display: String,
+
+    /// Print date and time values in ISO form whatever the server's DateStyle, with timestamptz
+    /// in this zone: utc, local or an offset such as +02:00 (libpq backend)
+    #[arg(long, env = "DISPLAY_TIMEZONE")]
+    pub display_timezone: Option<String>,
+
+    /// Format for change events: log (human-readable) or json (NDJSON)
+    #[arg(
+        long = "output",
+        env = "OUTPUT_FORMAT",
+        default_value = "log",
+        value_parser = OutputFormat::from_str
+    )]
+    pub output_format: OutputFormat,
+
+    /// File to append JSON events to instead of stdout
+    #[arg(long, env = "OUTPUT_FILE")]
+    pub output_file: Option<PathBuf>,
+
+    /// Compress the output file, the --record capture file and init-sync CSV files: none, gzip or zstd; the codec's extension is added to their names
+    #[arg(
+        long,
+        env = "OUTPUT_COMPRESSION",
+        default_value = "none",
+        value_parser = Compression::from_str
+    )]
+    pub compression: Compression,
+
+    /// Layout of JSON events: 2 (latest) adds schema_version to every event, 1 is the earlier layout without it
+    #[arg(
+        long,
+        env = "EVENT_SCHEMA_VERSION",
+        default_value = "2",
+        value_parser = EventSchemaVersion::from_str
+    )]
+    pub event_schema_version: EventSchemaVersion,
+
+    /// Only emit changes for tables matching these globs (schema.table, or table in any schema)
+    #[arg(long = "include-table", env = "INCLUDE_TABLES", value_delimiter = ',')]
+    pub include_tables: Vec<String>,
+
+    /// Never emit changes for tables matching these globs
+    #[arg(long = "exclude-table", env = "EXCLUDE_TABLES", value_delimiter = ',')]
+    pub exclude_tables: Vec<String>,
+
+    /// Only emit rows matching these predicates, e.g. "orders.status = 'paid'"; separate several with ';'
+    #[arg(
+        long = "row-filter",
+        env = "ROW_FILTERS",
+        value_delimiter = ';',
+        value_parser = RowPredicate::from_str
+    )]
+    pub row_filters: Vec<RowPredicate>,
+
+    /// Only emit a share of the changes of tables matching a glob, as table=1/N or table=P%
+    /// (e.g. "orders=1/100,events=5%"); changes are still counted in the summary and metrics
+    #[arg(
+        long = "sample",
+        env = "SAMPLE_TABLES",
+        value_delimiter = ',',
+        value_parser = SampleRule::from_str
+    )]
+    pub sample_rules: Vec<SampleRule>,
+
+    /// Mask values of columns matching these globs (schema.table.column, table.column or column)
+    #[arg(long = "mask-column", env = "MASK_COLUMNS", value_delimiter = ',')]
+    pub mask_columns: Vec<String>,
+
+    /// How masked values are shown: redact (***) or hash
+    #[arg(
+        long,
+        env = "MASK_MODE",
+        default_value = "redact",
+        value_parser = MaskMode::from_str
+    )]
+    pub mask_mode: MaskMode,
+
+    /// Log each CopyData message as a hexdump with offsets and ASCII before it is parsed, at trace level (libpq backend)
+    #[arg(long, env = "DUMP_RAW", value_parser = clap::builder::BoolishValueParser::new())]
+    pub dump_raw: bool,
+}
+
+impl DisplayArgs {
+    /// Structured output destination requested on the command line
+    pub fn output_config(&self) -> OutputConfig {
+        OutputConfig {
+            format: self.output_format,
+            path: self.output_file.clone(),
+            compression: self.compression,
+        }
+    }
+
+    /// Table filter requested on the command line
+    pub fn table_filter(&self) -> TableFilter {
+        TableFilter::new(self.include_tables.clone(), self.exclude_tables.clone())
+    }
+
+    /// Row filters requested on the command line
+    pub fn row_filter(&self) -> RowFilter {
+        RowFilter::new(self.row_filters.clone())
+    }
+
+    /// Table sampling requested on the command line
+    pub fn sampling(&self) -> Sampling {
+        Sampling::new(self.sample_rules.clone())
+    }
+
+    /// Column masking requested on the command line
+    pub fn column_mask(&self) -> ColumnMask {
+        ColumnMask::new(self.mask_columns.clone(), self.mask_mode)
+    }
+
+    /// Copy the display, output and filter options into the configuration
+    pub fn apply_to(&self, config: &mut ReplicationConfig) -> crate::errors::Result<()> {
+        config.update_display = UpdateDisplayMode::from_str(&self.update_display)?;
+        config.display_timezone = self
+            .display_timezone
+            .as_deref()
+            .map(DisplayTimeZone::from_str)
+            .transpose()?;
+        config.output = self.output_config();
+        config.event_schema = self.event_schema_version;
+        config.table_filter = self.table_filter();
+        config.row_filter = self.row_filter();
+        config.sampling = self.sampling();
+        config.column_mask = self.column_mask();
+        config.dump_raw = self.dump_raw;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    /// Capture file written by `stream --record`
+    pub file: PathBuf,
+
+    #[command(flatten)]
+    pub display: DisplayArgs,
+
+    /// Stop after this many INSERT/UPDATE/DELETE/TRUNCATE events
+    #[arg(long)]
+    pub max_events: Option<u64>,
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayDlqArgs {
+    /// Dead-letter file written by a sink with on-error = "dead-letter"
+    pub file: PathBuf,
+
+    /// Send every event to this [[sink]] instead of the sink it failed on
+    #[arg(long)]
+    pub sink: Option<String>,
+
+    /// Event sinks from the `[[sink]]` tables of the config file
+    #[arg(skip)]
+    pub sinks: Vec<SinkConfig>,
+}
+
+#[derive(Debug, Args)]
+pub struct StreamArgs {
+    #[command(flatten)]
+    pub slot: SlotArgs,
+
+    /// Publications to subscribe to, comma-separated or repeated
+    #[arg(
+        long = "publication",
+        env = "pub_name",
+        default_value = "pub",
+        value_delimiter = ','
+    )]
+    pub publication_names: Vec<String>,
+
+    /// Streaming implementation: walstream (pg-walstream) or libpq (in-tree decoder); defaults to the binary's own
+    #[arg(long, env = "REPLICATION_BACKEND", value_parser = BackendKind::from_str)]
+    pub backend: Option<BackendKind>,
+
+    /// How the slot is obtained: create (reusing a compatible slot), use-existing, recreate or temporary (temporary needs the libpq backend)
+    #[arg(
+        long,
+        env = "SLOT_MODE",
+        default_value = "create",
+        value_parser = ["create", "use-existing", "recreate", "temporary"]
+    )]
+    pub slot_mode: String,
+
+    /// Stream from a temporary slot the server drops with the connection, created again on
+    /// reconnect; the same as --slot-mode temporary (libpq backend)
+    #[arg(
+        long,
+        env = "TEMPORARY_SLOT",
+        conflicts_with = "slot_mode",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    pub temporary_slot: bool,
+
+    /// Drop the replication slot when streaming stops gracefully (Ctrl+C or a stop condition)
+    #[arg(long, env = "DROP_SLOT_ON_EXIT", value_parser = clap::builder::BoolishValueParser::new())]
+    pub drop_slot_on_exit: bool,
+
+    /// Logical replication protocol version (1-4)
+    #[arg(
+        long,
+        env = "PROTOCOL_VERSION",
+        default_value_t = 2,
+        value_parser = clap::value_parser!(u32).range(1..=4)
+    )]
+    pub protocol_version: u32,
+
+    /// Disable streaming of large in-progress transactions
+    #[arg(long, env = "NO_STREAMING", value_parser = clap::builder::BoolishValueParser::new())]
+    pub no_streaming: bool,
+
+    /// Stream in-progress transactions for parallel apply, needs protocol version 4 (libpq backend)
+    #[arg(
+        long,
+        env = "PARALLEL_STREAMING",
+        conflicts_with = "no_streaming",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    pub parallel_streaming: bool,
+
+    /// Receive column values in binary format instead of text, PostgreSQL 14+ (libpq backend)
+    #[arg(long, env = "BINARY_FORMAT", value_parser = clap::builder::BoolishValueParser::new())]
+    pub binary: bool,
+
+    /// Stream changes from any origin, or only local ones and not those replicated from another
+    /// server, sent to PostgreSQL 16+ and applied locally as well (libpq backend for none)
+    #[arg(long, env = "ORIGIN", default_value = "any", value_parser = ["any", "none"])]
+    pub origin: String,
+
+    /// Seconds between standby status updates sent to the server
+    #[arg(long = "feedback-interval", env = "FEEDBACK_INTERVAL_SECS")]
+    pub feedback_interval_secs: Option<u64>,
+
+    /// Maximum seconds between standby status updates even when nothing advanced (libpq backend)
+    #[arg(
+        long = "status-interval",
+        env = "STATUS_INTERVAL_SECS",
+        default_value_t = 10
+    )]
+    pub status_interval_secs: u64,
+
+    /// Seconds without any data from the server before the connection is closed and re-established, 0 to wait indefinitely (libpq backend)
+    #[arg(
+        long = "receiver-timeout",
+        env = "RECEIVER_TIMEOUT_SECS",
+        default_value_t = 60
+    )]
+    pub receiver_timeout_secs: u64,
+
+    /// When status updates are sent: periodic, or on-request to only answer keepalives asking for a reply (libpq backend)
+    #[arg(
+        long,
+        env = "FEEDBACK_MODE",
+        default_value = "periodic",
+        value_parser = ["periodic", "on-request"]
+    )]
+    pub feedback_mode: String,
+
+    /// Seconds between catch-up progress reports (libpq backend)
+    #[arg(
+        long = "progress-interval",
+        env = "PROGRESS_INTERVAL_SECS",
+        default_value_t = 5
+    )]
+    pub progress_interval_secs: u64,
+
+    /// Seconds between replication lag summaries and per-table delay percentiles, 0 to disable;
+    /// the walstream backend only reports the delays
+    #[arg(
+        long = "stats-interval",
+        env = "STATS_INTERVAL_SECS",
+        default_value_t = 30
+    )]
+    pub stats_interval_secs: u64,
+
+    /// Seconds between reports of this subscriber's pg_stat_subscription fields, 0 to disable (libpq backend)
+    #[arg(
+        long = "subscription-stats-interval",
+        env = "SUBSCRIPTION_STATS_INTERVAL_SECS",
+        default_value_t = 0
+    )]
+    pub subscription_stats_interval_secs: u64,
+
+    /// Reconnect attempts after the connection drops before giving up, 0 to disable
+    #[arg(long = "max-reconnects", env = "MAX_RECONNECTS", default_value_t = 5)]
+    pub max_reconnects: u32,
+
+    /// Seconds before the first reconnect attempt, doubled after every failed attempt
+    #[arg(
+        long = "reconnect-backoff",
+        env = "RECONNECT_BACKOFF_SECS",
+        default_value_t = 1
+    )]
+    pub reconnect_backoff_secs: u64,
+
+    /// Maximum seconds between reconnect attempts
+    #[arg(
+        long = "max-reconnect-backoff",
+        env = "MAX_RECONNECT_BACKOFF_SECS",
+        default_value_t = 60
+    )]
+    pub max_reconnect_backoff_secs: u64,
+
+    /// Fraction of the backoff randomly added or removed, between 0 and 1
+    #[arg(
+        long = "reconnect-jitter",
+        env = "RECONNECT_JITTER",
+        default_value_t = 0.2
+    )]
+    pub reconnect_jitter: f64,
+
+    /// Most relations kept in the relation cache, least recently used are evicted (libpq backend)
+    #[arg(long, env = "RELATION_CACHE_SIZE")]
+    pub relation_cache_size: Option<usize>,
+
+    /// Rows per table remembered to fill in unchanged TOAST values of UPDATEs (libpq backend)
+    #[arg(long, env = "TOAST_CACHE_ROWS")]
+    pub toast_cache_rows: Option<usize>,
+
+    /// Most row changes emitted per transaction, larger transactions are handled by --transaction-limit-action (libpq backend)
+    #[arg(long, env = "MAX_TRANSACTION_ROWS")]
+    pub max_transaction_rows: Option<u64>,
+
+    /// Most bytes of changes emitted per transaction, larger transactions are handled by --transaction-limit-action (libpq backend)
+    #[arg(long, env = "MAX_TRANSACTION_BYTES")]
+    pub max_transaction_bytes: Option<u64>,
+
+    /// What happens to a transaction over its limit: truncate with a summary line, skip the remaining rows, or abort with an error
+    #[arg(
+        long,
+        env = "TRANSACTION_LIMIT_ACTION",
+        default_value = "truncate",
+        value_parser = ["truncate", "skip", "abort"]
+    )]
+    pub transaction_limit_action: String,
+
+    /// Keep at most this many bytes of each column value, marking longer values as truncated
+    #[arg(long, env = "MAX_COLUMN_BYTES")]
+    pub max_column_bytes: Option<usize>,
+
+    /// Buffer each transaction and emit its changes together once it commits (libpq backend)
+    #[arg(
+        long,
+        env = "BUFFER_TRANSACTIONS",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    pub buffer_transactions: bool,
+
+    /// Megabytes of buffered transactions kept in memory before the largest is spilled to disk
+    #[arg(
+        long = "buffer-memory-limit",
+        env = "BUFFER_MEMORY_LIMIT_MB",
+        default_value_t = 64
+    )]
+    pub buffer_memory_limit_mb: usize,
+
+    /// Megabytes one buffered transaction may hold in memory before it is spilled to disk,
+    /// however little the others hold (default: only --buffer-memory-limit applies)
+    #[arg(long = "buffer-transaction-limit", env = "BUFFER_TRANSACTION_LIMIT_MB")]
+    pub buffer_transaction_limit_mb: Option<usize>,
+
+    /// Directory buffered transactions are spilled to (default: the system temp directory)
+    #[arg(long = "spill-dir", env = "SPILL_DIRECTORY")]
+    pub spill_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub display: DisplayArgs,
+
+    /// CopyData messages queued between receiving and processing; 0 does both in turn (libpq backend)
+    #[arg(long, env = "RECEIVE_QUEUE_CAPACITY", default_value_t = 1024)]
+    pub receive_queue_capacity: usize,
+
+    /// Most change events processed per second; the connection is still serviced while throttled (libpq backend)
+    #[arg(
+        long,
+        env = "MAX_EVENTS_PER_SEC",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_events_per_sec: Option<u64>,
+
+    /// Most bytes of received messages processed per second (libpq backend)
+    #[arg(
+        long,
+        env = "MAX_BYTES_PER_SEC",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_bytes_per_sec: Option<u64>,
+
+    /// Open a second, regular connection for catalog lookups and slot status while streaming (libpq backend)
+    #[arg(
+        long,
+        env = "CONTROL_CONNECTION",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    pub control_connection: bool,
+
+    /// Seconds between checks of the WAL the slot retains against max_slot_wal_keep_size over the control connection, 0 disables them
+    #[arg(
+        long = "wal-keep-check-secs",
+        env = "WAL_KEEP_CHECK_SECS",
+        default_value_t = 60
+    )]
+    pub wal_keep_check_secs: u64,
+
+    /// Warn while the slot retains this percentage of max_slot_wal_keep_size or more
+    #[arg(
+        long = "wal-keep-warn-percent",
+        env = "WAL_KEEP_WARN_PERCENT",
+        default_value_t = 80.0
+    )]
+    pub wal_keep_warn_percent: f64,
+
+    /// Apply changes to this database as well as displaying them (libpq backend)
+    #[arg(
+        long = "target-connection",
+        env = "TARGET_DB_CONNECTION_STRING",
+        hide_env_values = true
+    )]
+    pub target_connection_string: Option<String>,
+
+    /// File recording the applied LSN, used to resume after a restart (libpq backend)
+    #[arg(long, env = "CHECKPOINT_FILE")]
+    pub checkpoint_file: Option<PathBuf>,
+
+    /// File recording recent commits, so transactions delivered again after a reconnect or
+    /// restart are verified against their first delivery (libpq backend)
+    #[arg(long, env = "COMMIT_LOG_FILE")]
+    pub commit_log: Option<PathBuf>,
+
+    /// Commits kept in the commit log
+    #[arg(long, env = "COMMIT_LOG_WINDOW", default_value_t = 1000)]
+    pub commit_log_window: usize,
+
+    /// Check stream invariants such as Begin/Commit pairing: off, warn or strict (libpq backend)
+    #[arg(
+        long,
+        env = "VALIDATE_STREAM",
+        default_value = "off",
+        value_parser = ["off", "warn", "strict"]
+    )]
+    pub validate: String,
+
+    /// Attach a deterministic ID to every change event so consumers can drop redelivered events
+    #[arg(long, env = "EVENT_IDS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub event_ids: bool,
+
+    /// Attach the transaction's xid, commit LSN and time, the slot and the change's position in
+    /// the transaction to every change event
+    #[arg(
+        long,
+        env = "TRANSACTION_ENVELOPE",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    pub transaction_envelope: bool,
+
+    /// Extra timestamps in the transaction envelope: a comma-separated list of receive, process
+    /// and clock-skew, or none; setting any turns the envelope on
+    #[arg(
+        long,
+        env = "EVENT_TIMESTAMPS",
+        default_value = "none",
+        value_parser = EventTimestamps::from_str
+    )]
+    pub event_timestamps: EventTimestamps,
+
+    /// POST batches of change events as JSON to this http:// or https:// URL
+    #[arg(long, env = "WEBHOOK_URL")]
+    pub webhook_url: Option<String>,
+
+    /// Change events per webhook request
+    #[arg(long, env = "WEBHOOK_BATCH_SIZE", default_value_t = 100)]
+    pub webhook_batch_size: usize,
+
+    /// Milliseconds an event may wait before a partial batch is sent to the webhook
+    #[arg(
+        long = "webhook-flush-interval",
+        env = "WEBHOOK_FLUSH_INTERVAL_MS",
+        default_value_t = 1000
+    )]
+    pub webhook_flush_interval_ms: u64,
+
+    /// Retries of a failed webhook request before the run stops
+    #[arg(long, env = "WEBHOOK_MAX_RETRIES", default_value_t = 5)]
+    pub webhook_max_retries: u32,
+
+    /// Write change events as Parquet files partitioned by table and date under this directory
+    #[arg(long = "parquet-dir", env = "PARQUET_DIRECTORY")]
+    pub parquet_dir: Option<PathBuf>,
+
+    /// Rows buffered per table before they are written to its Parquet file as a row group
+    #[arg(long, env = "PARQUET_ROW_GROUP_SIZE", default_value_t = 10000)]
+    pub parquet_row_group_size: usize,
+
+    /// Write change events as Avro messages in the schema registry wire format under this directory
+    #[arg(
+        long = "avro-dir",
+        env = "AVRO_DIRECTORY",
+        requires = "schema_registry_url"
+    )]
+    pub avro_dir: Option<PathBuf>,
+
+    /// http:// URL of the Confluent-compatible schema registry the Avro schemas are registered with
+    #[arg(long, env = "SCHEMA_REGISTRY_URL", requires = "avro_dir")]
+    pub schema_registry_url: Option<String>,
+
+    /// Upload batches of change events to this bucket of S3-compatible storage
+    #[arg(long, env = "S3_BUCKET", requires = "s3_endpoint")]
+    pub s3_bucket: Option<String>,
+
+    /// https:// or http:// URL of the S3-compatible service, e.g. https://s3.us-east-1.amazonaws.com
+    /// or http://minio:9000
+    #[arg(long, env = "S3_ENDPOINT", requires = "s3_bucket")]
+    pub s3_endpoint: Option<String>,
+
+    /// Key prefix of the uploaded objects
+    #[arg(long, env = "S3_PREFIX", default_value = "")]
+    pub s3_prefix: String,
+
+    /// Region the S3 requests are signed for
+    #[arg(long, env = "S3_REGION", default_value = "us-east-1")]
+    pub s3_region: String,
+
+    /// Access key ID of the S3 credentials
+    #[arg(long, env = "AWS_ACCESS_KEY_ID", default_value = "")]
+    pub s3_access_key_id: String,
+
+    /// Secret access key of the S3 credentials
+    #[arg(
+        long,
+        env = "AWS_SECRET_ACCESS_KEY",
+        default_value = "",
+        hide_env_values = true
+    )]
+    pub s3_secret_access_key: String,
+
+    /// Format of the uploaded files: newline-delimited JSON, or Parquet partitioned by table and date
+    #[arg(long, env = "S3_FORMAT", default_value = "json", value_parser = ["json", "parquet"])]
+    pub s3_format: String,
+
+    /// Compress JSON objects: none, gzip or zstd; keys end in .jsonl.gz or .jsonl.zst
+    #[arg(
+        long,
+        env = "S3_COMPRESSION",
+        default_value = "none",
+        value_parser = Compression::from_str
+    )]
+    pub s3_compression: Compression,
+
+    /// Directory batches are written to before they are uploaded (default: the system temp directory)
+    #[arg(long, env = "S3_STAGING_DIRECTORY")]
+    pub s3_staging_dir: Option<PathBuf>,
+
+    /// Upload the batch once its change events reach this many megabytes as JSON
+    #[arg(long, env = "S3_ROTATE_MB", default_value_t = 64)]
+    pub s3_rotate_mb: u64,
+
+    /// Upload the batch once its oldest change event is this many seconds old
+    #[arg(long, env = "S3_ROTATE_SECS", default_value_t = 60)]
+    pub s3_rotate_secs: u64,
+
+    /// Part size in megabytes of multipart uploads; smaller files are uploaded in one request (minimum 5)
+    #[arg(long, env = "S3_PART_SIZE_MB", default_value_t = 16)]
+    pub s3_part_size_mb: usize,
+
+    /// Retries of a failed upload before the run stops
+    #[arg(long, env = "S3_MAX_RETRIES", default_value_t = 5)]
+    pub s3_max_retries: u32,
+
+    /// Record every raw CopyData message to this capture file for offline replay (libpq backend)
+    #[arg(long = "record", env = "RECORD_FILE")]
+    pub record_file: Option<PathBuf>,
+
+    /// Create the slot with an exported snapshot and copy the published tables to the sinks before streaming (libpq backend)
+    #[arg(long, env = "INIT_SYNC", value_parser = clap::builder::BoolishValueParser::new())]
+    pub init_sync: bool,
+
+    /// Also write the tables copied by --init-sync as schema.table.csv files to this directory
+    #[arg(
+        long = "init-sync-csv-dir",
+        env = "INIT_SYNC_CSV_DIRECTORY",
+        requires = "init_sync"
+    )]
+    pub init_sync_csv_dir: Option<PathBuf>,
+
+    /// Serve /livez, /readyz and /health on this port for orchestrator probes
+    #[arg(long, env = "HEALTH_PORT")]
+    pub health_port: Option<u16>,
+
+    /// Keep running as a service: restart the stream with the reconnect backoff after failures that may recover, such as a connection still down once the reconnect attempts are used up
+    #[arg(long, env = "SERVICE_MODE", value_parser = clap::builder::BoolishValueParser::new())]
+    pub service: bool,
+
+    /// Write the process ID to this file while running, for a service supervisor
+    #[arg(long, env = "PID_FILE")]
+    pub pid_file: Option<PathBuf>,
+
+    /// Seconds without a message or keepalive before /livez reports the stream as stuck
+    #[arg(
+        long = "health-timeout",
+        env = "HEALTH_TIMEOUT_SECS",
+        default_value_t = 60
+    )]
+    pub health_timeout_secs: u64,
+
+    /// Accept pause, resume, status and feedback-now commands on a Unix socket at this path
+    #[arg(long, env = "CONTROL_SOCKET")]
+    pub control_socket: Option<PathBuf>,
+
+    /// Alert when the stream falls this many WAL bytes behind the server (libpq backend)
+    #[arg(long, env = "ALERT_BYTES_BEHIND")]
+    pub alert_bytes_behind: Option<u64>,
+
+    /// Alert after this many seconds without a keepalive or other message from the server
+    #[arg(long = "alert-keepalive-secs", env = "ALERT_KEEPALIVE_SECS")]
+    pub alert_keepalive_secs: Option<u64>,
+
+    /// Alert after this many seconds without a commit
+    #[arg(long = "alert-commit-secs", env = "ALERT_COMMIT_SECS")]
+    pub alert_commit_secs: Option<u64>,
+
+    /// Alert when the slot retains this percentage of max_slot_wal_keep_size (libpq backend, needs --control-connection)
+    #[arg(long = "alert-wal-keep-percent", env = "ALERT_WAL_KEEP_PERCENT")]
+    pub alert_wal_keep_percent: Option<f64>,
+
+    /// What a firing alert does: log at error level, also call --alert-webhook-url, or exit with --alert-exit-code
+    #[arg(
+        long,
+        env = "ALERT_ACTION",
+        default_value = "log",
+        value_parser = ["log", "webhook", "exit"]
+    )]
+    pub alert_action: String,
+
+    /// http(s):// URL alerts and their resolutions are POSTed to as JSON with --alert-action webhook
+    #[arg(long, env = "ALERT_WEBHOOK_URL")]
+    pub alert_webhook_url: Option<String>,
+
+    /// Exit code of the process when an alert fires with --alert-action exit
+    #[arg(long, env = "ALERT_EXIT_CODE", default_value_t = 3)]
+    pub alert_exit_code: i32,
+
+    /// Start streaming at this LSN (X/Y) instead of the checkpoint or the slot's confirmed position
+    #[arg(long, env = "START_LSN", value_parser = Lsn::from_str)]
+    pub start_lsn: Option<Lsn>,
+
+    /// Skip transactions that committed before this RFC 3339 time
+    #[arg(long, env = "START_TIME", value_parser = parse_stop_time)]
+    pub start_time: Option<DateTime<Utc>>,
+
+    /// Stop after this many INSERT/UPDATE/DELETE/TRUNCATE events
+    #[arg(long, env = "STOP_MAX_EVENTS")]
+    pub max_events: Option<u64>,
+
+    /// Stop once WAL at or past this LSN (X/Y) has been received
+    #[arg(long, env = "STOP_UNTIL_LSN", value_parser = Lsn::from_str)]
+    pub until_lsn: Option<Lsn>,
+
+    /// Stop at this RFC 3339 wall-clock time
+    #[arg(long, env = "STOP_UNTIL_TIME", value_parser = parse_stop_time)]
+    pub until_time: Option<DateTime<Utc>>,
+
+    /// Watch mode: stream for a bounded duration (e.g. 30s, 5m, 1h) then print a summary
+    #[arg(long = "for", env = "WATCH_DURATION", value_parser = parse_duration)]
+    pub watch_duration: Option<Duration>,
+
+    /// Replication sources from the `[[source]]` tables of the config file
+    #[arg(skip)]
+    pub sources: Vec<SourceConfig>,
+
+    /// Event sinks from the `[[sink]]` tables of the config file
+    #[arg(skip)]
+    pub sinks: Vec<SinkConfig>,
+}
+
+impl StreamArgs {
+    /// Stop conditions requested on the command line
+    pub fn stop_conditions(&self) -> StopConditions {
+        StopConditions {
+            max_events: self.max_events,
+            until_lsn: self.until_lsn,
+            until_time: self.until_time,
+        }
+    }
+
+    /// Reconnection policy requested on the command line
+    pub fn reconnect_policy(&self) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_retries: self.max_reconnects,
+            initial_backoff: Duration::from_secs(self.reconnect_backoff_secs),
+            max_backoff: Duration::from_secs(self.max_reconnect_backoff_secs),
+            jitter: self.reconnect_jitter,
+        }
+    }
+
+    /// Webhook sink requested on the command line, None when no URL is set
+    pub fn webhook_config(&self) -> Option<WebhookConfig> {
+        let url = self.webhook_url.clone()?;
+        Some(WebhookConfig {
+            url,
+            batch_size: self.webhook_batch_size.max(1),
+            flush_interval: Duration::from_millis(self.webhook_flush_interval_ms),
+            retry: ReconnectPolicy {
+                max_retries: self.webhook_max_retries,
+                max_backoff: Duration::from_secs(30),
+                ..ReconnectPolicy::default()
+            },
+        })
+    }
+
+    /// Parquet sink requested on the command line, None when no directory is set
+    #[cfg(feature = "parquet")]
+    pub fn parquet_config(&self) -> Option<ParquetConfig> {
+        let directory = self.parquet_dir.clone()?;
+        Some(ParquetConfig {
+            directory,
+            row_group_size: self.parquet_row_group_size.max(1),
+        })
+    }
+
+    /// Lag alerts requested on the command line, None when no threshold is set
+    pub fn alert_config(&self) -> crate::errors::Result<Option<AlertConfig>> {
+        if self.alert_bytes_behind.is_none()
+            && self.alert_keepalive_secs.is_none()
+            && self.alert_commit_secs.is_none()
+            && self.alert_wal_keep_percent.is_none()
+        {
+            return Ok(None);
+        }
+
+        let action = match self.alert_action.as_str() {
+            "webhook" => AlertAction::Webhook(self.alert_webhook_url.clone().ok_or_else(|| {
+                crate::errors::ReplicationError::config(
+                    "--alert-action webhook needs --alert-webhook-url",
+                )
+            })?),
+            "exit" => AlertAction::Exit(self.alert_exit_code),
+            _ => AlertAction::Log,
+        };
+        Ok(Some(AlertConfig {
+            max_bytes_behind: self.alert_bytes_behind,
+            max_keepalive_silence: self.alert_keepalive_secs.map(Duration::from_secs),
+            max_commit_silence: self.alert_commit_secs.map(Duration::from_secs),
+            max_wal_keep_percent: self.alert_wal_keep_percent,
+            action,
+        }))
+    }
+
+    /// Per-transaction limits requested on the command line, None when no limit is set
+    pub fn transaction_limits(&self) -> crate::errors::Result<Option<TransactionLimits>> {
+        if self.max_transaction_rows.is_none() && self.max_transaction_bytes.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(TransactionLimits {
+            max_rows: self.max_transaction_rows,
+            max_bytes: self.max_transaction_bytes,
+            action: LimitAction::from_str(&self.transaction_limit_action)?,
+        }))
+    }
+
+    /// Rate limits requested on the command line, None when neither is set
+    pub fn rate_limits(&self) -> Option<RateLimits> {
+        if self.max_events_per_sec.is_none() && self.max_bytes_per_sec.is_none() {
+            return None;
+        }
+        Some(RateLimits {
+            max_events_per_sec: self.max_events_per_sec,
+            max_bytes_per_sec: self.max_bytes_per_sec,
+        })
+    }
+
+    /// Avro sink requested on the command line, None when no directory is set
+    pub fn avro_config(&self) -> Option<AvroConfig> {
+        Some(AvroConfig {
+            directory: self.avro_dir.clone()?,
+            registry_url: self.schema_registry_url.clone()?,
+            topic_prefix: None,
+        })
+    }
+
+    /// S3 sink requested on the command line, None when no bucket is set
+    #[cfg(feature = "s3")]
+    pub fn s3_config(&self) -> crate::errors::Result<Option<S3Config>> {
+        let (Some(bucket), Some(endpoint)) = (&self.s3_bucket, &self.s3_endpoint) else {
+            return Ok(None);
+        };
+        Ok(Some(S3Config {
+            endpoint: endpoint.clone(),
+            bucket: bucket.clone(),
+            prefix: self.s3_prefix.trim_matches('/').to_string(),
+            region: self.s3_region.clone(),
+            access_key_id: self.s3_access_key_id.clone(),
+            secret_access_key: self.s3_secret_access_key.clone(),
+            format: ObjectFormat::from_str(&self.s3_format)?,
+            compression: self.s3_compression,
+            staging_dir: self
+                .s3_staging_dir
+                .clone()
+                .unwrap_or_else(std::env::temp_dir),
+            max_batch_bytes: self.s3_rotate_mb.max(1) * 1024 * 1024,
+            rotate_interval: Duration::from_secs(self.s3_rotate_secs),
+            part_size: self.s3_part_size_mb * 1024 * 1024,
+            row_group_size: self.parquet_row_group_size.max(1),
+            retry: ReconnectPolicy {
+                max_retries: self.s3_max_retries,
+                max_backoff: Duration::from_secs(30),
+                ..ReconnectPolicy::default()
+            },
+        }))
+    }
+
+    /// Replication configuration for the stream, shared by both backends
+    pub fn replication_config(&self) -> crate::errors::Result<ReplicationConfig> {
+        self.replication_config_for(
+            self.slot.conninfo()?,
+            self.publication_names.clone(),
+            self.slot.slot_name.clone(),
+        )
+    }
+
+    /// Replication configuration of each `[[source]]`, labelled with its name
+    ///
+    /// Sources share every other setting; the Parquet, Avro and CSV directories get a
+    /// subdirectory per source and file sinks a file name prefixed with the source, so their
+    /// files do not collide.
+    pub fn source_configs(&self) -> crate::errors::Result<Vec<ReplicationConfig>> {
+        if self.record_file.is_some() {
+            return Err(crate::errors::ReplicationError::config(
+                "Recording is not supported with several sources",
+            ));
+        }
+        if self.checkpoint_file.is_some() {
+            return Err(crate::errors::ReplicationError::config(
+                "Set checkpoint-file in each [[source]] instead of for all sources",
+            ));
+        }
+        if self.commit_log.is_some() {
+            return Err(crate::errors::ReplicationError::config(
+                "Set commit-log in each [[source]] instead of for all sources",
+            ));
+        }
+
+        self.sources
+            .iter()
+            .map(|source| {
+                let mut config = self.replication_config_for(
+                    self.slot.apply(&source.connection)?,
+                    source
+                        .publication
+                        .clone()
+                        .unwrap_or_else(|| self.publication_names.clone()),
+                    source
+                        .slot
+                        .clone()
+                        .unwrap_or_else(|| self.slot.slot_name.clone()),
+                )?;
+                config.source = Some(source.name.clone());
+                config.checkpoint_path = source.checkpoint_file.clone();
+                config.commit_log_path = source.commit_log.clone();
+                #[cfg(feature = "parquet")]
+                if let Some(parquet) = config.parquet.as_mut() {
+                    parquet.directory = parquet.directory.join(&source.name);
+                }
+                #[cfg(feature = "s3")]
+                if let Some(s3) = config.s3.as_mut() {
+                    s3.prefix = match s3.prefix.as_str() {
+                        "" => source.name.clone(),
+                        prefix => format!("{}/{}", prefix, source.name),
+                    };
+                }
+                // Topics and subjects of the sources must not collide in the registry
+                if let Some(avro) = config.avro.as_mut() {
+                    avro.directory = avro.directory.join(&source.name);
+                    avro.topic_prefix = Some(source.name.clone());
+                }
+                // Each source writes its own files next to the configured one
+                for sink in &mut config.sinks {
+                    for path in [sink.path.as_mut(), sink.dead_letter_path.as_mut()]
+                        .into_iter()
+                        .flatten()
+                    {
+                        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+                        *path = path.with_file_name(format!("{}-{}", source.name, file_name));
+                    }
+                }
+                if let Some(csv_dir) = config
+                    .init_sync
+                    .as_mut()
+                    .and_then(|init_sync| init_sync.csv_dir.as_mut())
+                {
+                    *csv_dir = csv_dir.join(&source.name);
+                }
+                Ok(config)
+            })
+            .collect()
+    }
+
+    fn replication_config_for(
+        &self,
+        connection_string: String,
+        publication_names: Vec<String>,
+        slot_name: String,
+    ) -> crate::errors::Result<ReplicationConfig> {
+        let slot_mode = if self.temporary_slot {
+            SlotMode::Temporary
+        } else {
+            SlotMode::from_str(&self.slot_mode)?
+        };
+        let mut builder = ReplicationConfig::builder(connection_string)
+            .with_publications(publication_names)
+            .with_slot_name(slot_name)
+            .with_slot_mode(slot_mode)
+            .with_drop_slot_on_exit(self.drop_slot_on_exit)
+            .with_proto_version(self.protocol_version)
+            // Streamed blocks of in-progress transactions can interleave and abort, so they are
+            // not applied to a target
+            .with_streaming(!self.no_streaming && self.target_connection_string.is_none())
+            .with_parallel_streaming(self.parallel_streaming)
+            .with_binary(self.binary)
+            .with_origin(OriginFilter::from_str(&self.origin)?)
+            .with_status_interval(Duration::from_secs(self.status_interval_secs))
+            .with_receiver_timeout(
+                (self.receiver_timeout_secs > 0)
+                    .then(|| Duration::from_secs(self.receiver_timeout_secs)),
+            )
+            .with_feedback_mode(FeedbackMode::from_str(&self.feedback_mode)?)
+            .with_reconnect(self.reconnect_policy());
+        if let Some(feedback_interval_secs) = self.feedback_interval_secs {
+            builder = builder.with_feedback_interval(Duration::from_secs(feedback_interval_secs));
+        }
+        let mut config = builder.build()?;
+
+        config.validation = ValidationMode::from_str(&self.validate)?;
+        config.progress_interval_secs = self.progress_interval_secs;
+        config.stats_interval_secs = self.stats_interval_secs;
+        config.subscription_stats_interval_secs = self.subscription_stats_interval_secs;
+        config.transaction_buffer = self.assembler_config();
+        config.relation_cache_size = self.relation_cache_size;
+        config.toast_cache_rows = self.toast_cache_rows;
+        config.transaction_limits = self.transaction_limits()?;
+        config.max_column_bytes = self.max_column_bytes;
+        config.control_connection = self.control_connection;
+        config.wal_keep = (self.wal_keep_check_secs > 0).then(|| WalKeepConfig {
+            interval: Duration::from_secs(self.wal_keep_check_secs),
+            warn_percent: self.wal_keep_warn_percent,
+        });
+        config.receive_queue_capacity = self.receive_queue_capacity;
+        config.rate_limits = self.rate_limits();
+        self.display.apply_to(&mut config)?;
+        config.start_lsn = self.start_lsn;
+        config.start_time = self.start_time;
+        config.stop_conditions = self.stop_conditions();
+        config.checkpoint_path = self.checkpoint_file.clone();
+        config.commit_log_path = self.commit_log.clone();
+        config.commit_log_window = self.commit_log_window;
+        config.target_connection_string = self.target_connection_string.clone();
+        config.record_path = self.record_file.clone();
+        config.webhook = self.webhook_config();
+        #[cfg(feature = "parquet")]
+        {
+            config.parquet = self.parquet_config();
+        }
+        #[cfg(not(feature = "parquet"))]
+        if self.parquet_dir.is_some() {
+            return Err(crate::errors::ReplicationError::config(
+                "PARQUET_DIRECTORY needs a build with the parquet feature",
+            ));
+        }
+        config.avro = self.avro_config();
+        #[cfg(feature = "s3")]
+        {
+            config.s3 = self.s3_config()?;
+        }
+        #[cfg(not(feature = "s3"))]
+        if self.s3_bucket.is_some() {
+            return Err(crate::errors::ReplicationError::config(
+                "S3_BUCKET needs a build with the s3 feature",
+            ));
+        }
+        config.sinks = self.sinks.clone();
+        config.alerts = self.alert_config()?;
+        config.event_ids = self.event_ids;
+        config.event_timestamps = self.event_timestamps;
+        config.transaction_envelope =
+            self.transaction_envelope || !self.event_timestamps.is_empty();
+        config.init_sync = self.init_sync.then(|| InitSyncConfig {
+            csv_dir: self.init_sync_csv_dir.clone(),
+        });
+
+        // Watch mode streams for a bounded duration and summarizes what it saw
+        config.watch_duration = self.watch_duration;
+        if let Some(duration) = self.watch_duration {
+            config.stop_conditions.stop_after(duration)?;
+        }
+        Ok(config)
+    }
+
+    /// Transaction buffering requested on the command line, None when disabled
+    pub fn assembler_config(&self) -> Option<AssemblerConfig> {
+        if !self.buffer_transactions {
+            return None;
+        }
+
+        let mut config = AssemblerConfig {
+            memory_limit: self.buffer_memory_limit_mb * 1024 * 1024,
+            transaction_limit: self
+                .buffer_transaction_limit_mb
+                .map(|limit| limit * 1024 * 1024),
+            parallel: self.parallel_streaming,
+            ..AssemblerConfig::default()
+        };
+        if let Some(spill_dir) = &self.spill_dir {
+            config.spill_dir = spill_dir.clone();
+        }
+        Some(config)
+    }
+}
@@ -0,0 +1,417 @@
+//! Verification of transactions delivered again
+//! After a reconnect or restart the server sends every transaction committed after the slot's
+//! confirmed position again, including those already processed. The commit log records the most
+//! recent commits with a hash of their changes in a file of JSON lines, so a commit received
+//! again, also in a later run, is compared with what was received the first time and any
+//! difference is reported as a divergence.
+
+use crate::errors::{ReplicationError, Result};
+use crate::types::{Lsn, ReplicationMessage, TupleData};
+use crate::utils::Xid;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use tracing::{debug, error, info, warn};
+
+/// A committed transaction as first received
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitRecord {
+    pub slot_name: String,
+    pub xid: Xid,
+    pub commit_lsn: Lsn,
+    pub end_lsn: Lsn,
+    /// Changes received in the transaction
+    pub changes: u64,
+    /// FNV-1a hash of the changes, None when a subtransaction aborted while the transaction was
+    /// streamed, as what was sent before the abort may differ between deliveries
+    pub hash: Option<u64>,
+}
+
+/// Changes of a transaction received so far
+#[derive(Debug)]
+struct OpenTransaction {
+    changes: u64,
+    hash: ContentHash,
+    verifiable: bool,
+}
+
+/// Incremental 64-bit FNV-1a hash
+#[derive(Debug, Clone, Copy)]
+struct ContentHash(u64);
+
+impl ContentHash {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_tuple(&mut self, tuple: &TupleData) {
+        self.write(&tuple.column_count.to_be_bytes());
+        for column in &tuple.columns {
+            self.write(&[column.data_type as u8]);
+            self.write(&column.length.to_be_bytes());
+            self.write(&column.data);
+        }
+    }
+
+    /// Add a change; the xid and whether it was streamed are left out, so a transaction hashes
+    /// the same however it was delivered
+    fn write_change(&mut self, message: &ReplicationMessage) {
+        match message {
+            ReplicationMessage::Insert {
+                relation_id,
+                tuple_data,
+                ..
+            } => {
+                self.write(b"I");
+                self.write(&relation_id.to_be_bytes());
+                self.write_tuple(tuple_data);
+            }
+            ReplicationMessage::Update {
+                relation_id,
+                key_type,
+                old_tuple_data,
+                new_tuple_data,
+                ..
+            } => {
+                self.write(b"U");
+                self.write(&relation_id.to_be_bytes());
+                if let (Some(key_type), Some(old)) = (key_type, old_tuple_data) {
+                    self.write(&[*key_type as u8]);
+                    self.write_tuple(old);
+                }
+                self.write_tuple(new_tuple_data);
+            }
+            ReplicationMessage::Delete {
+                relation_id,
+                key_type,
+                tuple_data,
+                ..
+            } => {
+                self.write(b"D");
+                self.write(&relation_id.to_be_bytes());
+                self.write(&[*key_type as u8]);
+                self.write_tuple(tuple_data);
+            }
+            ReplicationMessage::Truncate {
+                relation_ids,
+                flags,
+                ..
+            } => {
+                self.write(b"T");
+                self.write(&flags.to_be_bytes());
+                for relation_id in relation_ids {
+                    self.write(&relation_id.to_be_bytes());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Outcomes of the comparisons of commits delivered again
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RedeliveryCounts {
+    /// Commits that matched what was received the first time
+    pub matched: u64,
+    /// Commits whose xid, change count or changes differed
+    pub diverged: u64,
+    /// Commits older than the window, or that could not be compared
+    pub unverified: u64,
+}
+
+/// Records recent commits in a file and compares commits delivered again against them
+#[derive(Debug)]
+pub struct CommitLog {
+    path: PathBuf,
+    slot_name: String,
+    window: usize,
+    file: File,
+    /// Lines appended since the file was last rewritten
+    appended: usize,
+    /// The last `window` commits, in the order of their end LSN
+    commits: VecDeque<CommitRecord>,
+    open: HashMap<Xid, OpenTransaction>,
+    counts: RedeliveryCounts,
+}
+
+impl CommitLog {
+    /// Load the commits recorded for the slot and open the file for appending
+    pub fn open(path: PathBuf, slot_name: String, window: usize) -> Result<Self> {
+        let commits = load_commits(&path, &slot_name, window)?;
+        if let Some(last) = commits.back() {
+            info!(
+                "Commit log {}: {} commits recorded, the last at {}",
+                path.display(),
+                commits.len(),
+                last.end_lsn
+            );
+        }
+        let mut log = Self {
+            file: open_append(&path)?,
+            path,
+            slot_name,
+            window: window.max(1),
+            appended: 0,
+            commits,
+            open: HashMap::new(),
+            counts: RedeliveryCounts::default(),
+        };
+        // Start from a file holding only this slot's window
+        log.rewrite()?;
+        Ok(log)
+    }
+
+    /// Start hashing a transaction at its Begin or first StreamStart
+    pub fn begin(&mut self, xid: Xid) {
+        self.open.insert(
+            xid,
+            OpenTransaction {
+                changes: 0,
+                hash: ContentHash::new(),
+                verifiable: true,
+            },
+        );
+    }
+
+    /// Add a change of the transaction `xid`, if it is being hashed
+    pub fn record_change(&mut self, xid: Xid, message: &ReplicationMessage) {
+        if let Some(transaction) = self.open.get_mut(&xid) {
+            transaction.changes += 1;
+            transaction.hash.write_change(message);
+        }
+    }
+
+    /// Note that a subtransaction of the streamed transaction `xid` aborted
+    pub fn abort_subtransaction(&mut self, xid: Xid) {
+        if let Some(transaction) = self.open.get_mut(&xid) {
+            transaction.verifiable = false;
+        }
+    }
+
+    pub fn abort(&mut self, xid: Xid) {
+        self.open.remove(&xid);
+    }
+
+    /// Forget the transactions in progress when the connection is lost; the server sends them
+    /// again from the start
+    pub fn clear_open(&mut self) {
+        self.open.clear();
+    }
+
+    /// Record the commit of `xid`, or compare it with the recorded commit when it was already
+    /// received
+    pub fn commit(&mut self, xid: Xid, commit_lsn: Lsn, end_lsn: Lsn) -> Result<()> {
+        let Some(transaction) = self.open.remove(&xid) else {
+            return Ok(());
+        };
+        let record = CommitRecord {
+            slot_name: self.slot_name.clone(),
+            xid,
+            commit_lsn,
+            end_lsn,
+            changes: transaction.changes,
+            hash: transaction.verifiable.then_some(transaction.hash.0),
+        };
+
+        let last_end = self
+            .commits
+            .back()
+            .map_or(Lsn::INVALID, |last| last.end_lsn);
+        if record.end_lsn <= last_end {
+            self.verify(&record);
+            return Ok(());
+        }
+        self.append(record)
+    }
+
+    /// Compare a commit delivered again with the commit recorded at its position
+    fn verify(&mut self, received: &CommitRecord) {
+        let recorded = self
+            .commits
+            .binary_search_by_key(&received.end_lsn, |commit| commit.end_lsn)
+            .ok()
+            .map(|index| &self.commits[index]);
+        let Some(recorded) = recorded else {
+            // Older than the window, or not processed the first time, such as a transaction
+            // skipped by the start time or origin filters
+            debug!(
+                "Transaction {} committed at {} was delivered again and is not in the commit log",
+                received.xid, received.commit_lsn
+            );
+            self.counts.unverified += 1;
+            return;
+        };
+
+        let differences = compare(recorded, received);
+        if differences.is_empty() {
+            match (recorded.hash, received.hash) {
+                (Some(_), Some(_)) => {
+                    debug!(
+                        "Transaction {} committed at {} was delivered again and matches",
+                        received.xid, received.commit_lsn
+                    );
+                    self.counts.matched += 1;
+                }
+                _ => self.counts.unverified += 1,
+            }
+            return;
+        }
+        error!(
+            xid = received.xid,
+            commit_lsn = %received.commit_lsn,
+            "Transaction {} committed at {} was delivered again and differs from the first delivery: {}",
+            received.xid,
+            received.commit_lsn,
+            differences.join(", ")
+        );
+        self.counts.diverged += 1;
+    }
+
+    fn append(&mut self, record: CommitRecord) -> Result<()> {
+        let line = serialize(&record)?;
+        self.file.write_all(line.as_bytes())?;
+        self.commits.push_back(record);
+        while self.commits.len() > self.window {
+            self.commits.pop_front();
+        }
+        self.appended += 1;
+        if self.appended >= self.window {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    /// Replace the file with the commits of the window, atomically
+    fn rewrite(&mut self) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        for record in &self.commits {
+            file.write_all(serialize(record)?.as_bytes())?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = open_append(&self.path)?;
+        self.appended = 0;
+        Ok(())
+    }
+
+    /// Make the commits appended so far durable
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Log how the commits delivered again compared, if there were any
+    pub fn log_summary(&self) {
+        let counts = self.counts;
+        if counts == RedeliveryCounts::default() {
+            return;
+        }
+        let summary = format!(
+            "Transactions delivered again: {} matched, {} diverged, {} could not be compared",
+            counts.matched, counts.diverged, counts.unverified
+        );
+        if counts.diverged > 0 {
+            warn!(
+                matched = counts.matched,
+                diverged = counts.diverged,
+                unverified = counts.unverified,
+                "{}",
+                summary
+            );
+        } else {
+            info!(
+                matched = counts.matched,
+                diverged = counts.diverged,
+                unverified = counts.unverified,
+                "{}",
+                summary
+            );
+        }
+    }
+}
+
+/// How a commit delivered again differs from the recorded one
+fn compare(recorded: &CommitRecord, received: &CommitRecord) -> Vec<String> {
+    let mut differences = Vec::new();
+    if recorded.xid != received.xid {
+        differences.push(format!("xid was {}", recorded.xid));
+    }
+    if recorded.commit_lsn != received.commit_lsn {
+        differences.push(format!("commit LSN was {}", recorded.commit_lsn));
+    }
+    if recorded.changes != received.changes {
+        differences.push(format!(
+            "{} changes instead of {}",
+            received.changes, recorded.changes
+        ));
+    }
+    if let (Some(before), Some(now)) = (recorded.hash, received.hash) {
+        if before != now && recorded.changes == received.changes {
+            differences.push("the changes differ".to_string());
+        }
+    }
+    differences
+}
+
+fn serialize(record: &CommitRecord) -> Result<String> {
+    let mut line = serde_json::to_string(record).map_err(|e| {
+        ReplicationError::checkpoint(format!("Failed to serialize commit record: {}", e))
+    })?;
+    line.push('\n');
+    Ok(line)
+}
+
+fn open_append(path: &PathBuf) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+/// The last `window` commits of the slot recorded in the file, none when it does not exist
+///
+/// A line cut short by a crash while it was written is skipped.
+fn load_commits(path: &PathBuf, slot_name: &str, window: usize) -> Result<VecDeque<CommitRecord>> {
+    let mut commits = VecDeque::new();
+    if !path.exists() {
+        return Ok(commits);
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let mut skipped = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<CommitRecord>(&line) {
+            Ok(record) if record.slot_name == slot_name => {
+                if commits
+                    .back()
+                    .is_some_and(|last: &CommitRecord| last.end_lsn >= record.end_lsn)
+                {
+                    continue;
+                }
+                commits.push_back(record);
+                if commits.len() > window {
+                    commits.pop_front();
+                }
+            }
+            Ok(_) => {}
+            Err(_) => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        warn!(
+            "Skipped {} unreadable lines of commit log {}",
+            skipped,
+            path.display()
+        );
+    }
+    Ok(commits)
+}
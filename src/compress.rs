@@ -0,0 +1,194 @@
+//! Streaming gzip and zstd compression of output files
+//! gzip goes through flate2 and zstd through the reference library, both at their default
+//! levels, so the files are as small as the `gzip` and `zstd` tools make them and anything
+//! those tools write reads back. Input is compressed as it is written, so memory stays bounded
+//! however much is written.
+
+use crate::errors::{ReplicationError, Result};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Compression of an output file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = ReplicationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "gzip" | "gz" => Ok(Self::Gzip),
+            "zstd" | "zst" => Ok(Self::Zstd),
+            _ => Err(ReplicationError::config(format!(
+                "Invalid compression '{}', expected none, gzip or zstd",
+                s
+            ))),
+        }
+    }
+}
+
+impl Compression {
+    /// File name extension of the codec
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gz"),
+            Self::Zstd => Some("zst"),
+        }
+    }
+
+    /// The path with the codec's extension appended, unless it already ends with it
+    pub fn file_path(self, path: &Path) -> PathBuf {
+        match self.extension() {
+            Some(extension) if path.extension().is_none_or(|ext| ext != extension) => {
+                let mut name = path.as_os_str().to_owned();
+                name.push(".");
+                name.push(extension);
+                PathBuf::from(name)
+            }
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// The codec of data starting with these bytes
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&GZIP_MAGIC) {
+            Self::Gzip
+        } else if magic.starts_with(&ZSTD_MAGIC.to_le_bytes()) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+
+/// Writer compressing everything written to it before passing it on
+///
+/// `flush` only flushes what was compressed so far to the inner writer; the rest of the input
+/// stays with the codec until it fills a block or the writer is finished, so flushing often
+/// does not cost compression. Dropping the writer finishes it, ignoring errors.
+pub struct CompressedWriter<W: Write> {
+    encoder: Option<Encoder<W>>,
+}
+
+enum Encoder<W: Write> {
+    Plain(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(inner: W, compression: Compression) -> Self {
+        let encoder = match compression {
+            Compression::None => Encoder::Plain(inner),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                inner,
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => Encoder::Zstd(
+                // Only fails to allocate the compression context
+                zstd::stream::write::Encoder::new(inner, zstd::DEFAULT_COMPRESSION_LEVEL)
+                    .expect("Failed to create the zstd compression context"),
+            ),
+        };
+        Self {
+            encoder: Some(encoder),
+        }
+    }
+
+    /// Compress the rest of the input, end the gzip member or zstd frame and return the inner
+    /// writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let mut inner = match self.encoder.take().expect("encoder taken only once") {
+            Encoder::Plain(inner) => inner,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        };
+        inner.flush()?;
+        Ok(inner)
+    }
+
+    fn encoder(&mut self) -> io::Result<&mut Encoder<W>> {
+        self.encoder
+            .as_mut()
+            .ok_or_else(|| io::Error::other("Compressed writer already finished"))
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self.encoder()? {
+            Encoder::Plain(inner) => inner.write(data),
+            Encoder::Gzip(encoder) => encoder.write(data),
+            Encoder::Zstd(encoder) => encoder.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Flushing the codecs would end their block early
+        match self.encoder()? {
+            Encoder::Plain(inner) => inner.flush(),
+            Encoder::Gzip(encoder) => encoder.get_mut().flush(),
+            Encoder::Zstd(encoder) => encoder.get_mut().flush(),
+        }
+    }
+}
+
+impl<W: Write> Drop for CompressedWriter<W> {
+    fn drop(&mut self) {
+        let _ = match self.encoder.as_mut() {
+            Some(Encoder::Gzip(encoder)) => encoder.try_finish(),
+            Some(Encoder::Zstd(encoder)) => encoder.do_finish(),
+            Some(Encoder::Plain(_)) | None => Ok(()),
+        };
+    }
+}
+
+/// Reader of data that may be gzip or zstd compressed, told apart by its first bytes
+///
+/// Concatenated gzip members and zstd frames, as appending to a compressed file makes, are
+/// read one after the other.
+pub enum CompressedReader<R: BufRead> {
+    Plain(R),
+    Gzip(flate2::bufread::MultiGzDecoder<R>),
+    Zstd(zstd::stream::read::Decoder<'static, R>),
+}
+
+impl<R: BufRead> CompressedReader<R> {
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let magic = inner.fill_buf()?;
+        Ok(match Compression::detect(magic) {
+            Compression::None => Self::Plain(inner),
+            Compression::Gzip => Self::Gzip(flate2::bufread::MultiGzDecoder::new(inner)),
+            Compression::Zstd => Self::Zstd(zstd::stream::read::Decoder::with_buffer(inner)?),
+        })
+    }
+
+    pub fn compression(&self) -> Compression {
+        match self {
+            Self::Plain(_) => Compression::None,
+            Self::Gzip(_) => Compression::Gzip,
+            Self::Zstd(_) => Compression::Zstd,
+        }
+    }
+}
+
+impl<R: BufRead> Read for CompressedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(reader) => reader.read(buf),
+            Self::Gzip(decoder) => decoder.read(buf),
+            Self::Zstd(decoder) => decoder.read(buf),
+        }
+    }
+}
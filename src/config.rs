@@ -0,0 +1,268 @@
+//! TOML configuration file support
+//! Settings from the file sit between environment variables and built-in defaults, so the
+//! precedence is CLI flags > environment variables > config file > defaults
+//!
+//! Top-level keys are the long names of the command line flags (`slot`, `publication`,
+//! `protocol-version`, `include-table`, ...); `_` may be used instead of `-`. Logging is
+//! configured in a `[logging]` table whose keys are the `LOG_*` variable names without the
+//! prefix (`output`, `directory`, `level`, ...).
+//!
+//! Several replication sources can be streamed at once by listing them as `[[source]]` tables,
+//! each with a `name`, a `connection`, and optionally its own `slot`, `publication`,
+//! `checkpoint-file` and `commit-log`; the top-level settings apply to every source.
+//!
+//! Change events can be sent to several sinks at once by listing them as `[[sink]]` tables,
+//! each with a `type` (`console`, `file` or `http`), its destination, and its own batching and
+//! `on-error` policy.
+
+use crate::sink::SinkConfig;
+use anyhow::{Context, Result};
+use clap::Command;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// Flag and environment variable naming the configuration file
+pub const CONFIG_FLAG: &str = "--config";
+pub const CONFIG_ENV: &str = "CONFIG_FILE";
+
+/// Parsed configuration file
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    settings: Table,
+    logging: Table,
+    sources: Vec<SourceConfig>,
+    sinks: Vec<SinkConfig>,
+}
+
+/// A replication source from a `[[source]]` table
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SourceConfig {
+    /// Label of the source in logs, change events and metrics
+    pub name: String,
+    /// Connection string, must include replication=database
+    pub connection: String,
+    /// Slot of the source, the top-level slot when not set
+    pub slot: Option<String>,
+    /// Publications of the source, the top-level publications when not set
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub publication: Option<Vec<String>>,
+    /// Checkpoint file of the source
+    #[serde(alias = "checkpoint_file")]
+    pub checkpoint_file: Option<PathBuf>,
+    /// Commit log of the source
+    #[serde(alias = "commit_log")]
+    pub commit_log: Option<PathBuf>,
+}
+
+impl ConfigFile {
+    /// Load the file named by `--config` or `CONFIG_FILE`, or an empty config when neither is set
+    ///
+    /// The path has to be known before the command line is parsed because the file supplies
+    /// the parser's defaults, so the arguments are scanned for it directly.
+    pub fn from_args_or_env() -> Result<Self> {
+        let path = config_path_from_args(env::args())
+            .or_else(|| env::var_os(CONFIG_ENV).map(PathBuf::from));
+        match path {
+            Some(path) => Self::load(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut settings: Table = contents
+            .parse()
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+        let logging = match settings.remove("logging") {
+            Some(Value::Table(logging)) => logging,
+            Some(_) => anyhow::bail!("[logging] in {} must be a table", path.display()),
+            None => Table::new(),
+        };
+
+        let sources = match settings.remove("source") {
+            Some(Value::Array(sources)) => sources
+                .into_iter()
+                .map(|source| source.try_into::<SourceConfig>())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .with_context(|| format!("Invalid [[source]] in {}", path.display()))?,
+            Some(_) => anyhow::bail!(
+                "[[source]] in {} must be an array of tables",
+                path.display()
+            ),
+            None => Vec::new(),
+        };
+        for (i, source) in sources.iter().enumerate() {
+            if sources[..i].iter().any(|other| other.name == source.name) {
+                anyhow::bail!(
+                    "Duplicate source name {} in {}",
+                    source.name,
+                    path.display()
+                );
+            }
+        }
+
+        let sinks = match settings.remove("sink") {
+            Some(Value::Array(sinks)) => sinks
+                .into_iter()
+                .map(|sink| sink.try_into::<SinkConfig>())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .with_context(|| format!("Invalid [[sink]] in {}", path.display()))?,
+            Some(_) => anyhow::bail!("[[sink]] in {} must be an array of tables", path.display()),
+            None => Vec::new(),
+        };
+        for (i, sink) in sinks.iter().enumerate() {
+            sink.validate()
+                .with_context(|| format!("Invalid [[sink]] in {}", path.display()))?;
+            if sinks[..i].iter().any(|other| other.name() == sink.name()) {
+                anyhow::bail!(
+                    "Duplicate sink name {} in {}, set a name for each sink",
+                    sink.name(),
+                    path.display()
+                );
+            }
+        }
+
+        Ok(Self {
+            settings,
+            logging,
+            sources,
+            sinks,
+        })
+    }
+
+    /// Replication sources listed as `[[source]]` tables, empty for a single stream
+    pub fn sources(&self) -> &[SourceConfig] {
+        &self.sources
+    }
+
+    /// Event sinks listed as `[[sink]]` tables
+    pub fn sinks(&self) -> &[SinkConfig] {
+        &self.sinks
+    }
+
+    /// Value of a `LOG_*` setting from the `[logging]` table
+    pub fn logging_value(&self, env_name: &str) -> Option<String> {
+        let key = env_name
+            .strip_prefix("LOG_")
+            .unwrap_or(env_name)
+            .to_lowercase();
+        self.logging.get(&key).map(value_to_string)
+    }
+
+    /// Install the file's settings as defaults of the matching flags of every command
+    pub fn apply_defaults(&self, command: Command) -> Result<Command> {
+        for key in self.settings.keys() {
+            let long = key.replace('_', "-");
+            if !has_long_arg(&command, &long) {
+                anyhow::bail!("Unknown setting in config file: {}", key);
+            }
+        }
+
+        Ok(self.apply_to(command))
+    }
+
+    fn apply_to(&self, mut command: Command) -> Command {
+        // Each source has its own connection, so the top-level one becomes optional
+        if !self.sources.is_empty() {
+            let id = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some("connection"))
+                .map(|arg| arg.get_id().clone());
+            if let Some(id) = id {
+                command = command.mut_arg(id, |arg| arg.required(false).default_value(""));
+            }
+        }
+
+        for (key, value) in &self.settings {
+            let long = key.replace('_', "-");
+            let Some(id) = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(long.as_str()))
+                .map(|arg| arg.get_id().clone())
+            else {
+                continue;
+            };
+
+            let values: Vec<String> = match value {
+                Value::Array(items) => items.iter().map(value_to_string).collect(),
+                value => vec![value_to_string(value)],
+            };
+            command = command.mut_arg(id, |arg| arg.required(false).default_values(values));
+        }
+
+        let subcommands: Vec<String> = command
+            .get_subcommands()
+            .map(|subcommand| subcommand.get_name().to_string())
+            .collect();
+        for name in subcommands {
+            command = command.mut_subcommand(name, |subcommand| self.apply_to(subcommand));
+        }
+
+        command
+    }
+}
+
+fn has_long_arg(command: &Command, long: &str) -> bool {
+    command
+        .get_arguments()
+        .any(|arg| arg.get_long() == Some(long))
+        || command
+            .get_subcommands()
+            .any(|subcommand| has_long_arg(subcommand, long))
+}
+
+/// Publications given as one comma-separated string or as an array
+fn string_or_list<'de, D>(deserializer: D) -> std::result::Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    Ok(match Option::<StringOrList>::deserialize(deserializer)? {
+        Some(StringOrList::String(names)) => Some(
+            names
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .collect(),
+        ),
+        Some(StringOrList::List(names)) => Some(names),
+        None => None,
+    })
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(value_to_string)
+            .collect::<Vec<_>>()
+            .join(","),
+        value => value.to_string(),
+    }
+}
+
+/// Find `--config <path>` or `--config=<path>` in the raw arguments
+fn config_path_from_args(args: impl Iterator<Item = String>) -> Option<PathBuf> {
+    let mut args = args.skip(1);
+    while let Some(arg) = args.next() {
+        if arg == CONFIG_FLAG {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
@@ -0,0 +1,190 @@
+//! Connection parameters given as typed options
+//! Validates timeouts, TCP keepalives and the application name and appends them to the libpq
+//! connection string under their libpq spelling, in key/value or URI form
+
+use crate::errors::{ReplicationError, Result};
+
+/// Longest application_name the server keeps, NAMEDATALEN - 1 bytes
+pub const MAX_APPLICATION_NAME_LEN: usize = 63;
+
+/// Connection settings given outside the connection string
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionParams {
+    /// Seconds to wait for the connection to be established, 0 to wait indefinitely
+    pub connect_timeout: Option<u64>,
+    /// Whether TCP keepalives are used, libpq's default is on
+    pub keepalives: Option<bool>,
+    /// Seconds of inactivity before a TCP keepalive is sent
+    pub keepalives_idle: Option<u32>,
+    /// Seconds between unanswered TCP keepalives
+    pub keepalives_interval: Option<u32>,
+    /// Unanswered TCP keepalives before the connection is considered dead
+    pub keepalives_count: Option<u32>,
+    /// Name shown for the connection in pg_stat_activity and pg_stat_replication
+    pub application_name: Option<String>,
+    /// Milliseconds a statement may run before the server cancels it, 0 for no limit
+    pub statement_timeout_ms: Option<u64>,
+}
+
+impl ConnectionParams {
+    pub fn is_empty(&self) -> bool {
+        self.options().is_empty()
+    }
+
+    /// Check the values libpq would otherwise reject or silently adjust
+    pub fn validate(&self) -> Result<()> {
+        if self.connect_timeout == Some(1) {
+            return Err(ReplicationError::config(
+                "connect_timeout must be 0 or at least 2 seconds, libpq raises 1 to 2",
+            ));
+        }
+
+        let keepalive_settings = [
+            ("keepalives_idle", self.keepalives_idle),
+            ("keepalives_interval", self.keepalives_interval),
+            ("keepalives_count", self.keepalives_count),
+        ];
+        for (option, value) in keepalive_settings {
+            match value {
+                Some(0) => {
+                    return Err(ReplicationError::config(format!(
+                        "{} must be at least 1",
+                        option
+                    )))
+                }
+                Some(_) if self.keepalives == Some(false) => {
+                    return Err(ReplicationError::config(format!(
+                        "{} has no effect with keepalives disabled",
+                        option
+                    )))
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(name) = &self.application_name {
+            if name.len() > MAX_APPLICATION_NAME_LEN {
+                return Err(ReplicationError::config(format!(
+                    "application_name is {} bytes, the server keeps at most {}",
+                    name.len(),
+                    MAX_APPLICATION_NAME_LEN
+                )));
+            }
+            // The server replaces anything else with '?'
+            if !name.bytes().all(|byte| (0x20..0x7f).contains(&byte)) {
+                return Err(ReplicationError::config(
+                    "application_name may only contain printable ASCII characters",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Append the options to a connection string, refusing options it already sets
+    pub fn apply(&self, conninfo: &str) -> Result<String> {
+        if self.is_empty() {
+            return Ok(conninfo.to_string());
+        }
+        self.validate()?;
+        append_options(conninfo, &self.options())
+    }
+
+    fn options(&self) -> Vec<(&'static str, String)> {
+        let mut options = Vec::new();
+        if let Some(timeout) = self.connect_timeout {
+            options.push(("connect_timeout", timeout.to_string()));
+        }
+        if let Some(keepalives) = self.keepalives {
+            options.push(("keepalives", u8::from(keepalives).to_string()));
+        }
+        if let Some(idle) = self.keepalives_idle {
+            options.push(("keepalives_idle", idle.to_string()));
+        }
+        if let Some(interval) = self.keepalives_interval {
+            options.push(("keepalives_interval", interval.to_string()));
+        }
+        if let Some(count) = self.keepalives_count {
+            options.push(("keepalives_count", count.to_string()));
+        }
+        if let Some(name) = &self.application_name {
+            options.push(("application_name", name.clone()));
+        }
+        // Sent as a server setting in the startup packet
+        if let Some(timeout) = self.statement_timeout_ms {
+            options.push(("options", format!("-c statement_timeout={}", timeout)));
+        }
+        options
+    }
+}
+
+/// Append options to a key/value or URI connection string, refusing options it already sets
+pub(crate) fn append_options(conninfo: &str, options: &[(&str, String)]) -> Result<String> {
+    let uri = conninfo.starts_with("postgres://") || conninfo.starts_with("postgresql://");
+    let mut result = conninfo.trim_end().to_string();
+    for (key, value) in options {
+        let already_set = if uri {
+            uri_has_option(conninfo, key)
+        } else {
+            keyword_has_option(conninfo, key)
+        };
+        if already_set {
+            return Err(ReplicationError::config(format!(
+                "{} is set both in the connection string and as an option",
+                key
+            )));
+        }
+
+        if uri {
+            let separator = if result.contains('?') { '&' } else { '?' };
+            result.push(separator);
+            result.push_str(key);
+            result.push('=');
+            result.push_str(&percent_encode(value));
+        } else {
+            result.push(' ');
+            result.push_str(key);
+            result.push('=');
+            result.push_str(&quote_keyword_value(value));
+        }
+    }
+    Ok(result)
+}
+
+/// Whether a key/value connection string sets the keyword, allowing spaces around `=`
+fn keyword_has_option(conninfo: &str, key: &str) -> bool {
+    let tokens: Vec<&str> = conninfo.split_whitespace().collect();
+    tokens.iter().enumerate().any(|(index, token)| {
+        token
+            .strip_prefix(key)
+            .is_some_and(|rest| rest.starts_with('='))
+            || (*token == key
+                && tokens
+                    .get(index + 1)
+                    .is_some_and(|next| next.starts_with('=')))
+    })
+}
+
+fn uri_has_option(conninfo: &str, key: &str) -> bool {
+    conninfo.split_once('?').is_some_and(|(_, query)| {
+        query
+            .split('&')
+            .any(|pair| pair.split('=').next() == Some(key))
+    })
+}
+
+/// Single-quote a value for a key/value connection string
+fn quote_keyword_value(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
@@ -0,0 +1,310 @@
+//! Control connection for catalog queries while streaming
+//! The replication connection is in COPY BOTH mode once streaming starts and cannot run SQL, so a
+//! second, regular connection looks up publication membership and column lists, type names,
+//! slot status and the WAL the slot retains
+
+use crate::errors::{ReplicationError, Result};
+use crate::publication::{published_tables, PublishedTable};
+use crate::slot::{slot_status, wal_retention, SlotStatus, WalRetention};
+use crate::types::{RelationInfo, TypeInfo};
+use crate::utils::{Oid, PGConnection, PGResult};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Session settings that fix the text form of catalog values whatever the server defaults are
+const SESSION_SETTINGS: &str =
+    "SET DateStyle = 'ISO, YMD'; SET IntervalStyle = 'iso_8601'; SET TimeZone = 'UTC'";
+
+/// OIDs below this are assigned at initdb, so built-in types never need a lookup
+const FIRST_NORMAL_OBJECT_ID: Oid = 16384;
+
+/// A type as named in the catalog
+#[derive(Debug, Clone)]
+struct CatalogType {
+    /// Type name, schema-qualified outside pg_catalog; the element type's name for arrays
+    name: String,
+    array: bool,
+}
+
+/// Regular SQL connection to the database the slot belongs to
+pub struct ControlConnection {
+    conninfo: String,
+    /// None after a failed query, reopened by the next one
+    connection: Option<PGConnection>,
+    publications: Vec<String>,
+    slot_name: String,
+    /// Publications, column list and row filters of each table, from pg_publication_tables
+    tables: HashMap<(String, String), PublishedTable>,
+    /// Types looked up so far, by OID
+    types: HashMap<Oid, CatalogType>,
+    status_interval: Duration,
+    last_status: Instant,
+}
+
+impl ControlConnection {
+    /// Open the connection with the replication connection's settings, without replication
+    pub fn connect(
+        replication_conninfo: &str,
+        publications: Vec<String>,
+        slot_name: String,
+        status_interval: Duration,
+    ) -> Result<Self> {
+        let conninfo = regular_conninfo(replication_conninfo);
+        let connection = open(&conninfo)?;
+        info!("Opened control connection for catalog queries");
+
+        let mut control = Self {
+            conninfo,
+            connection: Some(connection),
+            publications,
+            slot_name,
+            tables: HashMap::new(),
+            types: HashMap::new(),
+            status_interval,
+            last_status: Instant::now(),
+        };
+        control.refresh_publications()?;
+        Ok(control)
+    }
+
+    /// Publications of the stream that include the table, None when it is not known
+    pub fn publications_of(&self, schema: &str, table: &str) -> Option<&[String]> {
+        self.published_table(schema, table)
+            .map(|table| table.publications.as_slice())
+    }
+
+    /// How the publications of the stream publish the table, None when it is not known
+    pub fn published_table(&self, schema: &str, table: &str) -> Option<&PublishedTable> {
+        self.tables.get(&(schema.to_string(), table.to_string()))
+    }
+
+    /// Reload the tables of every publication of the stream
+    pub fn refresh_publications(&mut self) -> Result<()> {
+        let publications = self.publications.clone();
+        let result = published_tables(self.connection()?, &publications);
+        let tables = self.forget_on_error(result)?;
+
+        self.tables = tables
+            .into_iter()
+            .map(|table| ((table.schema.clone(), table.table.clone()), table))
+            .collect();
+        debug!(
+            "Loaded publication membership of {} tables",
+            self.tables.len()
+        );
+        Ok(())
+    }
+
+    /// Reload publication membership when a table not seen before is announced
+    pub fn note_relation(&mut self, schema: &str, table: &str) {
+        if self.publications_of(schema, table).is_some() {
+            return;
+        }
+        if let Err(e) = self.refresh_publications() {
+            warn!("Failed to refresh publication tables: {}", e);
+        }
+    }
+
+    /// Name of a user-defined type, None for built-in types or when the lookup fails
+    pub fn type_info(&mut self, oid: Oid) -> Option<TypeInfo> {
+        if oid < FIRST_NORMAL_OBJECT_ID {
+            return None;
+        }
+        let query = format!(
+            "SELECT n.nspname, t.typname FROM pg_type t \
+             JOIN pg_namespace n ON n.oid = t.typnamespace WHERE t.oid = {};",
+            oid
+        );
+        match self.query(&query) {
+            Ok(result) if result.ntuples() == 1 => Some(TypeInfo {
+                oid,
+                namespace: result.getvalue(0, 0).unwrap_or_default(),
+                type_name: result.getvalue(0, 1).unwrap_or_default(),
+            }),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Failed to look up type {}: {}", oid, e);
+                None
+            }
+        }
+    }
+
+    /// Fill in the type names of a relation's columns, decoding their type modifiers
+    ///
+    /// Types not looked up before are fetched in one query; columns whose type cannot be
+    /// found keep no name.
+    pub fn resolve_column_types(&mut self, relation: &mut RelationInfo) {
+        let mut missing: Vec<Oid> = relation
+            .columns
+            .iter()
+            .map(|column| column.column_type)
+            .filter(|oid| !self.types.contains_key(oid))
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+        if !missing.is_empty() {
+            if let Err(e) = self.load_types(&missing) {
+                warn!("Failed to look up column types: {}", e);
+            }
+        }
+
+        for column in &mut relation.columns {
+            column.type_name = self.types.get(&column.column_type).map(|catalog_type| {
+                let name = type_with_modifier(&catalog_type.name, column.atttypmod);
+                if catalog_type.array {
+                    format!("{}[]", name)
+                } else {
+                    name
+                }
+            });
+        }
+    }
+
+    fn load_types(&mut self, oids: &[Oid]) -> Result<()> {
+        let oids = oids
+            .iter()
+            .map(|oid| oid.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        // Arrays are named by their element type so its modifier can be decoded
+        let query = format!(
+            "SELECT t.oid, n.nspname, t.typname, e.typname IS NOT NULL, en.nspname, e.typname \
+             FROM pg_type t JOIN pg_namespace n ON n.oid = t.typnamespace \
+             LEFT JOIN pg_type e ON e.oid = t.typelem AND t.typcategory = 'A' \
+             LEFT JOIN pg_namespace en ON en.oid = e.typnamespace \
+             WHERE t.oid IN ({});",
+            oids
+        );
+        let result = self.query(&query)?;
+        for row in 0..result.ntuples() {
+            let Some(oid) = result.getvalue(row, 0).and_then(|oid| oid.parse().ok()) else {
+                continue;
+            };
+            let array = result.getvalue(row, 3).as_deref() == Some("t");
+            let (namespace, type_name) = if array { (4, 5) } else { (1, 2) };
+            let namespace = result.getvalue(row, namespace).unwrap_or_default();
+            let type_name = result.getvalue(row, type_name).unwrap_or_default();
+            let name = if namespace == "pg_catalog" {
+                type_name
+            } else {
+                format!("{}.{}", namespace, type_name)
+            };
+            self.types.insert(oid, CatalogType { name, array });
+        }
+        debug!("Looked up {} column types", result.ntuples());
+        Ok(())
+    }
+
+    /// Status of the streaming slot
+    pub fn slot_status(&mut self) -> Result<Option<SlotStatus>> {
+        let slot_name = self.slot_name.clone();
+        let result = slot_status(self.connection()?, Some(&slot_name));
+        self.forget_on_error(result)
+            .map(|slots| slots.into_iter().next())
+    }
+
+    /// WAL the server retains for the streaming slot
+    pub fn wal_retention(&mut self) -> Result<Option<WalRetention>> {
+        let slot_name = self.slot_name.clone();
+        let result = wal_retention(self.connection()?, &slot_name);
+        self.forget_on_error(result)
+    }
+
+    /// Log the slot's status once the status interval has passed
+    pub fn maybe_report_slot(&mut self) {
+        if self.status_interval.is_zero() || self.last_status.elapsed() < self.status_interval {
+            return;
+        }
+        self.last_status = Instant::now();
+        match self.slot_status() {
+            Ok(Some(status)) => status.log(),
+            Ok(None) => warn!("Replication slot {} no longer exists", self.slot_name),
+            Err(e) => warn!("Failed to query slot status: {}", e),
+        }
+    }
+
+    fn connection(&mut self) -> Result<&PGConnection> {
+        if self.connection.is_none() {
+            debug!("Reopening control connection");
+            self.connection = Some(open(&self.conninfo)?);
+        }
+        self.connection
+            .as_ref()
+            .ok_or_else(|| ReplicationError::connection("Control connection is not open"))
+    }
+
+    fn query(&mut self, query: &str) -> Result<PGResult> {
+        let result = self.connection()?.exec(query).and_then(|result| {
+            if result.is_ok() {
+                Ok(result)
+            } else {
+                Err(ReplicationError::protocol(format!(
+                    "Catalog query failed: {}",
+                    result.error_message()
+                )))
+            }
+        });
+        self.forget_on_error(result)
+    }
+
+    /// Drop the connection after a failure so the next query starts on a fresh one
+    fn forget_on_error<T>(&mut self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+}
+
+/// Connect and apply the session settings
+fn open(conninfo: &str) -> Result<PGConnection> {
+    let connection = PGConnection::connect(conninfo)?;
+    let result = connection.exec(SESSION_SETTINGS)?;
+    if !result.is_ok() {
+        return Err(ReplicationError::connection(format!(
+            "Failed to configure control connection: {}",
+            result.error_message()
+        )));
+    }
+    Ok(connection)
+}
+
+/// A type name with its decoded type modifier, such as varchar(255) or numeric(10,2)
+///
+/// Follows the typmod encoding of the built-in types; other types, and a modifier of -1,
+/// keep the bare name.
+pub fn type_with_modifier(type_name: &str, typmod: i32) -> String {
+    const VARHDRSZ: i32 = 4;
+    if typmod < 0 {
+        return type_name.to_string();
+    }
+    let modifier = match type_name {
+        "varchar" | "bpchar" if typmod >= VARHDRSZ => format!("({})", typmod - VARHDRSZ),
+        "numeric" if typmod >= VARHDRSZ => {
+            let typmod = typmod - VARHDRSZ;
+            let precision = (typmod >> 16) & 0xFFFF;
+            // The scale is an 11-bit signed value since PostgreSQL 15
+            let scale = ((typmod & 0x7FF) ^ 1024) - 1024;
+            format!("({},{})", precision, scale)
+        }
+        "bit" | "varbit" | "timestamp" | "timestamptz" | "time" | "timetz" => {
+            format!("({})", typmod)
+        }
+        // The upper bits hold the fields of an interval, the lower ones its precision
+        "interval" if typmod & 0xFFFF != 0xFFFF => format!("({})", typmod & 0xFFFF),
+        _ => String::new(),
+    };
+    format!("{}{}", type_name, modifier)
+}
+
+/// The replication connection string with replication turned off; the last setting wins
+pub fn regular_conninfo(conninfo: &str) -> String {
+    let uri = conninfo.starts_with("postgres://") || conninfo.starts_with("postgresql://");
+    if uri {
+        let separator = if conninfo.contains('?') { '&' } else { '?' };
+        format!("{}{}replication=false", conninfo, separator)
+    } else {
+        format!("{} replication=false", conninfo.trim_end())
+    }
+}
@@ -0,0 +1,180 @@
+//! Local control socket
+//! A Unix socket accepting one command per line: `pause`, `resume`, `status` and `feedback-now`,
+//! each optionally followed by a source name to address one of several sources. A paused stream
+//! stops reading changes but keeps answering the server, so the slot's confirmed position holds
+//! still until it is resumed. Every command is answered with a line starting with `OK` or `ERR`.
+
+use crate::health::HealthState;
+use crate::types::Lsn;
+use serde_json::{json, Map, Value};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info};
+
+/// Requests from the control socket to a streaming loop
+#[derive(Debug, Default)]
+pub struct StreamControl {
+    paused: AtomicBool,
+    feedback_requested: AtomicBool,
+    /// Flushed position last reported to the server
+    confirmed_lsn: AtomicU64,
+}
+
+impl StreamControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Pause or resume, returning whether the state changed
+    pub fn set_paused(&self, paused: bool) -> bool {
+        self.paused.swap(paused, Ordering::Relaxed) != paused
+    }
+
+    /// Ask the loop to send a status update to the server right away
+    pub fn request_feedback(&self) {
+        self.feedback_requested.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether feedback was requested since the last call
+    pub fn take_feedback_request(&self) -> bool {
+        self.feedback_requested.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn set_confirmed_lsn(&self, lsn: Lsn) {
+        self.confirmed_lsn.store(lsn.value(), Ordering::Relaxed);
+    }
+
+    pub fn confirmed_lsn(&self) -> Lsn {
+        Lsn(self.confirmed_lsn.load(Ordering::Relaxed))
+    }
+}
+
+/// Streams addressed by the socket, each named by its source when there are several
+pub type ControlSources = Vec<(Option<String>, Arc<StreamControl>, Arc<HealthState>)>;
+
+/// Serve the control socket until the process exits, replacing a socket left by an earlier run
+pub async fn serve(
+    path: &Path,
+    sources: ControlSources,
+    liveness_timeout: Duration,
+) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    // Pausing holds back the slot, so only the owner may connect
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Control socket listening on {}", path.display());
+    let sources = Arc::new(sources);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let sources = sources.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &sources, liveness_timeout).await {
+                debug!("Control socket connection failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Answer the commands of one connection until it closes
+async fn respond(
+    stream: UnixStream,
+    sources: &ControlSources,
+    liveness_timeout: Duration,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match execute(line.trim(), sources, liveness_timeout) {
+            Ok(reply) => format!("OK {}\n", reply),
+            Err(reply) => format!("ERR {}\n", reply),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn execute(
+    line: &str,
+    sources: &ControlSources,
+    liveness_timeout: Duration,
+) -> Result<String, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let source = words.next();
+    let selected: Vec<_> = sources
+        .iter()
+        .filter(|(name, _, _)| source.is_none() || name.as_deref() == source)
+        .collect();
+    if selected.is_empty() {
+        return Err(format!("unknown source {}", source.unwrap_or_default()));
+    }
+    let target = source
+        .map(|source| format!(" {}", source))
+        .unwrap_or_default();
+
+    match command {
+        "pause" | "resume" => {
+            let paused = command == "pause";
+            let action = if paused { "paused" } else { "resumed" };
+            for (name, control, _) in &selected {
+                if !control.set_paused(paused) {
+                    continue;
+                }
+                match name {
+                    Some(name) => info!("Streaming of {} {} over the control socket", name, action),
+                    None => info!("Streaming {} over the control socket", action),
+                }
+            }
+            Ok(format!("{}{}", action, target))
+        }
+        "feedback-now" => {
+            for (_, control, _) in &selected {
+                control.request_feedback();
+            }
+            Ok(format!("feedback requested{}", target))
+        }
+        "status" => {
+            let status = |control: &StreamControl, health: &HealthState| {
+                let mut status = match serde_json::to_value(health.report(liveness_timeout)) {
+                    Ok(Value::Object(status)) => status,
+                    _ => Map::new(),
+                };
+                status.insert("paused".to_string(), json!(control.is_paused()));
+                status.insert(
+                    "confirmed_lsn".to_string(),
+                    json!(control.confirmed_lsn().to_string()),
+                );
+                Value::Object(status)
+            };
+            let reply = match selected.as_slice() {
+                [(None, control, health)] => status(control, health),
+                selected => Value::Object(
+                    selected
+                        .iter()
+                        .map(|(name, control, health)| {
+                            (name.clone().unwrap_or_default(), status(control, health))
+                        })
+                        .collect(),
+                ),
+            };
+            Ok(reply.to_string())
+        }
+        "" => Err("empty command".to_string()),
+        command => Err(format!(
+            "unknown command {}, expected pause, resume, status or feedback-now",
+            command
+        )),
+    }
+}
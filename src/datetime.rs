@@ -0,0 +1,562 @@
+//! Date and time values in text form
+//! The walsender formats date, time, timestamp, timestamptz and interval values with its session's
+//! DateStyle and IntervalStyle. The parsers here read every style the server can produce into
+//! chrono types: ISO, SQL, Postgres and German dates tell themselves apart by their shape, so
+//! only the day and month order of DateStyle is needed, for SQL and Postgres dates such as
+//! `01/02/2024`. Normalized values are rendered in ISO form with timestamptz in the configured
+//! display time zone.
+
+use crate::errors::{ReplicationError, Result};
+use crate::utils::Oid;
+use chrono::{
+    DateTime, Datelike, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc,
+};
+use std::fmt;
+use std::str::FromStr;
+
+const DATEOID: Oid = 1082;
+const TIMEOID: Oid = 1083;
+const TIMESTAMPOID: Oid = 1114;
+const TIMESTAMPTZOID: Oid = 1184;
+const INTERVALOID: Oid = 1186;
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+const MICROS_PER_MINUTE: i64 = 60 * MICROS_PER_SECOND;
+const MICROS_PER_HOUR: i64 = 60 * MICROS_PER_MINUTE;
+const MICROS_PER_DAY: i64 = 24 * MICROS_PER_HOUR;
+
+/// Output format of DateStyle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DateFormat {
+    #[default]
+    Iso,
+    Sql,
+    Postgres,
+    German,
+}
+
+/// Order of day and month in SQL and Postgres dates; ISO dates are always year first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DateOrder {
+    Ymd,
+    Dmy,
+    #[default]
+    Mdy,
+}
+
+/// A session's DateStyle, e.g. `ISO, MDY`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DateStyle {
+    pub format: DateFormat,
+    pub order: DateOrder,
+}
+
+impl FromStr for DateStyle {
+    type Err = ReplicationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut style = DateStyle::default();
+        for part in s.split([',', ' ']).filter(|part| !part.is_empty()) {
+            match part.to_lowercase().as_str() {
+                "iso" => style.format = DateFormat::Iso,
+                "sql" => style.format = DateFormat::Sql,
+                "postgres" => style.format = DateFormat::Postgres,
+                "german" => style.format = DateFormat::German,
+                "ymd" => style.order = DateOrder::Ymd,
+                "dmy" | "euro" | "european" => style.order = DateOrder::Dmy,
+                "mdy" | "us" | "noneuro" | "noneuropean" => style.order = DateOrder::Mdy,
+                _ => {
+                    return Err(ReplicationError::config(format!(
+                        "Invalid DateStyle: {}",
+                        s
+                    )))
+                }
+            }
+        }
+        Ok(style)
+    }
+}
+
+impl fmt::Display for DateStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = match self.format {
+            DateFormat::Iso => "ISO",
+            DateFormat::Sql => "SQL",
+            DateFormat::Postgres => "Postgres",
+            DateFormat::German => "German",
+        };
+        let order = match self.order {
+            DateOrder::Ymd => "YMD",
+            DateOrder::Dmy => "DMY",
+            DateOrder::Mdy => "MDY",
+        };
+        write!(f, "{}, {}", format, order)
+    }
+}
+
+/// An interval as PostgreSQL stores it; months and days are kept apart from the time because
+/// their length varies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Interval {
+    pub months: i32,
+    pub days: i32,
+    pub micros: i64,
+}
+
+impl Interval {
+    /// The interval as a fixed duration, None when it has months, whose length varies; days
+    /// count as 24 hours
+    pub fn duration(&self) -> Option<chrono::Duration> {
+        if self.months != 0 {
+            return None;
+        }
+        Some(chrono::Duration::microseconds(
+            i64::from(self.days) * MICROS_PER_DAY + self.micros,
+        ))
+    }
+}
+
+/// ISO 8601 form, like IntervalStyle `iso_8601`, e.g. `P1Y2M3DT4H5M6.5S`
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Interval::default() {
+            return write!(f, "PT0S");
+        }
+        write!(f, "P")?;
+        for (value, unit) in [(self.months / 12, 'Y'), (self.months % 12, 'M')] {
+            if value != 0 {
+                write!(f, "{}{}", value, unit)?;
+            }
+        }
+        if self.days != 0 {
+            write!(f, "{}D", self.days)?;
+        }
+        if self.micros == 0 {
+            return Ok(());
+        }
+        write!(f, "T")?;
+        let hours = self.micros / MICROS_PER_HOUR;
+        let minutes = self.micros % MICROS_PER_HOUR / MICROS_PER_MINUTE;
+        let micros = self.micros % MICROS_PER_MINUTE;
+        if hours != 0 {
+            write!(f, "{}H", hours)?;
+        }
+        if minutes != 0 {
+            write!(f, "{}M", minutes)?;
+        }
+        if micros != 0 {
+            let sign = if micros < 0 { "-" } else { "" };
+            let micros = micros.abs();
+            write!(
+                f,
+                "{}{}{}S",
+                sign,
+                micros / MICROS_PER_SECOND,
+                fraction(micros % MICROS_PER_SECOND)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Time zone timestamptz values are shown in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayTimeZone {
+    Utc,
+    /// The time zone of the machine running the checker
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl DisplayTimeZone {
+    fn convert(&self, time: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        match self {
+            DisplayTimeZone::Utc => time.with_timezone(&Utc).fixed_offset(),
+            DisplayTimeZone::Local => time.with_timezone(&Local).fixed_offset(),
+            DisplayTimeZone::Fixed(offset) => time.with_timezone(offset),
+        }
+    }
+}
+
+impl FromStr for DisplayTimeZone {
+    type Err = ReplicationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "utc" | "z" => Ok(DisplayTimeZone::Utc),
+            "local" => Ok(DisplayTimeZone::Local),
+            _ => match parse_numeric_offset(s) {
+                Some(offset) => Ok(DisplayTimeZone::Fixed(offset)),
+                None => Err(ReplicationError::config(format!(
+                    "Invalid display time zone: {}. Valid values are: utc, local, or an offset such as +02:00",
+                    s
+                ))),
+            },
+        }
+    }
+}
+
+/// Parse a date in any DateStyle, e.g. `2024-01-02`, `01/02/2024`, `02.01.2024` or
+/// `0044-03-15 BC`; None for `infinity` and text that is not a date
+pub fn parse_date(text: &str, order: DateOrder) -> Option<NaiveDate> {
+    let (text, bc) = split_bc(text.trim());
+    date_from(text, order, bc)
+}
+
+/// Parse a time of day, e.g. `07:37:16.5`; `24:00:00` has no chrono form
+pub fn parse_time(text: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(text.trim(), "%H:%M:%S%.f").ok()
+}
+
+/// Parse a timestamp without time zone in any DateStyle, e.g. `1997-12-17 07:37:16`,
+/// `12/17/1997 07:37:16.00`, `Wed Dec 17 07:37:16 1997` or `17.12.1997 07:37:16.00`
+pub fn parse_timestamp(text: &str, order: DateOrder) -> Option<NaiveDateTime> {
+    let (text, bc) = split_bc(text.trim());
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    naive_datetime(&tokens, order, bc)
+}
+
+/// Parse a timestamp with time zone in any DateStyle, e.g. `1997-12-17 07:37:16-08`,
+/// `12/17/1997 07:37:16.00 PST` or `Wed Dec 17 07:37:16 1997 PST`. Zones written as an
+/// abbreviation are only known for common abbreviations, such as UTC, CET or PST.
+pub fn parse_timestamptz(text: &str, order: DateOrder) -> Option<DateTime<FixedOffset>> {
+    let (text, bc) = split_bc(text.trim());
+    let mut tokens: Vec<&str> = text.split_whitespace().collect();
+    let offset = match tokens.len() {
+        // ISO: the offset follows the time, e.g. 07:37:16.5+05:30
+        2 => {
+            let time = tokens[1];
+            let at = time.find(['+', '-'])?;
+            tokens[1] = &time[..at];
+            parse_numeric_offset(&time[at..])?
+        }
+        // SQL and German with the zone after the time, Postgres after the year
+        3 | 6 => parse_offset(tokens.pop()?)?,
+        _ => return None,
+    };
+    let naive = naive_datetime(&tokens, order, bc)?;
+    offset.from_local_datetime(&naive).single()
+}
+
+/// Parse an interval in any IntervalStyle, e.g. `1 year 2 mons 3 days 04:05:06`,
+/// `@ 1 day 2 hours ago`, `1-2 3 4:05:06` or `P1Y2M3DT4H5M6S`
+pub fn parse_interval(text: &str) -> Option<Interval> {
+    let text = text.trim();
+    if let Some(iso) = text.strip_prefix('P') {
+        return parse_iso_interval(iso);
+    }
+    if text.starts_with('@') || text.chars().any(|c| c.is_ascii_alphabetic()) {
+        return parse_postgres_interval(text);
+    }
+    parse_sql_interval(text)
+}
+
+/// A temporal value in ISO form, with timestamptz in the display time zone; None for other
+/// types, infinities and text that does not parse, which is shown as sent
+pub fn normalize(
+    type_oid: Oid,
+    text: &str,
+    order: DateOrder,
+    zone: DisplayTimeZone,
+) -> Option<String> {
+    match type_oid {
+        DATEOID => parse_date(text, order).map(|date| with_era(&date, "")),
+        TIMEOID => parse_time(text).map(|time| time.format("%H:%M:%S%.f").to_string()),
+        TIMESTAMPOID => parse_timestamp(text, order)
+            .map(|time| with_era(&time.date(), time.format(" %H:%M:%S%.f"))),
+        TIMESTAMPTZOID => parse_timestamptz(text, order).map(|time| {
+            let time = zone.convert(time);
+            with_era(&time.date_naive(), time.format(" %H:%M:%S%.f%:z"))
+        }),
+        INTERVALOID => parse_interval(text).map(|interval| interval.to_string()),
+        _ => None,
+    }
+}
+
+/// `YYYY-MM-DD` followed by the time, with years before 1 AD as PostgreSQL writes them:
+/// `0044-03-15 12:00:00 BC`
+fn with_era(date: &NaiveDate, time: impl fmt::Display) -> String {
+    if date.year() <= 0 {
+        format!("{:04}-{}{} BC", 1 - date.year(), date.format("%m-%d"), time)
+    } else {
+        format!("{}{}", date.format("%Y-%m-%d"), time)
+    }
+}
+
+fn split_bc(text: &str) -> (&str, bool) {
+    match text.strip_suffix(" BC") {
+        Some(text) => (text, true),
+        None => (text, false),
+    }
+}
+
+/// A date of ISO, SQL, German or Postgres style, without its era
+fn date_from(text: &str, order: DateOrder, bc: bool) -> Option<NaiveDate> {
+    let parts: Vec<&str> = text.split(['-', '/', '.']).collect();
+    let [first, second, third] = parts.as_slice() else {
+        return None;
+    };
+    // ISO writes the year first with at least four digits; German is always day first
+    let (year, month, day) = if first.len() >= 3 {
+        (first, second, third)
+    } else if text.contains('.') || order == DateOrder::Dmy {
+        (third, second, first)
+    } else {
+        (third, first, second)
+    };
+    make_date(
+        year.parse().ok()?,
+        month.parse().ok()?,
+        day.parse().ok()?,
+        bc,
+    )
+}
+
+fn make_date(year: i32, month: u32, day: u32, bc: bool) -> Option<NaiveDate> {
+    // 1 BC is year 0 in chrono's proleptic calendar
+    let year = if bc { 1 - year } else { year };
+    NaiveDate::from_ymd_opt(year, month, day)
+}
+
+/// A date and time from the whitespace-separated parts of a timestamp without its zone
+fn naive_datetime(tokens: &[&str], order: DateOrder, bc: bool) -> Option<NaiveDateTime> {
+    let (date, time) = match tokens {
+        [date, time] => (date_from(date, order, bc)?, parse_time(time)?),
+        // Postgres style: `Wed Dec 17 07:37:16 1997`, or `Wed 17 Dec ...` when day first
+        [_day_of_week, first, second, time, year] => {
+            let (month, day) = if first.chars().all(|c| c.is_ascii_alphabetic()) {
+                (first, second)
+            } else {
+                (second, first)
+            };
+            let date = make_date(
+                year.parse().ok()?,
+                month_number(month)?,
+                day.parse().ok()?,
+                bc,
+            )?;
+            (date, parse_time(time)?)
+        }
+        _ => return None,
+    };
+    Some(date.and_time(time))
+}
+
+fn month_number(name: &str) -> Option<u32> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let name = name.to_lowercase();
+    MONTHS
+        .iter()
+        .position(|month| name.starts_with(month))
+        .map(|index| index as u32 + 1)
+}
+
+/// A numeric offset or a common zone abbreviation
+fn parse_offset(zone: &str) -> Option<FixedOffset> {
+    if zone.starts_with(['+', '-']) {
+        return parse_numeric_offset(zone);
+    }
+    let hours_minutes = match zone.to_uppercase().as_str() {
+        "UTC" | "GMT" | "UT" | "Z" | "WET" => (0, 0),
+        "BST" | "CET" | "MET" | "WEST" => (1, 0),
+        "CEST" | "MEST" | "EET" | "SAST" => (2, 0),
+        "EEST" | "MSK" => (3, 0),
+        "HKT" | "AWST" => (8, 0),
+        "JST" | "KST" => (9, 0),
+        "ACST" => (9, 30),
+        "AEST" => (10, 0),
+        "ACDT" => (10, 30),
+        "AEDT" => (11, 0),
+        "NZST" => (12, 0),
+        "NZDT" => (13, 0),
+        "HST" => (-10, 0),
+        "AKST" => (-9, 0),
+        "AKDT" | "PST" => (-8, 0),
+        "PDT" | "MST" => (-7, 0),
+        "MDT" | "CST" => (-6, 0),
+        "CDT" | "EST" => (-5, 0),
+        "EDT" => (-4, 0),
+        _ => return None,
+    };
+    FixedOffset::east_opt(hours_minutes.0 * 3600 + hours_minutes.1 * 60)
+}
+
+/// `+05`, `-0330`, `+05:30` or `-03:30:15`
+fn parse_numeric_offset(zone: &str) -> Option<FixedOffset> {
+    let (sign, digits) = match zone.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    let parts: Vec<&str> = if digits.contains(':') {
+        digits.split(':').collect()
+    } else if digits.len() == 4 {
+        vec![&digits[..2], &digits[2..]]
+    } else {
+        vec![digits]
+    };
+    if parts.is_empty() || parts.len() > 3 {
+        return None;
+    }
+    let mut seconds = 0;
+    for (part, scale) in parts.iter().zip([3600, 60, 1]) {
+        if part.is_empty() || !part.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        seconds += part.parse::<i32>().ok()? * scale;
+    }
+    FixedOffset::east_opt(sign * seconds)
+}
+
+/// `[-]HH:MM:SS[.ffffff]` as signed microseconds
+fn parse_clock(text: &str) -> Option<i64> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let mut parts = text.split(':');
+    let hours: i64 = parts.next()?.parse().ok()?;
+    let minutes: i64 = parts.next()?.parse().ok()?;
+    let seconds = parse_seconds(parts.next().unwrap_or("0"))?;
+    if parts.next().is_some() {
+        return None;
+    }
+    let micros = hours * MICROS_PER_HOUR + minutes * MICROS_PER_MINUTE + seconds;
+    Some(if negative { -micros } else { micros })
+}
+
+/// Seconds with up to six fractional digits as signed microseconds
+fn parse_seconds(text: &str) -> Option<i64> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+    if fraction.len() > 6 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let whole: i64 = if whole.is_empty() {
+        0
+    } else {
+        whole.parse().ok()?
+    };
+    let fraction: i64 = format!("{:0<6}", fraction).parse().ok()?;
+    let micros = whole * MICROS_PER_SECOND + fraction;
+    Some(if negative { -micros } else { micros })
+}
+
+/// `postgres` and `postgres_verbose` styles: `1 year 2 mons -3 days +04:05:06` or
+/// `@ 1 year 2 mons 3 days 4 hours 5 mins 6 secs ago`
+fn parse_postgres_interval(text: &str) -> Option<Interval> {
+    let text = text.strip_prefix('@').unwrap_or(text);
+    let mut interval = Interval::default();
+    // `postgres_verbose` writes a zero interval as `@ 0`, without a unit
+    if text.trim() == "0" {
+        return Some(interval);
+    }
+    let mut tokens = text.split_whitespace().peekable();
+    let mut ago = false;
+    while let Some(token) = tokens.next() {
+        if token == "ago" {
+            ago = true;
+            continue;
+        }
+        if token.contains(':') {
+            interval.micros += parse_clock(token)?;
+            continue;
+        }
+        let unit = tokens.next()?.to_lowercase();
+        let unit = unit.trim_end_matches('s');
+        match unit {
+            "year" => interval.months += token.parse::<i32>().ok()? * 12,
+            "mon" => interval.months += token.parse::<i32>().ok()?,
+            "day" => interval.days += token.parse::<i32>().ok()?,
+            "hour" => interval.micros += token.parse::<i64>().ok()? * MICROS_PER_HOUR,
+            "min" => interval.micros += token.parse::<i64>().ok()? * MICROS_PER_MINUTE,
+            "sec" => interval.micros += parse_seconds(token)?,
+            _ => return None,
+        }
+    }
+    if ago {
+        interval = Interval {
+            months: -interval.months,
+            days: -interval.days,
+            micros: -interval.micros,
+        };
+    }
+    Some(interval)
+}
+
+/// `sql_standard` style: `1-2 3 4:05:06`, where one leading `-` negates every field unless the
+/// fields carry their own signs, as in `+1-2 -3 +4:05:06`
+fn parse_sql_interval(text: &str) -> Option<Interval> {
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    if tokens.is_empty() {
+        return None;
+    }
+    let negate_all = tokens[0].starts_with('-')
+        && !tokens[1..]
+            .iter()
+            .any(|token| token.starts_with(['+', '-']));
+    let mut interval = Interval::default();
+    for (i, token) in tokens.iter().enumerate() {
+        let token = if negate_all && i == 0 {
+            &token[1..]
+        } else {
+            token
+        };
+        let (negative, unsigned) = match token.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, token.strip_prefix('+').unwrap_or(token)),
+        };
+        let sign = if negative != negate_all { -1 } else { 1 };
+        if unsigned.contains(':') {
+            interval.micros += i64::from(sign) * parse_clock(unsigned)?;
+        } else if let Some((years, months)) = unsigned.split_once('-') {
+            interval.months +=
+                sign * (years.parse::<i32>().ok()? * 12 + months.parse::<i32>().ok()?);
+        } else {
+            interval.days += sign * unsigned.parse::<i32>().ok()?;
+        }
+    }
+    Some(interval)
+}
+
+/// `iso_8601` style after the `P`: `1Y2M3DT4H5M6.5S`, each field with its own sign
+fn parse_iso_interval(text: &str) -> Option<Interval> {
+    let mut interval = Interval::default();
+    let mut in_time = false;
+    let mut number = String::new();
+    for c in text.chars() {
+        match c {
+            'T' => in_time = true,
+            '0'..='9' | '.' | '-' | '+' => number.push(c),
+            unit => {
+                let value = std::mem::take(&mut number);
+                match (in_time, unit) {
+                    (false, 'Y') => interval.months += value.parse::<i32>().ok()? * 12,
+                    (false, 'M') => interval.months += value.parse::<i32>().ok()?,
+                    (false, 'W') => interval.days += value.parse::<i32>().ok()? * 7,
+                    (false, 'D') => interval.days += value.parse::<i32>().ok()?,
+                    (true, 'H') => interval.micros += value.parse::<i64>().ok()? * MICROS_PER_HOUR,
+                    (true, 'M') => {
+                        interval.micros += value.parse::<i64>().ok()? * MICROS_PER_MINUTE
+                    }
+                    (true, 'S') => interval.micros += parse_seconds(&value)?,
+                    _ => return None,
+                }
+            }
+        }
+    }
+    number.is_empty().then_some(interval)
+}
+
+/// Fractional seconds with trailing zeros removed, empty for whole seconds
+fn fraction(micros: i64) -> String {
+    if micros == 0 {
+        return String::new();
+    }
+    format!(".{:06}", micros).trim_end_matches('0').to_string()
+}
@@ -0,0 +1,239 @@
+//! Dead-letter files for events a sink failed to deliver
+//! With `on-error = "dead-letter"`, a batch a sink still fails to deliver after its retries is
+//! appended to the sink's dead-letter file, one JSON line per event with the error, and the sink
+//! carries on with the next batch. The events count as delivered once they are in the file, so
+//! the slot moves past them. `replay-dlq` sends the events of a dead-letter file to a sink again
+//! and leaves only those that still fail in the file.
+
+use crate::errors::{ReplicationError, Result};
+use crate::sink::{EventSink, SinkConfig, SinkEvent};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// An event a sink failed to deliver, as a line of a dead-letter file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// Sink the event was meant for
+    pub sink: String,
+    pub failed_at: DateTime<Utc>,
+    pub error: String,
+    pub error_kind: String,
+    pub error_code: String,
+    /// Delivery attempts made, the first one included
+    pub attempts: u32,
+    /// The event as the sink would have received it, kept verbatim
+    pub event: Box<RawValue>,
+}
+
+/// Appends failed batches to a dead-letter file
+pub struct DeadLetterWriter {
+    path: PathBuf,
+    file: File,
+}
+
+impl DeadLetterWriter {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| file_error("open", &path, e))?;
+        Ok(Self { path, file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append the batch and sync the file, so the events are safe before they are acknowledged
+    pub fn write(
+        &mut self,
+        sink: &str,
+        batch: &[SinkEvent],
+        error: &ReplicationError,
+        attempts: u32,
+    ) -> Result<()> {
+        let failed_at = Utc::now();
+        let mut lines = Vec::new();
+        for event in batch {
+            let letter = DeadLetter {
+                sink: sink.to_string(),
+                failed_at,
+                error: error.to_string(),
+                error_kind: error.kind().to_string(),
+                error_code: error.code().to_string(),
+                attempts,
+                event: serde_json::value::to_raw_value(event).map_err(encode_error)?,
+            };
+            serde_json::to_writer(&mut lines, &letter).map_err(encode_error)?;
+            lines.push(b'\n');
+        }
+        // One write per batch keeps a batch's lines together in the file
+        self.file
+            .write_all(&lines)
+            .and_then(|()| self.file.sync_data())
+            .map_err(|e| file_error("write", &self.path, e))
+    }
+}
+
+/// Read every dead letter of a file
+pub fn read_dead_letters(path: &Path) -> Result<Vec<DeadLetter>> {
+    let file = File::open(path).map_err(|e| file_error("open", path, e))?;
+    let mut letters = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| file_error("read", path, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let letter = serde_json::from_str(&line).map_err(|e| {
+            ReplicationError::dead_letter(format!(
+                "Line {} of dead-letter file {} is not a dead letter: {}",
+                index + 1,
+                path.display(),
+                e
+            ))
+        })?;
+        letters.push(letter);
+    }
+    Ok(letters)
+}
+
+/// Outcome of replaying a dead-letter file
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub delivered: usize,
+    /// Events left in the file because their delivery failed again
+    pub remaining: usize,
+}
+
+/// Send the events of a dead-letter file to the sinks they were meant for, or all to `target`
+///
+/// Events are sent in file order, in batches of the sink's batch size. Replay stops at the first
+/// batch that fails; the file is rewritten with the events from that batch on, and removed once
+/// every event was delivered, so running it again picks up where it stopped.
+pub async fn replay_dead_letters(
+    path: &Path,
+    sinks: &[SinkConfig],
+    target: Option<&str>,
+) -> Result<ReplaySummary> {
+    let letters = read_dead_letters(path)?;
+    info!(
+        "Replaying {} events from dead-letter file {}",
+        letters.len(),
+        path.display()
+    );
+
+    let mut opened: HashMap<String, (Box<dyn EventSink>, usize)> = HashMap::new();
+    let mut delivered = 0;
+    let mut failure = None;
+    while delivered < letters.len() {
+        let name = target.unwrap_or(&letters[delivered].sink).to_string();
+        let (sink, batch_size) = match opened.entry(name.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let config = sinks
+                    .iter()
+                    .find(|sink| sink.name() == name)
+                    .ok_or_else(|| {
+                        ReplicationError::config(format!(
+                            "No [[sink]] named {} in the config file",
+                            name
+                        ))
+                    })?;
+                // Replayed events belong to no transaction, so they have no segment to go to
+                if config.segmented() {
+                    return Err(ReplicationError::config(format!(
+                        "Sink {} writes LSN-named segments, dead letters cannot be replayed to it",
+                        name
+                    )));
+                }
+                entry.insert((config.open()?, config.options().batch_size))
+            }
+        };
+
+        // Consecutive events for the same sink go out together
+        let batch: Vec<SinkEvent> = letters[delivered..]
+            .iter()
+            .take(*batch_size)
+            .take_while(|letter| target.is_some() || letter.sink == name)
+            .map(|letter| SinkEvent::Replayed(letter.event.clone()))
+            .collect();
+        let result = async {
+            sink.write(&batch).await?;
+            sink.flush().await
+        }
+        .await;
+        if let Err(e) = result {
+            warn!(
+                error_kind = %e.kind(),
+                error_code = e.code(),
+                "Sink {} failed to take {} replayed events: {}",
+                name,
+                batch.len(),
+                e
+            );
+            failure = Some(e);
+            break;
+        }
+        delivered += batch.len();
+    }
+
+    let remaining = &letters[delivered..];
+    if remaining.is_empty() {
+        fs::remove_file(path).map_err(|e| file_error("remove", path, e))?;
+    } else {
+        rewrite(path, remaining)?;
+    }
+    info!(
+        "Delivered {} dead-lettered events, {} left in {}",
+        delivered,
+        remaining.len(),
+        path.display()
+    );
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(ReplaySummary {
+            delivered,
+            remaining: remaining.len(),
+        }),
+    }
+}
+
+/// Replace the file with the given letters, through a temporary file so it is never cut short
+fn rewrite(path: &Path, letters: &[DeadLetter]) -> Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let mut lines = Vec::new();
+    for letter in letters {
+        serde_json::to_writer(&mut lines, letter).map_err(encode_error)?;
+        lines.push(b'\n');
+    }
+    File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(&lines)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp_path, path))
+        .map_err(|e| file_error("rewrite", path, e))
+}
+
+fn file_error(action: &str, path: &Path, e: std::io::Error) -> ReplicationError {
+    ReplicationError::dead_letter(format!(
+        "Failed to {} dead-letter file {}: {}",
+        action,
+        path.display(),
+        e
+    ))
+}
+
+fn encode_error(e: serde_json::Error) -> ReplicationError {
+    ReplicationError::dead_letter(format!("Failed to encode dead letter: {}", e))
+}
@@ -0,0 +1,245 @@
+//! Transaction metadata attached to change events
+//! With the envelope enabled, every change record carries a `transaction` object naming its
+//! transaction, the slot it was read from and its position in the transaction, so consumers can
+//! regroup and order changes without tracking Begin and Commit themselves. Local receive and
+//! processing times and an estimate of the server's clock skew can be added next to the commit
+//! time for measuring latency between the hosts.
+
+use crate::errors::ReplicationError;
+use crate::types::Lsn;
+use crate::utils::Xid;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+
+/// Keepalives the clock skew is estimated over, so the estimate follows a drifting clock
+const CLOCK_SKEW_SAMPLES: usize = 64;
+
+/// The transaction a change belongs to, as far as it is known when the change is emitted
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TransactionInfo {
+    /// Top-level transaction ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xid: Option<Xid>,
+    /// Unknown for changes of a streamed transaction emitted before it commits, and with the
+    /// walstream backend, which does not report it at Begin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_lsn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_time: Option<DateTime<Utc>>,
+    pub slot: String,
+    /// Position of the change in its transaction, counting from 0
+    pub sequence: u64,
+    /// Whether the change was streamed before its transaction committed
+    pub streamed: bool,
+    /// Local time the message carrying the change was received, when enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<DateTime<Utc>>,
+    /// Local time the change was emitted, when enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub processed_at: Option<DateTime<Utc>>,
+    /// Estimated milliseconds the server's clock is ahead of the local one, when enabled and
+    /// a keepalive has been received
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_skew_ms: Option<f64>,
+}
+
+/// Local timestamps added to the envelope next to the commit time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EventTimestamps {
+    /// When the message carrying the change was received
+    pub receive: bool,
+    /// When the change was emitted
+    pub process: bool,
+    /// How far the server's clock is estimated to be ahead
+    pub clock_skew: bool,
+}
+
+impl EventTimestamps {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Fill in the enabled timestamps of an envelope
+    pub fn stamp(
+        &self,
+        info: &mut TransactionInfo,
+        received_at: DateTime<Utc>,
+        clock_skew_ms: Option<f64>,
+    ) {
+        if self.receive {
+            info.received_at = Some(received_at);
+        }
+        if self.process {
+            info.processed_at = Some(Utc::now());
+        }
+        if self.clock_skew {
+            info.clock_skew_ms = clock_skew_ms;
+        }
+    }
+}
+
+impl FromStr for EventTimestamps {
+    type Err = ReplicationError;
+
+    /// A comma-separated list of receive, process and clock-skew, or none
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut timestamps = Self::default();
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name.to_ascii_lowercase().as_str() {
+                "none" => {}
+                "receive" => timestamps.receive = true,
+                "process" => timestamps.process = true,
+                "clock-skew" | "clock_skew" => timestamps.clock_skew = true,
+                _ => {
+                    return Err(ReplicationError::config(format!(
+                        "Invalid event timestamp '{}', expected receive, process or clock-skew",
+                        name
+                    )))
+                }
+            }
+        }
+        Ok(timestamps)
+    }
+}
+
+/// Estimates how far the server's clock is ahead of the local one from keepalive send times
+///
+/// A keepalive arrives its network delay after it was sent, so the smallest difference between
+/// arrival and send time over the recent keepalives is the skew plus the shortest delay; the
+/// estimate errs toward the server being behind by that delay.
+#[derive(Debug, Default)]
+pub struct ClockSkew {
+    /// Arrival minus send time of the recent keepalives, in microseconds
+    samples: VecDeque<i64>,
+}
+
+impl ClockSkew {
+    pub fn observe(&mut self, send_time: DateTime<Utc>, received_at: DateTime<Utc>) {
+        let Some(delay) = (received_at - send_time).num_microseconds() else {
+            return;
+        };
+        if self.samples.len() == CLOCK_SKEW_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(delay);
+    }
+
+    /// Milliseconds the server's clock is ahead of the local one, None before any keepalive
+    pub fn estimate_ms(&self) -> Option<f64> {
+        self.samples
+            .iter()
+            .min()
+            .map(|delay| -(*delay as f64) / 1000.0)
+    }
+}
+
+/// Commit of the transaction between Begin and Commit, or of a streamed one being emitted
+#[derive(Debug)]
+struct Committed {
+    xid: Option<Xid>,
+    commit_lsn: Option<Lsn>,
+    commit_time: DateTime<Utc>,
+    sequence: u64,
+}
+
+/// Tracks the transactions being received to build the envelope of each change
+#[derive(Debug)]
+pub struct TransactionEnvelopes {
+    slot: String,
+    /// Transaction between Begin and Commit
+    current: Option<Committed>,
+    /// Streamed transaction committing while its buffered changes are emitted
+    committing: Option<(Xid, Committed)>,
+    /// Changes numbered so far of each streamed transaction keyed by its xid
+    streamed: HashMap<Xid, u64>,
+}
+
+impl TransactionEnvelopes {
+    pub fn new(slot: String) -> Self {
+        Self {
+            slot,
+            current: None,
+            committing: None,
+            streamed: HashMap::new(),
+        }
+    }
+
+    pub fn begin(&mut self, xid: Option<Xid>, commit_lsn: Option<Lsn>, commit_time: DateTime<Utc>) {
+        self.current = Some(Committed {
+            xid,
+            commit_lsn,
+            commit_time,
+            sequence: 0,
+        });
+    }
+
+    pub fn commit(&mut self) {
+        self.current = None;
+    }
+
+    /// Record the commit of a streamed transaction before its buffered changes are emitted
+    pub fn commit_streamed(&mut self, xid: Xid, commit_lsn: Lsn, commit_time: DateTime<Utc>) {
+        let sequence = self.streamed.remove(&xid).unwrap_or(0);
+        let committed = Committed {
+            xid: Some(xid),
+            commit_lsn: Some(commit_lsn),
+            commit_time,
+            sequence,
+        };
+        self.committing = Some((xid, committed));
+    }
+
+    /// Forget a streamed transaction once it committed or aborted
+    pub fn end_streamed(&mut self, xid: Xid) {
+        self.streamed.remove(&xid);
+        if self
+            .committing
+            .as_ref()
+            .is_some_and(|(committing, _)| *committing == xid)
+        {
+            self.committing = None;
+        }
+    }
+
+    /// Envelope of the next change, which belongs to the streamed top-level transaction
+    /// `streamed_xid` if set and otherwise to the transaction between Begin and Commit; None
+    /// outside a transaction
+    pub fn next(&mut self, streamed_xid: Option<Xid>) -> Option<TransactionInfo> {
+        let committed = match (streamed_xid, self.committing.as_mut()) {
+            // Every streamed change emitted meanwhile belongs to the committing transaction
+            (Some(_), Some((_, committed))) => committed,
+            (Some(xid), _) => {
+                let sequence = self.streamed.entry(xid).or_insert(0);
+                let info = TransactionInfo {
+                    xid: Some(xid),
+                    commit_lsn: None,
+                    commit_time: None,
+                    slot: self.slot.clone(),
+                    sequence: *sequence,
+                    streamed: true,
+                    received_at: None,
+                    processed_at: None,
+                    clock_skew_ms: None,
+                };
+                *sequence += 1;
+                return Some(info);
+            }
+            (None, _) => self.current.as_mut()?,
+        };
+        let info = TransactionInfo {
+            xid: committed.xid,
+            commit_lsn: committed.commit_lsn.map(|lsn| lsn.to_string()),
+            commit_time: Some(committed.commit_time),
+            slot: self.slot.clone(),
+            sequence: committed.sequence,
+            streamed: streamed_xid.is_some(),
+            received_at: None,
+            processed_at: None,
+            clock_skew_ms: None,
+        };
+        committed.sequence += 1;
+        Some(info)
+    }
+}
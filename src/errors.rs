@@ -1,8 +1,51 @@
 //! Comprehensive error types for PostgreSQL replication checker
-//! Provides structured error handling using thiserror for better error reporting
+//! Provides structured error handling using thiserror for better error reporting. Every error
+//! has a kind and a stable code for logs and metrics, and says whether retrying may succeed.
 
+use std::fmt;
 use thiserror::Error;
 
+/// Broad category of an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorKind {
+    /// The connection to the server failed or was lost
+    Connection,
+    /// The server refused a command or sent something unexpected
+    Protocol,
+    /// A message could not be decoded
+    Parse,
+    /// Invalid settings
+    Config,
+    /// Delivering changes to a sink or the apply target failed
+    Sink,
+    /// Checkpoint, capture, spill or dead-letter files could not be read or written
+    Storage,
+    /// Stopped on purpose by an alert or a transaction limit
+    Policy,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Connection => "connection",
+            ErrorKind::Protocol => "protocol",
+            ErrorKind::Parse => "parse",
+            ErrorKind::Config => "config",
+            ErrorKind::Sink => "sink",
+            ErrorKind::Storage => "storage",
+            ErrorKind::Policy => "policy",
+            ErrorKind::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 /// Main error type for the PostgreSQL replication checker application
 #[derive(Error, Debug)]
 pub enum ReplicationError {
@@ -35,6 +78,58 @@ pub enum ReplicationError {
     #[error("Buffer operation error: {message}")]
     BufferOperation { message: String },
 
+    /// Checkpoint persistence errors
+    #[error("Checkpoint error: {message}")]
+    Checkpoint { message: String },
+
+    /// Errors applying changes to the target database
+    #[error("Apply error: {message}")]
+    Apply { message: String },
+
+    /// Capture file read/write errors
+    #[error("Capture error: {message}")]
+    Capture { message: String },
+
+    /// Transaction spill file errors
+    #[error("Spill error: {message}")]
+    Spill { message: String },
+
+    /// Dead-letter file errors
+    #[error("Dead-letter error: {message}")]
+    DeadLetter { message: String },
+
+    /// Errors delivering change events to the webhook
+    #[error("Webhook error: {message}")]
+    Webhook { message: String, retryable: bool },
+
+    /// Parquet file write errors
+    #[error("Parquet error: {message}")]
+    Parquet { message: String },
+
+    /// Avro encoding and schema registry errors
+    #[error("Avro error: {message}")]
+    Avro { message: String },
+
+    /// S3 upload errors
+    #[error("S3 error: {message}")]
+    S3 { message: String, retryable: bool },
+
+    /// Errors of a sink configured with `[[sink]]` or added with `add_sink`
+    #[error("Sink error: {message}")]
+    Sink { message: String, retryable: bool },
+
+    /// A lag alert that stops the process with its own exit code
+    #[error("Alert: {message}")]
+    Alert { message: String, exit_code: i32 },
+
+    /// A transaction over its row or byte limit with the abort action
+    #[error("Transaction limit exceeded: {message}")]
+    TransactionLimit { message: String },
+
+    /// Initial table copy errors
+    #[error("Initial sync error: {message}")]
+    InitSync { message: String },
+
     /// Network/IO related errors
     #[error("Network IO error")]
     NetworkIO(#[from] std::io::Error),
@@ -47,6 +142,10 @@ pub enum ReplicationError {
     #[error("C string conversion error")]
     CStringConversion(#[from] std::ffi::NulError),
 
+    /// Errors of the pg-walstream backend
+    #[error("pg-walstream error: {0}")]
+    Walstream(#[from] pg_walstream::ReplicationError),
+
     /// Task execution errors for async operations
     #[error("Task execution error")]
     TaskExecution(#[from] tokio::task::JoinError),
@@ -117,4 +216,224 @@ impl ReplicationError {
             message: message.into(),
         }
     }
+
+    /// Create a checkpoint error
+    pub fn checkpoint<S: Into<String>>(message: S) -> Self {
+        Self::Checkpoint {
+            message: message.into(),
+        }
+    }
+
+    /// Create an apply error
+    pub fn apply<S: Into<String>>(message: S) -> Self {
+        Self::Apply {
+            message: message.into(),
+        }
+    }
+
+    /// Create a capture file error
+    pub fn capture<S: Into<String>>(message: S) -> Self {
+        Self::Capture {
+            message: message.into(),
+        }
+    }
+
+    /// Create a spill file error
+    pub fn spill<S: Into<String>>(message: S) -> Self {
+        Self::Spill {
+            message: message.into(),
+        }
+    }
+
+    /// Create a dead-letter file error
+    pub fn dead_letter<S: Into<String>>(message: S) -> Self {
+        Self::DeadLetter {
+            message: message.into(),
+        }
+    }
+
+    /// Create a webhook delivery error
+    pub fn webhook<S: Into<String>>(message: S) -> Self {
+        Self::Webhook {
+            message: message.into(),
+            retryable: false,
+        }
+    }
+
+    /// Create a webhook delivery error that sending again may resolve
+    pub fn webhook_retryable<S: Into<String>>(message: S) -> Self {
+        Self::Webhook {
+            message: message.into(),
+            retryable: true,
+        }
+    }
+
+    /// Create a Parquet file write error
+    pub fn parquet<S: Into<String>>(message: S) -> Self {
+        Self::Parquet {
+            message: message.into(),
+        }
+    }
+
+    /// Create an Avro encoding or schema registry error
+    pub fn avro<S: Into<String>>(message: S) -> Self {
+        Self::Avro {
+            message: message.into(),
+        }
+    }
+
+    /// Create an S3 sink error
+    pub fn s3<S: Into<String>>(message: S) -> Self {
+        Self::S3 {
+            message: message.into(),
+            retryable: false,
+        }
+    }
+
+    /// Create an S3 sink error that uploading again may resolve
+    pub fn s3_retryable<S: Into<String>>(message: S) -> Self {
+        Self::S3 {
+            message: message.into(),
+            retryable: true,
+        }
+    }
+
+    /// Create an event sink error
+    pub fn sink<S: Into<String>>(message: S) -> Self {
+        Self::Sink {
+            message: message.into(),
+            retryable: false,
+        }
+    }
+
+    /// Create an event sink error that writing the batch again may resolve
+    pub fn sink_retryable<S: Into<String>>(message: S) -> Self {
+        Self::Sink {
+            message: message.into(),
+            retryable: true,
+        }
+    }
+
+    /// Create an initial table copy error
+    pub fn init_sync<S: Into<String>>(message: S) -> Self {
+        Self::InitSync {
+            message: message.into(),
+        }
+    }
+
+    /// Create an error for a transaction over its size limit
+    pub fn transaction_limit<S: Into<String>>(message: S) -> Self {
+        Self::TransactionLimit {
+            message: message.into(),
+        }
+    }
+
+    /// Create a lag alert error that exits the process with `exit_code`
+    pub fn alert<S: Into<String>>(message: S, exit_code: i32) -> Self {
+        Self::Alert {
+            message: message.into(),
+            exit_code,
+        }
+    }
+
+    /// Exit code the process should stop with, for errors that set one
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            Self::Alert { exit_code, .. } => Some(*exit_code),
+            _ => None,
+        }
+    }
+
+    /// Category of the error
+    pub fn kind(&self) -> ErrorKind {
+        use pg_walstream::ReplicationError as Walstream;
+        match self {
+            Self::Connection { .. } | Self::NetworkIO(_) => ErrorKind::Connection,
+            Self::Protocol { .. } => ErrorKind::Protocol,
+            Self::MessageParsing { .. }
+            | Self::BufferOperation { .. }
+            | Self::StringConversion(_) => ErrorKind::Parse,
+            Self::Configuration { .. } | Self::MissingEnvVar(_) | Self::CStringConversion(_) => {
+                ErrorKind::Config
+            }
+            Self::Webhook { .. }
+            | Self::Parquet { .. }
+            | Self::Avro { .. }
+            | Self::S3 { .. }
+            | Self::Sink { .. }
+            | Self::Apply { .. } => ErrorKind::Sink,
+            Self::Checkpoint { .. }
+            | Self::Capture { .. }
+            | Self::Spill { .. }
+            | Self::DeadLetter { .. } => ErrorKind::Storage,
+            Self::Alert { .. } | Self::TransactionLimit { .. } => ErrorKind::Policy,
+            Self::Walstream(error) => match error {
+                Walstream::TransientConnection(_)
+                | Walstream::PermanentConnection(_)
+                | Walstream::ReplicationConnection(_)
+                | Walstream::Authentication(_)
+                | Walstream::Timeout(_)
+                | Walstream::Io(_) => ErrorKind::Connection,
+                Walstream::Protocol(_) | Walstream::ReplicationSlot(_) => ErrorKind::Protocol,
+                Walstream::Buffer(_) => ErrorKind::Parse,
+                Walstream::Config(_) | Walstream::StringConversion(_) => ErrorKind::Config,
+                Walstream::Cancelled(_) | Walstream::Generic(_) => ErrorKind::Other,
+            },
+            Self::InitSync { .. } | Self::TaskExecution(_) | Self::Other(_) => ErrorKind::Other,
+        }
+    }
+
+    /// Stable identifier of the error variant for logs and metrics
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Connection { .. } => "connection",
+            Self::Configuration { .. } => "configuration",
+            Self::MessageParsing { .. } => "message_parsing",
+            Self::Protocol { .. } => "protocol",
+            Self::BufferOperation { .. } => "buffer",
+            Self::Checkpoint { .. } => "checkpoint",
+            Self::Apply { .. } => "apply",
+            Self::Capture { .. } => "capture",
+            Self::Spill { .. } => "spill",
+            Self::DeadLetter { .. } => "dead_letter",
+            Self::Webhook { .. } => "webhook",
+            Self::Parquet { .. } => "parquet",
+            Self::Avro { .. } => "avro",
+            Self::S3 { .. } => "s3",
+            Self::Sink { .. } => "sink",
+            Self::Alert { .. } => "alert",
+            Self::TransactionLimit { .. } => "transaction_limit",
+            Self::InitSync { .. } => "init_sync",
+            Self::NetworkIO(_) => "network_io",
+            Self::StringConversion(_) => "string_conversion",
+            Self::CStringConversion(_) => "c_string_conversion",
+            Self::Walstream(_) => "walstream",
+            Self::TaskExecution(_) => "task_execution",
+            Self::Other(_) => "other",
+            Self::MissingEnvVar(_) => "missing_env_var",
+        }
+    }
+
+    /// Whether trying the failed operation again may succeed: a lost connection, a transient
+    /// pg-walstream error, or a sink delivery that failed on the network or with a server error
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Connection { .. } | Self::NetworkIO(_) => true,
+            Self::Walstream(error) => error.is_transient(),
+            Self::Webhook { retryable, .. }
+            | Self::S3 { retryable, .. }
+            | Self::Sink { retryable, .. } => *retryable,
+            _ => false,
+        }
+    }
+
+    /// Whether the connection to the server was lost, so reconnecting may help
+    pub fn is_connection_lost(&self) -> bool {
+        self.kind() == ErrorKind::Connection && self.is_retryable()
+    }
+}
+
+/// Whether an HTTP response status is worth retrying: server errors, timeouts and throttling
+pub fn is_retryable_status(status: u16) -> bool {
+    status >= 500 || matches!(status, 408 | 429)
 }
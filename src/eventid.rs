@@ -0,0 +1,61 @@
+//! Deterministic IDs of change events
+//! An ID names the transaction, the changed relation and the change's position in the
+//! transaction, so a change delivered again after a reconnect or restart gets the same ID and
+//! downstream consumers can drop the duplicate
+
+use crate::types::Lsn;
+use crate::utils::{Oid, Xid};
+use std::collections::HashMap;
+
+/// Numbers the changes of the transactions being received
+///
+/// A transaction is keyed by its commit LSN when that is known from its Begin message, and by
+/// `x<xid>` when it is streamed before it commits or the backend does not report the commit LSN
+/// up front. IDs have the form `<transaction>:<relation oid>:<index>`, the index counting the
+/// transaction's changes from 0.
+#[derive(Debug, Default)]
+pub struct EventIds {
+    /// Key of the transaction between Begin and Commit and the changes numbered so far
+    current: Option<(String, u64)>,
+    /// Changes numbered so far of each transaction keyed by its xid
+    streamed: HashMap<Xid, u64>,
+}
+
+impl EventIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start numbering a transaction whose commit LSN is known
+    pub fn begin(&mut self, commit_lsn: Lsn) {
+        self.current = Some((commit_lsn.to_string(), 0));
+    }
+
+    pub fn commit(&mut self) {
+        self.current = None;
+    }
+
+    /// Forget a transaction numbered by its xid once it committed or aborted
+    pub fn end_streamed(&mut self, xid: Xid) {
+        self.streamed.remove(&xid);
+    }
+
+    /// ID of the next change, which belongs to the transaction `streamed_xid` if set and
+    /// otherwise to the transaction between Begin and Commit; None outside a transaction
+    pub fn next(&mut self, streamed_xid: Option<Xid>, relation_oid: Oid) -> Option<String> {
+        let (key, index) = match streamed_xid {
+            Some(xid) => (xid_key(xid), self.streamed.entry(xid).or_insert(0)),
+            None => {
+                let (key, index) = self.current.as_mut()?;
+                (key.clone(), index)
+            }
+        };
+        let id = format!("{}:{}:{}", key, relation_oid, index);
+        *index += 1;
+        Some(id)
+    }
+}
+
+fn xid_key(xid: Xid) -> String {
+    format!("x{}", xid)
+}
@@ -0,0 +1,185 @@
+//! Cursor-style consumption of decoded transactions
+//! An `EventStream` yields each committed transaction with its changes, as a `Stream` or through
+//! `recv`. The position confirmed to the server only advances up to the LSN the application
+//! passes to `ack`, so transactions it had not finished processing when it stopped are delivered
+//! again after a restart.
+
+use crate::errors::Result;
+use crate::handler::ChangeHandler;
+use crate::output::{ChangeRecord, SchemaChangeRecord};
+use crate::types::Lsn;
+use crate::utils::Xid;
+use chrono::{DateTime, Utc};
+use futures_core::Stream;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tracing::info;
+
+/// A committed transaction and everything it changed in the streamed tables
+#[derive(Debug, Clone)]
+pub struct TransactionEvent {
+    /// None for a streamed transaction whose changes carried no xid
+    pub xid: Option<Xid>,
+    pub commit_lsn: Lsn,
+    /// Position to acknowledge once the transaction has been processed
+    pub end_lsn: Lsn,
+    pub commit_time: DateTime<Utc>,
+    /// Tables whose columns changed, ahead of the changes using the new columns
+    pub schema_changes: Vec<SchemaChangeRecord>,
+    pub changes: Vec<ChangeRecord>,
+}
+
+/// Positions shared by the collector and the stream
+#[derive(Debug, Default)]
+struct Cursor {
+    /// End LSN the application acknowledged
+    acked_lsn: AtomicU64,
+    /// End LSN of the last transaction sent to the stream
+    sent_lsn: AtomicU64,
+    /// Transactions sent but not yet taken from the stream
+    pending: AtomicUsize,
+}
+
+/// Receives the committed transactions, see [`ReplicationBackend::event_stream`]
+///
+/// [`ReplicationBackend::event_stream`]: crate::backend::ReplicationBackend::event_stream
+#[derive(Debug)]
+pub struct EventStream {
+    receiver: mpsc::UnboundedReceiver<TransactionEvent>,
+    cursor: Arc<Cursor>,
+}
+
+impl EventStream {
+    /// The next committed transaction, None once streaming has stopped
+    pub async fn recv(&mut self) -> Option<TransactionEvent> {
+        let event = self.receiver.recv().await;
+        if event.is_some() {
+            self.cursor.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+        event
+    }
+
+    /// Mark every transaction ending at or before `lsn` as processed, allowing the server to
+    /// discard its WAL; acknowledging an earlier position than before has no effect
+    pub fn ack(&self, lsn: Lsn) {
+        self.cursor
+            .acked_lsn
+            .fetch_max(lsn.value(), Ordering::Relaxed);
+    }
+
+    /// Position acknowledged so far
+    pub fn acked_lsn(&self) -> Lsn {
+        Lsn(self.cursor.acked_lsn.load(Ordering::Relaxed))
+    }
+}
+
+impl Stream for EventStream {
+    type Item = TransactionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = self.receiver.poll_recv(cx);
+        if let Poll::Ready(Some(_)) = &polled {
+            self.cursor.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+        polled
+    }
+}
+
+/// Groups the changes passed to the sinks into transactions for an `EventStream`
+///
+/// Changes are grouped between one commit and the next, so changes of streamed transactions are
+/// only grouped by transaction with transaction buffering enabled.
+#[derive(Debug)]
+pub struct EventCollector {
+    sender: mpsc::UnboundedSender<TransactionEvent>,
+    cursor: Arc<Cursor>,
+    /// Most transactions waiting in the stream before reading pauses
+    capacity: usize,
+    /// Stops the replication loop once the stream is dropped
+    shutdown: Arc<AtomicBool>,
+    xid: Option<Xid>,
+    schema_changes: Vec<SchemaChangeRecord>,
+    changes: Vec<ChangeRecord>,
+}
+
+impl EventCollector {
+    pub fn new(capacity: usize, shutdown: Arc<AtomicBool>) -> (Self, EventStream) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let cursor = Arc::new(Cursor::default());
+        let collector = Self {
+            sender,
+            cursor: cursor.clone(),
+            capacity: capacity.max(1),
+            shutdown,
+            xid: None,
+            schema_changes: Vec::new(),
+            changes: Vec::new(),
+        };
+        (collector, EventStream { receiver, cursor })
+    }
+
+    /// Whether the application is `capacity` transactions behind, in which case the stream
+    /// should read nothing more until it catches up
+    pub fn is_full(&self) -> bool {
+        self.cursor.pending.load(Ordering::Relaxed) >= self.capacity
+    }
+
+    /// End LSN up to which every transaction was acknowledged, None once the application
+    /// acknowledged every transaction sent, when it holds nothing back
+    pub fn pending_lsn(&self) -> Option<Lsn> {
+        let acked = Lsn(self.cursor.acked_lsn.load(Ordering::Relaxed));
+        (acked < Lsn(self.cursor.sent_lsn.load(Ordering::Relaxed))).then_some(acked)
+    }
+}
+
+impl ChangeHandler for EventCollector {
+    fn on_begin(&mut self, xid: Xid, _final_lsn: Lsn, _commit_time: DateTime<Utc>) -> Result<()> {
+        self.xid = Some(xid);
+        Ok(())
+    }
+
+    fn on_change(&mut self, change: &ChangeRecord) -> Result<()> {
+        self.xid = self.xid.or(change.xid);
+        self.changes.push(change.clone());
+        Ok(())
+    }
+
+    fn on_schema_change(&mut self, change: &SchemaChangeRecord) -> Result<()> {
+        self.schema_changes.push(change.clone());
+        Ok(())
+    }
+
+    fn on_commit(
+        &mut self,
+        commit_lsn: Lsn,
+        end_lsn: Lsn,
+        commit_time: DateTime<Utc>,
+    ) -> Result<()> {
+        let xid = self.xid.take();
+        if self.changes.is_empty() && self.schema_changes.is_empty() {
+            return Ok(());
+        }
+
+        let event = TransactionEvent {
+            xid,
+            commit_lsn,
+            end_lsn,
+            commit_time,
+            schema_changes: std::mem::take(&mut self.schema_changes),
+            changes: std::mem::take(&mut self.changes),
+        };
+        self.cursor
+            .sent_lsn
+            .store(end_lsn.value(), Ordering::Relaxed);
+        self.cursor.pending.fetch_add(1, Ordering::Relaxed);
+        // Transactions sent after the stream was dropped are never acknowledged, so the
+        // confirmed position stays at the last acknowledged one
+        if self.sender.send(event).is_err() && !self.shutdown.swap(true, Ordering::Relaxed) {
+            info!("Event stream was dropped, stopping stream");
+        }
+        Ok(())
+    }
+}
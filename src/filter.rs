@@ -0,0 +1,84 @@
+//! Table and schema filtering of emitted change events
+//! Include/exclude patterns are globs (`*` and `?`) matched against the schema-qualified table name
+
+/// Selects which relations have their changes printed and emitted
+#[derive(Debug, Clone, Default)]
+pub struct TableFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl TableFilter {
+    /// Build a filter; with no include patterns every table not excluded is selected
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> Self {
+        Self { include, exclude }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether changes to `schema.table` should be emitted
+    pub fn matches(&self, schema: &str, table: &str) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| pattern_matches(pattern, schema, table));
+
+        included
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| pattern_matches(pattern, schema, table))
+    }
+
+    /// Same as `matches` for a name that may be schema-qualified, defaulting to `public`
+    pub fn matches_qualified(&self, name: &str) -> bool {
+        let (schema, table) = name.split_once('.').unwrap_or(("public", name));
+        self.matches(schema, table)
+    }
+}
+
+/// A pattern without a schema part matches tables of that name in any schema
+pub(crate) fn pattern_matches(pattern: &str, schema: &str, table: &str) -> bool {
+    match pattern.split_once('.') {
+        Some((schema_pattern, table_pattern)) => {
+            glob_match(schema_pattern, schema) && glob_match(table_pattern, table)
+        }
+        None => glob_match(pattern, table),
+    }
+}
+
+/// Match `text` against a glob where `*` matches any run of characters and `?` exactly one
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` seen and the text position it was tried against
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, star_t)) = backtrack {
+            // Let the last `*` absorb one more character and retry
+            p = star + 1;
+            t = star_t + 1;
+            backtrack = Some((star, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,437 @@
+//! gRPC streaming of change events
+//! Built with the `grpc` feature. With GRPC_LISTEN_ADDR set, `stream` serves the
+//! `replication_checker.v1.ChangeStream` service of `proto/replication_checker.proto`: every
+//! `Subscribe` call receives the transactions decoded from then on as `ReplicationEvent`
+//! messages, limited to the tables its request selects. The messages and the service are
+//! generated from the .proto by `tonic-prost-build`, and the server is tonic's, speaking
+//! gRPC over cleartext HTTP/2.
+//!
+//! Events are published to the subscribers through a bounded queue and never wait for them: a
+//! subscriber that falls GRPC_SUBSCRIBER_BUFFER events behind has its call ended with
+//! RESOURCE_EXHAUSTED, and the stream carries on without it.
+
+use crate::errors::{ReplicationError, Result};
+use crate::filter::TableFilter;
+use crate::handler::ChangeHandler;
+use crate::output::{ChangeRecord, SchemaChangeRecord};
+use crate::relcache::ColumnDef;
+use crate::row::{Row, RowValue};
+use crate::types::Lsn;
+use crate::utils::Xid;
+use crate::watch::ChangeKind;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::info;
+
+/// Messages and service generated from `proto/replication_checker.proto`
+pub mod proto {
+    tonic::include_proto!("replication_checker.v1");
+}
+
+use proto::change_stream_server::{ChangeStream, ChangeStreamServer};
+use proto::replication_event::Message;
+
+/// Events a subscriber may fall behind by default
+const DEFAULT_SUBSCRIBER_BUFFER: usize = 4_096;
+
+/// Longest exiting waits for the calls in progress to end
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Calls served at once on one connection
+const MAX_STREAMS: u32 = 100;
+
+/// Largest request message accepted
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// Events queued from a subscription to its call
+const OUTGOING_CAPACITY: usize = 64;
+
+/// Configuration read from the GRPC_* environment variables
+#[derive(Debug, Clone)]
+pub struct GrpcConfig {
+    pub listen: SocketAddr,
+    /// Events a subscriber may fall behind before its call is ended
+    pub subscriber_buffer: usize,
+}
+
+impl GrpcConfig {
+    /// Read the server settings, None when GRPC_LISTEN_ADDR is not set
+    pub fn from_env() -> Result<Option<Self>> {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.trim().is_empty());
+        let Some(listen) = var("GRPC_LISTEN_ADDR") else {
+            return Ok(None);
+        };
+        let listen = listen.trim().parse().map_err(|_| {
+            ReplicationError::config(format!(
+                "Invalid GRPC_LISTEN_ADDR {}: expected an address such as 0.0.0.0:50051",
+                listen
+            ))
+        })?;
+        let subscriber_buffer = match var("GRPC_SUBSCRIBER_BUFFER") {
+            Some(buffer) => buffer
+                .trim()
+                .parse()
+                .ok()
+                .filter(|&buffer| buffer > 0)
+                .ok_or_else(|| {
+                    ReplicationError::config(format!(
+                        "Invalid GRPC_SUBSCRIBER_BUFFER {}: expected a positive number of events",
+                        buffer
+                    ))
+                })?,
+            None => DEFAULT_SUBSCRIBER_BUFFER,
+        };
+        Ok(Some(Self {
+            listen,
+            subscriber_buffer,
+        }))
+    }
+}
+
+/// What a published event is about, for the subscribers to filter on
+#[derive(Debug)]
+enum EventKind {
+    /// The stream stopped, ending every call
+    End,
+    Begin,
+    Commit,
+    /// A row change or schema change of the table
+    Table {
+        schema: String,
+        table: String,
+    },
+}
+
+/// An event as published to the subscribers
+#[derive(Debug)]
+pub struct PublishedEvent {
+    source: Option<String>,
+    kind: EventKind,
+    message: proto::ReplicationEvent,
+}
+
+/// Queue from the streams to the subscribers
+#[derive(Debug, Clone)]
+pub struct GrpcHub {
+    events: broadcast::Sender<Arc<PublishedEvent>>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl GrpcHub {
+    pub fn new(subscriber_buffer: usize) -> Self {
+        let (events, _) = broadcast::channel(subscriber_buffer);
+        Self {
+            events,
+            shutdown: watch::Sender::new(false),
+        }
+    }
+
+    /// End every call once it is sent the events published so far, and stop accepting
+    /// connections
+    pub fn shutdown(&self) {
+        let _ = self.events.send(Arc::new(PublishedEvent {
+            source: None,
+            kind: EventKind::End,
+            message: proto::ReplicationEvent::default(),
+        }));
+        self.shutdown.send_replace(true);
+    }
+
+    /// Handler publishing the transactions of one source
+    pub fn publisher(&self, source: Option<String>) -> GrpcPublisher {
+        GrpcPublisher {
+            source,
+            events: self.events.clone(),
+        }
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.events.receiver_count()
+    }
+}
+
+/// Publishes the decoded transactions of a source to the gRPC subscribers
+pub struct GrpcPublisher {
+    source: Option<String>,
+    events: broadcast::Sender<Arc<PublishedEvent>>,
+}
+
+impl GrpcPublisher {
+    /// Build and publish the event, unless nobody is subscribed
+    fn publish(&self, kind: EventKind, message: impl FnOnce() -> Message) {
+        if self.events.receiver_count() == 0 {
+            return;
+        }
+        let message = proto::ReplicationEvent {
+            source: self.source.clone().unwrap_or_default(),
+            message: Some(message()),
+        };
+        // Subscribers that left since the count was taken are no error
+        let _ = self.events.send(Arc::new(PublishedEvent {
+            source: self.source.clone(),
+            kind,
+            message,
+        }));
+    }
+}
+
+impl ChangeHandler for GrpcPublisher {
+    fn on_begin(&mut self, xid: Xid, final_lsn: Lsn, commit_time: DateTime<Utc>) -> Result<()> {
+        self.publish(EventKind::Begin, || {
+            Message::Begin(proto::Begin {
+                xid,
+                final_lsn: final_lsn.to_string(),
+                commit_time: commit_time.timestamp_micros(),
+            })
+        });
+        Ok(())
+    }
+
+    fn on_change(&mut self, change: &ChangeRecord) -> Result<()> {
+        let kind = EventKind::Table {
+            schema: change.schema.clone(),
+            table: change.table.clone(),
+        };
+        self.publish(kind, || {
+            let message = proto::Change {
+                schema: change.schema.clone(),
+                table: change.table.clone(),
+                lsn: change.lsn.clone().unwrap_or_default(),
+                xid: change.xid,
+                id: change.id.clone().unwrap_or_default(),
+                old: change.old.as_ref().map(encode_row),
+                new: change.new.as_ref().map(encode_row),
+            };
+            match change.op {
+                ChangeKind::Insert => Message::Insert(message),
+                ChangeKind::Update => Message::Update(message),
+                ChangeKind::Delete => Message::Delete(message),
+                ChangeKind::Truncate => Message::Truncate(message),
+            }
+        });
+        Ok(())
+    }
+
+    fn on_schema_change(&mut self, change: &SchemaChangeRecord) -> Result<()> {
+        let kind = EventKind::Table {
+            schema: change.schema.clone(),
+            table: change.table.clone(),
+        };
+        self.publish(kind, || {
+            let diff = &change.diff;
+            let columns = |columns: &[ColumnDef]| {
+                columns
+                    .iter()
+                    .map(|column| proto::Column {
+                        name: column.name.clone(),
+                        r#type: column.type_name.clone(),
+                    })
+                    .collect()
+            };
+            Message::Relation(proto::Relation {
+                schema: change.schema.clone(),
+                table: change.table.clone(),
+                lsn: change.lsn.clone().unwrap_or_default(),
+                version: change.version,
+                previous_table: diff.previous_table.clone().unwrap_or_default(),
+                added: columns(&diff.added),
+                removed: columns(&diff.removed),
+                renamed: diff
+                    .renamed
+                    .iter()
+                    .map(|rename| proto::ColumnRename {
+                        from: rename.from.clone(),
+                        to: rename.to.clone(),
+                    })
+                    .collect(),
+                type_changed: diff
+                    .type_changed
+                    .iter()
+                    .map(|change| proto::ColumnTypeChange {
+                        column: change.column.clone(),
+                        from: change.from.clone(),
+                        to: change.to.clone(),
+                    })
+                    .collect(),
+                replica_identity: diff.replica_identity.clone().unwrap_or_default(),
+                key_columns: diff.key_columns.clone().unwrap_or_default(),
+            })
+        });
+        Ok(())
+    }
+
+    fn on_commit(
+        &mut self,
+        commit_lsn: Lsn,
+        end_lsn: Lsn,
+        commit_time: DateTime<Utc>,
+    ) -> Result<()> {
+        self.publish(EventKind::Commit, || {
+            Message::Commit(proto::Commit {
+                commit_lsn: commit_lsn.to_string(),
+                end_lsn: end_lsn.to_string(),
+                commit_time: commit_time.timestamp_micros(),
+            })
+        });
+        Ok(())
+    }
+}
+
+/// A `Row` message; columns left out of the publication are not sent
+fn encode_row(row: &Row) -> proto::Row {
+    let columns = row
+        .columns()
+        .iter()
+        .filter_map(|column| {
+            let value = match &column.value {
+                RowValue::Text(text) => proto::value::Value::Text(text.to_string()),
+                RowValue::Null => proto::value::Value::Null(true),
+                RowValue::Unchanged => proto::value::Value::Unchanged(true),
+                RowValue::NotPublished => return None,
+            };
+            Some(proto::Value {
+                name: column.name.to_string(),
+                value: Some(value),
+            })
+        })
+        .collect();
+    proto::Row { columns }
+}
+
+/// The `ChangeStream` service, subscribing each call to the hub
+struct ChangeStreamService {
+    hub: GrpcHub,
+}
+
+#[tonic::async_trait]
+impl ChangeStream for ChangeStreamService {
+    type SubscribeStream = ReceiverStream<std::result::Result<proto::ReplicationEvent, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> std::result::Result<Response<Self::SubscribeStream>, Status> {
+        if *self.hub.shutdown.borrow() {
+            return Err(Status::unavailable("the stream is stopping"));
+        }
+        let peer = request
+            .remote_addr()
+            .map_or_else(|| "unknown".to_string(), |peer| peer.to_string());
+        let request = request.into_inner();
+        let filter = TableFilter::new(request.include_tables, request.exclude_tables);
+
+        let events = self.hub.events.subscribe();
+        info!(
+            "gRPC subscriber {} subscribed, {} subscribers",
+            peer,
+            self.hub.subscribers()
+        );
+        if !filter.is_empty() {
+            info!("gRPC subscriber {} table filter: {:?}", peer, filter);
+        }
+        let (outgoing, receiver) = mpsc::channel(OUTGOING_CAPACITY);
+        tokio::spawn(async move {
+            let status = forward(filter, events, outgoing).await;
+            info!(
+                "gRPC subscription of {} ended{}",
+                peer,
+                status
+                    .map(|status| format!(": {}", status.message()))
+                    .unwrap_or_default()
+            );
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+/// Accept gRPC connections until the hub is shut down, then give the calls in progress a
+/// moment to end
+pub async fn serve(
+    addr: SocketAddr,
+    hub: GrpcHub,
+) -> std::result::Result<(), tonic::transport::Error> {
+    info!("gRPC server listening on http://{}", addr);
+    let mut shutdown = hub.shutdown.subscribe();
+    let mut stopping = hub.shutdown.subscribe();
+    let service = ChangeStreamServer::new(ChangeStreamService { hub })
+        .max_decoding_message_size(MAX_REQUEST_SIZE);
+    let server = Server::builder()
+        .max_concurrent_streams(MAX_STREAMS)
+        .add_service(service)
+        .serve_with_shutdown(addr, async move {
+            let _ = shutdown.wait_for(|&shutdown| shutdown).await;
+        });
+    tokio::pin!(server);
+    tokio::select! {
+        result = &mut server => return result,
+        _ = stopping.wait_for(|&shutdown| shutdown) => {}
+    }
+    tokio::time::timeout(SHUTDOWN_TIMEOUT, server)
+        .await
+        .unwrap_or(Ok(()))
+}
+
+/// Forward the events a subscriber selected to its call until the call ends, returning the
+/// error status it ended with
+///
+/// Begin is held back until the first change of the transaction the subscriber selected, and
+/// Commit only follows such a change, so transactions touching none of its tables are not sent.
+async fn forward(
+    filter: TableFilter,
+    mut events: broadcast::Receiver<Arc<PublishedEvent>>,
+    outgoing: mpsc::Sender<std::result::Result<proto::ReplicationEvent, Status>>,
+) -> Option<Status> {
+    // Per source, as the transactions of several sources interleave
+    let mut begins: HashMap<Option<String>, Arc<PublishedEvent>> = HashMap::new();
+    let mut selected: HashMap<Option<String>, bool> = HashMap::new();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                let status = Status::resource_exhausted(format!(
+                    "subscriber fell {} events behind the stream",
+                    missed
+                ));
+                let _ = outgoing.send(Err(status.clone())).await;
+                return Some(status);
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        let mut send = Vec::new();
+        match &event.kind {
+            EventKind::End => return None,
+            EventKind::Begin => {
+                selected.insert(event.source.clone(), false);
+                begins.insert(event.source.clone(), event);
+            }
+            EventKind::Table { schema, table } => {
+                if filter.matches(schema, table) {
+                    send.extend(begins.remove(&event.source));
+                    selected.insert(event.source.clone(), true);
+                    send.push(event);
+                }
+            }
+            EventKind::Commit => {
+                begins.remove(&event.source);
+                if selected.remove(&event.source).unwrap_or(false) {
+                    send.push(event);
+                }
+            }
+        }
+        for event in send {
+            // The call was cancelled or the client went away
+            if outgoing.send(Ok(event.message.clone())).await.is_err() {
+                return None;
+            }
+        }
+    }
+}
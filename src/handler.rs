@@ -0,0 +1,93 @@
+//! Pluggable handlers for decoded changes
+//! Lets programs embedding the `ReplicationServer` receive each transaction's changes instead of
+//! (or in addition to) the log output
+
+use crate::errors::Result;
+use crate::output::{ChangeRecord, EventWriter, SchemaChangeRecord};
+use crate::types::Lsn;
+use crate::utils::Xid;
+use chrono::{DateTime, Utc};
+
+/// Receives the transactions decoded by a `ReplicationServer`
+///
+/// Changes of a regular transaction arrive between `on_begin` and `on_commit`. Large
+/// transactions streamed before they commit have no `on_begin`: their changes are delivered as
+/// they are received and `on_commit` follows the Stream Commit. With transaction buffering
+/// enabled, changes are held back until their transaction commits and changes of aborted
+/// streamed transactions are never delivered. Changes of tables excluded by the table filter
+/// are never delivered, and an error returned by a handler stops the replication loop.
+pub trait ChangeHandler {
+    /// Called when a transaction starts
+    fn on_begin(&mut self, _xid: Xid, _final_lsn: Lsn, _commit_time: DateTime<Utc>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for every row change of an INSERT, UPDATE, DELETE or TRUNCATE
+    fn on_change(&mut self, change: &ChangeRecord) -> Result<()>;
+
+    /// Called when a table is sent with columns differing from the version seen before, ahead
+    /// of the changes that use the new columns
+    fn on_schema_change(&mut self, _change: &SchemaChangeRecord) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once a transaction has committed
+    fn on_commit(
+        &mut self,
+        _commit_lsn: Lsn,
+        _end_lsn: Lsn,
+        _commit_time: DateTime<Utc>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before the position processed so far is confirmed to the server as flushed;
+    /// whatever was written must be made durable, an error keeps the position from advancing
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ChangeHandler for EventWriter {
+    fn on_change(&mut self, change: &ChangeRecord) -> Result<()> {
+        self.write_record(change)?;
+        Ok(())
+    }
+
+    fn on_schema_change(&mut self, change: &SchemaChangeRecord) -> Result<()> {
+        self.write_record(change)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sync()?;
+        Ok(())
+    }
+}
+
+impl<H: ChangeHandler + ?Sized> ChangeHandler for Box<H> {
+    fn on_begin(&mut self, xid: Xid, final_lsn: Lsn, commit_time: DateTime<Utc>) -> Result<()> {
+        (**self).on_begin(xid, final_lsn, commit_time)
+    }
+
+    fn on_change(&mut self, change: &ChangeRecord) -> Result<()> {
+        (**self).on_change(change)
+    }
+
+    fn on_schema_change(&mut self, change: &SchemaChangeRecord) -> Result<()> {
+        (**self).on_schema_change(change)
+    }
+
+    fn on_commit(
+        &mut self,
+        commit_lsn: Lsn,
+        end_lsn: Lsn,
+        commit_time: DateTime<Utc>,
+    ) -> Result<()> {
+        (**self).on_commit(commit_lsn, end_lsn, commit_time)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
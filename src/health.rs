@@ -0,0 +1,490 @@
+//! Health check HTTP endpoint
+//! Serves liveness and readiness for orchestrators such as Kubernetes with a minimal HTTP/1.1
+//! responder, so a stuck checker can be restarted automatically
+//!
+//! - `GET /livez` (or `/healthz`): 200 while a message or keepalive arrived within the
+//!   liveness timeout or the stream is paused, 503 otherwise
+//! - `GET /readyz`: 200 while connected with the slot streaming, 503 otherwise
+//! - `GET /health`: the full report, always 200
+//! - `GET /metrics`: per-table commit-to-receive delay percentiles, changes left out by
+//!   sampling, error counts by kind and code, replication lag and catch-up progress in
+//!   Prometheus text format
+//!
+//! With several replication sources, probes succeed only when every source passes, `/health`
+//! reports each source by name and metrics carry a `source` label.
+
+use crate::errors::{ErrorKind, ReplicationError};
+use crate::progress::CatchUpSnapshot;
+use crate::stats::{LagSnapshot, TableDelaySnapshot};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+/// Liveness and readiness signals updated by the replication loop
+#[derive(Debug)]
+pub struct HealthState {
+    started: Instant,
+    connected: AtomicBool,
+    streaming: AtomicBool,
+    /// Paused over the control socket, so no messages are expected
+    paused: AtomicBool,
+    /// Milliseconds after `started` at which the last message arrived
+    last_message_ms: AtomicU64,
+    /// Per-table delays as of the last stats report
+    table_delays: Mutex<Vec<TableDelaySnapshot>>,
+    /// Errors seen so far by kind, code and whether they were retryable
+    errors: Mutex<BTreeMap<ErrorCount, u64>>,
+    /// Changes of sampled tables left out of the output, by table
+    sampled_out: Mutex<BTreeMap<String, u64>>,
+    /// Lag against the server as of the last stats report
+    lag: Mutex<Option<LagSnapshot>>,
+    /// Catch-up progress as of the last progress report
+    catch_up: Mutex<Option<CatchUpSnapshot>>,
+}
+
+/// Labels errors are counted by
+type ErrorCount = (ErrorKind, &'static str, bool);
+
+/// Streams served by the endpoint, each named by its source when there are several
+pub type HealthSources = Vec<(Option<String>, Arc<HealthState>)>;
+
+/// Health as reported by the endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub live: bool,
+    pub ready: bool,
+    pub connected: bool,
+    /// START_REPLICATION succeeded, so the slot is active for this connection
+    pub slot_active: bool,
+    pub paused: bool,
+    pub seconds_since_last_message: f64,
+}
+
+impl HealthState {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            connected: AtomicBool::new(false),
+            streaming: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            last_message_ms: AtomicU64::new(0),
+            table_delays: Mutex::new(Vec::new()),
+            errors: Mutex::new(BTreeMap::new()),
+            sampled_out: Mutex::new(BTreeMap::new()),
+            lag: Mutex::new(None),
+            catch_up: Mutex::new(None),
+        }
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+        if !connected {
+            self.streaming.store(false, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_streaming(&self, streaming: bool) {
+        self.streaming.store(streaming, Ordering::Relaxed);
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Record that a message or keepalive arrived from the server
+    pub fn record_message(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last_message_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Time since the last message, or since startup when none arrived yet
+    pub fn since_last_message(&self) -> Duration {
+        let last = Duration::from_millis(self.last_message_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+
+    pub fn set_table_delays(&self, delays: Vec<TableDelaySnapshot>) {
+        if let Ok(mut table_delays) = self.table_delays.lock() {
+            *table_delays = delays;
+        }
+    }
+
+    pub fn set_sampled_out(&self, counts: BTreeMap<String, u64>) {
+        if let Ok(mut sampled_out) = self.sampled_out.lock() {
+            *sampled_out = counts;
+        }
+    }
+
+    pub fn sampled_out(&self) -> BTreeMap<String, u64> {
+        match self.sampled_out.lock() {
+            Ok(sampled_out) => sampled_out.clone(),
+            Err(_) => BTreeMap::new(),
+        }
+    }
+
+    pub fn set_lag(&self, snapshot: LagSnapshot) {
+        if let Ok(mut lag) = self.lag.lock() {
+            *lag = Some(snapshot);
+        }
+    }
+
+    /// Lag as of the last stats report, None before the first one
+    pub fn lag(&self) -> Option<LagSnapshot> {
+        self.lag.lock().ok().and_then(|lag| *lag)
+    }
+
+    pub fn set_catch_up(&self, snapshot: CatchUpSnapshot) {
+        if let Ok(mut catch_up) = self.catch_up.lock() {
+            *catch_up = Some(snapshot);
+        }
+    }
+
+    /// Catch-up progress as of the last progress report, None before the first one
+    pub fn catch_up(&self) -> Option<CatchUpSnapshot> {
+        self.catch_up.lock().ok().and_then(|catch_up| *catch_up)
+    }
+
+    /// Count an error that ended a replication attempt, whether it was retried or fatal
+    pub fn record_error(&self, error: &ReplicationError) {
+        if let Ok(mut errors) = self.errors.lock() {
+            *errors
+                .entry((error.kind(), error.code(), error.is_retryable()))
+                .or_default() += 1;
+        }
+    }
+
+    /// Errors counted so far
+    pub fn error_counts(&self) -> Vec<(ErrorCount, u64)> {
+        match self.errors.lock() {
+            Ok(errors) => errors
+                .iter()
+                .map(|(labels, count)| (*labels, *count))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Per-table delays as of the last stats report
+    pub fn table_delays(&self) -> Vec<TableDelaySnapshot> {
+        match self.table_delays.lock() {
+            Ok(delays) => delays.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub fn report(&self, liveness_timeout: Duration) -> HealthReport {
+        let connected = self.connected.load(Ordering::Relaxed);
+        let slot_active = connected && self.streaming.load(Ordering::Relaxed);
+        let since_last_message = self.since_last_message();
+        let paused = self.paused.load(Ordering::Relaxed);
+
+        HealthReport {
+            live: paused || since_last_message <= liveness_timeout,
+            ready: slot_active,
+            connected,
+            slot_active,
+            paused,
+            seconds_since_last_message: since_last_message.as_secs_f64(),
+        }
+    }
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serve the health endpoint until the process exits
+pub async fn serve(
+    addr: SocketAddr,
+    sources: HealthSources,
+    liveness_timeout: Duration,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Health endpoint listening on http://{}", addr);
+    let sources = Arc::new(sources);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let sources = sources.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &sources, liveness_timeout).await {
+                debug!("Health request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    sources: &HealthSources,
+    liveness_timeout: Duration,
+) -> std::io::Result<()> {
+    let mut request = [0u8; 1024];
+    let len = stream.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..len]);
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    if (method, path) == ("GET", "/metrics") {
+        return write_response(
+            stream,
+            "200 OK",
+            "text/plain; version=0.0.4",
+            &metrics(sources),
+        )
+        .await;
+    }
+
+    let reports: Vec<(Option<&str>, HealthReport)> = sources
+        .iter()
+        .map(|(source, state)| (source.as_deref(), state.report(liveness_timeout)))
+        .collect();
+    let live = reports.iter().all(|(_, report)| report.live);
+    let ready = reports.iter().all(|(_, report)| report.ready);
+    let body = match reports.as_slice() {
+        [(None, report)] => serde_json::to_string(report),
+        reports => serde_json::to_string(
+            &reports
+                .iter()
+                .map(|(source, report)| (source.unwrap_or_default(), report))
+                .collect::<BTreeMap<_, _>>(),
+        ),
+    }
+    .unwrap_or_default();
+    let status = match (method, path) {
+        ("GET", "/livez" | "/healthz") if live => "200 OK",
+        ("GET", "/livez" | "/healthz") => "503 Service Unavailable",
+        ("GET", "/readyz") if ready => "200 OK",
+        ("GET", "/readyz") => "503 Service Unavailable",
+        ("GET", "/health") => "200 OK",
+        ("GET", _) => "404 Not Found",
+        _ => "405 Method Not Allowed",
+    };
+
+    write_response(stream, status, "application/json", &body).await
+}
+
+async fn write_response(
+    mut stream: TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Delay percentiles and event counts per table, error counts, lag and catch-up progress in
+/// Prometheus text format
+fn metrics(sources: &HealthSources) -> String {
+    // Every series of a table carries the same labels
+    let tables: Vec<(String, TableDelaySnapshot)> = sources
+        .iter()
+        .flat_map(|(source, state)| {
+            let source = source
+                .as_deref()
+                .map(|source| format!("source=\"{}\",", prometheus_label(source)))
+                .unwrap_or_default();
+            state.table_delays().into_iter().map(move |table| {
+                let labels = format!("{}table=\"{}\"", source, prometheus_label(&table.table));
+                (labels, table)
+            })
+        })
+        .collect();
+
+    let mut body = String::new();
+    body.push_str(
+        "# HELP replication_commit_delay_seconds Delay between a transaction's commit and its changes being received\n\
+         # TYPE replication_commit_delay_seconds summary\n",
+    );
+    for (labels, table) in &tables {
+        for (quantile, value) in [
+            ("0.5", table.p50_seconds),
+            ("0.95", table.p95_seconds),
+            ("0.99", table.p99_seconds),
+        ] {
+            let _ = writeln!(
+                body,
+                "replication_commit_delay_seconds{{{},quantile=\"{}\"}} {}",
+                labels, quantile, value
+            );
+        }
+        let _ = writeln!(
+            body,
+            "replication_commit_delay_seconds_sum{{{}}} {}",
+            labels, table.sum_seconds
+        );
+        let _ = writeln!(
+            body,
+            "replication_commit_delay_seconds_count{{{}}} {}",
+            labels, table.events
+        );
+    }
+
+    body.push_str(
+        "# HELP replication_events_total Changes received per table\n\
+         # TYPE replication_events_total counter\n",
+    );
+    for (labels, table) in &tables {
+        let _ = writeln!(
+            body,
+            "replication_events_total{{{}}} {}",
+            labels, table.events
+        );
+    }
+
+    body.push_str(
+        "# HELP replication_events_sampled_out_total Changes of sampled tables left out of the output\n\
+         # TYPE replication_events_sampled_out_total counter\n",
+    );
+    for (source, state) in sources {
+        let source = source
+            .as_deref()
+            .map(|source| format!("source=\"{}\",", prometheus_label(source)))
+            .unwrap_or_default();
+        for (table, count) in state.sampled_out() {
+            let _ = writeln!(
+                body,
+                "replication_events_sampled_out_total{{{}table=\"{}\"}} {}",
+                source,
+                prometheus_label(&table),
+                count
+            );
+        }
+    }
+
+    body.push_str(
+        "# HELP replication_errors_total Errors that ended a replication attempt, retried or fatal\n\
+         # TYPE replication_errors_total counter\n",
+    );
+    for (source, state) in sources {
+        let source = source
+            .as_deref()
+            .map(|source| format!("source=\"{}\",", prometheus_label(source)))
+            .unwrap_or_default();
+        for ((kind, code, retryable), count) in state.error_counts() {
+            let _ = writeln!(
+                body,
+                "replication_errors_total{{{}kind=\"{}\",code=\"{}\",retryable=\"{}\"}} {}",
+                source, kind, code, retryable, count
+            );
+        }
+    }
+
+    let lag: Vec<(String, LagSnapshot)> = sources
+        .iter()
+        .filter_map(|(source, state)| Some((source_labels(source.as_deref()), state.lag()?)))
+        .collect();
+    write_gauge(
+        &mut body,
+        "replication_server_wal_end_lsn",
+        "Latest WAL end reported by the server",
+        lag.iter()
+            .map(|(labels, lag)| (labels, lag.server_wal_end.value() as f64)),
+    );
+    write_gauge(
+        &mut body,
+        "replication_received_bytes_behind",
+        "Bytes of WAL between the received position and the server's WAL end",
+        lag.iter()
+            .map(|(labels, lag)| (labels, lag.received_bytes_behind as f64)),
+    );
+    write_gauge(
+        &mut body,
+        "replication_applied_bytes_behind",
+        "Bytes of WAL between the applied position and the server's WAL end",
+        lag.iter()
+            .map(|(labels, lag)| (labels, lag.applied_bytes_behind as f64)),
+    );
+    write_gauge(
+        &mut body,
+        "replication_seconds_behind",
+        "Delay between the server sending the last message and its arrival",
+        lag.iter().map(|(labels, lag)| (labels, lag.seconds_behind)),
+    );
+    write_gauge(
+        &mut body,
+        "replication_events_per_second",
+        "Change events processed per second over the last stats interval",
+        lag.iter().map(|(labels, lag)| (labels, lag.events_per_sec)),
+    );
+
+    let catch_up: Vec<(String, CatchUpSnapshot)> = sources
+        .iter()
+        .filter_map(|(source, state)| Some((source_labels(source.as_deref()), state.catch_up()?)))
+        .collect();
+    write_gauge(
+        &mut body,
+        "replication_catch_up_bytes_remaining",
+        "Bytes of WAL left to consume to reach the server's WAL end",
+        catch_up
+            .iter()
+            .map(|(labels, progress)| (labels, progress.bytes_remaining as f64)),
+    );
+    write_gauge(
+        &mut body,
+        "replication_catch_up_bytes_per_second",
+        "WAL consumed per second over the last progress interval",
+        catch_up
+            .iter()
+            .map(|(labels, progress)| (labels, progress.bytes_per_sec)),
+    );
+    write_gauge(
+        &mut body,
+        "replication_catch_up_eta_seconds",
+        "Estimated time until caught up, absent while no WAL is being consumed",
+        catch_up
+            .iter()
+            .filter_map(|(labels, progress)| Some((labels, progress.eta?.as_secs_f64()))),
+    );
+    body
+}
+
+/// Append a gauge with one value per label set
+fn write_gauge<'a>(
+    body: &mut String,
+    name: &str,
+    help: &str,
+    values: impl Iterator<Item = (&'a String, f64)>,
+) {
+    let _ = writeln!(body, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+    for (labels, value) in values {
+        let _ = writeln!(body, "{}{} {}", name, labels, value);
+    }
+}
+
+/// Label set of a series with only the source label, empty with a single unnamed source
+fn source_labels(source: Option<&str>) -> String {
+    source
+        .map(|source| format!("{{source=\"{}\"}}", prometheus_label(source)))
+        .unwrap_or_default()
+}
+
+/// Escape a label value for the Prometheus text format
+fn prometheus_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,56 @@
+//! Hexdump of raw CopyData messages for protocol debugging
+//! With `--dump-raw`, each message is logged before it is parsed in the layout of `hexdump -C`:
+//! the offset, sixteen bytes in two groups of eight, and the bytes as ASCII with `.` for
+//! anything unprintable. The dumps are logged at trace level under this module's target, so
+//! `LOG_LEVEL=info,replication_checker::hexdump=trace` shows them without tracing everything.
+
+use std::fmt::Write;
+use tracing::{trace, Level};
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Format bytes as hexdump lines followed by a line with the total length
+pub fn hexdump(data: &[u8]) -> String {
+    // Each line is 78 characters including the newline
+    let mut out = String::with_capacity((data.len() / BYTES_PER_LINE + 2) * 78);
+    for (line, chunk) in data.chunks(BYTES_PER_LINE).enumerate() {
+        let _ = write!(out, "{:08x} ", line * BYTES_PER_LINE);
+        for i in 0..BYTES_PER_LINE {
+            if i % 8 == 0 {
+                out.push(' ');
+            }
+            match chunk.get(i) {
+                Some(byte) => {
+                    let _ = write!(out, "{:02x} ", byte);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str(" |");
+        out.extend(chunk.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    let _ = write!(out, "{:08x}", data.len());
+    out
+}
+
+/// Whether the dumps are shown at the configured log level
+pub fn enabled() -> bool {
+    tracing::enabled!(Level::TRACE)
+}
+
+/// Log a CopyData message as a hexdump; the dump is only built when trace level is enabled
+pub fn trace_message(data: &[u8]) {
+    trace!(
+        "CopyData message '{}', {} bytes\n{}",
+        data.first().map_or('-', |&byte| byte as char),
+        data.len(),
+        hexdump(data)
+    );
+}
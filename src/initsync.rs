@@ -0,0 +1,358 @@
+//! Initial copy of the published tables before streaming
+//! The slot is created with an exported snapshot and every published table is read with
+//! COPY TO STDOUT under that snapshot, so streaming from the slot's consistent point continues
+//! exactly where the copy ends. Only the columns and rows the publications send are copied
+
+use crate::compress::{CompressedWriter, Compression};
+use crate::control::regular_conninfo;
+use crate::errors::{ReplicationError, Result};
+use crate::publication::published_tables;
+use crate::quote::{quote_ident, quote_literal, quote_qualified};
+use crate::row::{Row, RowValue};
+use crate::types::{utf8_prefix, ColumnData, ColumnInfo, RelationInfo, TupleData};
+use crate::utils::{PGConnection, PGResult};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// How the published tables are copied before streaming starts
+#[derive(Debug, Clone, Default)]
+pub struct InitSyncConfig {
+    /// Directory each table is also written to as `schema.table.csv`, if any, with the
+    /// extension of the output compression added
+    pub csv_dir: Option<PathBuf>,
+}
+
+/// A published table to copy
+#[derive(Debug)]
+pub struct SnapshotTable {
+    /// The table's columns as they are copied, in the shape of a Relation message
+    pub relation: RelationInfo,
+    /// Publications of the stream that include the table
+    pub publications: Vec<String>,
+    /// WHERE condition of the publications' row filters, None when every row is published
+    pub row_filter: Option<String>,
+}
+
+/// Regular connection reading the tables inside a transaction using the exported snapshot
+pub struct SnapshotReader {
+    connection: PGConnection,
+}
+
+impl SnapshotReader {
+    /// Connect and open a read-only transaction on the snapshot
+    pub fn open(replication_conninfo: &str, snapshot_name: &str) -> Result<Self> {
+        let connection = PGConnection::connect(&regular_conninfo(replication_conninfo))?;
+        let reader = Self { connection };
+        reader.execute("BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY")?;
+        reader.execute(&format!(
+            "SET TRANSACTION SNAPSHOT {}",
+            quote_literal(snapshot_name)
+        ))?;
+        info!("Reading tables under snapshot {}", snapshot_name);
+        Ok(reader)
+    }
+
+    /// Tables of the publications with their published columns, ordered by name
+    pub fn published_tables(&self, publication_names: &[String]) -> Result<Vec<SnapshotTable>> {
+        published_tables(&self.connection, publication_names)
+            .map_err(|e| ReplicationError::init_sync(e.to_string()))?
+            .into_iter()
+            .map(|table| {
+                let (columns, replica_identity) = self.columns(table.oid)?;
+                // Columns left out by a column list are not in the Relation message either
+                let columns: Vec<ColumnInfo> = columns
+                    .into_iter()
+                    .filter(|column| table.columns.contains(&column.column_name))
+                    .collect();
+                let mut relation = RelationInfo {
+                    oid: table.oid,
+                    namespace: table.schema.clone(),
+                    relation_name: table.table.clone(),
+                    replica_identity,
+                    column_count: columns.len() as i16,
+                    columns,
+                    published_mask: None,
+                };
+                relation.published_mask = Some(table.column_mask(&relation));
+                Ok(SnapshotTable {
+                    row_filter: table.row_filter(),
+                    relation,
+                    publications: table.publications,
+                })
+            })
+            .collect()
+    }
+
+    /// Stream the rows of a table that pass the row filter in COPY text format, one line per call
+    pub fn copy_table(
+        &self,
+        relation: &RelationInfo,
+        row_filter: Option<&str>,
+        mut on_row: impl FnMut(&[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let columns = relation
+            .columns
+            .iter()
+            .map(|column| quote_ident(&column.column_name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        // The query form also copies partitioned tables
+        let sql = format!(
+            "COPY (SELECT {} FROM {}{}) TO STDOUT",
+            columns,
+            quote_qualified(&relation.namespace, &relation.relation_name),
+            row_filter
+                .map(|filter| format!(" WHERE {}", filter))
+                .unwrap_or_default()
+        );
+        let result = self.connection.exec(&sql)?;
+        if !result.is_copy_out() {
+            return Err(ReplicationError::init_sync(format!(
+                "{} failed: {}",
+                sql,
+                result.error_message()
+            )));
+        }
+
+        while let Some(line) = self.connection.get_copy_row()? {
+            on_row(&line)?;
+        }
+        Ok(())
+    }
+
+    /// End the snapshot transaction
+    pub fn finish(self) -> Result<()> {
+        self.execute("COMMIT")?;
+        Ok(())
+    }
+
+    /// Columns sent by pgoutput for the table without a column list, flagged as pgoutput flags
+    /// its replica identity, and the replica identity; generated columns are left out
+    fn columns(&self, oid: u32) -> Result<(Vec<ColumnInfo>, char)> {
+        let oid = oid.to_string();
+        let result = self.connection.exec_params(
+            "SELECT a.attname, a.atttypid, a.atttypmod, c.relreplident = 'f' OR EXISTS ( \
+                 SELECT 1 FROM pg_index i WHERE i.indrelid = c.oid AND a.attnum = ANY(i.indkey) \
+                 AND CASE c.relreplident WHEN 'd' THEN i.indisprimary \
+                     WHEN 'i' THEN i.indisreplident ELSE false END), c.relreplident \
+             FROM pg_attribute a JOIN pg_class c ON c.oid = a.attrelid \
+             WHERE a.attrelid = $1::oid AND a.attnum > 0 AND NOT a.attisdropped \
+             AND a.attgenerated = '' ORDER BY a.attnum",
+            &[Some(&oid)],
+        )?;
+        if !result.is_ok() {
+            return Err(ReplicationError::init_sync(format!(
+                "Failed to look up the columns of table {}: {}",
+                oid,
+                result.error_message()
+            )));
+        }
+
+        let replica_identity = result
+            .getvalue(0, 4)
+            .and_then(|identity| identity.chars().next())
+            .unwrap_or('d');
+        let columns = (0..result.ntuples())
+            .map(|row| ColumnInfo {
+                key_flag: (result.getvalue(row, 3).as_deref() == Some("t")) as i8,
+                column_name: result.getvalue(row, 0).unwrap_or_default(),
+                column_type: result
+                    .getvalue(row, 1)
+                    .and_then(|oid| oid.parse().ok())
+                    .unwrap_or(0),
+                atttypmod: result
+                    .getvalue(row, 2)
+                    .and_then(|typmod| typmod.parse().ok())
+                    .unwrap_or(-1),
+                type_name: None,
+            })
+            .collect();
+        Ok((columns, replica_identity))
+    }
+
+    fn execute(&self, sql: &str) -> Result<PGResult> {
+        let result = self.connection.exec(sql)?;
+        if !result.is_ok() {
+            return Err(ReplicationError::init_sync(format!(
+                "{} failed: {}",
+                sql,
+                result.error_message()
+            )));
+        }
+        Ok(result)
+    }
+}
+
+/// Decode a row in COPY text format into text column values, as an Insert would carry them
+///
+/// Values longer than `max_column_bytes` keep only their first bytes, like streamed values.
+pub fn parse_copy_row(line: &[u8], max_column_bytes: Option<usize>) -> TupleData {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let columns: Vec<ColumnData> = line
+        .split(|&byte| byte == b'\t')
+        .map(|field| {
+            if field == b"\\N" {
+                return ColumnData {
+                    data_type: 'n',
+                    length: 0,
+                    data: Vec::new(),
+                };
+            }
+            let data = unescape_copy_field(field);
+            let length = data.len() as i32;
+            let data = match max_column_bytes {
+                Some(max) => utf8_prefix(&data, max).to_vec(),
+                None => data,
+            };
+            ColumnData {
+                data_type: 't',
+                length,
+                data,
+            }
+        })
+        .collect();
+
+    TupleData {
+        column_count: columns.len() as i16,
+        columns,
+        processed_length: line.len(),
+    }
+}
+
+/// Undo the backslash escapes of the COPY text format
+fn unescape_copy_field(field: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let byte = field[i];
+        i += 1;
+        if byte != b'\\' || i == field.len() {
+            data.push(byte);
+            continue;
+        }
+
+        let escaped = field[i];
+        i += 1;
+        match escaped {
+            b'b' => data.push(0x08),
+            b'f' => data.push(0x0C),
+            b'n' => data.push(b'\n'),
+            b'r' => data.push(b'\r'),
+            b't' => data.push(b'\t'),
+            b'v' => data.push(0x0B),
+            b'0'..=b'7' => {
+                // Up to three octal digits
+                let mut value = (escaped - b'0') as u32;
+                let end = (i + 2).min(field.len());
+                while i < end && (b'0'..=b'7').contains(&field[i]) {
+                    value = value * 8 + (field[i] - b'0') as u32;
+                    i += 1;
+                }
+                data.push(value as u8);
+            }
+            b'x' if i < field.len() && field[i].is_ascii_hexdigit() => {
+                // Up to two hex digits
+                let end = (i + 2).min(field.len());
+                let mut value = 0u32;
+                while i < end && field[i].is_ascii_hexdigit() {
+                    value = value * 16 + (field[i] as char).to_digit(16).unwrap_or(0);
+                    i += 1;
+                }
+                data.push(value as u8);
+            }
+            other => data.push(other),
+        }
+    }
+    data
+}
+
+/// Writes the copied rows of one table to a CSV file with a header line
+pub struct CsvTableWriter {
+    writer: CompressedWriter<BufWriter<File>>,
+    path: PathBuf,
+    columns: Vec<String>,
+}
+
+impl CsvTableWriter {
+    /// Create `schema.table.csv` in the directory, replacing an earlier copy
+    pub fn create(
+        directory: &Path,
+        relation: &RelationInfo,
+        compression: Compression,
+    ) -> Result<Self> {
+        fs::create_dir_all(directory).map_err(|e| {
+            ReplicationError::init_sync(format!("Failed to create {}: {}", directory.display(), e))
+        })?;
+        let path = compression.file_path(&directory.join(format!(
+            "{}.{}.csv",
+            relation.namespace, relation.relation_name
+        )));
+        let file = File::create(&path).map_err(|e| {
+            ReplicationError::init_sync(format!("Failed to create {}: {}", path.display(), e))
+        })?;
+
+        let columns: Vec<String> = relation
+            .columns
+            .iter()
+            .map(|column| column.column_name.clone())
+            .collect();
+        let mut writer = Self {
+            writer: CompressedWriter::new(BufWriter::new(file), compression),
+            path,
+            columns,
+        };
+        let header = writer
+            .columns
+            .iter()
+            .map(|column| csv_field(column))
+            .collect::<Vec<_>>()
+            .join(",");
+        writer.write_line(&header)?;
+        Ok(writer)
+    }
+
+    /// Write a row as the other sinks receive it; NULL is an empty unquoted field
+    pub fn write_row(&mut self, row: &Row) -> Result<()> {
+        let line = self
+            .columns
+            .iter()
+            .map(|column| match row.get(column).and_then(RowValue::as_text) {
+                Some(value) => csv_field(value),
+                None => String::new(),
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        self.write_line(&line)
+    }
+
+    pub fn finish(self) -> Result<PathBuf> {
+        self.writer
+            .finish()
+            .and_then(|mut writer| writer.flush())
+            .map_err(|e| {
+                ReplicationError::init_sync(format!(
+                    "Failed to write {}: {}",
+                    self.path.display(),
+                    e
+                ))
+            })?;
+        Ok(self.path)
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        writeln!(self.writer, "{}", line).map_err(|e| {
+            ReplicationError::init_sync(format!("Failed to write {}: {}", self.path.display(), e))
+        })
+    }
+}
+
+/// Quote a value when needed; empty strings are quoted to tell them apart from NULL
+fn csv_field(value: &str) -> String {
+    if value.is_empty() || value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
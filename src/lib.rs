@@ -0,0 +1,102 @@
+//! PostgreSQL Replication Checker
+//!
+//! The logical replication consumer behind the `pg_replica_rs` and `pg_replica_libpq` binaries,
+//! usable from other Rust programs. `ReplicationServer` connects to the server, prepares the slot
+//! and decodes the pgoutput stream with `MessageParser`; `WalstreamBackend` does the same through
+//! pg-walstream. Both implement `ReplicationBackend`: implement `ChangeHandler` and register it with
+//! `ReplicationBackend::add_handler` to receive the decoded transactions, or take an `EventStream`
+//! with `ReplicationBackend::event_stream` and acknowledge each transaction once processed.
+
+pub mod alert;
+#[cfg(feature = "alloc-stats")]
+pub mod allocstats;
+pub mod apply;
+pub mod assembler;
+pub mod avro;
+pub mod backend;
+pub mod binary;
+pub mod buffer;
+pub mod bufpool;
+pub mod capture;
+pub mod check;
+pub mod checkpoint;
+pub mod cli;
+pub mod commitlog;
+pub mod compress;
+pub mod config;
+pub mod conninfo;
+pub mod control;
+pub mod ctlsocket;
+pub mod datetime;
+pub mod dlq;
+pub mod envelope;
+pub mod errors;
+pub mod eventid;
+pub mod events;
+pub mod filter;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handler;
+pub mod health;
+pub mod hexdump;
+pub mod initsync;
+pub mod logging;
+pub mod mask;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod output;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod parser;
+pub mod physical;
+pub mod pipeline;
+pub mod progress;
+pub mod publication;
+pub mod quote;
+pub mod reconnect;
+pub mod relcache;
+pub mod reload;
+pub mod row;
+pub mod rowfilter;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod sample;
+pub mod segment;
+pub mod server;
+pub mod serverinfo;
+pub mod service;
+pub mod signals;
+pub mod sink;
+pub mod slot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
+pub mod stop;
+pub mod substat;
+pub mod summary;
+pub mod throttle;
+pub mod timeline;
+pub mod tls;
+pub mod toast;
+pub mod txlimit;
+pub mod txspan;
+pub mod types;
+pub mod utils;
+pub mod validate;
+pub mod verify;
+pub mod walkeep;
+pub mod walstream;
+pub mod watch;
+pub mod webhook;
+
+pub use backend::{BackendKind, ReplicationBackend};
+pub use envelope::TransactionInfo;
+pub use errors::{ReplicationError, Result};
+pub use events::{EventStream, TransactionEvent};
+pub use handler::ChangeHandler;
+pub use output::{ChangeRecord, EventSchemaVersion, SchemaChangeRecord};
+pub use parser::MessageParser;
+pub use row::{Row, RowValue};
+pub use server::ReplicationServer;
+pub use types::{Lsn, ReplicationConfig, ReplicationMessage, ReplicationMessageRef};
+pub use walstream::WalstreamBackend;
@@ -0,0 +1,203 @@
+//! PostgreSQL Replication Checker - libpq Edition
+//!
+//! Streams logical replication changes using the in-tree libpq based `ReplicationServer`,
+//! which speaks the replication protocol directly instead of going through pg-walstream.
+//! `--backend walstream` streams through pg-walstream instead.
+
+use replication_checker::backend::{self, BackendKind};
+use replication_checker::check::{self, CheckReport};
+use replication_checker::cli::{
+    AdvanceSlotArgs, Cli, Command, PhysicalArgs, ReplayArgs, ReplayDlqArgs, SlotArgs, StatusArgs,
+    StreamArgs, VerifyArgs,
+};
+use replication_checker::config::ConfigFile;
+use replication_checker::control::regular_conninfo;
+use replication_checker::dlq;
+use replication_checker::logging::{self, LoggingConfig};
+use replication_checker::physical;
+use replication_checker::server::ReplicationServer;
+use replication_checker::serverinfo::ServerInfo;
+use replication_checker::slot;
+use replication_checker::types::ReplicationConfig;
+use replication_checker::utils::PGConnection;
+use replication_checker::verify;
+use tracing::{error, info};
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOCATOR: replication_checker::allocstats::CountingAllocator =
+    replication_checker::allocstats::CountingAllocator;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The config file supplies defaults for both logging and the command line
+    let config_file = ConfigFile::from_args_or_env()?;
+
+    // Initialize logging from environment variables
+    let logging_config = LoggingConfig::from_env_and_file(&config_file)?;
+    logging_config.init_logging()?;
+
+    let result = match Cli::parse_with_config(&config_file)?.into_command() {
+        Command::Stream(args) => stream(args).await,
+        Command::CreateSlot(args) => create_slot(&args),
+        Command::DropSlot(args) => drop_slot(&args),
+        Command::AdvanceSlot(args) => advance_slot(&args),
+        Command::Replay(args) => replay(args),
+        Command::ReplayDlq(args) => replay_dlq(&args).await,
+        Command::Status(args) => status(&args),
+        Command::Check(args) => check(&args),
+        Command::Verify(args) => verify(&args),
+        Command::Physical(args) => physical(&args).await,
+    };
+    logging::shutdown();
+    result
+}
+
+/// Create the logical replication slot using the pgoutput plugin
+fn create_slot(args: &SlotArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let connection = PGConnection::connect(&args.conninfo()?)?;
+    connection.log_tls_state();
+    slot::create_logical_slot(&connection, &args.slot_name, false)?;
+    info!("Replication slot {} created", args.slot_name);
+    Ok(())
+}
+
+/// Drop the replication slot, waiting for it to become inactive
+fn drop_slot(args: &SlotArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let connection = PGConnection::connect(&args.conninfo()?)?;
+    connection.log_tls_state();
+    slot::drop_slot(&connection, &args.slot_name)?;
+    info!("Replication slot {} dropped", args.slot_name);
+    Ok(())
+}
+
+/// Advance the slot over a regular connection, or with --dry-run only report how much WAL
+/// advancing would release
+fn advance_slot(args: &AdvanceSlotArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let connection = PGConnection::connect(&regular_conninfo(&args.slot.conninfo()?))?;
+    connection.log_tls_state();
+    let advance = slot::plan_slot_advance(&connection, &args.slot.slot_name, args.lsn)?;
+    advance.log();
+    if args.dry_run {
+        info!(
+            "Dry run: replication slot {} was not advanced",
+            args.slot.slot_name
+        );
+        return Ok(());
+    }
+
+    let lsn = slot::advance_slot(&connection, &advance)?;
+    info!(
+        "Replication slot {} advanced to {}",
+        args.slot.slot_name, lsn
+    );
+    Ok(())
+}
+
+/// Log a health snapshot of the slot, or of every slot, without starting a stream
+fn status(args: &StatusArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let connection = PGConnection::connect(&args.slot.conninfo()?)?;
+    connection.log_tls_state();
+    // Only a replication connection can run IDENTIFY_SYSTEM
+    if let Ok(info) = ServerInfo::fetch(&connection) {
+        info.log();
+    }
+    let slot_name = (!args.all_slots).then_some(args.slot.slot_name.as_str());
+    let slots = slot::slot_status(&connection, slot_name)?;
+    if slots.is_empty() {
+        return match slot_name {
+            Some(slot_name) => Err(format!("Replication slot {} does not exist", slot_name).into()),
+            None => {
+                info!("No replication slots found");
+                Ok(())
+            }
+        };
+    }
+
+    for slot in &slots {
+        slot.log();
+    }
+    Ok(())
+}
+
+/// Check the stream's configuration against the server without streaming, failing when a
+/// source has a problem
+fn check(args: &StreamArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let reports = check::check_stream(args, BackendKind::Libpq);
+    for report in &reports {
+        report.log();
+    }
+
+    let problems: usize = reports.iter().map(CheckReport::problems).sum();
+    let warnings: usize = reports.iter().map(CheckReport::warnings).sum();
+    info!(
+        "Checked {} sources: {} problems, {} warnings",
+        reports.len(),
+        problems,
+        warnings
+    );
+    match problems {
+        0 => Ok(()),
+        _ => Err(format!("{} problems found, see the report above", problems).into()),
+    }
+}
+
+/// Observe physical replication until the duration passes or Ctrl+C
+async fn physical(args: &PhysicalArgs) -> Result<(), Box<dyn std::error::Error>> {
+    physical::observe(&args.physical_config()?).await?;
+    Ok(())
+}
+
+/// Compare the tables between the publisher and the subscriber, failing when any differ
+fn verify(args: &VerifyArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let source = PGConnection::connect(&regular_conninfo(&args.source.conninfo()?))?;
+    source.log_tls_state();
+    let target = PGConnection::connect(&regular_conninfo(&args.target_connection_string))?;
+    let reports = verify::verify_tables(&source, &target, &args.verify_config());
+    for report in &reports {
+        report.log();
+    }
+
+    let differing = reports.iter().filter(|report| !report.matches()).count();
+    info!(
+        "Verified {} tables: {} match, {} differ or could not be compared",
+        reports.len(),
+        reports.len() - differing,
+        differing
+    );
+    match differing {
+        0 => Ok(()),
+        _ => Err(format!("{} of {} tables differ", differing, reports.len()).into()),
+    }
+}
+
+/// Send the events of a dead-letter file to their sinks again
+async fn replay_dlq(args: &ReplayDlqArgs) -> Result<(), Box<dyn std::error::Error>> {
+    dlq::replay_dead_letters(&args.file, &args.sinks, args.sink.as_deref()).await?;
+    Ok(())
+}
+
+async fn stream(args: StreamArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if let Err(e) = backend::run_stream(args, BackendKind::Libpq).await {
+        // An alert with the exit action stops the process with its own exit code
+        if let Some(code) = e.exit_code() {
+            error!("{}", e);
+            std::process::exit(code);
+        }
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Decode a capture file offline, applying the same display options as `stream`
+fn replay(args: ReplayArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = ReplicationConfig::offline();
+    args.display.apply_to(&mut config)?;
+    backend::log_selection(&config);
+    config.stop_conditions.max_events = args.max_events;
+
+    info!("Replaying capture file {}", args.file.display());
+    let mut server = ReplicationServer::offline(config)?;
+    server.replay(&args.file)?;
+    Ok(())
+}
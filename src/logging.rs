@@ -1,6 +1,7 @@
 //! Logging configuration module for PostgreSQL replication checker
 //! Supports both console and file logging based on environment variables
 
+use crate::config::ConfigFile;
 use anyhow::{Context, Result};
 use std::env;
 use std::fs;
@@ -10,6 +11,8 @@ use std::str::FromStr;
 use std::sync::OnceLock;
 use tracing::{info, warn};
 use tracing_appender::{non_blocking, rolling};
+#[cfg(feature = "otlp")]
+use tracing_subscriber::filter::FilterExt;
 use tracing_subscriber::{
     fmt::{self, time::ChronoUtc},
     layer::SubscriberExt,
@@ -21,9 +24,10 @@ use tracing_subscriber::{
 static LOGGING_GUARD: OnceLock<tracing_appender::non_blocking::WorkerGuard> = OnceLock::new();
 
 /// Log output destinations
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Default, PartialEq)]
 pub enum LogOutput {
     /// Log only to console/stderr
+    #[default]
     Console,
     /// Log only to file
     File,
@@ -31,12 +35,6 @@ pub enum LogOutput {
     All,
 }
 
-impl Default for LogOutput {
-    fn default() -> Self {
-        LogOutput::Console
-    }
-}
-
 impl FromStr for LogOutput {
     type Err = anyhow::Error;
 
@@ -45,7 +43,10 @@ impl FromStr for LogOutput {
             "console" => Ok(LogOutput::Console),
             "file" => Ok(LogOutput::File),
             "all" | "both" => Ok(LogOutput::All),
-            _ => Err(anyhow::anyhow!("Invalid log output: {}. Valid values are: console, file, all", s)),
+            _ => Err(anyhow::anyhow!(
+                "Invalid log output: {}. Valid values are: console, file, all",
+                s
+            )),
         }
     }
 }
@@ -93,28 +94,29 @@ impl Default for LoggingConfig {
 }
 
 impl LoggingConfig {
-    /// Create logging configuration from environment variables
-    pub fn from_env() -> Result<Self> {
+    /// Create logging configuration from environment variables, falling back to the
+    /// `[logging]` table of the config file
+    pub fn from_env_and_file(file: &ConfigFile) -> Result<Self> {
+        let var = |name: &str| env::var(name).ok().or_else(|| file.logging_value(name));
         let mut config = Self::default();
 
         // Log output configuration - replaces separate console/file flags
-        if let Ok(val) = env::var("LOG_OUTPUT") {
-            config.log_output = LogOutput::from_str(&val)
-                .context("Invalid LOG_OUTPUT value")?;
+        if let Some(val) = var("LOG_OUTPUT") {
+            config.log_output = LogOutput::from_str(&val).context("Invalid LOG_OUTPUT value")?;
         }
 
         // Log directory
-        if let Ok(val) = env::var("LOG_DIRECTORY") {
+        if let Some(val) = var("LOG_DIRECTORY") {
             config.log_directory = PathBuf::from(val);
         }
 
         // Log file prefix
-        if let Ok(val) = env::var("LOG_FILE_PREFIX") {
+        if let Some(val) = var("LOG_FILE_PREFIX") {
             config.log_file_prefix = val;
         }
 
         // Rotation policy
-        if let Ok(val) = env::var("LOG_ROTATION") {
+        if let Some(val) = var("LOG_ROTATION") {
             config.rotation = match val.to_lowercase().as_str() {
                 "never" | "none" => LogRotation::Never,
                 "hourly" | "hour" => LogRotation::Hourly,
@@ -128,17 +130,17 @@ impl LoggingConfig {
         }
 
         // log level
-        if let Ok(val) = env::var("LOG_LEVEL") {
+        if let Some(val) = var("LOG_LEVEL") {
             config.log_level = val;
         }
 
         // JSON format
-        if let Ok(val) = env::var("LOG_JSON_FORMAT") {
+        if let Some(val) = var("LOG_JSON_FORMAT") {
             config.json_format = val.parse().unwrap_or(false);
         }
 
         // ANSI colors
-        if let Ok(val) = env::var("LOG_ANSI_ENABLED") {
+        if let Some(val) = var("LOG_ANSI_ENABLED") {
             config.ansi_enabled = val.parse().unwrap_or(true);
         }
 
@@ -155,8 +157,8 @@ impl LoggingConfig {
 
         // Console layer
         if console_enabled {
-            let console_filter = EnvFilter::try_new(&self.log_level)
-                .context("Invalid console log level")?;
+            let console_filter =
+                EnvFilter::try_new(&self.log_level).context("Invalid console log level")?;
 
             let console_layer = fmt::layer()
                 .with_writer(io::stderr)
@@ -178,14 +180,14 @@ impl LoggingConfig {
                     .context("Failed to create log directory")?;
             }
 
-            let file_filter = EnvFilter::try_new(&self.log_level)
-                .context("Invalid file log level")?;
+            let file_filter =
+                EnvFilter::try_new(&self.log_level).context("Invalid file log level")?;
 
             let file_writer = self.create_file_writer()?;
             let (non_blocking_writer, guard) = non_blocking(file_writer);
 
             // Store the guard globally to keep it alive for the entire application lifecycle
-            if let Err(_) = LOGGING_GUARD.set(guard) {
+            if LOGGING_GUARD.set(guard).is_err() {
                 warn!("Logging guard already set, this may cause log loss");
             }
 
@@ -218,6 +220,18 @@ impl LoggingConfig {
             return Err(anyhow::anyhow!("No logging layers enabled"));
         }
 
+        // Spans go to the OTLP endpoint when the OTEL_* variables ask for it
+        #[cfg(feature = "otlp")]
+        if let Some(otlp_layer) = crate::otlp::init().context("Invalid OpenTelemetry settings")? {
+            let otlp_filter =
+                EnvFilter::try_new(&self.log_level).context("Invalid OpenTelemetry log level")?;
+            layers.push(
+                otlp_layer
+                    .with_filter(otlp_filter.and(crate::otlp::exported_spans()))
+                    .boxed(),
+            );
+        }
+
         // Initialize the subscriber
         tracing_subscriber::registry()
             .with(layers)
@@ -228,6 +242,10 @@ impl LoggingConfig {
             "Logging initialized: output={:?}, json={}",
             self.log_output, self.json_format
         );
+        #[cfg(feature = "otlp")]
+        if let Some(endpoints) = crate::otlp::describe() {
+            info!("OpenTelemetry export enabled: {}", endpoints);
+        }
 
         Ok(())
     }
@@ -236,7 +254,9 @@ impl LoggingConfig {
     fn create_file_writer(&self) -> Result<Box<dyn io::Write + Send + Sync>> {
         match self.rotation {
             LogRotation::Never => {
-                let log_file = self.log_directory.join(format!("{}.log", self.log_file_prefix));
+                let log_file = self
+                    .log_directory
+                    .join(format!("{}.log", self.log_file_prefix));
                 let file = fs::OpenOptions::new()
                     .create(true)
                     .append(true)
@@ -261,3 +281,9 @@ impl LoggingConfig {
         }
     }
 }
+
+/// Send the telemetry still queued for export before the process exits
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    crate::otlp::shutdown();
+}
@@ -2,126 +2,202 @@
 //!
 //! A Rust implementation of a PostgreSQL logical replication client that connects to a database,
 //! creates replication slots, and displays changes in real-time using pg-walstream library.
+//! `--backend libpq` streams with the in-tree decoder instead.
 //!
 //! Based on the C++ implementation: https://github.com/fkfk000/replication_checker
 
-mod logging;
-
-use crate::logging::LoggingConfig;
-use std::env;
-use std::time::Duration;
-use tokio::signal;
+use replication_checker::backend::{self, BackendKind};
+use replication_checker::check::{self, CheckReport};
+use replication_checker::cli::{
+    AdvanceSlotArgs, Cli, Command, PhysicalArgs, ReplayDlqArgs, SlotArgs, StatusArgs, StreamArgs,
+    VerifyArgs,
+};
+use replication_checker::config::ConfigFile;
+use replication_checker::control::regular_conninfo;
+use replication_checker::dlq;
+use replication_checker::logging::{self, LoggingConfig};
+use replication_checker::physical;
+use replication_checker::quote::{quote_ident, quote_literal};
+use replication_checker::slot;
+use replication_checker::utils::PGConnection;
+use replication_checker::verify;
 use tracing::{error, info, warn};
 
-use pg_walstream::{
-    CancellationToken, LogicalReplicationStream, ReplicationStreamConfig, RetryConfig,
-    SharedLsnFeedback,
-};
+use pg_walstream::PgReplicationConnection;
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOCATOR: replication_checker::allocstats::CountingAllocator =
+    replication_checker::allocstats::CountingAllocator;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The config file supplies defaults for both logging and the command line
+    let config_file = ConfigFile::from_args_or_env()?;
+
     // Initialize logging from environment variables
-    let logging_config = LoggingConfig::from_env()?;
+    let logging_config = LoggingConfig::from_env_and_file(&config_file)?;
     logging_config.init_logging()?;
 
-    // Check for required environment variables
-    let slot_name = env::var("slot_name").unwrap_or_else(|_| "sub".to_string());
-    let publication_name = env::var("pub_name").unwrap_or_else(|_| "pub".to_string());
-
-    info!("Slot name: {}", slot_name);
-    info!("Publication name: {}", publication_name);
-
-    // Get connection string from environment variable
-    let connection_string = env::var("DB_CONNECTION_STRING")
-        .map_err(|_| "DB_CONNECTION_STRING environment variable not set")?;
-
-    info!("Using connection string with replication enabled");
-
-    // Create configuration
-    let config = ReplicationStreamConfig::new(
-        slot_name,
-        publication_name,
-        2, // Protocol version 2 - supports streaming transactions
-        true, // Enable streaming for large transactions
-        Duration::from_secs(10), // Feedback interval
-        Duration::from_secs(30), // Connection timeout
-        Duration::from_secs(60), // Health check interval
-        RetryConfig::default(), // Use default retry configuration
-    );
-
-    // Run the replication stream
-    match run_replication_stream(&connection_string, config).await {
-        Ok(()) => {
-            info!("Replication stream completed successfully");
-            Ok(())
-        }
-        Err(e) => {
-            error!("Replication stream failed: {}", e);
-            Err(e)
+    let result = match Cli::parse_with_config(&config_file)?.into_command() {
+        Command::Stream(args) => stream(args).await,
+        Command::CreateSlot(args) => create_slot(&args),
+        Command::DropSlot(args) => drop_slot(&args),
+        Command::AdvanceSlot(args) => advance_slot(&args),
+        Command::Replay(_) => {
+            Err("replay decodes with the in-tree parser, use pg_replica_libpq".into())
         }
-    }
+        Command::ReplayDlq(args) => replay_dlq(&args).await,
+        Command::Status(args) => status(&args),
+        Command::Check(args) => check(&args),
+        Command::Verify(args) => verify(&args),
+        Command::Physical(args) => physical(&args).await,
+    };
+    logging::shutdown();
+    result
 }
 
-async fn run_replication_stream(
-    connection_string: &str,
-    config: ReplicationStreamConfig,
-) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Creating logical replication stream");
+/// Create the logical replication slot using the pgoutput plugin
+fn create_slot(args: &SlotArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let connection = PgReplicationConnection::connect(&args.conninfo()?)?;
+    connection.create_replication_slot(&args.slot_name, "pgoutput")?;
+    info!("Replication slot {} created", args.slot_name);
+    Ok(())
+}
 
-    // Create the replication stream
-    let mut stream = LogicalReplicationStream::new(connection_string, config).await?;
+/// Drop the replication slot, waiting for it to become inactive
+///
+/// Falls back to pg_drop_replication_slot() when the replication command is rejected, such as
+/// over a regular (non-replication) connection
+fn drop_slot(args: &SlotArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let connection = PgReplicationConnection::connect(&args.conninfo()?)?;
+    if let Err(e) = connection.exec(&format!(
+        "DROP_REPLICATION_SLOT {} WAIT",
+        quote_ident(&args.slot_name)
+    )) {
+        warn!(
+            "DROP_REPLICATION_SLOT failed: {}. Retrying with pg_drop_replication_slot()",
+            e
+        );
+        connection.exec(&format!(
+            "SELECT pg_drop_replication_slot({})",
+            quote_literal(&args.slot_name)
+        ))?;
+    }
+    info!("Replication slot {} dropped", args.slot_name);
+    Ok(())
+}
 
-    // Set up LSN feedback for tracking progress
-    let lsn_feedback = SharedLsnFeedback::new_shared();
-    stream.set_shared_lsn_feedback(lsn_feedback.clone());
+/// Advance the slot over a regular connection, or with --dry-run only report how much WAL
+/// advancing would release
+fn advance_slot(args: &AdvanceSlotArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let connection = PGConnection::connect(&regular_conninfo(&args.slot.conninfo()?))?;
+    connection.log_tls_state();
+    let advance = slot::plan_slot_advance(&connection, &args.slot.slot_name, args.lsn)?;
+    advance.log();
+    if args.dry_run {
+        info!(
+            "Dry run: replication slot {} was not advanced",
+            args.slot.slot_name
+        );
+        return Ok(());
+    }
 
-    info!("Starting replication stream from latest position");
+    let lsn = slot::advance_slot(&connection, &advance)?;
+    info!(
+        "Replication slot {} advanced to {}",
+        args.slot.slot_name, lsn
+    );
+    Ok(())
+}
 
-    // Start replication from the beginning (None = start from latest)
-    stream.start(None).await?;
+/// Log a health snapshot of the slot, or of every slot, without starting a stream
+fn status(args: &StatusArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let connection = PGConnection::connect(&args.slot.conninfo()?)?;
+    connection.log_tls_state();
+    let slot_name = (!args.all_slots).then_some(args.slot.slot_name.as_str());
+    let slots = slot::slot_status(&connection, slot_name)?;
+    if slots.is_empty() {
+        return match slot_name {
+            Some(slot_name) => Err(format!("Replication slot {} does not exist", slot_name).into()),
+            None => {
+                info!("No replication slots found");
+                Ok(())
+            }
+        };
+    }
 
-    // Create cancellation token for graceful shutdown
-    let cancel_token = CancellationToken::new();
-    let cancel_token_clone = cancel_token.clone();
+    for slot in &slots {
+        slot.log();
+    }
+    Ok(())
+}
 
-    // Set up graceful shutdown handling
-    tokio::spawn(async move {
-        signal::ctrl_c()
-            .await
-            .expect("Failed to install CTRL+C signal handler");
-        warn!("Received interrupt signal, shutting down gracefully...");
-        cancel_token_clone.cancel();
-    });
+/// Check the stream's configuration against the server without streaming, failing when a
+/// source has a problem
+fn check(args: &StreamArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let reports = check::check_stream(args, BackendKind::Walstream);
+    for report in &reports {
+        report.log();
+    }
 
-    info!("Processing replication events (Press Ctrl+C to stop)...");
+    let problems: usize = reports.iter().map(CheckReport::problems).sum();
+    let warnings: usize = reports.iter().map(CheckReport::warnings).sum();
+    info!(
+        "Checked {} sources: {} problems, {} warnings",
+        reports.len(),
+        problems,
+        warnings
+    );
+    match problems {
+        0 => Ok(()),
+        _ => Err(format!("{} problems found, see the report above", problems).into()),
+    }
+}
 
-    // Process events in a loop
-    loop {
-        if cancel_token.is_cancelled() {
-            info!("Cancellation requested, stopping stream");
-            break;
-        }
+/// Observe physical replication until the duration passes or Ctrl+C
+async fn physical(args: &PhysicalArgs) -> Result<(), Box<dyn std::error::Error>> {
+    physical::observe(&args.physical_config()?).await?;
+    Ok(())
+}
 
-        match stream.next_event(&cancel_token).await? {
-            Some(event) => {
-                // Display the received event
-                info!("Event: {:?}", event);
+/// Compare the tables between the publisher and the subscriber, failing when any differ
+fn verify(args: &VerifyArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let source = PGConnection::connect(&regular_conninfo(&args.source.conninfo()?))?;
+    source.log_tls_state();
+    let target = PGConnection::connect(&regular_conninfo(&args.target_connection_string))?;
+    let reports = verify::verify_tables(&source, &target, &args.verify_config());
+    for report in &reports {
+        report.log();
+    }
 
-                // Update LSN feedback after processing
-                if let Some(lsn) = event.lsn {
-                    lsn_feedback.update_applied_lsn(lsn.value());
-                }
-            }
-            None => {
-                // No event available, continue
-                tokio::time::sleep(Duration::from_millis(10)).await;
-            }
-        }
+    let differing = reports.iter().filter(|report| !report.matches()).count();
+    info!(
+        "Verified {} tables: {} match, {} differ or could not be compared",
+        reports.len(),
+        reports.len() - differing,
+        differing
+    );
+    match differing {
+        0 => Ok(()),
+        _ => Err(format!("{} of {} tables differ", differing, reports.len()).into()),
     }
+}
 
-    info!("Stopping replication stream");
-    stream.stop().await?;
-    info!("Graceful shutdown completed");
+/// Send the events of a dead-letter file to their sinks again
+async fn replay_dlq(args: &ReplayDlqArgs) -> Result<(), Box<dyn std::error::Error>> {
+    dlq::replay_dead_letters(&args.file, &args.sinks, args.sink.as_deref()).await?;
+    Ok(())
+}
 
+async fn stream(args: StreamArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if let Err(e) = backend::run_stream(args, BackendKind::Walstream).await {
+        // An alert with the exit action stops the process with its own exit code
+        if let Some(code) = e.exit_code() {
+            error!("{}", e);
+            std::process::exit(code);
+        }
+        return Err(e.into());
+    }
     Ok(())
 }
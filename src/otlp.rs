@@ -0,0 +1,500 @@
+//! OpenTelemetry export of traces and metrics
+//! Built with the `otlp` feature. Spans, such as the transaction spans, are exported with the
+//! events logged within them through `tracing-opentelemetry`, and the series of the health
+//! endpoint's `/metrics` are observed by the OpenTelemetry SDK on every export, so both reach
+//! Jaeger, Tempo or a Prometheus-compatible backend through an OpenTelemetry collector. The
+//! exporters are `opentelemetry-otlp`'s over HTTP, as protobuf or JSON, configured with the
+//! standard `OTEL_*` environment variables.
+//!
+//! The `source` span of a stream lasts the whole run, so it is not exported; the transaction
+//! spans within it carry the source's name and each starts a trace of its own.
+
+use crate::errors::{ReplicationError, Result};
+use crate::health::{HealthSources, HealthState};
+use opentelemetry::metrics::{AsyncInstrument, Meter, MeterProvider};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::{InstrumentationScope, Key, KeyValue};
+use opentelemetry_otlp::{
+    ExporterBuildError, MetricExporter, Protocol, SpanExporter, WithExportConfig,
+};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+use opentelemetry_sdk::Resource;
+use std::env;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tracing::{warn, Metadata};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::filter::{filter_fn, FilterFn};
+use tracing_subscriber::Registry;
+
+/// Span that lasts the whole run and is not exported
+const RUN_SPAN: &str = "source";
+
+/// Longest exiting waits for the queued spans to be sent
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where and how one signal is exported
+#[derive(Debug, Clone)]
+pub struct SignalConfig {
+    pub endpoint: String,
+    pub protocol: Protocol,
+}
+
+/// Configuration read from the `OTEL_*` environment variables
+///
+/// Headers, timeouts, span batching and the metric interval are left to the SDK, which reads
+/// the variables itself.
+#[derive(Debug, Clone)]
+pub struct OtlpConfig {
+    /// None when trace export is off
+    pub traces: Option<SignalConfig>,
+    /// None when metric export is off
+    pub metrics: Option<SignalConfig>,
+}
+
+impl OtlpConfig {
+    /// Read the exporter settings, None when neither traces nor metrics are to be exported
+    ///
+    /// Export is on once OTEL_EXPORTER_OTLP_ENDPOINT or a signal's own endpoint is set, or
+    /// OTEL_TRACES_EXPORTER or OTEL_METRICS_EXPORTER is `otlp`; OTEL_SDK_DISABLED turns it off.
+    pub fn from_env() -> Result<Option<Self>> {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.trim().is_empty());
+        if var("OTEL_SDK_DISABLED").is_some_and(|value| value.eq_ignore_ascii_case("true")) {
+            return Ok(None);
+        }
+
+        let base = var("OTEL_EXPORTER_OTLP_ENDPOINT");
+        let signal = |signal: &str, path: &str| -> Result<Option<SignalConfig>> {
+            let upper = signal.to_uppercase();
+            let exporter = var(&format!("OTEL_{}_EXPORTER", upper));
+            match exporter.as_deref() {
+                Some("none") => return Ok(None),
+                Some("otlp") | None => {}
+                Some(other) => {
+                    return Err(ReplicationError::config(format!(
+                        "Unsupported OTEL_{}_EXPORTER {}: only otlp and none are supported",
+                        upper, other
+                    )))
+                }
+            }
+            let endpoint = match (
+                var(&format!("OTEL_EXPORTER_OTLP_{}_ENDPOINT", upper)),
+                &base,
+            ) {
+                (Some(endpoint), _) => endpoint,
+                (None, Some(base)) => format!("{}/{}", base.trim_end_matches('/'), path),
+                (None, None) if exporter.is_some() => format!("http://localhost:4318/{}", path),
+                (None, None) => return Ok(None),
+            };
+            // Checked now so a bad URL stops the run before it starts
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                return Err(ReplicationError::config(format!(
+                    "Invalid OTLP {} endpoint {}: expected an http:// or https:// URL",
+                    signal, endpoint
+                )));
+            }
+
+            let mut protocol = Protocol::HttpBinary;
+            for name in [
+                format!("OTEL_EXPORTER_OTLP_{}_PROTOCOL", upper),
+                "OTEL_EXPORTER_OTLP_PROTOCOL".to_string(),
+            ] {
+                if let Some(value) = var(&name) {
+                    protocol = match value.as_str() {
+                        "http/protobuf" => Protocol::HttpBinary,
+                        "http/json" => Protocol::HttpJson,
+                        other => {
+                            return Err(ReplicationError::config(format!(
+                                "Unsupported {} {}: only http/protobuf and http/json are supported",
+                                name, other
+                            )))
+                        }
+                    };
+                    break;
+                }
+            }
+            Ok(Some(SignalConfig { endpoint, protocol }))
+        };
+        let traces = signal("traces", "v1/traces")?;
+        let metrics = signal("metrics", "v1/metrics")?;
+        if traces.is_none() && metrics.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self { traces, metrics }))
+    }
+}
+
+/// The layer exporting spans
+pub type OtlpLayer = OpenTelemetryLayer<Registry, Tracer>;
+
+/// The configuration read at startup, when export is on
+static CONFIG: OnceLock<OtlpConfig> = OnceLock::new();
+
+static TRACER_PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+static METER_PROVIDER: OnceLock<SdkMeterProvider> = OnceLock::new();
+
+/// Read the configuration, start the exporters and return the layer exporting spans, None when
+/// trace export is off
+pub fn init() -> Result<Option<OtlpLayer>> {
+    let Some(config) = OtlpConfig::from_env()? else {
+        return Ok(None);
+    };
+    let config = CONFIG.get_or_init(|| config);
+    let resource = resource();
+
+    if let Some(metrics) = &config.metrics {
+        let exporter = MetricExporter::builder()
+            .with_http()
+            .with_protocol(metrics.protocol)
+            .with_endpoint(metrics.endpoint.clone())
+            .build()
+            .map_err(|e| build_error("metric", e))?;
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter).build())
+            .with_resource(resource.clone())
+            .build();
+        let _ = METER_PROVIDER.set(provider);
+    }
+
+    let Some(traces) = &config.traces else {
+        return Ok(None);
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_protocol(traces.protocol)
+        .with_endpoint(traces.endpoint.clone())
+        .build()
+        .map_err(|e| build_error("span", e))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+    let tracer = provider.tracer_with_scope(scope());
+    let _ = TRACER_PROVIDER.set(provider);
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+fn build_error(exporter: &str, error: ExporterBuildError) -> ReplicationError {
+    ReplicationError::config(format!(
+        "Failed to start the OTLP {} exporter: {}",
+        exporter, error
+    ))
+}
+
+/// Filter of the spans exported, all but the `source` span lasting the whole run
+pub fn exported_spans() -> FilterFn<impl Fn(&Metadata<'_>) -> bool> {
+    filter_fn(|metadata| !(metadata.is_span() && metadata.name() == RUN_SPAN))
+}
+
+/// The resource of the `OTEL_*` variables, `service.name` defaulting to the binary name
+fn resource() -> Resource {
+    let detected = Resource::builder().build();
+    let mut resource = Resource::builder();
+    let unnamed = detected
+        .get(&Key::from_static_str("service.name"))
+        .is_none_or(|name| name.as_str().starts_with("unknown_service"));
+    if unnamed {
+        let program = env::args()
+            .next()
+            .as_deref()
+            .and_then(|program| Path::new(program).file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string());
+        resource = resource.with_service_name(program);
+    }
+    if detected
+        .get(&Key::from_static_str("service.version"))
+        .is_none()
+    {
+        resource =
+            resource.with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")));
+    }
+    resource.build()
+}
+
+fn scope() -> InstrumentationScope {
+    InstrumentationScope::builder(env!("CARGO_PKG_NAME"))
+        .with_version(env!("CARGO_PKG_VERSION"))
+        .build()
+}
+
+/// Endpoints the exporters send to, for the startup log line
+pub fn describe() -> Option<String> {
+    let config = CONFIG.get()?;
+    let signal = |signal: &Option<SignalConfig>| match signal {
+        Some(signal) => format!(
+            "{} ({})",
+            signal.endpoint,
+            match signal.protocol {
+                Protocol::HttpJson => "http/json",
+                _ => "http/protobuf",
+            }
+        ),
+        None => "off".to_string(),
+    };
+    Some(format!(
+        "traces={}, metrics={}",
+        signal(&config.traces),
+        signal(&config.metrics)
+    ))
+}
+
+/// Send the spans still queued, waiting at most a few seconds, and the final metric values
+pub fn shutdown() {
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        if let Err(e) = provider.shutdown_with_timeout(SHUTDOWN_TIMEOUT) {
+            warn!("Failed to export the remaining spans: {}", e);
+        }
+    }
+    if let Some(provider) = METER_PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            warn!("Failed to export the final metrics: {}", e);
+        }
+    }
+}
+
+/// Observe the health endpoint's metrics of every source on each export, with the connection
+/// state as gauges; nothing to do when metric export is off
+pub fn register_metrics(sources: HealthSources) {
+    let Some(provider) = METER_PROVIDER.get() else {
+        return;
+    };
+    let meter = provider.meter_with_scope(scope());
+    let sources = Arc::new(sources);
+
+    // Summaries have no instrument of their own, so the quantiles are a gauge beside the sum;
+    // replication_events_total is the count
+    meter
+        .f64_observable_gauge("replication_commit_delay_seconds")
+        .with_description("Delay between a transaction's commit and its changes being received")
+        .with_unit("s")
+        .with_callback(observe(&sources, |state| {
+            state
+                .table_delays()
+                .into_iter()
+                .flat_map(|delay| {
+                    [
+                        ("0.5", delay.p50_seconds),
+                        ("0.95", delay.p95_seconds),
+                        ("0.99", delay.p99_seconds),
+                    ]
+                    .map(|(quantile, value)| {
+                        (
+                            vec![
+                                KeyValue::new("table", delay.table.clone()),
+                                KeyValue::new("quantile", quantile),
+                            ],
+                            value,
+                        )
+                    })
+                })
+                .collect()
+        }))
+        .build();
+    meter
+        .f64_observable_counter("replication_commit_delay_seconds_sum")
+        .with_description("Commit delay of the changes received per table, summed")
+        .with_unit("s")
+        .with_callback(observe(&sources, |state| {
+            state
+                .table_delays()
+                .into_iter()
+                .map(|delay| (table(delay.table), delay.sum_seconds))
+                .collect()
+        }))
+        .build();
+    meter
+        .u64_observable_counter("replication_events_total")
+        .with_description("Changes received per table")
+        .with_unit("1")
+        .with_callback(observe(&sources, |state| {
+            state
+                .table_delays()
+                .into_iter()
+                .map(|delay| (table(delay.table), delay.events))
+                .collect()
+        }))
+        .build();
+    meter
+        .u64_observable_counter("replication_events_sampled_out_total")
+        .with_description("Changes of sampled tables left out of the output")
+        .with_unit("1")
+        .with_callback(observe(&sources, |state| {
+            state
+                .sampled_out()
+                .into_iter()
+                .map(|(name, count)| (table(name), count))
+                .collect()
+        }))
+        .build();
+    meter
+        .u64_observable_counter("replication_errors_total")
+        .with_description("Errors that ended a replication attempt, retried or fatal")
+        .with_unit("1")
+        .with_callback(observe(&sources, |state| {
+            state
+                .error_counts()
+                .into_iter()
+                .map(|((kind, code, retryable), count)| {
+                    (
+                        vec![
+                            KeyValue::new("kind", kind.to_string()),
+                            KeyValue::new("code", code),
+                            KeyValue::new("retryable", retryable.to_string()),
+                        ],
+                        count,
+                    )
+                })
+                .collect()
+        }))
+        .build();
+
+    // Liveness depends on the health endpoint's timeout, so only its inputs are exported
+    meter
+        .u64_observable_gauge("replication_connected")
+        .with_description("Whether the replication connection is up")
+        .with_unit("1")
+        .with_callback(observe(&sources, |state| {
+            vec![(Vec::new(), u64::from(state.report(Duration::MAX).connected))]
+        }))
+        .build();
+    meter
+        .u64_observable_gauge("replication_slot_active")
+        .with_description("Whether the slot is streaming to this connection")
+        .with_unit("1")
+        .with_callback(observe(&sources, |state| {
+            vec![(
+                Vec::new(),
+                u64::from(state.report(Duration::MAX).slot_active),
+            )]
+        }))
+        .build();
+
+    let gauges: [(&'static str, &'static str, &'static str, SourceValue); 9] = [
+        (
+            "replication_seconds_since_last_message",
+            "Time since the last message or keepalive from the server",
+            "s",
+            |state| Some(state.report(Duration::MAX).seconds_since_last_message),
+        ),
+        (
+            "replication_server_wal_end_lsn",
+            "Latest WAL end reported by the server",
+            "By",
+            |state| state.lag().map(|lag| lag.server_wal_end.value() as f64),
+        ),
+        (
+            "replication_received_bytes_behind",
+            "Bytes of WAL between the received position and the server's WAL end",
+            "By",
+            |state| state.lag().map(|lag| lag.received_bytes_behind as f64),
+        ),
+        (
+            "replication_applied_bytes_behind",
+            "Bytes of WAL between the applied position and the server's WAL end",
+            "By",
+            |state| state.lag().map(|lag| lag.applied_bytes_behind as f64),
+        ),
+        (
+            "replication_seconds_behind",
+            "Delay between the server sending the last message and its arrival",
+            "s",
+            |state| state.lag().map(|lag| lag.seconds_behind),
+        ),
+        (
+            "replication_events_per_second",
+            "Change events processed per second over the last stats interval",
+            "1/s",
+            |state| state.lag().map(|lag| lag.events_per_sec),
+        ),
+        (
+            "replication_catch_up_bytes_remaining",
+            "Bytes of WAL left to consume to reach the server's WAL end",
+            "By",
+            |state| {
+                state
+                    .catch_up()
+                    .map(|progress| progress.bytes_remaining as f64)
+            },
+        ),
+        (
+            "replication_catch_up_bytes_per_second",
+            "WAL consumed per second over the last progress interval",
+            "By/s",
+            |state| state.catch_up().map(|progress| progress.bytes_per_sec),
+        ),
+        (
+            "replication_catch_up_eta_seconds",
+            "Estimated time until caught up, absent while no WAL is being consumed",
+            "s",
+            |state| {
+                state
+                    .catch_up()
+                    .and_then(|progress| progress.eta)
+                    .map(|eta| eta.as_secs_f64())
+            },
+        ),
+    ];
+    for (name, description, unit, value) in gauges {
+        source_gauge(&meter, &sources, name, description, unit, value);
+    }
+}
+
+/// One value of a source, None when it has none yet
+type SourceValue = fn(&HealthState) -> Option<f64>;
+
+/// The points of a source, each with its attributes
+type SourcePoints<T> = fn(&HealthState) -> Vec<(Vec<KeyValue>, T)>;
+
+/// Callback observing the points of every source, with a `source` attribute when it is named
+fn observe<T: 'static>(
+    sources: &Arc<HealthSources>,
+    points: SourcePoints<T>,
+) -> impl Fn(&dyn AsyncInstrument<T>) + Send + Sync + 'static {
+    let sources = Arc::clone(sources);
+    move |instrument| {
+        for (source, state) in sources.iter() {
+            for (mut attributes, value) in points(state) {
+                if let Some(source) = source {
+                    attributes.insert(0, KeyValue::new("source", source.clone()));
+                }
+                instrument.observe(value, &attributes);
+            }
+        }
+    }
+}
+
+/// A gauge of one value per source
+fn source_gauge(
+    meter: &Meter,
+    sources: &Arc<HealthSources>,
+    name: &'static str,
+    description: &'static str,
+    unit: &'static str,
+    value: SourceValue,
+) {
+    let sources = Arc::clone(sources);
+    meter
+        .f64_observable_gauge(name)
+        .with_description(description)
+        .with_unit(unit)
+        .with_callback(move |instrument| {
+            for (source, state) in sources.iter() {
+                if let Some(value) = value(state) {
+                    let attributes: Vec<KeyValue> = source
+                        .iter()
+                        .map(|source| KeyValue::new("source", source.clone()))
+                        .collect();
+                    instrument.observe(value, &attributes);
+                }
+            }
+        })
+        .build();
+}
+
+fn table(name: String) -> Vec<KeyValue> {
+    vec![KeyValue::new("table", name)]
+}
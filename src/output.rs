@@ -0,0 +1,220 @@
+//! Structured output of decoded change events
+//! Writes INSERT/UPDATE/DELETE/TRUNCATE and schema change events as newline-delimited JSON to stdout or a file,
+//! optionally gzip or zstd compressed
+
+use crate::compress::{CompressedWriter, Compression};
+use crate::envelope::TransactionInfo;
+use crate::relcache::SchemaDiff;
+use crate::row::Row;
+use crate::watch::ChangeKind;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Output format for change events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Human-readable tracing log lines only
+    #[default]
+    Log,
+    /// Newline-delimited JSON, one change per line
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "log" => Ok(OutputFormat::Log),
+            "json" | "ndjson" => Ok(OutputFormat::Json),
+            _ => Err(anyhow::anyhow!(
+                "Invalid output format: {}. Valid values are: log, json",
+                s
+            )),
+        }
+    }
+}
+
+/// Layout of the change events in the structured output and the JSON sinks
+///
+/// Version 1 is the layout from before events were versioned. Version 2 adds `schema_version`
+/// as the first field of every row change and schema change record. Within a version, fields
+/// are only ever added, never renamed, removed or given another type, so consumers should
+/// ignore fields they do not know; anything else raises the version, and the previous one stays
+/// selectable for a while.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EventSchemaVersion {
+    V1,
+    #[default]
+    V2,
+}
+
+impl EventSchemaVersion {
+    /// The version written unless an older one is requested
+    pub const LATEST: Self = EventSchemaVersion::V2;
+
+    pub fn number(self) -> u32 {
+        match self {
+            EventSchemaVersion::V1 => 1,
+            EventSchemaVersion::V2 => 2,
+        }
+    }
+
+    /// Value of the `schema_version` field, None for version 1, which has no such field
+    pub fn field(self) -> Option<u32> {
+        match self {
+            EventSchemaVersion::V1 => None,
+            version => Some(version.number()),
+        }
+    }
+}
+
+impl FromStr for EventSchemaVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "1" => Ok(EventSchemaVersion::V1),
+            "2" | "latest" => Ok(EventSchemaVersion::V2),
+            _ => Err(anyhow::anyhow!(
+                "Invalid event schema version: {}. Valid values are: 1, 2, latest",
+                s
+            )),
+        }
+    }
+}
+
+/// Where and how change events are written
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutputConfig {
+    pub format: OutputFormat,
+    /// File to append events to, stdout when not set
+    pub path: Option<PathBuf>,
+    /// Compression of the output file, and of the capture and CSV files written next to it
+    pub compression: Compression,
+}
+
+/// A single row change as written to the structured output
+///
+/// Fields of schema version 2 in order: `schema_version`, `id`, `source`, `op`, `schema`,
+/// `table`, `lsn`, `xid`, `publications`, `transaction`, `old` and `new`. Only
+/// `schema_version`, `op`, `schema` and `table` are always present; the others are left out
+/// when they have no value. Rows map column names to their text value or null.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeRecord {
+    /// Event schema version, left out in version 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u32>,
+    /// Deterministic ID for deduplicating redelivered events, when event IDs are enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Replication source the change came from, when several are streamed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub op: ChangeKind,
+    pub schema: String,
+    pub table: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lsn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xid: Option<u32>,
+    /// Publication set of the stream; pgoutput does not say which publication matched a change
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub publications: Vec<String>,
+    /// Transaction metadata, when the transaction envelope is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<TransactionInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<Row>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new: Option<Row>,
+}
+
+/// A table whose columns changed, detected from a Relation message that differs from the cached
+/// version, passed to the sinks ahead of the changes using the new columns
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaChangeRecord {
+    /// Event schema version, left out in version 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Always `schema_change`, telling these records apart from row changes
+    pub op: &'static str,
+    pub schema: String,
+    pub table: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lsn: Option<String>,
+    /// Relation version after the change, starting at 1 when the table was first seen
+    pub version: u32,
+    #[serde(flatten)]
+    pub diff: SchemaDiff,
+}
+
+/// Writes change records as NDJSON
+pub struct EventWriter {
+    writer: Box<dyn Write + Send>,
+    /// The output file, synced before its position is confirmed; None for stdout
+    file: Option<fs::File>,
+}
+
+impl EventWriter {
+    /// Open the writer for the configured destination, or None when structured output is off
+    pub fn open(config: &OutputConfig) -> Result<Option<Self>> {
+        if config.format == OutputFormat::Log {
+            return Ok(None);
+        }
+
+        let mut synced = None;
+        let writer: Box<dyn Write + Send> = match &config.path {
+            Some(path) => {
+                // Each run appends a gzip member or zstd frame, which decompress as one stream
+                let path = config.compression.file_path(path);
+                let file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| format!("Failed to open output file {}", path.display()))?;
+                synced = Some(file.try_clone().context("Failed to open output file")?);
+                Box::new(CompressedWriter::new(
+                    BufWriter::new(file),
+                    config.compression,
+                ))
+            }
+            None => Box::new(io::stdout()),
+        };
+
+        Ok(Some(Self {
+            writer,
+            file: synced,
+        }))
+    }
+
+    /// Serialize one record as a JSON line and flush it; compressed output is flushed a block
+    /// at a time and finished when the writer is dropped
+    pub fn write_record(&mut self, record: &impl Serialize) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, record).context("Failed to serialize event")?;
+        self.writer
+            .write_all(b"\n")
+            .context("Failed to write event")?;
+        self.writer
+            .flush()
+            .context("Failed to flush event output")?;
+        Ok(())
+    }
+
+    /// Flush the output and sync the file to disk
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .context("Failed to flush event output")?;
+        if let Some(file) = &self.file {
+            file.sync_data().context("Failed to sync the output file")?;
+        }
+        Ok(())
+    }
+}
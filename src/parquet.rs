@@ -0,0 +1,491 @@
+//! Parquet file sink for change events
+//! Writes the changes of each table to Parquet files partitioned by table and commit date, with a
+//! typed column per table column derived from its Relation message. Files are written with the
+//! parquet crate's Arrow writer, uncompressed, and get their final name once closed, so readers
+//! never see a partial file.
+
+use crate::errors::{ReplicationError, Result};
+use crate::handler::ChangeHandler;
+use crate::mask::ColumnMask;
+use crate::output::ChangeRecord;
+use crate::row::Row;
+use crate::types::{Lsn, RelationInfo};
+use crate::utils::{Oid, Xid};
+use crate::watch::ChangeKind;
+use arrow::array::{
+    ArrayRef, BooleanArray, Float32Array, Float64Array, Int32Array, Int64Array, RecordBatch,
+    StringArray, TimestampMicrosecondArray,
+};
+use arrow::datatypes::{DataType, Field as ArrowField, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Utc};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+const CREATED_BY: &str = concat!("pg_replica_rs version ", env!("CARGO_PKG_VERSION"));
+
+/// Where Parquet files are written and how many rows each row group holds
+#[derive(Debug, Clone)]
+pub struct ParquetConfig {
+    /// Root of the `schema.table/date=YYYY-MM-DD/` partitions
+    pub directory: PathBuf,
+    /// Rows buffered in memory before they are written as a row group
+    pub row_group_size: usize,
+}
+
+/// Logical type of a Parquet column, chosen from the PostgreSQL type of the table column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnKind {
+    Boolean,
+    Int32,
+    Int64,
+    Float,
+    Double,
+    Utf8,
+    TimestampMicros,
+}
+
+impl ColumnKind {
+    fn for_type(type_oid: Oid) -> Self {
+        match type_oid {
+            16 => ColumnKind::Boolean,
+            21 | 23 => ColumnKind::Int32,
+            20 | 26 => ColumnKind::Int64,
+            700 => ColumnKind::Float,
+            701 => ColumnKind::Double,
+            _ => ColumnKind::Utf8,
+        }
+    }
+
+    /// Arrow type of the column, which the writer maps to its Parquet type
+    fn data_type(self) -> DataType {
+        match self {
+            ColumnKind::Boolean => DataType::Boolean,
+            ColumnKind::Int32 => DataType::Int32,
+            ColumnKind::Int64 => DataType::Int64,
+            ColumnKind::Float => DataType::Float32,
+            ColumnKind::Double => DataType::Float64,
+            ColumnKind::Utf8 => DataType::Utf8,
+            ColumnKind::TimestampMicros => {
+                DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+            }
+        }
+    }
+
+    /// Parse the text form of a value, None when it does not fit the column type
+    fn parse(self, text: &str) -> Option<Field> {
+        match self {
+            ColumnKind::Boolean => match text {
+                "t" | "true" => Some(Field::Boolean(true)),
+                "f" | "false" => Some(Field::Boolean(false)),
+                _ => None,
+            },
+            ColumnKind::Int32 => text.parse().ok().map(Field::Int32),
+            ColumnKind::Int64 | ColumnKind::TimestampMicros => text.parse().ok().map(Field::Int64),
+            ColumnKind::Float => text.parse().ok().map(Field::Float),
+            ColumnKind::Double => text.parse().ok().map(Field::Double),
+            ColumnKind::Utf8 => Some(Field::Text(text.to_string())),
+        }
+    }
+}
+
+/// A non-null value of a row
+#[derive(Debug, Clone)]
+enum Field {
+    Boolean(bool),
+    Int32(i32),
+    Int64(i64),
+    Float(f32),
+    Double(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Column {
+    name: String,
+    kind: ColumnKind,
+}
+
+/// Columns of a table's files: the change metadata followed by the table columns
+fn table_schema(relation: &RelationInfo, mask: &ColumnMask) -> Vec<Column> {
+    let metadata = [
+        ("_op", ColumnKind::Utf8),
+        ("_lsn", ColumnKind::Utf8),
+        ("_xid", ColumnKind::Int64),
+        ("_commit_time", ColumnKind::TimestampMicros),
+    ];
+    let columns = relation.columns.iter().map(|column| Column {
+        name: column.column_name.clone(),
+        // Masked values are replaced with text, whatever the column type
+        kind: if mask.matches(
+            &relation.namespace,
+            &relation.relation_name,
+            &column.column_name,
+        ) {
+            ColumnKind::Utf8
+        } else {
+            ColumnKind::for_type(column.column_type)
+        },
+    });
+
+    metadata
+        .iter()
+        .map(|(name, kind)| Column {
+            name: name.to_string(),
+            kind: *kind,
+        })
+        .chain(columns)
+        .collect()
+}
+
+/// Arrow schema of a table's files, every column nullable
+fn arrow_schema(columns: &[Column]) -> SchemaRef {
+    Arc::new(Schema::new(
+        columns
+            .iter()
+            .map(|column| ArrowField::new(&column.name, column.kind.data_type(), true))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// Values of one column of the buffered rows as an Arrow array of the column's type
+fn column_array(kind: ColumnKind, rows: &[Vec<Option<Field>>], index: usize) -> ArrayRef {
+    let values = rows.iter().map(|row| row[index].as_ref());
+    match kind {
+        ColumnKind::Boolean => Arc::new(BooleanArray::from_iter(values.map(|field| match field {
+            Some(Field::Boolean(value)) => Some(*value),
+            _ => None,
+        }))),
+        ColumnKind::Int32 => Arc::new(Int32Array::from_iter(values.map(|field| match field {
+            Some(Field::Int32(value)) => Some(*value),
+            _ => None,
+        }))),
+        ColumnKind::Int64 => Arc::new(Int64Array::from_iter(values.map(|field| match field {
+            Some(Field::Int64(value)) => Some(*value),
+            _ => None,
+        }))),
+        ColumnKind::TimestampMicros => Arc::new(
+            TimestampMicrosecondArray::from_iter(values.map(|field| match field {
+                Some(Field::Int64(value)) => Some(*value),
+                _ => None,
+            }))
+            .with_timezone("UTC"),
+        ),
+        ColumnKind::Float => Arc::new(Float32Array::from_iter(values.map(|field| match field {
+            Some(Field::Float(value)) => Some(*value),
+            _ => None,
+        }))),
+        ColumnKind::Double => Arc::new(Float64Array::from_iter(values.map(|field| match field {
+            Some(Field::Double(value)) => Some(*value),
+            _ => None,
+        }))),
+        ColumnKind::Utf8 => Arc::new(StringArray::from_iter(values.map(|field| match field {
+            Some(Field::Text(value)) => Some(value.as_str()),
+            _ => None,
+        }))),
+    }
+}
+
+/// An open Parquet file of one table partition
+struct PartitionWriter {
+    date: String,
+    path: PathBuf,
+    in_progress_path: PathBuf,
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+    columns: Vec<Column>,
+    rows: Vec<Vec<Option<Field>>>,
+    num_rows: i64,
+}
+
+impl PartitionWriter {
+    fn create(
+        path: PathBuf,
+        date: String,
+        columns: Vec<Column>,
+        row_group_size: usize,
+    ) -> Result<Self> {
+        let in_progress_path = path.with_extension("parquet.inprogress");
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                ReplicationError::parquet(format!("Failed to create {}: {}", parent.display(), e))
+            })?;
+        }
+        let file = File::create(&in_progress_path).map_err(|e| {
+            ReplicationError::parquet(format!(
+                "Failed to create {}: {}",
+                in_progress_path.display(),
+                e
+            ))
+        })?;
+
+        let schema = arrow_schema(&columns);
+        let properties = WriterProperties::builder()
+            .set_created_by(CREATED_BY.to_string())
+            .set_compression(Compression::UNCOMPRESSED)
+            .set_max_row_group_size(row_group_size)
+            .build();
+        let writer = ArrowWriter::try_new(file, schema.clone(), Some(properties)).map_err(|e| {
+            ReplicationError::parquet(format!(
+                "Failed to start {}: {}",
+                in_progress_path.display(),
+                e
+            ))
+        })?;
+
+        Ok(Self {
+            date,
+            path,
+            in_progress_path,
+            writer,
+            schema,
+            columns,
+            rows: Vec::new(),
+            num_rows: 0,
+        })
+    }
+
+    /// Write the buffered rows as a row group
+    fn write_row_group(&mut self) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+
+        let rows = std::mem::take(&mut self.rows);
+        let arrays = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| column_array(column.kind, &rows, index))
+            .collect();
+        let error = |e: &dyn std::fmt::Display| {
+            ReplicationError::parquet(format!(
+                "Failed to write {}: {}",
+                self.in_progress_path.display(),
+                e
+            ))
+        };
+        let batch = RecordBatch::try_new(self.schema.clone(), arrays).map_err(|e| error(&e))?;
+        self.writer.write(&batch).map_err(|e| error(&e))?;
+        self.writer.flush().map_err(|e| error(&e))?;
+
+        self.num_rows += rows.len() as i64;
+        Ok(())
+    }
+
+    /// Write the remaining rows and the footer, then give the file its final name
+    fn close(mut self) -> Result<(PathBuf, i64)> {
+        self.write_row_group()?;
+        self.writer.close().map_err(|e| {
+            ReplicationError::parquet(format!(
+                "Failed to finish {}: {}",
+                self.in_progress_path.display(),
+                e
+            ))
+        })?;
+        fs::rename(&self.in_progress_path, &self.path).map_err(|e| {
+            ReplicationError::parquet(format!(
+                "Failed to rename {} to {}: {}",
+                self.in_progress_path.display(),
+                self.path.display(),
+                e
+            ))
+        })?;
+        Ok((self.path, self.num_rows))
+    }
+}
+
+/// Writes change events of every table to partitioned Parquet files
+pub struct ParquetSink {
+    config: ParquetConfig,
+    mask: ColumnMask,
+    schemas: HashMap<(String, String), Vec<Column>>,
+    writers: HashMap<(String, String), PartitionWriter>,
+    /// Commit time of the transaction being received, if it started with a Begin
+    commit_time: Option<DateTime<Utc>>,
+    files_written: u64,
+    rows_written: i64,
+}
+
+impl ParquetSink {
+    pub fn new(config: ParquetConfig, mask: ColumnMask) -> Result<Self> {
+        fs::create_dir_all(&config.directory).map_err(|e| {
+            ReplicationError::parquet(format!(
+                "Failed to create {}: {}",
+                config.directory.display(),
+                e
+            ))
+        })?;
+        info!(
+            "Writing change events to Parquet files under {}",
+            config.directory.display()
+        );
+        Ok(Self {
+            config,
+            mask,
+            schemas: HashMap::new(),
+            writers: HashMap::new(),
+            commit_time: None,
+            files_written: 0,
+            rows_written: 0,
+        })
+    }
+
+    /// Derive the schema of a table from its Relation message, closing its open file when the
+    /// columns changed so the next rows start a file with the new schema
+    pub fn register_relation(&mut self, relation: &RelationInfo) -> Result<()> {
+        let key = (relation.namespace.clone(), relation.relation_name.clone());
+        let columns = table_schema(relation, &self.mask);
+        if self.schemas.get(&key) == Some(&columns) {
+            return Ok(());
+        }
+        if let Some(writer) = self.writers.remove(&key) {
+            self.finish_file(writer)?;
+        }
+        self.schemas.insert(key, columns);
+        Ok(())
+    }
+
+    /// Close every open file, writing its remaining rows
+    pub fn close(&mut self) -> Result<()> {
+        let writers: Vec<PartitionWriter> = self.writers.drain().map(|(_, w)| w).collect();
+        for writer in writers {
+            self.finish_file(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Files completed so far and the rows they hold
+    pub fn files_written(&self) -> (u64, i64) {
+        (self.files_written, self.rows_written)
+    }
+
+    fn finish_file(&mut self, writer: PartitionWriter) -> Result<()> {
+        let (path, rows) = writer.close()?;
+        debug!("Wrote {} rows to {}", rows, path.display());
+        self.files_written += 1;
+        self.rows_written += rows;
+        Ok(())
+    }
+
+    /// The open file of a table partition, starting a new one on a new date
+    fn writer_for(
+        &mut self,
+        key: &(String, String),
+        date: &str,
+    ) -> Result<Option<&mut PartitionWriter>> {
+        if self
+            .writers
+            .get(key)
+            .is_some_and(|writer| writer.date != date)
+        {
+            if let Some(writer) = self.writers.remove(key) {
+                self.finish_file(writer)?;
+            }
+        }
+        let Some(columns) = self.schemas.get(key) else {
+            return Ok(None);
+        };
+
+        if !self.writers.contains_key(key) {
+            let path = self
+                .config
+                .directory
+                .join(format!("{}.{}", key.0, key.1))
+                .join(format!("date={}", date))
+                .join(format!(
+                    "part-{}.parquet",
+                    Utc::now().format("%Y%m%dT%H%M%S%.6f")
+                ));
+            let writer = PartitionWriter::create(
+                path,
+                date.to_string(),
+                columns.clone(),
+                self.config.row_group_size,
+            )?;
+            self.writers.insert(key.clone(), writer);
+        }
+        Ok(self.writers.get_mut(key))
+    }
+}
+
+impl ChangeHandler for ParquetSink {
+    fn on_begin(&mut self, _xid: Xid, _final_lsn: Lsn, commit_time: DateTime<Utc>) -> Result<()> {
+        self.commit_time = Some(commit_time);
+        Ok(())
+    }
+
+    fn on_change(&mut self, change: &ChangeRecord) -> Result<()> {
+        let key = (change.schema.clone(), change.table.clone());
+        // Streamed transactions have no Begin, their changes are dated when received
+        let commit_time = self.commit_time;
+        let date = commit_time
+            .unwrap_or_else(Utc::now)
+            .format("%Y-%m-%d")
+            .to_string();
+        let row_group_size = self.config.row_group_size;
+
+        let Some(writer) = self.writer_for(&key, &date)? else {
+            debug!(
+                "No schema known for {}.{}, change not written to Parquet",
+                key.0, key.1
+            );
+            return Ok(());
+        };
+
+        let values = change.new.as_ref().or(change.old.as_ref());
+        let row = writer
+            .columns
+            .iter()
+            .map(|column| match column.name.as_str() {
+                "_op" => Some(Field::Text(op_name(change.op).to_string())),
+                "_lsn" => change.lsn.clone().map(Field::Text),
+                "_xid" => change.xid.map(|xid| Field::Int64(i64::from(xid))),
+                "_commit_time" => commit_time.map(|time| Field::Int64(time.timestamp_micros())),
+                name => column_value(values, name, column.kind),
+            })
+            .collect();
+        writer.rows.push(row);
+
+        if writer.rows.len() >= row_group_size {
+            writer.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn on_commit(
+        &mut self,
+        _commit_lsn: Lsn,
+        _end_lsn: Lsn,
+        _commit_time: DateTime<Utc>,
+    ) -> Result<()> {
+        self.commit_time = None;
+        Ok(())
+    }
+}
+
+fn op_name(op: ChangeKind) -> &'static str {
+    match op {
+        ChangeKind::Insert => "insert",
+        ChangeKind::Update => "update",
+        ChangeKind::Delete => "delete",
+        ChangeKind::Truncate => "truncate",
+    }
+}
+
+/// Typed value of a table column; NULL, unchanged TOAST values and unparsable text are null
+fn column_value(values: Option<&Row>, name: &str, kind: ColumnKind) -> Option<Field> {
+    let text = values?.get(name)?.as_text()?;
+    kind.parse(text).or_else(|| {
+        debug!(
+            "Value of column {} does not fit {:?}, written as null",
+            name, kind
+        );
+        None
+    })
+}
@@ -6,6 +6,12 @@ use crate::errors::{ReplicationError, Result};
 use crate::types::*;
 use tracing::{debug, error, warn};
 
+/// Most columns a table can have, PostgreSQL's MaxTupleAttributeNumber
+pub const MAX_COLUMNS: usize = 1664;
+
+/// Most relations accepted in one Truncate message
+pub const MAX_TRUNCATE_RELATIONS: usize = 65536;
+
 /// Parse logical replication messages from a buffer
 pub struct MessageParser;
 
@@ -15,15 +21,28 @@ impl MessageParser {
     /// Errors with ReplicationError on failure
     /// please refer to https://www.postgresql.org/docs/current/protocol-logicalrep-message-formats.html#PROTOCOL-LOGICALREP-MESSAGE-FORMATS
     pub fn parse_wal_message(buffer: &[u8], in_streaming_txn: bool) -> Result<ReplicationMessage> {
+        Self::parse_wal_message_ref(buffer, in_streaming_txn).map(ReplicationMessageRef::into_owned)
+    }
+
+    /// Parse a WAL message without copying names or column values out of the buffer
+    pub fn parse_wal_message_ref(
+        buffer: &[u8],
+        in_streaming_txn: bool,
+    ) -> Result<ReplicationMessageRef<'_>> {
         let mut reader = BufferReader::new(buffer);
         let message_type = reader.skip_message_type()?;
 
-        debug!("Parsing message type: {}, streaming: {}", message_type, in_streaming_txn);
+        debug!(
+            "Parsing message type: {}, streaming: {}",
+            message_type, in_streaming_txn
+        );
 
         match message_type {
             'B' => Self::parse_begin_message(&mut reader),
             'C' => Self::parse_commit_message(&mut reader),
             'R' => Self::parse_relation_message(&mut reader, in_streaming_txn),
+            'Y' => Self::parse_type_message(&mut reader, in_streaming_txn),
+            'O' => Self::parse_origin_message(&mut reader),
             'I' => Self::parse_insert_message(&mut reader),
             'U' => Self::parse_update_message(&mut reader),
             'D' => Self::parse_delete_message(&mut reader),
@@ -32,6 +51,7 @@ impl MessageParser {
             'E' => Self::parse_stream_stop_message(&mut reader),
             'c' => Self::parse_stream_commit_message(&mut reader),
             'A' => Self::parse_stream_abort_message(&mut reader),
+            'p' => Self::parse_stream_prepare_message(&mut reader),
             _ => {
                 warn!("Unknown message type: {}", message_type);
                 Err(ReplicationError::parse_with_context(
@@ -42,35 +62,37 @@ impl MessageParser {
         }
     }
 
-    fn parse_begin_message(reader: &mut BufferReader) -> Result<ReplicationMessage> {
+    fn parse_begin_message<'a>(reader: &mut BufferReader<'a>) -> Result<ReplicationMessageRef<'a>> {
         // BEGIN message: final_lsn (8) + timestamp (8) + xid (4) = 20 bytes + 1 for type
         if !reader.has_bytes(20) {
             return Err(ReplicationError::parse("Begin message too short"));
         }
 
-        let final_lsn = reader.read_u64()?;
+        let final_lsn = reader.read_lsn()?;
         let timestamp = reader.read_i64()?;
         let xid = reader.read_u32()?;
 
-        Ok(ReplicationMessage::Begin {
+        Ok(ReplicationMessageRef::Begin {
             final_lsn,
             timestamp,
             xid,
         })
     }
 
-    fn parse_commit_message(reader: &mut BufferReader) -> Result<ReplicationMessage> {
+    fn parse_commit_message<'a>(
+        reader: &mut BufferReader<'a>,
+    ) -> Result<ReplicationMessageRef<'a>> {
         // COMMIT message: flags (1) + commit_lsn (8) + end_lsn (8) + timestamp (8) = 25 bytes + 1 for type
         if !reader.has_bytes(25) {
             return Err(ReplicationError::parse("Commit message too short"));
         }
 
         let flags = reader.read_u8()?;
-        let commit_lsn = reader.read_u64()?;
-        let end_lsn = reader.read_u64()?;
+        let commit_lsn = reader.read_lsn()?;
+        let end_lsn = reader.read_lsn()?;
         let timestamp = reader.read_i64()?;
 
-        Ok(ReplicationMessage::Commit {
+        Ok(ReplicationMessageRef::Commit {
             flags,
             commit_lsn,
             end_lsn,
@@ -78,7 +100,10 @@ impl MessageParser {
         })
     }
 
-    fn parse_relation_message(reader: &mut BufferReader, in_streaming_txn: bool) -> Result<ReplicationMessage> {
+    fn parse_relation_message<'a>(
+        reader: &mut BufferReader<'a>,
+        in_streaming_txn: bool,
+    ) -> Result<ReplicationMessageRef<'a>> {
         // RELATION message in streaming mode: xid (4) + oid (4) + namespace (null-terminated) + relation_name (null-terminated) + replica_identity (1) + column_count (2) + columns
         // RELATION message in non-streaming mode: oid (4) + namespace (null-terminated) + relation_name (null-terminated) + replica_identity (1) + column_count (2) + columns
         let min_bytes = if in_streaming_txn { 11 } else { 7 };
@@ -98,8 +123,9 @@ impl MessageParser {
         let relation_name = reader.read_null_terminated_string()?;
         let replica_identity = reader.read_u8()? as char;
         let column_count = reader.read_i16()?;
+        let capacity = reader.check_count(column_count.into(), MAX_COLUMNS, 10, "columns")?;
 
-        let mut columns = Vec::with_capacity(column_count as usize);
+        let mut columns = Vec::with_capacity(capacity);
         for i in 0..column_count {
             if !reader.has_bytes(9) {
                 // Minimum: key_flag (1) + column_name (1) + column_type (4) + atttypmod (4)
@@ -119,6 +145,7 @@ impl MessageParser {
                 column_name,
                 column_type,
                 atttypmod,
+                type_name: None,
             });
         }
 
@@ -129,12 +156,68 @@ impl MessageParser {
             replica_identity,
             column_count,
             columns,
+            published_mask: None,
+        };
+
+        Ok(ReplicationMessageRef::Relation { relation })
+    }
+
+    fn parse_type_message<'a>(
+        reader: &mut BufferReader<'a>,
+        in_streaming_txn: bool,
+    ) -> Result<ReplicationMessageRef<'a>> {
+        // TYPE message: optional xid (4) in streaming mode + type oid (4) + namespace (null-terminated) + type_name (null-terminated)
+        let min_bytes = if in_streaming_txn { 10 } else { 6 };
+        if !reader.has_bytes(min_bytes) {
+            return Err(ReplicationError::parse("Type message too short"));
+        }
+
+        let _xid = if in_streaming_txn {
+            Some(reader.read_u32()?)
+        } else {
+            None
+        };
+
+        let oid = reader.read_u32()?;
+        let namespace = reader.read_null_terminated_string()?;
+        let type_name = reader.read_null_terminated_string()?;
+
+        // An empty namespace means pg_catalog
+        let namespace = if namespace.is_empty() {
+            "pg_catalog".to_string()
+        } else {
+            namespace
         };
 
-        Ok(ReplicationMessage::Relation { relation })
+        Ok(ReplicationMessageRef::Type {
+            type_info: TypeInfo {
+                oid,
+                namespace,
+                type_name,
+            },
+        })
     }
 
-    fn parse_insert_message(reader: &mut BufferReader) -> Result<ReplicationMessage> {
+    fn parse_origin_message<'a>(
+        reader: &mut BufferReader<'a>,
+    ) -> Result<ReplicationMessageRef<'a>> {
+        // ORIGIN message: origin commit lsn (8) + origin name (null-terminated)
+        if !reader.has_bytes(9) {
+            return Err(ReplicationError::parse("Origin message too short"));
+        }
+
+        let origin_lsn = reader.read_lsn()?;
+        let origin_name = reader.read_null_terminated_str()?;
+
+        Ok(ReplicationMessageRef::Origin {
+            origin_lsn,
+            origin_name,
+        })
+    }
+
+    fn parse_insert_message<'a>(
+        reader: &mut BufferReader<'a>,
+    ) -> Result<ReplicationMessageRef<'a>> {
         // INSERT message: first u32 could be relation_id or transaction_id depending on streaming
         if !reader.has_bytes(5) {
             // Minimum: transaction_id_or_oid (4) + 'N' marker (1)
@@ -164,7 +247,7 @@ impl MessageParser {
 
         let tuple_data = Self::parse_tuple_data(reader)?;
 
-        Ok(ReplicationMessage::Insert {
+        Ok(ReplicationMessageRef::Insert {
             relation_id,
             tuple_data,
             is_stream,
@@ -172,7 +255,9 @@ impl MessageParser {
         })
     }
 
-    fn parse_update_message(reader: &mut BufferReader) -> Result<ReplicationMessage> {
+    fn parse_update_message<'a>(
+        reader: &mut BufferReader<'a>,
+    ) -> Result<ReplicationMessageRef<'a>> {
         // UPDATE message: first u32 could be relation_id or transaction_id depending on streaming
         if !reader.has_bytes(5) {
             // Minimum: transaction_id_or_oid (4) + marker (1)
@@ -223,7 +308,7 @@ impl MessageParser {
 
         let new_tuple_data = Self::parse_tuple_data(reader)?;
 
-        Ok(ReplicationMessage::Update {
+        Ok(ReplicationMessageRef::Update {
             relation_id,
             key_type,
             old_tuple_data,
@@ -233,7 +318,9 @@ impl MessageParser {
         })
     }
 
-    fn parse_delete_message(reader: &mut BufferReader) -> Result<ReplicationMessage> {
+    fn parse_delete_message<'a>(
+        reader: &mut BufferReader<'a>,
+    ) -> Result<ReplicationMessageRef<'a>> {
         // DELETE message: first u32 could be relation_id or transaction_id depending on streaming
         if !reader.has_bytes(5) {
             // Minimum: transaction_id_or_oid (4) + key_type (1)
@@ -257,7 +344,7 @@ impl MessageParser {
 
         let tuple_data = Self::parse_tuple_data(reader)?;
 
-        Ok(ReplicationMessage::Delete {
+        Ok(ReplicationMessageRef::Delete {
             relation_id,
             key_type,
             tuple_data,
@@ -266,7 +353,9 @@ impl MessageParser {
         })
     }
 
-    fn parse_truncate_message(reader: &mut BufferReader) -> Result<ReplicationMessage> {
+    fn parse_truncate_message<'a>(
+        reader: &mut BufferReader<'a>,
+    ) -> Result<ReplicationMessageRef<'a>> {
         // TRUNCATE message: Complex logic to determine if streaming or not
         if !reader.has_bytes(9) {
             // Minimum: first_u32 (4) + second_u32 (4) + flags (1)
@@ -278,7 +367,7 @@ impl MessageParser {
 
         // Estimate remaining bytes needed based on second_u32 as potential relation count
         let remaining_bytes = reader.remaining();
-        let expected_for_streaming = 1 + (second_u32 as usize * 4); // flags + relation IDs
+        let expected_for_streaming = 1 + (second_u32 as usize).saturating_mul(4); // flags + relation IDs
 
         let (is_stream, xid, num_relations) = if remaining_bytes == expected_for_streaming {
             // Streaming transaction: first_u32 is xid, second_u32 is num_relations
@@ -291,8 +380,14 @@ impl MessageParser {
         };
 
         let flags = reader.read_u8()? as i8;
-
-        let mut relation_ids = Vec::with_capacity(num_relations as usize);
+        let capacity = reader.check_count(
+            num_relations.into(),
+            MAX_TRUNCATE_RELATIONS,
+            4,
+            "truncated relations",
+        )?;
+
+        let mut relation_ids = Vec::with_capacity(capacity);
         for i in 0..num_relations {
             if !reader.has_bytes(4) {
                 return Err(ReplicationError::parse_with_context(
@@ -304,7 +399,7 @@ impl MessageParser {
             relation_ids.push(relation_id);
         }
 
-        Ok(ReplicationMessage::Truncate {
+        Ok(ReplicationMessageRef::Truncate {
             relation_ids,
             flags,
             is_stream,
@@ -312,7 +407,9 @@ impl MessageParser {
         })
     }
 
-    fn parse_stream_start_message(reader: &mut BufferReader) -> Result<ReplicationMessage> {
+    fn parse_stream_start_message<'a>(
+        reader: &mut BufferReader<'a>,
+    ) -> Result<ReplicationMessageRef<'a>> {
         // STREAM START message: xid (4) + optional first_segment (1)
         if !reader.has_bytes(4) {
             return Err(ReplicationError::parse("Stream start message too short"));
@@ -326,15 +423,19 @@ impl MessageParser {
             false
         };
 
-        Ok(ReplicationMessage::StreamStart { xid, first_segment })
+        Ok(ReplicationMessageRef::StreamStart { xid, first_segment })
     }
 
-    fn parse_stream_stop_message(_reader: &mut BufferReader) -> Result<ReplicationMessage> {
+    fn parse_stream_stop_message(
+        _reader: &mut BufferReader,
+    ) -> Result<ReplicationMessageRef<'static>> {
         // STREAM STOP message has no additional data
-        Ok(ReplicationMessage::StreamStop)
+        Ok(ReplicationMessageRef::StreamStop)
     }
 
-    fn parse_stream_commit_message(reader: &mut BufferReader) -> Result<ReplicationMessage> {
+    fn parse_stream_commit_message<'a>(
+        reader: &mut BufferReader<'a>,
+    ) -> Result<ReplicationMessageRef<'a>> {
         // STREAM COMMIT message: xid (4) + flags (1) + commit_lsn (8) + end_lsn (8) + timestamp (8) = 29 bytes
         if !reader.has_bytes(29) {
             return Err(ReplicationError::parse("Stream commit message too short"));
@@ -342,11 +443,11 @@ impl MessageParser {
 
         let xid = reader.read_u32()?;
         let flags = reader.read_u8()?;
-        let commit_lsn = reader.read_u64()?;
-        let end_lsn = reader.read_u64()?;
+        let commit_lsn = reader.read_lsn()?;
+        let end_lsn = reader.read_lsn()?;
         let timestamp = reader.read_i64()?;
 
-        Ok(ReplicationMessage::StreamCommit {
+        Ok(ReplicationMessageRef::StreamCommit {
             xid,
             flags,
             commit_lsn,
@@ -355,7 +456,9 @@ impl MessageParser {
         })
     }
 
-    fn parse_stream_abort_message(reader: &mut BufferReader) -> Result<ReplicationMessage> {
+    fn parse_stream_abort_message<'a>(
+        reader: &mut BufferReader<'a>,
+    ) -> Result<ReplicationMessageRef<'a>> {
         // STREAM ABORT message: xid (4) + subtransaction_xid (4) = 8 bytes
         if !reader.has_bytes(8) {
             return Err(ReplicationError::parse("Stream abort message too short"));
@@ -364,13 +467,47 @@ impl MessageParser {
         let xid = reader.read_u32()?;
         let subtransaction_xid = reader.read_u32()?;
 
-        Ok(ReplicationMessage::StreamAbort {
+        // Protocol version 4 with parallel streaming adds abort_lsn (8) + abort_timestamp (8)
+        let (abort_lsn, abort_timestamp) = if reader.has_bytes(16) {
+            (Some(reader.read_lsn()?), Some(reader.read_i64()?))
+        } else {
+            (None, None)
+        };
+
+        Ok(ReplicationMessageRef::StreamAbort {
             xid,
             subtransaction_xid,
+            abort_lsn,
+            abort_timestamp,
+        })
+    }
+
+    fn parse_stream_prepare_message<'a>(
+        reader: &mut BufferReader<'a>,
+    ) -> Result<ReplicationMessageRef<'a>> {
+        // STREAM PREPARE message: flags (1) + prepare_lsn (8) + end_lsn (8) + timestamp (8) + xid (4) + gid (string)
+        if !reader.has_bytes(29) {
+            return Err(ReplicationError::parse("Stream prepare message too short"));
+        }
+
+        let flags = reader.read_u8()?;
+        let prepare_lsn = reader.read_lsn()?;
+        let end_lsn = reader.read_lsn()?;
+        let timestamp = reader.read_i64()?;
+        let xid = reader.read_u32()?;
+        let gid = reader.read_null_terminated_str()?;
+
+        Ok(ReplicationMessageRef::StreamPrepare {
+            flags,
+            prepare_lsn,
+            end_lsn,
+            timestamp,
+            xid,
+            gid,
         })
     }
 
-    fn parse_tuple_data(reader: &mut BufferReader) -> Result<TupleData> {
+    fn parse_tuple_data<'a>(reader: &mut BufferReader<'a>) -> Result<TupleDataRef<'a>> {
         // TUPLE DATA: column_count (2) + columns
         if !reader.has_bytes(2) {
             return Err(ReplicationError::parse("Tuple data too short"));
@@ -378,8 +515,10 @@ impl MessageParser {
 
         let start_position = reader.position();
         let column_count = reader.read_i16()?;
+        // Minimum: data_type (1), for a NULL or unchanged TOAST value
+        let capacity = reader.check_count(column_count.into(), MAX_COLUMNS, 1, "columns")?;
 
-        let mut columns = Vec::with_capacity(column_count as usize);
+        let mut columns = Vec::with_capacity(capacity);
 
         for i in 0..column_count {
             if !reader.has_bytes(1) {
@@ -394,28 +533,24 @@ impl MessageParser {
             let column_data = match data_type {
                 'n' => {
                     // NULL value
-                    ColumnData {
+                    ColumnDataRef {
                         data_type: 'n',
-                        length: 0,
-                        data: String::new(),
+                        data: &[],
                     }
                 }
                 'u' => {
                     // Unchanged TOAST value
                     debug!("Unchanged TOAST value encountered");
-                    ColumnData {
+                    ColumnDataRef {
                         data_type: 'u',
-                        length: 0,
-                        data: String::new(),
+                        data: &[],
                     }
                 }
-                't' => {
-                    // Text data with length prefix
-                    let text_data = reader.read_length_prefixed_string()?;
-                    ColumnData {
-                        data_type: 't',
-                        length: text_data.len() as i32,
-                        data: text_data,
+                't' | 'b' => {
+                    // Text or binary format data with length prefix
+                    ColumnDataRef {
+                        data_type,
+                        data: reader.read_length_prefixed_bytes()?,
                     }
                 }
                 _ => {
@@ -432,7 +567,7 @@ impl MessageParser {
 
         let processed_length = reader.position() - start_position;
 
-        Ok(TupleData {
+        Ok(TupleDataRef {
             column_count,
             columns,
             processed_length,
@@ -0,0 +1,403 @@
+//! Physical replication observation
+//! The `physical` command streams WAL with START_REPLICATION PHYSICAL the way a standby does,
+//! without keeping it: the XLogData headers are parsed to report WAL throughput and how far the
+//! stream is behind the server's end of WAL, and timeline switches are followed and logged
+//! with the switch point and reason from the new timeline's history.
+//! Optionally hot-standby feedback is sent, holding back the server's xmin like a standby
+//! running queries would.
+
+use crate::buffer::BufferReader;
+use crate::errors::{ReplicationError, Result};
+use crate::pipeline::StatusUpdate;
+use crate::quote::quote_ident;
+use crate::signals::ShutdownSignals;
+use crate::timeline::{identify_system, parse_timeline, TimelineSwitch};
+use crate::types::Lsn;
+use crate::utils::{system_time_to_postgres_timestamp, PGConnection, PGResult};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, warn};
+
+/// Longest the receive loop sleeps before checking timers and Ctrl+C
+const WAKEUP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How and for how long the physical stream is observed
+#[derive(Debug, Clone)]
+pub struct PhysicalConfig {
+    /// Connection string, switched to a physical replication connection
+    pub connection_string: String,
+    /// Physical slot to stream from, which then keeps the WAL this stream has not confirmed
+    pub slot_name: Option<String>,
+    /// Position to start at instead of the server's current end of WAL
+    pub start_lsn: Option<Lsn>,
+    /// Time between throughput reports
+    pub report_interval: Duration,
+    /// Time between standby status updates
+    pub status_interval: Duration,
+    /// Send hot-standby feedback with the server's oldest running xmin
+    pub hot_standby_feedback: bool,
+    /// Stop after this long, otherwise at Ctrl+C
+    pub duration: Option<Duration>,
+}
+
+/// A parsed XLogData ('w') or primary keepalive ('k') message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhysicalMessage {
+    XLogData {
+        start: Lsn,
+        server_end: Lsn,
+        send_time: i64,
+        length: usize,
+    },
+    Keepalive {
+        server_end: Lsn,
+        send_time: i64,
+        reply_requested: bool,
+    },
+}
+
+impl PhysicalMessage {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = BufferReader::new(data);
+        match reader.read_u8()? {
+            b'w' => {
+                let start = reader.read_lsn()?;
+                let server_end = reader.read_lsn()?;
+                let send_time = reader.read_i64()?;
+                Ok(Self::XLogData {
+                    start,
+                    server_end,
+                    send_time,
+                    length: reader.remaining(),
+                })
+            }
+            b'k' => Ok(Self::Keepalive {
+                server_end: reader.read_lsn()?,
+                send_time: reader.read_i64()?,
+                reply_requested: reader.read_u8()? != 0,
+            }),
+            other => Err(ReplicationError::protocol(format!(
+                "Unexpected message type '{}' in physical replication stream",
+                other as char
+            ))),
+        }
+    }
+}
+
+/// WAL received over the whole run and since the last report
+#[derive(Debug)]
+struct PhysicalStats {
+    started: Instant,
+    timeline: u32,
+    /// End of the WAL received so far
+    received: Lsn,
+    /// End of WAL on the server as of its last message
+    server_end: Lsn,
+    bytes: u64,
+    messages: u64,
+    timeline_switches: u32,
+    last_report: Instant,
+    bytes_at_report: u64,
+}
+
+impl PhysicalStats {
+    fn new(timeline: u32, start: Lsn) -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            timeline,
+            received: start,
+            server_end: start,
+            bytes: 0,
+            messages: 0,
+            timeline_switches: 0,
+            last_report: now,
+            bytes_at_report: 0,
+        }
+    }
+
+    fn report(&mut self) {
+        let elapsed = self.last_report.elapsed().as_secs_f64().max(f64::EPSILON);
+        let bytes = self.bytes - self.bytes_at_report;
+        info!(
+            timeline = self.timeline,
+            received_lsn = %self.received,
+            server_end_lsn = %self.server_end,
+            behind_bytes = self.server_end.bytes_since(self.received),
+            bytes_per_sec = bytes as f64 / elapsed,
+            "Physical stream on timeline {}: received up to {}, {:.1} KB/s, {} bytes behind the server's end of WAL {}",
+            self.timeline,
+            self.received,
+            bytes as f64 / 1024.0 / elapsed,
+            self.server_end.bytes_since(self.received),
+            self.server_end
+        );
+        self.last_report = Instant::now();
+        self.bytes_at_report = self.bytes;
+    }
+
+    fn log_summary(&self) {
+        let elapsed = self.started.elapsed().as_secs_f64().max(f64::EPSILON);
+        info!(
+            total_bytes = self.bytes,
+            messages = self.messages,
+            timeline = self.timeline,
+            timeline_switches = self.timeline_switches,
+            duration_secs = elapsed,
+            "Physical stream summary: {} bytes of WAL in {} messages over {:.1}s ({:.1} KB/s), ended on timeline {} after {} timeline switches",
+            self.bytes,
+            self.messages,
+            elapsed,
+            self.bytes as f64 / 1024.0 / elapsed,
+            self.timeline,
+            self.timeline_switches
+        );
+    }
+}
+
+/// The connection string with physical replication turned on; the last setting wins
+pub fn physical_conninfo(conninfo: &str) -> String {
+    let uri = conninfo.starts_with("postgres://") || conninfo.starts_with("postgresql://");
+    if uri {
+        let separator = if conninfo.contains('?') { '&' } else { '?' };
+        format!("{}{}replication=true", conninfo, separator)
+    } else {
+        format!("{} replication=true", conninfo.trim_end())
+    }
+}
+
+/// Stream WAL until the duration passes, Ctrl+C is pressed or the server ends streaming,
+/// following timeline switches
+pub async fn observe(config: &PhysicalConfig) -> Result<()> {
+    let mut connection = PGConnection::connect(&physical_conninfo(&config.connection_string))?;
+    connection.log_tls_state();
+    let feedback = match config.hot_standby_feedback {
+        true => Some(PGConnection::connect(&crate::control::regular_conninfo(
+            &config.connection_string,
+        ))?),
+        false => None,
+    };
+
+    let identity = identify_system(&connection)?;
+    let mut timeline = identity.timeline;
+    let mut start = config.start_lsn.unwrap_or(identity.xlogpos);
+    info!(
+        "Observing physical replication of system {} from {} on timeline {}",
+        identity.system_id, start, timeline
+    );
+
+    let mut stats = PhysicalStats::new(timeline, start);
+    let deadline = config.duration.map(|duration| Instant::now() + duration);
+    let mut signals = ShutdownSignals::new()?;
+    let mut async_enabled = false;
+
+    let result = loop {
+        start_replication(&connection, config.slot_name.as_deref(), start, timeline)?;
+        if !async_enabled {
+            connection.enable_async()?;
+            async_enabled = true;
+        }
+
+        let ended = tokio::select! {
+            ended = stream_timeline(&connection, config, feedback.as_ref(), &mut stats, deadline) => ended,
+            signal = signals.recv() => {
+                info!("Received {}, stopping the physical stream", signal);
+                Ok(StreamEnd::Stopped)
+            }
+        };
+        match ended {
+            Ok(StreamEnd::Timeline {
+                next_timeline,
+                next_start,
+            }) => {
+                let mut switch = TimelineSwitch::resolve(&connection, timeline, next_timeline);
+                // The server names where the next timeline starts even without a history
+                switch.switchpoint.get_or_insert(next_start);
+                switch.log();
+                timeline = next_timeline;
+                start = next_start;
+                stats.timeline = next_timeline;
+                stats.timeline_switches += 1;
+            }
+            Ok(StreamEnd::Stopped) => {
+                let _ = connection.end_copy(Duration::from_secs(1));
+                break Ok(());
+            }
+            Ok(StreamEnd::ServerStopped) => {
+                info!("Server ended the physical stream");
+                break Ok(());
+            }
+            Err(e) => break Err(e),
+        }
+    };
+
+    stats.log_summary();
+    result
+}
+
+/// How streaming a timeline ended
+enum StreamEnd {
+    /// The server reached the end of the timeline; the next one starts at `next_start`
+    Timeline { next_timeline: u32, next_start: Lsn },
+    /// The server ended streaming without a next timeline, e.g. while shutting down
+    ServerStopped,
+    /// The duration passed
+    Stopped,
+}
+
+/// Receive the current timeline until it ends or the deadline passes
+async fn stream_timeline(
+    connection: &PGConnection,
+    config: &PhysicalConfig,
+    feedback: Option<&PGConnection>,
+    stats: &mut PhysicalStats,
+    deadline: Option<Instant>,
+) -> Result<StreamEnd> {
+    let mut last_status = Instant::now();
+    loop {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            send_status(connection, stats.received, false)?;
+            return Ok(StreamEnd::Stopped);
+        }
+        if stats.last_report.elapsed() >= config.report_interval {
+            stats.report();
+        }
+        if last_status.elapsed() >= config.status_interval {
+            send_status(connection, stats.received, false)?;
+            if let Some(feedback) = feedback {
+                send_hot_standby_feedback(connection, feedback)?;
+            }
+            last_status = Instant::now();
+        }
+
+        let data = match connection.next_copy_data(WAKEUP_INTERVAL).await {
+            Ok(Some(data)) => data,
+            Ok(None) => continue,
+            // The server ends COPY BOTH mode at the end of a timeline or when it stops
+            Err(e) => return end_of_stream(connection).map_err(|_| e),
+        };
+
+        match PhysicalMessage::parse(&data)? {
+            PhysicalMessage::XLogData {
+                start,
+                server_end,
+                length,
+                ..
+            } => {
+                stats.received = start.advance(length as u64);
+                stats.server_end = server_end.max(stats.received);
+                stats.bytes += length as u64;
+                stats.messages += 1;
+            }
+            PhysicalMessage::Keepalive {
+                server_end,
+                reply_requested,
+                ..
+            } => {
+                stats.server_end = server_end;
+                if reply_requested {
+                    send_status(connection, stats.received, false)?;
+                    last_status = Instant::now();
+                }
+            }
+        }
+    }
+}
+
+fn start_replication(
+    connection: &PGConnection,
+    slot_name: Option<&str>,
+    start: Lsn,
+    timeline: u32,
+) -> Result<()> {
+    let slot = slot_name
+        .map(|slot_name| format!("SLOT {} ", quote_ident(slot_name)))
+        .unwrap_or_default();
+    let query = format!(
+        "START_REPLICATION {}PHYSICAL {} TIMELINE {}",
+        slot, start, timeline
+    );
+    debug!("{}", query);
+    let result = connection.exec(&query)?;
+    if !result.is_copy_both() {
+        return Err(ReplicationError::protocol(format!(
+            "{} failed: {}",
+            query,
+            result.error_message()
+        )));
+    }
+    Ok(())
+}
+
+/// Collect the results that follow the server ending COPY BOTH mode: the next timeline and
+/// where it starts at the end of a timeline, or only a command completion when streaming stops
+fn end_of_stream(connection: &PGConnection) -> Result<StreamEnd> {
+    let mut result = next_result(connection)?;
+    // The server ended its side of the copy first; end ours to receive the results
+    if result.is_copy_in() {
+        connection.end_copy(Duration::from_secs(10))?;
+        result = next_result(connection)?;
+    }
+    if !result.is_ok() {
+        return Err(ReplicationError::protocol(format!(
+            "Physical stream failed: {}",
+            result.error_message()
+        )));
+    }
+    let end = match result.ntuples() {
+        0 => StreamEnd::ServerStopped,
+        _ => StreamEnd::Timeline {
+            next_timeline: parse_timeline(result.getvalue(0, 0).as_deref())?,
+            next_start: result.getvalue(0, 1).unwrap_or_default().parse()?,
+        },
+    };
+    while connection.get_result().is_some() {}
+    Ok(end)
+}
+
+fn next_result(connection: &PGConnection) -> Result<PGResult> {
+    connection.get_result().ok_or_else(|| {
+        ReplicationError::connection("Replication stream ended by the server without a result")
+    })
+}
+
+/// Confirm everything received as written and flushed, so a slot releases it; none of it is
+/// applied
+fn send_status(connection: &PGConnection, received: Lsn, reply_requested: bool) -> Result<()> {
+    let mut status = StatusUpdate::new();
+    let status = status.encode(received, received, Lsn::INVALID, reply_requested)?;
+    connection.put_copy_data(status)?;
+    connection.flush()
+}
+
+/// Report the oldest xmin running on the server as this standby's xmin
+fn send_hot_standby_feedback(connection: &PGConnection, feedback: &PGConnection) -> Result<()> {
+    let result = feedback.exec("SELECT txid_snapshot_xmin(txid_current_snapshot())")?;
+    let Some(xmin) = result
+        .is_ok()
+        .then(|| result.getvalue(0, 0))
+        .flatten()
+        .and_then(|xmin| xmin.parse::<u64>().ok())
+    else {
+        warn!(
+            "Failed to read the xmin for hot-standby feedback: {}",
+            result.error_message()
+        );
+        return Ok(());
+    };
+    connection.put_copy_data(&hot_standby_feedback(xmin))?;
+    connection.flush()
+}
+
+/// Encode a hot-standby feedback ('h') message for a 64-bit xid with its epoch; the catalog
+/// xmin is left unset
+pub fn hot_standby_feedback(xmin: u64) -> Vec<u8> {
+    let timestamp = system_time_to_postgres_timestamp(SystemTime::now());
+    let mut message = Vec::with_capacity(25);
+    message.push(b'h');
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message.extend_from_slice(&(xmin as u32).to_be_bytes()); // xmin
+    message.extend_from_slice(&((xmin >> 32) as u32).to_be_bytes()); // xmin epoch
+    message.extend_from_slice(&0u32.to_be_bytes()); // catalog xmin
+    message.extend_from_slice(&0u32.to_be_bytes()); // catalog xmin epoch
+    message
+}
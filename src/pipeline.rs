@@ -0,0 +1,269 @@
+//! Receive stage of the streaming pipeline
+//! A receiver thread owns the replication connection while streaming: it reads CopyData into a
+//! bounded queue and answers the server, so a slow sink in the processing stage delays the queue
+//! instead of the keepalive replies that keep the server from timing the connection out
+
+use crate::buffer::BufferWriter;
+use crate::bufpool::PooledBuffer;
+use crate::errors::Result;
+use crate::reconnect::ReceiveWatchdog;
+use crate::types::Lsn;
+use crate::utils::{system_time_to_postgres_timestamp, PGConnection};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Longest the receiver waits for data or queue space before servicing status updates
+const RECEIVER_WAKEUP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// State shared by the receive and processing stages
+#[derive(Debug)]
+pub struct PipelineShared {
+    capacity: usize,
+    /// Positions the processing stage allows to be reported
+    received_lsn: AtomicU64,
+    flushed_lsn: AtomicU64,
+    applied_lsn: AtomicU64,
+    /// The processing stage wants a status update sent with the positions above
+    send_requested: AtomicBool,
+    stop: AtomicBool,
+    depth: AtomicUsize,
+    peak_depth: AtomicUsize,
+    /// Times the receiver found the queue full
+    full_waits: AtomicU64,
+}
+
+impl PipelineShared {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            received_lsn: AtomicU64::new(0),
+            flushed_lsn: AtomicU64::new(0),
+            applied_lsn: AtomicU64::new(0),
+            send_requested: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+            depth: AtomicUsize::new(0),
+            peak_depth: AtomicUsize::new(0),
+            full_waits: AtomicU64::new(0),
+        }
+    }
+
+    /// Publish the positions to report and ask the receiver to send them
+    pub fn request_status_update(&self, received: Lsn, flushed: Lsn, applied: Lsn) {
+        self.received_lsn.store(received.value(), Ordering::Relaxed);
+        self.flushed_lsn.store(flushed.value(), Ordering::Relaxed);
+        self.applied_lsn.store(applied.value(), Ordering::Relaxed);
+        self.send_requested.store(true, Ordering::Release);
+    }
+
+    /// Ask the receiver to return the connection
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Release);
+    }
+
+    /// Messages waiting in the queue
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// Record that the processing stage took a message off the queue
+    pub fn dequeued(&self) {
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn log_summary(&self) {
+        info!(
+            peak_depth = self.peak_depth.load(Ordering::Relaxed),
+            full_waits = self.full_waits.load(Ordering::Relaxed),
+            "Receive queue: peak depth {} of {}, full {} times",
+            self.peak_depth.load(Ordering::Relaxed),
+            self.capacity,
+            self.full_waits.load(Ordering::Relaxed)
+        );
+    }
+
+    fn enqueued(&self) {
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_depth.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    fn stopping(&self) -> bool {
+        self.stop.load(Ordering::Acquire)
+    }
+
+    fn positions(&self) -> (Lsn, Lsn, Lsn) {
+        (
+            Lsn(self.received_lsn.load(Ordering::Relaxed)),
+            Lsn(self.flushed_lsn.load(Ordering::Relaxed)),
+            Lsn(self.applied_lsn.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+/// Length of a standby status update: type, three positions, timestamp and reply flag
+pub const STATUS_UPDATE_LEN: usize = 1 + 8 + 8 + 8 + 8 + 1;
+
+/// Buffer standby status update ('r') messages are encoded into
+///
+/// The sender keeps one for the life of the stream, so status updates at high keepalive rates
+/// allocate nothing.
+#[derive(Debug)]
+pub struct StatusUpdate {
+    buffer: [u8; STATUS_UPDATE_LEN],
+}
+
+impl Default for StatusUpdate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatusUpdate {
+    pub fn new() -> Self {
+        Self {
+            buffer: [0; STATUS_UPDATE_LEN],
+        }
+    }
+
+    /// Encode an update stamped with the current time, returning the message
+    pub fn encode(
+        &mut self,
+        received: Lsn,
+        flushed: Lsn,
+        applied: Lsn,
+        reply_requested: bool,
+    ) -> Result<&[u8]> {
+        let timestamp = system_time_to_postgres_timestamp(SystemTime::now());
+        let mut writer = BufferWriter::new(&mut self.buffer);
+        writer.write_u8(b'r')?;
+        writer.write_u64(received.value())?; // Received LSN
+        writer.write_u64(flushed.value())?; // Flushed LSN
+        writer.write_u64(applied.value())?; // Applied LSN
+        writer.write_i64(timestamp)?; // Timestamp
+        writer.write_u8(u8::from(reply_requested))?; // Whether the server should reply
+        Ok(writer.into_written())
+    }
+}
+
+/// Receive stage: read CopyData into the queue until stopped, the queue is closed or the
+/// connection fails, then hand the connection back
+///
+/// Runs on its own blocking thread; status updates are sent when the processing stage asks,
+/// when the server requests a reply, and every `status_interval` while the queue is full. The
+/// connection is reported lost when nothing arrives for `receiver_timeout`.
+pub fn receive(
+    connection: PGConnection,
+    queue: mpsc::Sender<PooledBuffer>,
+    shared: Arc<PipelineShared>,
+    status_interval: Duration,
+    receiver_timeout: Option<Duration>,
+) -> (PGConnection, Result<()>) {
+    let handle = tokio::runtime::Handle::current();
+    let mut watchdog = ReceiveWatchdog::new(receiver_timeout);
+    let mut status = StatusUpdate::new();
+    let result = handle.block_on(receive_loop(
+        &connection,
+        &queue,
+        &shared,
+        status_interval,
+        &mut watchdog,
+        &mut status,
+    ));
+    (connection, result)
+}
+
+async fn receive_loop(
+    connection: &PGConnection,
+    queue: &mpsc::Sender<PooledBuffer>,
+    shared: &PipelineShared,
+    status_interval: Duration,
+    watchdog: &mut ReceiveWatchdog,
+    status: &mut StatusUpdate,
+) -> Result<()> {
+    loop {
+        if shared.stopping() || queue.is_closed() {
+            return Ok(());
+        }
+        send_requested_status(connection, shared, status)?;
+
+        let Some(data) = connection.next_copy_data(RECEIVER_WAKEUP_INTERVAL).await? else {
+            if watchdog.check()? {
+                send_status(connection, shared, status, true)?;
+            }
+            continue;
+        };
+        watchdog.received();
+
+        // Keepalives asking for a reply are answered before they wait in the queue
+        if data.first() == Some(&b'k') && data.get(17).is_some_and(|reply| *reply != 0) {
+            send_status(connection, shared, status, false)?;
+        }
+
+        let mut waiting_since: Option<Instant> = None;
+        let mut last_status = Instant::now();
+        let permit = loop {
+            match tokio::time::timeout(RECEIVER_WAKEUP_INTERVAL, queue.reserve()).await {
+                Ok(Ok(permit)) => break permit,
+                // The processing stage has stopped
+                Ok(Err(_)) => return Ok(()),
+                Err(_) => {
+                    if waiting_since.is_none() {
+                        shared.full_waits.fetch_add(1, Ordering::Relaxed);
+                        debug!("Receive queue is full, waiting for the processing stage");
+                    }
+                    let since = *waiting_since.get_or_insert_with(Instant::now);
+                    if shared.stopping() {
+                        return Ok(());
+                    }
+                    send_requested_status(connection, shared, status)?;
+                    // Keep the server from timing out while the processing stage catches up
+                    if last_status.elapsed() >= status_interval {
+                        warn!(
+                            "Receive queue full for {:.1}s, sending a status update",
+                            since.elapsed().as_secs_f64()
+                        );
+                        send_status(connection, shared, status, false)?;
+                        last_status = Instant::now();
+                    }
+                }
+            }
+        };
+        shared.enqueued();
+        permit.send(data);
+        // Time spent waiting for queue space is not the server's silence
+        watchdog.received();
+    }
+}
+
+fn send_requested_status(
+    connection: &PGConnection,
+    shared: &PipelineShared,
+    status: &mut StatusUpdate,
+) -> Result<()> {
+    if shared.send_requested.swap(false, Ordering::Acquire) {
+        send_status(connection, shared, status, false)?;
+    }
+    Ok(())
+}
+
+/// Send the positions last published by the processing stage, asking the server for a reply
+/// with `reply_requested` even before any position is known
+fn send_status(
+    connection: &PGConnection,
+    shared: &PipelineShared,
+    status: &mut StatusUpdate,
+    reply_requested: bool,
+) -> Result<()> {
+    let (received, flushed, applied) = shared.positions();
+    if !received.is_valid() && !reply_requested {
+        return Ok(());
+    }
+    connection.put_copy_data(status.encode(received, flushed, applied, reply_requested)?)?;
+    if let Err(e) = connection.flush() {
+        warn!("Failed to flush feedback (non-fatal): {}", e);
+    }
+    debug!("Receiver sent feedback with LSN: {}", received);
+    Ok(())
+}
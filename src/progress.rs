@@ -0,0 +1,124 @@
+//! Catch-up progress tracking for streams that start behind the server's WAL position
+//! Estimates bytes remaining, consumption rate and time to catch up from the WAL headers
+
+use crate::health::HealthState;
+use crate::types::Lsn;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Lag below which the stream is considered caught up (one default WAL segment)
+const CATCH_UP_THRESHOLD_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Point-in-time view of the catch-up progress
+#[derive(Debug, Clone, Copy)]
+pub struct CatchUpSnapshot {
+    /// Bytes of WAL between the last received position and the server's WAL end
+    pub bytes_remaining: u64,
+    /// WAL consumption rate since the previous report
+    pub bytes_per_sec: f64,
+    /// Estimated time until caught up, if WAL is being consumed
+    pub eta: Option<Duration>,
+}
+
+/// Tracks how far the stream is behind the server and reports it periodically
+#[derive(Debug)]
+pub struct CatchUpProgress {
+    report_interval: Duration,
+    server_wal_end: Lsn,
+    received_lsn: Lsn,
+    last_report_time: Instant,
+    last_report_lsn: Lsn,
+    reporting: bool,
+}
+
+impl CatchUpProgress {
+    pub fn new(report_interval: Duration) -> Self {
+        Self {
+            report_interval,
+            server_wal_end: Lsn::INVALID,
+            received_lsn: Lsn::INVALID,
+            last_report_time: Instant::now(),
+            last_report_lsn: Lsn::INVALID,
+            reporting: false,
+        }
+    }
+
+    /// Record the server's current end of WAL from a keepalive or XLogData header
+    pub fn observe_server_wal_end(&mut self, lsn: Lsn) {
+        self.server_wal_end = std::cmp::max(self.server_wal_end, lsn);
+    }
+
+    /// Record the start position of the WAL data just received
+    pub fn observe_received(&mut self, lsn: Lsn) {
+        if !self.last_report_lsn.is_valid() {
+            self.last_report_lsn = lsn;
+        }
+        self.received_lsn = std::cmp::max(self.received_lsn, lsn);
+    }
+
+    /// Bytes of WAL the stream still has to consume to reach the server's WAL end
+    pub fn bytes_remaining(&self) -> u64 {
+        if !self.received_lsn.is_valid() {
+            return 0;
+        }
+        self.server_wal_end.bytes_since(self.received_lsn)
+    }
+
+    /// Current progress, with the rate measured since the previous report
+    pub fn snapshot(&self) -> CatchUpSnapshot {
+        let bytes_remaining = self.bytes_remaining();
+        let elapsed = self.last_report_time.elapsed().as_secs_f64();
+        let consumed = self.received_lsn.bytes_since(self.last_report_lsn);
+        let bytes_per_sec = if elapsed > 0.0 {
+            consumed as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = if bytes_per_sec > 0.0 {
+            Some(Duration::from_secs_f64(
+                bytes_remaining as f64 / bytes_per_sec,
+            ))
+        } else {
+            None
+        };
+
+        CatchUpSnapshot {
+            bytes_remaining,
+            bytes_per_sec,
+            eta,
+        }
+    }
+
+    /// Log the progress if the report interval elapsed and the stream is far enough behind,
+    /// and publish it to the health endpoint
+    pub fn maybe_report(&mut self, health: &HealthState) {
+        if self.last_report_time.elapsed() < self.report_interval {
+            return;
+        }
+
+        let snapshot = self.snapshot();
+        health.set_catch_up(snapshot);
+        if snapshot.bytes_remaining > CATCH_UP_THRESHOLD_BYTES {
+            let eta = snapshot
+                .eta
+                .map(|eta| format!("{}s", eta.as_secs()))
+                .unwrap_or_else(|| "unknown".to_string());
+            info!(
+                bytes_remaining = snapshot.bytes_remaining,
+                bytes_per_sec = snapshot.bytes_per_sec as u64,
+                eta_secs = snapshot.eta.map(|eta| eta.as_secs()),
+                "Catching up: {} bytes remaining, {:.0} bytes/s, ETA {}",
+                snapshot.bytes_remaining,
+                snapshot.bytes_per_sec,
+                eta
+            );
+            self.reporting = true;
+        } else if self.reporting {
+            info!("Caught up with server WAL position");
+            self.reporting = false;
+        }
+
+        self.last_report_time = Instant::now();
+        self.last_report_lsn = self.received_lsn;
+    }
+}
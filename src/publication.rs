@@ -0,0 +1,286 @@
+//! Column lists and row filters of the stream's publications
+//! Since PostgreSQL 15 a publication can narrow a table to a column list and a WHERE row filter.
+//! The Relation message then describes only the published columns and filtered rows are never
+//! sent, so the catalog is the only place the table definition and the filters can be seen
+
+use crate::control::regular_conninfo;
+use crate::errors::{ReplicationError, Result};
+use crate::quote::quote_literal;
+use crate::types::RelationInfo;
+use crate::utils::{Oid, PGConnection};
+use serde::Serialize;
+use std::collections::HashSet;
+use tracing::{info, warn};
+
+/// First server version with publication column lists and row filters
+const COLUMN_LISTS_VERSION: u32 = 150000;
+
+/// How the stream's publications publish one table
+#[derive(Debug, Clone)]
+pub struct PublishedTable {
+    pub oid: Oid,
+    pub schema: String,
+    pub table: String,
+    /// Publications of the stream that include the table
+    pub publications: Vec<String>,
+    /// Columns of the table definition in order, generated columns excluded
+    pub table_columns: Vec<String>,
+    /// Generated columns of the table, which the server only sends when asked to (PostgreSQL 18)
+    pub generated_columns: Vec<String>,
+    /// Every column of the table definition in order, generated ones included
+    pub definition: Vec<String>,
+    /// Published columns in table order, all of them unless a column list narrows the table
+    pub columns: Vec<String>,
+    /// Row filter of each publication, None for a publication that sends every row
+    pub row_filters: Vec<(String, Option<String>)>,
+}
+
+impl PublishedTable {
+    /// Columns of the table left out by the column lists
+    pub fn omitted_columns(&self) -> Vec<&str> {
+        self.table_columns
+            .iter()
+            .filter(|column| !self.columns.contains(column))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Which columns of the table definition the Relation message sends, and why the others
+    /// are left out
+    pub fn column_mask(&self, relation: &RelationInfo) -> PublishedColumnMask {
+        let columns = self
+            .definition
+            .iter()
+            .map(|name| {
+                let status = if relation
+                    .columns
+                    .iter()
+                    .any(|column| &column.column_name == name)
+                {
+                    ColumnPublication::Published
+                } else if self.generated_columns.contains(name) {
+                    ColumnPublication::Generated
+                } else {
+                    ColumnPublication::NotInColumnList
+                };
+                MaskedColumn {
+                    name: name.clone(),
+                    status,
+                }
+            })
+            .collect();
+        PublishedColumnMask { columns }
+    }
+
+    /// Condition rows must meet to be sent: rows are published when any publication's filter
+    /// matches, so None as soon as one publication has no filter
+    pub fn row_filter(&self) -> Option<String> {
+        let filters = self
+            .row_filters
+            .iter()
+            .map(|(_, filter)| filter.as_deref())
+            .collect::<Option<Vec<_>>>()?;
+        match filters.as_slice() {
+            [] => None,
+            [filter] => Some(filter.to_string()),
+            filters => Some(
+                filters
+                    .iter()
+                    .map(|filter| format!("({})", filter))
+                    .collect::<Vec<_>>()
+                    .join(" OR "),
+            ),
+        }
+    }
+
+    /// Log the published columns when a column list narrows the table, and the row filters
+    pub fn log(&self) {
+        let name = format!("{}.{}", self.schema, self.table);
+        let omitted = self.omitted_columns();
+        if omitted.is_empty() {
+            info!(
+                "Publication table {} ({}): all {} columns",
+                name,
+                self.publications.join(", "),
+                self.columns.len()
+            );
+        } else {
+            info!(
+                "Publication table {} ({}): column list [{}] of [{}], not published: [{}]",
+                name,
+                self.publications.join(", "),
+                self.columns.join(", "),
+                self.table_columns.join(", "),
+                omitted.join(", ")
+            );
+        }
+        for (publication, filter) in &self.row_filters {
+            if let Some(filter) = filter {
+                info!(
+                    "Publication {} row filter on {}: WHERE {}",
+                    publication, name, filter
+                );
+            }
+        }
+    }
+}
+
+/// Whether a column of the table definition is sent, and why not
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnPublication {
+    Published,
+    /// Left out by the column lists of the publications
+    NotInColumnList,
+    /// A generated column, not sent unless the publication publishes generated columns
+    Generated,
+}
+
+/// A column of the table definition and whether it is sent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MaskedColumn {
+    pub name: String,
+    pub status: ColumnPublication,
+}
+
+/// The columns of a table's definition in order, each marked with whether the stream sends it
+///
+/// Tuples only carry the columns of their Relation message, so sinks building a schema of the
+/// whole table use the mask to tell the columns that never arrive from those that are NULL.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PublishedColumnMask {
+    pub columns: Vec<MaskedColumn>,
+}
+
+impl PublishedColumnMask {
+    /// Whether a column at this position of the table definition is sent
+    pub fn bits(&self) -> Vec<bool> {
+        self.columns
+            .iter()
+            .map(|column| column.status == ColumnPublication::Published)
+            .collect()
+    }
+
+    /// Columns of the table definition that are not sent
+    pub fn not_published(&self) -> impl Iterator<Item = &MaskedColumn> {
+        self.columns
+            .iter()
+            .filter(|column| column.status != ColumnPublication::Published)
+    }
+
+    /// Every column of the table is sent
+    pub fn is_complete(&self) -> bool {
+        self.not_published().next().is_none()
+    }
+}
+
+/// Tables of the publications with their column lists and row filters, ordered by name
+pub fn published_tables(
+    connection: &PGConnection,
+    publication_names: &[String],
+) -> Result<Vec<PublishedTable>> {
+    let names = publication_names
+        .iter()
+        .map(|name| quote_literal(name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    // Older servers publish every column and row
+    let (attnames, rowfilter) = if connection.server_version() >= COLUMN_LISTS_VERSION {
+        ("array_to_json(p.attnames)", "p.rowfilter")
+    } else {
+        ("NULL::json", "NULL::text")
+    };
+    let query = format!(
+        "SELECT c.oid, p.schemaname, p.tablename, p.pubname, {}, {}, \
+             (SELECT array_to_json(array_agg(a.attname ORDER BY a.attnum)) FROM pg_attribute a \
+              WHERE a.attrelid = c.oid AND a.attnum > 0 AND NOT a.attisdropped), \
+             (SELECT array_to_json(array_agg(a.attname ORDER BY a.attnum)) FROM pg_attribute a \
+              WHERE a.attrelid = c.oid AND a.attnum > 0 AND NOT a.attisdropped \
+              AND a.attgenerated <> '') \
+         FROM pg_publication_tables p \
+         JOIN pg_namespace n ON n.nspname = p.schemaname \
+         JOIN pg_class c ON c.relnamespace = n.oid AND c.relname = p.tablename \
+         WHERE p.pubname IN ({}) ORDER BY 2, 3, 4",
+        attnames, rowfilter, names
+    );
+    let result = connection.exec(&query)?;
+    if !result.is_ok() {
+        return Err(ReplicationError::protocol(format!(
+            "Failed to look up publication tables: {}",
+            result.error_message()
+        )));
+    }
+
+    let mut tables: Vec<PublishedTable> = Vec::new();
+    for row in 0..result.ntuples() {
+        // NULL comes back as an empty string
+        let value = |col| result.getvalue(row, col).filter(|value| !value.is_empty());
+        let oid = value(0).and_then(|oid| oid.parse().ok()).unwrap_or(0);
+        let publication = value(3).unwrap_or_default();
+        let definition = json_names(value(6));
+        let generated_columns = json_names(value(7));
+        let table_columns: Vec<String> = definition
+            .iter()
+            .filter(|column| !generated_columns.contains(column))
+            .cloned()
+            .collect();
+        // A column list of any publication adds its columns; without one the table is whole
+        let listed = match value(4) {
+            Some(attnames) => json_names(Some(attnames)),
+            None => table_columns.clone(),
+        };
+        let row_filter = value(5);
+
+        if tables.last().map(|table| table.oid) != Some(oid) {
+            tables.push(PublishedTable {
+                oid,
+                schema: value(1).unwrap_or_default(),
+                table: value(2).unwrap_or_default(),
+                publications: Vec::new(),
+                table_columns,
+                generated_columns,
+                definition,
+                columns: Vec::new(),
+                row_filters: Vec::new(),
+            });
+        }
+        let Some(table) = tables.last_mut() else {
+            continue;
+        };
+        let published: HashSet<&String> = table.columns.iter().chain(&listed).collect();
+        table.columns = table
+            .table_columns
+            .iter()
+            .filter(|column| published.contains(column))
+            .cloned()
+            .collect();
+        table.publications.push(publication.clone());
+        table.row_filters.push((publication, row_filter));
+    }
+    Ok(tables)
+}
+
+/// Log the column list and row filter of every published table at startup; failures only warn
+/// since streaming does not depend on them
+pub fn log_published_tables(replication_conninfo: &str, publication_names: &[String]) {
+    let tables = PGConnection::connect(&regular_conninfo(replication_conninfo))
+        .and_then(|connection| published_tables(&connection, publication_names));
+    match tables {
+        Ok(tables) if tables.is_empty() => warn!(
+            "Publications {} include no tables",
+            publication_names.join(", ")
+        ),
+        Ok(tables) => tables.iter().for_each(PublishedTable::log),
+        Err(e) => warn!(
+            "Failed to look up publication column lists and row filters: {}",
+            e
+        ),
+    }
+}
+
+/// Column names from a JSON array, empty for NULL
+fn json_names(value: Option<String>) -> Vec<String> {
+    value
+        .and_then(|value| serde_json::from_str(&value).ok())
+        .unwrap_or_default()
+}
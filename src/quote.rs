@@ -0,0 +1,61 @@
+//! Quoting of names and values written into SQL and replication commands
+//! Replication connections only accept the simple query protocol, so slot, publication,
+//! snapshot and table names end up inside the command text. Identifiers are always quoted, which
+//! keeps their case and lets them hold quotes, spaces and any other character; literals are
+//! quoted for the grammar that reads them, as replication commands have no escape strings.
+
+/// Quote an SQL identifier, doubling any embedded double quotes
+pub fn quote_ident(name: &str) -> String {
+    let mut quoted = String::with_capacity(name.len() + 2);
+    quoted.push('"');
+    for c in name.chars() {
+        if c == '"' {
+            quoted.push('"');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Quote a schema-qualified name as `"schema"."name"`
+pub fn quote_qualified(schema: &str, name: &str) -> String {
+    format!("{}.{}", quote_ident(schema), quote_ident(name))
+}
+
+/// Quote an SQL string literal, doubling any embedded single quotes
+///
+/// A value with a backslash is written as an escape string with its backslashes doubled, like
+/// PostgreSQL's own quote_literal, so it reads the same whatever `standard_conforming_strings`
+/// is set to.
+pub fn quote_literal(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 3);
+    if value.contains('\\') {
+        quoted.push('E');
+    }
+    quoted.push('\'');
+    for c in value.chars() {
+        if c == '\'' || c == '\\' {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Quote a string literal of a replication command such as the options of START_REPLICATION
+///
+/// The replication grammar only knows doubled single quotes and keeps backslashes as they are.
+pub fn quote_command_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Value of the pgoutput `publication_names` option: a comma-separated list of quoted names
+pub fn publication_names_option(names: &[String]) -> String {
+    names
+        .iter()
+        .map(|name| quote_ident(name))
+        .collect::<Vec<_>>()
+        .join(",")
+}
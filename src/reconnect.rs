@@ -0,0 +1,117 @@
+//! Reconnection policy for the replication connection
+//! Exponential backoff with jitter between attempts to re-establish a dropped stream, and
+//! detection of a connection the server has silently stopped sending on
+
+use crate::errors::{ReplicationError, Result};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// How often and how patiently a dropped replication connection is re-established
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    /// Consecutive failed attempts before giving up, 0 disables reconnecting
+    pub max_retries: u32,
+    /// Delay before the first attempt, doubled after every failure
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between attempts
+    pub max_backoff: Duration,
+    /// Fraction of the delay randomly added or removed, so clients do not retry in lockstep
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay before the given attempt, counting from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        // Uniform factor in [1 - jitter, 1 + jitter]
+        let factor = 1.0 + jitter * (2.0 * random_unit() - 1.0);
+        delay.mul_f64(factor)
+    }
+}
+
+/// Notices when nothing has been received for the receiver timeout, like PostgreSQL's
+/// `wal_receiver_timeout`
+///
+/// A network partition leaves the socket open without data ever arriving, so reads would wait
+/// forever. Halfway through the timeout the server is asked for a reply, which an idle but
+/// reachable server answers with a keepalive; once the whole timeout has passed the connection
+/// is reported lost so it is closed and re-established.
+#[derive(Debug)]
+pub struct ReceiveWatchdog {
+    /// None disables the check
+    timeout: Option<Duration>,
+    last_received: Instant,
+    reply_requested: bool,
+}
+
+impl ReceiveWatchdog {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            last_received: Instant::now(),
+            reply_requested: false,
+        }
+    }
+
+    /// Record that data arrived, or that nothing was read on purpose, e.g. while paused
+    pub fn received(&mut self) {
+        self.last_received = Instant::now();
+        self.reply_requested = false;
+    }
+
+    /// Whether a status update asking the server for a reply should be sent now; an error
+    /// once nothing has arrived for the whole timeout
+    pub fn check(&mut self) -> Result<bool> {
+        let Some(timeout) = self.timeout else {
+            return Ok(false);
+        };
+        let silent = self.last_received.elapsed();
+        if silent >= timeout {
+            warn!(
+                "Nothing received from the server for {:.1}s, closing the connection to reconnect",
+                silent.as_secs_f64()
+            );
+            return Err(ReplicationError::connection(format!(
+                "Receiver timeout: no data from the server for {:.1}s",
+                silent.as_secs_f64()
+            )));
+        }
+        if !self.reply_requested && silent >= timeout / 2 {
+            debug!(
+                "Nothing received for {:.1}s, asking the server for a reply",
+                silent.as_secs_f64()
+            );
+            self.reply_requested = true;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+/// Random value in [0, 1); std's randomly keyed hasher is enough for spreading out retries
+fn random_unit() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,395 @@
+//! Cache of the relations announced by Relation messages
+//! Tracks a version per relation that is bumped whenever its columns change, and optionally
+//! evicts the least recently used relations once a configured number is cached
+
+use crate::types::{ColumnInfo, RelationInfo};
+use crate::utils::Oid;
+use serde::Serialize;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use tracing::debug;
+
+/// A cached relation and the bookkeeping used for versioning and eviction
+#[derive(Debug)]
+struct CachedRelation {
+    relation: RelationInfo,
+    /// 1 for the first Relation message, bumped on every schema change
+    version: u32,
+    /// Tick of the last lookup, the lowest is evicted first
+    last_used: Cell<u64>,
+    /// A tuple of this version has already been reported as not matching its columns
+    mismatch_reported: bool,
+}
+
+/// What a Relation message changed in the cache
+#[derive(Debug, PartialEq, Eq)]
+pub enum RelationUpdate {
+    /// The relation was not cached yet
+    New,
+    /// The relation was sent again with the same columns
+    Unchanged,
+    /// Columns or replica identity differ from the cached version
+    SchemaChanged {
+        version: u32,
+        /// Columns of the previous version as `name:type`, see [`describe_columns`]
+        previous_columns: Vec<String>,
+        /// What changed from the previous version
+        diff: SchemaDiff,
+    },
+}
+
+/// A column added to or removed from a relation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ColumnDef {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_name: String,
+}
+
+/// A column whose name changed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ColumnRename {
+    pub from: String,
+    pub to: String,
+}
+
+/// A column whose type or type modifier changed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ColumnTypeChange {
+    pub column: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// Differences between two versions of a relation
+///
+/// pgoutput only sends the current columns, so renames are inferred: a column missing from the
+/// new version whose position is taken by a new column of the same type counts as renamed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SchemaDiff {
+    /// Previous `schema.table` when the table was renamed or moved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_table: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<ColumnDef>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<ColumnDef>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub renamed: Vec<ColumnRename>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub type_changed: Vec<ColumnTypeChange>,
+    /// New replica identity when it changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replica_identity: Option<String>,
+    /// New replica identity key columns when they changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_columns: Option<Vec<String>>,
+}
+
+impl SchemaDiff {
+    /// Compare the previous version of a relation with the new one
+    pub fn between(previous: &RelationInfo, relation: &RelationInfo) -> Self {
+        let mut diff = SchemaDiff::default();
+        if previous.namespace != relation.namespace
+            || previous.relation_name != relation.relation_name
+        {
+            diff.previous_table =
+                Some(format!("{}.{}", previous.namespace, previous.relation_name));
+        }
+        if previous.replica_identity != relation.replica_identity {
+            diff.replica_identity =
+                Some(replica_identity_name(relation.replica_identity).to_string());
+        }
+        let key_columns = |relation: &RelationInfo| -> Vec<String> {
+            relation
+                .columns
+                .iter()
+                .filter(|column| column.key_flag & 1 != 0)
+                .map(|column| column.column_name.clone())
+                .collect()
+        };
+        if key_columns(previous) != key_columns(relation) {
+            diff.key_columns = Some(key_columns(relation));
+        }
+
+        let find = |columns: &[ColumnInfo], name: &str| {
+            columns.iter().position(|column| column.column_name == name)
+        };
+        let mut removed: Vec<(usize, &ColumnInfo)> = previous
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|(_, column)| find(&relation.columns, &column.column_name).is_none())
+            .collect();
+        for (position, column) in relation.columns.iter().enumerate() {
+            if let Some(index) = find(&previous.columns, &column.column_name) {
+                let old = &previous.columns[index];
+                if !same_type(old, column) {
+                    diff.type_changed.push(ColumnTypeChange {
+                        column: column.column_name.clone(),
+                        from: column_type(old),
+                        to: column_type(column),
+                    });
+                }
+                continue;
+            }
+            match removed
+                .iter()
+                .position(|(old_position, old)| *old_position == position && same_type(old, column))
+            {
+                Some(index) => {
+                    let (_, old) = removed.remove(index);
+                    diff.renamed.push(ColumnRename {
+                        from: old.column_name.clone(),
+                        to: column.column_name.clone(),
+                    });
+                }
+                None => diff.added.push(ColumnDef::of(column)),
+            }
+        }
+        diff.removed = removed
+            .into_iter()
+            .map(|(_, column)| ColumnDef::of(column))
+            .collect();
+        diff
+    }
+
+    /// One line listing the differences, e.g. `added note:text, renamed id -> user_id`
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(previous_table) = &self.previous_table {
+            parts.push(format!("renamed from {}", previous_table));
+        }
+        for column in &self.added {
+            parts.push(format!("added {}:{}", column.name, column.type_name));
+        }
+        for column in &self.removed {
+            parts.push(format!("removed {}:{}", column.name, column.type_name));
+        }
+        for rename in &self.renamed {
+            parts.push(format!("renamed {} -> {}", rename.from, rename.to));
+        }
+        for change in &self.type_changed {
+            parts.push(format!(
+                "{} changed from {} to {}",
+                change.column, change.from, change.to
+            ));
+        }
+        if let Some(replica_identity) = &self.replica_identity {
+            parts.push(format!("replica identity {}", replica_identity));
+        }
+        if let Some(key_columns) = &self.key_columns {
+            parts.push(format!("key columns [{}]", key_columns.join(", ")));
+        }
+        parts.join(", ")
+    }
+}
+
+impl ColumnDef {
+    fn of(column: &ColumnInfo) -> Self {
+        Self {
+            name: column.column_name.clone(),
+            type_name: column_type(column),
+        }
+    }
+}
+
+/// Relations by OID, bounded by an optional capacity
+#[derive(Debug, Default)]
+pub struct RelationCache {
+    entries: HashMap<Oid, CachedRelation>,
+    /// Most relations kept, None keeps every relation
+    capacity: Option<usize>,
+    clock: Cell<u64>,
+    /// Relations dropped from the cache, reported when a change for them arrives
+    evicted: HashSet<Oid>,
+    evictions: u64,
+}
+
+impl RelationCache {
+    pub fn new(capacity: Option<usize>) -> Self {
+        Self {
+            capacity: capacity.map(|capacity| capacity.max(1)),
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Relations evicted so far
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Store a relation, comparing it with the cached version and evicting if over capacity
+    pub fn insert(&mut self, relation: RelationInfo) -> RelationUpdate {
+        let oid = relation.oid;
+        let tick = self.tick();
+        self.evicted.remove(&oid);
+
+        let update = match self.entries.get_mut(&oid) {
+            Some(cached) if same_schema(&cached.relation, &relation) => {
+                cached.relation = relation;
+                cached.last_used.set(tick);
+                RelationUpdate::Unchanged
+            }
+            Some(cached) => {
+                let previous_columns = describe_columns(&cached.relation);
+                let diff = SchemaDiff::between(&cached.relation, &relation);
+                cached.version += 1;
+                cached.relation = relation;
+                cached.last_used.set(tick);
+                cached.mismatch_reported = false;
+                RelationUpdate::SchemaChanged {
+                    version: cached.version,
+                    previous_columns,
+                    diff,
+                }
+            }
+            None => {
+                self.entries.insert(
+                    oid,
+                    CachedRelation {
+                        relation,
+                        version: 1,
+                        last_used: Cell::new(tick),
+                        mismatch_reported: false,
+                    },
+                );
+                RelationUpdate::New
+            }
+        };
+
+        self.evict_over_capacity();
+        update
+    }
+
+    /// Look a relation up, marking it as recently used
+    pub fn get(&self, oid: Oid) -> Option<&RelationInfo> {
+        let cached = self.entries.get(&oid)?;
+        cached.last_used.set(self.tick());
+        Some(&cached.relation)
+    }
+
+    /// Version of a cached relation
+    pub fn version(&self, oid: Oid) -> Option<u32> {
+        self.entries.get(&oid).map(|cached| cached.version)
+    }
+
+    /// Whether a relation was cached before being evicted
+    pub fn was_evicted(&self, oid: Oid) -> bool {
+        self.evicted.contains(&oid)
+    }
+
+    /// Mark the current version of a relation as having a mismatching tuple, returning true the
+    /// first time so the mismatch is reported once per version
+    pub fn report_mismatch(&mut self, oid: Oid) -> bool {
+        match self.entries.get_mut(&oid) {
+            Some(cached) if !cached.mismatch_reported => {
+                cached.mismatch_reported = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        let tick = self.clock.get() + 1;
+        self.clock.set(tick);
+        tick
+    }
+
+    fn evict_over_capacity(&mut self) {
+        let Some(capacity) = self.capacity else {
+            return;
+        };
+        while self.entries.len() > capacity {
+            let Some(oid) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used.get())
+                .map(|(oid, _)| *oid)
+            else {
+                return;
+            };
+            if let Some(cached) = self.entries.remove(&oid) {
+                debug!(
+                    "Evicted relation {}.{} (oid {}) from the relation cache",
+                    cached.relation.namespace, cached.relation.relation_name, oid
+                );
+            }
+            self.evicted.insert(oid);
+            self.evictions += 1;
+        }
+    }
+}
+
+/// Same name, replica identity and column names and types
+fn same_schema(cached: &RelationInfo, relation: &RelationInfo) -> bool {
+    cached.namespace == relation.namespace
+        && cached.relation_name == relation.relation_name
+        && cached.replica_identity == relation.replica_identity
+        && cached.columns.len() == relation.columns.len()
+        && cached.columns.iter().zip(&relation.columns).all(|(a, b)| {
+            a.column_name == b.column_name && same_type(a, b) && a.key_flag == b.key_flag
+        })
+}
+
+fn same_type(a: &ColumnInfo, b: &ColumnInfo) -> bool {
+    a.column_type == b.column_type && a.atttypmod == b.atttypmod
+}
+
+/// The resolved type name of a column, or else its type OID
+fn column_type(column: &ColumnInfo) -> String {
+    column
+        .type_name
+        .clone()
+        .unwrap_or_else(|| format!("oid {}", column.column_type))
+}
+
+fn replica_identity_name(replica_identity: char) -> &'static str {
+    match replica_identity {
+        'd' => "default",
+        'n' => "nothing",
+        'f' => "full",
+        'i' => "index",
+        _ => "unknown",
+    }
+}
+
+/// Columns as `name:type`, with the resolved type name or else the type OID
+pub fn describe_columns(relation: &RelationInfo) -> Vec<String> {
+    relation
+        .columns
+        .iter()
+        .map(|column| match &column.type_name {
+            Some(type_name) => format!("{}:{}", column.column_name, type_name),
+            None => format!("{}:{}", column.column_name, column.column_type),
+        })
+        .collect()
+}
+
+/// One line describing a relation: its replica identity and each column with its type, key
+/// columns marked
+pub fn describe_relation(relation: &RelationInfo) -> String {
+    let replica_identity = replica_identity_name(relation.replica_identity);
+    let columns = relation
+        .columns
+        .iter()
+        .map(|column| {
+            let key = if column.key_flag & 1 != 0 { " key" } else { "" };
+            format!("{} {}{}", column.column_name, column_type(column), key)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "{}.{} (replica identity {}): [{}]",
+        relation.namespace, relation.relation_name, replica_identity, columns
+    )
+}
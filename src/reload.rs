@@ -0,0 +1,91 @@
+//! Runtime reload of the display, output and filter settings
+//! SIGHUP reads the config file and command line again; the streaming loop switches to the new
+//! settings between transactions, keeping the connection and the slot position
+
+use crate::cli::{Cli, Command};
+use crate::config::ConfigFile;
+use crate::datetime::DisplayTimeZone;
+use crate::errors::{ReplicationError, Result};
+use crate::filter::TableFilter;
+use crate::mask::ColumnMask;
+use crate::output::OutputConfig;
+use crate::rowfilter::RowFilter;
+use crate::sample::Sampling;
+use crate::types::{ReplicationConfig, UpdateDisplayMode};
+use std::sync::Mutex;
+
+/// The settings of a stream that can change without reconnecting
+#[derive(Debug, Clone)]
+pub struct DisplaySettings {
+    pub update_display: UpdateDisplayMode,
+    pub display_timezone: Option<DisplayTimeZone>,
+    pub output: OutputConfig,
+    pub table_filter: TableFilter,
+    pub row_filter: RowFilter,
+    pub sampling: Sampling,
+    pub column_mask: ColumnMask,
+}
+
+impl DisplaySettings {
+    /// The reloadable settings of a configuration
+    pub fn from_config(config: &ReplicationConfig) -> Self {
+        Self {
+            update_display: config.update_display,
+            display_timezone: config.display_timezone,
+            output: config.output.clone(),
+            table_filter: config.table_filter.clone(),
+            row_filter: config.row_filter.clone(),
+            sampling: config.sampling.clone(),
+            column_mask: config.column_mask.clone(),
+        }
+    }
+
+    /// Read the settings again from the config file, environment and command line
+    ///
+    /// The environment of a running process does not change, so in practice this picks up
+    /// edits to the config file; command-line flags still take precedence over it.
+    pub fn load() -> Result<Self> {
+        let config_file = ConfigFile::from_args_or_env()?;
+        let Command::Stream(args) = Cli::try_parse_with_config(&config_file)?.into_command() else {
+            return Err(ReplicationError::config(
+                "Settings can only be reloaded for the stream command",
+            ));
+        };
+
+        let mut config = ReplicationConfig::offline();
+        args.display.apply_to(&mut config)?;
+        Ok(Self::from_config(&config))
+    }
+
+    /// Replace the reloadable settings of the configuration
+    pub fn apply_to(self, config: &mut ReplicationConfig) {
+        config.update_display = self.update_display;
+        config.display_timezone = self.display_timezone;
+        config.output = self.output;
+        config.table_filter = self.table_filter;
+        config.row_filter = self.row_filter;
+        config.sampling = self.sampling;
+        config.column_mask = self.column_mask;
+    }
+}
+
+/// Reloaded settings waiting for the streaming loop to switch to them
+#[derive(Debug, Default)]
+pub struct PendingReload {
+    settings: Mutex<Option<DisplaySettings>>,
+}
+
+impl PendingReload {
+    /// Hand new settings to the streaming loop, replacing any it has not picked up yet
+    pub fn request(&self, settings: DisplaySettings) {
+        *self.settings.lock().unwrap_or_else(|e| e.into_inner()) = Some(settings);
+    }
+
+    /// Take the pending settings, if any
+    pub fn take(&self) -> Option<DisplaySettings> {
+        self.settings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
+}
@@ -0,0 +1,278 @@
+//! Rows of a table as ordered maps of column name to value
+//! A tuple is matched to the columns of its relation once, and the log output, the structured
+//! output and every sink read the resulting `Row` by column name rather than by position.
+
+use crate::datetime::{self, DateOrder, DisplayTimeZone};
+use crate::mask::ColumnMask;
+use crate::publication::{ColumnPublication, PublishedColumnMask};
+use crate::toast::UNCHANGED_TOAST_MARKER;
+use crate::types::{RelationInfo, TupleData};
+use crate::utils::Oid;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Shown in place of the value of a column the publication does not send
+pub const NOT_PUBLISHED_MARKER: &str = "<not published>";
+
+/// Value of a column in a row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowValue {
+    Null,
+    /// TOAST value that did not change and was not sent
+    Unchanged,
+    /// Text form of the value; binary values are decoded according to the column type
+    Text(String),
+    /// Column of the table left out by a column list or generated, never sent by the stream
+    NotPublished,
+}
+
+impl RowValue {
+    /// The text of a value that is neither NULL nor an unchanged TOAST value
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Self::Text(text) => Some(text),
+            Self::Null | Self::Unchanged | Self::NotPublished => None,
+        }
+    }
+}
+
+/// A column of a row with its type, when the relation describes it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowColumn {
+    pub name: String,
+    /// Type OID from the Relation message; unknown with the walstream backend
+    pub type_oid: Option<Oid>,
+    pub value: RowValue,
+}
+
+/// Columns of a row in the order of their relation
+///
+/// Serializes as a JSON object keyed by column name in which NULL is null and unchanged TOAST
+/// values are left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Row {
+    columns: Vec<RowColumn>,
+}
+
+impl Row {
+    /// Match the values of a tuple to the columns of its relation. Values beyond the columns
+    /// the relation describes are dropped and columns the tuple has no value for are left out,
+    /// so a tuple sent before a Relation message that added columns still maps by name.
+    pub fn from_tuple(relation: &RelationInfo, tuple_data: &TupleData) -> Self {
+        let columns = relation
+            .columns
+            .iter()
+            .zip(&tuple_data.columns)
+            .map(|(column, column_data)| RowColumn {
+                name: column.column_name.clone(),
+                type_oid: Some(column.column_type),
+                value: match column_data.data_type {
+                    'n' => RowValue::Null,
+                    'u' => RowValue::Unchanged,
+                    _ => RowValue::Text(column_data.to_text(column.column_type)),
+                },
+            })
+            .collect();
+        Self { columns }
+    }
+
+    /// A row decoded by pg-walstream, which hands over columns as an unordered map of text
+    /// values; they are ordered by name
+    pub fn from_json(data: &HashMap<String, Value>) -> Self {
+        let mut columns: Vec<RowColumn> = data
+            .iter()
+            .map(|(name, value)| RowColumn {
+                name: name.clone(),
+                type_oid: None,
+                value: match value {
+                    Value::Null => RowValue::Null,
+                    Value::String(text) => RowValue::Text(text.clone()),
+                    other => RowValue::Text(other.to_string()),
+                },
+            })
+            .collect();
+        columns.sort_by(|a, b| a.name.cmp(&b.name));
+        Self { columns }
+    }
+
+    /// Value of the named column; None when the row has no such column
+    pub fn get(&self, name: &str) -> Option<&RowValue> {
+        self.columns
+            .iter()
+            .find(|column| column.name == name)
+            .map(|column| &column.value)
+    }
+
+    pub fn columns(&self) -> &[RowColumn] {
+        &self.columns
+    }
+
+    pub fn columns_mut(&mut self) -> impl Iterator<Item = &mut RowColumn> {
+        self.columns.iter_mut()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.columns.iter().map(|column| column.name.as_str())
+    }
+
+    /// Add the columns of the table definition the stream does not send, in table order, so
+    /// they show as not published rather than missing; the structured output leaves them out
+    pub fn add_unpublished(&mut self, mask: &PublishedColumnMask) {
+        let mut sent: Vec<Option<RowColumn>> = self.columns.drain(..).map(Some).collect();
+        for masked in &mask.columns {
+            if masked.status != ColumnPublication::Published {
+                self.columns.push(RowColumn {
+                    name: masked.name.clone(),
+                    type_oid: None,
+                    value: RowValue::NotPublished,
+                });
+            } else if let Some(column) = sent
+                .iter_mut()
+                .find(|column| {
+                    column
+                        .as_ref()
+                        .is_some_and(|column| column.name == masked.name)
+                })
+                .and_then(Option::take)
+            {
+                self.columns.push(column);
+            }
+        }
+        // Columns the catalog did not know of yet keep their place at the end
+        self.columns.extend(sent.into_iter().flatten());
+    }
+
+    /// Replace the values of masked columns of `schema.table`; NULL stays visible
+    pub fn mask(&mut self, mask: &ColumnMask, schema: &str, table: &str) {
+        if mask.is_empty() {
+            return;
+        }
+        for column in &mut self.columns {
+            if let RowValue::Text(text) = &mut column.value {
+                if mask.matches(schema, table, &column.name) {
+                    *text = mask.mask(text);
+                }
+            }
+        }
+    }
+
+    /// Rewrite date and time values in ISO form, timestamps with time zone shifted to `zone`;
+    /// values of other types and text that does not parse are left as sent
+    pub fn normalize_temporal(&mut self, order: DateOrder, zone: DisplayTimeZone) {
+        for column in &mut self.columns {
+            if let (Some(type_oid), RowValue::Text(text)) = (column.type_oid, &mut column.value) {
+                if let Some(normalized) = datetime::normalize(type_oid, text, order, zone) {
+                    *text = normalized;
+                }
+            }
+        }
+    }
+
+    /// `name: value` pairs for the log, leaving NULL columns out; columns of user-defined
+    /// types are labelled with the type name `type_name` knows them by
+    pub fn display(&self, type_name: impl Fn(Oid) -> Option<String>) -> String {
+        self.columns
+            .iter()
+            .filter_map(|column| {
+                let value = match &column.value {
+                    RowValue::Null => return None,
+                    RowValue::Unchanged => UNCHANGED_TOAST_MARKER,
+                    RowValue::NotPublished => NOT_PUBLISHED_MARKER,
+                    RowValue::Text(text) => text,
+                };
+                Some(match column.type_oid.and_then(&type_name) {
+                    Some(type_name) => format!("{} ({}): {}", column.name, type_name, value),
+                    None => format!("{}: {}", column.name, value),
+                })
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Key columns and columns whose value an UPDATE from `old` to this row changed
+    ///
+    /// `full_old_row` says `old` is the whole old row, sent under REPLICA IDENTITY FULL;
+    /// otherwise only its key columns are known, so every other column is listed except
+    /// unchanged TOAST values. With `diff`, changed columns whose old value is known are listed
+    /// as `old -> new` and unchanged columns of a whole old row are left out, key or not.
+    pub fn changes(
+        &self,
+        old: Option<&Row>,
+        key_columns: &[String],
+        full_old_row: bool,
+        diff: bool,
+    ) -> Vec<ColumnChange> {
+        let full = full_old_row && old.is_some();
+        let text = |value: &RowValue| match value {
+            RowValue::Null => "NULL".to_string(),
+            RowValue::Unchanged => UNCHANGED_TOAST_MARKER.to_string(),
+            RowValue::NotPublished => NOT_PUBLISHED_MARKER.to_string(),
+            RowValue::Text(text) => text.clone(),
+        };
+        self.columns
+            .iter()
+            .filter(|column| column.value != RowValue::NotPublished)
+            .filter_map(|column| {
+                let key = key_columns.contains(&column.name);
+                let old_value = old
+                    .filter(|_| full || key)
+                    .and_then(|old| old.get(&column.name));
+                let changed = column.value != RowValue::Unchanged
+                    && old_value.is_none_or(|old_value| *old_value != column.value);
+                // The key identifies the row, except among the differences of a whole old row
+                if !changed && !(key && !(diff && full)) {
+                    return None;
+                }
+                Some(ColumnChange {
+                    name: column.name.clone(),
+                    // Under REPLICA IDENTITY FULL every column is part of the key
+                    key: key && !full_old_row,
+                    old: old_value.filter(|_| diff && changed).map(text),
+                    new: text(&column.value),
+                })
+            })
+            .collect()
+    }
+}
+
+/// A column of an UPDATE shown by the changed-only and diff displays
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnChange {
+    pub name: String,
+    /// Marked as part of the replica identity key
+    pub key: bool,
+    /// Value before the update, when it is shown
+    pub old: Option<String>,
+    pub new: String,
+}
+
+/// `name: old -> new` pairs for the log, key columns marked `(key)`, e.g.
+/// `id (key): 7, status: pending -> paid`
+pub fn format_changes(changes: &[ColumnChange]) -> String {
+    changes
+        .iter()
+        .map(|change| {
+            let key = if change.key { " (key)" } else { "" };
+            match &change.old {
+                Some(old) => format!("{}{}: {} -> {}", change.name, key, old, change.new),
+                None => format!("{}{}: {}", change.name, key, change.new),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Serialize for Row {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for column in &self.columns {
+            match &column.value {
+                RowValue::Null => map.serialize_entry(&column.name, &None::<&str>)?,
+                RowValue::Unchanged | RowValue::NotPublished => {}
+                RowValue::Text(text) => map.serialize_entry(&column.name, text)?,
+            }
+        }
+        map.end()
+    }
+}
@@ -0,0 +1,573 @@
+//! Row-level filters on emitted change events
+//! Each filter is a small SQL-like predicate over the columns of one table, such as
+//! `orders.status = 'paid'` or `public.users.id > 1000 AND users.active = true`. Comparisons
+//! with NULL are unknown and a row is only emitted when every filter of its table is true.
+
+use crate::errors::{ReplicationError, Result};
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// Comparison operators
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompareOp {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    Text(String),
+    Number(Decimal),
+    Boolean(bool),
+}
+
+/// An exact decimal number, so bigints past 2^53 and numerics of any precision compare without
+/// rounding
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Decimal {
+    negative: bool,
+    /// Digits before the point, without leading zeros
+    integer: String,
+    /// Digits after the point, without trailing zeros
+    fraction: String,
+}
+
+impl Decimal {
+    /// Parse `[+-]digits[.digits]`, None for anything else such as exponents or NaN
+    fn parse(text: &str) -> Option<Self> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if (integer.is_empty() && fraction.is_empty())
+            || !integer
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+        let integer = integer.trim_start_matches('0');
+        let fraction = fraction.trim_end_matches('0');
+        Some(Self {
+            // -0 is zero
+            negative: negative && !(integer.is_empty() && fraction.is_empty()),
+            integer: integer.to_string(),
+            fraction: fraction.to_string(),
+        })
+    }
+
+    fn to_f64(&self) -> f64 {
+        let sign = if self.negative { "-" } else { "" };
+        format!("{}0{}.{}0", sign, self.integer, self.fraction)
+            .parse()
+            .unwrap_or(f64::NAN)
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (negative, _) => {
+                // Without leading zeros the longer integer part is the larger, and without
+                // trailing zeros fractions compare digit by digit
+                let magnitude = self
+                    .integer
+                    .len()
+                    .cmp(&other.integer.len())
+                    .then_with(|| self.integer.cmp(&other.integer))
+                    .then_with(|| self.fraction.cmp(&other.fraction));
+                if negative {
+                    magnitude.reverse()
+                } else {
+                    magnitude
+                }
+            }
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Compare {
+        column: String,
+        op: CompareOp,
+        literal: Literal,
+    },
+    In {
+        column: String,
+        literals: Vec<Literal>,
+        negated: bool,
+    },
+    IsNull {
+        column: String,
+        negated: bool,
+    },
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// SQL three-valued evaluation, None standing for unknown
+    fn eval(&self, value: &dyn Fn(&str) -> Option<String>) -> Option<bool> {
+        match self {
+            Expr::Compare {
+                column,
+                op,
+                literal,
+            } => {
+                let ordering = compare(&value(column)?, literal)?;
+                Some(match op {
+                    CompareOp::Eq => ordering == Ordering::Equal,
+                    CompareOp::NotEq => ordering != Ordering::Equal,
+                    CompareOp::Lt => ordering == Ordering::Less,
+                    CompareOp::LtEq => ordering != Ordering::Greater,
+                    CompareOp::Gt => ordering == Ordering::Greater,
+                    CompareOp::GtEq => ordering != Ordering::Less,
+                })
+            }
+            Expr::In {
+                column,
+                literals,
+                negated,
+            } => {
+                let text = value(column)?;
+                let found = literals
+                    .iter()
+                    .any(|literal| compare(&text, literal) == Some(Ordering::Equal));
+                Some(found != *negated)
+            }
+            Expr::IsNull { column, negated } => Some(value(column).is_none() != *negated),
+            Expr::Not(inner) => inner.eval(value).map(|result| !result),
+            Expr::And(left, right) => match (left.eval(value), right.eval(value)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Expr::Or(left, right) => match (left.eval(value), right.eval(value)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Compare a column's text value with a literal: numerically for number literals, as
+/// PostgreSQL prints booleans for boolean literals, and as text otherwise
+///
+/// Integer and numeric values compare exactly; only values a decimal cannot hold, like the
+/// exponents, NaN and Infinity of floating point columns, are compared as f64.
+fn compare(text: &str, literal: &Literal) -> Option<Ordering> {
+    match literal {
+        Literal::Text(expected) => Some(text.cmp(expected.as_str())),
+        Literal::Number(expected) => {
+            let text = text.trim();
+            match Decimal::parse(text) {
+                Some(actual) => Some(actual.cmp(expected)),
+                None => text.parse::<f64>().ok()?.partial_cmp(&expected.to_f64()),
+            }
+        }
+        Literal::Boolean(expected) => {
+            let actual = match text {
+                "t" | "true" => true,
+                "f" | "false" => false,
+                _ => return None,
+            };
+            Some(actual.cmp(expected))
+        }
+    }
+}
+
+/// A predicate over the rows of one table
+#[derive(Debug, Clone)]
+pub struct RowPredicate {
+    schema: Option<String>,
+    table: String,
+    expr: Expr,
+    source: String,
+}
+
+impl RowPredicate {
+    /// Whether the predicate is about `schema.table`
+    pub fn applies_to(&self, schema: &str, table: &str) -> bool {
+        self.table == table && self.schema.as_deref().is_none_or(|s| s == schema)
+    }
+
+    /// Whether the row passes, looking column values up as text with None for NULL
+    pub fn matches(&self, value: &dyn Fn(&str) -> Option<String>) -> bool {
+        self.expr.eval(value) == Some(true)
+    }
+}
+
+impl FromStr for RowPredicate {
+    type Err = ReplicationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser {
+            source: s,
+            tokens,
+            position: 0,
+            target: None,
+        };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(syntax_error(s, &format!("unexpected {:?}", token)));
+        }
+        let (schema, table) = parser
+            .target
+            .ok_or_else(|| syntax_error(s, "no column referenced"))?;
+
+        Ok(Self {
+            schema,
+            table,
+            expr,
+            source: s.trim().to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for RowPredicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Row filters of every table, compiled from the configured predicates
+#[derive(Debug, Clone, Default)]
+pub struct RowFilter {
+    predicates: Vec<RowPredicate>,
+}
+
+impl RowFilter {
+    pub fn new(predicates: Vec<RowPredicate>) -> Self {
+        Self { predicates }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.predicates.is_empty()
+    }
+
+    pub fn predicates(&self) -> &[RowPredicate] {
+        &self.predicates
+    }
+
+    /// Whether any predicate is about `schema.table`
+    pub fn applies_to(&self, schema: &str, table: &str) -> bool {
+        self.predicates
+            .iter()
+            .any(|predicate| predicate.applies_to(schema, table))
+    }
+
+    /// Whether a row of `schema.table` passes every predicate of the table
+    pub fn matches(
+        &self,
+        schema: &str,
+        table: &str,
+        value: &dyn Fn(&str) -> Option<String>,
+    ) -> bool {
+        self.predicates
+            .iter()
+            .filter(|predicate| predicate.applies_to(schema, table))
+            .all(|predicate| predicate.matches(value))
+    }
+}
+
+fn syntax_error(source: &str, detail: &str) -> ReplicationError {
+    ReplicationError::config(format!("Invalid row filter '{}': {}", source, detail))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// Identifier, with whether it was double-quoted
+    Ident(String, bool),
+    Text(String),
+    Number(Decimal),
+    Op(CompareOp),
+    Dot,
+    Comma,
+    LParen,
+    RParen,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '.' => {
+                tokens.push(Token::Dot);
+                i += 1;
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                i += 1;
+            }
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '=' => {
+                tokens.push(Token::Op(CompareOp::Eq));
+                i += 1;
+            }
+            '!' | '<' | '>' => {
+                let next = chars.get(i + 1).copied();
+                let (op, width) = match (c, next) {
+                    ('!', Some('=')) | ('<', Some('>')) => (CompareOp::NotEq, 2),
+                    ('<', Some('=')) => (CompareOp::LtEq, 2),
+                    ('>', Some('=')) => (CompareOp::GtEq, 2),
+                    ('<', _) => (CompareOp::Lt, 1),
+                    ('>', _) => (CompareOp::Gt, 1),
+                    _ => return Err(syntax_error(source, "'!' must be followed by '='")),
+                };
+                tokens.push(Token::Op(op));
+                i += width;
+            }
+            '\'' | '"' => {
+                // Quotes inside are doubled, as in SQL
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(syntax_error(source, "unterminated quote")),
+                        Some(&q) if q == c && chars.get(i + 1) == Some(&c) => {
+                            text.push(c);
+                            i += 2;
+                        }
+                        Some(&q) if q == c => {
+                            i += 1;
+                            break;
+                        }
+                        Some(&other) => {
+                            text.push(other);
+                            i += 1;
+                        }
+                    }
+                }
+                tokens.push(if c == '"' {
+                    Token::Ident(text, true)
+                } else {
+                    Token::Text(text)
+                });
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let number = Decimal::parse(&text)
+                    .ok_or_else(|| syntax_error(source, &format!("invalid number {}", text)))?;
+                tokens.push(Token::Number(number));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$')
+                {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(chars[start..i].iter().collect(), false));
+            }
+            other => {
+                return Err(syntax_error(
+                    source,
+                    &format!("unexpected character '{}'", other),
+                ))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser: or := and (OR and)*, and := unary (AND unary)*,
+/// unary := NOT unary | ( or ) | comparison
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+    /// Schema and table every column reference must name
+    target: Option<(Option<String>, String)>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn error(&self, detail: &str) -> ReplicationError {
+        syntax_error(self.source, detail)
+    }
+
+    /// Consume the keyword if it comes next
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(word, false)) if word.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_unary()?;
+        while self.keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.position += 1;
+            let expr = self.parse_or()?;
+            if self.next() != Some(Token::RParen) {
+                return Err(self.error("missing ')'"));
+            }
+            return Ok(expr);
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr> {
+        let column = self.parse_column()?;
+
+        if self.keyword("is") {
+            let negated = self.keyword("not");
+            if !self.keyword("null") {
+                return Err(self.error("expected NULL after IS"));
+            }
+            return Ok(Expr::IsNull { column, negated });
+        }
+
+        let negated = self.keyword("not");
+        if self.keyword("in") {
+            if self.next() != Some(Token::LParen) {
+                return Err(self.error("expected '(' after IN"));
+            }
+            let mut literals = vec![self.parse_literal()?];
+            loop {
+                match self.next() {
+                    Some(Token::Comma) => literals.push(self.parse_literal()?),
+                    Some(Token::RParen) => break,
+                    _ => return Err(self.error("expected ',' or ')' in IN list")),
+                }
+            }
+            return Ok(Expr::In {
+                column,
+                literals,
+                negated,
+            });
+        }
+        if negated {
+            return Err(self.error("expected IN after NOT"));
+        }
+
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => return Err(self.error(&format!("expected an operator after {}", column))),
+        };
+        let literal = self.parse_literal()?;
+        Ok(Expr::Compare {
+            column,
+            op,
+            literal,
+        })
+    }
+
+    /// `table.column` or `schema.table.column`, all naming the same table
+    fn parse_column(&mut self) -> Result<String> {
+        let mut parts = Vec::new();
+        loop {
+            match self.next() {
+                Some(Token::Ident(name, _)) => parts.push(name),
+                _ => return Err(self.error("expected a column reference")),
+            }
+            if self.peek() != Some(&Token::Dot) {
+                break;
+            }
+            self.position += 1;
+        }
+
+        let (target, column) = match parts.as_slice() {
+            [table, column] => ((None, table.clone()), column.clone()),
+            [schema, table, column] => ((Some(schema.clone()), table.clone()), column.clone()),
+            _ => {
+                return Err(self.error(&format!(
+                    "column {} must be written as table.column or schema.table.column",
+                    parts.join(".")
+                )))
+            }
+        };
+
+        match &mut self.target {
+            None => self.target = Some(target),
+            Some((schema, table)) if *table == target.1 => match (schema.as_ref(), target.0) {
+                (Some(existing), Some(new)) if *existing != new => {
+                    return Err(self.error("all columns must belong to the same table"))
+                }
+                (None, Some(new)) => *schema = Some(new),
+                _ => {}
+            },
+            Some(_) => return Err(self.error("all columns must belong to the same table")),
+        }
+        Ok(column)
+    }
+
+    fn parse_literal(&mut self) -> Result<Literal> {
+        match self.next() {
+            Some(Token::Text(text)) => Ok(Literal::Text(text)),
+            Some(Token::Number(number)) => Ok(Literal::Number(number)),
+            Some(Token::Ident(word, false)) if word.eq_ignore_ascii_case("true") => {
+                Ok(Literal::Boolean(true))
+            }
+            Some(Token::Ident(word, false)) if word.eq_ignore_ascii_case("false") => {
+                Ok(Literal::Boolean(false))
+            }
+            _ => Err(self.error("expected a string, number or boolean literal")),
+        }
+    }
+}
@@ -0,0 +1,601 @@
+//! S3 sink for change events
+//! Collects change events into batch files, as newline-delimited JSON or as Parquet files
+//! partitioned by table and date, and uploads them to a bucket of S3-compatible storage once a
+//! batch reaches its size limit or rotation interval. Files larger than the part size are sent
+//! as multipart uploads. Like the webhook sink, the server is never confirmed WAL past the last
+//! transaction whose events were all uploaded, so delivery is at-least-once.
+//!
+//! Uploads go through object_store's S3 client, with path-style addressing, which AWS, MinIO,
+//! Ceph and other S3-compatible stores accept, over TLS for an `https://` endpoint.
+
+use crate::compress::{CompressedWriter, Compression};
+use crate::errors::{ReplicationError, Result};
+use crate::handler::ChangeHandler;
+use crate::mask::ColumnMask;
+use crate::output::{ChangeRecord, SchemaChangeRecord};
+#[cfg(feature = "parquet")]
+use crate::parquet::{ParquetConfig, ParquetSink};
+use crate::reconnect::ReconnectPolicy;
+use crate::types::{Lsn, RelationInfo};
+#[cfg(feature = "parquet")]
+use crate::utils::Xid;
+use chrono::{DateTime, Utc};
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::path::Path as ObjectPath;
+use object_store::{ClientOptions, MultipartUpload, ObjectStore, PutPayload, RetryConfig};
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tracing::{debug, info, warn};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// S3 rejects parts smaller than this, other than the last one
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Format of the uploaded batch files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ObjectFormat {
+    /// One JSON change event per line
+    #[default]
+    Json,
+    /// Parquet files partitioned by table and commit date, as written by the Parquet sink
+    Parquet,
+}
+
+impl FromStr for ObjectFormat {
+    type Err = ReplicationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(ObjectFormat::Json),
+            "parquet" if cfg!(feature = "parquet") => Ok(ObjectFormat::Parquet),
+            "parquet" => Err(ReplicationError::config(
+                "S3 object format parquet needs a build with the parquet feature",
+            )),
+            _ => Err(ReplicationError::config(format!(
+                "Invalid S3 object format: {}. Valid values are: json, parquet",
+                s
+            ))),
+        }
+    }
+}
+
+/// Bucket, credentials and batching of the S3 sink
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    /// `https://host[:port]` or `http://host[:port]` of the S3-compatible service
+    pub endpoint: String,
+    pub bucket: String,
+    /// Key prefix of the uploaded objects, without leading or trailing slashes
+    pub prefix: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub format: ObjectFormat,
+    /// Compression of JSON objects; Parquet files are written uncompressed
+    pub compression: Compression,
+    /// Local directory batches are written to before they are uploaded
+    pub staging_dir: PathBuf,
+    /// Bytes of change events, as JSON, after which the batch is uploaded
+    pub max_batch_bytes: u64,
+    /// Longest the oldest event of a batch waits before the batch is uploaded
+    pub rotate_interval: Duration,
+    /// Bytes per part of a multipart upload; smaller files are sent in one request
+    pub part_size: usize,
+    /// Rows per row group of Parquet files
+    pub row_group_size: usize,
+    /// Retries of a failed upload before the run stops
+    pub retry: ReconnectPolicy,
+}
+
+/// Batches change events into files and uploads them to the bucket
+pub struct S3Sink {
+    config: S3Config,
+    client: S3Client,
+    /// Directory of this process's batch files, removed on close
+    staging_dir: PathBuf,
+    /// Open JSON batch file and its path
+    json: Option<(PathBuf, CompressedWriter<BufWriter<File>>)>,
+    #[cfg(feature = "parquet")]
+    parquet: Option<ParquetSink>,
+    batch_bytes: u64,
+    /// When the oldest event of the batch arrived
+    batch_started: Option<Instant>,
+    /// End LSN of the last commit received
+    committed_lsn: Lsn,
+    /// End LSN of the last transaction whose events were all uploaded
+    uploaded_lsn: Lsn,
+    objects_uploaded: u64,
+    bytes_uploaded: u64,
+}
+
+impl S3Sink {
+    pub fn new(config: S3Config, mask: ColumnMask) -> Result<Self> {
+        let client = S3Client::new(&config)?;
+        let staging_dir = config
+            .staging_dir
+            .join(format!("pgr-s3-{}", std::process::id()));
+        fs::create_dir_all(&staging_dir).map_err(|e| {
+            ReplicationError::s3(format!("Failed to create {}: {}", staging_dir.display(), e))
+        })?;
+        #[cfg(feature = "parquet")]
+        let parquet = match config.format {
+            ObjectFormat::Parquet => Some(ParquetSink::new(
+                ParquetConfig {
+                    directory: staging_dir.clone(),
+                    row_group_size: config.row_group_size,
+                },
+                mask,
+            )?),
+            ObjectFormat::Json => None,
+        };
+        #[cfg(not(feature = "parquet"))]
+        let _ = mask;
+        info!(
+            "Uploading change events to s3://{}/{} at {} as {:?}, every {} bytes or {}s",
+            config.bucket,
+            config.prefix,
+            config.endpoint,
+            config.format,
+            config.max_batch_bytes,
+            config.rotate_interval.as_secs()
+        );
+        Ok(Self {
+            config,
+            client,
+            staging_dir,
+            json: None,
+            #[cfg(feature = "parquet")]
+            parquet,
+            batch_bytes: 0,
+            batch_started: None,
+            committed_lsn: Lsn::INVALID,
+            uploaded_lsn: Lsn::INVALID,
+            objects_uploaded: 0,
+            bytes_uploaded: 0,
+        })
+    }
+
+    /// End LSN up to which every event was uploaded, the limit of what may be confirmed while
+    /// a batch waits to be uploaded; None with no batch, when nothing is held back
+    pub fn pending_lsn(&self) -> Option<Lsn> {
+        self.batch_started.map(|_| self.uploaded_lsn)
+    }
+
+    /// Pass a table's schema to the Parquet writer
+    pub fn register_relation(&mut self, relation: &RelationInfo) -> Result<()> {
+        #[cfg(feature = "parquet")]
+        if let Some(parquet) = self.parquet.as_mut() {
+            return parquet.register_relation(relation);
+        }
+        #[cfg(not(feature = "parquet"))]
+        let _ = relation;
+        Ok(())
+    }
+
+    /// Upload the batch once the rotation interval has passed since its oldest event
+    pub fn maybe_flush(&mut self) -> Result<()> {
+        match self.batch_started {
+            Some(started) if started.elapsed() >= self.config.rotate_interval => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Close the batch files and upload them, retrying with backoff
+    pub fn flush(&mut self) -> Result<()> {
+        if self.batch_started.is_none() {
+            self.uploaded_lsn = self.committed_lsn;
+            return Ok(());
+        }
+
+        let mut files = Vec::new();
+        if let Some((path, writer)) = self.json.take() {
+            writer
+                .finish()
+                .and_then(|mut writer| writer.flush())
+                .map_err(|e| {
+                    ReplicationError::s3(format!("Failed to flush {}: {}", path.display(), e))
+                })?;
+            files.push(path);
+        }
+        #[cfg(feature = "parquet")]
+        if let Some(parquet) = self.parquet.as_mut() {
+            parquet.close()?;
+            collect_files(&self.staging_dir, "parquet", &mut files)?;
+        }
+
+        for path in files {
+            let relative = path.strip_prefix(&self.staging_dir).unwrap_or(&path);
+            let key = object_key(&self.config.prefix, relative);
+            let size = self.upload_with_retry(&key, &path)?;
+            fs::remove_file(&path).map_err(|e| {
+                ReplicationError::s3(format!("Failed to remove {}: {}", path.display(), e))
+            })?;
+            self.objects_uploaded += 1;
+            self.bytes_uploaded += size;
+            debug!(
+                "Uploaded {} bytes to s3://{}/{}",
+                size, self.config.bucket, key
+            );
+        }
+
+        debug!("Uploaded change events up to {}", self.committed_lsn);
+        self.batch_bytes = 0;
+        self.batch_started = None;
+        // Events of a transaction still open were uploaded too, but it only counts as uploaded
+        // once its commit has been seen
+        self.uploaded_lsn = self.committed_lsn;
+        Ok(())
+    }
+
+    /// Upload the last batch and remove the staging directory
+    pub fn close(&mut self) -> Result<()> {
+        self.flush()?;
+        if let Err(e) = fs::remove_dir_all(&self.staging_dir) {
+            warn!("Failed to remove {}: {}", self.staging_dir.display(), e);
+        }
+        Ok(())
+    }
+
+    /// Objects and bytes uploaded so far
+    pub fn objects_uploaded(&self) -> (u64, u64) {
+        (self.objects_uploaded, self.bytes_uploaded)
+    }
+
+    fn upload_with_retry(&self, key: &str, path: &Path) -> Result<u64> {
+        let mut attempt = 0;
+        loop {
+            match self.client.upload(key, path, self.config.part_size) {
+                Ok(size) => return Ok(size),
+                Err(e) if e.is_retryable() && attempt < self.config.retry.max_retries => {
+                    attempt += 1;
+                    let delay = self.config.retry.backoff(attempt);
+                    warn!(
+                        error_kind = %e.kind(),
+                        error_code = e.code(),
+                        "S3 upload of {} failed: {}. Retrying in {:.1}s (attempt {}/{})",
+                        key,
+                        e,
+                        delay.as_secs_f64(),
+                        attempt,
+                        self.config.retry.max_retries
+                    );
+                    std::thread::sleep(delay);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The JSON batch file, created for the first event of a batch
+    fn json_writer(&mut self) -> Result<&mut CompressedWriter<BufWriter<File>>> {
+        if self.json.is_none() {
+            let now = Utc::now();
+            let path = self.config.compression.file_path(
+                &self
+                    .staging_dir
+                    .join(format!("date={}", now.format("%Y-%m-%d")))
+                    .join(format!("changes-{}.jsonl", now.format("%Y%m%dT%H%M%S%.6f"))),
+            );
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|e| {
+                    ReplicationError::s3(format!("Failed to create {}: {}", parent.display(), e))
+                })?;
+            }
+            let file = File::create(&path).map_err(|e| {
+                ReplicationError::s3(format!("Failed to create {}: {}", path.display(), e))
+            })?;
+            let writer = CompressedWriter::new(BufWriter::new(file), self.config.compression);
+            self.json = Some((path, writer));
+        }
+        match self.json.as_mut() {
+            Some((_, writer)) => Ok(writer),
+            None => Err(ReplicationError::s3("JSON batch file is not open")),
+        }
+    }
+}
+
+impl ChangeHandler for S3Sink {
+    #[cfg(feature = "parquet")]
+    fn on_begin(&mut self, xid: Xid, final_lsn: Lsn, commit_time: DateTime<Utc>) -> Result<()> {
+        match self.parquet.as_mut() {
+            Some(parquet) => parquet.on_begin(xid, final_lsn, commit_time),
+            None => Ok(()),
+        }
+    }
+
+    fn on_change(&mut self, change: &ChangeRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(change)
+            .map_err(|e| ReplicationError::s3(format!("Failed to encode change event: {}", e)))?;
+        line.push(b'\n');
+        #[cfg(feature = "parquet")]
+        let written = match self.parquet.as_mut() {
+            Some(parquet) => parquet.on_change(change).map(|()| true)?,
+            None => false,
+        };
+        #[cfg(not(feature = "parquet"))]
+        let written = false;
+        if !written {
+            self.json_writer()?.write_all(&line).map_err(|e| {
+                ReplicationError::s3(format!("Failed to write JSON batch file: {}", e))
+            })?;
+        }
+        self.batch_bytes += line.len() as u64;
+        self.batch_started.get_or_insert_with(Instant::now);
+
+        if self.batch_bytes >= self.config.max_batch_bytes {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Written to JSON batches only, Parquet files start over with the new columns instead
+    fn on_schema_change(&mut self, change: &SchemaChangeRecord) -> Result<()> {
+        #[cfg(feature = "parquet")]
+        if self.parquet.is_some() {
+            return Ok(());
+        }
+        let mut line = serde_json::to_vec(change).map_err(|e| {
+            ReplicationError::s3(format!("Failed to encode schema change event: {}", e))
+        })?;
+        line.push(b'\n');
+        self.json_writer()?
+            .write_all(&line)
+            .map_err(|e| ReplicationError::s3(format!("Failed to write JSON batch file: {}", e)))?;
+        self.batch_bytes += line.len() as u64;
+        self.batch_started.get_or_insert_with(Instant::now);
+        Ok(())
+    }
+
+    fn on_commit(
+        &mut self,
+        commit_lsn: Lsn,
+        end_lsn: Lsn,
+        commit_time: DateTime<Utc>,
+    ) -> Result<()> {
+        #[cfg(feature = "parquet")]
+        if let Some(parquet) = self.parquet.as_mut() {
+            parquet.on_commit(commit_lsn, end_lsn, commit_time)?;
+        }
+        #[cfg(not(feature = "parquet"))]
+        let _ = (commit_lsn, commit_time);
+        self.committed_lsn = end_lsn;
+        // Nothing is outstanding, so the transaction counts as uploaded right away
+        if self.batch_started.is_none() {
+            self.uploaded_lsn = end_lsn;
+        }
+        Ok(())
+    }
+}
+
+/// Completed files with the extension under a directory, in-progress files are skipped
+#[cfg(feature = "parquet")]
+fn collect_files(directory: &Path, extension: &str, files: &mut Vec<PathBuf>) -> Result<()> {
+    let error = |e: std::io::Error| {
+        ReplicationError::s3(format!("Failed to list {}: {}", directory.display(), e))
+    };
+    for entry in fs::read_dir(directory).map_err(error)? {
+        let path = entry.map_err(error)?.path();
+        if path.is_dir() {
+            collect_files(&path, extension, files)?;
+        } else if path.extension().is_some_and(|ext| ext == extension) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Object key of a staged file: the prefix followed by its path below the staging directory
+fn object_key(prefix: &str, relative: &Path) -> String {
+    let relative = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    if prefix.is_empty() {
+        relative
+    } else {
+        format!("{}/{}", prefix, relative)
+    }
+}
+
+/// S3 client of the object and multipart upload requests
+///
+/// object_store is async, while the sink is called from the processing loop; uploads run on a
+/// runtime of the client's own and the caller waits for them, as it did for a blocking request.
+struct S3Client {
+    store: Arc<AmazonS3>,
+    runtime: Option<Runtime>,
+    bucket: String,
+}
+
+impl S3Client {
+    fn new(config: &S3Config) -> Result<Self> {
+        let endpoint = config.endpoint.trim_end_matches('/');
+        let allow_http = match (
+            endpoint.strip_prefix("https://"),
+            endpoint.strip_prefix("http://"),
+        ) {
+            (Some(authority), _) | (None, Some(authority))
+                if authority.is_empty() || authority.contains('/') =>
+            {
+                return Err(ReplicationError::config(format!(
+                    "S3 endpoint {} must be https://host[:port] or http://host[:port]",
+                    config.endpoint
+                )))
+            }
+            (Some(_), _) => false,
+            (None, Some(_)) => true,
+            (None, None) => {
+                return Err(ReplicationError::config(format!(
+                    "Unsupported S3 endpoint {}: use an https:// or http:// URL",
+                    config.endpoint
+                )))
+            }
+        };
+
+        // Whole uploads are retried by the sink with its own policy
+        let retry = RetryConfig {
+            max_retries: 0,
+            ..RetryConfig::default()
+        };
+        let store = AmazonS3Builder::new()
+            .with_endpoint(endpoint)
+            .with_virtual_hosted_style_request(false)
+            .with_bucket_name(&config.bucket)
+            .with_region(&config.region)
+            .with_access_key_id(&config.access_key_id)
+            .with_secret_access_key(&config.secret_access_key)
+            .with_retry(retry)
+            .with_client_options(
+                ClientOptions::new()
+                    .with_allow_http(allow_http)
+                    .with_timeout(REQUEST_TIMEOUT),
+            )
+            .build()
+            .map_err(|e| ReplicationError::config(format!("Invalid S3 configuration: {}", e)))?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("s3-upload")
+            .enable_all()
+            .build()
+            .map_err(|e| ReplicationError::s3(format!("Failed to start the S3 client: {}", e)))?;
+
+        Ok(Self {
+            store: Arc::new(store),
+            runtime: Some(runtime),
+            bucket: config.bucket.clone(),
+        })
+    }
+
+    /// Upload a file in one request, or in parts when it is larger than `part_size`,
+    /// returning its size
+    fn upload(&self, key: &str, path: &Path, part_size: usize) -> Result<u64> {
+        let store = Arc::clone(&self.store);
+        let location = ObjectPath::from(key);
+        let path = path.to_path_buf();
+        let part_size = part_size.max(MIN_PART_SIZE);
+        let (sender, receiver) = mpsc::channel();
+        let runtime = match self.runtime.as_ref() {
+            Some(runtime) => runtime,
+            None => return Err(ReplicationError::s3("S3 client is shut down")),
+        };
+        runtime.spawn(async move {
+            let result = upload_file(&store, &location, &path, part_size).await;
+            sender.send(result).ok();
+        });
+        let size = receiver
+            .recv()
+            .map_err(|_| ReplicationError::s3_retryable(format!("Upload of {} stopped", key)))?
+            .map_err(|e| upload_error(&self.bucket, key, e))?;
+        Ok(size)
+    }
+}
+
+impl Drop for S3Client {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks, which the async processing task it is dropped from forbids
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Failure of an upload, as object_store or while reading the staged file
+enum UploadError {
+    Store(object_store::Error),
+    Read(std::io::Error),
+}
+
+async fn upload_file(
+    store: &AmazonS3,
+    location: &ObjectPath,
+    path: &Path,
+    part_size: usize,
+) -> std::result::Result<u64, UploadError> {
+    let mut file = File::open(path).map_err(UploadError::Read)?;
+    let size = file.metadata().map_err(UploadError::Read)?.len();
+    if size <= part_size as u64 {
+        let mut body = Vec::with_capacity(size as usize);
+        file.read_to_end(&mut body).map_err(UploadError::Read)?;
+        store
+            .put(location, PutPayload::from(body))
+            .await
+            .map_err(UploadError::Store)?;
+        return Ok(size);
+    }
+
+    let mut upload = store
+        .put_multipart(location)
+        .await
+        .map_err(UploadError::Store)?;
+    match upload_parts(upload.as_mut(), &mut file, part_size).await {
+        Ok(parts) => {
+            debug!("Uploaded {} in {} parts", location, parts);
+            Ok(size)
+        }
+        Err(e) => {
+            // Parts of an abandoned upload are stored, and billed, until it is aborted
+            if let Err(abort_error) = upload.abort().await {
+                warn!(
+                    "Failed to abort multipart upload of {}: {}",
+                    location, abort_error
+                );
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Send the file in parts of `part_size` bytes and complete the upload, returning the part count
+async fn upload_parts(
+    upload: &mut dyn MultipartUpload,
+    file: &mut File,
+    part_size: usize,
+) -> std::result::Result<usize, UploadError> {
+    let mut parts = 0;
+    loop {
+        let mut buffer = Vec::with_capacity(part_size);
+        let read = Read::by_ref(file)
+            .take(part_size as u64)
+            .read_to_end(&mut buffer)
+            .map_err(UploadError::Read)?;
+        if read == 0 {
+            break;
+        }
+        upload
+            .put_part(PutPayload::from(buffer))
+            .await
+            .map_err(UploadError::Store)?;
+        parts += 1;
+        if read < part_size {
+            break;
+        }
+    }
+    upload.complete().await.map_err(UploadError::Store)?;
+    Ok(parts)
+}
+
+/// Sink error of a failed upload; requests that failed on the connection or with a server
+/// error may succeed when resent, rejected credentials, permissions or buckets will not
+fn upload_error(bucket: &str, key: &str, error: UploadError) -> ReplicationError {
+    match error {
+        UploadError::Read(e) => {
+            ReplicationError::s3(format!("Failed to read the file of {}: {}", key, e))
+        }
+        UploadError::Store(e) => {
+            let message = format!("Upload to s3://{}/{} failed: {}", bucket, key, e);
+            match e {
+                object_store::Error::Generic { .. } | object_store::Error::JoinError { .. } => {
+                    ReplicationError::s3_retryable(message)
+                }
+                _ => ReplicationError::s3(message),
+            }
+        }
+    }
+}
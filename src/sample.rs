@@ -0,0 +1,239 @@
+//! Sampling of change events on high-volume tables
+//! A rule such as `orders=1/100` or `public.events*=5%` lets only a share of a table's changes
+//! through to the output and sinks once the table and row filters passed them. Sampling is
+//! deterministic, the first change of a table always passes, and the run summary and metrics
+//! still count every change.
+
+use crate::errors::{ReplicationError, Result};
+use crate::filter::pattern_matches;
+use crate::health::HealthState;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// How often the sampled-out counts are published to the health endpoint
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Share of a table's changes that is emitted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleRate {
+    /// One change out of every N
+    OneIn(u64),
+    /// A percentage of the changes, above 0 and at most 100
+    Percent(f64),
+}
+
+impl SampleRate {
+    /// Whether the change numbered `seen` (from 0) of a table is emitted; spreads the emitted
+    /// changes evenly so any run of changes keeps the rate
+    pub fn keeps(&self, seen: u64) -> bool {
+        match *self {
+            SampleRate::OneIn(n) => seen.is_multiple_of(n),
+            SampleRate::Percent(percent) => {
+                let emitted = |count: u64| (count as f64 * percent / 100.0).ceil();
+                emitted(seen + 1) > emitted(seen)
+            }
+        }
+    }
+}
+
+impl fmt::Display for SampleRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SampleRate::OneIn(n) => write!(f, "1/{}", n),
+            SampleRate::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+impl FromStr for SampleRate {
+    type Err = ReplicationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let invalid = || {
+            ReplicationError::config(format!(
+                "Invalid sample rate: {}. Use 1/N or a percentage such as 5%",
+                s
+            ))
+        };
+
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent: f64 = percent.trim().parse().map_err(|_| invalid())?;
+            if !(percent > 0.0 && percent <= 100.0) {
+                return Err(ReplicationError::config(format!(
+                    "Sample percentage {} must be above 0 and at most 100",
+                    percent
+                )));
+            }
+            return Ok(SampleRate::Percent(percent));
+        }
+
+        let (one, n) = s.split_once('/').ok_or_else(invalid)?;
+        if one.trim() != "1" {
+            return Err(invalid());
+        }
+        match n.trim().parse::<u64>() {
+            Ok(0) | Err(_) => Err(invalid()),
+            Ok(n) => Ok(SampleRate::OneIn(n)),
+        }
+    }
+}
+
+/// Sample rate of the tables matching a glob, written `pattern=1/N` or `pattern=P%`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleRule {
+    pattern: String,
+    rate: SampleRate,
+}
+
+impl SampleRule {
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn rate(&self) -> SampleRate {
+        self.rate
+    }
+}
+
+impl fmt::Display for SampleRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.pattern, self.rate)
+    }
+}
+
+impl FromStr for SampleRule {
+    type Err = ReplicationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (pattern, rate) = s.split_once('=').ok_or_else(|| {
+            ReplicationError::config(format!(
+                "Invalid sample rule: {}. Use table=1/N or table=P%",
+                s
+            ))
+        })?;
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return Err(ReplicationError::config(format!(
+                "Sample rule {} names no table",
+                s
+            )));
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+            rate: rate.parse()?,
+        })
+    }
+}
+
+/// Sample rules of all tables; the first rule matching a table applies
+#[derive(Debug, Clone, Default)]
+pub struct Sampling {
+    rules: Vec<SampleRule>,
+}
+
+impl Sampling {
+    pub fn new(rules: Vec<SampleRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn rules(&self) -> &[SampleRule] {
+        &self.rules
+    }
+
+    /// Sample rate of `schema.table`, None when all its changes are emitted
+    pub fn rate(&self, schema: &str, table: &str) -> Option<SampleRate> {
+        self.rules
+            .iter()
+            .find(|rule| pattern_matches(&rule.pattern, schema, table))
+            .map(|rule| rule.rate)
+    }
+}
+
+/// Changes of a sampled table seen and emitted so far
+#[derive(Debug, Clone, Copy, Default)]
+struct TableSample {
+    seen: u64,
+    emitted: u64,
+}
+
+/// Decides which changes of sampled tables are emitted, counting them per table
+#[derive(Debug)]
+pub struct EventSampler {
+    tables: BTreeMap<String, TableSample>,
+    last_published: Instant,
+    changed: bool,
+}
+
+impl EventSampler {
+    pub fn new() -> Self {
+        Self {
+            tables: BTreeMap::new(),
+            last_published: Instant::now(),
+            changed: false,
+        }
+    }
+
+    /// Whether a change of `schema.table` that passed the filters is emitted
+    pub fn keep(&mut self, sampling: &Sampling, schema: &str, table: &str) -> bool {
+        let Some(rate) = sampling.rate(schema, table) else {
+            return true;
+        };
+
+        let name = format!("{}.{}", schema, table);
+        let sample = self.tables.entry(name).or_default();
+        let keep = rate.keeps(sample.seen);
+        sample.seen += 1;
+        if keep {
+            sample.emitted += 1;
+        }
+        self.changed = true;
+        keep
+    }
+
+    /// Changes left out so far, by schema-qualified table name
+    pub fn sampled_out(&self) -> BTreeMap<String, u64> {
+        self.tables
+            .iter()
+            .map(|(table, sample)| (table.clone(), sample.seen - sample.emitted))
+            .collect()
+    }
+
+    /// Publish the sampled-out counts to the health endpoint, at most once a second
+    pub fn maybe_publish(&mut self, health: &HealthState) {
+        if !self.changed || self.last_published.elapsed() < PUBLISH_INTERVAL {
+            return;
+        }
+        health.set_sampled_out(self.sampled_out());
+        self.last_published = Instant::now();
+        self.changed = false;
+    }
+
+    /// Log how many changes of each sampled table were emitted
+    pub fn log_summary(&self) {
+        for (table, sample) in &self.tables {
+            info!(
+                table = table.as_str(),
+                seen = sample.seen,
+                emitted = sample.emitted,
+                "Sampled {}: emitted {} of {} changes",
+                table,
+                sample.emitted,
+                sample.seen
+            );
+        }
+    }
+}
+
+impl Default for EventSampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,313 @@
+//! Exactly-once file output in LSN-named segments
+//! The segmented file sink appends events to an in-progress file and completes it at a
+//! transaction boundary: the file is synced and renamed to the range of end LSNs of the
+//! transactions it holds, and only then are they acknowledged. At startup the in-progress file
+//! left by a crash is removed, and transactions the server sends again that end at or before the
+//! last completed segment are skipped, so each transaction lands in exactly one segment.
+
+use crate::errors::{ReplicationError, Result};
+use crate::sink::{EventSink, SinkEvent, SinkFuture};
+use crate::types::Lsn;
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Name of the segment being written, in the sink's directory
+const IN_PROGRESS_NAME: &str = "segment.jsonl.inprogress";
+
+/// Extension of completed segments, named `<first end LSN>-<last end LSN>.jsonl` in 16 hex digits
+const SEGMENT_EXTENSION: &str = "jsonl";
+
+/// When a segment is completed
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentLimits {
+    /// Bytes after which the segment is completed at the next transaction end
+    pub max_bytes: u64,
+    /// Age after which the segment is completed at the next transaction end
+    pub max_age: Duration,
+}
+
+impl Default for SegmentLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024 * 1024,
+            max_age: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The segment being written
+struct OpenSegment {
+    writer: BufWriter<File>,
+    opened: Instant,
+    /// Bytes written, including those of a transaction that has not ended yet
+    written: u64,
+    /// Bytes of the transactions that ended, the segment is cut here when completed
+    committed: u64,
+    /// End LSNs of the first and last transaction in the segment
+    first_lsn: Option<Lsn>,
+    last_lsn: Lsn,
+}
+
+/// Writes events as JSON lines to LSN-named segment files in a directory
+pub struct SegmentedFileSink {
+    directory: PathBuf,
+    limits: SegmentLimits,
+    current: Option<OpenSegment>,
+    /// End LSN of the last transaction in a completed segment
+    durable_lsn: Lsn,
+    /// Transactions ending at or before this position are in a segment from an earlier run
+    recovered_lsn: Lsn,
+    skipped: u64,
+}
+
+impl SegmentedFileSink {
+    /// Open the directory, removing an in-progress segment and finding where the completed
+    /// segments end
+    pub fn open(directory: PathBuf, limits: SegmentLimits) -> Result<Self> {
+        fs::create_dir_all(&directory)
+            .map_err(|e| segment_error("create directory", &directory, e))?;
+
+        let in_progress = directory.join(IN_PROGRESS_NAME);
+        if let Ok(metadata) = fs::metadata(&in_progress) {
+            warn!(
+                "Discarding {} bytes of an incomplete segment in {}, their transactions are streamed again",
+                metadata.len(),
+                directory.display()
+            );
+            fs::remove_file(&in_progress).map_err(|e| segment_error("remove", &in_progress, e))?;
+        }
+
+        let entries = fs::read_dir(&directory).map_err(|e| segment_error("read", &directory, e))?;
+        let recovered_lsn = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| segment_range(&entry.path()))
+            .map(|(_, last)| last)
+            .max()
+            .unwrap_or(Lsn::INVALID);
+        if recovered_lsn.is_valid() {
+            info!(
+                "Segments in {} end at {}, transactions up to it are not written again",
+                directory.display(),
+                recovered_lsn
+            );
+        }
+
+        Ok(Self {
+            directory,
+            limits,
+            current: None,
+            durable_lsn: recovered_lsn,
+            recovered_lsn,
+            skipped: 0,
+        })
+    }
+
+    fn in_progress_path(&self) -> PathBuf {
+        self.directory.join(IN_PROGRESS_NAME)
+    }
+
+    fn segment(&mut self) -> Result<&mut OpenSegment> {
+        if self.current.is_none() {
+            let path = self.in_progress_path();
+            let file = File::create(&path).map_err(|e| segment_error("create", &path, e))?;
+            self.current = Some(OpenSegment {
+                writer: BufWriter::new(file),
+                opened: Instant::now(),
+                written: 0,
+                committed: 0,
+                first_lsn: None,
+                last_lsn: Lsn::INVALID,
+            });
+        }
+        Ok(self.current.as_mut().expect("segment was just opened"))
+    }
+
+    fn append(&mut self, batch: &[SinkEvent]) -> Result<()> {
+        let mut lines = Vec::new();
+        for event in batch {
+            serde_json::to_writer(&mut lines, event)
+                .map_err(|e| ReplicationError::sink(format!("Failed to encode event: {}", e)))?;
+            lines.push(b'\n');
+        }
+        let path = self.in_progress_path();
+        let segment = self.segment()?;
+        segment
+            .writer
+            .write_all(&lines)
+            .map_err(|e| segment_error("write", &path, e))?;
+        segment.written += lines.len() as u64;
+        Ok(())
+    }
+
+    /// Mark the end of a transaction, completing the segment once it is big or old enough
+    fn end_transaction(&mut self, end_lsn: Lsn) -> Result<()> {
+        let Some(segment) = self.current.as_mut() else {
+            // Nothing of the transaction was written, so nothing needs to be made durable
+            self.durable_lsn = self.durable_lsn.max(end_lsn);
+            return Ok(());
+        };
+
+        if end_lsn <= self.recovered_lsn {
+            // Already in a completed segment, cut its events off again
+            let path = self.directory.join(IN_PROGRESS_NAME);
+            let committed = segment.committed;
+            truncate(segment, committed).map_err(|e| segment_error("truncate", &path, e))?;
+            if self.skipped == 0 {
+                info!(
+                    "Skipping transactions up to {} sent again, they are in a completed segment",
+                    self.recovered_lsn
+                );
+            }
+            self.skipped += 1;
+            debug!("Skipped transaction ending at {}", end_lsn);
+            return Ok(());
+        }
+
+        segment.committed = segment.written;
+        segment.last_lsn = end_lsn;
+        if segment.committed == 0 {
+            self.durable_lsn = self.durable_lsn.max(end_lsn);
+            return Ok(());
+        }
+        segment.first_lsn.get_or_insert(end_lsn);
+        if segment.committed >= self.limits.max_bytes
+            || segment.opened.elapsed() >= self.limits.max_age
+        {
+            self.complete()?;
+        }
+        Ok(())
+    }
+
+    /// Sync the transactions that ended in the segment and rename it to their LSN range,
+    /// dropping events of a transaction still open
+    fn complete(&mut self) -> Result<()> {
+        let Some(mut segment) = self.current.take() else {
+            return Ok(());
+        };
+        let path = self.in_progress_path();
+        let Some(first_lsn) = segment.first_lsn else {
+            drop(segment);
+            return fs::remove_file(&path).map_err(|e| segment_error("remove", &path, e));
+        };
+
+        let committed = segment.committed;
+        truncate(&mut segment, committed)
+            .and_then(|()| segment.writer.get_ref().sync_all())
+            .map_err(|e| segment_error("sync", &path, e))?;
+        drop(segment.writer);
+
+        let name = format!(
+            "{:016X}-{:016X}.{}",
+            first_lsn.value(),
+            segment.last_lsn.value(),
+            SEGMENT_EXTENSION
+        );
+        let target = self.directory.join(&name);
+        fs::rename(&path, &target).map_err(|e| segment_error("rename", &path, e))?;
+        // The rename is only durable once the directory entry is
+        File::open(&self.directory)
+            .and_then(|directory| directory.sync_all())
+            .map_err(|e| segment_error("sync directory", &self.directory, e))?;
+
+        self.durable_lsn = self.durable_lsn.max(segment.last_lsn);
+        info!(
+            "Completed segment {} with {} bytes up to {}",
+            target.display(),
+            committed,
+            segment.last_lsn
+        );
+        Ok(())
+    }
+}
+
+impl EventSink for SegmentedFileSink {
+    fn write<'a>(&'a mut self, batch: &'a [SinkEvent]) -> SinkFuture<'a> {
+        Box::pin(async move { self.append(batch) })
+    }
+
+    fn flush(&mut self) -> SinkFuture<'_> {
+        Box::pin(async move {
+            let path = self.in_progress_path();
+            let Some(segment) = self.current.as_mut() else {
+                return Ok(());
+            };
+            segment
+                .writer
+                .flush()
+                .map_err(|e| segment_error("write", &path, e))?;
+            // A quiet stream completes its segment once no transaction is open in it
+            if segment.written == segment.committed
+                && segment.opened.elapsed() >= self.limits.max_age
+            {
+                self.complete()?;
+            }
+            Ok(())
+        })
+    }
+
+    fn tracks_commits(&self) -> bool {
+        true
+    }
+
+    fn commit(&mut self, end_lsn: Lsn) -> SinkFuture<'_> {
+        Box::pin(async move { self.end_transaction(end_lsn) })
+    }
+
+    fn durable_lsn(&self) -> Option<Lsn> {
+        Some(self.durable_lsn)
+    }
+}
+
+impl Drop for SegmentedFileSink {
+    fn drop(&mut self) {
+        // Complete what ended at shutdown, so it is not streamed and skipped again
+        if let Err(e) = self.complete() {
+            warn!("Failed to complete the last segment: {}", e);
+        }
+        if self.skipped > 0 {
+            info!(
+                "Skipped {} transactions already in segments in {}",
+                self.skipped,
+                self.directory.display()
+            );
+        }
+    }
+}
+
+/// Cut the segment back to `length` bytes and continue writing from there
+fn truncate(segment: &mut OpenSegment, length: u64) -> std::io::Result<()> {
+    segment.writer.flush()?;
+    if segment.written != length {
+        let file = segment.writer.get_mut();
+        file.set_len(length)?;
+        file.seek(SeekFrom::Start(length))?;
+        segment.written = length;
+    }
+    Ok(())
+}
+
+/// First and last end LSN of a completed segment, from its file name
+fn segment_range(path: &Path) -> Option<(Lsn, Lsn)> {
+    if path.extension()? != SEGMENT_EXTENSION {
+        return None;
+    }
+    let (first, last) = path.file_stem()?.to_str()?.split_once('-')?;
+    if first.len() != 16 || last.len() != 16 {
+        return None;
+    }
+    let first = u64::from_str_radix(first, 16).ok()?;
+    let last = u64::from_str_radix(last, 16).ok()?;
+    Some((Lsn(first), Lsn(last)))
+}
+
+fn segment_error(action: &str, path: &Path, e: std::io::Error) -> ReplicationError {
+    ReplicationError::sink(format!(
+        "Failed to {} segment {}: {}",
+        action,
+        path.display(),
+        e
+    ))
+}
@@ -1,89 +1,746 @@
 //! PostgreSQL replication server implementation
 //! Main server that handles connection, replication slot management, and message processing
 
-use crate::buffer::{BufferReader, BufferWriter};
-use crate::errors::Result;
+use crate::alert::AlertMonitor;
+#[cfg(feature = "alloc-stats")]
+use crate::allocstats::{self, Component};
+use crate::apply::ApplyTarget;
+use crate::assembler::{BufferedMessage, CommittedTransaction, TransactionAssembler};
+use crate::avro::AvroSink;
+use crate::buffer::BufferReader;
+use crate::bufpool::{BufferPool, PooledBuffer};
+use crate::capture::{CaptureReader, CaptureWriter};
+use crate::checkpoint::CheckpointStore;
+use crate::commitlog::CommitLog;
+use crate::control::ControlConnection;
+use crate::ctlsocket::StreamControl;
+use crate::datetime::{normalize, DateOrder};
+use crate::envelope::{ClockSkew, TransactionEnvelopes};
+use crate::errors::{ErrorKind, ReplicationError, Result};
+use crate::eventid::EventIds;
+use crate::events::{EventCollector, EventStream};
+use crate::handler::ChangeHandler;
+use crate::health::HealthState;
+use crate::hexdump;
+use crate::initsync::{parse_copy_row, CsvTableWriter, SnapshotReader, SnapshotTable};
+use crate::mask::ColumnMask;
+use crate::output::{ChangeRecord, EventWriter, SchemaChangeRecord};
+#[cfg(feature = "parquet")]
+use crate::parquet::ParquetSink;
 use crate::parser::MessageParser;
+use crate::pipeline::{receive, PipelineShared, StatusUpdate};
+use crate::progress::{CatchUpProgress, CatchUpSnapshot};
+use crate::publication::{log_published_tables, ColumnPublication};
+use crate::quote::{publication_names_option, quote_command_literal, quote_ident};
+use crate::reconnect::ReceiveWatchdog;
+use crate::relcache::{describe_columns, describe_relation, RelationCache, RelationUpdate};
+use crate::reload::PendingReload;
+use crate::row::{format_changes, ColumnChange, Row};
+use crate::rowfilter::RowFilter;
+#[cfg(feature = "s3")]
+use crate::s3::S3Sink;
+use crate::sample::EventSampler;
+use crate::serverinfo::ServerInfo;
+use crate::sink::{EventSink, SinkFanout, SinkOptions};
+use crate::slot::{
+    create_slot_with_snapshot, drop_slot, find_slot, prepare_slot, CreatedSlot, SlotMode,
+};
+use crate::stats::{DelayStats, LagSnapshot, LagStats};
+use crate::stop::StopReason;
+use crate::substat::{SubscriptionSnapshot, SubscriptionStats};
+use crate::summary::{RunReport, RunSummary};
+use crate::throttle::Throttle;
+use crate::timeline::TimelineSwitch;
+use crate::toast::{ToastCache, UNCHANGED_TOAST_MARKER};
+use crate::txlimit::{LimitAction, TransactionLimiter};
+use crate::txspan::TransactionSpans;
 use crate::types::*;
-use crate::utils::{format_timestamp_from_pg, system_time_to_postgres_timestamp, PGConnection, INVALID_XLOG_REC_PTR};
-use std::time::{Duration, Instant, SystemTime};
-use tracing::{debug, error, info, warn};
+use crate::utils::{
+    format_timestamp_from_pg, pg_commit_time, pg_timestamp_to_datetime, Oid, PGConnection, Xid,
+};
+use crate::validate::StreamValidator;
+use crate::walkeep::WalKeepWatchdog;
+use crate::watch::{ChangeKind, WatchSummary};
+use chrono::{DateTime, Utc};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn, Span};
+
+/// Longest the replication loop sleeps waiting for data before servicing its timers
+const IDLE_WAKEUP_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Longest the final status update may take to reach the server when the stream stops
+const FINAL_FEEDBACK_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct ReplicationServer {
-    connection: PGConnection,
+    /// Replication connection, None when replaying a capture file offline
+    connection: Option<PGConnection>,
+    /// Buffers CopyData messages are received into, kept across reconnects
+    buffer_pool: BufferPool,
+    /// Status updates sent on this thread are encoded into it, so feedback allocates nothing
+    status_update: StatusUpdate,
+    /// Regular connection for catalog queries, if enabled
+    control: Option<ControlConnection>,
+    /// State shared with the receive stage, which holds the connection while streaming
+    pipeline: Option<Arc<PipelineShared>>,
     config: ReplicationConfig,
     state: ReplicationState,
+    progress: CatchUpProgress,
+    stats: LagStats,
+    delays: DelayStats,
+    subscription: SubscriptionStats,
+    /// Commit time of the transaction being received, from its Begin message
+    commit_time: Option<DateTime<Utc>>,
+    events_processed: u64,
+    last_data_lsn: Lsn,
+    /// Spans of the transactions in progress, their messages are processed within
+    transaction_spans: TransactionSpans,
+    /// Transaction whose buffered messages are being processed, once it is no longer streaming
+    replaying_xid: Option<Xid>,
+    watch: Option<WatchSummary>,
+    summary: RunSummary,
+    sampler: EventSampler,
+    /// Structured output, reopened when a reload changes it
+    output: Option<EventWriter>,
+    handlers: Vec<Box<dyn ChangeHandler>>,
+    checkpoint: Option<CheckpointStore>,
+    /// Recent commits, against which transactions delivered again are verified
+    commit_log: Option<CommitLog>,
+    apply: Option<ApplyTarget>,
+    capture: Option<CaptureWriter>,
+    shutdown: Arc<AtomicBool>,
+    reload: Arc<PendingReload>,
+    health: Arc<HealthState>,
+    /// Identity and version reported on the last connect, its timeline recognising a switch
+    /// on reconnect
+    server_info: Option<ServerInfo>,
+    /// The slot created for this run as CREATE_REPLICATION_SLOT described it, streaming cannot
+    /// resume before its consistent point
+    created_slot: Option<CreatedSlot>,
+    validator: Option<StreamValidator>,
+    #[cfg(feature = "parquet")]
+    parquet: Option<ParquetSink>,
+    avro: Option<AvroSink>,
+    #[cfg(feature = "s3")]
+    s3: Option<S3Sink>,
+    /// Sinks of the `[[sink]]` tables and those added with `add_sink`
+    fanout: SinkFanout,
+    /// Transactions handed to an `EventStream`
+    events: Option<EventCollector>,
+    /// Numbers emitted changes when event IDs are enabled
+    event_ids: Option<EventIds>,
+    /// Builds the transaction metadata of emitted changes when the envelope is enabled
+    envelopes: Option<TransactionEnvelopes>,
+    /// When the message being processed was received
+    received_at: DateTime<Utc>,
+    clock_skew: ClockSkew,
+    /// Last rows per table, for backfilling unchanged TOAST values
+    toast_cache: Option<ToastCache>,
+    /// Drops or rejects the changes of transactions over their size limit
+    transaction_limiter: Option<TransactionLimiter>,
+    /// Holds back processing to the configured events and bytes per second
+    throttle: Option<Throttle>,
+    alerts: Option<AlertMonitor>,
+    /// Checks the WAL the slot retains, when the control connection is open
+    wal_keep: Option<WalKeepWatchdog>,
+    stream_control: Arc<StreamControl>,
 }
 
 impl ReplicationServer {
     pub fn new(config: ReplicationConfig) -> Result<Self> {
         let connection = PGConnection::connect(&config.connection_string)?;
         info!("Successfully connected to database server");
+        connection.log_tls_state();
+
+        Self::with_connection(config, Some(connection))
+    }
+
+    /// Create a server that only decodes capture files, without a replication connection
+    pub fn offline(config: ReplicationConfig) -> Result<Self> {
+        Self::with_connection(config, None)
+    }
+
+    fn with_connection(
+        config: ReplicationConfig,
+        mut connection: Option<PGConnection>,
+    ) -> Result<Self> {
+        let progress = CatchUpProgress::new(Duration::from_secs(config.progress_interval_secs));
+        let buffer_pool = BufferPool::new();
+        if let Some(connection) = connection.as_mut() {
+            connection.set_buffer_pool(buffer_pool.clone());
+        }
+        let mut stats = LagStats::new(Duration::from_secs(config.stats_interval_secs));
+        stats.track_buffer_pool(buffer_pool.clone());
+        let delays = DelayStats::new(Duration::from_secs(config.stats_interval_secs));
+        let subscription =
+            SubscriptionStats::new(Duration::from_secs(config.subscription_stats_interval_secs));
+        let watch = config.watch_duration.map(|_| WatchSummary::new());
+        let output = EventWriter::open(&config.output)?;
+        let checkpoint = config.checkpoint_path.clone().map(CheckpointStore::new);
+        let commit_log = config
+            .commit_log_path
+            .clone()
+            .map(|path| CommitLog::open(path, config.slot_name.clone(), config.commit_log_window))
+            .transpose()?;
+        let apply = config
+            .target_connection_string
+            .as_deref()
+            .map(ApplyTarget::connect)
+            .transpose()?;
+        let capture = config
+            .record_path
+            .as_deref()
+            .map(|path| CaptureWriter::create(path, config.output.compression))
+            .transpose()?;
+        if config.max_column_bytes.is_some() && apply.is_some() {
+            return Err(ReplicationError::config(
+                "Truncating column values cannot be combined with applying changes to a target",
+            ));
+        }
+        // A target must receive whole transactions, so a limit may only stop the run
+        if apply.is_some()
+            && config
+                .transaction_limits
+                .as_ref()
+                .is_some_and(|limits| limits.action != LimitAction::Abort)
+        {
+            return Err(ReplicationError::config(
+                "Transaction limits that truncate or skip changes cannot be combined with applying changes to a target, use the abort action",
+            ));
+        }
+        if let Some(max) = config.max_column_bytes {
+            info!("Column values are truncated to {} bytes", max);
+        }
+        if config.init_sync.is_some() && config.start_lsn.is_some() {
+            return Err(ReplicationError::config(
+                "Initial sync streams from the new slot's consistent point, a start LSN cannot be set",
+            ));
+        }
+        if config.dump_raw && !hexdump::enabled() {
+            warn!("--dump-raw logs at trace level, set LOG_LEVEL=info,replication_checker::hexdump=trace to see the dumps");
+        }
+        let validator = StreamValidator::for_mode(config.validation);
+        #[cfg(feature = "parquet")]
+        let parquet = config
+            .parquet
+            .clone()
+            .map(|parquet| ParquetSink::new(parquet, config.column_mask.clone()))
+            .transpose()?;
+        let avro = config
+            .avro
+            .clone()
+            .map(|avro| AvroSink::new(avro, config.column_mask.clone()))
+            .transpose()?;
+        #[cfg(feature = "s3")]
+        let s3 = config
+            .s3
+            .clone()
+            .map(|s3| S3Sink::new(s3, config.column_mask.clone()))
+            .transpose()?;
+        let mut fanout = SinkFanout::from_configs(&config.sinks)?;
+        if let Some(webhook) = &config.webhook {
+            fanout.add_webhook(webhook)?;
+        }
+        let control = match &connection {
+            Some(_) if config.control_connection => Some(ControlConnection::connect(
+                &config.connection_string,
+                config.publication_names.clone(),
+                config.slot_name.clone(),
+                Duration::from_secs(config.stats_interval_secs),
+            )?),
+            _ => None,
+        };
+        let event_ids = config.event_ids.then(EventIds::new);
+        let envelopes = config
+            .transaction_envelope
+            .then(|| TransactionEnvelopes::new(config.slot_name.clone()));
+        let toast_cache = config.toast_cache_rows.map(ToastCache::new);
+        let transaction_limiter = config
+            .transaction_limits
+            .clone()
+            .map(TransactionLimiter::new);
+        let throttle = config.rate_limits.map(Throttle::new);
+        let alerts = config
+            .alerts
+            .clone()
+            .map(|alerts| {
+                AlertMonitor::new(alerts, config.slot_name.clone(), config.source.clone())
+            })
+            .transpose()?;
+        if control.is_none()
+            && config
+                .alerts
+                .as_ref()
+                .is_some_and(|alerts| alerts.max_wal_keep_percent.is_some())
+        {
+            return Err(ReplicationError::config(
+                "The WAL keep alert needs the control connection",
+            ));
+        }
+        let wal_keep = config
+            .wal_keep
+            .filter(|_| control.is_some())
+            .map(|wal_keep| WalKeepWatchdog::new(wal_keep, config.slot_name.clone()));
+        let health = Arc::new(HealthState::new());
+        health.set_connected(connection.is_some());
+        let mut state = ReplicationState::new();
+        state.relations = RelationCache::new(config.relation_cache_size);
+        state.assembler = config
+            .transaction_buffer
+            .clone()
+            .map(TransactionAssembler::new);
+        let transaction_spans = TransactionSpans::new(config.source.clone());
 
         Ok(Self {
             connection,
+            buffer_pool,
+            status_update: StatusUpdate::new(),
+            control,
+            pipeline: None,
             config,
-            state: ReplicationState::new(),
+            state,
+            progress,
+            stats,
+            delays,
+            subscription,
+            commit_time: None,
+            events_processed: 0,
+            last_data_lsn: Lsn::INVALID,
+            transaction_spans,
+            replaying_xid: None,
+            watch,
+            summary: RunSummary::new(),
+            sampler: EventSampler::new(),
+            output,
+            handlers: Vec::new(),
+            checkpoint,
+            commit_log,
+            apply,
+            capture,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            reload: Arc::new(PendingReload::default()),
+            health,
+            server_info: None,
+            created_slot: None,
+            validator,
+            #[cfg(feature = "parquet")]
+            parquet,
+            avro,
+            #[cfg(feature = "s3")]
+            s3,
+            fanout,
+            events: None,
+            event_ids,
+            envelopes,
+            received_at: Utc::now(),
+            clock_skew: ClockSkew::default(),
+            toast_cache,
+            transaction_limiter,
+            throttle,
+            alerts,
+            wal_keep,
+            stream_control: Arc::new(StreamControl::new()),
         })
     }
 
-    pub fn identify_system(&self) -> Result<()> {
+    /// Register a handler that receives every decoded transaction
+    pub fn add_handler(&mut self, handler: impl ChangeHandler + 'static) {
+        self.handlers.push(Box::new(handler));
+    }
+
+    /// Send batches of change events to a sink running on its own thread, next to the sinks of
+    /// the `[[sink]]` tables; the position confirmed never passes what it acknowledged
+    pub fn add_sink(&mut self, options: SinkOptions, sink: impl EventSink + 'static) -> Result<()> {
+        self.fanout.add(options, Box::new(sink))
+    }
+
+    /// Receive committed transactions as a stream; the server is only told the positions
+    /// acknowledged with `EventStream::ack`, and reading pauses while `capacity` transactions
+    /// wait in the stream. Replaces a stream taken before.
+    pub fn event_stream(&mut self, capacity: usize) -> EventStream {
+        let (collector, stream) = EventCollector::new(capacity, self.shutdown.clone());
+        self.events = Some(collector);
+        stream
+    }
+
+    /// Flag that stops the replication loop gracefully once set, e.g. from a signal handler
+    pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        self.shutdown.clone()
+    }
+
+    /// Settings reloaded at runtime, switched to between transactions
+    pub fn reload_handle(&self) -> Arc<PendingReload> {
+        self.reload.clone()
+    }
+
+    /// Liveness and readiness of the stream, as served by the health endpoint
+    pub fn health_state(&self) -> Arc<HealthState> {
+        self.health.clone()
+    }
+
+    /// Pause, resume and feedback requests for the streaming loop
+    pub fn stream_control(&self) -> Arc<StreamControl> {
+        self.stream_control.clone()
+    }
+
+    /// Current catch-up progress towards the server's WAL end
+    pub fn catch_up_progress(&self) -> CatchUpSnapshot {
+        self.progress.snapshot()
+    }
+
+    /// This subscriber's statistics in the fields of pg_stat_subscription
+    pub fn subscription_stats(&self) -> SubscriptionSnapshot {
+        self.subscription.snapshot()
+    }
+
+    /// The slot created for this run, None when an existing slot is streamed from
+    pub fn created_slot(&self) -> Option<&CreatedSlot> {
+        self.created_slot.as_ref()
+    }
+
+    /// Consistent point of the slot created for this run
+    fn slot_start(&self) -> Lsn {
+        self.created_slot
+            .as_ref()
+            .map(|slot| slot.consistent_point)
+            .unwrap_or_default()
+    }
+
+    /// Current replication lag against the server
+    pub fn lag_stats(&self) -> LagSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Statistics of the run so far, as logged on shutdown
+    pub fn run_report(&self) -> RunReport {
+        self.summary.report()
+    }
+
+    /// The replication connection, which offline servers do not have
+    fn connection(&self) -> Result<&PGConnection> {
+        self.connection
+            .as_ref()
+            .ok_or_else(|| ReplicationError::connection("Not connected to a server"))
+    }
+
+    /// Identify the server and its version, logging a timeline switch since the previous
+    /// connection
+    pub fn identify_system(&mut self) -> Result<()> {
         debug!("Identifying system");
-        match self.connection.exec("IDENTIFY_SYSTEM") {
-            Ok(result) => {
-                let status = result.status();
-                if result.is_ok() && result.ntuples() > 0 {
-                    let system_id = result.getvalue(0, 0);
-                    let timeline = result.getvalue(0, 1); 
-                    let xlogpos = result.getvalue(0, 2);
-                    let dbname = result.getvalue(0, 3);
-                    info!("IDENTIFY_SYSTEM succeeded: status: {:?}, system_id: {:?}, timeline: {:?}, xlogpos: {:?}, dbname: {:?}", 
-                        status, system_id, timeline, xlogpos, dbname);
-                } else {
-                    return Err(crate::errors::ReplicationError::protocol(format!(
-                        "IDENTIFY_SYSTEM failed: status: {:?}, rows: {}, columns: {}. This usually means the connection is not in replication mode or lacks replication privileges.",
-                        status, result.ntuples(), result.nfields()
-                    )));
+        let info = ServerInfo::fetch(self.connection()?)?;
+        info.log();
+
+        // Logical slots carry over a promotion, so streaming simply continues on the new
+        // timeline from the last confirmed position
+        let timeline = info.identity.timeline;
+        if let Some(previous) = self
+            .server_info
+            .as_ref()
+            .map(|previous| previous.identity.timeline)
+            .filter(|previous| *previous != timeline)
+        {
+            let switch = TimelineSwitch::resolve(self.connection()?, previous, timeline);
+            switch.log();
+            self.summary.record_timeline_switch();
+        }
+        self.server_info = Some(info);
+        Ok(())
+    }
+
+    /// Identity, version and capabilities of the server from the last `identify_system`
+    pub fn server_info(&self) -> Option<&ServerInfo> {
+        self.server_info.as_ref()
+    }
+
+    /// Prepare the slot and stream changes, reconnecting with backoff when the connection drops
+    pub async fn create_replication_slot_and_start(&mut self) -> Result<()> {
+        // Refuse unsupported options before a slot is created for them
+        if let Some(info) = &self.server_info {
+            info.check_options(&self.config)?;
+        }
+        log_published_tables(
+            &self.config.connection_string,
+            &self.config.publication_names,
+        );
+        match self.config.init_sync {
+            Some(_) => self.initial_sync()?,
+            None => {
+                self.created_slot = prepare_slot(
+                    self.connection()?,
+                    &self.config.slot_name,
+                    self.config.slot_mode,
+                )?;
+            }
+        }
+
+        let policy = self.config.reconnect;
+        let mut attempt = 0;
+        loop {
+            let started = if attempt == 0 {
+                self.start_replication()
+            } else {
+                self.reconnect()
+            };
+            let result = match started {
+                Ok(()) => {
+                    attempt = 0;
+                    self.replication_loop().await
+                }
+                Err(e) => Err(e),
+            };
+
+            let error = match result {
+                Ok(()) => {
+                    self.health.set_connected(false);
+                    return self.drop_slot_on_exit();
                 }
+                Err(e) => e,
+            };
+            // Only a lost connection is worth reconnecting for; sinks retry their own errors
+            let reconnect = error.kind() == ErrorKind::Connection && error.is_retryable();
+            if reconnect {
+                self.health.set_connected(false);
             }
-            Err(err) => {
-                return Err(crate::errors::ReplicationError::protocol(format!(
-                    "IDENTIFY_SYSTEM command failed: {}",
-                    err
-                )));
+            attempt += 1;
+            if !reconnect || attempt > policy.max_retries {
+                return Err(error);
             }
-        }
 
-        info!("System identification successful");
-        Ok(())
+            self.health.record_error(&error);
+            let delay = policy.backoff(attempt);
+            warn!(
+                error_kind = %error.kind(),
+                error_code = error.code(),
+                "Replication connection lost: {}. Reconnecting in {:.1}s (attempt {}/{})",
+                error,
+                delay.as_secs_f64(),
+                attempt,
+                policy.max_retries
+            );
+            tokio::time::sleep(delay).await;
+        }
     }
 
-    pub async fn create_replication_slot_and_start(&mut self) -> Result<()> {
-        self.create_replication_slot()?;
-        self.start_replication().await?;
+    /// Create the slot with an exported snapshot and copy the published tables under it as one
+    /// transaction of inserts, so streaming continues from the slot's consistent point
+    fn initial_sync(&mut self) -> Result<()> {
+        let slot = create_slot_with_snapshot(
+            self.connection()?,
+            &self.config.slot_name,
+            self.config.slot_mode,
+        )?;
+        let snapshot_name = slot.snapshot_name.as_deref().unwrap_or_default();
+        let reader = SnapshotReader::open(&self.config.connection_string, snapshot_name)?;
+        let tables: Vec<SnapshotTable> = reader
+            .published_tables(&self.config.publication_names)?
+            .into_iter()
+            .filter(|table| {
+                let relation = &table.relation;
+                self.config.table_filter.is_empty()
+                    || self
+                        .config
+                        .table_filter
+                        .matches(&relation.namespace, &relation.relation_name)
+            })
+            .collect();
+
+        // The copied rows are reported at the consistent point, which is where streaming resumes
+        let lsn = slot.consistent_point;
+        self.created_slot = Some(slot);
+        let sync_time = Utc::now();
+        self.last_data_lsn = lsn;
+        for handler in self.sinks() {
+            handler.on_begin(0, lsn, sync_time)?;
+        }
+        if let Some(apply) = self.apply.as_mut() {
+            apply.begin()?;
+        }
+
+        let mut total_rows = 0;
+        let table_count = tables.len();
+        for table in tables {
+            total_rows += self.copy_table(&reader, table)?;
+        }
+        reader.finish()?;
+
+        if let Some(apply) = self.apply.as_mut() {
+            apply.commit()?;
+        }
+        for handler in self.sinks() {
+            handler.on_commit(lsn, lsn, sync_time)?;
+        }
+        self.state.update_applied_lsn(lsn);
+        self.persist_position()?;
+        info!(
+            "Initial sync copied {} rows from {} tables, streaming from {}",
+            total_rows, table_count, lsn
+        );
         Ok(())
     }
 
-    fn create_replication_slot(&self) -> Result<()> {
-        // https://www.postgresql.org/docs/14/protocol-replication.html
-        let create_slot_sql = format!(
-            "CREATE_REPLICATION_SLOT \"{}\" LOGICAL pgoutput NOEXPORT_SNAPSHOT;",
-            self.config.slot_name
-        );
+    /// Copy one table to the sinks, the CSV directory and the apply target, returning the number
+    /// of rows that passed the row filter
+    fn copy_table(&mut self, reader: &SnapshotReader, table: SnapshotTable) -> Result<u64> {
+        let SnapshotTable {
+            relation,
+            publications,
+            row_filter,
+        } = table;
+        let name = format!("{}.{}", relation.namespace, relation.relation_name);
+        let mut csv = self
+            .config
+            .init_sync
+            .as_ref()
+            .and_then(|init_sync| init_sync.csv_dir.as_deref())
+            .map(|directory| {
+                CsvTableWriter::create(directory, &relation, self.config.output.compression)
+            })
+            .transpose()?;
+        #[cfg(feature = "parquet")]
+        if let Some(parquet) = self.parquet.as_mut() {
+            parquet.register_relation(&relation)?;
+        }
+        if let Some(avro) = self.avro.as_mut() {
+            avro.register_relation(&relation)?;
+        }
+        #[cfg(feature = "s3")]
+        if let Some(s3) = self.s3.as_mut() {
+            s3.register_relation(&relation)?;
+        }
+        if let Some(apply) = self.apply.as_mut() {
+            apply.start_copy(&relation)?;
+        }
+
+        let lsn = Some(self.last_data_lsn.to_string());
+        let max_column_bytes = self.config.max_column_bytes;
+        let mut rows = 0;
+        reader.copy_table(&relation, row_filter.as_deref(), |line| {
+            let tuple_data = parse_copy_row(line, max_column_bytes);
+            if !row_matches(&self.config.row_filter, &relation, &tuple_data) {
+                return Ok(());
+            }
+            // The target receives the row exactly as the source copied it
+            if let Some(apply) = self.apply.as_mut() {
+                apply.copy_row(line)?;
+            }
 
-        info!("Creating replication slot: {}", self.config.slot_name);
-        let result = self.connection.exec(&create_slot_sql)?;
+            let record = ChangeRecord {
+                schema_version: self.config.event_schema.field(),
+                id: None,
+                source: self.config.source.clone(),
+                op: ChangeKind::Insert,
+                schema: relation.namespace.clone(),
+                table: relation.relation_name.clone(),
+                lsn: lsn.clone(),
+                xid: None,
+                publications: publications.clone(),
+                transaction: None,
+                old: None,
+                new: Some(masked_row(&relation, &tuple_data, &self.config.column_mask)),
+            };
+            #[cfg(feature = "alloc-stats")]
+            let _scope = allocstats::enter(Component::Sinks);
+            if let (Some(csv), Some(row)) = (csv.as_mut(), record.new.as_ref()) {
+                csv.write_row(row)?;
+            }
+            for handler in self.sinks() {
+                handler.on_change(&record)?;
+            }
+            self.summary
+                .record_truncated_values(count_truncated(&tuple_data));
+            rows += 1;
+            Ok(())
+        })?;
 
-        if !result.is_ok() {
-            warn!("Replication slot creation may have failed, but continuing");
-        } else {
-            info!("Replication slot created successfully");
+        if let Some(apply) = self.apply.as_mut() {
+            apply.finish_copy()?;
+        }
+        match csv {
+            Some(csv) => info!(
+                "Copied {} rows of {} to {}",
+                rows,
+                name,
+                csv.finish()?.display()
+            ),
+            None => info!("Copied {} rows of {}", rows, name),
+        }
+        // Streamed changes of the table are decoded with the Relation message sent before them
+        self.state.add_relation(relation);
+        Ok(rows)
+    }
+
+    /// Drop the slot after a graceful stop when configured to, so test runs leave no slot behind
+    fn drop_slot_on_exit(&mut self) -> Result<()> {
+        // The server drops temporary slots together with the session
+        if !self.config.drop_slot_on_exit || self.config.slot_mode == SlotMode::Temporary {
+            return Ok(());
         }
 
+        // The slot stays active until the streaming connection is closed
+        self.connection = None;
+        let connection = PGConnection::connect(&self.config.connection_string)?;
+        drop_slot(&connection, &self.config.slot_name)?;
+        info!("Dropped replication slot {}", self.config.slot_name);
         Ok(())
     }
 
-    async fn start_replication(&mut self) -> Result<()> {
+    /// Open a new connection and restart streaming after the last processed transaction
+    fn reconnect(&mut self) -> Result<()> {
+        // Close the broken connection before opening its replacement
+        self.connection = None;
+        let mut connection = PGConnection::connect(&self.config.connection_string)?;
+        connection.set_buffer_pool(self.buffer_pool.clone());
+        self.connection = Some(connection);
+        self.health.set_connected(true);
+        info!("Reconnected to database server");
+        self.identify_system()?;
+
+        // A temporary slot is dropped together with the session that created it, so a new one
+        // is created and streaming restarts at its consistent point
+        if self.config.slot_mode == SlotMode::Temporary {
+            let resume_lsn = self.state.applied_lsn.max(self.slot_start());
+            let slot_name = &self.config.slot_name;
+            self.created_slot = prepare_slot(self.connection()?, slot_name, SlotMode::Temporary)?;
+            let start = self.slot_start();
+            warn!(
+                "Temporary slot {} was dropped with the lost connection and created again at {}: changes committed between {} and {} ({} bytes of WAL) are not streamed",
+                self.config.slot_name,
+                start,
+                resume_lsn,
+                start,
+                start.bytes_since(resume_lsn)
+            );
+        }
+
+        // The interrupted transaction is sent again in full
+        self.state.current_xid = None;
+        self.state.skipping_transaction = false;
+        self.state.foreign_origin_xids.clear();
+        self.state.stop_streaming();
+        if let Some(commit_log) = self.commit_log.as_mut() {
+            commit_log.clear_open();
+        }
+        self.transaction_spans.clear();
+        if let Some(assembler) = self.state.assembler.as_mut() {
+            assembler.clear();
+        }
+        if let Some(validator) = self.validator.as_mut() {
+            validator.reset();
+        }
+        // A walsender cut off by a receiver timeout holds the slot until the server's
+        // wal_sender_timeout ends it, so the attempt is retried like a lost connection
+        self.start_replication().map_err(|e| match e {
+            ReplicationError::Protocol { .. } if e.to_string().contains("is active for PID") => {
+                ReplicationError::connection(e.to_string())
+            }
+            e => e,
+        })
+    }
+
+    fn start_replication(&mut self) -> Result<()> {
         /*
         proto_version
             Protocol version. Currently versions 1, 2, 3, and 4 are supported. A valid version is required.
@@ -92,55 +749,467 @@ impl ReplicationServer {
             Version 4 is supported only for server version 16 and above, and it allows streams of large in-progress transactions to be applied in parallel.
         https://www.postgresql.org/docs/current/protocol-logical-replication.html#PROTOCOL-LOGICAL-REPLICATION-PARAMS
         */
+        if self.server_info.is_none() {
+            self.identify_system()?;
+        }
+        if let Some(info) = &self.server_info {
+            info.check_options(&self.config)?;
+        }
+        let start_lsn = match self.config.start_lsn {
+            // An explicit start position only applies until a transaction has been processed
+            Some(start_lsn) if !self.state.applied_lsn.is_valid() => {
+                self.check_start_lsn(start_lsn)?
+            }
+            // After a reconnect, resume after the last transaction that was fully processed
+            _ => std::cmp::max(self.load_checkpoint()?, self.state.applied_lsn),
+        };
+        // A slot has nothing to stream before the point it was created at
+        let start_lsn = start_lsn.max(self.slot_start());
+        if let Some(start_time) = self.config.start_time {
+            info!(
+                "Skipping transactions committed before {}",
+                start_time.to_rfc3339()
+            );
+        }
+        let mut options = vec![
+            format!("proto_version '{}'", self.config.protocol_version),
+            format!("streaming '{}'", self.streaming_option()),
+            format!(
+                "publication_names {}",
+                quote_command_literal(&publication_names_option(&self.config.publication_names))
+            ),
+        ];
+        // The binary option needs server version 14 or later, so only send it when asked to
+        if self.config.binary {
+            options.push("binary 'true'".to_string());
+        }
+        // Older servers reject the origin option, changes from other origins are then only
+        // ignored here on their Origin message
+        match &self.server_info {
+            Some(info) if info.supports_origin_filter() => {
+                options.push(format!("origin '{}'", self.config.origin.as_str()));
+            }
+            _ if self.config.origin == OriginFilter::None => info!(
+                "The server does not filter by origin, changes replicated from other origins are ignored as they arrive"
+            ),
+            _ => {}
+        }
         let start_replication_sql = format!(
-            "START_REPLICATION SLOT \"{}\" LOGICAL 0/0 (proto_version '2', streaming 'on', publication_names '\"{}\"');",
-            self.config.slot_name,
-            self.config.publication_name
+            "START_REPLICATION SLOT {} LOGICAL {} ({});",
+            quote_ident(&self.config.slot_name),
+            start_lsn,
+            options.join(", ")
         );
 
         info!(
-            "Starting replication with publication: {}, executing SQL: {}",
-            self.config.publication_name, start_replication_sql
+            "Starting replication with publications: {}, executing SQL: {}",
+            self.config.publication_names.join(", "),
+            start_replication_sql
         );
-        let _ = self.connection.exec(&start_replication_sql)?;
+        let result = self.connection()?.exec(&start_replication_sql)?;
+        if !result.is_copy_both() {
+            return Err(ReplicationError::protocol(format!(
+                "START_REPLICATION failed: {}",
+                result.error_message()
+            )));
+        }
+        if let Some(connection) = self.connection.as_mut() {
+            connection.enable_async()?;
+        }
+        self.health.set_streaming(true);
 
         info!("Started receiving data from database server");
-        self.replication_loop().await?;
         Ok(())
     }
 
+    /// Value of the `streaming` option sent with START_REPLICATION
+    fn streaming_option(&self) -> &'static str {
+        match (self.config.streaming, self.config.parallel_streaming) {
+            (false, _) => "off",
+            (true, false) => "on",
+            (true, true) => "parallel",
+        }
+    }
+
+    /// Warn when the requested start position is behind the slot, which the server ignores
+    fn check_start_lsn(&self, start_lsn: Lsn) -> Result<Lsn> {
+        let slot = find_slot(self.connection()?, &self.config.slot_name)?;
+        match slot.and_then(|slot| slot.confirmed_flush_lsn) {
+            Some(confirmed) if start_lsn < confirmed => warn!(
+                "Start LSN {} is before the slot's confirmed position {}, the server starts streaming at {}",
+                start_lsn, confirmed, confirmed
+            ),
+            _ => info!("Starting from requested LSN {}", start_lsn),
+        }
+        Ok(start_lsn)
+    }
+
     async fn replication_loop(&mut self) -> Result<()> {
+        if self.config.receive_queue_capacity == 0 {
+            return self.sequential_loop().await;
+        }
+
+        let connection = self
+            .connection
+            .take()
+            .ok_or_else(|| ReplicationError::connection("Not connected to a server"))?;
+        let shared = Arc::new(PipelineShared::new(self.config.receive_queue_capacity));
+        let (sender, mut queue) = mpsc::channel(self.config.receive_queue_capacity);
+        let status_interval = Duration::from_secs(self.config.status_interval_secs);
+        let receiver_timeout = self.config.receiver_timeout;
+        let receiver = {
+            let shared = shared.clone();
+            tokio::task::spawn_blocking(move || {
+                receive(
+                    connection,
+                    sender,
+                    shared,
+                    status_interval,
+                    receiver_timeout,
+                )
+            })
+        };
+        self.pipeline = Some(shared.clone());
+        debug!(
+            "Receiving into a queue of {} messages",
+            self.config.receive_queue_capacity
+        );
+
+        let processed = self.process_queue(&mut queue).await;
+
+        // Take the connection back for the final status update or the reconnect
+        shared.stop();
+        drop(queue);
+        self.pipeline = None;
+        let (connection, received) = receiver.await?;
+        self.connection = Some(connection);
+        shared.log_summary();
+
+        match processed? {
+            true => self.finish_stream(),
+            false => match received {
+                Err(e) => Err(e),
+                Ok(()) => Err(ReplicationError::protocol(
+                    "Receive stage stopped without an error",
+                )),
+            },
+        }
+    }
+
+    /// Processing stage: parse and emit queued messages until a stop condition or shutdown,
+    /// returning true, or until the receive stage stops, returning false
+    async fn process_queue(&mut self, queue: &mut mpsc::Receiver<PooledBuffer>) -> Result<bool> {
+        loop {
+            if let Some(reason) = self.stop_reason() {
+                info!("Stop condition met: {}", reason);
+                return Ok(true);
+            }
+            if self.shutdown.load(Ordering::Relaxed) {
+                info!("Shutdown requested, stopping stream");
+                return Ok(true);
+            }
+            self.apply_reload()?;
+
+            #[cfg(feature = "s3")]
+            if let Some(s3) = self.s3.as_mut() {
+                s3.maybe_flush()?;
+            }
+            self.fanout.check()?;
+            self.check_and_send_feedback()?;
+            self.progress.maybe_report(&self.health);
+            self.stats.maybe_report(&self.health);
+            self.delays.maybe_report(&self.health);
+            self.subscription.maybe_report();
+            self.sampler.maybe_publish(&self.health);
+            let paused = self.service_stream_control()?;
+            // Nothing is expected from the server while paused
+            if let Some(alerts) = self.alerts.as_mut().filter(|_| !paused) {
+                let bytes_behind = self.stats.bytes_behind_server();
+                alerts.maybe_check(Some(bytes_behind), self.health.since_last_message())?;
+            }
+            if let Some(control) = self.control.as_mut() {
+                control.maybe_report_slot();
+                let used = self
+                    .wal_keep
+                    .as_mut()
+                    .and_then(|wal_keep| wal_keep.maybe_check(control));
+                if let Some((alerts, used)) = self.alerts.as_mut().zip(used) {
+                    alerts.record_wal_keep_used(used);
+                }
+            }
+            // A paused stream reads nothing, so the positions it reports hold still
+            if paused || self.events_backlogged() {
+                tokio::time::sleep(IDLE_WAKEUP_INTERVAL).await;
+                continue;
+            }
+            // The receive stage keeps answering the server while processing is held back
+            if let Some(wait) = self.throttle.as_mut().and_then(Throttle::wait) {
+                tokio::time::sleep(wait.min(IDLE_WAKEUP_INTERVAL)).await;
+                continue;
+            }
+
+            match tokio::time::timeout(IDLE_WAKEUP_INTERVAL, queue.recv()).await {
+                // Nothing queued: service the timers above again
+                Err(_) => continue,
+                Ok(None) => return Ok(false),
+                Ok(Some(data)) => {
+                    if let Some(pipeline) = &self.pipeline {
+                        pipeline.dequeued();
+                    }
+                    if let Some(capture) = self.capture.as_mut() {
+                        capture.write(&data)?;
+                    }
+                    self.received_at = data.received_at();
+                    self.process_throttled(&data)?;
+                }
+            }
+        }
+    }
+
+    /// Receive and process messages in turn on this task
+    async fn sequential_loop(&mut self) -> Result<()> {
+        let mut watchdog = ReceiveWatchdog::new(self.config.receiver_timeout);
         loop {
+            if self.check_stop_conditions()? {
+                return Ok(());
+            }
+            if self.shutdown.load(Ordering::Relaxed) {
+                info!("Shutdown requested, stopping stream");
+                self.finish_stream()?;
+                return Ok(());
+            }
+            self.apply_reload()?;
+
+            #[cfg(feature = "s3")]
+            if let Some(s3) = self.s3.as_mut() {
+                s3.maybe_flush()?;
+            }
+            self.fanout.check()?;
             self.check_and_send_feedback()?;
+            self.progress.maybe_report(&self.health);
+            self.stats.maybe_report(&self.health);
+            self.delays.maybe_report(&self.health);
+            self.subscription.maybe_report();
+            self.sampler.maybe_publish(&self.health);
+            let paused = self.service_stream_control()?;
+            // Nothing is expected from the server while paused
+            if let Some(alerts) = self.alerts.as_mut().filter(|_| !paused) {
+                let bytes_behind = self.stats.bytes_behind_server();
+                alerts.maybe_check(Some(bytes_behind), self.health.since_last_message())?;
+            }
+            if let Some(control) = self.control.as_mut() {
+                control.maybe_report_slot();
+                let used = self
+                    .wal_keep
+                    .as_mut()
+                    .and_then(|wal_keep| wal_keep.maybe_check(control));
+                if let Some((alerts, used)) = self.alerts.as_mut().zip(used) {
+                    alerts.record_wal_keep_used(used);
+                }
+            }
+            // A paused stream reads nothing, so the positions it reports hold still
+            if paused || self.events_backlogged() {
+                watchdog.received();
+                tokio::time::sleep(IDLE_WAKEUP_INTERVAL).await;
+                continue;
+            }
+            if let Some(wait) = self.throttle.as_mut().and_then(Throttle::wait) {
+                // Keepalives are not read meanwhile, so status updates keep the server from
+                // timing out even with on-request feedback
+                let status_interval = Duration::from_secs(self.config.status_interval_secs);
+                if self.state.last_feedback_time.elapsed() >= status_interval {
+                    self.send_feedback()?;
+                }
+                watchdog.received();
+                tokio::time::sleep(wait.min(IDLE_WAKEUP_INTERVAL)).await;
+                continue;
+            }
 
-            match self.connection.get_copy_data(0)? {
+            let connection = self.connection()?;
+            // Wait for the next message, waking up periodically so feedback and stop
+            // conditions are still serviced while the server is idle
+            match connection.next_copy_data(IDLE_WAKEUP_INTERVAL).await? {
                 None => {
-                    info!("No data received, continuing");
-                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    if watchdog.check()? {
+                        self.request_reply()?;
+                    }
                     continue;
                 }
                 Some(data) => {
-                    if data.is_empty() {
-                        continue;
-                    }
-                    
-                    // please refer to https://www.postgresql.org/docs/current/protocol-replication.html#PROTOCOL-REPLICATION-XLOGDATA
-                    match data[0] as char {
-                        'k' => {
-                            self.process_keepalive_message(&data)?;
-                        }
-                        'w' => {
-                            self.process_wal_message(&data)?;
-                        }
-                        _ => {
-                            warn!("Received unknown message type: {}", data[0] as char);
-                        }
+                    watchdog.received();
+                    self.received_at = Utc::now();
+                    if let Some(capture) = self.capture.as_mut() {
+                        capture.write(&data)?;
                     }
+                    self.process_throttled(&data)?;
                 }
             }
         }
     }
 
+    /// Process a received message, spending its events and bytes from the rate limit
+    fn process_throttled(&mut self, data: &[u8]) -> Result<()> {
+        let events = self.events_processed;
+        self.process_copy_data(data)?;
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.consume(self.events_processed - events, data.len());
+        }
+        Ok(())
+    }
+
+    /// Decode a capture file written with `record`, as if it were arriving from the server
+    pub fn replay(&mut self, path: &Path) -> Result<()> {
+        let mut reader = CaptureReader::open(path)?;
+        let mut messages: u64 = 0;
+        let mut first_received_at = None;
+
+        while let Some(captured) = reader.next_message()? {
+            if self.check_stop_conditions()? {
+                break;
+            }
+
+            debug!("Replaying message recorded at {}", captured.received_at);
+            first_received_at.get_or_insert(captured.received_at);
+            self.received_at = captured.received_at;
+            self.process_copy_data(&captured.data)?;
+            messages += 1;
+        }
+
+        match first_received_at {
+            Some(started) => info!(
+                "Replayed {} messages recorded from {}, {} change events",
+                messages, started, self.events_processed
+            ),
+            None => info!("Capture file contains no messages"),
+        }
+        self.close_file_sinks()?;
+        if let Some(validator) = &self.validator {
+            validator.log_summary();
+        }
+        if let Some(toast_cache) = &self.toast_cache {
+            toast_cache.log_summary();
+        }
+        if let Some(assembler) = &self.state.assembler {
+            assembler.log_summary();
+        }
+        if let Some(commit_log) = &self.commit_log {
+            commit_log.log_summary();
+        }
+        if let Some(limiter) = &self.transaction_limiter {
+            limiter.log_summary();
+        }
+        if let Some(throttle) = &self.throttle {
+            throttle.log_summary();
+        }
+        self.sampler.log_summary();
+        self.summary.log_summary();
+        Ok(())
+    }
+
+    /// The stop condition that is met, if any
+    fn stop_reason(&self) -> Option<StopReason> {
+        self.config
+            .stop_conditions
+            .check(self.events_processed, self.last_data_lsn)
+    }
+
+    /// Stop if a configured stop condition is met, sending final feedback first
+    fn check_stop_conditions(&mut self) -> Result<bool> {
+        let Some(reason) = self.stop_reason() else {
+            return Ok(false);
+        };
+
+        info!("Stop condition met: {}", reason);
+        self.finish_stream()?;
+        Ok(true)
+    }
+
+    /// Send final feedback and persist everything before the stream stops
+    fn finish_stream(&mut self) -> Result<()> {
+        self.close_file_sinks()?;
+        self.send_final_feedback()?;
+        if let Some(capture) = self.capture.as_mut() {
+            capture.flush()?;
+            info!(
+                "Recorded {} messages to {}",
+                capture.messages(),
+                capture.path().display()
+            );
+        }
+        if let Some(watch) = &self.watch {
+            watch.log_summary();
+        }
+        if let Some(validator) = &self.validator {
+            validator.log_summary();
+        }
+        if let Some(toast_cache) = &self.toast_cache {
+            toast_cache.log_summary();
+        }
+        if let Some(assembler) = &self.state.assembler {
+            assembler.log_summary();
+        }
+        if let Some(commit_log) = &self.commit_log {
+            commit_log.log_summary();
+        }
+        if let Some(limiter) = &self.transaction_limiter {
+            limiter.log_summary();
+        }
+        if let Some(throttle) = &self.throttle {
+            throttle.log_summary();
+        }
+        self.sampler.log_summary();
+        self.summary.log_summary();
+        Ok(())
+    }
+
+    /// Write the rows still buffered and close the open Parquet files, flush the Avro files and
+    /// deliver what the `[[sink]]` sinks have queued
+    fn close_file_sinks(&mut self) -> Result<()> {
+        self.fanout.close()?;
+        #[cfg(feature = "parquet")]
+        if let Some(parquet) = self.parquet.as_mut() {
+            parquet.close()?;
+            let (files, rows) = parquet.files_written();
+            info!("Wrote {} rows to {} Parquet files", rows, files);
+        }
+        if let Some(avro) = self.avro.as_mut() {
+            avro.close()?;
+            info!("Wrote {} Avro messages", avro.messages_written());
+        }
+        #[cfg(feature = "s3")]
+        if let Some(s3) = self.s3.as_mut() {
+            s3.close()?;
+            let (objects, bytes) = s3.objects_uploaded();
+            info!("Uploaded {} objects ({} bytes) to S3", objects, bytes);
+        }
+        Ok(())
+    }
+
+    /// Dispatch one CopyData message from the server
+    fn process_copy_data(&mut self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.health.record_message();
+        if self.config.dump_raw {
+            hexdump::trace_message(data);
+        }
+
+        // please refer to https://www.postgresql.org/docs/current/protocol-replication.html#PROTOCOL-REPLICATION-XLOGDATA
+        match data[0] as char {
+            'k' => {
+                self.process_keepalive_message(data)?;
+            }
+            'w' => {
+                self.process_wal_message(data)?;
+            }
+            _ => {
+                warn!("Received unknown message type: {}", data[0] as char);
+            }
+        }
+        Ok(())
+    }
+
     fn process_keepalive_message(&mut self, data: &[u8]) -> Result<()> {
         if data.len() < 18 {
             // 'k' + 8 bytes LSN + 8 bytes timestamp + 1 byte reply flag
@@ -149,18 +1218,39 @@ impl ReplicationServer {
             ));
         }
 
-        debug!("Processing keepalive message");
-
         let mut reader = BufferReader::new(data);
         let _msg_type = reader.skip_message_type()?; // Skip 'k'
-        let log_pos = reader.read_u64()?;
-        let _timestamp = reader.read_i64()?; // Skip timestamp
-        let reply_requested = if reader.remaining() > 0 { reader.read_u8()? } else { 0 };
+        let log_pos = reader.read_lsn()?;
+        let send_time = reader.read_i64()?;
+        let reply_requested = if reader.remaining() > 0 {
+            reader.read_u8()?
+        } else {
+            0
+        };
+        let sent_at = pg_timestamp_to_datetime(send_time);
+        debug!(
+            "Keepalive: wal_end {}, sent at {}, reply requested: {}",
+            log_pos,
+            format_timestamp_from_pg(send_time),
+            reply_requested != 0
+        );
 
         self.state.update_lsn(log_pos);
+        // With no transaction open everything up to the server's position has been processed,
+        // which lets the slot advance while no published table changes
+        if self.transaction_xid().is_none() {
+            self.state.update_applied_lsn(log_pos);
+        }
+        self.progress.observe_server_wal_end(log_pos);
+        self.stats.observe_server(log_pos, sent_at);
+        self.subscription.observe_keepalive(log_pos, sent_at);
+        if let Some(sent_at) = sent_at {
+            self.clock_skew.observe(sent_at, self.received_at);
+        }
 
         // Only send feedback when server explicitly requests a reply
         if reply_requested != 0 {
+            self.persist_position()?;
             self.send_feedback()?;
         }
         Ok(())
@@ -178,13 +1268,21 @@ impl ReplicationServer {
         let _msg_type = reader.skip_message_type()?; // Skip 'w'
 
         // Parse WAL message header
-        let data_start = reader.read_u64()?;
-        let _wal_end = reader.read_u64()?;
-        let _send_time = reader.read_i64()?;
+        let data_start = reader.read_lsn()?;
+        let wal_end = reader.read_lsn()?;
+        let send_time = reader.read_i64()?;
 
-        if data_start > 0 {
+        if data_start.is_valid() {
             self.state.update_lsn(data_start);
+            self.progress.observe_received(data_start);
+            self.stats.observe_received(data_start);
+            self.summary.record_position(data_start);
+            self.last_data_lsn = data_start;
         }
+        let sent_at = pg_timestamp_to_datetime(send_time);
+        self.progress.observe_server_wal_end(wal_end);
+        self.stats.observe_server(wal_end, sent_at);
+        self.subscription.observe_message(wal_end, sent_at);
 
         if reader.remaining() == 0 {
             return Err(crate::errors::ReplicationError::protocol(
@@ -194,8 +1292,25 @@ impl ReplicationServer {
 
         // Parse the actual logical replication message
         let message_data = &data[reader.position()..];
-        match MessageParser::parse_wal_message(message_data, self.state.in_streaming_txn) {
+        let in_stream = self.state.in_streaming_txn;
+        let parsed = Self::parse_message(message_data, in_stream);
+        if let (Ok(message), Some(validator)) = (&parsed, self.validator.as_mut()) {
+            validator.check(message, data_start)?;
+        }
+        if let Ok(message) = &parsed {
+            if !self.within_transaction_limits(message, message_data.len())? {
+                return Ok(());
+            }
+        }
+        match parsed {
+            Ok(message) if self.state.assembler.is_some() => {
+                let message = self.copy_message(message);
+                self.assemble_message(message, message_data, in_stream)?;
+            }
+            // Changes to filtered-out tables are dropped before their values are copied
+            Ok(message) if self.is_filtered_out(&message) => {}
             Ok(message) => {
+                let message = self.copy_message(message);
                 self.process_replication_message(message)?;
             }
             Err(e) => {
@@ -209,27 +1324,429 @@ impl ReplicationServer {
         Ok(())
     }
 
-    fn process_replication_message(&mut self, message: ReplicationMessage) -> Result<()> {
+    /// Count a change against the limits of its transaction, returning whether it is processed
+    ///
+    /// Runs before a message is buffered, so the changes dropped from a large transaction never
+    /// reach the assembler. Transactions are forgotten when they commit or abort.
+    fn within_transaction_limits(
+        &mut self,
+        message: &ReplicationMessageRef<'_>,
+        bytes: usize,
+    ) -> Result<bool> {
+        let Some(limiter) = self.transaction_limiter.as_mut() else {
+            return Ok(true);
+        };
         match message {
-            ReplicationMessage::Begin { xid, .. } => {
-                info!("BEGIN: Xid {}", xid);
+            ReplicationMessageRef::Insert { .. }
+            | ReplicationMessageRef::Update { .. }
+            | ReplicationMessageRef::Delete { .. } => {
+                if let Some(xid) = self.state.streaming_xid.or(self.state.current_xid) {
+                    return limiter.admit(xid, bytes);
+                }
             }
+            ReplicationMessageRef::Commit { .. } => {
+                if let Some(xid) = self.state.current_xid {
+                    limiter.finish(xid);
+                }
+            }
+            ReplicationMessageRef::StreamCommit { xid, .. }
+            | ReplicationMessageRef::StreamPrepare { xid, .. } => limiter.finish(*xid),
+            ReplicationMessageRef::StreamAbort {
+                xid,
+                subtransaction_xid,
+                ..
+            } if xid == subtransaction_xid => limiter.finish(*xid),
+            _ => {}
+        }
+        Ok(true)
+    }
 
-            ReplicationMessage::Commit { 
-                flags,
+    /// Buffer a message in the transaction assembler, emitting whole transactions on commit
+    fn assemble_message(
+        &mut self,
+        message: ReplicationMessage,
+        data: &[u8],
+        in_stream: bool,
+    ) -> Result<()> {
+        let (xid, change_xid) = match &message {
+            ReplicationMessage::Begin { xid, .. } => {
+                self.state.current_xid = Some(*xid);
+                (*xid, None)
+            }
+            ReplicationMessage::Commit { .. } => {
+                if let Some(xid) = self.state.current_xid {
+                    self.emit_transaction(xid)?;
+                }
+                return self.process_replication_message(message);
+            }
+            ReplicationMessage::StreamCommit {
+                xid,
                 commit_lsn,
-                end_lsn,
                 timestamp,
-             } => {
-                info!("COMMIT: flags: {}, lsn: {}, end_lsn: {}, commit_time: {}", flags, commit_lsn, end_lsn, format_timestamp_from_pg(timestamp));
+                ..
+            } => {
+                // The buffered changes are emitted knowing their commit
+                if let Some(envelopes) = self.envelopes.as_mut() {
+                    envelopes.commit_streamed(*xid, *commit_lsn, pg_commit_time(*timestamp)?);
+                }
+                self.emit_transaction(*xid)?;
+                return self.process_replication_message(message);
             }
-
-            ReplicationMessage::Relation { relation } => {
-                // info!(
+            ReplicationMessage::StreamAbort {
+                xid,
+                subtransaction_xid,
+                ..
+            } => {
+                if let Some(assembler) = self.state.assembler.as_mut() {
+                    assembler.abort(*xid, *subtransaction_xid);
+                }
+                return self.process_replication_message(message);
+            }
+            ReplicationMessage::StreamPrepare { xid, .. } => {
+                self.emit_transaction(*xid)?;
+                return self.process_replication_message(message);
+            }
+            ReplicationMessage::StreamStop => {
+                // Parallel apply emits each block as it ends, aborts can no longer retract it
+                let parallel = self.state.assembler.as_ref().is_some_and(|a| a.parallel());
+                if let (true, Some(xid)) = (parallel, self.state.streaming_xid) {
+                    self.emit_transaction(xid)?;
+                }
+                return self.process_replication_message(message);
+            }
+            ReplicationMessage::StreamStart { .. } => {
+                return self.process_replication_message(message);
+            }
+            ReplicationMessage::Insert { xid, .. }
+            | ReplicationMessage::Update { xid, .. }
+            | ReplicationMessage::Delete { xid, .. }
+            | ReplicationMessage::Truncate { xid, .. } => {
+                match self.state.streaming_xid.or(self.state.current_xid) {
+                    Some(top_xid) => (top_xid, *xid),
+                    None => return self.process_replication_message(message),
+                }
+            }
+            _ => match self.state.streaming_xid.or(self.state.current_xid) {
+                Some(top_xid) => (top_xid, None),
+                None => return self.process_replication_message(message),
+            },
+        };
+
+        #[cfg(feature = "alloc-stats")]
+        let _scope = allocstats::enter(Component::Assembler);
+        let buffered = BufferedMessage {
+            lsn: self.last_data_lsn,
+            in_stream,
+            xid: change_xid,
+            data: data.to_vec(),
+        };
+        match self.state.assembler.as_mut() {
+            Some(assembler) => assembler.push(xid, buffered),
+            None => Ok(()),
+        }
+    }
+
+    /// Process the buffered messages of a committed transaction in the order they arrived
+    fn emit_transaction(&mut self, xid: Xid) -> Result<()> {
+        let Some(assembler) = self.state.assembler.as_mut() else {
+            return Ok(());
+        };
+        let mut transaction = {
+            #[cfg(feature = "alloc-stats")]
+            let _scope = allocstats::enter(Component::Assembler);
+            assembler.take(xid)?
+        };
+
+        // Records carry the position of the change, the commit is processed at its own
+        let commit_data_lsn = self.last_data_lsn;
+        self.replaying_xid = Some(xid);
+        let result = self.process_buffered(&mut transaction);
+        self.replaying_xid = None;
+        self.last_data_lsn = commit_data_lsn;
+        result
+    }
+
+    /// Process the messages left in a taken transaction
+    fn process_buffered(&mut self, transaction: &mut CommittedTransaction) -> Result<()> {
+        loop {
+            let next = {
+                #[cfg(feature = "alloc-stats")]
+                let _scope = allocstats::enter(Component::Assembler);
+                transaction.next_message()?
+            };
+            let Some(buffered) = next else {
+                return Ok(());
+            };
+            self.last_data_lsn = buffered.lsn;
+            let message = Self::parse_message(&buffered.data, buffered.in_stream)?;
+            let message = self.copy_message(message);
+            self.process_replication_message(message)?;
+        }
+    }
+
+    /// Hash the changes of each transaction into the commit log, recording its commit or, when
+    /// the transaction was delivered before, verifying it
+    fn track_commit(&mut self, message: &ReplicationMessage) -> Result<()> {
+        let current_xid = self.transaction_xid();
+        let Some(commit_log) = self.commit_log.as_mut() else {
+            return Ok(());
+        };
+        match message {
+            ReplicationMessage::Begin { xid, .. }
+            | ReplicationMessage::StreamStart {
+                xid,
+                first_segment: true,
+                ..
+            } => commit_log.begin(*xid),
+            ReplicationMessage::Commit {
+                commit_lsn,
+                end_lsn,
+                ..
+            } => {
+                if let Some(xid) = current_xid {
+                    commit_log.commit(xid, *commit_lsn, *end_lsn)?;
+                }
+            }
+            ReplicationMessage::StreamCommit {
+                xid,
+                commit_lsn,
+                end_lsn,
+                ..
+            } => commit_log.commit(*xid, *commit_lsn, *end_lsn)?,
+            ReplicationMessage::StreamAbort {
+                xid,
+                subtransaction_xid,
+                ..
+            } if subtransaction_xid != xid => commit_log.abort_subtransaction(*xid),
+            ReplicationMessage::StreamAbort { xid, .. } => commit_log.abort(*xid),
+            ReplicationMessage::Insert { .. }
+            | ReplicationMessage::Update { .. }
+            | ReplicationMessage::Delete { .. }
+            | ReplicationMessage::Truncate { .. } => {
+                if let Some(xid) = current_xid {
+                    commit_log.record_change(xid, message);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Decode a message in place, borrowing its values from `data`
+    fn parse_message(data: &[u8], in_stream: bool) -> Result<ReplicationMessageRef<'_>> {
+        #[cfg(feature = "alloc-stats")]
+        let _scope = allocstats::enter(Component::Parser);
+        MessageParser::parse_wal_message_ref(data, in_stream)
+    }
+
+    /// Copy a parsed message out of its buffer, truncating column values to the configured size
+    fn copy_message(&mut self, message: ReplicationMessageRef) -> ReplicationMessage {
+        #[cfg(feature = "alloc-stats")]
+        let _scope = allocstats::enter(Component::Parser);
+        let Some(max) = self.config.max_column_bytes else {
+            return message.into_owned();
+        };
+        let message = message.into_owned_capped(Some(max));
+        let truncated = match &message {
+            ReplicationMessage::Insert { tuple_data, .. }
+            | ReplicationMessage::Delete { tuple_data, .. } => count_truncated(tuple_data),
+            ReplicationMessage::Update {
+                old_tuple_data,
+                new_tuple_data,
+                ..
+            } => {
+                old_tuple_data.as_ref().map_or(0, count_truncated) + count_truncated(new_tuple_data)
+            }
+            _ => 0,
+        };
+        if truncated > 0 {
+            debug!("Truncated {} column values to {} bytes", truncated, max);
+            self.summary.record_truncated_values(truncated);
+        }
+        message
+    }
+
+    fn process_replication_message(&mut self, message: ReplicationMessage) -> Result<()> {
+        if self.skip_before_start_time(&message) || self.skip_foreign_origin(&message) {
+            return Ok(());
+        }
+
+        // Drop changes for filtered-out tables before any formatting happens
+        let Some(mut message) = self.apply_table_filter(message) else {
+            return Ok(());
+        };
+        // Hashed as received, before TOAST values are filled in from the cache
+        self.track_commit(&message)?;
+        self.backfill_toast(&mut message);
+        if !self.row_selected(&message) {
+            return Ok(());
+        }
+
+        // Begin and the first StreamStart open the transaction's span, every message of the
+        // transaction is processed within it so its log lines are events of the span
+        let xid = match &message {
+            ReplicationMessage::Begin { xid, final_lsn, .. } => {
+                self.transaction_spans.begin(*xid, Some(*final_lsn));
+                Some(*xid)
+            }
+            ReplicationMessage::StreamStart { xid, .. } => {
+                self.transaction_spans.start_segment(*xid);
+                Some(*xid)
+            }
+            ReplicationMessage::StreamCommit { xid, .. }
+            | ReplicationMessage::StreamAbort { xid, .. }
+            | ReplicationMessage::StreamPrepare { xid, .. } => Some(*xid),
+            _ => self.transaction_xid(),
+        };
+        let _span = self.transaction_spans.span(xid).map(Span::entered);
+
+        if matches!(
+            message,
+            ReplicationMessage::Insert { .. }
+                | ReplicationMessage::Update { .. }
+                | ReplicationMessage::Delete { .. }
+                | ReplicationMessage::Truncate { .. }
+        ) {
+            self.events_processed += 1;
+            self.stats.record_event();
+        }
+        self.check_tuple_columns(&message);
+        self.record_counts(&message);
+        // Changes left out by sampling are counted above but not displayed or emitted; the
+        // target still receives them, or it would no longer match the source
+        let selected = self.sample_selected(&message);
+        if selected {
+            self.dispatch_to_handlers(&message)?;
+        }
+        self.apply_change(&message)?;
+        if !selected {
+            return Ok(());
+        }
+
+        match message {
+            ReplicationMessage::Begin { xid, .. } => {
+                info!("BEGIN: Xid {}", xid);
+                self.state.current_xid = Some(xid);
+            }
+
+            ReplicationMessage::Commit {
+                flags,
+                commit_lsn,
+                end_lsn,
+                timestamp,
+            } => {
+                if let Some(xid) = self.state.current_xid {
+                    self.transaction_spans
+                        .end(xid, "committed", Some(commit_lsn), Some(end_lsn));
+                }
+                info!(
+                    "COMMIT: flags: {}, lsn: {}, end_lsn: {}, commit_time: {}",
+                    flags,
+                    commit_lsn,
+                    end_lsn,
+                    format_timestamp_from_pg(timestamp)
+                );
+                self.state.current_xid = None;
+                self.state.update_applied_lsn(end_lsn);
+                self.stats.observe_applied(end_lsn);
+            }
+
+            ReplicationMessage::Relation { mut relation } => {
+                // info!(
                 //     "Received relation info for {}.{}",
                 //     relation.namespace, relation.relation_name
                 // );
-                self.state.add_relation(relation);
+                if let Some(control) = self.control.as_mut() {
+                    control.note_relation(&relation.namespace, &relation.relation_name);
+                    // Types announced by no Type message in this session are named from the catalog
+                    for column in &relation.columns {
+                        if self.state.type_name(column.column_type).is_none() {
+                            if let Some(type_info) = control.type_info(column.column_type) {
+                                self.state.add_type(type_info);
+                            }
+                        }
+                    }
+                    control.resolve_column_types(&mut relation);
+                    // Column lists and generated columns leave columns of the table definition
+                    // out of the message, the sinks see which from the mask
+                    relation.published_mask = control
+                        .published_table(&relation.namespace, &relation.relation_name)
+                        .map(|published| published.column_mask(&relation));
+                }
+                #[cfg(feature = "parquet")]
+                if let Some(parquet) = self.parquet.as_mut() {
+                    parquet.register_relation(&relation)?;
+                }
+                if let Some(avro) = self.avro.as_mut() {
+                    avro.register_relation(&relation)?;
+                }
+                #[cfg(feature = "s3")]
+                if let Some(s3) = self.s3.as_mut() {
+                    s3.register_relation(&relation)?;
+                }
+                let table = format!("{}.{}", relation.namespace, relation.relation_name);
+                let columns = describe_columns(&relation);
+                let description = describe_relation(&relation);
+                let omitted = relation
+                    .published_mask
+                    .iter()
+                    .flat_map(|mask| mask.not_published())
+                    .map(|column| match column.status {
+                        ColumnPublication::Generated => format!("{} (generated)", column.name),
+                        _ => format!("{} (column list)", column.name),
+                    })
+                    .collect::<Vec<_>>();
+                let oid = relation.oid;
+                let schema = relation.namespace.clone();
+                let relation_name = relation.relation_name.clone();
+                let update = self.state.add_relation(relation);
+                // Cached rows are laid out by the previous columns
+                if let (RelationUpdate::SchemaChanged { .. }, Some(cache)) =
+                    (&update, self.toast_cache.as_mut())
+                {
+                    cache.clear_table(oid);
+                }
+                // Column types are only named when the catalog can be queried
+                if update == RelationUpdate::New && self.control.is_some() {
+                    info!("DESCRIBE: table {}", description);
+                }
+                if !omitted.is_empty() && !matches!(update, RelationUpdate::Unchanged) {
+                    info!(
+                        "Table {} is published without some of its columns, not sent: [{}]",
+                        table,
+                        omitted.join(", ")
+                    );
+                }
+                if let RelationUpdate::SchemaChanged {
+                    version,
+                    previous_columns,
+                    diff,
+                } = update
+                {
+                    info!(
+                        "SCHEMA CHANGE: table {} (version {}): {}; [{}] -> [{}]",
+                        table,
+                        version,
+                        diff.summary(),
+                        previous_columns.join(", "),
+                        columns.join(", ")
+                    );
+                    let change = SchemaChangeRecord {
+                        schema_version: self.config.event_schema.field(),
+                        source: self.config.source.clone(),
+                        op: "schema_change",
+                        schema,
+                        table: relation_name,
+                        lsn: Some(self.last_data_lsn.to_string()),
+                        version,
+                        diff,
+                    };
+                    #[cfg(feature = "alloc-stats")]
+                    let _scope = allocstats::enter(Component::Sinks);
+                    for handler in self.sinks() {
+                        handler.on_schema_change(&change)?;
+                    }
+                }
             }
 
             ReplicationMessage::Insert {
@@ -250,7 +1767,7 @@ impl ReplicationServer {
                     );
                     self.info_tuple_data(relation, &tuple_data)?;
                 } else {
-                    error!("Received INSERT for unknown relation: {}", relation_id);
+                    self.report_unknown_relation("INSERT", relation_id);
                 }
             }
 
@@ -273,20 +1790,32 @@ impl ReplicationServer {
                         relation.namespace, relation.relation_name
                     );
 
-                    if let Some(old_data) = old_tuple_data {
-                        let key_info = match key_type {
-                            Some('K') => "INDEX: ",
-                            Some('O') => "REPLICA IDENTITY: ",
-                            _ => "",
-                        };
-                        info!("Old {}: ", key_info);
-                        self.info_tuple_data(relation, &old_data)?;
-                    } 
-
-                    info!("New Row: ");
-                    self.info_tuple_data(relation, &new_tuple_data)?;
+                    if self.config.update_display != UpdateDisplayMode::Full {
+                        let diff = self.config.update_display == UpdateDisplayMode::Diff;
+                        info!("Changed Columns: ");
+                        self.info_changed_columns(
+                            relation,
+                            key_type,
+                            old_tuple_data.as_ref(),
+                            &new_tuple_data,
+                            diff,
+                        )?;
+                    } else {
+                        if let Some(old_data) = old_tuple_data {
+                            let key_info = match key_type {
+                                Some('K') => "INDEX: ",
+                                Some('O') => "REPLICA IDENTITY: ",
+                                _ => "",
+                            };
+                            info!("Old {}: ", key_info);
+                            self.info_tuple_data(relation, &old_data)?;
+                        }
+
+                        info!("New Row: ");
+                        self.info_tuple_data(relation, &new_tuple_data)?;
+                    }
                 } else {
-                    error!("Received UPDATE for unknown relation: {}", relation_id);
+                    self.report_unknown_relation("UPDATE", relation_id);
                 }
             }
 
@@ -314,7 +1843,7 @@ impl ReplicationServer {
                     );
                     self.info_tuple_data(relation, &tuple_data)?;
                 } else {
-                    error!("Received DELETE for unknown relation: {}", relation_id);
+                    self.report_unknown_relation("DELETE", relation_id);
                 }
             }
 
@@ -346,6 +1875,21 @@ impl ReplicationServer {
                 }
             }
 
+            ReplicationMessage::Type { type_info } => {
+                debug!(
+                    "Received type info for {}.{} (oid {})",
+                    type_info.namespace, type_info.type_name, type_info.oid
+                );
+                self.state.add_type(type_info);
+            }
+
+            ReplicationMessage::Origin {
+                origin_lsn,
+                origin_name,
+            } => {
+                info!("ORIGIN: {}, origin commit lsn: {}", origin_name, origin_lsn);
+            }
+
             ReplicationMessage::StreamStart { xid, .. } => {
                 info!("Opening a streamed block for transaction {}", xid);
                 self.state.start_streaming(xid);
@@ -356,13 +1900,75 @@ impl ReplicationServer {
                 self.state.stop_streaming();
             }
 
-            ReplicationMessage::StreamCommit { xid, .. } => {
+            ReplicationMessage::StreamCommit {
+                xid,
+                commit_lsn,
+                end_lsn,
+                ..
+            } => {
+                self.transaction_spans
+                    .end(xid, "committed", Some(commit_lsn), Some(end_lsn));
                 info!("Committing streamed transaction {}\n", xid);
                 self.state.stop_streaming();
+                if let Some(ids) = self.event_ids.as_mut() {
+                    ids.end_streamed(xid);
+                }
+                if let Some(envelopes) = self.envelopes.as_mut() {
+                    envelopes.end_streamed(xid);
+                }
+                self.state.update_applied_lsn(end_lsn);
+                self.stats.observe_applied(end_lsn);
+            }
+
+            ReplicationMessage::StreamAbort {
+                xid,
+                subtransaction_xid,
+                abort_lsn,
+                abort_timestamp,
+            } => {
+                // Aborting a subtransaction leaves the rest of the transaction to commit
+                if xid == subtransaction_xid {
+                    self.transaction_spans.end(xid, "aborted", None, abort_lsn);
+                }
+                match (abort_lsn, abort_timestamp) {
+                    (Some(abort_lsn), Some(abort_timestamp)) => info!(
+                        "Aborting streamed transaction {} (subtransaction {}) at {}, abort_time: {}",
+                        xid,
+                        subtransaction_xid,
+                        abort_lsn,
+                        format_timestamp_from_pg(abort_timestamp)
+                    ),
+                    _ => info!("Aborting streamed transaction {}", xid),
+                }
+                self.state.stop_streaming();
+                if xid == subtransaction_xid {
+                    if let Some(ids) = self.event_ids.as_mut() {
+                        ids.end_streamed(xid);
+                    }
+                    if let Some(envelopes) = self.envelopes.as_mut() {
+                        envelopes.end_streamed(xid);
+                    }
+                }
             }
 
-            ReplicationMessage::StreamAbort { xid, .. } => {
-                info!("Aborting streamed transaction {}", xid);
+            ReplicationMessage::StreamPrepare {
+                xid,
+                prepare_lsn,
+                end_lsn,
+                timestamp,
+                gid,
+                ..
+            } => {
+                self.transaction_spans
+                    .end(xid, "prepared", Some(prepare_lsn), Some(end_lsn));
+                info!(
+                    "Preparing streamed transaction {} as '{}', lsn: {}, end_lsn: {}, prepare_time: {}",
+                    xid,
+                    gid,
+                    prepare_lsn,
+                    end_lsn,
+                    format_timestamp_from_pg(timestamp)
+                );
                 self.state.stop_streaming();
             }
         }
@@ -370,67 +1976,996 @@ impl ReplicationServer {
         Ok(())
     }
 
+    /// Whether the message belongs to a transaction committed before the configured start time
+    ///
+    /// The commit time of a streamed transaction is only known once it commits, so streamed
+    /// changes are never skipped.
+    fn skip_before_start_time(&mut self, message: &ReplicationMessage) -> bool {
+        let Some(start_time) = self.config.start_time else {
+            return false;
+        };
+
+        match message {
+            ReplicationMessage::Begin { timestamp, .. } => {
+                self.state.skipping_transaction = pg_timestamp_to_datetime(*timestamp)
+                    .is_some_and(|commit_time| commit_time < start_time);
+                self.state.skipping_transaction
+            }
+            ReplicationMessage::Commit { end_lsn, .. } if self.state.skipping_transaction => {
+                // Skipped transactions still count as processed for feedback
+                self.state.skipping_transaction = false;
+                self.state.update_applied_lsn(*end_lsn);
+                self.stats.observe_applied(*end_lsn);
+                true
+            }
+            ReplicationMessage::Insert { is_stream, .. }
+            | ReplicationMessage::Update { is_stream, .. }
+            | ReplicationMessage::Delete { is_stream, .. }
+            | ReplicationMessage::Truncate { is_stream, .. } => {
+                self.state.skipping_transaction && !is_stream
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the message is a change of a transaction replicated from another origin, when only
+    /// local changes are streamed
+    ///
+    /// pgoutput sends an Origin message after Begin, or the first StreamStart, of such a
+    /// transaction. The transaction itself still commits and advances the position.
+    fn skip_foreign_origin(&mut self, message: &ReplicationMessage) -> bool {
+        if self.config.origin != OriginFilter::None {
+            return false;
+        }
+
+        match message {
+            ReplicationMessage::Origin { origin_name, .. } => {
+                if let Some(xid) = self.transaction_xid() {
+                    debug!(
+                        "Transaction {} was replicated from origin {}, ignoring its changes",
+                        xid, origin_name
+                    );
+                    self.state.foreign_origin_xids.insert(xid);
+                }
+                false
+            }
+            ReplicationMessage::Insert { .. }
+            | ReplicationMessage::Update { .. }
+            | ReplicationMessage::Delete { .. }
+            | ReplicationMessage::Truncate { .. } => {
+                let foreign = self
+                    .transaction_xid()
+                    .is_some_and(|xid| self.state.foreign_origin_xids.contains(&xid));
+                if foreign {
+                    self.summary.record_foreign_origin_change();
+                }
+                foreign
+            }
+            ReplicationMessage::Commit { .. } => {
+                if let Some(xid) = self.state.current_xid {
+                    self.state.foreign_origin_xids.remove(&xid);
+                }
+                false
+            }
+            ReplicationMessage::StreamCommit { xid, .. }
+            | ReplicationMessage::StreamPrepare { xid, .. } => {
+                self.state.foreign_origin_xids.remove(xid);
+                false
+            }
+            ReplicationMessage::StreamAbort {
+                xid,
+                subtransaction_xid,
+                ..
+            } => {
+                if xid == subtransaction_xid {
+                    self.state.foreign_origin_xids.remove(xid);
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether a row change only touches a table the filter excludes
+    fn is_filtered_out(&self, message: &ReplicationMessageRef) -> bool {
+        let filter = &self.config.table_filter;
+        if filter.is_empty() {
+            return false;
+        }
+
+        match message
+            .relation_id()
+            .and_then(|oid| self.state.get_relation(oid))
+        {
+            Some(relation) => !filter.matches(&relation.namespace, &relation.relation_name),
+            None => false,
+        }
+    }
+
+    /// Apply the table filter, returning None when the message only touches filtered-out tables
+    fn apply_table_filter(&self, message: ReplicationMessage) -> Option<ReplicationMessage> {
+        let filter = &self.config.table_filter;
+        if filter.is_empty() {
+            return Some(message);
+        }
+
+        // Unknown relations are let through so the missing relation is still reported
+        let selected = |relation_id: Oid| match self.state.get_relation(relation_id) {
+            Some(relation) => filter.matches(&relation.namespace, &relation.relation_name),
+            None => true,
+        };
+
+        match message {
+            ReplicationMessage::Insert { relation_id, .. }
+            | ReplicationMessage::Update { relation_id, .. }
+            | ReplicationMessage::Delete { relation_id, .. } => {
+                selected(relation_id).then_some(message)
+            }
+            ReplicationMessage::Truncate {
+                relation_ids,
+                flags,
+                is_stream,
+                xid,
+            } => {
+                let relation_ids: Vec<Oid> = relation_ids
+                    .into_iter()
+                    .filter(|relation_id| selected(*relation_id))
+                    .collect();
+                (!relation_ids.is_empty()).then_some(ReplicationMessage::Truncate {
+                    relation_ids,
+                    flags,
+                    is_stream,
+                    xid,
+                })
+            }
+            _ => Some(message),
+        }
+    }
+
+    /// Whether a row change passes the row filter of its table
+    ///
+    /// Inserts and updates are checked against the new row and deletes against the old row,
+    /// which only has the key columns unless the table has REPLICA IDENTITY FULL.
+    fn row_selected(&self, message: &ReplicationMessage) -> bool {
+        let filter = &self.config.row_filter;
+        if filter.is_empty() {
+            return true;
+        }
+
+        let (relation_id, tuple_data) = match message {
+            ReplicationMessage::Insert {
+                relation_id,
+                tuple_data,
+                ..
+            }
+            | ReplicationMessage::Delete {
+                relation_id,
+                tuple_data,
+                ..
+            } => (*relation_id, tuple_data),
+            ReplicationMessage::Update {
+                relation_id,
+                new_tuple_data,
+                ..
+            } => (*relation_id, new_tuple_data),
+            _ => return true,
+        };
+        match self.state.get_relation(relation_id) {
+            Some(relation) => row_matches(filter, relation, tuple_data),
+            None => true,
+        }
+    }
+
+    /// Whether a row change is emitted under the sampling of its table
+    fn sample_selected(&mut self, message: &ReplicationMessage) -> bool {
+        if self.config.sampling.is_empty() {
+            return true;
+        }
+
+        let relation_id = match message {
+            ReplicationMessage::Insert { relation_id, .. }
+            | ReplicationMessage::Update { relation_id, .. }
+            | ReplicationMessage::Delete { relation_id, .. } => *relation_id,
+            _ => return true,
+        };
+        match self.state.get_relation(relation_id) {
+            Some(relation) => self.sampler.keep(
+                &self.config.sampling,
+                &relation.namespace,
+                &relation.relation_name,
+            ),
+            None => true,
+        }
+    }
+
+    /// Track rows in the TOAST cache and fill in the unchanged TOAST values of updates
+    fn backfill_toast(&mut self, message: &mut ReplicationMessage) {
+        let Some(cache) = self.toast_cache.as_mut() else {
+            return;
+        };
+        match message {
+            ReplicationMessage::Insert {
+                relation_id,
+                tuple_data,
+                ..
+            } => {
+                if let Some(relation) = self.state.get_relation(*relation_id) {
+                    cache.record_insert(relation, tuple_data);
+                }
+            }
+            ReplicationMessage::Update {
+                relation_id,
+                old_tuple_data,
+                new_tuple_data,
+                ..
+            } => {
+                if let Some(relation) = self.state.get_relation(*relation_id) {
+                    cache.backfill_update(relation, old_tuple_data.as_ref(), new_tuple_data);
+                }
+            }
+            ReplicationMessage::Delete {
+                relation_id,
+                tuple_data,
+                ..
+            } => {
+                if let Some(relation) = self.state.get_relation(*relation_id) {
+                    cache.record_delete(relation, tuple_data);
+                }
+            }
+            ReplicationMessage::Truncate { relation_ids, .. } => {
+                for relation_id in relation_ids.iter() {
+                    cache.clear_table(*relation_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Warn once per relation version when a tuple does not have the columns its Relation
+    /// message describes, which means the cached schema is stale
+    fn check_tuple_columns(&mut self, message: &ReplicationMessage) {
+        let (relation_id, tuple_data) = match message {
+            ReplicationMessage::Insert {
+                relation_id,
+                tuple_data,
+                ..
+            }
+            | ReplicationMessage::Delete {
+                relation_id,
+                tuple_data,
+                ..
+            } => (*relation_id, tuple_data),
+            ReplicationMessage::Update {
+                relation_id,
+                new_tuple_data,
+                ..
+            } => (*relation_id, new_tuple_data),
+            _ => return,
+        };
+        let Some(relation) = self.state.get_relation(relation_id) else {
+            return;
+        };
+        let expected = relation.columns.len();
+        if tuple_data.columns.len() == expected {
+            return;
+        }
+
+        let table = format!("{}.{}", relation.namespace, relation.relation_name);
+        let version = self.state.relations.version(relation_id).unwrap_or(0);
+        if self.state.relations.report_mismatch(relation_id) {
+            warn!(
+                "Tuple for table {} has {} columns but relation version {} describes {}; values are shown by position until the server sends the new schema",
+                table,
+                tuple_data.columns.len(),
+                version,
+                expected
+            );
+        }
+    }
+
+    fn report_unknown_relation(&self, kind: &str, relation_id: Oid) {
+        if self.state.relations.was_evicted(relation_id) {
+            error!(
+                "Received {} for relation {} evicted from the relation cache, raise --relation-cache-size above the number of published tables",
+                kind, relation_id
+            );
+        } else {
+            error!("Received {} for unknown relation: {}", kind, relation_id);
+        }
+    }
+
     fn info_tuple_data(&self, relation: &RelationInfo, tuple_data: &TupleData) -> Result<()> {
-        let line: String = tuple_data
+        let mut row = masked_row(relation, tuple_data, &self.config.column_mask);
+        if let Some(mask) = &relation.published_mask {
+            row.add_unpublished(mask);
+        }
+        if let Some(zone) = self.config.display_timezone {
+            row.normalize_temporal(self.date_order(), zone);
+        }
+        // User-defined types are labelled with the name from their Type message
+        info!("[{}]", row.display(|oid| self.state.type_name(oid)));
+        Ok(())
+    }
+
+    /// Top-level transaction the message being processed belongs to
+    fn transaction_xid(&self) -> Option<Xid> {
+        self.state
+            .streaming_xid
+            .or(self.replaying_xid)
+            .or(self.state.current_xid)
+    }
+
+    /// Count table changes and sample commit lag for the run summary and watch mode, and the
+    /// per-table commit-to-receive delay of changes outside streamed transactions
+    fn record_counts(&mut self, message: &ReplicationMessage) {
+        let state = &self.state;
+        let table_name = |relation_id: Oid| match state.get_relation(relation_id) {
+            Some(relation) => format!("{}.{}", relation.namespace, relation.relation_name),
+            None => format!("UNKNOWN_RELATION({})", relation_id),
+        };
+
+        let changes = match message {
+            ReplicationMessage::Insert { relation_id, .. } => {
+                vec![(table_name(*relation_id), ChangeKind::Insert)]
+            }
+            ReplicationMessage::Update { relation_id, .. } => {
+                vec![(table_name(*relation_id), ChangeKind::Update)]
+            }
+            ReplicationMessage::Delete { relation_id, .. } => {
+                vec![(table_name(*relation_id), ChangeKind::Delete)]
+            }
+            ReplicationMessage::Truncate { relation_ids, .. } => relation_ids
+                .iter()
+                .map(|relation_id| (table_name(*relation_id), ChangeKind::Truncate))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        // Streamed changes arrive before their commit time is known
+        let commit_time = self.commit_time.filter(|_| !self.state.in_streaming_txn);
+        let xid = self.transaction_xid();
+        for (table, kind) in &changes {
+            self.summary.record_change(table, *kind);
+            self.transaction_spans.record_change(xid, table, *kind);
+            if let Some(watch) = self.watch.as_mut() {
+                watch.record_change(table, *kind);
+            }
+            if let Some(commit_time) = commit_time {
+                self.delays.record(table, commit_time);
+            }
+        }
+
+        match message {
+            ReplicationMessage::Begin { timestamp, .. } => {
+                self.commit_time = pg_timestamp_to_datetime(*timestamp);
+            }
+            ReplicationMessage::Commit { timestamp, .. }
+            | ReplicationMessage::StreamCommit { timestamp, .. } => {
+                self.commit_time = None;
+                // A commit time out of range fails the dispatch to the handlers instead
+                if let Some(commit_time) = pg_timestamp_to_datetime(*timestamp) {
+                    self.summary.record_commit(commit_time);
+                    if let Some(alerts) = self.alerts.as_mut() {
+                        alerts.record_commit();
+                    }
+                    if let Some(watch) = self.watch.as_mut() {
+                        watch.record_commit(commit_time);
+                    }
+                }
+            }
+            ReplicationMessage::StreamStart {
+                first_segment: true,
+                ..
+            } => self.summary.record_streamed_transaction(),
+            _ => {}
+        }
+    }
+
+    /// Replay the message against the apply target when one is configured
+    fn apply_change(&mut self, message: &ReplicationMessage) -> Result<()> {
+        let Some(apply) = self.apply.as_mut() else {
+            return Ok(());
+        };
+        let state = &self.state;
+        let relation = |relation_id: Oid| {
+            state
+                .get_relation(relation_id)
+                .ok_or_else(|| ReplicationError::apply(format!("Unknown relation {}", relation_id)))
+        };
+
+        match message {
+            ReplicationMessage::Begin { .. } => apply.begin(),
+            ReplicationMessage::Commit { .. } => apply.commit(),
+            ReplicationMessage::Insert {
+                relation_id,
+                tuple_data,
+                ..
+            } => apply.insert(relation(*relation_id)?, tuple_data),
+            ReplicationMessage::Update {
+                relation_id,
+                old_tuple_data,
+                new_tuple_data,
+                ..
+            } => apply.update(
+                relation(*relation_id)?,
+                old_tuple_data.as_ref(),
+                new_tuple_data,
+            ),
+            ReplicationMessage::Delete {
+                relation_id,
+                tuple_data,
+                ..
+            } => apply.delete(relation(*relation_id)?, tuple_data),
+            ReplicationMessage::Truncate {
+                relation_ids,
+                flags,
+                ..
+            } => {
+                let relations = relation_ids
+                    .iter()
+                    .map(|relation_id| relation(*relation_id))
+                    .collect::<Result<Vec<_>>>()?;
+                apply.truncate(&relations, *flags)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Switch to settings reloaded since the last call, once no transaction is open so each
+    /// transaction is displayed and filtered consistently
+    fn apply_reload(&mut self) -> Result<()> {
+        if self.state.current_xid.is_some() || self.state.in_streaming_txn {
+            return Ok(());
+        }
+        let Some(settings) = self.reload.take() else {
+            return Ok(());
+        };
+
+        if settings.output != self.config.output {
+            // Flush and close the old destination before the new one is opened
+            self.output = None;
+            self.output = EventWriter::open(&settings.output)?;
+        }
+        settings.apply_to(&mut self.config);
+        info!("Reloaded display, output and filter settings");
+        crate::backend::log_selection(&self.config);
+        Ok(())
+    }
+
+    /// The structured output and registered handlers followed by the Parquet, Avro and S3 sinks,
+    /// the `[[sink]]` fan-out with the webhook and the event stream
+    fn sinks(&mut self) -> impl Iterator<Item = &mut (dyn ChangeHandler + 'static)> {
+        #[cfg(feature = "parquet")]
+        let parquet = self
+            .parquet
+            .as_mut()
+            .map(|parquet| parquet as &mut (dyn ChangeHandler + 'static));
+        #[cfg(not(feature = "parquet"))]
+        let parquet = None;
+        #[cfg(feature = "s3")]
+        let s3 = self
+            .s3
+            .as_mut()
+            .map(|s3| s3 as &mut (dyn ChangeHandler + 'static));
+        #[cfg(not(feature = "s3"))]
+        let s3 = None;
+        self.output
+            .as_mut()
+            .map(|output| output as &mut (dyn ChangeHandler + 'static))
+            .into_iter()
+            .chain(self.handlers.iter_mut().map(|handler| handler.as_mut()))
+            .chain(parquet)
+            .chain(
+                self.avro
+                    .as_mut()
+                    .map(|avro| avro as &mut (dyn ChangeHandler + 'static)),
+            )
+            .chain(s3)
+            .chain(
+                (!self.fanout.is_empty())
+                    .then_some(&mut self.fanout as &mut (dyn ChangeHandler + 'static)),
+            )
+            .chain(
+                self.events
+                    .as_mut()
+                    .map(|events| events as &mut (dyn ChangeHandler + 'static)),
+            )
+    }
+
+    /// Pass transaction boundaries and row changes to the registered handlers
+    fn dispatch_to_handlers(&mut self, message: &ReplicationMessage) -> Result<()> {
+        #[cfg(feature = "alloc-stats")]
+        let _scope = allocstats::enter(Component::Sinks);
+        if self.sinks().next().is_none() {
+            return Ok(());
+        }
+
+        match *message {
+            ReplicationMessage::Begin {
+                xid,
+                final_lsn,
+                timestamp,
+            } => {
+                if let Some(ids) = self.event_ids.as_mut() {
+                    ids.begin(final_lsn);
+                }
+                let commit_time = pg_commit_time(timestamp)?;
+                if let Some(envelopes) = self.envelopes.as_mut() {
+                    envelopes.begin(Some(xid), Some(final_lsn), commit_time);
+                }
+                for handler in self.sinks() {
+                    handler.on_begin(xid, final_lsn, commit_time)?;
+                }
+                return Ok(());
+            }
+            ReplicationMessage::Commit {
+                commit_lsn,
+                end_lsn,
+                timestamp,
+                ..
+            }
+            | ReplicationMessage::StreamCommit {
+                commit_lsn,
+                end_lsn,
+                timestamp,
+                ..
+            } => {
+                if let Some(ids) = self.event_ids.as_mut() {
+                    ids.commit();
+                }
+                if let Some(envelopes) = self.envelopes.as_mut() {
+                    envelopes.commit();
+                }
+                let commit_time = pg_commit_time(timestamp)?;
+                for handler in self.sinks() {
+                    handler.on_commit(commit_lsn, end_lsn, commit_time)?;
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let state = &self.state;
+        let lsn = Some(self.last_data_lsn.to_string());
+        let publications = &self.config.publication_names;
+        let control = self.control.as_ref();
+        let mask = &self.config.column_mask;
+        let source = &self.config.source;
+        let schema_version = self.config.event_schema.field();
+        let record = |relation: &RelationInfo, op: ChangeKind, xid: Option<Xid>| ChangeRecord {
+            schema_version,
+            id: None,
+            source: source.clone(),
+            op,
+            schema: relation.namespace.clone(),
+            table: relation.relation_name.clone(),
+            lsn: lsn.clone(),
+            xid: xid.or(state.current_xid),
+            // The control connection knows which publications include the table
+            publications: control
+                .and_then(|control| {
+                    control.publications_of(&relation.namespace, &relation.relation_name)
+                })
+                .map_or_else(|| publications.clone(), <[String]>::to_vec),
+            transaction: None,
+            old: None,
+            new: None,
+        };
+
+        // Each record is paired with its relation for numbering
+        let records: Vec<(Oid, ChangeRecord)> = match message {
+            ReplicationMessage::Insert {
+                relation_id,
+                tuple_data,
+                xid,
+                ..
+            } => state
+                .get_relation(*relation_id)
+                .map(|relation| ChangeRecord {
+                    new: Some(masked_row(relation, tuple_data, mask)),
+                    ..record(relation, ChangeKind::Insert, *xid)
+                })
+                .map(|change| (*relation_id, change))
+                .into_iter()
+                .collect(),
+            ReplicationMessage::Update {
+                relation_id,
+                old_tuple_data,
+                new_tuple_data,
+                xid,
+                ..
+            } => state
+                .get_relation(*relation_id)
+                .map(|relation| ChangeRecord {
+                    old: old_tuple_data
+                        .as_ref()
+                        .map(|old| masked_row(relation, old, mask)),
+                    new: Some(masked_row(relation, new_tuple_data, mask)),
+                    ..record(relation, ChangeKind::Update, *xid)
+                })
+                .map(|change| (*relation_id, change))
+                .into_iter()
+                .collect(),
+            ReplicationMessage::Delete {
+                relation_id,
+                tuple_data,
+                xid,
+                ..
+            } => state
+                .get_relation(*relation_id)
+                .map(|relation| ChangeRecord {
+                    old: Some(masked_row(relation, tuple_data, mask)),
+                    ..record(relation, ChangeKind::Delete, *xid)
+                })
+                .map(|change| (*relation_id, change))
+                .into_iter()
+                .collect(),
+            ReplicationMessage::Truncate {
+                relation_ids, xid, ..
+            } => relation_ids
+                .iter()
+                .filter_map(|relation_id| state.get_relation(*relation_id))
+                .map(|relation| (relation.oid, record(relation, ChangeKind::Truncate, *xid)))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        // Changes of streamed transactions carry their xid
+        let streamed_xid = match message {
+            ReplicationMessage::Insert { xid, .. }
+            | ReplicationMessage::Update { xid, .. }
+            | ReplicationMessage::Delete { xid, .. }
+            | ReplicationMessage::Truncate { xid, .. } => *xid,
+            _ => None,
+        };
+        // The envelope names the top-level transaction rather than a subtransaction
+        let top_xid = streamed_xid.map(|xid| self.state.streaming_xid.unwrap_or(xid));
+        let records: Vec<ChangeRecord> = records
+            .into_iter()
+            .map(|(relation_id, mut change)| {
+                change.id = self
+                    .event_ids
+                    .as_mut()
+                    .and_then(|ids| ids.next(streamed_xid, relation_id));
+                change.transaction = self
+                    .envelopes
+                    .as_mut()
+                    .and_then(|envelopes| envelopes.next(top_xid))
+                    .map(|mut info| {
+                        self.config.event_timestamps.stamp(
+                            &mut info,
+                            self.received_at,
+                            self.clock_skew.estimate_ms(),
+                        );
+                        info
+                    });
+                change
+            })
+            .collect();
+
+        for handler in self.sinks() {
+            for record in &records {
+                handler.on_change(record)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Log the replica identity key columns plus every column whose value differs between the
+    /// old and new tuple. A difference can only be detected when the full old row was sent
+    /// (REPLICA IDENTITY FULL), or for key columns when the old key was sent because it changed;
+    /// otherwise every column except unchanged TOAST values is shown. With `diff`, only the
+    /// changed columns of a full old row are shown, and changed columns with a known old value
+    /// as `old -> new`.
+    fn info_changed_columns(
+        &self,
+        relation: &RelationInfo,
+        key_type: Option<char>,
+        old_tuple_data: Option<&TupleData>,
+        new_tuple_data: &TupleData,
+        diff: bool,
+    ) -> Result<()> {
+        let full_old_tuple = match key_type {
+            Some('O') => old_tuple_data,
+            _ => None,
+        };
+        // Under REPLICA IDENTITY FULL every column is flagged as a key column
+        let full_identity = relation.replica_identity == 'f' || full_old_tuple.is_some();
+
+        let changes: Vec<ColumnChange> = new_tuple_data
             .columns
             .iter()
             .enumerate()
-            .filter_map(|(i, column_data)| {
-                if column_data.data_type == 'n' || i >= relation.columns.len() {
-                    None
-                } else {
-                    Some(format!("{}: {}", relation.columns[i].column_name, column_data.data))
+            .filter_map(|(i, new_column)| {
+                let column = relation.columns.get(i)?;
+                let is_key = column.key_flag & 1 != 0;
+                let old_column = match key_type {
+                    Some('O') => old_tuple_data,
+                    Some('K') if is_key => old_tuple_data,
+                    _ => None,
                 }
+                .and_then(|old| old.columns.get(i));
+                let changed = new_column.data_type != 'u'
+                    && match old_column {
+                        // Values that were truncated may differ beyond the bytes kept
+                        Some(old_column) => {
+                            old_column.data_type != new_column.data_type
+                                || old_column.is_truncated()
+                                || old_column.length != new_column.length
+                                || old_column.data != new_column.data
+                        }
+                        None => true,
+                    };
+
+                // The diff of a full old row leaves the unchanged columns out, key or not
+                if !changed && !(is_key && !(diff && full_old_tuple.is_some())) {
+                    return None;
+                }
+
+                let text = |value: &ColumnData| match value.data_type {
+                    'n' => "NULL".to_string(),
+                    'u' => UNCHANGED_TOAST_MARKER.to_string(),
+                    _ => self.display_text(
+                        column.column_type,
+                        self.config.column_mask.apply(
+                            &relation.namespace,
+                            &relation.relation_name,
+                            &column.column_name,
+                            value.to_text(column.column_type),
+                        ),
+                    ),
+                };
+                Some(ColumnChange {
+                    name: column.column_name.clone(),
+                    key: is_key && !full_identity,
+                    old: old_column.filter(|_| diff && changed).map(text),
+                    new: text(new_column),
+                })
             })
-            .collect::<Vec<_>>()
-            .join(", ");
+            .collect();
 
-        info!("[{}]", line);
+        info!("[{}]", format_changes(&changes));
         Ok(())
     }
 
-    fn send_feedback(&mut self) -> Result<()> {
-        if self.state.received_lsn == 0 {
-            return Ok(());
+    /// A value as the log shows it: date and time values in ISO form when a display time zone
+    /// is configured, otherwise as the server sent them
+    fn display_text(&self, type_oid: Oid, text: String) -> String {
+        match self.config.display_timezone {
+            Some(zone) => normalize(type_oid, &text, self.date_order(), zone).unwrap_or(text),
+            None => text,
         }
+    }
 
-        let now = SystemTime::now();
-        let timestamp = system_time_to_postgres_timestamp(now);
-        let mut reply_buf = [0u8; 34]; // 1 + 8 + 8 + 8 + 8 + 1
-        let bytes_written = {
-            let mut writer = BufferWriter::new(&mut reply_buf);
+    /// Day and month order of the replication session's DateStyle
+    fn date_order(&self) -> DateOrder {
+        self.server_info
+            .as_ref()
+            .map(|info| info.date_style.order)
+            .unwrap_or_default()
+    }
 
-            writer.write_u8(b'r')?;
-            writer.write_u64(self.state.received_lsn)?; // Received LSN
-            writer.write_u64(self.state.received_lsn)?; // Flushed LSN (same as received)
-            writer.write_u64(INVALID_XLOG_REC_PTR)?; // Applied LSN (not tracking)
-            writer.write_i64(timestamp)?; // Timestamp
-            writer.write_u8(0)?; // Don't request reply
+    fn send_feedback(&mut self) -> Result<()> {
+        if !self.state.received_lsn.is_valid() {
+            return Ok(());
+        }
+        let received = self.state.received_lsn;
+        let flushed = self.flushed_lsn_for_feedback();
+        let applied = self.applied_lsn_for_feedback();
 
-            writer.bytes_written()
-        };
+        match (&self.connection, &self.pipeline) {
+            (Some(connection), _) => {
+                let reply = self
+                    .status_update
+                    .encode(received, flushed, applied, false)?;
+                if let Err(e) = connection.put_copy_data(reply) {
+                    warn!("Failed to put feedback copy data: {}", e);
+                    return Err(e);
+                }
 
-        if let Err(e) = self.connection.put_copy_data(&reply_buf[..bytes_written]) {
-            warn!("Failed to put feedback copy data: {}", e);
-            return Err(e);
+                if let Err(e) = connection.flush() {
+                    warn!("Failed to flush feedback (non-fatal): {}", e);
+                }
+            }
+            // The receive stage holds the connection and sends the update
+            (None, Some(pipeline)) => pipeline.request_status_update(received, flushed, applied),
+            (None, None) => return Ok(()),
         }
 
-        if let Err(e) = self.connection.flush() {
+        self.state.last_feedback_time = Instant::now();
+        self.state.last_feedback_lsn = (self.state.received_lsn, self.state.applied_lsn);
+        self.stream_control.set_confirmed_lsn(flushed);
+        debug!("Sent feedback with LSN: {}", self.state.received_lsn);
+        Ok(())
+    }
+
+    /// Send a status update asking the server to reply, which shows whether it is still reachable
+    fn request_reply(&mut self) -> Result<()> {
+        let received = self.state.received_lsn;
+        let flushed = self.flushed_lsn_for_feedback();
+        let applied = self.applied_lsn_for_feedback();
+        let connection = self
+            .connection
+            .as_ref()
+            .ok_or_else(|| ReplicationError::connection("Not connected to a server"))?;
+        let reply = self
+            .status_update
+            .encode(received, flushed, applied, true)?;
+        connection.put_copy_data(reply)?;
+        if let Err(e) = connection.flush() {
             warn!("Failed to flush feedback (non-fatal): {}", e);
         }
+        Ok(())
+    }
 
-        debug!("Sent feedback with LSN: {}", self.state.received_lsn);
+    /// Send a last status update and end COPY mode, so the slot's confirmed_flush_lsn is current
+    /// before the connection closes
+    fn send_final_feedback(&mut self) -> Result<()> {
+        self.persist_position()?;
+        self.send_feedback()?;
+        let Some(connection) = &self.connection else {
+            return Ok(());
+        };
+
+        info!(
+            "Confirming flushed position {} before stopping",
+            self.flushed_lsn_for_feedback()
+        );
+        if !connection.end_copy(FINAL_FEEDBACK_TIMEOUT)? {
+            warn!(
+                "Final feedback was not fully sent within {}s",
+                FINAL_FEEDBACK_TIMEOUT.as_secs()
+            );
+        }
         Ok(())
     }
 
     fn check_and_send_feedback(&mut self) -> Result<()> {
-        let now = Instant::now();
-        if now.duration_since(self.state.last_feedback_time)
-            > Duration::from_secs(self.config.feedback_interval_secs)
+        if self.config.feedback_mode == FeedbackMode::OnRequest {
+            return Ok(());
+        }
+
+        let elapsed = self.state.last_feedback_time.elapsed();
+        let advanced =
+            self.state.last_feedback_lsn != (self.state.received_lsn, self.state.applied_lsn);
+
+        if (advanced && elapsed > Duration::from_secs(self.config.feedback_interval_secs))
+            || elapsed > Duration::from_secs(self.config.status_interval_secs)
         {
+            self.persist_position()?;
             self.send_feedback()?;
-            self.state.last_feedback_time = now;
         }
         Ok(())
     }
+
+    /// Send feedback requested over the control socket, returning whether the stream is paused
+    fn service_stream_control(&mut self) -> Result<bool> {
+        if self.stream_control.take_feedback_request() {
+            info!("Sending feedback requested over the control socket");
+            self.persist_position()?;
+            self.send_feedback()?;
+        }
+        let paused = self.stream_control.is_paused();
+        self.health.set_paused(paused);
+        Ok(paused)
+    }
+
+    /// Flushed LSN reported to the server, the applied position last made durable by
+    /// `persist_position`
+    fn flushed_lsn_for_feedback(&self) -> Lsn {
+        self.state.flushed_lsn
+    }
+
+    /// Make the applied position durable: flush and sync the sinks, then save the checkpoint.
+    /// Only once both succeeded may the position be reported as flushed, so a crash never
+    /// confirms changes that were still in a buffer.
+    fn persist_position(&mut self) -> Result<()> {
+        let applied = self.applied_lsn_for_feedback();
+        if !applied.is_valid() || applied <= self.state.flushed_lsn {
+            return Ok(());
+        }
+        for handler in self.sinks() {
+            handler.flush()?;
+        }
+        if let Some(commit_log) = self.commit_log.as_mut() {
+            commit_log.sync()?;
+        }
+        if let Some(store) = &mut self.checkpoint {
+            store.save(&self.config.slot_name, applied, applied)?;
+        }
+        self.state.flushed_lsn = applied;
+        Ok(())
+    }
+
+    /// Applied LSN reported to the server, invalid until a transaction has been processed
+    fn applied_lsn_for_feedback(&self) -> Lsn {
+        match self.delivered_lsn() {
+            Some(delivered) => self.state.applied_lsn.min(delivered),
+            None => self.state.applied_lsn,
+        }
+    }
+
+    /// End LSN up to which S3, the webhook and `[[sink]]` sinks delivered every event and the
+    /// event stream was acknowledged, None without them or once they caught up; then positions
+    /// of keepalives received while idle are confirmed as well
+    fn delivered_lsn(&self) -> Option<Lsn> {
+        #[cfg(feature = "s3")]
+        let s3 = self.s3.as_ref().and_then(|s3| s3.pending_lsn());
+        #[cfg(not(feature = "s3"))]
+        let s3 = None;
+        let sinks = self.fanout.pending_lsn();
+        let events = self.events.as_ref().and_then(EventCollector::pending_lsn);
+        s3.into_iter().chain(sinks).chain(events).min()
+    }
+
+    /// Whether the application is behind on the event stream, which holds reading like a pause
+    fn events_backlogged(&self) -> bool {
+        self.events.as_ref().is_some_and(EventCollector::is_full)
+    }
+
+    /// Load the checkpoint for this slot and return the LSN to resume from, 0/0 when there is none
+    fn load_checkpoint(&mut self) -> Result<Lsn> {
+        let Some(store) = &self.checkpoint else {
+            return Ok(Lsn::INVALID);
+        };
+
+        match store.load()? {
+            Some(checkpoint) if checkpoint.slot_name == self.config.slot_name => {
+                info!(
+                    "Resuming from checkpoint: applied {}, flushed {}, saved at {}",
+                    checkpoint.applied_lsn, checkpoint.flushed_lsn, checkpoint.updated_at
+                );
+                self.state.update_applied_lsn(checkpoint.applied_lsn);
+                Ok(checkpoint.applied_lsn)
+            }
+            Some(checkpoint) => {
+                warn!(
+                    "Ignoring checkpoint for slot {}, streaming slot {}",
+                    checkpoint.slot_name, self.config.slot_name
+                );
+                Ok(Lsn::INVALID)
+            }
+            None => {
+                info!("No checkpoint found, starting from the slot's confirmed position");
+                Ok(Lsn::INVALID)
+            }
+        }
+    }
+}
+
+/// Whether a row of the relation passes the row filter of its table
+fn row_matches(filter: &RowFilter, relation: &RelationInfo, tuple_data: &TupleData) -> bool {
+    if !filter.applies_to(&relation.namespace, &relation.relation_name) {
+        return true;
+    }
+
+    // NULL and unchanged TOAST values are both unknown to the filter
+    let value = |name: &str| {
+        let index = relation
+            .columns
+            .iter()
+            .position(|column| column.column_name == name)?;
+        let column_data = tuple_data.columns.get(index)?;
+        match column_data.data_type {
+            'n' | 'u' => None,
+            _ => Some(column_data.to_text(relation.columns[index].column_type)),
+        }
+    };
+    filter.matches(&relation.namespace, &relation.relation_name, &value)
+}
+
+/// Number of values in the tuple that were truncated
+fn count_truncated(tuple_data: &TupleData) -> usize {
+    tuple_data
+        .columns
+        .iter()
+        .filter(|column| column.is_truncated())
+        .count()
+}
+
+/// The row of a tuple with masked columns holding their masked form
+fn masked_row(relation: &RelationInfo, tuple_data: &TupleData, mask: &ColumnMask) -> Row {
+    let mut row = Row::from_tuple(relation, tuple_data);
+    row.mask(mask, &relation.namespace, &relation.relation_name);
+    row
 }
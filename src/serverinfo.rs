@@ -0,0 +1,155 @@
+//! Server identity and capabilities
+//! IDENTIFY_SYSTEM and SHOW server_version describe the server a replication connection reached.
+//! Its version decides which pgoutput options START_REPLICATION accepts, so options the server
+//! or the configured protocol version cannot honour are refused with a clear error before the
+//! command is sent, instead of failing with the server's message.
+
+use crate::datetime::DateStyle;
+use crate::errors::{ReplicationError, Result};
+use crate::timeline::{identify_system, SystemIdentity};
+use crate::types::ReplicationConfig;
+use crate::utils::PGConnection;
+use tracing::info;
+
+/// The server as seen on connecting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub identity: SystemIdentity,
+    /// server_version as the server reports it, e.g. "16.2 (Debian 16.2-1.pgdg120+2)"
+    pub version: String,
+    /// Server version as an integer, e.g. 160002 for 16.2
+    pub version_num: u32,
+    /// DateStyle of the session, which text values of date and time columns are written in
+    pub date_style: DateStyle,
+}
+
+impl ServerInfo {
+    /// Run IDENTIFY_SYSTEM and SHOW server_version on a replication connection
+    pub fn fetch(connection: &PGConnection) -> Result<Self> {
+        let identity = identify_system(connection)?;
+        let result = connection.exec("SHOW server_version")?;
+        if !result.is_ok() || result.ntuples() == 0 {
+            return Err(ReplicationError::protocol(format!(
+                "SHOW server_version failed: {}",
+                result.error_message()
+            )));
+        }
+        Ok(Self {
+            identity,
+            version: result.getvalue(0, 0).unwrap_or_default(),
+            version_num: connection.server_version(),
+            date_style: connection
+                .parameter_status("DateStyle")
+                .and_then(|style| style.parse().ok())
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Major version, e.g. 16
+    pub fn major_version(&self) -> u32 {
+        self.version_num / 10000
+    }
+
+    /// Highest logical replication protocol version the server speaks
+    pub fn max_protocol_version(&self) -> u32 {
+        match self.major_version() {
+            16.. => 4,
+            15 => 3,
+            14 => 2,
+            _ => 1,
+        }
+    }
+
+    /// Whether large in-progress transactions can be streamed
+    pub fn supports_streaming(&self) -> bool {
+        self.major_version() >= 14
+    }
+
+    /// Whether streamed transactions can be sent for parallel apply
+    pub fn supports_parallel_streaming(&self) -> bool {
+        self.major_version() >= 16
+    }
+
+    /// Whether pgoutput sends values in binary
+    pub fn supports_binary(&self) -> bool {
+        self.major_version() >= 14
+    }
+
+    /// Whether prepared transactions can be decoded at PREPARE TRANSACTION
+    pub fn supports_two_phase(&self) -> bool {
+        self.major_version() >= 15
+    }
+
+    /// Whether START_REPLICATION accepts the origin option
+    pub fn supports_origin_filter(&self) -> bool {
+        self.major_version() >= 16
+    }
+
+    /// Refuse START_REPLICATION options the configured protocol version or the server lacks
+    pub fn check_options(&self, config: &ReplicationConfig) -> Result<()> {
+        let version = config.protocol_version;
+        let max_version = self.max_protocol_version();
+        if version > max_version {
+            let required = match version {
+                2 => 14,
+                3 => 15,
+                _ => 16,
+            };
+            return Err(ReplicationError::config(format!(
+                "Protocol version {} needs PostgreSQL {} or later, the server runs {}; use protocol version {} or lower",
+                version,
+                required,
+                self.version,
+                max_version
+            )));
+        }
+        if config.streaming && version < 2 {
+            return Err(ReplicationError::config(format!(
+                "Streaming in-progress transactions needs protocol version 2 or later, but version {} is configured; raise it or disable streaming",
+                version
+            )));
+        }
+        if config.streaming && config.parallel_streaming && version < 4 {
+            return Err(ReplicationError::config(format!(
+                "Parallel streaming needs protocol version 4, but version {} is configured",
+                version
+            )));
+        }
+        if config.binary && !self.supports_binary() {
+            return Err(ReplicationError::config(format!(
+                "Binary format needs PostgreSQL 14 or later, the server runs {}",
+                self.version
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn log(&self) {
+        info!(
+            system_id = self.identity.system_id.as_str(),
+            timeline = self.identity.timeline,
+            server_version = self.version.as_str(),
+            "PostgreSQL {}, system {}, timeline {}, WAL at {}, database {}; protocol versions 1-{}, streaming: {}, parallel streaming: {}, binary: {}, two-phase: {}, origin filter: {}, DateStyle: {}",
+            self.version,
+            self.identity.system_id,
+            self.identity.timeline,
+            self.identity.xlogpos,
+            self.identity.dbname.as_deref().unwrap_or("-"),
+            self.max_protocol_version(),
+            yes_no(self.supports_streaming()),
+            yes_no(self.supports_parallel_streaming()),
+            yes_no(self.supports_binary()),
+            yes_no(self.supports_two_phase()),
+            yes_no(self.supports_origin_filter()),
+            self.date_style
+        );
+    }
+}
+
+fn yes_no(supported: bool) -> &'static str {
+    if supported {
+        "yes"
+    } else {
+        "no"
+    }
+}
@@ -0,0 +1,97 @@
+//! Running under a service supervisor
+//! In service mode a stream that fails with a retryable error, such as a connection still down
+//! once the reconnect attempts are used up, is started again after a backoff instead of ending
+//! the process, so systemd or a Windows service wrapper only has to restart it for failures
+//! that need attention. The PID file tells the supervisor which process to signal.
+
+use crate::errors::{ReplicationError, Result};
+use crate::signals::ShutdownSignals;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// The process ID written to a file, which is removed again when dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    pid: u32,
+}
+
+impl PidFile {
+    /// Write the ID of this process to `path`, refusing when the file names another process
+    /// that is still running
+    pub fn create(path: &Path) -> Result<Self> {
+        let pid = std::process::id();
+        if let Some(previous) = read_pid(path).filter(|previous| *previous != pid) {
+            if is_running(previous) {
+                return Err(ReplicationError::config(format!(
+                    "PID file {} names process {}, which is still running",
+                    path.display(),
+                    previous
+                )));
+            }
+            warn!(
+                "Replacing the PID file {} of process {}, which is no longer running",
+                path.display(),
+                previous
+            );
+        }
+
+        // Written next to the file and renamed, so a supervisor never reads a partial ID
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, format!("{}\n", pid))
+            .and_then(|_| fs::rename(&temporary, path))
+            .map_err(|e| {
+                ReplicationError::config(format!(
+                    "Failed to write PID file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+        info!("Wrote PID {} to {}", pid, path.display());
+        Ok(Self {
+            path: path.to_path_buf(),
+            pid,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Another instance may have taken the file over
+        if read_pid(&self.path) == Some(self.pid) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("Failed to remove PID file {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Whether a process with the ID exists; elsewhere than Linux a PID file left behind is taken
+/// to be stale, so a crashed run never blocks the next one
+fn is_running(pid: u32) -> bool {
+    if cfg!(target_os = "linux") {
+        Path::new("/proc").join(pid.to_string()).exists()
+    } else {
+        false
+    }
+}
+
+/// Wait `delay` before the stream is restarted, returning false when a shutdown signal
+/// arrives first
+pub async fn wait_before_restart(delay: Duration) -> Result<bool> {
+    let mut signals = ShutdownSignals::new()?;
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => Ok(true),
+        signal = signals.recv() => {
+            warn!("Received {} while waiting to restart, shutting down", signal);
+            Ok(false)
+        }
+    }
+}
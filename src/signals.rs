@@ -0,0 +1,111 @@
+//! Signals that stop or reload the stream
+//! On Unix SIGINT, SIGTERM and SIGQUIT stop streaming gracefully and SIGHUP reloads the display
+//! settings. Windows has no such signals; the console's Ctrl+C, Ctrl+Break, close, logoff and
+//! shutdown events stop streaming instead, and there is nothing to reload on.
+
+use std::io;
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+#[cfg(windows)]
+use tokio::signal::windows::{
+    ctrl_break, ctrl_c, ctrl_close, ctrl_logoff, ctrl_shutdown, CtrlBreak, CtrlC, CtrlClose,
+    CtrlLogoff, CtrlShutdown,
+};
+
+/// Listens for the signals asking the process to stop
+pub struct ShutdownSignals {
+    #[cfg(unix)]
+    interrupt: Signal,
+    #[cfg(unix)]
+    terminate: Signal,
+    #[cfg(unix)]
+    quit: Signal,
+    #[cfg(windows)]
+    ctrl_c: CtrlC,
+    #[cfg(windows)]
+    ctrl_break: CtrlBreak,
+    #[cfg(windows)]
+    close: CtrlClose,
+    #[cfg(windows)]
+    logoff: CtrlLogoff,
+    #[cfg(windows)]
+    shutdown: CtrlShutdown,
+}
+
+impl ShutdownSignals {
+    /// Install the handlers; signals arriving from now on are no longer fatal
+    #[cfg(unix)]
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            quit: signal(SignalKind::quit())?,
+        })
+    }
+
+    #[cfg(windows)]
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            ctrl_c: ctrl_c()?,
+            ctrl_break: ctrl_break()?,
+            close: ctrl_close()?,
+            logoff: ctrl_logoff()?,
+            shutdown: ctrl_shutdown()?,
+        })
+    }
+
+    /// Wait for the next signal, returning its name
+    #[cfg(unix)]
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.quit.recv() => "SIGQUIT",
+        }
+    }
+
+    /// Wait for the next console event, returning its name. Windows ends the process shortly
+    /// after a close, logoff or shutdown event, so there is only time for a quick stop
+    #[cfg(windows)]
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.ctrl_c.recv() => "Ctrl+C",
+            _ = self.ctrl_break.recv() => "Ctrl+Break",
+            _ = self.close.recv() => "console close",
+            _ = self.logoff.recv() => "logoff",
+            _ = self.shutdown.recv() => "system shutdown",
+        }
+    }
+}
+
+/// Listens for SIGHUP, which asks to reload settings; never fires on Windows
+pub struct ReloadSignal {
+    #[cfg(unix)]
+    hangup: Signal,
+}
+
+impl ReloadSignal {
+    #[cfg(unix)]
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    #[cfg(windows)]
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {})
+    }
+
+    /// Wait for the next SIGHUP, None once no more can arrive
+    #[cfg(unix)]
+    pub async fn recv(&mut self) -> Option<()> {
+        self.hangup.recv().await
+    }
+
+    #[cfg(windows)]
+    pub async fn recv(&mut self) -> Option<()> {
+        None
+    }
+}
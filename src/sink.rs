@@ -0,0 +1,956 @@
+//! Pluggable event sinks with fan-out
+//! An `EventSink` receives batches of change events; any number of sinks run side by side, each
+//! on its own thread with its own queue, batching and error policy, so a slow or failing sink
+//! does not hold up the others until its queue fills. Sinks are configured as `[[sink]]` tables
+//! in the config file (console, file and http are built in, sqlite with its feature) or added
+//! with `add_sink`, which is how a Kafka or other client is plugged in. Like the webhook, a sink
+//! only acknowledges a transaction once all its events were written and flushed, and the server
+//! is never told a position past the slowest sink, so delivery is at-least-once. With the
+//! dead-letter error policy, a batch that cannot be delivered is written to a file instead (see
+//! `dlq`).
+
+use crate::compress::Compression;
+use crate::dlq::DeadLetterWriter;
+use crate::errors::{ReplicationError, Result};
+use crate::handler::ChangeHandler;
+use crate::output::{ChangeRecord, EventWriter, OutputConfig, OutputFormat, SchemaChangeRecord};
+use crate::reconnect::ReconnectPolicy;
+use crate::segment::{SegmentLimits, SegmentedFileSink};
+use crate::types::Lsn;
+use crate::webhook::{Endpoint, WebhookConfig};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::future::Future;
+use std::io::Write;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// An event handed to the sinks, serialized as the structured output writes it
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum SinkEvent {
+    Change(ChangeRecord),
+    SchemaChange(SchemaChangeRecord),
+    /// An event read back from a dead-letter file, passed on as it was written
+    Replayed(Box<RawValue>),
+}
+
+/// Future returned by the `EventSink` methods
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Destination of batches of change events
+///
+/// Each sink runs on a thread of its own, so `write` may block or await as long as it needs.
+/// Errors that `is_retryable` are retried with backoff; others, and retries running out, are
+/// handled by the sink's error policy.
+pub trait EventSink: Send {
+    /// Deliver a batch of events in order
+    fn write<'a>(&'a mut self, batch: &'a [SinkEvent]) -> SinkFuture<'a>;
+
+    /// Make everything written so far durable; called after every batch and at shutdown
+    fn flush(&mut self) -> SinkFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Whether `commit` is called at each transaction end, between the writes of its events
+    /// and those of the next transaction
+    fn tracks_commits(&self) -> bool {
+        false
+    }
+
+    /// Every event of the transaction ending at `end_lsn` was passed to `write`
+    fn commit(&mut self, _end_lsn: Lsn) -> SinkFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// End LSN of the last transaction made durable, for sinks that make events durable later
+    /// than `flush`; None acknowledges every transaction once flushed
+    fn durable_lsn(&self) -> Option<Lsn> {
+        None
+    }
+}
+
+/// What happens to a batch a sink fails to deliver
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorPolicy {
+    /// Stop the run; the failed events are sent again after a restart
+    #[default]
+    Stop,
+    /// Log and drop the batch, and carry on with the next one
+    Skip,
+    /// Append the batch to the sink's dead-letter file, and carry on with the next one
+    #[serde(rename = "dead-letter", alias = "dead_letter")]
+    DeadLetter,
+}
+
+impl ErrorPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorPolicy::Stop => "stop",
+            ErrorPolicy::Skip => "skip",
+            ErrorPolicy::DeadLetter => "dead-letter",
+        }
+    }
+}
+
+/// Batching and error handling of one sink
+#[derive(Debug, Clone)]
+pub struct SinkOptions {
+    /// Label of the sink in logs
+    pub name: String,
+    /// Events per batch; a batch is written as soon as it is full
+    pub batch_size: usize,
+    /// Longest an event waits before a partial batch is written
+    pub flush_interval: Duration,
+    /// Events queued for the sink before the stream waits for it
+    pub queue_capacity: usize,
+    pub on_error: ErrorPolicy,
+    /// File undeliverable batches are appended to with the dead-letter policy
+    pub dead_letter_path: Option<PathBuf>,
+    /// Retries of a retryable failure before the error policy applies
+    pub retry: ReconnectPolicy,
+}
+
+impl SinkOptions {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            flush_interval: Duration::from_millis(DEFAULT_FLUSH_INTERVAL_MS),
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            on_error: ErrorPolicy::default(),
+            dead_letter_path: None,
+            retry: ReconnectPolicy::default(),
+        }
+    }
+}
+
+const DEFAULT_BATCH_SIZE: usize = 100;
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 1000;
+const DEFAULT_QUEUE_CAPACITY: usize = 10_000;
+
+/// Kind of a sink configured in a `[[sink]]` table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    /// Standard output, as JSON lines or log lines
+    Console,
+    /// A JSON lines file, optionally compressed
+    File,
+    /// An `http://` or `https://` endpoint each batch is POSTed to as a JSON array
+    Http,
+    /// The events table of a SQLite database, needs the `sqlite` feature
+    Sqlite,
+}
+
+/// Format of the console sink
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsoleFormat {
+    #[default]
+    Json,
+    Log,
+}
+
+/// A sink from a `[[sink]]` table
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SinkConfig {
+    /// Label of the sink in logs, the type when not set
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: SinkKind,
+    /// Format of a console sink
+    #[serde(default)]
+    pub format: ConsoleFormat,
+    /// File of a file or sqlite sink, or the directory of a segmented file sink
+    pub path: Option<PathBuf>,
+    /// Compression of a file sink: none, gzip or zstd
+    pub compression: Option<String>,
+    /// Size after which a segmented file sink completes its segment
+    #[serde(alias = "segment_bytes")]
+    pub segment_bytes: Option<u64>,
+    /// Age after which a segmented file sink completes its segment
+    #[serde(alias = "segment_secs")]
+    pub segment_secs: Option<u64>,
+    /// Endpoint of an http sink
+    pub url: Option<String>,
+    #[serde(alias = "batch_size")]
+    pub batch_size: Option<usize>,
+    #[serde(alias = "flush_interval_ms")]
+    pub flush_interval_ms: Option<u64>,
+    #[serde(alias = "queue_capacity")]
+    pub queue_capacity: Option<usize>,
+    #[serde(default, alias = "on_error")]
+    pub on_error: ErrorPolicy,
+    /// Dead-letter file of the dead-letter error policy
+    #[serde(alias = "dead_letter_path")]
+    pub dead_letter_path: Option<PathBuf>,
+    #[serde(alias = "max_retries")]
+    pub max_retries: Option<u32>,
+}
+
+impl SinkConfig {
+    /// Label of the sink in logs
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            match self.kind {
+                SinkKind::Console => "console",
+                SinkKind::File => "file",
+                SinkKind::Http => "http",
+                SinkKind::Sqlite => "sqlite",
+            }
+            .to_string()
+        })
+    }
+
+    /// Check the settings the sink's type needs and refuse those it ignores
+    pub fn validate(&self) -> Result<()> {
+        let name = self.name();
+        let require = |set: bool, key: &str| {
+            if set {
+                Ok(())
+            } else {
+                Err(ReplicationError::config(format!(
+                    "Sink {} needs {}",
+                    name, key
+                )))
+            }
+        };
+        let refuse = |set: bool, key: &str| {
+            if set {
+                Err(ReplicationError::config(format!(
+                    "Sink {} does not use {}",
+                    name, key
+                )))
+            } else {
+                Ok(())
+            }
+        };
+
+        match self.kind {
+            SinkKind::Console => {
+                refuse(self.segmented(), "segments")?;
+                refuse(self.path.is_some(), "path")?;
+                refuse(self.compression.is_some(), "compression")?;
+                refuse(self.url.is_some(), "url")?;
+            }
+            SinkKind::File => {
+                require(self.path.is_some(), "path")?;
+                refuse(self.url.is_some(), "url")?;
+                refuse(self.format != ConsoleFormat::Json, "format")?;
+                // A segment holds each transaction exactly once, which skipping a batch breaks
+                if self.segmented() {
+                    refuse(self.compression.is_some(), "compression with segments")?;
+                    refuse(self.on_error != ErrorPolicy::Stop, "on-error with segments")?;
+                }
+            }
+            SinkKind::Http => {
+                refuse(self.segmented(), "segments")?;
+                require(self.url.is_some(), "url")?;
+                refuse(self.path.is_some(), "path")?;
+                refuse(self.compression.is_some(), "compression")?;
+                refuse(self.format != ConsoleFormat::Json, "format")?;
+            }
+            SinkKind::Sqlite => {
+                if cfg!(not(feature = "sqlite")) {
+                    return Err(ReplicationError::config(format!(
+                        "Sink {} needs a build with the sqlite feature",
+                        name
+                    )));
+                }
+                refuse(self.segmented(), "segments")?;
+                require(self.path.is_some(), "path")?;
+                refuse(self.url.is_some(), "url")?;
+                refuse(self.compression.is_some(), "compression")?;
+                refuse(self.format != ConsoleFormat::Json, "format")?;
+            }
+        }
+        if self.on_error == ErrorPolicy::DeadLetter {
+            require(self.dead_letter_path.is_some(), "dead-letter-path")?;
+        } else {
+            refuse(self.dead_letter_path.is_some(), "dead-letter-path")?;
+        }
+        if self.batch_size == Some(0) || self.queue_capacity == Some(0) {
+            return Err(ReplicationError::config(format!(
+                "Sink {} needs a batch-size and queue-capacity of at least 1",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether a file sink writes LSN-named segments to the `path` directory
+    pub fn segmented(&self) -> bool {
+        self.segment_bytes.is_some() || self.segment_secs.is_some()
+    }
+
+    pub fn options(&self) -> SinkOptions {
+        let mut options = SinkOptions::new(self.name());
+        if let Some(batch_size) = self.batch_size {
+            options.batch_size = batch_size;
+        }
+        if let Some(flush_interval_ms) = self.flush_interval_ms {
+            options.flush_interval = Duration::from_millis(flush_interval_ms);
+        }
+        if let Some(queue_capacity) = self.queue_capacity {
+            options.queue_capacity = queue_capacity;
+        }
+        options.on_error = self.on_error;
+        options.dead_letter_path = self.dead_letter_path.clone();
+        if let Some(max_retries) = self.max_retries {
+            options.retry.max_retries = max_retries;
+        }
+        options
+    }
+
+    /// Open the sink
+    pub fn open(&self) -> Result<Box<dyn EventSink>> {
+        self.validate()?;
+        Ok(match self.kind {
+            SinkKind::Console => Box::new(ConsoleSink::new(self.format)),
+            SinkKind::File if self.segmented() => {
+                let mut limits = SegmentLimits::default();
+                if let Some(segment_bytes) = self.segment_bytes {
+                    limits.max_bytes = segment_bytes;
+                }
+                if let Some(segment_secs) = self.segment_secs {
+                    limits.max_age = Duration::from_secs(segment_secs);
+                }
+                let directory = self.path.clone().unwrap_or_default();
+                Box::new(SegmentedFileSink::open(directory, limits)?)
+            }
+            SinkKind::File => {
+                let compression = match &self.compression {
+                    Some(compression) => compression.parse()?,
+                    None => Compression::None,
+                };
+                // validate() checked the path is set
+                let path = self.path.clone().unwrap_or_default();
+                Box::new(FileSink::open(path, compression)?)
+            }
+            SinkKind::Http => Box::new(HttpSink::new(self.url.as_deref().unwrap_or_default())?),
+            #[cfg(feature = "sqlite")]
+            SinkKind::Sqlite => {
+                // validate() checked the path is set
+                let path = self.path.clone().unwrap_or_default();
+                Box::new(crate::sqlite::SqliteSink::open(&path)?)
+            }
+            // validate() refused the sink without the feature
+            #[cfg(not(feature = "sqlite"))]
+            SinkKind::Sqlite => unreachable!("sqlite sink opened without the sqlite feature"),
+        })
+    }
+}
+
+/// Writes events to standard output
+pub struct ConsoleSink {
+    format: ConsoleFormat,
+}
+
+impl ConsoleSink {
+    pub fn new(format: ConsoleFormat) -> Self {
+        Self { format }
+    }
+}
+
+impl EventSink for ConsoleSink {
+    fn write<'a>(&'a mut self, batch: &'a [SinkEvent]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            match self.format {
+                ConsoleFormat::Json => {
+                    let mut stdout = std::io::stdout().lock();
+                    for event in batch {
+                        serde_json::to_writer(&mut stdout, event).map_err(|e| {
+                            ReplicationError::sink(format!("Failed to encode event: {}", e))
+                        })?;
+                        stdout.write_all(b"\n")?;
+                    }
+                    stdout.flush()?;
+                }
+                ConsoleFormat::Log => {
+                    for event in batch {
+                        match event {
+                            SinkEvent::Change(change) => info!(
+                                "{:?} {}.{} at {}",
+                                change.op,
+                                change.schema,
+                                change.table,
+                                change.lsn.as_deref().unwrap_or("-")
+                            ),
+                            SinkEvent::SchemaChange(change) => info!(
+                                "Schema of {}.{} changed, version {}",
+                                change.schema, change.table, change.version
+                            ),
+                            SinkEvent::Replayed(event) => {
+                                let event: serde_json::Value =
+                                    serde_json::from_str(event.get()).unwrap_or_default();
+                                let field = |name: &str| {
+                                    event
+                                        .get(name)
+                                        .and_then(|value| value.as_str())
+                                        .unwrap_or("-")
+                                };
+                                info!(
+                                    "Replayed {} {}.{} at {}",
+                                    field("op"),
+                                    field("schema"),
+                                    field("table"),
+                                    field("lsn")
+                                )
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Appends events as JSON lines to a file
+pub struct FileSink {
+    writer: EventWriter,
+}
+
+impl FileSink {
+    pub fn open(path: PathBuf, compression: Compression) -> Result<Self> {
+        let config = OutputConfig {
+            format: OutputFormat::Json,
+            path: Some(path),
+            compression,
+        };
+        let writer = EventWriter::open(&config)
+            .map_err(|e| ReplicationError::sink(format!("{:#}", e)))?
+            .ok_or_else(|| ReplicationError::sink("File sink has no output"))?;
+        Ok(Self { writer })
+    }
+}
+
+impl EventSink for FileSink {
+    fn write<'a>(&'a mut self, batch: &'a [SinkEvent]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            for event in batch {
+                self.writer
+                    .write_record(event)
+                    .map_err(|e| ReplicationError::sink(format!("{:#}", e)))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// POSTs each batch as a JSON array to an `http://` or `https://` endpoint
+pub struct HttpSink {
+    endpoint: Endpoint,
+}
+
+impl HttpSink {
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(Self {
+            endpoint: Endpoint::parse(url)?,
+        })
+    }
+}
+
+impl EventSink for HttpSink {
+    fn write<'a>(&'a mut self, batch: &'a [SinkEvent]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let body = serde_json::to_vec(batch)
+                .map_err(|e| ReplicationError::sink(format!("Failed to encode batch: {}", e)))?;
+            let endpoint = self.endpoint.clone();
+            tokio::task::spawn_blocking(move || endpoint.post(&body))
+                .await
+                .map_err(|e| ReplicationError::sink(format!("Request task failed: {}", e)))?
+        })
+    }
+}
+
+enum SinkMessage {
+    Event(Box<SinkEvent>),
+    /// End LSN of a committed transaction whose events were all queued
+    Commit(Lsn),
+}
+
+/// Progress of a sink, shared with its thread
+#[derive(Debug, Default)]
+struct SinkState {
+    /// End LSN of the last transaction whose events were all written and flushed
+    acked_lsn: AtomicU64,
+    /// End LSN of the last transaction queued for the sink
+    queued_lsn: AtomicU64,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    dead_lettered: AtomicU64,
+    /// Why the sink stopped, for the stop error policy
+    failure: Mutex<Option<String>>,
+}
+
+impl SinkState {
+    fn failure(&self) -> Option<String> {
+        self.failure
+            .lock()
+            .map(|failure| failure.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// A sink running on its own thread
+struct SinkWorker {
+    name: String,
+    sender: Option<SyncSender<SinkMessage>>,
+    thread: Option<JoinHandle<()>>,
+    state: Arc<SinkState>,
+}
+
+impl SinkWorker {
+    fn spawn(options: SinkOptions, sink: Box<dyn EventSink>) -> Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(options.queue_capacity.max(1));
+        let state = Arc::new(SinkState::default());
+        let name = options.name.clone();
+        let dead_letter = match options.on_error {
+            ErrorPolicy::DeadLetter => {
+                let path = options.dead_letter_path.clone().ok_or_else(|| {
+                    ReplicationError::config(format!(
+                        "Sink {} needs a dead-letter file for the dead-letter policy",
+                        name
+                    ))
+                })?;
+                Some(DeadLetterWriter::open(path)?)
+            }
+            _ => None,
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let thread = std::thread::Builder::new()
+            .name(format!("sink-{}", name))
+            .spawn({
+                let state = state.clone();
+                move || SinkLoop::new(options, sink, state, runtime, dead_letter).run(receiver)
+            })?;
+        Ok(Self {
+            name,
+            sender: Some(sender),
+            thread: Some(thread),
+            state,
+        })
+    }
+
+    fn send(&self, message: SinkMessage) -> Result<()> {
+        self.check()?;
+        let Some(sender) = &self.sender else {
+            return Err(ReplicationError::sink(format!(
+                "Sink {} is closed",
+                self.name
+            )));
+        };
+        let commit = match &message {
+            SinkMessage::Commit(end_lsn) => Some(*end_lsn),
+            SinkMessage::Event(_) => None,
+        };
+        // Waits while the queue is full, holding back the stream until the sink catches up
+        sender.send(message).map_err(|_| {
+            ReplicationError::sink(format!("Sink {} stopped unexpectedly", self.name))
+        })?;
+        if let Some(end_lsn) = commit {
+            self.state
+                .queued_lsn
+                .store(end_lsn.value(), Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// End LSN the sink acknowledged, None once it acknowledged every transaction queued
+    fn pending_lsn(&self) -> Option<Lsn> {
+        let acked = Lsn(self.state.acked_lsn.load(Ordering::Acquire));
+        let queued = Lsn(self.state.queued_lsn.load(Ordering::Acquire));
+        (acked < queued).then_some(acked)
+    }
+
+    fn check(&self) -> Result<()> {
+        match self.state.failure() {
+            Some(failure) => Err(ReplicationError::sink(format!(
+                "Sink {} failed: {}",
+                self.name, failure
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Deliver what is queued and wait for the thread to end
+    fn close(&mut self) -> Result<()> {
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                return Err(ReplicationError::sink(format!(
+                    "Sink {} panicked",
+                    self.name
+                )));
+            }
+        }
+        self.check()
+    }
+}
+
+/// Batching, retries and acknowledgement of one sink, run on its thread
+struct SinkLoop {
+    options: SinkOptions,
+    sink: Box<dyn EventSink>,
+    state: Arc<SinkState>,
+    runtime: tokio::runtime::Runtime,
+    dead_letter: Option<DeadLetterWriter>,
+    batch: Vec<SinkEvent>,
+    /// When the oldest event of the batch arrived
+    batch_started: Option<Instant>,
+    /// End LSN of the last commit received
+    committed_lsn: Lsn,
+    /// Transactions that ended within the batch, with the number of events before their end,
+    /// for sinks that track commits
+    commits: Vec<(usize, Lsn)>,
+    failed: bool,
+}
+
+impl SinkLoop {
+    fn new(
+        options: SinkOptions,
+        sink: Box<dyn EventSink>,
+        state: Arc<SinkState>,
+        runtime: tokio::runtime::Runtime,
+        dead_letter: Option<DeadLetterWriter>,
+    ) -> Self {
+        Self {
+            options,
+            sink,
+            state,
+            runtime,
+            dead_letter,
+            batch: Vec::new(),
+            batch_started: None,
+            committed_lsn: Lsn::INVALID,
+            commits: Vec::new(),
+            failed: false,
+        }
+    }
+
+    fn run(mut self, receiver: mpsc::Receiver<SinkMessage>) {
+        loop {
+            let message = match self.batch_started {
+                Some(started) => {
+                    let wait = self
+                        .options
+                        .flush_interval
+                        .saturating_sub(started.elapsed());
+                    receiver.recv_timeout(wait)
+                }
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match message {
+                Ok(SinkMessage::Event(event)) => {
+                    // A stopped sink keeps taking events so the stream is not blocked before
+                    // it notices the failure
+                    if self.failed {
+                        continue;
+                    }
+                    self.batch.push(*event);
+                    self.batch_started.get_or_insert_with(Instant::now);
+                    if self.batch.len() >= self.options.batch_size {
+                        self.deliver();
+                    }
+                }
+                Ok(SinkMessage::Commit(end_lsn)) => {
+                    self.committed_lsn = end_lsn;
+                    if self.failed {
+                        continue;
+                    }
+                    if !self.batch.is_empty() {
+                        if self.sink.tracks_commits() {
+                            self.commits.push((self.batch.len(), end_lsn));
+                        }
+                    } else if self.sink.tracks_commits() {
+                        match self.runtime.block_on(self.sink.commit(end_lsn)) {
+                            Ok(()) => self.acknowledge(),
+                            Err(e) => self.fail(&e),
+                        }
+                    } else {
+                        self.state
+                            .acked_lsn
+                            .store(end_lsn.value(), Ordering::Release);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => self.deliver(),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.deliver();
+                    if !self.failed {
+                        if let Err(e) = self.runtime.block_on(self.sink.flush()) {
+                            self.fail(&e);
+                        }
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Write and flush the batch, retrying retryable failures, then apply the error policy
+    fn deliver(&mut self) {
+        if self.batch.is_empty() || self.failed {
+            return;
+        }
+
+        let count = self.batch.len() as u64;
+        let mut attempt = 0;
+        let result = loop {
+            let (sink, batch, commits) = (&mut self.sink, &self.batch, &self.commits);
+            let result = self.runtime.block_on(async {
+                write_batch(sink.as_mut(), batch, commits).await?;
+                sink.flush().await
+            });
+            match result {
+                Err(e) if e.is_retryable() && attempt < self.options.retry.max_retries => {
+                    attempt += 1;
+                    let delay = self.options.retry.backoff(attempt);
+                    warn!(
+                        sink = self.options.name.as_str(),
+                        error_kind = %e.kind(),
+                        error_code = e.code(),
+                        "Sink {} failed: {}. Retrying in {:.1}s (attempt {}/{})",
+                        self.options.name,
+                        e,
+                        delay.as_secs_f64(),
+                        attempt,
+                        self.options.retry.max_retries
+                    );
+                    std::thread::sleep(delay);
+                }
+                result => break result,
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                self.state.delivered.fetch_add(count, Ordering::Relaxed);
+                debug!(
+                    "Sink {} delivered {} events up to {}",
+                    self.options.name, count, self.committed_lsn
+                );
+            }
+            Err(e) if self.options.on_error == ErrorPolicy::Skip => {
+                self.state.dropped.fetch_add(count, Ordering::Relaxed);
+                warn!(
+                    sink = self.options.name.as_str(),
+                    error_kind = %e.kind(),
+                    error_code = e.code(),
+                    "Sink {} dropped {} events: {}",
+                    self.options.name,
+                    count,
+                    e
+                );
+            }
+            Err(e) => match self.dead_letter.as_mut() {
+                Some(dead_letter) => {
+                    if let Err(write_error) =
+                        dead_letter.write(&self.options.name, &self.batch, &e, attempt + 1)
+                    {
+                        self.fail(&ReplicationError::sink(format!(
+                            "{}, and writing the batch to the dead-letter file failed: {}",
+                            e, write_error
+                        )));
+                        return;
+                    }
+                    self.state.dead_lettered.fetch_add(count, Ordering::Relaxed);
+                    warn!(
+                        sink = self.options.name.as_str(),
+                        error_kind = %e.kind(),
+                        error_code = e.code(),
+                        "Sink {} failed to deliver {} events, wrote them to {}: {}",
+                        self.options.name,
+                        count,
+                        dead_letter.path().display(),
+                        e
+                    );
+                }
+                None => {
+                    self.fail(&e);
+                    return;
+                }
+            },
+        }
+
+        self.batch.clear();
+        self.batch_started = None;
+        self.commits.clear();
+        // Events of a transaction still open were written too, but it is only acknowledged
+        // once its commit has been seen
+        self.acknowledge();
+    }
+
+    /// Acknowledge the last commit, or what the sink reports durable if it does so itself
+    fn acknowledge(&self) {
+        let lsn = self.sink.durable_lsn().unwrap_or(self.committed_lsn);
+        self.state.acked_lsn.store(lsn.value(), Ordering::Release);
+    }
+
+    fn fail(&mut self, e: &ReplicationError) {
+        error!(
+            sink = self.options.name.as_str(),
+            error_kind = %e.kind(),
+            error_code = e.code(),
+            "Sink {} failed: {}",
+            self.options.name,
+            e
+        );
+        self.failed = true;
+        self.batch.clear();
+        self.batch_started = None;
+        self.commits.clear();
+        if let Ok(mut failure) = self.state.failure.lock() {
+            *failure = Some(e.to_string());
+        }
+    }
+}
+
+/// Write a batch, telling a sink that tracks commits where each transaction in it ended
+async fn write_batch(
+    sink: &mut dyn EventSink,
+    batch: &[SinkEvent],
+    commits: &[(usize, Lsn)],
+) -> Result<()> {
+    let mut start = 0;
+    for &(end, end_lsn) in commits {
+        if end > start {
+            sink.write(&batch[start..end]).await?;
+        }
+        sink.commit(end_lsn).await?;
+        start = end;
+    }
+    if start < batch.len() {
+        sink.write(&batch[start..]).await?;
+    }
+    Ok(())
+}
+
+/// Passes every change to each sink's queue
+#[derive(Default)]
+pub struct SinkFanout {
+    workers: Vec<SinkWorker>,
+}
+
+impl SinkFanout {
+    /// Start the sinks of the `[[sink]]` tables
+    pub fn from_configs(configs: &[SinkConfig]) -> Result<Self> {
+        let mut fanout = Self::default();
+        for config in configs {
+            fanout.add(config.options(), config.open()?)?;
+        }
+        Ok(fanout)
+    }
+
+    /// Start the http sink delivering to the webhook
+    pub fn add_webhook(&mut self, config: &WebhookConfig) -> Result<()> {
+        self.add(config.sink_options(), Box::new(HttpSink::new(&config.url)?))
+    }
+
+    /// Start a sink on its own thread
+    pub fn add(&mut self, options: SinkOptions, sink: Box<dyn EventSink>) -> Result<()> {
+        if self
+            .workers
+            .iter()
+            .any(|worker| worker.name == options.name)
+        {
+            return Err(ReplicationError::config(format!(
+                "Duplicate sink name {}, set a name for each sink",
+                options.name
+            )));
+        }
+        info!(
+            "Sending change events to sink {} in batches of {}, {} on error",
+            options.name,
+            options.batch_size,
+            options.on_error.as_str()
+        );
+        self.workers.push(SinkWorker::spawn(options, sink)?);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    /// End LSN up to which every sink acknowledged every event, None without sinks or once
+    /// they acknowledged everything queued, when they hold nothing back
+    pub fn pending_lsn(&self) -> Option<Lsn> {
+        self.workers
+            .iter()
+            .filter_map(SinkWorker::pending_lsn)
+            .min()
+    }
+
+    /// Fail when a sink stopped on an error
+    pub fn check(&self) -> Result<()> {
+        self.workers.iter().try_for_each(SinkWorker::check)
+    }
+
+    /// Deliver the queued events, stop the sinks and log what each delivered
+    pub fn close(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for worker in &mut self.workers {
+            if worker.thread.is_none() {
+                continue;
+            }
+            if let Err(e) = worker.close() {
+                result = result.and(Err(e));
+            }
+            info!(
+                "Delivered {} events to sink {}, dropped {}, dead-lettered {}",
+                worker.state.delivered.load(Ordering::Relaxed),
+                worker.name,
+                worker.state.dropped.load(Ordering::Relaxed),
+                worker.state.dead_lettered.load(Ordering::Relaxed)
+            );
+        }
+        result
+    }
+
+    fn send(&self, event: impl Fn() -> SinkMessage) -> Result<()> {
+        self.workers
+            .iter()
+            .try_for_each(|worker| worker.send(event()))
+    }
+}
+
+impl Drop for SinkFanout {
+    fn drop(&mut self) {
+        // A failure was logged when the sink stopped
+        if let Err(e) = self.close() {
+            debug!("Closing sinks failed: {}", e);
+        }
+    }
+}
+
+impl ChangeHandler for SinkFanout {
+    fn on_change(&mut self, change: &ChangeRecord) -> Result<()> {
+        self.send(|| SinkMessage::Event(Box::new(SinkEvent::Change(change.clone()))))
+    }
+
+    fn on_schema_change(&mut self, change: &SchemaChangeRecord) -> Result<()> {
+        self.send(|| SinkMessage::Event(Box::new(SinkEvent::SchemaChange(change.clone()))))
+    }
+
+    fn on_commit(
+        &mut self,
+        _commit_lsn: Lsn,
+        end_lsn: Lsn,
+        _commit_time: DateTime<Utc>,
+    ) -> Result<()> {
+        self.send(|| SinkMessage::Commit(end_lsn))
+    }
+}
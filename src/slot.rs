@@ -0,0 +1,578 @@
+//! Replication slot management for the libpq backend
+//! Creates, inspects and drops logical slots over a replication connection, and reports their status
+
+use crate::errors::{ReplicationError, Result};
+use crate::quote::{quote_ident, quote_literal};
+use crate::types::Lsn;
+use crate::utils::{PGConnection, PGResult};
+use std::str::FromStr;
+use tracing::{info, warn};
+
+const OUTPUT_PLUGIN: &str = "pgoutput";
+
+/// How the replication slot is obtained before streaming starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlotMode {
+    /// Create the slot, reusing it if it already exists with the pgoutput plugin
+    #[default]
+    Create,
+    /// Require the slot to exist already
+    UseExisting,
+    /// Drop the slot if it exists and create it again, discarding its position
+    Recreate,
+    /// Create a temporary slot that the server drops when the session ends
+    Temporary,
+}
+
+impl FromStr for SlotMode {
+    type Err = ReplicationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "create" => Ok(SlotMode::Create),
+            "use-existing" | "use_existing" => Ok(SlotMode::UseExisting),
+            "recreate" => Ok(SlotMode::Recreate),
+            "temporary" => Ok(SlotMode::Temporary),
+            _ => Err(ReplicationError::config(format!(
+                "Invalid slot mode: {}. Valid values are: create, use-existing, recreate, temporary",
+                s
+            ))),
+        }
+    }
+}
+
+/// An existing replication slot as reported by pg_replication_slots
+#[derive(Debug)]
+pub struct SlotInfo {
+    pub plugin: Option<String>,
+    pub slot_type: String,
+    pub active: bool,
+    /// Position up to which the consumer confirmed changes, streaming never starts before it
+    pub confirmed_flush_lsn: Option<Lsn>,
+}
+
+/// A slot created with CREATE_REPLICATION_SLOT, as described by the row the command returns
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreatedSlot {
+    pub slot_name: String,
+    /// Position streaming from the slot starts at, right after the snapshot if one was exported
+    pub consistent_point: Lsn,
+    /// Name passed to SET TRANSACTION SNAPSHOT, when the slot was created with EXPORT_SNAPSHOT
+    pub snapshot_name: Option<String>,
+    pub output_plugin: String,
+}
+
+impl CreatedSlot {
+    /// Parse the single row of slot_name, consistent_point, snapshot_name and output_plugin
+    fn from_result(result: &PGResult) -> Result<Self> {
+        if result.ntuples() != 1 || result.nfields() < 4 {
+            return Err(ReplicationError::protocol(format!(
+                "CREATE_REPLICATION_SLOT returned {} rows of {} columns, expected 1 row of 4",
+                result.ntuples(),
+                result.nfields()
+            )));
+        }
+        let consistent_point = result.getvalue(0, 1).unwrap_or_default();
+        Ok(Self {
+            slot_name: result.getvalue(0, 0).unwrap_or_default(),
+            consistent_point: consistent_point.parse().map_err(|e| {
+                ReplicationError::protocol(format!(
+                    "Invalid consistent point {:?} of the created slot: {}",
+                    consistent_point, e
+                ))
+            })?,
+            // The column is NULL without an exported snapshot
+            snapshot_name: result.getvalue(0, 2).filter(|name| !name.is_empty()),
+            output_plugin: result.getvalue(0, 3).unwrap_or_default(),
+        })
+    }
+}
+
+/// Make the slot available according to the mode, returning the slot if it was created
+pub fn prepare_slot(
+    connection: &PGConnection,
+    slot_name: &str,
+    mode: SlotMode,
+) -> Result<Option<CreatedSlot>> {
+    let existing = find_slot(connection, slot_name)?;
+
+    match (mode, existing) {
+        (SlotMode::Temporary, Some(_)) => Err(ReplicationError::config(format!(
+            "Cannot create temporary slot {}: a slot with that name already exists",
+            slot_name
+        ))),
+        (SlotMode::Temporary, None) => create_logical_slot(connection, slot_name, true).map(Some),
+        (SlotMode::Recreate, existing) => {
+            if existing.is_some() {
+                drop_slot(connection, slot_name)?;
+                info!("Dropped existing replication slot {}", slot_name);
+            }
+            create_logical_slot(connection, slot_name, false).map(Some)
+        }
+        (SlotMode::Create, None) => create_logical_slot(connection, slot_name, false).map(Some),
+        (SlotMode::UseExisting, None) => Err(ReplicationError::config(format!(
+            "Replication slot {} does not exist",
+            slot_name
+        ))),
+        (SlotMode::Create | SlotMode::UseExisting, Some(slot)) => {
+            check_usable(slot_name, &slot)?;
+            info!("Using existing replication slot {}", slot_name);
+            Ok(None)
+        }
+    }
+}
+
+/// Look up a slot by name
+pub fn find_slot(connection: &PGConnection, slot_name: &str) -> Result<Option<SlotInfo>> {
+    // Replication connections only accept the simple query protocol, so the name is inlined
+    let query = format!(
+        "SELECT plugin, slot_type, active, confirmed_flush_lsn FROM pg_replication_slots WHERE slot_name = {};",
+        quote_literal(slot_name)
+    );
+
+    let result = connection.exec(&query)?;
+    if !result.is_ok() {
+        return Err(ReplicationError::protocol(format!(
+            "Failed to look up replication slot {}: {}",
+            slot_name,
+            result.error_message()
+        )));
+    }
+
+    if result.ntuples() == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(SlotInfo {
+        plugin: result.getvalue(0, 0).filter(|plugin| !plugin.is_empty()),
+        slot_type: result.getvalue(0, 1).unwrap_or_default(),
+        active: result.getvalue(0, 2).as_deref() == Some("t"),
+        confirmed_flush_lsn: result.getvalue(0, 3).and_then(|lsn| lsn.parse().ok()),
+    }))
+}
+
+/// Reject existing slots that cannot be streamed with pgoutput
+pub(crate) fn check_usable(slot_name: &str, slot: &SlotInfo) -> Result<()> {
+    if slot.slot_type != "logical" {
+        return Err(ReplicationError::config(format!(
+            "Replication slot {} is a {} slot, a logical slot is required",
+            slot_name, slot.slot_type
+        )));
+    }
+
+    if slot.plugin.as_deref() != Some(OUTPUT_PLUGIN) {
+        return Err(ReplicationError::config(format!(
+            "Replication slot {} uses output plugin {}, expected {}. Use --slot-mode recreate to replace it",
+            slot_name,
+            slot.plugin.as_deref().unwrap_or("none"),
+            OUTPUT_PLUGIN
+        )));
+    }
+
+    if slot.active {
+        return Err(ReplicationError::config(format!(
+            "Replication slot {} is in use by another connection",
+            slot_name
+        )));
+    }
+
+    Ok(())
+}
+
+/// Create a logical replication slot using the pgoutput plugin
+pub fn create_logical_slot(
+    connection: &PGConnection,
+    slot_name: &str,
+    temporary: bool,
+) -> Result<CreatedSlot> {
+    exec_create_slot(connection, slot_name, temporary, "NOEXPORT_SNAPSHOT")
+}
+
+/// Create the slot for an initial sync, exporting the snapshot it starts from
+///
+/// The slot must be new so that its consistent point matches the snapshot: an existing slot is
+/// only replaced in recreate mode. The returned slot always has a snapshot name; the snapshot
+/// stays usable until the connection runs its next command.
+pub fn create_slot_with_snapshot(
+    connection: &PGConnection,
+    slot_name: &str,
+    mode: SlotMode,
+) -> Result<CreatedSlot> {
+    let existing = find_slot(connection, slot_name)?;
+    match (mode, existing) {
+        (SlotMode::UseExisting, _) => {
+            return Err(ReplicationError::config(
+                "Initial sync creates its slot, it cannot use an existing one",
+            ))
+        }
+        (SlotMode::Recreate, Some(_)) => {
+            drop_slot(connection, slot_name)?;
+            info!("Dropped existing replication slot {}", slot_name);
+        }
+        (SlotMode::Create | SlotMode::Temporary, Some(_)) => {
+            return Err(ReplicationError::config(format!(
+                "Initial sync needs a new slot, but {} already exists. Use --slot-mode recreate to replace it",
+                slot_name
+            )))
+        }
+        (_, None) => {}
+    }
+
+    let temporary = mode == SlotMode::Temporary;
+    let slot = exec_create_slot(connection, slot_name, temporary, "EXPORT_SNAPSHOT")?;
+    if slot.snapshot_name.is_none() {
+        return Err(ReplicationError::protocol(format!(
+            "Replication slot {} was created without an exported snapshot",
+            slot_name
+        )));
+    }
+    Ok(slot)
+}
+
+fn exec_create_slot(
+    connection: &PGConnection,
+    slot_name: &str,
+    temporary: bool,
+    snapshot_action: &str,
+) -> Result<CreatedSlot> {
+    // https://www.postgresql.org/docs/14/protocol-replication.html
+    let create_slot_sql = format!(
+        "CREATE_REPLICATION_SLOT {} {}LOGICAL {} {};",
+        quote_ident(slot_name),
+        if temporary { "TEMPORARY " } else { "" },
+        OUTPUT_PLUGIN,
+        snapshot_action
+    );
+
+    let result = connection.exec(&create_slot_sql)?;
+    if !result.is_ok() {
+        return Err(ReplicationError::protocol(format!(
+            "Failed to create replication slot {}: {}",
+            slot_name,
+            result.error_message()
+        )));
+    }
+
+    let slot = CreatedSlot::from_result(&result)?;
+    info!(
+        "Created {}replication slot {} with plugin {} at {}{}",
+        if temporary { "temporary " } else { "" },
+        slot.slot_name,
+        slot.output_plugin,
+        slot.consistent_point,
+        slot.snapshot_name
+            .as_deref()
+            .map(|name| format!(", exported snapshot {}", name))
+            .unwrap_or_default()
+    );
+    Ok(slot)
+}
+
+/// Drop a replication slot, waiting for it to become inactive if it is in use
+///
+/// Falls back to `pg_drop_replication_slot()` when the replication command is rejected, such as
+/// over a regular (non-replication) connection.
+pub fn drop_slot(connection: &PGConnection, slot_name: &str) -> Result<()> {
+    let drop_slot_sql = format!("DROP_REPLICATION_SLOT {} WAIT;", quote_ident(slot_name));
+
+    let result = connection.exec(&drop_slot_sql)?;
+    if result.is_ok() {
+        return Ok(());
+    }
+
+    warn!(
+        "DROP_REPLICATION_SLOT failed: {}. Retrying with pg_drop_replication_slot()",
+        result.error_message()
+    );
+    let fallback_sql = format!(
+        "SELECT pg_drop_replication_slot({});",
+        quote_literal(slot_name)
+    );
+    let result = connection.exec(&fallback_sql)?;
+    if !result.is_ok() {
+        return Err(ReplicationError::protocol(format!(
+            "Failed to drop replication slot {}: {}",
+            slot_name,
+            result.error_message()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Health snapshot of a replication slot and the walsender streaming from it
+#[derive(Debug)]
+pub struct SlotStatus {
+    pub slot_name: String,
+    pub slot_type: String,
+    pub plugin: Option<String>,
+    pub active: bool,
+    /// Backend streaming from the slot, when active
+    pub active_pid: Option<i32>,
+    /// Oldest WAL position the slot still needs
+    pub restart_lsn: Option<Lsn>,
+    pub confirmed_flush_lsn: Option<Lsn>,
+    /// WAL bytes the server keeps for the slot, between its restart_lsn and the current position
+    pub retained_bytes: Option<i64>,
+    /// Walsender state from pg_stat_replication, e.g. streaming or catchup
+    pub sender_state: Option<String>,
+    pub sent_lsn: Option<Lsn>,
+    pub client_addr: Option<String>,
+}
+
+impl SlotStatus {
+    /// Log the snapshot, one line per slot
+    pub fn log(&self) {
+        let lsn = |lsn: Option<Lsn>| lsn.map_or_else(|| "-".to_string(), |lsn| lsn.to_string());
+        info!(
+            "Slot {} ({} {}): active: {}, pid: {}, restart_lsn: {}, confirmed_flush_lsn: {}, retained WAL: {}",
+            self.slot_name,
+            self.slot_type,
+            self.plugin.as_deref().unwrap_or("-"),
+            self.active,
+            self.active_pid.map_or_else(|| "-".to_string(), |pid| pid.to_string()),
+            lsn(self.restart_lsn),
+            lsn(self.confirmed_flush_lsn),
+            self.retained_bytes
+                .map_or_else(|| "-".to_string(), |bytes| format!("{} bytes", bytes))
+        );
+        if let Some(state) = &self.sender_state {
+            info!(
+                "  walsender: state: {}, sent_lsn: {}, client: {}",
+                state,
+                lsn(self.sent_lsn),
+                self.client_addr.as_deref().unwrap_or("local")
+            );
+        }
+    }
+}
+
+/// Query pg_replication_slots and pg_stat_replication for one slot, or every slot with None
+pub fn slot_status(connection: &PGConnection, slot_name: Option<&str>) -> Result<Vec<SlotStatus>> {
+    // On a standby the retained WAL is measured against the last received position
+    let query = format!(
+        "SELECT s.slot_name, s.slot_type, s.plugin, s.active, s.active_pid, s.restart_lsn, \
+         s.confirmed_flush_lsn, \
+         pg_wal_lsn_diff(CASE WHEN pg_is_in_recovery() THEN pg_last_wal_receive_lsn() \
+         ELSE pg_current_wal_lsn() END, s.restart_lsn)::bigint, \
+         r.state, r.sent_lsn, r.client_addr \
+         FROM pg_replication_slots s LEFT JOIN pg_stat_replication r ON r.pid = s.active_pid{} \
+         ORDER BY s.slot_name;",
+        slot_name
+            .map(|name| format!(" WHERE s.slot_name = {}", quote_literal(name)))
+            .unwrap_or_default()
+    );
+
+    let result = connection.exec(&query)?;
+    if !result.is_ok() {
+        return Err(ReplicationError::protocol(format!(
+            "Failed to query replication slot status: {}",
+            result.error_message()
+        )));
+    }
+
+    let value = |row: i32, col: i32| result.getvalue(row, col).filter(|value| !value.is_empty());
+    let lsn = |row: i32, col: i32| value(row, col).and_then(|lsn| lsn.parse().ok());
+    Ok((0..result.ntuples())
+        .map(|row| SlotStatus {
+            slot_name: value(row, 0).unwrap_or_default(),
+            slot_type: value(row, 1).unwrap_or_default(),
+            plugin: value(row, 2),
+            active: value(row, 3).as_deref() == Some("t"),
+            active_pid: value(row, 4).and_then(|pid| pid.parse().ok()),
+            restart_lsn: lsn(row, 5),
+            confirmed_flush_lsn: lsn(row, 6),
+            retained_bytes: value(row, 7).and_then(|bytes| bytes.parse().ok()),
+            sender_state: value(row, 8),
+            sent_lsn: lsn(row, 9),
+            client_addr: value(row, 10),
+        })
+        .collect())
+}
+
+/// WAL a slot retains, against the limit max_slot_wal_keep_size puts on it
+#[derive(Debug)]
+pub struct WalRetention {
+    /// Current WAL position, the last received one on a standby
+    pub current_lsn: Lsn,
+    /// None once the slot has lost the WAL it needs
+    pub restart_lsn: Option<Lsn>,
+    /// reserved, extended, unreserved or lost
+    pub wal_status: Option<String>,
+    /// WAL bytes that can be written before the slot is in danger of losing WAL, None without a limit
+    pub safe_wal_size: Option<i64>,
+    /// max_slot_wal_keep_size in bytes, None when unlimited
+    pub max_keep_bytes: Option<u64>,
+}
+
+impl WalRetention {
+    /// WAL bytes between the slot's restart_lsn and the current position
+    pub fn retained_bytes(&self) -> Option<u64> {
+        self.restart_lsn
+            .map(|restart_lsn| self.current_lsn.bytes_since(restart_lsn))
+    }
+}
+
+/// Query the WAL retained for a slot, None when the slot does not exist
+pub fn wal_retention(connection: &PGConnection, slot_name: &str) -> Result<Option<WalRetention>> {
+    // wal_status, safe_wal_size and max_slot_wal_keep_size came with PostgreSQL 13
+    if connection.server_version() < 130000 {
+        return Err(ReplicationError::config(
+            "max_slot_wal_keep_size and the slot's WAL status need PostgreSQL 13 or later",
+        ));
+    }
+    let query = format!(
+        "SELECT CASE WHEN pg_is_in_recovery() THEN pg_last_wal_receive_lsn() \
+         ELSE pg_current_wal_lsn() END, s.restart_lsn, s.wal_status, s.safe_wal_size, \
+         (SELECT setting::bigint * CASE unit WHEN 'MB' THEN 1048576 WHEN 'kB' THEN 1024 ELSE 1 END \
+         FROM pg_settings WHERE name = 'max_slot_wal_keep_size') \
+         FROM pg_replication_slots s WHERE s.slot_name = {};",
+        quote_literal(slot_name)
+    );
+
+    let result = connection.exec(&query)?;
+    if !result.is_ok() {
+        return Err(ReplicationError::protocol(format!(
+            "Failed to query the slot's WAL retention: {}",
+            result.error_message()
+        )));
+    }
+    if result.ntuples() == 0 {
+        return Ok(None);
+    }
+
+    let value = |col: i32| result.getvalue(0, col).filter(|value| !value.is_empty());
+    let lsn = |col: i32| value(col).and_then(|lsn| lsn.parse().ok());
+    Ok(Some(WalRetention {
+        current_lsn: lsn(0).unwrap_or_default(),
+        restart_lsn: lsn(1),
+        wal_status: value(2),
+        safe_wal_size: value(3).and_then(|bytes| bytes.parse().ok()),
+        // -1 disables the limit
+        max_keep_bytes: value(4)
+            .and_then(|bytes| bytes.parse::<i64>().ok())
+            .and_then(|bytes| u64::try_from(bytes).ok()),
+    }))
+}
+
+/// Where advancing a slot moves it from and to
+#[derive(Debug)]
+pub struct SlotAdvance {
+    pub slot_name: String,
+    pub slot_type: String,
+    /// Oldest WAL position the slot needs now
+    pub restart_lsn: Lsn,
+    /// Position the slot is advanced from: its confirmed position, or restart_lsn for a
+    /// physical slot
+    pub from_lsn: Lsn,
+    pub requested_lsn: Lsn,
+    /// Requested position capped at the server's current WAL position, as the server caps it
+    pub to_lsn: Lsn,
+    /// WAL bytes the server keeps for the slot now
+    pub retained_bytes: Option<i64>,
+}
+
+impl SlotAdvance {
+    /// WAL retained now that lies before the new position. A logical slot may keep part of it
+    /// for transactions still running there, so this is the most that is released
+    pub fn released_bytes(&self) -> u64 {
+        self.to_lsn.bytes_since(self.restart_lsn)
+    }
+
+    pub fn log(&self) {
+        if self.to_lsn < self.requested_lsn {
+            warn!(
+                "{} is beyond the server's current WAL position, the slot can only be advanced to {}",
+                self.requested_lsn, self.to_lsn
+            );
+        }
+        info!(
+            "Slot {} ({}): at {}, restart_lsn: {}, retained WAL: {}; advancing to {} skips {} bytes of WAL and releases up to {} bytes",
+            self.slot_name,
+            self.slot_type,
+            self.from_lsn,
+            self.restart_lsn,
+            self.retained_bytes
+                .map_or_else(|| "-".to_string(), |bytes| format!("{} bytes", bytes)),
+            self.to_lsn,
+            self.to_lsn.bytes_since(self.from_lsn),
+            self.released_bytes()
+        );
+    }
+}
+
+/// Check that the slot can be advanced to `lsn` and work out where it would end up
+pub fn plan_slot_advance(
+    connection: &PGConnection,
+    slot_name: &str,
+    lsn: Lsn,
+) -> Result<SlotAdvance> {
+    let status = slot_status(connection, Some(slot_name))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| {
+            ReplicationError::config(format!("Replication slot {} does not exist", slot_name))
+        })?;
+    if status.active {
+        return Err(ReplicationError::config(format!(
+            "Replication slot {} is in use by process {}; stop its consumer before advancing it",
+            slot_name,
+            status.active_pid.unwrap_or_default()
+        )));
+    }
+    let restart_lsn = status.restart_lsn.ok_or_else(|| {
+        ReplicationError::config(format!(
+            "Replication slot {} has not reserved WAL and cannot be advanced",
+            slot_name
+        ))
+    })?;
+    let from_lsn = status.confirmed_flush_lsn.unwrap_or(restart_lsn);
+    if lsn < from_lsn {
+        return Err(ReplicationError::config(format!(
+            "Cannot move replication slot {} back from {} to {}",
+            slot_name, from_lsn, lsn
+        )));
+    }
+
+    // The server advances no further than the WAL it has flushed, or replayed on a standby
+    let result = connection.exec(
+        "SELECT CASE WHEN pg_is_in_recovery() THEN pg_last_wal_replay_lsn() \
+         ELSE pg_current_wal_flush_lsn() END;",
+    )?;
+    let current_lsn = match result.getvalue(0, 0) {
+        Some(value) if result.is_ok() => value.parse()?,
+        _ => {
+            return Err(ReplicationError::protocol(format!(
+                "Failed to query the current WAL position: {}",
+                result.error_message()
+            )))
+        }
+    };
+
+    Ok(SlotAdvance {
+        slot_name: status.slot_name,
+        slot_type: status.slot_type,
+        restart_lsn,
+        from_lsn,
+        requested_lsn: lsn,
+        to_lsn: lsn.min(current_lsn).max(from_lsn),
+        retained_bytes: status.retained_bytes,
+    })
+}
+
+/// Advance the slot with pg_replication_slot_advance(), returning the position it reached
+pub fn advance_slot(connection: &PGConnection, advance: &SlotAdvance) -> Result<Lsn> {
+    let result = connection.exec(&format!(
+        "SELECT end_lsn FROM pg_replication_slot_advance({}, {});",
+        quote_literal(&advance.slot_name),
+        quote_literal(&advance.to_lsn.to_string())
+    ))?;
+    match result.getvalue(0, 0) {
+        Some(value) if result.is_ok() => value.parse(),
+        _ => Err(ReplicationError::protocol(format!(
+            "Failed to advance replication slot {}: {}",
+            advance.slot_name,
+            result.error_message()
+        ))),
+    }
+}
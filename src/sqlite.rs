@@ -0,0 +1,180 @@
+//! SQLite audit sink
+//! Appends every change event to an `events` table of a local SQLite database, one database
+//! transaction per batch, giving a durable trail that can be queried with plain SQL. SQLite is
+//! reached through rusqlite, linking the system libsqlite3, or one compiled into the binary with
+//! the `sqlite-bundled` feature; the sink is only built with the `sqlite` feature.
+
+use crate::errors::{ReplicationError, Result};
+use crate::sink::{EventSink, SinkEvent, SinkFuture};
+use rusqlite::{params, Connection, ErrorCode, TransactionBehavior};
+use serde_json::Value;
+use std::path::Path;
+use std::time::Duration;
+use tracing::info;
+
+/// How long a write waits for another connection's lock before the batch fails as retryable
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// WAL journaling lets readers query the trail while the sink writes; FULL sync makes every
+/// committed batch durable before it is acknowledged
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+PRAGMA synchronous = FULL;
+CREATE TABLE IF NOT EXISTS events (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    recorded_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    source TEXT,
+    event_id TEXT,
+    xid INTEGER,
+    lsn TEXT,
+    schema_name TEXT NOT NULL,
+    table_name TEXT NOT NULL,
+    op TEXT NOT NULL,
+    old TEXT,
+    new TEXT
+);
+CREATE UNIQUE INDEX IF NOT EXISTS events_event_id ON events (ifnull(source, ''), event_id)
+    WHERE event_id IS NOT NULL;
+CREATE INDEX IF NOT EXISTS events_table ON events (schema_name, table_name);
+CREATE INDEX IF NOT EXISTS events_xid ON events (xid);
+";
+
+/// Events redelivered after a restart carry the same ID and are ignored when IDs are enabled
+const INSERT: &str = "INSERT OR IGNORE INTO events \
+    (source, event_id, xid, lsn, schema_name, table_name, op, old, new) \
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)";
+
+/// Turn a SQLite error into a sink error; a locked database may free up, so that is retryable
+fn sqlite_error(action: &str) -> impl FnOnce(rusqlite::Error) -> ReplicationError + '_ {
+    move |e| {
+        let message = format!("SQLite failed to {}: {}", action, e);
+        match e.sqlite_error_code() {
+            Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => {
+                ReplicationError::sink_retryable(message)
+            }
+            _ => ReplicationError::sink(message),
+        }
+    }
+}
+
+/// One row of the events table
+struct AuditRow {
+    source: Option<String>,
+    event_id: Option<String>,
+    xid: Option<i64>,
+    lsn: Option<String>,
+    schema: String,
+    table: String,
+    op: String,
+    old: Option<String>,
+    new: Option<String>,
+}
+
+impl AuditRow {
+    /// Lay out an event as the sinks receive it; a schema change keeps what changed in `new`
+    fn from_event(event: &SinkEvent) -> Result<Self> {
+        let mut value = serde_json::to_value(event)
+            .map_err(|e| ReplicationError::sink(format!("Failed to encode event: {}", e)))?;
+        let Value::Object(fields) = &mut value else {
+            return Err(ReplicationError::sink("Event is not a JSON object"));
+        };
+        let mut text = |name: &str| match fields.remove(name) {
+            Some(Value::String(text)) => Some(text),
+            _ => None,
+        };
+        let source = text("source");
+        let event_id = text("id");
+        let lsn = text("lsn");
+        let schema = text("schema").unwrap_or_default();
+        let table = text("table").unwrap_or_default();
+        let op = text("op").unwrap_or_default();
+        let xid = fields.remove("xid").and_then(|xid| xid.as_i64());
+        let image = |value: Option<Value>| value.map(|value| value.to_string());
+        let (old, new) = if op == "schema_change" {
+            fields.remove("schema_version");
+            (None, Some(value.to_string()))
+        } else {
+            (image(fields.remove("old")), image(fields.remove("new")))
+        };
+
+        Ok(Self {
+            source,
+            event_id,
+            xid,
+            lsn,
+            schema,
+            table,
+            op,
+            old,
+            new,
+        })
+    }
+}
+
+/// Writes change events to the `events` table of a SQLite database
+pub struct SqliteSink {
+    connection: Connection,
+}
+
+impl SqliteSink {
+    /// Open or create the database and its events table
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path).map_err(|e| {
+            ReplicationError::sink(format!(
+                "Failed to open SQLite database {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .and_then(|()| connection.execute_batch(SCHEMA))
+            .map_err(sqlite_error("create the events table"))?;
+        info!(
+            "Recording change events to SQLite database {}",
+            path.display()
+        );
+        Ok(Self { connection })
+    }
+
+    /// Insert the batch in one transaction; dropping it on an error rolls it back, so a retry
+    /// starts afresh
+    fn write_batch(&mut self, batch: &[SinkEvent]) -> Result<()> {
+        let rows = batch
+            .iter()
+            .map(AuditRow::from_event)
+            .collect::<Result<Vec<_>>>()?;
+
+        let transaction = self
+            .connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(sqlite_error("begin a transaction"))?;
+        {
+            let mut insert = transaction
+                .prepare_cached(INSERT)
+                .map_err(sqlite_error("prepare statement"))?;
+            for row in &rows {
+                insert
+                    .execute(params![
+                        row.source,
+                        row.event_id,
+                        row.xid,
+                        row.lsn,
+                        row.schema,
+                        row.table,
+                        row.op,
+                        row.old,
+                        row.new,
+                    ])
+                    .map_err(sqlite_error("insert event"))?;
+            }
+        }
+        transaction.commit().map_err(sqlite_error("commit"))
+    }
+}
+
+impl EventSink for SqliteSink {
+    fn write<'a>(&'a mut self, batch: &'a [SinkEvent]) -> SinkFuture<'a> {
+        Box::pin(async move { self.write_batch(batch) })
+    }
+}
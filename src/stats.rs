@@ -0,0 +1,356 @@
+//! Replication lag statistics
+//! Compares the server's WAL end with the received and applied positions, measures the delay
+//! between the server sending a message and it being processed here, reports CopyData buffer
+//! reuse, and keeps per-table
+//! histograms of the delay between a transaction committing and its changes arriving
+
+use crate::bufpool::{BufferPool, BufferPoolStats};
+use crate::health::HealthState;
+use crate::types::Lsn;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Point-in-time view of the replication lag
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LagSnapshot {
+    /// Latest WAL end reported by the server
+    pub server_wal_end: Lsn,
+    /// Bytes of WAL between the received position and the server's WAL end
+    pub received_bytes_behind: u64,
+    /// Bytes of WAL between the applied position and the server's WAL end
+    pub applied_bytes_behind: u64,
+    /// Delay between the server's send time of the last message and its arrival
+    pub seconds_behind: f64,
+    /// Change events processed per second since the previous report
+    pub events_per_sec: f64,
+    /// Reuse of the CopyData buffers, when a pool is tracked
+    pub buffer_pool: Option<BufferPoolStats>,
+}
+
+/// Tracks lag against the server and reports it periodically
+#[derive(Debug)]
+pub struct LagStats {
+    report_interval: Duration,
+    server_wal_end: Lsn,
+    received_lsn: Lsn,
+    applied_lsn: Lsn,
+    send_delay: Duration,
+    events: u64,
+    last_report_time: Instant,
+    last_report_events: u64,
+    buffer_pool: Option<BufferPool>,
+    #[cfg(feature = "alloc-stats")]
+    allocations: crate::allocstats::AllocStats,
+}
+
+impl LagStats {
+    /// Create the tracker; a zero interval disables periodic reports
+    pub fn new(report_interval: Duration) -> Self {
+        Self {
+            report_interval,
+            server_wal_end: Lsn::INVALID,
+            received_lsn: Lsn::INVALID,
+            applied_lsn: Lsn::INVALID,
+            send_delay: Duration::ZERO,
+            events: 0,
+            last_report_time: Instant::now(),
+            last_report_events: 0,
+            buffer_pool: None,
+            #[cfg(feature = "alloc-stats")]
+            allocations: crate::allocstats::AllocStats::default(),
+        }
+    }
+
+    /// Include the pool's usage in snapshots and reports
+    pub fn track_buffer_pool(&mut self, pool: BufferPool) {
+        self.buffer_pool = Some(pool);
+    }
+
+    /// Record the server's WAL end and send time from a keepalive or XLogData header; a send
+    /// time out of range leaves the delay as it was
+    pub fn observe_server(&mut self, wal_end: Lsn, send_time: Option<DateTime<Utc>>) {
+        self.server_wal_end = std::cmp::max(self.server_wal_end, wal_end);
+        if let Some(send_time) = send_time {
+            self.send_delay = (Utc::now() - send_time).to_std().unwrap_or(Duration::ZERO);
+        }
+    }
+
+    pub fn observe_received(&mut self, lsn: Lsn) {
+        self.received_lsn = std::cmp::max(self.received_lsn, lsn);
+    }
+
+    pub fn observe_applied(&mut self, lsn: Lsn) {
+        self.applied_lsn = std::cmp::max(self.applied_lsn, lsn);
+    }
+
+    /// Count one processed change event
+    pub fn record_event(&mut self) {
+        self.events += 1;
+    }
+
+    /// Current lag, with the event rate measured since the previous report
+    pub fn snapshot(&self) -> LagSnapshot {
+        let elapsed = self.last_report_time.elapsed().as_secs_f64();
+        let events = self.events - self.last_report_events;
+        let events_per_sec = if elapsed > 0.0 {
+            events as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        LagSnapshot {
+            server_wal_end: self.server_wal_end,
+            received_bytes_behind: self.bytes_behind(self.received_lsn),
+            applied_bytes_behind: self.bytes_behind(self.applied_lsn),
+            seconds_behind: self.send_delay.as_secs_f64(),
+            events_per_sec,
+            buffer_pool: self.buffer_pool.as_ref().map(BufferPool::stats),
+        }
+    }
+
+    /// Bytes of WAL the stream is behind the server's WAL end, measured from the applied
+    /// position once one is known
+    pub fn bytes_behind_server(&self) -> u64 {
+        self.bytes_behind(self.applied_lsn)
+            .max(self.bytes_behind(self.received_lsn))
+    }
+
+    /// Distance to the server's WAL end, 0 until a position has been observed
+    fn bytes_behind(&self, lsn: Lsn) -> u64 {
+        if !lsn.is_valid() {
+            return 0;
+        }
+        self.server_wal_end.bytes_since(lsn)
+    }
+
+    /// Log the lag summary if the report interval elapsed and publish it to the health endpoint
+    pub fn maybe_report(&mut self, health: &HealthState) {
+        if self.report_interval.is_zero() || self.last_report_time.elapsed() < self.report_interval
+        {
+            return;
+        }
+
+        let snapshot = self.snapshot();
+        health.set_lag(snapshot);
+        info!(
+            received_bytes_behind = snapshot.received_bytes_behind,
+            applied_bytes_behind = snapshot.applied_bytes_behind,
+            seconds_behind = snapshot.seconds_behind,
+            events_per_sec = snapshot.events_per_sec,
+            "Lag: {} bytes behind received, {} bytes behind applied, {:.3}s behind, {:.1} events/s",
+            snapshot.received_bytes_behind,
+            snapshot.applied_bytes_behind,
+            snapshot.seconds_behind,
+            snapshot.events_per_sec
+        );
+        if let Some(pool) = snapshot.buffer_pool {
+            info!(
+                buffers_acquired = pool.acquired,
+                buffers_reused = pool.reused,
+                buffers_discarded = pool.discarded,
+                buffers_idle = pool.idle,
+                "Buffer pool: {} of {} CopyData buffers reused ({:.1}%), {} freed, {} idle",
+                pool.reused,
+                pool.acquired,
+                pool.reuse_ratio() * 100.0,
+                pool.discarded,
+                pool.idle
+            );
+        }
+        #[cfg(feature = "alloc-stats")]
+        self.allocations.report();
+
+        self.last_report_time = Instant::now();
+        self.last_report_events = self.events;
+    }
+}
+
+/// Values below this are counted exactly, larger ones in buckets of 1/64 of their power of two
+const EXACT_VALUES: u64 = 128;
+
+/// Histogram of microsecond delays with about 1.6% precision, in the manner of HdrHistogram
+#[derive(Debug, Clone, Default)]
+pub struct DelayHistogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum_micros: u64,
+    max_micros: u64,
+}
+
+impl DelayHistogram {
+    pub fn record(&mut self, delay: Duration) {
+        let micros = u64::try_from(delay.as_micros()).unwrap_or(u64::MAX);
+        let index = bucket_index(micros);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.total += 1;
+        self.sum_micros = self.sum_micros.saturating_add(micros);
+        self.max_micros = self.max_micros.max(micros);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros)
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_micros)
+    }
+
+    /// Delay at or below which the fraction `quantile` of the recorded delays fall, zero when
+    /// nothing was recorded
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        if self.total == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((quantile * self.total as f64).ceil() as u64).clamp(1, self.total);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(bucket_value(index).min(self.max_micros));
+            }
+        }
+        self.max()
+    }
+}
+
+/// Bucket of a value: exact below `EXACT_VALUES`, then 64 buckets per power of two
+fn bucket_index(value: u64) -> usize {
+    if value < EXACT_VALUES {
+        return value as usize;
+    }
+    // Keep the 7 most significant bits, the top one of which is always set
+    let shift = 63 - value.leading_zeros() - 6;
+    (shift as usize) * 64 + (value >> shift) as usize
+}
+
+/// Highest value counted in a bucket
+fn bucket_value(index: usize) -> u64 {
+    if (index as u64) < EXACT_VALUES {
+        return index as u64;
+    }
+    let shift = (index / 64 - 1) as u32;
+    let significant = (index % 64 + 64) as u64;
+    ((significant + 1) << shift) - 1
+}
+
+/// Commit-to-receive delay of one table's changes
+#[derive(Debug, Clone, Serialize)]
+pub struct TableDelaySnapshot {
+    pub table: String,
+    pub events: u64,
+    pub p50_seconds: f64,
+    pub p95_seconds: f64,
+    pub p99_seconds: f64,
+    pub max_seconds: f64,
+    pub sum_seconds: f64,
+}
+
+impl TableDelaySnapshot {
+    fn new(table: &str, histogram: &DelayHistogram) -> Self {
+        Self {
+            table: table.to_string(),
+            events: histogram.count(),
+            p50_seconds: histogram.percentile(0.50).as_secs_f64(),
+            p95_seconds: histogram.percentile(0.95).as_secs_f64(),
+            p99_seconds: histogram.percentile(0.99).as_secs_f64(),
+            max_seconds: histogram.max().as_secs_f64(),
+            sum_seconds: histogram.sum().as_secs_f64(),
+        }
+    }
+}
+
+/// Per-table delays since the previous report and since the start of the run
+#[derive(Debug, Default)]
+struct TableDelays {
+    interval: DelayHistogram,
+    total: DelayHistogram,
+}
+
+/// Tracks, per table, the delay between a transaction's commit time and the local time its
+/// changes are received, and reports percentiles periodically
+#[derive(Debug)]
+pub struct DelayStats {
+    report_interval: Duration,
+    tables: BTreeMap<String, TableDelays>,
+    last_report_time: Instant,
+}
+
+impl DelayStats {
+    /// Create the tracker; a zero interval disables periodic reports
+    pub fn new(report_interval: Duration) -> Self {
+        Self {
+            report_interval,
+            tables: BTreeMap::new(),
+            last_report_time: Instant::now(),
+        }
+    }
+
+    /// Record a change of `table` received now from a transaction committed at `commit_time`
+    pub fn record(&mut self, table: &str, commit_time: DateTime<Utc>) {
+        // Clock skew between the hosts can put the commit time in the future
+        let delay = (Utc::now() - commit_time)
+            .to_std()
+            .unwrap_or(Duration::ZERO);
+        if !self.tables.contains_key(table) {
+            self.tables
+                .insert(table.to_string(), TableDelays::default());
+        }
+        if let Some(delays) = self.tables.get_mut(table) {
+            delays.interval.record(delay);
+            delays.total.record(delay);
+        }
+    }
+
+    /// Delays of every table since the start of the run
+    pub fn snapshot(&self) -> Vec<TableDelaySnapshot> {
+        self.tables
+            .iter()
+            .map(|(table, delays)| TableDelaySnapshot::new(table, &delays.total))
+            .collect()
+    }
+
+    /// Log the throughput and delay percentiles of each table that changed since the previous
+    /// report, and publish the run's totals to the health endpoint
+    pub fn maybe_report(&mut self, health: &HealthState) {
+        if self.report_interval.is_zero() || self.last_report_time.elapsed() < self.report_interval
+        {
+            return;
+        }
+
+        let elapsed = self.last_report_time.elapsed().as_secs_f64();
+        for (table, delays) in &mut self.tables {
+            if delays.interval.count() == 0 {
+                continue;
+            }
+            let interval = TableDelaySnapshot::new(table, &delays.interval);
+            info!(
+                table = %table,
+                events_per_sec = interval.events as f64 / elapsed,
+                p50_seconds = interval.p50_seconds,
+                p95_seconds = interval.p95_seconds,
+                p99_seconds = interval.p99_seconds,
+                "Delay {}: {:.1} events/s, commit to receive p50 {:.3}s, p95 {:.3}s, p99 {:.3}s, max {:.3}s",
+                table,
+                interval.events as f64 / elapsed,
+                interval.p50_seconds,
+                interval.p95_seconds,
+                interval.p99_seconds,
+                interval.max_seconds
+            );
+            delays.interval = DelayHistogram::default();
+        }
+
+        health.set_table_delays(self.snapshot());
+        self.last_report_time = Instant::now();
+    }
+}
@@ -0,0 +1,93 @@
+//! Stop conditions for bounded replication captures
+//! Ends the stream after a number of change events, at a target LSN, or at a wall-clock time
+
+use crate::errors::ReplicationError;
+use crate::types::Lsn;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::time::Duration;
+
+/// Why the stream was stopped by a configured condition
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    MaxEvents(u64),
+    UntilLsn(Lsn),
+    UntilTime(DateTime<Utc>),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::MaxEvents(count) => write!(f, "processed {} change events", count),
+            StopReason::UntilLsn(lsn) => write!(f, "reached LSN {}", lsn),
+            StopReason::UntilTime(time) => write!(f, "reached stop time {}", time.to_rfc3339()),
+        }
+    }
+}
+
+/// Optional limits after which the stream sends final feedback and exits cleanly
+#[derive(Debug, Clone, Default)]
+pub struct StopConditions {
+    /// Stop after this many INSERT/UPDATE/DELETE/TRUNCATE events
+    pub max_events: Option<u64>,
+    /// Stop once WAL at or past this LSN has been received
+    pub until_lsn: Option<Lsn>,
+    /// Stop at this wall-clock time
+    pub until_time: Option<DateTime<Utc>>,
+}
+
+impl StopConditions {
+    /// Stop once the given duration has elapsed from now, keeping any earlier stop time
+    pub fn stop_after(&mut self, duration: Duration) -> crate::errors::Result<()> {
+        let deadline = chrono::Duration::from_std(duration)
+            .ok()
+            .and_then(|duration| Utc::now().checked_add_signed(duration))
+            .ok_or_else(|| {
+                ReplicationError::config(format!(
+                    "Duration of {}s is too long for a stop time",
+                    duration.as_secs()
+                ))
+            })?;
+        self.until_time = Some(match self.until_time {
+            Some(until_time) => until_time.min(deadline),
+            None => deadline,
+        });
+        Ok(())
+    }
+
+    /// Returns true when no stop condition is configured
+    pub fn is_empty(&self) -> bool {
+        self.max_events.is_none() && self.until_lsn.is_none() && self.until_time.is_none()
+    }
+
+    /// Check the conditions against the events processed and the last received WAL position
+    pub fn check(&self, events_processed: u64, received_lsn: Lsn) -> Option<StopReason> {
+        if let Some(max_events) = self.max_events {
+            if events_processed >= max_events {
+                return Some(StopReason::MaxEvents(events_processed));
+            }
+        }
+
+        if let Some(until_lsn) = self.until_lsn {
+            if received_lsn >= until_lsn {
+                return Some(StopReason::UntilLsn(received_lsn));
+            }
+        }
+
+        if let Some(until_time) = self.until_time {
+            if Utc::now() >= until_time {
+                return Some(StopReason::UntilTime(until_time));
+            }
+        }
+
+        None
+    }
+}
+
+/// Parse an RFC 3339 stop time such as "2024-12-26T18:00:00Z"
+pub fn parse_stop_time(value: &str) -> Result<DateTime<Utc>> {
+    let time = DateTime::parse_from_rfc3339(value.trim())
+        .with_context(|| format!("Invalid stop time, expected RFC 3339: {}", value))?;
+    Ok(time.with_timezone(&Utc))
+}
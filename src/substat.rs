@@ -0,0 +1,97 @@
+//! Periodic report of this subscriber's own statistics, in the fields of pg_stat_subscription
+//! The values come from the keepalive and XLogData headers, the same way the server's logical
+//! replication workers update them, so the report lines up with the server-side views
+
+use crate::types::Lsn;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Point-in-time view of the subscription statistics
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SubscriptionSnapshot {
+    /// Last WAL end received from the server, in a keepalive or XLogData header
+    pub received_lsn: Lsn,
+    /// Send time of the last message received from the server
+    pub last_msg_send_time: Option<DateTime<Utc>>,
+    /// Receipt time of the last message received from the server
+    pub last_msg_receipt_time: Option<DateTime<Utc>>,
+    /// WAL end reported by the server in the last keepalive
+    pub latest_end_lsn: Lsn,
+    /// Send time of the last keepalive
+    pub latest_end_time: Option<DateTime<Utc>>,
+}
+
+/// Tracks the pg_stat_subscription fields and logs them periodically
+#[derive(Debug)]
+pub struct SubscriptionStats {
+    report_interval: Duration,
+    snapshot: SubscriptionSnapshot,
+    last_report_time: Instant,
+}
+
+impl SubscriptionStats {
+    /// Create the tracker; a zero interval disables periodic reports
+    pub fn new(report_interval: Duration) -> Self {
+        Self {
+            report_interval,
+            snapshot: SubscriptionSnapshot::default(),
+            last_report_time: Instant::now(),
+        }
+    }
+
+    /// Record the header of an XLogData message, whose send time is None when out of range
+    pub fn observe_message(&mut self, wal_end: Lsn, send_time: Option<DateTime<Utc>>) {
+        self.snapshot.received_lsn = std::cmp::max(self.snapshot.received_lsn, wal_end);
+        self.snapshot.last_msg_send_time = send_time;
+        self.snapshot.last_msg_receipt_time = Some(Utc::now());
+    }
+
+    /// Record a keepalive, which also moves the latest end position like it does on the server
+    pub fn observe_keepalive(&mut self, wal_end: Lsn, send_time: Option<DateTime<Utc>>) {
+        self.observe_message(wal_end, send_time);
+        self.snapshot.latest_end_lsn = wal_end;
+        self.snapshot.latest_end_time = send_time;
+    }
+
+    pub fn snapshot(&self) -> SubscriptionSnapshot {
+        self.snapshot
+    }
+
+    /// Log the statistics as one line if the report interval elapsed
+    pub fn maybe_report(&mut self) {
+        if self.report_interval.is_zero() || self.last_report_time.elapsed() < self.report_interval
+        {
+            return;
+        }
+
+        let snapshot = self.snapshot;
+        let received_lsn = snapshot.received_lsn;
+        let latest_end_lsn = snapshot.latest_end_lsn;
+        let last_msg_send_time = format_time(snapshot.last_msg_send_time);
+        let last_msg_receipt_time = format_time(snapshot.last_msg_receipt_time);
+        let latest_end_time = format_time(snapshot.latest_end_time);
+        info!(
+            received_lsn = %received_lsn,
+            last_msg_send_time = %last_msg_send_time,
+            last_msg_receipt_time = %last_msg_receipt_time,
+            latest_end_lsn = %latest_end_lsn,
+            latest_end_time = %latest_end_time,
+            "Subscription: received_lsn {}, last_msg_send_time {}, last_msg_receipt_time {}, latest_end_lsn {}, latest_end_time {}",
+            received_lsn,
+            last_msg_send_time,
+            last_msg_receipt_time,
+            latest_end_lsn,
+            latest_end_time
+        );
+
+        self.last_report_time = Instant::now();
+    }
+}
+
+/// RFC 3339 time with microseconds, or "null" before anything was received
+fn format_time(time: Option<DateTime<Utc>>) -> String {
+    time.map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Micros, true))
+        .unwrap_or_else(|| "null".to_string())
+}
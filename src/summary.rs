@@ -0,0 +1,186 @@
+//! Run statistics reported when the stream shuts down
+//! Collects transaction and per-table change counts, WAL volume and commit lag over the whole run
+
+use crate::types::Lsn;
+use crate::watch::{ChangeKind, TableCounts};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Final report of a run
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub started_at: DateTime<Utc>,
+    pub duration_secs: f64,
+    /// Committed transactions, streamed ones included
+    pub transactions: u64,
+    /// Large transactions that were streamed before they committed
+    pub streamed_transactions: u64,
+    /// WAL bytes between the first and last received positions
+    pub wal_bytes: u64,
+    /// Largest delay between a commit and it being processed here
+    pub max_lag_ms: u64,
+    /// Column values cut to the configured maximum size
+    pub truncated_values: u64,
+    /// Changes ignored because another origin replicated them to the server
+    pub foreign_origin_changes: u64,
+    /// Times the server was found on a new timeline when reconnecting
+    pub timeline_switches: u64,
+    pub tables: BTreeMap<String, TableCounts>,
+}
+
+/// Collects statistics over the whole run
+#[derive(Debug)]
+pub struct RunSummary {
+    started: Instant,
+    started_at: DateTime<Utc>,
+    transactions: u64,
+    streamed_transactions: u64,
+    first_lsn: Lsn,
+    last_lsn: Lsn,
+    max_lag: Duration,
+    truncated_values: u64,
+    foreign_origin_changes: u64,
+    timeline_switches: u64,
+    tables: BTreeMap<String, TableCounts>,
+}
+
+impl RunSummary {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            started_at: Utc::now(),
+            transactions: 0,
+            streamed_transactions: 0,
+            first_lsn: Lsn::INVALID,
+            last_lsn: Lsn::INVALID,
+            max_lag: Duration::ZERO,
+            truncated_values: 0,
+            foreign_origin_changes: 0,
+            timeline_switches: 0,
+            tables: BTreeMap::new(),
+        }
+    }
+
+    /// Record the position of WAL data received from the server
+    pub fn record_position(&mut self, lsn: Lsn) {
+        if !lsn.is_valid() {
+            return;
+        }
+        if !self.first_lsn.is_valid() {
+            self.first_lsn = lsn;
+        }
+        self.last_lsn = std::cmp::max(self.last_lsn, lsn);
+    }
+
+    /// Count a change for the given schema-qualified table name
+    pub fn record_change(&mut self, table: &str, kind: ChangeKind) {
+        self.tables
+            .entry(table.to_string())
+            .or_default()
+            .record(kind);
+    }
+
+    /// Count the first block of a streamed transaction
+    pub fn record_streamed_transaction(&mut self) {
+        self.streamed_transactions += 1;
+    }
+
+    /// Count column values that were truncated
+    pub fn record_truncated_values(&mut self, count: usize) {
+        self.truncated_values += count as u64;
+    }
+
+    /// Count a change ignored because it was replicated from another origin
+    pub fn record_foreign_origin_change(&mut self) {
+        self.foreign_origin_changes += 1;
+    }
+
+    pub fn record_timeline_switch(&mut self) {
+        self.timeline_switches += 1;
+    }
+
+    /// Record a commit and the delay between the commit time and now
+    pub fn record_commit(&mut self, commit_time: DateTime<Utc>) {
+        self.transactions += 1;
+        let lag = (Utc::now() - commit_time)
+            .to_std()
+            .unwrap_or(Duration::ZERO);
+        self.max_lag = self.max_lag.max(lag);
+    }
+
+    pub fn report(&self) -> RunReport {
+        RunReport {
+            started_at: self.started_at,
+            duration_secs: self.started.elapsed().as_secs_f64(),
+            transactions: self.transactions,
+            streamed_transactions: self.streamed_transactions,
+            wal_bytes: self.last_lsn.bytes_since(self.first_lsn),
+            max_lag_ms: self.max_lag.as_millis() as u64,
+            truncated_values: self.truncated_values,
+            foreign_origin_changes: self.foreign_origin_changes,
+            timeline_switches: self.timeline_switches,
+            tables: self.tables.clone(),
+        }
+    }
+
+    /// Log the final report, with the totals as structured fields
+    pub fn log_summary(&self) {
+        let report = self.report();
+        info!(
+            transactions = report.transactions,
+            streamed_transactions = report.streamed_transactions,
+            wal_bytes = report.wal_bytes,
+            max_lag_ms = report.max_lag_ms,
+            duration_secs = report.duration_secs,
+            "Run summary: {} transactions ({} streamed), {} WAL bytes, max commit lag {}ms in {:.1}s",
+            report.transactions,
+            report.streamed_transactions,
+            report.wal_bytes,
+            report.max_lag_ms,
+            report.duration_secs
+        );
+        if report.truncated_values > 0 {
+            info!(
+                truncated_values = report.truncated_values,
+                "Truncated {} column values", report.truncated_values
+            );
+        }
+        if report.foreign_origin_changes > 0 {
+            info!(
+                foreign_origin_changes = report.foreign_origin_changes,
+                "Ignored {} changes replicated from other origins", report.foreign_origin_changes
+            );
+        }
+        if report.timeline_switches > 0 {
+            info!(
+                timeline_switches = report.timeline_switches,
+                "Followed {} timeline switches", report.timeline_switches
+            );
+        }
+
+        for (table, counts) in &report.tables {
+            info!(
+                table = table.as_str(),
+                inserts = counts.inserts,
+                updates = counts.updates,
+                deletes = counts.deletes,
+                truncates = counts.truncates,
+                "  {}: {} inserts, {} updates, {} deletes, {} truncates",
+                table,
+                counts.inserts,
+                counts.updates,
+                counts.deletes,
+                counts.truncates
+            );
+        }
+    }
+}
+
+impl Default for RunSummary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,115 @@
+//! Rate limiting of event processing
+//! Token buckets refilled at the configured events and bytes per second hold back the
+//! processing stage, so downstream sinks see a bounded rate. Only processing waits: the
+//! connection keeps being serviced, so the server does not time it out while throttled.
+
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Most change events and pgoutput bytes processed per second
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimits {
+    pub max_events_per_sec: Option<u64>,
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+/// Tokens refilled at a fixed rate up to one second's worth; spending may run into debt,
+/// which is paid back before anything more is processed
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        Self { rate, tokens: rate }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+    }
+
+    /// Time until the debt is paid back
+    fn wait(&self) -> Duration {
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+}
+
+/// Holds back processing once the events or bytes processed exceed their rate
+#[derive(Debug)]
+pub struct Throttle {
+    events: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    last_refill: Instant,
+    /// Since when processing has been held back, if it is
+    throttled_since: Option<Instant>,
+    throttled_time: Duration,
+}
+
+impl Throttle {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            events: limits.max_events_per_sec.map(TokenBucket::new),
+            bytes: limits.max_bytes_per_sec.map(TokenBucket::new),
+            last_refill: Instant::now(),
+            throttled_since: None,
+            throttled_time: Duration::ZERO,
+        }
+    }
+
+    /// How long to wait before processing the next message, None when it may be processed now
+    pub fn wait(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        self.last_refill = now;
+        for bucket in self.events.iter_mut().chain(self.bytes.iter_mut()) {
+            bucket.refill(elapsed);
+        }
+
+        let wait = self
+            .events
+            .iter()
+            .chain(self.bytes.iter())
+            .map(TokenBucket::wait)
+            .max()
+            .unwrap_or_default();
+        if wait.is_zero() {
+            if let Some(since) = self.throttled_since.take() {
+                self.throttled_time += now.duration_since(since);
+            }
+            return None;
+        }
+        self.throttled_since.get_or_insert(now);
+        Some(wait)
+    }
+
+    /// Spend the tokens of a processed message
+    pub fn consume(&mut self, events: u64, bytes: usize) {
+        if let Some(bucket) = self.events.as_mut() {
+            bucket.tokens -= events as f64;
+        }
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.tokens -= bytes as f64;
+        }
+    }
+
+    pub fn log_summary(&self) {
+        let throttled = self.throttled_time
+            + self
+                .throttled_since
+                .map_or(Duration::ZERO, |since| since.elapsed());
+        if throttled.is_zero() {
+            return;
+        }
+        info!(
+            throttled_secs = throttled.as_secs_f64(),
+            "Rate limit: processing held back for {:.1}s",
+            throttled.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Server timelines
+//! IDENTIFY_SYSTEM reports the timeline the server is on and TIMELINE_HISTORY the switch points
+//! that led to it, so a promotion between two connections is recognised and where the new
+//! timeline branched off is known.
+
+use crate::errors::{ReplicationError, Result};
+use crate::types::Lsn;
+use crate::utils::PGConnection;
+use tracing::{info, warn};
+
+/// The server as reported by IDENTIFY_SYSTEM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemIdentity {
+    pub system_id: String,
+    pub timeline: u32,
+    /// Current end of WAL on the server
+    pub xlogpos: Lsn,
+    /// Database of a logical replication connection
+    pub dbname: Option<String>,
+}
+
+/// Run IDENTIFY_SYSTEM on a replication connection
+pub fn identify_system(connection: &PGConnection) -> Result<SystemIdentity> {
+    let result = connection.exec("IDENTIFY_SYSTEM").map_err(|e| {
+        ReplicationError::protocol(format!("IDENTIFY_SYSTEM command failed: {}", e))
+    })?;
+    if !result.is_ok() || result.ntuples() == 0 {
+        return Err(ReplicationError::protocol(format!(
+            "IDENTIFY_SYSTEM failed: status: {:?}, rows: {}, columns: {}. This usually means the connection is not in replication mode or lacks replication privileges.",
+            result.status(),
+            result.ntuples(),
+            result.nfields()
+        )));
+    }
+    Ok(SystemIdentity {
+        system_id: result.getvalue(0, 0).unwrap_or_default(),
+        timeline: parse_timeline(result.getvalue(0, 1).as_deref())?,
+        xlogpos: result.getvalue(0, 2).unwrap_or_default().parse()?,
+        dbname: result.getvalue(0, 3),
+    })
+}
+
+/// A timeline the history of a later one branched off from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimelineHistoryEntry {
+    pub timeline: u32,
+    /// Where the timeline ended and its successor began
+    pub switchpoint: Lsn,
+    pub reason: String,
+}
+
+/// The ancestors of a timeline, oldest first, from its timeline history file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TimelineHistory {
+    pub timeline: u32,
+    pub entries: Vec<TimelineHistoryEntry>,
+}
+
+impl TimelineHistory {
+    /// Fetch the history file of `timeline` with TIMELINE_HISTORY; timeline 1 has none
+    pub fn fetch(connection: &PGConnection, timeline: u32) -> Result<Self> {
+        if timeline <= 1 {
+            return Ok(Self {
+                timeline,
+                entries: Vec::new(),
+            });
+        }
+        let result = connection.exec(&format!("TIMELINE_HISTORY {}", timeline))?;
+        if !result.is_ok() || result.ntuples() == 0 {
+            return Err(ReplicationError::protocol(format!(
+                "TIMELINE_HISTORY {} failed: {}",
+                timeline,
+                result.error_message()
+            )));
+        }
+        Self::parse(timeline, &result.getvalue(0, 1).unwrap_or_default())
+    }
+
+    /// Parse a history file: one `timeline<TAB>switchpoint<TAB>reason` line per ancestor,
+    /// with blank lines and `#` comments ignored
+    pub fn parse(timeline: u32, content: &str) -> Result<Self> {
+        let entries = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let mut fields = line.splitn(3, char::is_whitespace);
+                let ancestor = parse_timeline(fields.next())?;
+                let switchpoint = fields.next().unwrap_or_default().parse()?;
+                Ok(TimelineHistoryEntry {
+                    timeline: ancestor,
+                    switchpoint,
+                    reason: fields.next().unwrap_or_default().trim().to_string(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { timeline, entries })
+    }
+
+    /// Where `timeline` ended, when it is an ancestor of this one
+    pub fn switchpoint(&self, timeline: u32) -> Option<Lsn> {
+        self.entries
+            .iter()
+            .find(|entry| entry.timeline == timeline)
+            .map(|entry| entry.switchpoint)
+    }
+}
+
+/// A change of the server's timeline, after a promotion or failover
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimelineSwitch {
+    pub from: u32,
+    pub to: u32,
+    /// Where `from` ended, when the history of `to` has it
+    pub switchpoint: Option<Lsn>,
+    pub reason: Option<String>,
+}
+
+impl TimelineSwitch {
+    /// Look the switch from `from` to `to` up in the history of `to`; a missing history only
+    /// leaves the switch point unknown
+    pub fn resolve(connection: &PGConnection, from: u32, to: u32) -> Self {
+        let history = TimelineHistory::fetch(connection, to)
+            .inspect_err(|e| warn!("Failed to read the history of timeline {}: {}", to, e))
+            .unwrap_or_default();
+        let entry = history.entries.iter().find(|entry| entry.timeline == from);
+        Self {
+            from,
+            to,
+            switchpoint: entry.map(|entry| entry.switchpoint),
+            reason: entry.map(|entry| entry.reason.clone()),
+        }
+    }
+
+    pub fn log(&self) {
+        let switchpoint = self
+            .switchpoint
+            .map_or_else(|| "an unknown position".to_string(), |lsn| lsn.to_string());
+        warn!(
+            event = "timeline_switch",
+            from_timeline = self.from,
+            to_timeline = self.to,
+            switchpoint = %switchpoint,
+            "Server switched from timeline {} to timeline {} at {}",
+            self.from,
+            self.to,
+            switchpoint
+        );
+        if let Some(reason) = self.reason.as_deref().filter(|reason| !reason.is_empty()) {
+            info!("Reason recorded for timeline {}: {}", self.to, reason);
+        }
+    }
+}
+
+pub fn parse_timeline(value: Option<&str>) -> Result<u32> {
+    value
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|timeline| *timeline > 0)
+        .ok_or_else(|| ReplicationError::protocol(format!("Invalid timeline: {:?}", value)))
+}
@@ -0,0 +1,106 @@
+//! TLS options of the database connection
+//! Validates sslmode and the certificate files and appends them to the libpq connection string,
+//! which may be in key/value or URI form
+
+use crate::conninfo::append_options;
+use crate::errors::{ReplicationError, Result};
+use std::path::{Path, PathBuf};
+
+/// Values libpq accepts for sslmode
+pub const SSL_MODES: [&str; 6] = [
+    "disable",
+    "allow",
+    "prefer",
+    "require",
+    "verify-ca",
+    "verify-full",
+];
+
+/// TLS settings given outside the connection string
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    pub sslmode: Option<String>,
+    /// CA certificates the server certificate is verified against
+    pub sslrootcert: Option<PathBuf>,
+    /// Client certificate, requires `sslkey`
+    pub sslcert: Option<PathBuf>,
+    /// Private key of the client certificate, requires `sslcert`
+    pub sslkey: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn is_empty(&self) -> bool {
+        self.sslmode.is_none()
+            && self.sslrootcert.is_none()
+            && self.sslcert.is_none()
+            && self.sslkey.is_none()
+    }
+
+    /// Check the mode and that the certificate files exist
+    pub fn validate(&self) -> Result<()> {
+        if let Some(sslmode) = &self.sslmode {
+            if !SSL_MODES.contains(&sslmode.as_str()) {
+                return Err(ReplicationError::config(format!(
+                    "Invalid sslmode: {}. Valid values are: {}",
+                    sslmode,
+                    SSL_MODES.join(", ")
+                )));
+            }
+        }
+
+        if self.sslcert.is_some() != self.sslkey.is_some() {
+            return Err(ReplicationError::config(
+                "sslcert and sslkey must be given together",
+            ));
+        }
+
+        let has_files =
+            self.sslrootcert.is_some() || self.sslcert.is_some() || self.sslkey.is_some();
+        if has_files && self.sslmode.as_deref() == Some("disable") {
+            return Err(ReplicationError::config(
+                "Certificate options have no effect with sslmode=disable",
+            ));
+        }
+
+        for (option, path) in self.files() {
+            if !path.is_file() {
+                return Err(ReplicationError::config(format!(
+                    "{} file {} does not exist",
+                    option,
+                    path.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Append the options to a connection string, refusing options it already sets
+    pub fn apply(&self, conninfo: &str) -> Result<String> {
+        if self.is_empty() {
+            return Ok(conninfo.to_string());
+        }
+        self.validate()?;
+        append_options(conninfo, &self.options())
+    }
+
+    fn files(&self) -> impl Iterator<Item = (&'static str, &Path)> {
+        [
+            ("sslrootcert", &self.sslrootcert),
+            ("sslcert", &self.sslcert),
+            ("sslkey", &self.sslkey),
+        ]
+        .into_iter()
+        .filter_map(|(option, path)| path.as_deref().map(|path| (option, path)))
+    }
+
+    fn options(&self) -> Vec<(&'static str, String)> {
+        self.sslmode
+            .iter()
+            .map(|sslmode| ("sslmode", sslmode.clone()))
+            .chain(
+                self.files()
+                    .map(|(option, path)| (option, path.to_string_lossy().into_owned())),
+            )
+            .collect()
+    }
+}
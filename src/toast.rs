@@ -0,0 +1,173 @@
+//! Backfill of unchanged TOAST values
+//! An UPDATE that leaves a TOASTed column untouched sends it as unchanged ('u') without its
+//! value. The value is taken from the old tuple when the table has REPLICA IDENTITY FULL, and
+//! otherwise from a per-table cache of the last row seen for each replica identity key
+
+use crate::types::{ColumnData, RelationInfo, TupleData};
+use crate::utils::Oid;
+use std::collections::{BTreeMap, HashMap};
+use tracing::info;
+
+/// Shown in place of an unchanged TOAST value that could not be backfilled
+pub const UNCHANGED_TOAST_MARKER: &str = "<unchanged toast: unavailable>";
+
+/// Replica identity key values of a row
+type RowKey = Vec<Option<Vec<u8>>>;
+
+/// Last rows of one table, evicted least recently used first
+#[derive(Debug, Default)]
+struct TableRows {
+    rows: HashMap<RowKey, (u64, Vec<ColumnData>)>,
+    /// Keys by the tick they were last used at
+    order: BTreeMap<u64, RowKey>,
+}
+
+impl TableRows {
+    fn remove(&mut self, key: &RowKey) -> Option<Vec<ColumnData>> {
+        let (tick, columns) = self.rows.remove(key)?;
+        self.order.remove(&tick);
+        Some(columns)
+    }
+}
+
+/// Last-seen row values per table, keyed by replica identity
+#[derive(Debug)]
+pub struct ToastCache {
+    /// Most rows kept per table
+    max_rows: usize,
+    tables: HashMap<Oid, TableRows>,
+    clock: u64,
+    backfilled: u64,
+    unavailable: u64,
+}
+
+impl ToastCache {
+    pub fn new(max_rows: usize) -> Self {
+        Self {
+            max_rows: max_rows.max(1),
+            tables: HashMap::new(),
+            clock: 0,
+            backfilled: 0,
+            unavailable: 0,
+        }
+    }
+
+    /// Remember the row an INSERT created
+    pub fn record_insert(&mut self, relation: &RelationInfo, tuple_data: &TupleData) {
+        if let Some(key) = row_key(relation, tuple_data) {
+            self.store(relation.oid, key, tuple_data);
+        }
+    }
+
+    /// Fill the unchanged TOAST values of an UPDATE's new tuple from the old tuple or the
+    /// cached row, then remember the new row
+    pub fn backfill_update(
+        &mut self,
+        relation: &RelationInfo,
+        old_tuple_data: Option<&TupleData>,
+        new_tuple_data: &mut TupleData,
+    ) {
+        // The row is cached under its old key when the update changed the key
+        let old_key = old_tuple_data.and_then(|old| row_key(relation, old));
+        let new_key = row_key(relation, new_tuple_data);
+        let cached = old_key
+            .as_ref()
+            .or(new_key.as_ref())
+            .and_then(|key| self.tables.get_mut(&relation.oid)?.remove(key));
+
+        // Only a full old tuple holds the other columns; a key tuple sends them as NULL
+        let full_old_tuple = old_tuple_data.filter(|_| relation.replica_identity == 'f');
+        for (index, column) in new_tuple_data.columns.iter_mut().enumerate() {
+            if column.data_type != 'u' {
+                continue;
+            }
+            let previous = full_old_tuple
+                .and_then(|old| old.columns.get(index))
+                .filter(|old| old.data_type != 'u')
+                .or_else(|| cached.as_ref().and_then(|row| row.get(index)));
+            match previous {
+                Some(previous) => {
+                    *column = previous.clone();
+                    self.backfilled += 1;
+                }
+                None => self.unavailable += 1,
+            }
+        }
+
+        if let Some(key) = new_key {
+            self.store(relation.oid, key, new_tuple_data);
+        }
+    }
+
+    /// Forget the row a DELETE removed
+    pub fn record_delete(&mut self, relation: &RelationInfo, tuple_data: &TupleData) {
+        let Some(key) = row_key(relation, tuple_data) else {
+            return;
+        };
+        if let Some(table) = self.tables.get_mut(&relation.oid) {
+            table.remove(&key);
+        }
+    }
+
+    /// Forget every row of a table, after a TRUNCATE or when its columns change
+    pub fn clear_table(&mut self, oid: Oid) {
+        self.tables.remove(&oid);
+    }
+
+    pub fn log_summary(&self) {
+        info!(
+            backfilled = self.backfilled,
+            unavailable = self.unavailable,
+            "Unchanged TOAST values: {} backfilled, {} unavailable",
+            self.backfilled,
+            self.unavailable
+        );
+    }
+
+    /// Cache a row, unless one of its values is itself unknown
+    fn store(&mut self, oid: Oid, key: RowKey, tuple_data: &TupleData) {
+        if tuple_data
+            .columns
+            .iter()
+            .any(|column| column.data_type == 'u')
+        {
+            if let Some(table) = self.tables.get_mut(&oid) {
+                table.remove(&key);
+            }
+            return;
+        }
+
+        self.clock += 1;
+        let tick = self.clock;
+        let table = self.tables.entry(oid).or_default();
+        table.remove(&key);
+        table.order.insert(tick, key.clone());
+        table.rows.insert(key, (tick, tuple_data.columns.clone()));
+        while table.rows.len() > self.max_rows {
+            let Some((_, oldest)) = table.order.pop_first() else {
+                break;
+            };
+            table.rows.remove(&oldest);
+        }
+    }
+}
+
+/// Values of the replica identity key columns, None when the table has no key or a key value
+/// is missing; with REPLICA IDENTITY FULL the old tuple carries the values instead
+fn row_key(relation: &RelationInfo, tuple_data: &TupleData) -> Option<RowKey> {
+    if relation.replica_identity == 'f' {
+        return None;
+    }
+    let key: RowKey = relation
+        .columns
+        .iter()
+        .zip(tuple_data.columns.iter())
+        .filter(|(column, _)| column.key_flag & 1 != 0)
+        .map(|(_, value)| match value.data_type {
+            'n' => Some(None),
+            'u' => None,
+            _ => Some(Some(value.data.clone())),
+        })
+        .collect::<Option<_>>()?;
+    (!key.is_empty()).then_some(key)
+}
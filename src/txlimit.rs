@@ -0,0 +1,154 @@
+//! Per-transaction size limits
+//! Counts the row changes and pgoutput bytes the server sends for each transaction. Once a
+//! transaction exceeds the row or byte limit, its remaining changes are dropped before they
+//! are buffered or emitted, so a bulk load on the source cannot flood the output or the
+//! transaction assembler; alternatively the run stops with an error.
+
+use crate::errors::{ReplicationError, Result};
+use crate::utils::Xid;
+use std::collections::HashMap;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+/// What happens to the changes of a transaction past its limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LimitAction {
+    /// Drop the remaining changes and log a summary line of what was left out at commit
+    #[default]
+    Truncate,
+    /// Drop the remaining changes, warning once when the limit is crossed
+    Skip,
+    /// Stop the run with an error
+    Abort,
+}
+
+impl FromStr for LimitAction {
+    type Err = ReplicationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "truncate" => Ok(LimitAction::Truncate),
+            "skip" => Ok(LimitAction::Skip),
+            "abort" => Ok(LimitAction::Abort),
+            _ => Err(ReplicationError::config(format!(
+                "Invalid transaction limit action: {}. Valid values are: truncate, skip, abort",
+                s
+            ))),
+        }
+    }
+}
+
+/// Largest transaction emitted in full and what happens to larger ones
+#[derive(Debug, Clone, Default)]
+pub struct TransactionLimits {
+    /// Most INSERT/UPDATE/DELETE changes emitted per transaction
+    pub max_rows: Option<u64>,
+    /// Most pgoutput bytes of changes emitted per transaction
+    pub max_bytes: Option<u64>,
+    pub action: LimitAction,
+}
+
+/// Changes received for an open transaction
+#[derive(Debug, Default)]
+struct TransactionSize {
+    rows: u64,
+    bytes: u64,
+    dropped_rows: u64,
+    dropped_bytes: u64,
+}
+
+/// Applies the limits to the changes of open transactions
+#[derive(Debug)]
+pub struct TransactionLimiter {
+    limits: TransactionLimits,
+    open: HashMap<Xid, TransactionSize>,
+    limited_transactions: u64,
+    dropped_rows: u64,
+}
+
+impl TransactionLimiter {
+    pub fn new(limits: TransactionLimits) -> Self {
+        Self {
+            limits,
+            open: HashMap::new(),
+            limited_transactions: 0,
+            dropped_rows: 0,
+        }
+    }
+
+    /// Count a change of `bytes` for a transaction, returning whether it is emitted
+    pub fn admit(&mut self, xid: Xid, bytes: usize) -> Result<bool> {
+        let bytes = bytes as u64;
+        let size = self.open.entry(xid).or_default();
+        if size.dropped_rows > 0 {
+            size.dropped_rows += 1;
+            size.dropped_bytes += bytes;
+            self.dropped_rows += 1;
+            return Ok(false);
+        }
+
+        let rows = size.rows + 1;
+        let total_bytes = size.bytes + bytes;
+        let exceeded = match (self.limits.max_rows, self.limits.max_bytes) {
+            (Some(max_rows), _) if rows > max_rows => format!("{} rows", max_rows),
+            (_, Some(max_bytes)) if total_bytes > max_bytes => format!("{} bytes", max_bytes),
+            _ => {
+                size.rows = rows;
+                size.bytes = total_bytes;
+                return Ok(true);
+            }
+        };
+
+        self.limited_transactions += 1;
+        match self.limits.action {
+            LimitAction::Abort => {
+                return Err(ReplicationError::transaction_limit(format!(
+                    "Transaction {} exceeds the limit of {}",
+                    xid, exceeded
+                )))
+            }
+            LimitAction::Skip => warn!(
+                "Transaction {} exceeds the limit of {}, skipping its remaining changes",
+                xid, exceeded
+            ),
+            LimitAction::Truncate => {}
+        }
+        size.dropped_rows = 1;
+        size.dropped_bytes = bytes;
+        self.dropped_rows += 1;
+        Ok(false)
+    }
+
+    /// Forget a transaction that committed or aborted, summarizing a truncated one
+    pub fn finish(&mut self, xid: Xid) {
+        let Some(size) = self.open.remove(&xid) else {
+            return;
+        };
+        if size.dropped_rows > 0 && self.limits.action == LimitAction::Truncate {
+            info!(
+                xid,
+                emitted_rows = size.rows,
+                dropped_rows = size.dropped_rows,
+                dropped_bytes = size.dropped_bytes,
+                "Transaction {} truncated: {} changes shown, {} changes ({} bytes) left out",
+                xid,
+                size.rows,
+                size.dropped_rows,
+                size.dropped_bytes
+            );
+        }
+    }
+
+    pub fn log_summary(&self) {
+        if self.limited_transactions == 0 {
+            return;
+        }
+        info!(
+            limited_transactions = self.limited_transactions,
+            dropped_rows = self.dropped_rows,
+            "Transaction limits: {} transactions over the limit, {} changes dropped",
+            self.limited_transactions,
+            self.dropped_rows
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Tracing spans around transactions
+//! Each transaction gets a `transaction` span from Begin, or the first StreamStart of a streamed
+//! transaction, to its Commit, abort or prepare, and the log lines of its changes are events
+//! within it, so JSON logs and other subscribers can group them. The span carries the xid from
+//! the start; the commit LSN, the tables changed and the row counts are recorded when it ends.
+
+use crate::types::Lsn;
+use crate::utils::Xid;
+use crate::watch::ChangeKind;
+use std::collections::{BTreeSet, HashMap};
+use tracing::field::{display, Empty};
+use tracing::{info_span, Span};
+
+/// A transaction's span and what it changed so far
+struct TransactionSpan {
+    span: Span,
+    tables: BTreeSet<String>,
+    inserts: u64,
+    updates: u64,
+    deletes: u64,
+    truncates: u64,
+    /// Streamed blocks received, 0 for a transaction sent at commit
+    segments: u32,
+}
+
+impl TransactionSpan {
+    fn new(xid: Xid, streamed: bool, source: Option<&str>) -> Self {
+        let span = info_span!(
+            "transaction",
+            xid,
+            streamed,
+            final_lsn = Empty,
+            commit_lsn = Empty,
+            end_lsn = Empty,
+            outcome = Empty,
+            segments = Empty,
+            tables = Empty,
+            rows = Empty,
+            inserts = Empty,
+            updates = Empty,
+            deletes = Empty,
+            truncates = Empty,
+        );
+        // The `source` span is not exported, so the exported span names the source itself
+        #[cfg(feature = "otlp")]
+        if let Some(source) = source {
+            use tracing_opentelemetry::OpenTelemetrySpanExt;
+            span.set_attribute("source.name", source.to_string());
+        }
+        #[cfg(not(feature = "otlp"))]
+        let _ = source;
+        Self {
+            span,
+            tables: BTreeSet::new(),
+            inserts: 0,
+            updates: 0,
+            deletes: 0,
+            truncates: 0,
+            segments: 0,
+        }
+    }
+
+    /// Record the totals, before the log line that ends the transaction so it carries them
+    fn finish(self, outcome: &str) {
+        let tables = self.tables.into_iter().collect::<Vec<_>>().join(",");
+        self.span.record("outcome", outcome);
+        if self.segments > 0 {
+            self.span.record("segments", self.segments);
+        }
+        self.span.record("tables", tables.as_str());
+        self.span.record(
+            "rows",
+            self.inserts + self.updates + self.deletes + self.truncates,
+        );
+        self.span.record("inserts", self.inserts);
+        self.span.record("updates", self.updates);
+        self.span.record("deletes", self.deletes);
+        self.span.record("truncates", self.truncates);
+    }
+}
+
+/// Spans of the transactions in progress, keyed by top-level xid
+pub struct TransactionSpans {
+    open: HashMap<Xid, TransactionSpan>,
+    /// Name of the stream's source in a multi-source run
+    source: Option<String>,
+}
+
+impl TransactionSpans {
+    pub fn new(source: Option<String>) -> Self {
+        Self {
+            open: HashMap::new(),
+            source,
+        }
+    }
+
+    /// Open the span of a transaction sent at commit
+    pub fn begin(&mut self, xid: Xid, final_lsn: Option<Lsn>) {
+        let transaction = TransactionSpan::new(xid, false, self.source.as_deref());
+        if let Some(final_lsn) = final_lsn {
+            transaction.span.record("final_lsn", display(final_lsn));
+        }
+        self.open.insert(xid, transaction);
+    }
+
+    /// Count a streamed block, opening the transaction's span at its first one
+    pub fn start_segment(&mut self, xid: Xid) {
+        self.open
+            .entry(xid)
+            .or_insert_with(|| TransactionSpan::new(xid, true, self.source.as_deref()))
+            .segments += 1;
+    }
+
+    /// Span to enter while processing a message of the transaction
+    pub fn span(&self, xid: Option<Xid>) -> Option<Span> {
+        xid.and_then(|xid| self.open.get(&xid))
+            .map(|transaction| transaction.span.clone())
+    }
+
+    /// Count a row change, or a truncated table, of the transaction
+    pub fn record_change(&mut self, xid: Option<Xid>, table: &str, kind: ChangeKind) {
+        let Some(transaction) = xid.and_then(|xid| self.open.get_mut(&xid)) else {
+            return;
+        };
+        if !transaction.tables.contains(table) {
+            transaction.tables.insert(table.to_string());
+        }
+        match kind {
+            ChangeKind::Insert => transaction.inserts += 1,
+            ChangeKind::Update => transaction.updates += 1,
+            ChangeKind::Delete => transaction.deletes += 1,
+            ChangeKind::Truncate => transaction.truncates += 1,
+        }
+    }
+
+    /// Record how the transaction ended, `committed`, `aborted` or `prepared`, on its span, which
+    /// closes once it is no longer entered
+    pub fn end(&mut self, xid: Xid, outcome: &str, commit_lsn: Option<Lsn>, end_lsn: Option<Lsn>) {
+        let Some(transaction) = self.open.remove(&xid) else {
+            return;
+        };
+        if let Some(commit_lsn) = commit_lsn {
+            transaction.span.record("commit_lsn", display(commit_lsn));
+        }
+        if let Some(end_lsn) = end_lsn {
+            transaction.span.record("end_lsn", display(end_lsn));
+        }
+        transaction.finish(outcome);
+    }
+
+    /// Drop the spans of transactions the connection was lost in the middle of
+    pub fn clear(&mut self) {
+        self.open.clear();
+    }
+}
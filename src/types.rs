@@ -1,8 +1,116 @@
 //! Data structures for PostgreSQL logical replication
 //! Contains types for representing relation information, tuple data, and messages
 
+use crate::alert::AlertConfig;
+use crate::assembler::{AssemblerConfig, TransactionAssembler};
+use crate::avro::AvroConfig;
+use crate::datetime::DisplayTimeZone;
+use crate::envelope::EventTimestamps;
+use crate::filter::TableFilter;
+use crate::initsync::InitSyncConfig;
+use crate::mask::ColumnMask;
+use crate::output::{EventSchemaVersion, OutputConfig};
+#[cfg(feature = "parquet")]
+use crate::parquet::ParquetConfig;
+use crate::publication::PublishedColumnMask;
+use crate::reconnect::ReconnectPolicy;
+use crate::relcache::{RelationCache, RelationUpdate};
+use crate::rowfilter::RowFilter;
+#[cfg(feature = "s3")]
+use crate::s3::S3Config;
+use crate::sample::Sampling;
+use crate::sink::SinkConfig;
+use crate::slot::SlotMode;
+use crate::stop::StopConditions;
+use crate::throttle::RateLimits;
+use crate::txlimit::TransactionLimits;
 use crate::utils::{Oid, Xid};
-use std::collections::HashMap;
+use crate::validate::ValidationMode;
+use crate::walkeep::WalKeepConfig;
+use crate::webhook::WebhookConfig;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A WAL position, shown and parsed in PostgreSQL's "X/Y" hexadecimal notation
+///
+/// Serialized as the plain number, which keeps the checkpoint and commit log files readable by
+/// earlier versions.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Lsn(pub u64);
+
+impl Lsn {
+    /// The invalid position 0/0, also sent to the server for "not known yet"
+    pub const INVALID: Lsn = Lsn(0);
+
+    pub fn value(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_valid(self) -> bool {
+        self != Self::INVALID
+    }
+
+    /// WAL bytes from `earlier` up to this position, 0 when `earlier` is ahead
+    pub fn bytes_since(self, earlier: Lsn) -> u64 {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    /// The position `bytes` further into the WAL
+    pub fn advance(self, bytes: u64) -> Lsn {
+        Lsn(self.0.saturating_add(bytes))
+    }
+}
+
+impl From<u64> for Lsn {
+    fn from(lsn: u64) -> Self {
+        Lsn(lsn)
+    }
+}
+
+impl From<Lsn> for u64 {
+    fn from(lsn: Lsn) -> Self {
+        lsn.0
+    }
+}
+
+impl std::fmt::Display for Lsn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:X}/{:X}", self.0 >> 32, self.0 & 0xFFFF_FFFF)
+    }
+}
+
+impl FromStr for Lsn {
+    type Err = crate::errors::ReplicationError;
+
+    fn from_str(s: &str) -> crate::errors::Result<Self> {
+        let invalid = || {
+            crate::errors::ReplicationError::config(format!(
+                "Invalid LSN: {}. Expected the X/Y hexadecimal form, e.g. 0/16B3748",
+                s
+            ))
+        };
+        let (high, low) = s.trim().split_once('/').ok_or_else(invalid)?;
+        // from_str_radix also takes a sign, which pg_lsn does not
+        let half = |digits: &str| {
+            digits
+                .bytes()
+                .all(|b| b.is_ascii_hexdigit())
+                .then(|| u32::from_str_radix(digits, 16).ok())
+                .flatten()
+                .ok_or_else(invalid)
+        };
+        let (high, low) = (half(high)?, half(low)?);
+        Ok(Lsn((u64::from(high) << 32) | u64::from(low)))
+    }
+}
 
 /// Information about a table column
 #[derive(Debug)]
@@ -11,6 +119,8 @@ pub struct ColumnInfo {
     pub column_name: String,
     pub column_type: Oid,
     pub atttypmod: i32,
+    /// Type name with its modifier, e.g. varchar(255), when resolved from the catalog
+    pub type_name: Option<String>,
 }
 
 /// Information about a relation (table)
@@ -22,14 +132,65 @@ pub struct RelationInfo {
     pub replica_identity: char,
     pub column_count: i16,
     pub columns: Vec<ColumnInfo>,
+    /// The table definition's columns marked with whether this message sends them, when the
+    /// catalog can be queried
+    pub published_mask: Option<PublishedColumnMask>,
 }
 
-/// Data for a single column in a tuple
+/// Information about a user-defined data type
 #[derive(Debug)]
+pub struct TypeInfo {
+    pub oid: Oid,
+    pub namespace: String,
+    pub type_name: String,
+}
+
+/// Data for a single column in a tuple
+#[derive(Debug, Clone)]
 pub struct ColumnData {
-    pub data_type: char, // 'n' for null, 't' for text, 'u' for unchanged
+    pub data_type: char, // 'n' for null, 't' for text, 'b' for binary, 'u' for unchanged
+    /// Length of the value as sent, which `data` is shorter than when it was truncated
     pub length: i32,
-    pub data: String,
+    pub data: Vec<u8>,
+}
+
+impl ColumnData {
+    /// Text form of the value; binary values are decoded according to the column type
+    pub fn to_text(&self, type_oid: Oid) -> String {
+        match self.data_type {
+            'b' if self.is_truncated() => {
+                format!("[binary value truncated, {} bytes]", self.length)
+            }
+            'b' => crate::binary::to_text(type_oid, &self.data),
+            _ if self.is_truncated() => {
+                truncated_text(&String::from_utf8_lossy(&self.data), self.length as usize)
+            }
+            _ => String::from_utf8_lossy(&self.data).into_owned(),
+        }
+    }
+
+    /// Whether only the first bytes of the value were kept
+    pub fn is_truncated(&self) -> bool {
+        self.data.len() < self.length as usize
+    }
+}
+
+/// A value cut to its first bytes, followed by a marker with its full length
+pub fn truncated_text(prefix: &str, length: usize) -> String {
+    format!("{}...[truncated, {} bytes]", prefix, length)
+}
+
+/// The longest prefix of at most `max` bytes that does not split a UTF-8 character
+pub fn utf8_prefix(data: &[u8], max: usize) -> &[u8] {
+    if data.len() <= max {
+        return data;
+    }
+    let mut end = max;
+    // Continuation bytes have the form 10xxxxxx
+    while end > 0 && data[end] & 0xC0 == 0x80 {
+        end -= 1;
+    }
+    &data[..end]
 }
 
 /// Data for a complete row/tuple
@@ -44,19 +205,26 @@ pub struct TupleData {
 #[derive(Debug)]
 pub enum ReplicationMessage {
     Begin {
-        final_lsn: u64,
+        final_lsn: Lsn,
         timestamp: i64,
         xid: Xid,
     },
     Commit {
         flags: u8,
-        commit_lsn: u64,
-        end_lsn: u64,
+        commit_lsn: Lsn,
+        end_lsn: Lsn,
         timestamp: i64,
     },
     Relation {
         relation: RelationInfo,
     },
+    Type {
+        type_info: TypeInfo,
+    },
+    Origin {
+        origin_lsn: Lsn,
+        origin_name: String,
+    },
     Insert {
         relation_id: Oid,
         tuple_data: TupleData,
@@ -92,36 +260,362 @@ pub enum ReplicationMessage {
     StreamCommit {
         xid: Xid,
         flags: u8,
-        commit_lsn: u64,
-        end_lsn: u64,
+        commit_lsn: Lsn,
+        end_lsn: Lsn,
+        timestamp: i64,
+    },
+    StreamAbort {
+        xid: Xid,
+        subtransaction_xid: Xid,
+        /// Present with protocol version 4 when streaming is set to parallel
+        abort_lsn: Option<Lsn>,
+        abort_timestamp: Option<i64>,
+    },
+    StreamPrepare {
+        flags: u8,
+        prepare_lsn: Lsn,
+        end_lsn: Lsn,
+        timestamp: i64,
+        xid: Xid,
+        gid: String,
+    },
+}
+
+/// A column value borrowed from the CopyData buffer it was parsed from
+#[derive(Debug, Clone, Copy)]
+pub struct ColumnDataRef<'a> {
+    pub data_type: char, // 'n' for null, 't' for text, 'b' for binary, 'u' for unchanged
+    pub data: &'a [u8],
+}
+
+impl ColumnDataRef<'_> {
+    /// Text form of the value; binary values are decoded according to the column type
+    pub fn to_text(&self, type_oid: Oid) -> String {
+        match self.data_type {
+            'b' => crate::binary::to_text(type_oid, self.data),
+            _ => String::from_utf8_lossy(self.data).into_owned(),
+        }
+    }
+
+    pub fn to_owned_data(&self) -> ColumnData {
+        self.to_owned_capped(None)
+    }
+
+    /// Copy at most `max_bytes` of the value; text is cut at a character boundary and binary
+    /// values are dropped entirely, since a prefix of them cannot be decoded
+    pub fn to_owned_capped(&self, max_bytes: Option<usize>) -> ColumnData {
+        let data = match max_bytes {
+            Some(max) if self.data.len() > max => match self.data_type {
+                'b' => &[],
+                _ => utf8_prefix(self.data, max),
+            },
+            _ => self.data,
+        };
+        ColumnData {
+            data_type: self.data_type,
+            length: self.data.len() as i32,
+            data: data.to_vec(),
+        }
+    }
+}
+
+/// A row whose column values borrow from the CopyData buffer
+#[derive(Debug, Clone)]
+pub struct TupleDataRef<'a> {
+    pub column_count: i16,
+    pub columns: Vec<ColumnDataRef<'a>>,
+    pub processed_length: usize, // How many bytes were processed
+}
+
+impl TupleDataRef<'_> {
+    pub fn into_owned(self) -> TupleData {
+        self.into_owned_capped(None)
+    }
+
+    /// Copy the row, keeping at most `max_column_bytes` of each value
+    pub fn into_owned_capped(self, max_column_bytes: Option<usize>) -> TupleData {
+        TupleData {
+            column_count: self.column_count,
+            columns: self
+                .columns
+                .iter()
+                .map(|column| column.to_owned_capped(max_column_bytes))
+                .collect(),
+            processed_length: self.processed_length,
+        }
+    }
+}
+
+/// Logical replication message borrowing names and column values from the CopyData buffer
+///
+/// Parsing into this form allocates one Vec per tuple instead of one per column, so messages
+/// that are dropped, e.g. by the table filter, cost little. Relation and Type messages are rare
+/// and kept owned since they outlive the buffer in the relation cache.
+#[derive(Debug)]
+pub enum ReplicationMessageRef<'a> {
+    Begin {
+        final_lsn: Lsn,
+        timestamp: i64,
+        xid: Xid,
+    },
+    Commit {
+        flags: u8,
+        commit_lsn: Lsn,
+        end_lsn: Lsn,
+        timestamp: i64,
+    },
+    Relation {
+        relation: RelationInfo,
+    },
+    Type {
+        type_info: TypeInfo,
+    },
+    Origin {
+        origin_lsn: Lsn,
+        origin_name: Cow<'a, str>,
+    },
+    Insert {
+        relation_id: Oid,
+        tuple_data: TupleDataRef<'a>,
+        is_stream: bool,
+        xid: Option<Xid>,
+    },
+    Update {
+        relation_id: Oid,
+        key_type: Option<char>, // 'K' for replica identity, 'O' for old tuple
+        old_tuple_data: Option<TupleDataRef<'a>>,
+        new_tuple_data: TupleDataRef<'a>,
+        is_stream: bool,
+        xid: Option<Xid>,
+    },
+    Delete {
+        relation_id: Oid,
+        key_type: char, // 'K' for replica identity, 'O' for old tuple
+        tuple_data: TupleDataRef<'a>,
+        is_stream: bool,
+        xid: Option<Xid>,
+    },
+    Truncate {
+        relation_ids: Vec<Oid>,
+        flags: i8,
+        is_stream: bool,
+        xid: Option<Xid>,
+    },
+    StreamStart {
+        xid: Xid,
+        first_segment: bool,
+    },
+    StreamStop,
+    StreamCommit {
+        xid: Xid,
+        flags: u8,
+        commit_lsn: Lsn,
+        end_lsn: Lsn,
         timestamp: i64,
     },
     StreamAbort {
         xid: Xid,
         subtransaction_xid: Xid,
+        /// Present with protocol version 4 when streaming is set to parallel
+        abort_lsn: Option<Lsn>,
+        abort_timestamp: Option<i64>,
+    },
+    StreamPrepare {
+        flags: u8,
+        prepare_lsn: Lsn,
+        end_lsn: Lsn,
+        timestamp: i64,
+        xid: Xid,
+        gid: Cow<'a, str>,
     },
 }
 
+impl ReplicationMessageRef<'_> {
+    /// Relation the message changes, for Insert, Update and Delete
+    pub fn relation_id(&self) -> Option<Oid> {
+        match self {
+            Self::Insert { relation_id, .. }
+            | Self::Update { relation_id, .. }
+            | Self::Delete { relation_id, .. } => Some(*relation_id),
+            _ => None,
+        }
+    }
+
+    /// Copy the borrowed data so the message no longer depends on the buffer
+    pub fn into_owned(self) -> ReplicationMessage {
+        self.into_owned_capped(None)
+    }
+
+    /// Copy the borrowed data, keeping at most `max_column_bytes` of each column value
+    pub fn into_owned_capped(self, max_column_bytes: Option<usize>) -> ReplicationMessage {
+        let tuple = |tuple: TupleDataRef| tuple.into_owned_capped(max_column_bytes);
+        match self {
+            Self::Begin {
+                final_lsn,
+                timestamp,
+                xid,
+            } => ReplicationMessage::Begin {
+                final_lsn,
+                timestamp,
+                xid,
+            },
+            Self::Commit {
+                flags,
+                commit_lsn,
+                end_lsn,
+                timestamp,
+            } => ReplicationMessage::Commit {
+                flags,
+                commit_lsn,
+                end_lsn,
+                timestamp,
+            },
+            Self::Relation { relation } => ReplicationMessage::Relation { relation },
+            Self::Type { type_info } => ReplicationMessage::Type { type_info },
+            Self::Origin {
+                origin_lsn,
+                origin_name,
+            } => ReplicationMessage::Origin {
+                origin_lsn,
+                origin_name: origin_name.into_owned(),
+            },
+            Self::Insert {
+                relation_id,
+                tuple_data,
+                is_stream,
+                xid,
+            } => ReplicationMessage::Insert {
+                relation_id,
+                tuple_data: tuple(tuple_data),
+                is_stream,
+                xid,
+            },
+            Self::Update {
+                relation_id,
+                key_type,
+                old_tuple_data,
+                new_tuple_data,
+                is_stream,
+                xid,
+            } => ReplicationMessage::Update {
+                relation_id,
+                key_type,
+                old_tuple_data: old_tuple_data.map(tuple),
+                new_tuple_data: tuple(new_tuple_data),
+                is_stream,
+                xid,
+            },
+            Self::Delete {
+                relation_id,
+                key_type,
+                tuple_data,
+                is_stream,
+                xid,
+            } => ReplicationMessage::Delete {
+                relation_id,
+                key_type,
+                tuple_data: tuple(tuple_data),
+                is_stream,
+                xid,
+            },
+            Self::Truncate {
+                relation_ids,
+                flags,
+                is_stream,
+                xid,
+            } => ReplicationMessage::Truncate {
+                relation_ids,
+                flags,
+                is_stream,
+                xid,
+            },
+            Self::StreamStart { xid, first_segment } => {
+                ReplicationMessage::StreamStart { xid, first_segment }
+            }
+            Self::StreamStop => ReplicationMessage::StreamStop,
+            Self::StreamCommit {
+                xid,
+                flags,
+                commit_lsn,
+                end_lsn,
+                timestamp,
+            } => ReplicationMessage::StreamCommit {
+                xid,
+                flags,
+                commit_lsn,
+                end_lsn,
+                timestamp,
+            },
+            Self::StreamAbort {
+                xid,
+                subtransaction_xid,
+                abort_lsn,
+                abort_timestamp,
+            } => ReplicationMessage::StreamAbort {
+                xid,
+                subtransaction_xid,
+                abort_lsn,
+                abort_timestamp,
+            },
+            Self::StreamPrepare {
+                flags,
+                prepare_lsn,
+                end_lsn,
+                timestamp,
+                xid,
+                gid,
+            } => ReplicationMessage::StreamPrepare {
+                flags,
+                prepare_lsn,
+                end_lsn,
+                timestamp,
+                xid,
+                gid: gid.into_owned(),
+            },
+        }
+    }
+}
+
 /// State for managing logical replication
 #[derive(Debug)]
 pub struct ReplicationState {
-    pub relations: HashMap<Oid, RelationInfo>,
-    pub received_lsn: u64,
-    pub flushed_lsn: u64,
+    pub relations: RelationCache,
+    pub types: HashMap<Oid, TypeInfo>,
+    pub received_lsn: Lsn,
+    /// Applied position last made durable by flushing the sinks and saving the checkpoint
+    pub flushed_lsn: Lsn,
+    /// End LSN of the last transaction fully processed
+    pub applied_lsn: Lsn,
     pub last_feedback_time: std::time::Instant,
+    /// Received and applied LSN carried by the last standby status update
+    pub last_feedback_lsn: (Lsn, Lsn),
     pub in_streaming_txn: bool,
     pub streaming_xid: Option<Xid>,
+    pub current_xid: Option<Xid>,
+    /// The current transaction committed before the configured start time and is not emitted
+    pub skipping_transaction: bool,
+    /// Transactions replicated from another origin, whose changes are ignored
+    pub foreign_origin_xids: HashSet<Xid>,
+    /// Buffers transactions until they commit, None when changes are emitted as they arrive
+    pub assembler: Option<TransactionAssembler>,
 }
 
 impl ReplicationState {
     pub fn new() -> Self {
         Self {
-            relations: HashMap::new(),
-            received_lsn: 0,
-            flushed_lsn: 0,
+            relations: RelationCache::default(),
+            types: HashMap::new(),
+            received_lsn: Lsn::INVALID,
+            flushed_lsn: Lsn::INVALID,
+            applied_lsn: Lsn::INVALID,
             last_feedback_time: std::time::Instant::now(),
+            last_feedback_lsn: (Lsn::INVALID, Lsn::INVALID),
             in_streaming_txn: false,
             streaming_xid: None,
+            current_xid: None,
+            skipping_transaction: false,
+            foreign_origin_xids: HashSet::new(),
+            assembler: None,
         }
     }
 
@@ -135,19 +629,36 @@ impl ReplicationState {
         self.streaming_xid = None;
     }
 
-    pub fn add_relation(&mut self, relation: RelationInfo) {
-        self.relations.insert(relation.oid, relation);
+    /// Cache a relation, reporting whether its schema changed since it was last sent
+    pub fn add_relation(&mut self, relation: RelationInfo) -> RelationUpdate {
+        self.relations.insert(relation)
     }
 
     pub fn get_relation(&self, oid: Oid) -> Option<&RelationInfo> {
-        self.relations.get(&oid)
+        self.relations.get(oid)
+    }
+
+    pub fn add_type(&mut self, type_info: TypeInfo) {
+        self.types.insert(type_info.oid, type_info);
     }
 
-    pub fn update_lsn(&mut self, lsn: u64) {
-        if lsn > 0 {
+    /// Schema-qualified name of a user-defined type announced by a Type message
+    pub fn type_name(&self, oid: Oid) -> Option<String> {
+        self.types
+            .get(&oid)
+            .map(|type_info| format!("{}.{}", type_info.namespace, type_info.type_name))
+    }
+
+    pub fn update_lsn(&mut self, lsn: Lsn) {
+        if lsn.is_valid() {
             self.received_lsn = std::cmp::max(self.received_lsn, lsn);
         }
     }
+
+    /// Mark a transaction as processed up to its end LSN
+    pub fn update_applied_lsn(&mut self, lsn: Lsn) {
+        self.applied_lsn = std::cmp::max(self.applied_lsn, lsn);
+    }
 }
 
 impl Default for ReplicationState {
@@ -156,43 +667,315 @@ impl Default for ReplicationState {
     }
 }
 
+/// How UPDATE events are rendered in the log output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UpdateDisplayMode {
+    /// Print the old tuple (when sent) followed by the complete new row
+    Full,
+    /// Print only the replica identity key columns and the columns whose value changed
+    ChangedOnly,
+    /// Like `ChangedOnly`, except that when the full old row was sent (REPLICA IDENTITY FULL)
+    /// only the changed columns are printed, each as `old -> new`
+    #[default]
+    Diff,
+}
+
+impl FromStr for UpdateDisplayMode {
+    type Err = crate::errors::ReplicationError;
+
+    fn from_str(s: &str) -> crate::errors::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "full" => Ok(UpdateDisplayMode::Full),
+            "changed" | "changed-only" | "changed_only" => Ok(UpdateDisplayMode::ChangedOnly),
+            "diff" => Ok(UpdateDisplayMode::Diff),
+            _ => Err(crate::errors::ReplicationError::config(format!(
+                "Invalid update display mode: {}. Valid values are: full, changed, diff",
+                s
+            ))),
+        }
+    }
+}
+
+/// When standby status updates are sent to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FeedbackMode {
+    /// Send on the feedback interval when the position advanced, and at least every status interval
+    #[default]
+    Periodic,
+    /// Only reply to keepalives that request a reply
+    OnRequest,
+}
+
+impl FromStr for FeedbackMode {
+    type Err = crate::errors::ReplicationError;
+
+    fn from_str(s: &str) -> crate::errors::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "periodic" => Ok(FeedbackMode::Periodic),
+            "on-request" | "on_request" => Ok(FeedbackMode::OnRequest),
+            _ => Err(crate::errors::ReplicationError::config(format!(
+                "Invalid feedback mode: {}. Valid values are: periodic, on-request",
+                s
+            ))),
+        }
+    }
+}
+
+/// Which changes to stream by the replication origin they came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OriginFilter {
+    /// Changes made locally and changes replicated from other origins
+    #[default]
+    Any,
+    /// Only changes made locally, not ones applied by another subscription
+    None,
+}
+
+impl OriginFilter {
+    /// Value of the `origin` option sent with START_REPLICATION
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OriginFilter::Any => "any",
+            OriginFilter::None => "none",
+        }
+    }
+}
+
+impl FromStr for OriginFilter {
+    type Err = crate::errors::ReplicationError;
+
+    fn from_str(s: &str) -> crate::errors::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "any" => Ok(OriginFilter::Any),
+            "none" => Ok(OriginFilter::None),
+            _ => Err(crate::errors::ReplicationError::config(format!(
+                "Invalid origin: {}. Valid values are: any, none",
+                s
+            ))),
+        }
+    }
+}
+
 /// Configuration for the replication checker with validation
 #[derive(Debug)]
 pub struct ReplicationConfig {
     pub connection_string: String,
-    pub publication_name: String,
+    /// Publications to subscribe to; changes from all of them arrive in one stream
+    pub publication_names: Vec<String>,
     pub slot_name: String,
+    pub slot_mode: SlotMode,
+    /// Drop the slot once streaming stops gracefully
+    pub drop_slot_on_exit: bool,
     pub feedback_interval_secs: u64,
+    /// Upper bound between status updates even when the position has not moved
+    pub status_interval_secs: u64,
+    /// Longest the server may send nothing before the connection is closed and re-established
+    pub receiver_timeout: Option<Duration>,
+    pub feedback_mode: FeedbackMode,
+    pub protocol_version: u32,
+    pub streaming: bool,
+    /// Stream in-progress transactions with `streaming 'parallel'`, protocol version 4 only
+    pub parallel_streaming: bool,
+    /// Ask for column values in binary format instead of text
+    pub binary: bool,
+    /// Whether changes replicated from other origins are streamed
+    pub origin: OriginFilter,
+    pub update_display: UpdateDisplayMode,
+    /// Show date and time values in ISO form, with timestamptz in this zone, instead of as the
+    /// server's DateStyle wrote them
+    pub display_timezone: Option<DisplayTimeZone>,
+    pub progress_interval_secs: u64,
+    /// Seconds between lag summaries, 0 disables them
+    pub stats_interval_secs: u64,
+    /// Seconds between reports of the pg_stat_subscription fields, 0 disables them
+    pub subscription_stats_interval_secs: u64,
+    /// How a dropped replication connection is re-established
+    pub reconnect: ReconnectPolicy,
+    /// Position to start streaming from instead of the checkpoint or the slot's position
+    pub start_lsn: Option<Lsn>,
+    /// Transactions committed before this time are skipped
+    pub start_time: Option<DateTime<Utc>>,
+    pub stop_conditions: StopConditions,
+    pub watch_duration: Option<Duration>,
+    /// Buffer each transaction and emit it on commit, None emits changes as they arrive
+    pub transaction_buffer: Option<AssemblerConfig>,
+    pub output: OutputConfig,
+    /// Layout of the change events written to the output and the sinks
+    pub event_schema: EventSchemaVersion,
+    pub table_filter: TableFilter,
+    /// Predicates rows must satisfy to be emitted
+    pub row_filter: RowFilter,
+    /// Share of the changes of high-volume tables emitted once they passed the filters
+    pub sampling: Sampling,
+    /// Columns whose values are masked in logs and output
+    pub column_mask: ColumnMask,
+    /// Whether stream invariants are checked, and whether violations stop the run
+    pub validation: ValidationMode,
+    /// Log each CopyData message as a hexdump at trace level before it is parsed
+    pub dump_raw: bool,
+    /// Most relations kept in the relation cache, None keeps all of them
+    pub relation_cache_size: Option<usize>,
+    /// Rows per table kept to backfill unchanged TOAST values, None disables the cache
+    pub toast_cache_rows: Option<usize>,
+    /// Row and byte limits of a single transaction, None emits transactions of any size
+    pub transaction_limits: Option<TransactionLimits>,
+    /// Name of the replication source when several are streamed, labelling its change events
+    pub source: Option<String>,
+    /// Bytes of each column value kept, longer values are truncated; None keeps whole values
+    pub max_column_bytes: Option<usize>,
+    /// Webhook that batches of change events are POSTed to, if any
+    pub webhook: Option<WebhookConfig>,
+    /// Directory that change events are written to as Parquet files, if any
+    #[cfg(feature = "parquet")]
+    pub parquet: Option<ParquetConfig>,
+    /// Avro sink and the schema registry its schemas are registered with, if any
+    pub avro: Option<AvroConfig>,
+    /// S3-compatible bucket that batches of change events are uploaded to, if any
+    #[cfg(feature = "s3")]
+    pub s3: Option<S3Config>,
+    /// Sinks from the `[[sink]]` tables that change events are fanned out to
+    pub sinks: Vec<SinkConfig>,
+    /// Lag thresholds and the action taken when one is exceeded, if any
+    pub alerts: Option<AlertConfig>,
+    /// CopyData messages queued between the receive and processing stages, 0 receives and
+    /// processes them in turn on one task
+    pub receive_queue_capacity: usize,
+    /// Most change events and bytes processed per second, if limited
+    pub rate_limits: Option<RateLimits>,
+    /// Open a second, regular connection for catalog queries while streaming
+    pub control_connection: bool,
+    /// How often the WAL the slot retains is checked over the control connection, None disables
+    /// the checks
+    pub wal_keep: Option<WalKeepConfig>,
+    /// Database that decoded changes are applied to, if any
+    pub target_connection_string: Option<String>,
+    /// File recording the applied LSN, used to resume after a restart
+    pub checkpoint_path: Option<PathBuf>,
+    /// File recording recent commits, against which transactions delivered again are verified
+    pub commit_log_path: Option<PathBuf>,
+    /// Commits kept in the commit log
+    pub commit_log_window: usize,
+    /// Capture file every raw CopyData message is recorded to
+    pub record_path: Option<PathBuf>,
+    /// Copy the published tables under the new slot's snapshot before streaming, if set
+    pub init_sync: Option<InitSyncConfig>,
+    /// Attach a deterministic ID to every emitted change event
+    pub event_ids: bool,
+    /// Attach the transaction metadata envelope to every emitted change event
+    pub transaction_envelope: bool,
+    /// Receive and processing times and the clock skew estimate added to the envelope
+    pub event_timestamps: EventTimestamps,
 }
 
 impl ReplicationConfig {
-    /// Create a new ReplicationConfig with validation
-    pub fn new(
-        connection_string: String,
-        publication_name: String,
-        slot_name: String,
-    ) -> crate::errors::Result<Self> {
-        // Basic validation
-        if connection_string.trim().is_empty() {
+    /// Start building a configuration that streams from the server at `connection_string`
+    pub fn builder(connection_string: impl Into<String>) -> ReplicationConfigBuilder {
+        ReplicationConfigBuilder {
+            config: Self {
+                connection_string: connection_string.into(),
+                ..Self::offline()
+            },
+        }
+    }
+
+    /// Configuration for decoding a capture file without connecting to a server
+    pub fn offline() -> Self {
+        Self {
+            connection_string: String::new(),
+            publication_names: Vec::new(),
+            slot_name: String::new(),
+            slot_mode: SlotMode::default(),
+            drop_slot_on_exit: false,
+            feedback_interval_secs: 1, // Send feedback every second
+            status_interval_secs: 10,  // Same default as wal_receiver_status_interval
+            receiver_timeout: Some(Duration::from_secs(60)), // Same as wal_receiver_timeout
+            feedback_mode: FeedbackMode::default(),
+            protocol_version: 2, // Version 2 supports streaming of large transactions
+            streaming: true,
+            parallel_streaming: false,
+            binary: false,
+            origin: OriginFilter::default(),
+            update_display: UpdateDisplayMode::default(),
+            display_timezone: None,
+            progress_interval_secs: 5, // Report catch-up progress every 5 seconds
+            stats_interval_secs: 30,
+            subscription_stats_interval_secs: 0,
+            reconnect: ReconnectPolicy::default(),
+            start_lsn: None,
+            start_time: None,
+            stop_conditions: StopConditions::default(),
+            watch_duration: None,
+            transaction_buffer: None,
+            output: OutputConfig::default(),
+            event_schema: EventSchemaVersion::default(),
+            table_filter: TableFilter::default(),
+            row_filter: RowFilter::default(),
+            sampling: Sampling::default(),
+            column_mask: ColumnMask::default(),
+            validation: ValidationMode::default(),
+            dump_raw: false,
+            relation_cache_size: None,
+            toast_cache_rows: None,
+            transaction_limits: None,
+            source: None,
+            max_column_bytes: None,
+            webhook: None,
+            #[cfg(feature = "parquet")]
+            parquet: None,
+            avro: None,
+            #[cfg(feature = "s3")]
+            s3: None,
+            sinks: Vec::new(),
+            alerts: None,
+            control_connection: false,
+            wal_keep: Some(WalKeepConfig::default()),
+            receive_queue_capacity: 1024,
+            rate_limits: None,
+            target_connection_string: None,
+            checkpoint_path: None,
+            commit_log_path: None,
+            commit_log_window: 1000,
+            record_path: None,
+            init_sync: None,
+            event_ids: false,
+            transaction_envelope: false,
+            event_timestamps: EventTimestamps::default(),
+        }
+    }
+
+    /// Check the settings a server connection needs, and those that only work together
+    fn validate(&self) -> crate::errors::Result<()> {
+        if self.connection_string.trim().is_empty() {
             return Err(crate::errors::ReplicationError::config(
                 "Connection string cannot be empty",
             ));
         }
 
-        if publication_name.trim().is_empty() {
+        if self.publication_names.is_empty() {
+            return Err(crate::errors::ReplicationError::config(
+                "At least one publication is required",
+            ));
+        }
+
+        if self
+            .publication_names
+            .iter()
+            .any(|name| name.trim().is_empty())
+        {
             return Err(crate::errors::ReplicationError::config(
                 "Publication name cannot be empty",
             ));
         }
 
-        if slot_name.trim().is_empty() {
+        if self.slot_name.trim().is_empty() {
             return Err(crate::errors::ReplicationError::config(
                 "Slot name cannot be empty",
             ));
         }
 
         // Validate slot name format (PostgreSQL naming rules)
-        if !slot_name
+        if !self
+            .slot_name
             .chars()
             .all(|c| c.is_ascii_alphanumeric() || c == '_')
         {
@@ -201,18 +984,182 @@ impl ReplicationConfig {
             ));
         }
 
-        if slot_name.len() > 63 {
+        if self.slot_name.len() > 63 {
             // PostgreSQL identifier length limit
             return Err(crate::errors::ReplicationError::config(
                 "Slot name cannot be longer than 63 characters",
             ));
         }
 
-        Ok(Self {
-            connection_string,
-            publication_name,
-            slot_name,
-            feedback_interval_secs: 1, // Send feedback every second
-        })
+        if !(1..=4).contains(&self.protocol_version) {
+            return Err(crate::errors::ReplicationError::config(format!(
+                "Protocol version must be between 1 and 4, got {}",
+                self.protocol_version
+            )));
+        }
+
+        if self.streaming && self.protocol_version < 2 {
+            return Err(crate::errors::ReplicationError::config(format!(
+                "Streaming in-progress transactions needs protocol version 2 or later, but version {} is configured; raise it or disable streaming",
+                self.protocol_version
+            )));
+        }
+
+        if self.streaming && self.parallel_streaming && self.protocol_version < 4 {
+            return Err(crate::errors::ReplicationError::config(format!(
+                "Parallel streaming needs protocol version 4, but version {} is configured",
+                self.protocol_version
+            )));
+        }
+
+        // A zero interval would send a status update on every loop iteration
+        if self.feedback_interval_secs == 0 {
+            return Err(crate::errors::ReplicationError::config(
+                "Feedback interval must be at least one second",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a `ReplicationConfig` for streaming from a server, checking the settings together
+/// at `build()`
+///
+/// Settings without a `with_` method keep their defaults and can be changed on the built
+/// configuration, whose fields are public.
+#[derive(Debug)]
+pub struct ReplicationConfigBuilder {
+    config: ReplicationConfig,
+}
+
+impl ReplicationConfigBuilder {
+    /// Subscribe to one more publication
+    pub fn with_publication(mut self, name: impl Into<String>) -> Self {
+        self.config.publication_names.push(name.into());
+        self
+    }
+
+    /// Subscribe to these publications as well; changes from all of them arrive in one stream
+    pub fn with_publications<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config
+            .publication_names
+            .extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn with_slot_name(mut self, slot_name: impl Into<String>) -> Self {
+        self.config.slot_name = slot_name.into();
+        self
+    }
+
+    pub fn with_slot_mode(mut self, slot_mode: SlotMode) -> Self {
+        self.config.slot_mode = slot_mode;
+        self
+    }
+
+    /// Drop the slot once streaming stops gracefully
+    pub fn with_drop_slot_on_exit(mut self, drop_slot_on_exit: bool) -> Self {
+        self.config.drop_slot_on_exit = drop_slot_on_exit;
+        self
+    }
+
+    /// Logical replication protocol version, 1 to 4 (default: 2)
+    pub fn with_proto_version(mut self, protocol_version: u32) -> Self {
+        self.config.protocol_version = protocol_version;
+        self
+    }
+
+    /// Stream large transactions while in progress, needs protocol version 2 (default: true)
+    pub fn with_streaming(mut self, streaming: bool) -> Self {
+        self.config.streaming = streaming;
+        self
+    }
+
+    /// Stream in-progress transactions with `streaming 'parallel'`, needs protocol version 4
+    pub fn with_parallel_streaming(mut self, parallel_streaming: bool) -> Self {
+        self.config.parallel_streaming = parallel_streaming;
+        self
+    }
+
+    /// Ask for column values in binary format instead of text
+    pub fn with_binary(mut self, binary: bool) -> Self {
+        self.config.binary = binary;
+        self
+    }
+
+    /// Whether changes replicated from other origins are streamed
+    pub fn with_origin(mut self, origin: OriginFilter) -> Self {
+        self.config.origin = origin;
+        self
+    }
+
+    /// Time between status updates while the position advances, in whole seconds (default: 1s)
+    pub fn with_feedback_interval(mut self, interval: Duration) -> Self {
+        self.config.feedback_interval_secs = interval.as_secs();
+        self
+    }
+
+    /// Upper bound between status updates even when the position has not moved, in whole
+    /// seconds (default: 10s)
+    pub fn with_status_interval(mut self, interval: Duration) -> Self {
+        self.config.status_interval_secs = interval.as_secs();
+        self
+    }
+
+    pub fn with_feedback_mode(mut self, feedback_mode: FeedbackMode) -> Self {
+        self.config.feedback_mode = feedback_mode;
+        self
+    }
+
+    /// Longest the server may send nothing before the connection is re-established, None
+    /// waits indefinitely (default: 60s)
+    pub fn with_receiver_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.receiver_timeout = timeout;
+        self
+    }
+
+    /// How a dropped replication connection is re-established
+    pub fn with_reconnect(mut self, reconnect: ReconnectPolicy) -> Self {
+        self.config.reconnect = reconnect;
+        self
+    }
+
+    /// Position to start streaming from instead of the checkpoint or the slot's position
+    pub fn with_start_lsn(mut self, lsn: impl Into<Lsn>) -> Self {
+        self.config.start_lsn = Some(lsn.into());
+        self
+    }
+
+    /// Buffer each transaction and emit it on commit
+    pub fn with_transaction_buffer(mut self, assembler: AssemblerConfig) -> Self {
+        self.config.transaction_buffer = Some(assembler);
+        self
+    }
+
+    pub fn with_output(mut self, output: OutputConfig) -> Self {
+        self.config.output = output;
+        self
+    }
+
+    pub fn with_table_filter(mut self, table_filter: TableFilter) -> Self {
+        self.config.table_filter = table_filter;
+        self
+    }
+
+    /// File recording the applied LSN, used to resume after a restart
+    pub fn with_checkpoint_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.checkpoint_path = Some(path.into());
+        self
+    }
+
+    /// Check the settings and return the configuration
+    pub fn build(self) -> crate::errors::Result<ReplicationConfig> {
+        self.config.validate()?;
+        Ok(self.config)
     }
 }
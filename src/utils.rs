@@ -1,13 +1,16 @@
 //! Utility functions for PostgreSQL replication
 //! Contains helper functions for byte manipulation, timestamp conversion, and other utilities
 
+use crate::bufpool::{BufferPool, PooledBuffer};
 use crate::errors::Result;
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
 use libpq_sys::*;
 use std::ffi::{CStr, CString};
+use std::os::unix::io::RawFd;
 use std::ptr;
-use std::time::{ SystemTime, UNIX_EPOCH};
-use tracing::warn;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::unix::AsyncFd;
+use tracing::{info, warn};
 
 // PostgreSQL epoch constants
 const PG_EPOCH_OFFSET_SECS: i64 = 946_684_800; // Seconds from 1970 to 2000
@@ -27,8 +30,7 @@ pub fn system_time_to_postgres_timestamp(time: SystemTime) -> TimestampTz {
         .expect("SystemTime is before Unix epoch");
 
     let unix_secs = duration_since_unix.as_secs() as i64;
-    let unix_micros =
-        unix_secs * 1_000_000 + (duration_since_unix.subsec_micros() as i64);
+    let unix_micros = unix_secs * 1_000_000 + (duration_since_unix.subsec_micros() as i64);
 
     // Shift Unix epoch to PostgreSQL epoch
     unix_micros - PG_EPOCH_OFFSET_SECS * 1_000_000
@@ -138,12 +140,34 @@ pub fn buf_send_i64(val: i64, buf: &mut [u8]) {
     buf[..8].copy_from_slice(&bytes);
 }
 
+/// Whether a read from the socket would not block: it holds data, reached end of file or failed
+fn socket_has_data(fd: RawFd) -> bool {
+    let mut byte = 0u8;
+    let read = unsafe {
+        libc::recv(
+            fd,
+            &mut byte as *mut u8 as *mut libc::c_void,
+            1,
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    read >= 0 || std::io::Error::last_os_error().kind() != std::io::ErrorKind::WouldBlock
+}
 
 /// Safe wrapper for PostgreSQL connection
 pub struct PGConnection {
     conn: *mut PGconn,
+    /// Socket registered with the tokio reactor once the connection switches to async reads
+    socket: Option<AsyncFd<RawFd>>,
+    /// Buffers CopyData messages are copied into
+    buffer_pool: BufferPool,
 }
 
+// SAFETY: libpq allows a connection to be used from any thread as long as it is not used by two
+// threads at once. Every method takes &self and the type is not Sync, so it can only be moved
+// between threads, which is how the receive stage of the streaming pipeline takes it over.
+unsafe impl Send for PGConnection {}
+
 impl PGConnection {
     pub fn connect(conninfo: &str) -> Result<Self> {
         let c_conninfo = CString::new(conninfo)?;
@@ -172,7 +196,11 @@ impl PGConnection {
             )));
         }
 
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            socket: None,
+            buffer_pool: BufferPool::new(),
+        })
     }
 
     pub fn exec(&self, query: &str) -> Result<PGResult> {
@@ -188,6 +216,94 @@ impl PGConnection {
         Ok(PGResult { result })
     }
 
+    /// Execute a statement with text-format parameters, None binding SQL NULL
+    pub fn exec_params(&self, query: &str, params: &[Option<&str>]) -> Result<PGResult> {
+        let c_query = CString::new(query)?;
+        let c_params = params
+            .iter()
+            .map(|param| param.map(CString::new).transpose())
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let param_ptrs: Vec<*const std::os::raw::c_char> = c_params
+            .iter()
+            .map(|param| param.as_ref().map_or(ptr::null(), |p| p.as_ptr()))
+            .collect();
+
+        let result = unsafe {
+            PQexecParams(
+                self.conn,
+                c_query.as_ptr(),
+                param_ptrs.len() as i32,
+                ptr::null(),
+                param_ptrs.as_ptr(),
+                ptr::null(),
+                ptr::null(),
+                0,
+            )
+        };
+
+        if result.is_null() {
+            return Err(crate::errors::ReplicationError::protocol(format!(
+                "Query execution failed: {}",
+                self.get_error_message()
+            )));
+        }
+
+        Ok(PGResult { result })
+    }
+
+    /// Server version as an integer, e.g. 160002 for 16.2
+    pub fn server_version(&self) -> u32 {
+        unsafe { PQserverVersion(self.conn) as u32 }
+    }
+
+    /// A setting the server reports on connecting and whenever it changes, such as `DateStyle`
+    pub fn parameter_status(&self, name: &str) -> Option<String> {
+        let c_name = CString::new(name).ok()?;
+        unsafe {
+            let value = PQparameterStatus(self.conn, c_name.as_ptr());
+            if value.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(value).to_string_lossy().into_owned())
+            }
+        }
+    }
+
+    /// Whether the connection is encrypted with TLS
+    pub fn ssl_in_use(&self) -> bool {
+        unsafe { PQsslInUse(self.conn) == 1 }
+    }
+
+    /// A TLS attribute of the connection such as `protocol` or `cipher`, None without TLS
+    pub fn ssl_attribute(&self, name: &str) -> Option<String> {
+        let c_name = CString::new(name).ok()?;
+        unsafe {
+            let value = PQsslAttribute(self.conn, c_name.as_ptr());
+            if value.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(value).to_string_lossy().into_owned())
+            }
+        }
+    }
+
+    /// Log whether TLS was negotiated, with its protocol and cipher
+    pub fn log_tls_state(&self) {
+        if self.ssl_in_use() {
+            info!(
+                "Connection encrypted with {} using {}",
+                self.ssl_attribute("protocol")
+                    .as_deref()
+                    .unwrap_or("unknown protocol"),
+                self.ssl_attribute("cipher")
+                    .as_deref()
+                    .unwrap_or("unknown cipher")
+            );
+        } else {
+            info!("Connection is not encrypted (TLS not in use)");
+        }
+    }
+
     fn get_error_message(&self) -> String {
         unsafe {
             let error_ptr = PQerrorMessage(self.conn);
@@ -203,9 +319,88 @@ impl PGConnection {
         }
     }
 
-    pub fn get_copy_data(&self, timeout: i32) -> Result<Option<Vec<u8>>> {
+    /// Put the connection in non-blocking mode and register its socket with the tokio reactor
+    ///
+    /// Must be called from within a tokio runtime, after the blocking setup commands have run.
+    pub fn enable_async(&mut self) -> Result<()> {
+        if unsafe { PQsetnonblocking(self.conn, 1) } != 0 {
+            return Err(crate::errors::ReplicationError::connection(format!(
+                "Failed to set connection to non-blocking mode: {}",
+                self.get_error_message()
+            )));
+        }
+
+        let fd = unsafe { PQsocket(self.conn) };
+        if fd < 0 {
+            return Err(crate::errors::ReplicationError::connection(
+                "Connection has no open socket",
+            ));
+        }
+
+        self.socket = Some(AsyncFd::new(fd)?);
+        Ok(())
+    }
+
+    /// Take the next CopyData message, waiting up to `timeout` for the server to send one
+    ///
+    /// Returns None when no complete message arrived in time, so callers can still send
+    /// feedback and check stop conditions while the server is idle.
+    pub async fn next_copy_data(&self, timeout: Duration) -> Result<Option<PooledBuffer>> {
+        let Some(socket) = &self.socket else {
+            return Err(crate::errors::ReplicationError::connection(
+                "Async reads are not enabled on this connection",
+            ));
+        };
+
+        if let Some(data) = self.get_copy_data(true)? {
+            return Ok(Some(data));
+        }
+        // Input can be readable without the socket signalling it again, such as TLS records
+        // OpenSSL decrypted along with an earlier message, so read it before waiting
+        self.consume_input()?;
+        if let Some(data) = self.get_copy_data(true)? {
+            return Ok(Some(data));
+        }
+
+        match tokio::time::timeout(timeout, socket.readable()).await {
+            Ok(guard) => {
+                let mut guard = guard?;
+                self.consume_input()?;
+                // libpq reads no more than fits its buffer, and the reactor only signals data
+                // arriving after readiness is cleared, so it is kept while the socket has data
+                if !socket_has_data(*socket.get_ref()) {
+                    guard.clear_ready();
+                }
+            }
+            // Input is consumed after a timeout too, like libpq does before it gives up waiting
+            Err(_) => self.consume_input()?,
+        }
+        self.get_copy_data(true)
+    }
+
+    /// Read whatever input is available into libpq's buffer without blocking
+    fn consume_input(&self) -> Result<()> {
+        if unsafe { PQconsumeInput(self.conn) } == 0 {
+            return Err(crate::errors::ReplicationError::connection(format!(
+                "Failed to read from server: {}",
+                self.get_error_message()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Copy CopyData messages into buffers from this pool, e.g. one kept across reconnects
+    pub fn set_buffer_pool(&mut self, pool: BufferPool) {
+        self.buffer_pool = pool;
+    }
+
+    /// Take the next complete CopyData message
+    ///
+    /// With `nonblocking` set, returns None when no complete message is buffered instead of
+    /// waiting on the socket.
+    pub fn get_copy_data(&self, nonblocking: bool) -> Result<Option<PooledBuffer>> {
         let mut buffer: *mut std::os::raw::c_char = ptr::null_mut();
-        let result = unsafe { PQgetCopyData(self.conn, &mut buffer, timeout) };
+        let result = unsafe { PQgetCopyData(self.conn, &mut buffer, nonblocking as i32) };
 
         match result {
             -2 => {
@@ -215,8 +410,10 @@ impl PGConnection {
                     error_msg
                 )))
             }
-            -1 => Ok(None), // No more data
-            0 => Ok(None),  // Timeout or no data available
+            -1 => Err(crate::errors::ReplicationError::connection(
+                "Replication stream ended by the server",
+            )),
+            0 => Ok(None), // No complete message buffered yet
             len => {
                 if buffer.is_null() {
                     return Err(crate::errors::ReplicationError::buffer(
@@ -225,7 +422,37 @@ impl PGConnection {
                 }
 
                 let data = unsafe {
-                    std::slice::from_raw_parts(buffer as *const u8, len as usize).to_vec()
+                    self.buffer_pool.copy_from(std::slice::from_raw_parts(
+                        buffer as *const u8,
+                        len as usize,
+                    ))
+                };
+
+                unsafe { PQfreemem(buffer as *mut std::os::raw::c_void) };
+                Ok(Some(data))
+            }
+        }
+    }
+
+    /// Take the next row of a COPY TO STDOUT, waiting for it; None once the copy is complete
+    pub fn get_copy_row(&self) -> Result<Option<PooledBuffer>> {
+        let mut buffer: *mut std::os::raw::c_char = ptr::null_mut();
+        let result = unsafe { PQgetCopyData(self.conn, &mut buffer, 0) };
+
+        match result {
+            -1 => {
+                self.finish_copy()?;
+                Ok(None)
+            }
+            len if len < 0 || buffer.is_null() => Err(crate::errors::ReplicationError::protocol(
+                format!("Copy operation failed: {}", self.get_error_message()),
+            )),
+            len => {
+                let data = unsafe {
+                    self.buffer_pool.copy_from(std::slice::from_raw_parts(
+                        buffer as *const u8,
+                        len as usize,
+                    ))
                 };
 
                 unsafe { PQfreemem(buffer as *mut std::os::raw::c_void) };
@@ -234,6 +461,29 @@ impl PGConnection {
         }
     }
 
+    /// Collect the results of a completed COPY, failing when the server rejected it
+    pub fn finish_copy(&self) -> Result<()> {
+        loop {
+            let result = unsafe { PQgetResult(self.conn) };
+            if result.is_null() {
+                return Ok(());
+            }
+            let result = PGResult { result };
+            if !result.is_ok() {
+                return Err(crate::errors::ReplicationError::protocol(format!(
+                    "COPY failed: {}",
+                    result.error_message()
+                )));
+            }
+        }
+    }
+
+    /// Take the next result of the running command, None once all were returned
+    pub fn get_result(&self) -> Option<PGResult> {
+        let result = unsafe { PQgetResult(self.conn) };
+        (!result.is_null()).then_some(PGResult { result })
+    }
+
     pub fn put_copy_data(&self, data: &[u8]) -> Result<()> {
         let result = unsafe {
             PQputCopyData(
@@ -276,10 +526,43 @@ impl PGConnection {
             }
         }
     }
+
+    /// Flush queued output, waiting up to `timeout` for a non-blocking socket to drain
+    ///
+    /// Returns false when data was still queued once the timeout elapsed.
+    pub fn flush_all(&self, timeout: Duration) -> Result<bool> {
+        let started = std::time::Instant::now();
+        loop {
+            match unsafe { PQflush(self.conn) } {
+                0 => return Ok(true),
+                1 if started.elapsed() < timeout => std::thread::sleep(Duration::from_millis(10)),
+                1 => return Ok(false),
+                _ => {
+                    return Err(crate::errors::ReplicationError::protocol(format!(
+                        "Failed to flush connection: {}",
+                        self.get_error_message()
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Send CopyDone to end COPY BOTH mode, telling the server streaming stops
+    pub fn end_copy(&self, timeout: Duration) -> Result<bool> {
+        if unsafe { PQputCopyEnd(self.conn, ptr::null()) } == -1 {
+            return Err(crate::errors::ReplicationError::protocol(format!(
+                "Failed to end copy: {}",
+                self.get_error_message()
+            )));
+        }
+        self.flush_all(timeout)
+    }
 }
 
 impl Drop for PGConnection {
     fn drop(&mut self) {
+        // Deregister the socket before libpq closes it
+        self.socket.take();
         if !self.conn.is_null() {
             unsafe { PQfinish(self.conn) };
         }
@@ -303,6 +586,21 @@ impl PGResult {
         )
     }
 
+    /// Whether the command switched the connection into COPY BOTH mode, as START_REPLICATION does
+    pub fn is_copy_both(&self) -> bool {
+        self.status() == ExecStatusType::PGRES_COPY_BOTH
+    }
+
+    /// Whether a COPY TO STDOUT started, with rows to read using `get_copy_row`
+    pub fn is_copy_out(&self) -> bool {
+        self.status() == ExecStatusType::PGRES_COPY_OUT
+    }
+
+    /// Whether a COPY FROM STDIN started, waiting for rows sent with `put_copy_data`
+    pub fn is_copy_in(&self) -> bool {
+        self.status() == ExecStatusType::PGRES_COPY_IN
+    }
+
     pub fn ntuples(&self) -> i32 {
         unsafe { PQntuples(self.result) }
     }
@@ -311,6 +609,36 @@ impl PGResult {
         unsafe { PQnfields(self.result) }
     }
 
+    /// Error message reported by the server for this result, empty on success
+    pub fn error_message(&self) -> String {
+        unsafe {
+            let error_ptr = PQresultErrorMessage(self.result);
+            if error_ptr.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(error_ptr)
+                    .to_string_lossy()
+                    .trim()
+                    .to_string()
+            }
+        }
+    }
+
+    /// Number of rows affected by an INSERT/UPDATE/DELETE, 0 for other commands
+    pub fn cmd_tuples(&self) -> u64 {
+        unsafe {
+            let tuples_ptr = PQcmdTuples(self.result);
+            if tuples_ptr.is_null() {
+                0
+            } else {
+                CStr::from_ptr(tuples_ptr)
+                    .to_string_lossy()
+                    .parse()
+                    .unwrap_or(0)
+            }
+        }
+    }
+
     pub fn getvalue(&self, row: i32, col: i32) -> Option<String> {
         let value_ptr = unsafe { PQgetvalue(self.result, row, col) };
         if value_ptr.is_null() {
@@ -329,21 +657,43 @@ impl Drop for PGResult {
     }
 }
 
-
 /// Convert a microsecond or nanosecond timestamp to a formatted UTC date string.
 ///
 /// # Arguments
 /// * `ts` - The timestamp value for microseconds
 ///
 /// # Returns
-/// A `String` in "YYYY-MM-DD HH:MM:SS.sss UTC" format.
+/// A `String` in "YYYY-MM-DD HH:MM:SS.sss UTC" format, `infinity` or `-infinity` for
+/// PostgreSQL's infinite timestamps, or the raw value when it is out of range.
 pub fn format_timestamp_from_pg(ts: i64) -> String {
+    match ts {
+        i64::MAX => "infinity".to_string(),
+        i64::MIN => "-infinity".to_string(),
+        _ => match pg_timestamp_to_datetime(ts) {
+            Some(timestamp) => timestamp.format("%Y-%m-%d %H:%M:%S%.3f UTC").to_string(),
+            None => format!("{} (out of range)", ts),
+        },
+    }
+}
+
+/// Convert a PostgreSQL timestamp (microseconds since 2000-01-01) to a UTC `DateTime`
+///
+/// None for timestamps chrono cannot represent: PostgreSQL allows years up to 294276 and
+/// infinite timestamps, chrono stops at year 262143.
+pub fn pg_timestamp_to_datetime(ts: i64) -> Option<DateTime<Utc>> {
+    let secs = ts.div_euclid(1_000_000).checked_add(PG_EPOCH_OFFSET_SECS)?;
+    let nsecs = ts.rem_euclid(1_000_000) * 1_000;
 
-    let secs = ts / 1_000_000 + PG_EPOCH_OFFSET_SECS;
-    let nsecs = (ts % 1_000_000) * 1_000;
-    
-    let datetime = DateTime::from_timestamp(secs, nsecs as u32)
-        .expect("Invalid timestamp");
+    DateTime::from_timestamp(secs, nsecs as u32)
+}
 
-    datetime.format("%Y-%m-%d %H:%M:%S%.3f UTC").to_string()
+/// Convert the commit timestamp of a Begin or Commit message, which handlers receive as a
+/// `DateTime`, failing when it is out of range
+pub fn pg_commit_time(ts: i64) -> Result<DateTime<Utc>> {
+    pg_timestamp_to_datetime(ts).ok_or_else(|| {
+        crate::errors::ReplicationError::protocol(format!(
+            "Commit timestamp {} is out of range",
+            format_timestamp_from_pg(ts)
+        ))
+    })
 }
@@ -0,0 +1,214 @@
+//! Stream invariant checks
+//! Validates the order of messages as they arrive: Begin/Commit pairing, increasing commit LSNs,
+//! no row changes outside a transaction and streamed changes only inside a streamed block
+
+use crate::errors::{ReplicationError, Result};
+use crate::types::{Lsn, ReplicationMessageRef};
+use crate::utils::Xid;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+/// What happens when the stream breaks an invariant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValidationMode {
+    /// Messages are not checked
+    #[default]
+    Off,
+    /// Violations are logged and counted
+    Warn,
+    /// The first violation stops the run with an error
+    Strict,
+}
+
+impl FromStr for ValidationMode {
+    type Err = ReplicationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(ValidationMode::Off),
+            "warn" => Ok(ValidationMode::Warn),
+            "strict" => Ok(ValidationMode::Strict),
+            _ => Err(ReplicationError::config(format!(
+                "Invalid validation mode: {}. Valid values are: off, warn, strict",
+                s
+            ))),
+        }
+    }
+}
+
+/// Tracks transaction and streamed block boundaries to detect out-of-order messages
+#[derive(Debug)]
+pub struct StreamValidator {
+    strict: bool,
+    /// Transaction opened by Begin and not committed yet
+    open_xid: Option<Xid>,
+    /// Transaction whose streamed block is open
+    stream_xid: Option<Xid>,
+    last_commit_lsn: Lsn,
+    violations: u64,
+}
+
+impl StreamValidator {
+    /// A validator for the mode, None when validation is off
+    pub fn for_mode(mode: ValidationMode) -> Option<Self> {
+        match mode {
+            ValidationMode::Off => None,
+            ValidationMode::Warn | ValidationMode::Strict => Some(Self {
+                strict: mode == ValidationMode::Strict,
+                open_xid: None,
+                stream_xid: None,
+                last_commit_lsn: Lsn::INVALID,
+                violations: 0,
+            }),
+        }
+    }
+
+    /// Violations found so far
+    pub fn violations(&self) -> u64 {
+        self.violations
+    }
+
+    /// Forget open transactions and blocks after a reconnect, which restarts them from scratch
+    pub fn reset(&mut self) {
+        self.open_xid = None;
+        self.stream_xid = None;
+    }
+
+    /// Check a message received at `lsn`, failing in strict mode when it breaks an invariant
+    pub fn check(&mut self, message: &ReplicationMessageRef<'_>, lsn: Lsn) -> Result<()> {
+        let Some(violation) = self.advance(message) else {
+            return Ok(());
+        };
+
+        self.violations += 1;
+        let context = format!(
+            "at {}, open transaction: {}, open streamed block: {}",
+            lsn,
+            describe_xid(self.open_xid),
+            describe_xid(self.stream_xid)
+        );
+        if self.strict {
+            return Err(ReplicationError::protocol_with_context(
+                format!("Stream invariant violated: {}", violation),
+                context,
+            ));
+        }
+        warn!("Stream invariant violated: {} ({})", violation, context);
+        Ok(())
+    }
+
+    /// Log the number of violations found during the run
+    pub fn log_summary(&self) {
+        info!(
+            violations = self.violations,
+            "Stream validation: {} invariant violations", self.violations
+        );
+    }
+
+    /// Update the tracked boundaries, returning a description of the broken invariant if any
+    fn advance(&mut self, message: &ReplicationMessageRef<'_>) -> Option<String> {
+        match message {
+            ReplicationMessageRef::Begin { xid, .. } => {
+                let violation = if let Some(stream_xid) = self.stream_xid {
+                    Some(format!(
+                        "Begin of transaction {} inside the streamed block of {}",
+                        xid, stream_xid
+                    ))
+                } else {
+                    self.open_xid.map(|open_xid| {
+                        format!(
+                            "Begin of transaction {} before transaction {} committed",
+                            xid, open_xid
+                        )
+                    })
+                };
+                self.open_xid = Some(*xid);
+                violation
+            }
+            ReplicationMessageRef::Commit { commit_lsn, .. } => {
+                let violation = match self.open_xid.take() {
+                    None => Some("Commit without a Begin".to_string()),
+                    Some(_) => None,
+                };
+                violation.or_else(|| self.check_commit_lsn(*commit_lsn))
+            }
+            ReplicationMessageRef::Insert { is_stream, xid, .. }
+            | ReplicationMessageRef::Update { is_stream, xid, .. }
+            | ReplicationMessageRef::Delete { is_stream, xid, .. }
+            | ReplicationMessageRef::Truncate { is_stream, xid, .. } => {
+                self.check_change(*is_stream, *xid)
+            }
+            ReplicationMessageRef::StreamStart { xid, .. } => {
+                let violation = match (self.stream_xid, self.open_xid) {
+                    (Some(stream_xid), _) => Some(format!(
+                        "Stream Start of transaction {} while the block of {} is open",
+                        xid, stream_xid
+                    )),
+                    (None, Some(open_xid)) => Some(format!(
+                        "Stream Start of transaction {} inside transaction {}",
+                        xid, open_xid
+                    )),
+                    (None, None) => None,
+                };
+                self.stream_xid = Some(*xid);
+                violation
+            }
+            ReplicationMessageRef::StreamStop => match self.stream_xid.take() {
+                None => Some("Stream Stop without a Stream Start".to_string()),
+                Some(_) => None,
+            },
+            ReplicationMessageRef::StreamCommit {
+                xid, commit_lsn, ..
+            } => self
+                .check_outside_block("Stream Commit", *xid)
+                .or_else(|| self.check_commit_lsn(*commit_lsn)),
+            ReplicationMessageRef::StreamAbort { xid, .. } => {
+                self.check_outside_block("Stream Abort", *xid)
+            }
+            ReplicationMessageRef::StreamPrepare { xid, .. } => {
+                self.check_outside_block("Stream Prepare", *xid)
+            }
+            _ => None,
+        }
+    }
+
+    fn check_change(&self, is_stream: bool, xid: Option<Xid>) -> Option<String> {
+        match (is_stream, self.stream_xid, self.open_xid) {
+            (true, None, _) => Some(format!(
+                "Streamed change of transaction {} outside a streamed block",
+                describe_xid(xid)
+            )),
+            (false, Some(stream_xid), _) => Some(format!(
+                "Change without a transaction id inside the streamed block of {}",
+                stream_xid
+            )),
+            (false, None, None) => Some("Change outside a transaction".to_string()),
+            _ => None,
+        }
+    }
+
+    fn check_outside_block(&self, kind: &str, xid: Xid) -> Option<String> {
+        self.stream_xid.map(|stream_xid| {
+            format!(
+                "{} of transaction {} while the block of {} is open",
+                kind, xid, stream_xid
+            )
+        })
+    }
+
+    /// Commits must arrive in increasing LSN order
+    fn check_commit_lsn(&mut self, commit_lsn: Lsn) -> Option<String> {
+        let previous = self.last_commit_lsn;
+        self.last_commit_lsn = std::cmp::max(previous, commit_lsn);
+        (commit_lsn <= previous).then(|| {
+            format!(
+                "Commit LSN {} is not after the previous commit at {}",
+                commit_lsn, previous
+            )
+        })
+    }
+}
+
+fn describe_xid(xid: Option<Xid>) -> String {
+    xid.map_or_else(|| "none".to_string(), |xid| xid.to_string())
+}
@@ -0,0 +1,422 @@
+//! Data consistency check between a publisher and a subscriber
+//! Splits each table into ranges of its primary key, compares the row count and an md5 over the
+//! ordered rows of every range on both databases, and can list the rows that differ in the
+//! ranges that do not match. Rows changed while the check runs may show up as differences until
+//! the subscriber has caught up.
+
+use crate::errors::{ReplicationError, Result};
+use crate::quote::{quote_ident, quote_qualified};
+use crate::utils::{PGConnection, PGResult};
+use std::collections::HashMap;
+use std::fmt;
+use tracing::{info, warn};
+
+/// Tables to compare and how closely
+#[derive(Debug, Clone)]
+pub struct VerifyConfig {
+    /// Tables as `schema.table`, or `table` in the public schema
+    pub tables: Vec<String>,
+    /// Rows of the source per compared range
+    pub chunk_rows: u64,
+    /// Compare the rows of ranges that differ one by one
+    pub row_diff: bool,
+    /// Most differing rows listed per table
+    pub max_row_diffs: usize,
+}
+
+/// Primary key values bounding a range, as text; the lower bound is exclusive and the upper
+/// inclusive, None leaves that side open
+#[derive(Debug, Clone, Default)]
+pub struct KeyRange {
+    pub lower: Option<Vec<String>>,
+    pub upper: Option<Vec<String>>,
+}
+
+impl fmt::Display for KeyRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Values of a composite key are grouped
+        let key = |values: &[String]| match values {
+            [value] => value.clone(),
+            values => format!("({})", values.join(", ")),
+        };
+        match &self.lower {
+            Some(lower) => write!(f, "({}", key(lower))?,
+            None => write!(f, "[start")?,
+        }
+        match &self.upper {
+            Some(upper) => write!(f, ", {}]", key(upper)),
+            None => write!(f, ", end]"),
+        }
+    }
+}
+
+/// A range whose rows differ between the databases
+#[derive(Debug, Clone)]
+pub struct RangeMismatch {
+    pub range: KeyRange,
+    pub source_rows: u64,
+    pub target_rows: u64,
+}
+
+/// How a single row differs, identified by its primary key values
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowDiff {
+    MissingOnTarget(String),
+    ExtraOnTarget(String),
+    Differs(String),
+}
+
+impl fmt::Display for RowDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RowDiff::MissingOnTarget(key) => write!(f, "row {} is missing on the target", key),
+            RowDiff::ExtraOnTarget(key) => write!(f, "row {} only exists on the target", key),
+            RowDiff::Differs(key) => write!(f, "row {} differs", key),
+        }
+    }
+}
+
+/// Outcome of comparing one table
+#[derive(Debug, Clone, Default)]
+pub struct TableReport {
+    pub table: String,
+    pub ranges: usize,
+    pub source_rows: u64,
+    pub target_rows: u64,
+    pub mismatches: Vec<RangeMismatch>,
+    pub row_diffs: Vec<RowDiff>,
+    /// More rows differ than were listed
+    pub row_diffs_truncated: bool,
+    /// Why the table could not be compared
+    pub error: Option<String>,
+}
+
+impl TableReport {
+    pub fn matches(&self) -> bool {
+        self.error.is_none() && self.mismatches.is_empty()
+    }
+
+    pub fn log(&self) {
+        if let Some(error) = &self.error {
+            warn!(
+                "VERIFY: table {}: could not be compared: {}",
+                self.table, error
+            );
+            return;
+        }
+        if self.matches() {
+            info!(
+                "VERIFY: table {}: {} rows in {} ranges match",
+                self.table, self.source_rows, self.ranges
+            );
+            return;
+        }
+
+        warn!(
+            "VERIFY: table {}: {} of {} ranges differ, {} rows on the source and {} on the target",
+            self.table,
+            self.mismatches.len(),
+            self.ranges,
+            self.source_rows,
+            self.target_rows
+        );
+        for mismatch in &self.mismatches {
+            warn!(
+                "  range {}: {} rows on the source, {} on the target",
+                mismatch.range, mismatch.source_rows, mismatch.target_rows
+            );
+        }
+        for diff in &self.row_diffs {
+            warn!("  {}", diff);
+        }
+        if self.row_diffs_truncated {
+            warn!(
+                "  more rows differ than the {} listed",
+                self.row_diffs.len()
+            );
+        }
+    }
+}
+
+/// Compare every configured table, one after the other
+pub fn verify_tables(
+    source: &PGConnection,
+    target: &PGConnection,
+    config: &VerifyConfig,
+) -> Vec<TableReport> {
+    config
+        .tables
+        .iter()
+        .map(|table| {
+            let mut report = TableReport {
+                table: table.clone(),
+                ..TableReport::default()
+            };
+            if let Err(e) = verify_table(source, target, config, &mut report) {
+                report.error = Some(e.to_string());
+            }
+            report
+        })
+        .collect()
+}
+
+/// Columns and primary key of a table, quoted for queries
+struct TableShape {
+    qualified_name: String,
+    columns: Vec<String>,
+    key: Vec<String>,
+}
+
+impl TableShape {
+    /// `ROW(...)::text` of the row's columns, hashed to compare rows
+    fn row_hash(&self) -> String {
+        format!("md5(ROW({})::text)", self.columns.join(", "))
+    }
+
+    /// The key columns cast to text
+    fn key_text(&self) -> String {
+        self.key
+            .iter()
+            .map(|column| format!("{}::text", column))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn key_order(&self) -> String {
+        self.key.join(", ")
+    }
+
+    /// WHERE clause selecting a range and its parameters
+    fn range_predicate<'a>(&self, range: &'a KeyRange) -> (String, Vec<Option<&'a str>>) {
+        let key = format!("({})", self.key_order());
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+        let mut bound = |values: &'a [String], operator: &str| {
+            let placeholders = values
+                .iter()
+                .map(|value| {
+                    params.push(Some(value.as_str()));
+                    format!("${}", params.len())
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            conditions.push(format!("{} {} ({})", key, operator, placeholders));
+        };
+        if let Some(lower) = &range.lower {
+            bound(lower, ">");
+        }
+        if let Some(upper) = &range.upper {
+            bound(upper, "<=");
+        }
+        match conditions.is_empty() {
+            true => (String::new(), params),
+            false => (format!(" WHERE {}", conditions.join(" AND ")), params),
+        }
+    }
+}
+
+fn verify_table(
+    source: &PGConnection,
+    target: &PGConnection,
+    config: &VerifyConfig,
+    report: &mut TableReport,
+) -> Result<()> {
+    let shape = table_shape(source, &report.table)?;
+    let ranges = key_ranges(source, &shape, config.chunk_rows.max(1))?;
+    report.ranges = ranges.len();
+
+    for range in ranges {
+        let (source_rows, source_hash) = range_checksum(source, &shape, &range)?;
+        let (target_rows, target_hash) = range_checksum(target, &shape, &range)
+            .map_err(|e| ReplicationError::protocol(format!("on the target: {}", e)))?;
+        report.source_rows += source_rows;
+        report.target_rows += target_rows;
+        if source_rows == target_rows && source_hash == target_hash {
+            continue;
+        }
+
+        if config.row_diff && !report.row_diffs_truncated {
+            let diffs = row_diffs(source, target, &shape, &range)?;
+            let room = config.max_row_diffs.saturating_sub(report.row_diffs.len());
+            report.row_diffs_truncated = diffs.len() > room;
+            report.row_diffs.extend(diffs.into_iter().take(room));
+        }
+        report.mismatches.push(RangeMismatch {
+            range,
+            source_rows,
+            target_rows,
+        });
+    }
+    Ok(())
+}
+
+/// Run a query, turning an error result into an error
+fn query(connection: &PGConnection, sql: &str, params: &[Option<&str>]) -> Result<PGResult> {
+    let result = connection.exec_params(sql, params)?;
+    if !result.is_ok() {
+        let message = result.error_message();
+        // The first line holds the error, the rest its context
+        let message = message.lines().next().unwrap_or_default();
+        return Err(ReplicationError::protocol(message.to_string()));
+    }
+    Ok(result)
+}
+
+/// Look the columns and primary key of a table up on the source
+fn table_shape(connection: &PGConnection, table: &str) -> Result<TableShape> {
+    let (schema, name) = table.split_once('.').unwrap_or(("public", table));
+    let qualified_name = quote_qualified(schema, name);
+
+    let columns = query(
+        connection,
+        "SELECT attname FROM pg_attribute \
+         WHERE attrelid = $1::regclass AND attnum > 0 AND NOT attisdropped ORDER BY attnum",
+        &[Some(&qualified_name)],
+    )?;
+    let key = query(
+        connection,
+        "SELECT a.attname FROM pg_index i \
+         JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey) \
+         WHERE i.indrelid = $1::regclass AND i.indisprimary \
+         ORDER BY array_position(i.indkey::int2[], a.attnum)",
+        &[Some(&qualified_name)],
+    )?;
+    let names = |result: &PGResult| -> Vec<String> {
+        (0..result.ntuples())
+            .filter_map(|row| result.getvalue(row, 0))
+            .map(|name| quote_ident(&name))
+            .collect()
+    };
+
+    let key = names(&key);
+    if key.is_empty() {
+        return Err(ReplicationError::config(format!(
+            "{} has no primary key to split it into ranges",
+            table
+        )));
+    }
+    Ok(TableShape {
+        qualified_name,
+        columns: names(&columns),
+        key,
+    })
+}
+
+/// Split the source table into ranges of `chunk_rows` rows, the last one open-ended so rows
+/// only on the target past the source's last key are compared too
+fn key_ranges(
+    connection: &PGConnection,
+    shape: &TableShape,
+    chunk_rows: u64,
+) -> Result<Vec<KeyRange>> {
+    let sql = format!(
+        "SELECT {key_text} FROM (SELECT {key}, row_number() OVER (ORDER BY {key}) AS n FROM {table}) s \
+         WHERE n % {chunk_rows} = 0 ORDER BY {key}",
+        key_text = shape.key_text(),
+        key = shape.key_order(),
+        table = shape.qualified_name,
+        chunk_rows = chunk_rows
+    );
+    let result = query(connection, &sql, &[])?;
+    let bounds: Vec<Vec<String>> = (0..result.ntuples())
+        .map(|row| {
+            (0..result.nfields())
+                .map(|col| result.getvalue(row, col).unwrap_or_default())
+                .collect()
+        })
+        .collect();
+
+    let mut ranges = Vec::with_capacity(bounds.len() + 1);
+    let mut lower = None;
+    for upper in bounds {
+        ranges.push(KeyRange {
+            lower: lower.replace(upper.clone()),
+            upper: Some(upper),
+        });
+    }
+    ranges.push(KeyRange { lower, upper: None });
+    Ok(ranges)
+}
+
+/// Row count and md5 over the hashes of the rows of a range, in key order
+fn range_checksum(
+    connection: &PGConnection,
+    shape: &TableShape,
+    range: &KeyRange,
+) -> Result<(u64, String)> {
+    let (predicate, params) = shape.range_predicate(range);
+    let sql = format!(
+        "SELECT count(*), coalesce(md5(string_agg({hash}, '' ORDER BY {key})), '') FROM {table}{predicate}",
+        hash = shape.row_hash(),
+        key = shape.key_order(),
+        table = shape.qualified_name,
+        predicate = predicate
+    );
+    let result = query(connection, &sql, &params)?;
+    let rows = result
+        .getvalue(0, 0)
+        .and_then(|count| count.parse().ok())
+        .unwrap_or_default();
+    Ok((rows, result.getvalue(0, 1).unwrap_or_default()))
+}
+
+/// Key and row hash of every row of a range
+fn range_rows(
+    connection: &PGConnection,
+    shape: &TableShape,
+    range: &KeyRange,
+) -> Result<Vec<(String, String)>> {
+    let (predicate, params) = shape.range_predicate(range);
+    let sql = format!(
+        "SELECT {hash}, {key_text} FROM {table}{predicate} ORDER BY {key}",
+        hash = shape.row_hash(),
+        key_text = shape.key_text(),
+        table = shape.qualified_name,
+        predicate = predicate,
+        key = shape.key_order()
+    );
+    let result = query(connection, &sql, &params)?;
+    Ok((0..result.ntuples())
+        .map(|row| {
+            let key = (1..result.nfields())
+                .map(|col| result.getvalue(row, col).unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(", ");
+            (
+                format!("({})", key),
+                result.getvalue(row, 0).unwrap_or_default(),
+            )
+        })
+        .collect())
+}
+
+/// Compare the rows of a range one by one
+fn row_diffs(
+    source: &PGConnection,
+    target: &PGConnection,
+    shape: &TableShape,
+    range: &KeyRange,
+) -> Result<Vec<RowDiff>> {
+    let source_rows = range_rows(source, shape, range)?;
+    let target_rows = range_rows(target, shape, range)?;
+    let mut target_hashes: HashMap<&str, &str> = target_rows
+        .iter()
+        .map(|(key, hash)| (key.as_str(), hash.as_str()))
+        .collect();
+
+    let mut diffs = Vec::new();
+    for (key, hash) in &source_rows {
+        match target_hashes.remove(key.as_str()) {
+            None => diffs.push(RowDiff::MissingOnTarget(key.clone())),
+            Some(target_hash) if target_hash != hash => diffs.push(RowDiff::Differs(key.clone())),
+            Some(_) => {}
+        }
+    }
+    diffs.extend(
+        target_rows
+            .iter()
+            .filter(|(key, _)| target_hashes.contains_key(key.as_str()))
+            .map(|(key, _)| RowDiff::ExtraOnTarget(key.clone())),
+    );
+    Ok(diffs)
+}
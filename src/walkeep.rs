@@ -0,0 +1,180 @@
+//! WAL retention watchdog
+//! A slot keeps the server from removing WAL from its restart_lsn on. With max_slot_wal_keep_size
+//! set, the server invalidates a slot whose retained WAL outgrows the limit, and the slot can never
+//! stream again. The watchdog polls the slot over the control connection, warns while the retained
+//! WAL is above a share of the limit, and estimates how long until the limit is reached from the
+//! rate WAL was written at recently, assuming the slot does not advance in the meantime.
+
+use crate::control::ControlConnection;
+use crate::errors::ErrorKind;
+use crate::types::Lsn;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// Span of the WAL positions the write rate is computed over
+const RATE_WINDOW: Duration = Duration::from_secs(300);
+
+/// How often the slot is polled and when it warns
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WalKeepConfig {
+    pub interval: Duration,
+    /// Share of max_slot_wal_keep_size, in percent, above which each poll warns
+    pub warn_percent: f64,
+}
+
+impl Default for WalKeepConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            warn_percent: 80.0,
+        }
+    }
+}
+
+/// Polls the retained WAL of the streaming slot
+pub struct WalKeepWatchdog {
+    config: WalKeepConfig,
+    slot_name: String,
+    last_check: Option<Instant>,
+    /// WAL positions seen within the rate window, oldest first
+    samples: VecDeque<(Instant, Lsn)>,
+    warning: bool,
+    /// The limit is off, or the slot already lost WAL, which is logged once
+    reported: bool,
+    /// Set when the server cannot report the slot's WAL status
+    disabled: bool,
+}
+
+impl WalKeepWatchdog {
+    pub fn new(config: WalKeepConfig, slot_name: String) -> Self {
+        Self {
+            config,
+            slot_name,
+            last_check: None,
+            samples: VecDeque::new(),
+            warning: false,
+            reported: false,
+            disabled: false,
+        }
+    }
+
+    /// Poll the slot once the interval has passed
+    ///
+    /// Returns the share of max_slot_wal_keep_size used up, in percent, when the slot was
+    /// polled and the limit is set, 100 once the slot has lost WAL.
+    pub fn maybe_check(&mut self, control: &mut ControlConnection) -> Option<f64> {
+        if self.disabled
+            || self
+                .last_check
+                .is_some_and(|last_check| last_check.elapsed() < self.config.interval)
+        {
+            return None;
+        }
+        let now = Instant::now();
+        self.last_check = Some(now);
+
+        let retention = match control.wal_retention() {
+            Ok(Some(retention)) => retention,
+            Ok(None) => {
+                warn!("Replication slot {} no longer exists", self.slot_name);
+                return None;
+            }
+            Err(e) if e.kind() == ErrorKind::Config => {
+                warn!("WAL retention watchdog disabled: {}", e);
+                self.disabled = true;
+                return None;
+            }
+            Err(e) => {
+                warn!("Failed to query the slot's WAL retention: {}", e);
+                return None;
+            }
+        };
+
+        self.samples.push_back((now, retention.current_lsn));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+
+        let (Some(retained), Some(max_keep)) =
+            (retention.retained_bytes(), retention.max_keep_bytes)
+        else {
+            if !self.reported {
+                self.reported = true;
+                if retention.wal_status.as_deref() == Some("lost") {
+                    error!(
+                        "Replication slot {} has lost WAL it needs and can no longer stream; recreate it",
+                        self.slot_name
+                    );
+                    return Some(100.0);
+                }
+                info!(
+                    "max_slot_wal_keep_size is unlimited, so the WAL slot {} retains is only bounded by disk space",
+                    self.slot_name
+                );
+            }
+            return None;
+        };
+
+        // The server counts the limit in whole segments from restart_lsn's, and safe_wal_size is
+        // what it will still allow, so the share used up is taken from that when it is known
+        let remaining = retention.safe_wal_size.map_or_else(
+            || max_keep.saturating_sub(retained),
+            |safe| safe.max(0) as u64,
+        );
+        let used = max_keep.saturating_sub(remaining) as f64 * 100.0 / max_keep.max(1) as f64;
+        let rate = self.write_rate();
+        debug!(
+            retained,
+            max_keep,
+            remaining,
+            "Slot {} retains {} bytes of WAL, {:.1}% of max_slot_wal_keep_size used, WAL status: {}",
+            self.slot_name,
+            retained,
+            used,
+            retention.wal_status.as_deref().unwrap_or("-")
+        );
+
+        if used >= self.config.warn_percent {
+            let estimate = match rate.filter(|rate| *rate > 0.0) {
+                Some(rate) => format!(
+                    "WAL is written at {:.0} bytes/s, so the limit is reached in about {} seconds unless the slot advances",
+                    rate,
+                    (remaining as f64 / rate).ceil() as u64
+                ),
+                None => "no WAL was written recently, so it is not growing".to_string(),
+            };
+            warn!(
+                retained,
+                max_keep,
+                remaining,
+                "Slot {} retains {} bytes of WAL, {:.1}% of max_slot_wal_keep_size ({} bytes) used, WAL status: {}; {}",
+                self.slot_name,
+                retained,
+                used,
+                max_keep,
+                retention.wal_status.as_deref().unwrap_or("-"),
+                estimate
+            );
+        } else if self.warning {
+            info!(
+                "Slot {} uses {:.1}% of max_slot_wal_keep_size again, below the warning threshold of {}%",
+                self.slot_name, used, self.config.warn_percent
+            );
+        }
+        self.warning = used >= self.config.warn_percent;
+        Some(used)
+    }
+
+    /// WAL bytes written per second across the samples, None until there are two
+    fn write_rate(&self) -> Option<f64> {
+        let (first_at, first_lsn) = self.samples.front()?;
+        let (last_at, last_lsn) = self.samples.back()?;
+        let elapsed = last_at.duration_since(*first_at).as_secs_f64();
+        (elapsed > 0.0).then(|| last_lsn.bytes_since(*first_lsn) as f64 / elapsed)
+    }
+}
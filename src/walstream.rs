@@ -0,0 +1,968 @@
+//! pg-walstream streaming backend
+//! pg-walstream speaks the replication protocol and decodes pgoutput; its events are converted to
+//! `ChangeRecord`s and go through the same filters, masking, sinks and stop conditions as those of
+//! the libpq backend
+
+use crate::alert::AlertMonitor;
+use crate::avro::AvroSink;
+use crate::ctlsocket::StreamControl;
+use crate::envelope::TransactionEnvelopes;
+use crate::errors::{ReplicationError, Result};
+use crate::eventid::EventIds;
+use crate::events::{EventCollector, EventStream};
+use crate::handler::ChangeHandler;
+use crate::health::HealthState;
+use crate::output::{ChangeRecord, EventWriter};
+#[cfg(feature = "parquet")]
+use crate::parquet::ParquetSink;
+use crate::publication::log_published_tables;
+use crate::quote::publication_names_option;
+use crate::reconnect::ReconnectPolicy;
+use crate::reload::PendingReload;
+use crate::row::{format_changes, Row, RowValue};
+#[cfg(feature = "s3")]
+use crate::s3::S3Sink;
+use crate::sample::EventSampler;
+use crate::sink::{EventSink, SinkFanout, SinkOptions};
+use crate::slot::{drop_slot, prepare_slot, SlotMode};
+use crate::stats::DelayStats;
+use crate::summary::RunSummary;
+use crate::txspan::TransactionSpans;
+use crate::types::{
+    truncated_text, utf8_prefix, ColumnInfo, FeedbackMode, Lsn, OriginFilter, RelationInfo,
+    ReplicationConfig, UpdateDisplayMode,
+};
+use crate::utils::{PGConnection, Xid};
+use crate::validate::ValidationMode;
+use crate::watch::{ChangeKind, WatchSummary};
+use chrono::{DateTime, Utc};
+use pg_walstream::{
+    CancellationToken, ChangeEvent, EventType, LogicalReplicationStream, ReplicaIdentity,
+    ReplicationStreamConfig, RetryConfig, SharedLsnFeedback,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn, Span};
+
+/// Longest the loop waits for an event before checking stop conditions and timers again
+const IDLE_WAKEUP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// pg-walstream decodes every column as text
+const TEXT_TYPE_OID: u32 = 25;
+
+/// Streams changes through pg-walstream
+pub struct WalstreamBackend {
+    config: ReplicationConfig,
+    /// Structured output, reopened when a reload changes it
+    output: Option<EventWriter>,
+    handlers: Vec<Box<dyn ChangeHandler>>,
+    #[cfg(feature = "parquet")]
+    parquet: Option<ParquetSink>,
+    avro: Option<AvroSink>,
+    #[cfg(feature = "s3")]
+    s3: Option<S3Sink>,
+    /// Sinks of the `[[sink]]` tables and those added with `add_sink`
+    fanout: SinkFanout,
+    /// Transactions handed to an `EventStream`
+    events: Option<EventCollector>,
+    /// Columns each table was last registered with in the Parquet and Avro sinks
+    relation_columns: HashMap<(String, String), Vec<String>>,
+    feedback: Arc<SharedLsnFeedback>,
+    shutdown: Arc<AtomicBool>,
+    reload: Arc<PendingReload>,
+    health: Arc<HealthState>,
+    watch: Option<WatchSummary>,
+    summary: RunSummary,
+    sampler: EventSampler,
+    delays: DelayStats,
+    alerts: Option<AlertMonitor>,
+    stream_control: Arc<StreamControl>,
+    /// Commit time of the transaction being received, None inside streamed transactions
+    commit_time: Option<DateTime<Utc>>,
+    events_processed: u64,
+    /// Position of the last event received
+    last_lsn: Lsn,
+    /// Transaction the changes being received belong to
+    current_xid: Option<Xid>,
+    skipping_transaction: bool,
+    /// Spans of the transactions in progress, their events are processed within
+    transaction_spans: TransactionSpans,
+    /// Numbers emitted changes when event IDs are enabled
+    event_ids: Option<EventIds>,
+    /// Builds the transaction metadata of emitted changes when the envelope is enabled
+    envelopes: Option<TransactionEnvelopes>,
+}
+
+impl WalstreamBackend {
+    /// Create the backend, refusing options only the libpq backend implements
+    pub fn new(config: ReplicationConfig) -> Result<Self> {
+        let unsupported = unsupported_options(&config);
+        if !unsupported.is_empty() {
+            return Err(ReplicationError::config(format!(
+                "Not supported by the walstream backend, use --backend libpq: {}",
+                unsupported.join(", ")
+            )));
+        }
+
+        let output = EventWriter::open(&config.output)?;
+        #[cfg(feature = "parquet")]
+        let parquet = config
+            .parquet
+            .clone()
+            .map(|parquet| ParquetSink::new(parquet, config.column_mask.clone()))
+            .transpose()?;
+        let avro = config
+            .avro
+            .clone()
+            .map(|avro| AvroSink::new(avro, config.column_mask.clone()))
+            .transpose()?;
+        #[cfg(feature = "s3")]
+        let s3 = config
+            .s3
+            .clone()
+            .map(|s3| S3Sink::new(s3, config.column_mask.clone()))
+            .transpose()?;
+        let mut fanout = SinkFanout::from_configs(&config.sinks)?;
+        if let Some(webhook) = &config.webhook {
+            fanout.add_webhook(webhook)?;
+        }
+        let watch = config.watch_duration.map(|_| WatchSummary::new());
+        let event_ids = config.event_ids.then(EventIds::new);
+        let envelopes = config
+            .transaction_envelope
+            .then(|| TransactionEnvelopes::new(config.slot_name.clone()));
+        let delays = DelayStats::new(Duration::from_secs(config.stats_interval_secs));
+        let alerts = config
+            .alerts
+            .clone()
+            .map(|alerts| {
+                AlertMonitor::new(alerts, config.slot_name.clone(), config.source.clone())
+            })
+            .transpose()?;
+        let transaction_spans = TransactionSpans::new(config.source.clone());
+
+        Ok(Self {
+            config,
+            output,
+            handlers: Vec::new(),
+            #[cfg(feature = "parquet")]
+            parquet,
+            avro,
+            #[cfg(feature = "s3")]
+            s3,
+            fanout,
+            events: None,
+            relation_columns: HashMap::new(),
+            feedback: SharedLsnFeedback::new_shared(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            reload: Arc::new(PendingReload::default()),
+            health: Arc::new(HealthState::new()),
+            watch,
+            summary: RunSummary::new(),
+            sampler: EventSampler::new(),
+            delays,
+            alerts,
+            stream_control: Arc::new(StreamControl::new()),
+            commit_time: None,
+            events_processed: 0,
+            last_lsn: Lsn::INVALID,
+            current_xid: None,
+            skipping_transaction: false,
+            transaction_spans,
+            event_ids,
+            envelopes,
+        })
+    }
+
+    /// Register a handler that receives every decoded transaction
+    pub fn add_handler(&mut self, handler: impl ChangeHandler + 'static) {
+        self.handlers.push(Box::new(handler));
+    }
+
+    /// Send batches of change events to a sink running on its own thread, next to the sinks of
+    /// the `[[sink]]` tables; the position confirmed never passes what it acknowledged
+    pub fn add_sink(&mut self, options: SinkOptions, sink: impl EventSink + 'static) -> Result<()> {
+        self.fanout.add(options, Box::new(sink))
+    }
+
+    /// Receive committed transactions as a stream; pg-walstream may only confirm the positions
+    /// acknowledged with `EventStream::ack`, and reading pauses while `capacity` transactions
+    /// wait in the stream. Replaces a stream taken before.
+    pub fn event_stream(&mut self, capacity: usize) -> EventStream {
+        let (collector, stream) = EventCollector::new(capacity, self.shutdown.clone());
+        self.events = Some(collector);
+        stream
+    }
+
+    /// Flag that stops the stream gracefully once set, e.g. from a signal handler
+    pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        self.shutdown.clone()
+    }
+
+    /// Settings reloaded at runtime, switched to between transactions
+    pub fn reload_handle(&self) -> Arc<PendingReload> {
+        self.reload.clone()
+    }
+
+    /// Liveness and readiness of the stream, as served by the health endpoint
+    pub fn health_state(&self) -> Arc<HealthState> {
+        self.health.clone()
+    }
+
+    /// Pause, resume and feedback requests for the streaming loop
+    pub fn stream_control(&self) -> Arc<StreamControl> {
+        self.stream_control.clone()
+    }
+
+    /// Prepare the slot and stream changes until shutdown or a stop condition
+    pub async fn stream(&mut self) -> Result<()> {
+        log_published_tables(
+            &self.config.connection_string,
+            &self.config.publication_names,
+        );
+        {
+            let connection = PGConnection::connect(&self.config.connection_string)?;
+            connection.log_tls_state();
+            prepare_slot(&connection, &self.config.slot_name, self.config.slot_mode)?;
+        }
+
+        info!("Creating logical replication stream");
+        let mut stream =
+            LogicalReplicationStream::new(&self.config.connection_string, self.stream_config())
+                .await?;
+        stream.set_shared_lsn_feedback(self.feedback.clone());
+        self.health.set_connected(true);
+
+        // Without a start LSN the server resumes from the slot's confirmed position
+        match self.config.start_lsn {
+            Some(lsn) => info!("Starting replication stream from LSN {}", lsn),
+            None => info!("Starting replication stream from latest position"),
+        }
+        stream.start(self.config.start_lsn.map(Lsn::value)).await?;
+        self.health.set_streaming(true);
+        if let Some(start_time) = self.config.start_time {
+            info!(
+                "Skipping transactions committed before {}",
+                start_time.to_rfc3339()
+            );
+        }
+
+        // Never cancelled: the loop wakes up on its own to check the shutdown flag
+        let cancel_token = CancellationToken::new();
+        let mut paused_feedback = Instant::now();
+        loop {
+            if let Some(reason) = self
+                .config
+                .stop_conditions
+                .check(self.events_processed, Lsn(stream.current_lsn()))
+            {
+                info!("Stop condition met: {}", reason);
+                break;
+            }
+            if self.shutdown.load(Ordering::Relaxed) {
+                info!("Shutdown requested, stopping stream");
+                break;
+            }
+            self.apply_reload()?;
+
+            #[cfg(feature = "s3")]
+            if let Some(s3) = self.s3.as_mut() {
+                s3.maybe_flush()?;
+            }
+            self.fanout.check()?;
+            self.confirm_position();
+            self.delays.maybe_report(&self.health);
+            self.sampler.maybe_publish(&self.health);
+            if self.stream_control.take_feedback_request() {
+                info!("Sending feedback requested over the control socket");
+                stream.send_feedback()?;
+            }
+            let paused = self.stream_control.is_paused();
+            self.health.set_paused(paused);
+            // pg-walstream does not report the server's WAL end; nothing is expected from the
+            // server while paused
+            if let Some(alerts) = self.alerts.as_mut().filter(|_| !paused) {
+                alerts.maybe_check(None, self.health.since_last_message())?;
+            }
+            // An application behind on the event stream holds reading like a pause
+            if paused || self.events.as_ref().is_some_and(EventCollector::is_full) {
+                // pg-walstream only sends status updates while reading, so keep the connection
+                // alive with the positions that hold still
+                let interval = Duration::from_secs(self.config.feedback_interval_secs);
+                if paused_feedback.elapsed() >= interval {
+                    stream.send_feedback()?;
+                    paused_feedback = Instant::now();
+                }
+                tokio::time::sleep(IDLE_WAKEUP_INTERVAL).await;
+                continue;
+            }
+
+            // next_event only awaits socket readability before reading, so abandoning it on
+            // timeout loses no data
+            match tokio::time::timeout(IDLE_WAKEUP_INTERVAL, stream.next_event(&cancel_token)).await
+            {
+                Err(_) => continue,
+                Ok(event) => {
+                    self.health.record_message();
+                    if let Some(event) = event? {
+                        self.process_event(event)?;
+                    }
+                }
+            }
+        }
+
+        self.finish_stream()?;
+        // Confirm the final position on every graceful stop, so the slot's confirmed_flush_lsn
+        // is current before the connection closes
+        stream.send_feedback()?;
+        info!("Stopping replication stream");
+        stream.stop().await?;
+        drop(stream);
+        self.health.set_connected(false);
+
+        if self.config.drop_slot_on_exit {
+            // The slot stays active until the streaming connection is closed
+            let connection = PGConnection::connect(&self.config.connection_string)?;
+            drop_slot(&connection, &self.config.slot_name)?;
+            info!("Dropped replication slot {}", self.config.slot_name);
+        }
+        Ok(())
+    }
+
+    fn stream_config(&self) -> ReplicationStreamConfig {
+        ReplicationStreamConfig::new(
+            self.config.slot_name.clone(),
+            walstream_publication_names(&self.config.publication_names),
+            self.config.protocol_version,
+            self.config.streaming,
+            Duration::from_secs(self.config.feedback_interval_secs),
+            Duration::from_secs(30), // Connection timeout
+            Duration::from_secs(60), // Health check interval
+            retry_config(&self.config.reconnect),
+        )
+    }
+
+    /// Pass one event through the filters to the sinks
+    fn process_event(&mut self, event: ChangeEvent) -> Result<()> {
+        // The stream hands over decoded events, the time one is taken is as close to its receipt
+        // as this backend sees
+        let received_at = Utc::now();
+        // Feedback must still advance past changes to filtered-out tables
+        if let Some(lsn) = event.lsn {
+            self.last_lsn = Lsn(lsn.value());
+            self.summary.record_position(self.last_lsn);
+        }
+
+        if self.skip_before_start_time(&event) {
+            return Ok(());
+        }
+
+        // pg-walstream reports no LSN at Begin, the span only gets the commit's
+        let xid = match &event.event_type {
+            EventType::Begin { transaction_id, .. } => {
+                self.transaction_spans.begin(*transaction_id, None);
+                Some(*transaction_id)
+            }
+            EventType::StreamStart { transaction_id, .. } => {
+                self.transaction_spans.start_segment(*transaction_id);
+                Some(*transaction_id)
+            }
+            EventType::StreamCommit { transaction_id, .. }
+            | EventType::StreamAbort { transaction_id } => Some(*transaction_id),
+            _ => self.current_xid,
+        };
+        let _span = self.transaction_spans.span(xid).map(Span::entered);
+
+        match &event.event_type {
+            EventType::Begin {
+                transaction_id,
+                commit_timestamp,
+            } => {
+                self.current_xid = Some(*transaction_id);
+                self.commit_time = Some(*commit_timestamp);
+                if let Some(envelopes) = self.envelopes.as_mut() {
+                    envelopes.begin(Some(*transaction_id), None, *commit_timestamp);
+                }
+                let lsn = self.last_lsn;
+                for handler in self.sinks() {
+                    handler.on_begin(*transaction_id, lsn, *commit_timestamp)?;
+                }
+            }
+            EventType::Commit { commit_timestamp }
+            | EventType::StreamCommit {
+                commit_timestamp, ..
+            } => {
+                if let Some(xid) = xid {
+                    let lsn = Some(self.last_lsn);
+                    self.transaction_spans.end(xid, "committed", lsn, None);
+                }
+                if let (Some(ids), Some(xid)) = (self.event_ids.as_mut(), self.current_xid) {
+                    ids.end_streamed(xid);
+                }
+                if let Some(envelopes) = self.envelopes.as_mut() {
+                    envelopes.commit();
+                    if let Some(xid) = self.current_xid {
+                        envelopes.end_streamed(xid);
+                    }
+                }
+                self.current_xid = None;
+                self.commit_time = None;
+                self.record_commit(*commit_timestamp);
+                let lsn = self.last_lsn;
+                for handler in self.sinks() {
+                    handler.on_commit(lsn, lsn, *commit_timestamp)?;
+                }
+            }
+            EventType::StreamStart {
+                transaction_id,
+                first_segment,
+            } => {
+                self.current_xid = Some(*transaction_id);
+                self.commit_time = None;
+                if *first_segment {
+                    self.summary.record_streamed_transaction();
+                }
+            }
+            EventType::StreamStop => self.current_xid = None,
+            EventType::StreamAbort { transaction_id } => {
+                self.transaction_spans
+                    .end(*transaction_id, "aborted", None, None);
+                if let Some(ids) = self.event_ids.as_mut() {
+                    ids.end_streamed(*transaction_id);
+                }
+                if let Some(envelopes) = self.envelopes.as_mut() {
+                    envelopes.end_streamed(*transaction_id);
+                }
+                self.current_xid = None;
+            }
+            _ => {}
+        }
+
+        // Columns identifying the row, for the changed-only and diff displays of an UPDATE
+        let identity = match &event.event_type {
+            EventType::Update {
+                replica_identity,
+                key_columns,
+                ..
+            } => Some((
+                key_columns.as_slice(),
+                matches!(replica_identity, ReplicaIdentity::Full),
+            )),
+            _ => None,
+        };
+        let mut records = change_records(
+            &event,
+            self.current_xid,
+            &self.config,
+            self.event_ids.as_mut(),
+        );
+        if records.is_empty() {
+            return Ok(());
+        }
+        records.retain(|record| self.selected(record));
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.events_processed += 1;
+
+        for record in &records {
+            let table = format!("{}.{}", record.schema, record.table);
+            self.summary.record_change(&table, record.op);
+            self.transaction_spans
+                .record_change(self.current_xid, &table, record.op);
+            if let Some(watch) = self.watch.as_mut() {
+                watch.record_change(&table, record.op);
+            }
+            if let Some(commit_time) = self.commit_time {
+                self.delays.record(&table, commit_time);
+            }
+        }
+        // Changes left out by sampling are counted above but not displayed or emitted
+        if !self.config.sampling.is_empty() {
+            let (sampler, sampling) = (&mut self.sampler, &self.config.sampling);
+            records.retain(|record| {
+                matches!(record.op, ChangeKind::Truncate)
+                    || sampler.keep(sampling, &record.schema, &record.table)
+            });
+            if records.is_empty() {
+                return Ok(());
+            }
+        }
+        if let Some(envelopes) = self.envelopes.as_mut() {
+            // Only streamed transactions have no commit time while their changes arrive
+            let streamed_xid = self.current_xid.filter(|_| self.commit_time.is_none());
+            for record in &mut records {
+                record.transaction = envelopes.next(streamed_xid).map(|mut info| {
+                    // Keepalive send times are not exposed, so there is no clock skew estimate
+                    self.config
+                        .event_timestamps
+                        .stamp(&mut info, received_at, None);
+                    info
+                });
+            }
+        }
+
+        for record in &mut records {
+            if let Some(max) = self.config.max_column_bytes {
+                let truncated = truncate_values(record, max);
+                self.summary.record_truncated_values(truncated);
+            }
+            self.register_columns(record)?;
+            mask_record(&self.config, record);
+            if let (Some((key_columns, full_identity)), Some(new)) = (identity, &record.new) {
+                if self.config.update_display != UpdateDisplayMode::Full {
+                    let diff = self.config.update_display == UpdateDisplayMode::Diff;
+                    let changes =
+                        new.changes(record.old.as_ref(), key_columns, full_identity, diff);
+                    info!(
+                        "{:?} {}.{} changed: [{}]",
+                        record.op,
+                        record.schema,
+                        record.table,
+                        format_changes(&changes)
+                    );
+                    continue;
+                }
+            }
+            let row = |row: &Option<Row>| match row {
+                Some(row) => format!("[{}]", row.display(|_| None)),
+                None => "-".to_string(),
+            };
+            info!(
+                "{:?} {}.{} old: {} new: {}",
+                record.op,
+                record.schema,
+                record.table,
+                row(&record.old),
+                row(&record.new)
+            );
+        }
+
+        for handler in self.sinks() {
+            for record in &records {
+                handler.on_change(record)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the record passes the table filter and the row filter of its table
+    fn selected(&self, record: &ChangeRecord) -> bool {
+        if !self
+            .config
+            .table_filter
+            .matches(&record.schema, &record.table)
+        {
+            return false;
+        }
+        let row = match record.op {
+            ChangeKind::Insert | ChangeKind::Update => record.new.as_ref(),
+            ChangeKind::Delete => record.old.as_ref(),
+            ChangeKind::Truncate => return true,
+        };
+        let Some(row) = row else {
+            return true;
+        };
+
+        let value = |name: &str| row.get(name)?.as_text().map(str::to_string);
+        self.config
+            .row_filter
+            .matches(&record.schema, &record.table, &value)
+    }
+
+    /// Describe a table to the Parquet, Avro and S3 sinks from the columns of its rows; pg-walstream
+    /// does not pass Relation messages on, so every column is written as text. Inserts carry
+    /// every column, other changes only describe tables not seen before.
+    fn register_columns(&mut self, record: &ChangeRecord) -> Result<()> {
+        #[cfg(feature = "parquet")]
+        let parquet = self.parquet.is_some();
+        #[cfg(not(feature = "parquet"))]
+        let parquet = false;
+        #[cfg(feature = "s3")]
+        let s3 = self.s3.is_some();
+        #[cfg(not(feature = "s3"))]
+        let s3 = false;
+        if !parquet && self.avro.is_none() && !s3 {
+            return Ok(());
+        }
+        let Some(row) = record.new.as_ref().or(record.old.as_ref()) else {
+            return Ok(());
+        };
+
+        let key = (record.schema.clone(), record.table.clone());
+        let columns: Vec<String> = row.names().map(str::to_string).collect();
+        let known = self.relation_columns.get(&key);
+        if known == Some(&columns) || (known.is_some() && !matches!(record.op, ChangeKind::Insert))
+        {
+            return Ok(());
+        }
+
+        debug!(
+            "Registering {} columns of {}.{}",
+            columns.len(),
+            key.0,
+            key.1
+        );
+        let relation = RelationInfo {
+            oid: 0,
+            namespace: key.0.clone(),
+            relation_name: key.1.clone(),
+            replica_identity: 'd',
+            column_count: columns.len() as i16,
+            columns: columns
+                .iter()
+                .map(|name| ColumnInfo {
+                    key_flag: 0,
+                    column_name: name.clone(),
+                    column_type: TEXT_TYPE_OID,
+                    atttypmod: -1,
+                    type_name: None,
+                })
+                .collect(),
+            published_mask: None,
+        };
+        #[cfg(feature = "parquet")]
+        if let Some(parquet) = self.parquet.as_mut() {
+            parquet.register_relation(&relation)?;
+        }
+        if let Some(avro) = self.avro.as_mut() {
+            avro.register_relation(&relation)?;
+        }
+        #[cfg(feature = "s3")]
+        if let Some(s3) = self.s3.as_mut() {
+            s3.register_relation(&relation)?;
+        }
+        self.relation_columns.insert(key, columns);
+        Ok(())
+    }
+
+    /// Whether the event belongs to a transaction committed before the start time; streamed
+    /// transactions only reveal their commit time when they commit and are never skipped
+    fn skip_before_start_time(&mut self, event: &ChangeEvent) -> bool {
+        let Some(start_time) = self.config.start_time else {
+            return false;
+        };
+
+        match &event.event_type {
+            EventType::Begin {
+                commit_timestamp, ..
+            } => {
+                self.skipping_transaction = *commit_timestamp < start_time;
+                self.skipping_transaction
+            }
+            EventType::Commit { .. } => std::mem::take(&mut self.skipping_transaction),
+            EventType::Insert { .. }
+            | EventType::Update { .. }
+            | EventType::Delete { .. }
+            | EventType::Truncate(_) => self.skipping_transaction,
+            _ => false,
+        }
+    }
+
+    fn record_commit(&mut self, commit_time: DateTime<Utc>) {
+        self.summary.record_commit(commit_time);
+        if let Some(alerts) = self.alerts.as_mut() {
+            alerts.record_commit();
+        }
+        if let Some(watch) = self.watch.as_mut() {
+            watch.record_commit(commit_time);
+        }
+    }
+
+    /// Publish the position pg-walstream may confirm, holding back events the webhook or a
+    /// `[[sink]]` sink has not acknowledged, S3 has not stored and the application has not
+    /// acknowledged
+    fn confirm_position(&self) {
+        #[cfg(feature = "s3")]
+        let s3 = self.s3.as_ref().and_then(|s3| s3.pending_lsn());
+        #[cfg(not(feature = "s3"))]
+        let s3 = None;
+        let sinks = self.fanout.pending_lsn();
+        let events = self.events.as_ref().and_then(EventCollector::pending_lsn);
+        let lsn = s3
+            .into_iter()
+            .chain(sinks)
+            .chain(events)
+            .fold(self.last_lsn, std::cmp::min);
+        self.feedback.update_applied_lsn(lsn.value());
+        self.stream_control.set_confirmed_lsn(lsn);
+    }
+
+    /// Deliver and persist everything before the stream stops
+    fn finish_stream(&mut self) -> Result<()> {
+        self.fanout.close()?;
+        #[cfg(feature = "parquet")]
+        if let Some(parquet) = self.parquet.as_mut() {
+            parquet.close()?;
+            let (files, rows) = parquet.files_written();
+            info!("Wrote {} rows to {} Parquet files", rows, files);
+        }
+        if let Some(avro) = self.avro.as_mut() {
+            avro.close()?;
+            info!("Wrote {} Avro messages", avro.messages_written());
+        }
+        #[cfg(feature = "s3")]
+        if let Some(s3) = self.s3.as_mut() {
+            s3.close()?;
+            let (objects, bytes) = s3.objects_uploaded();
+            info!("Uploaded {} objects ({} bytes) to S3", objects, bytes);
+        }
+        self.confirm_position();
+        info!(
+            "Confirming flushed position {} before stopping",
+            Lsn(self.feedback.get_flushed_lsn())
+        );
+
+        if let Some(watch) = &self.watch {
+            watch.log_summary();
+        }
+        self.sampler.log_summary();
+        self.summary.log_summary();
+        Ok(())
+    }
+
+    /// Switch to settings reloaded since the last call, once no transaction is open
+    fn apply_reload(&mut self) -> Result<()> {
+        if self.current_xid.is_some() {
+            return Ok(());
+        }
+        let Some(mut settings) = self.reload.take() else {
+            return Ok(());
+        };
+
+        if settings.display_timezone.is_some() {
+            warn!("A display time zone needs the libpq backend, keeping the current display");
+            settings.display_timezone = None;
+        }
+        if settings.output != self.config.output {
+            // Flush and close the old destination before the new one is opened
+            self.output = None;
+            self.output = EventWriter::open(&settings.output)?;
+        }
+        settings.apply_to(&mut self.config);
+        info!("Reloaded display, output and filter settings");
+        crate::backend::log_selection(&self.config);
+        Ok(())
+    }
+
+    /// The structured output and registered handlers followed by the Parquet, Avro and S3 sinks,
+    /// the `[[sink]]` fan-out with the webhook and the event stream
+    fn sinks(&mut self) -> impl Iterator<Item = &mut (dyn ChangeHandler + 'static)> {
+        #[cfg(feature = "parquet")]
+        let parquet = self
+            .parquet
+            .as_mut()
+            .map(|parquet| parquet as &mut (dyn ChangeHandler + 'static));
+        #[cfg(not(feature = "parquet"))]
+        let parquet = None;
+        #[cfg(feature = "s3")]
+        let s3 = self
+            .s3
+            .as_mut()
+            .map(|s3| s3 as &mut (dyn ChangeHandler + 'static));
+        #[cfg(not(feature = "s3"))]
+        let s3 = None;
+        self.output
+            .as_mut()
+            .map(|output| output as &mut (dyn ChangeHandler + 'static))
+            .into_iter()
+            .chain(self.handlers.iter_mut().map(|handler| handler.as_mut()))
+            .chain(parquet)
+            .chain(
+                self.avro
+                    .as_mut()
+                    .map(|avro| avro as &mut (dyn ChangeHandler + 'static)),
+            )
+            .chain(s3)
+            .chain(
+                (!self.fanout.is_empty())
+                    .then_some(&mut self.fanout as &mut (dyn ChangeHandler + 'static)),
+            )
+            .chain(
+                self.events
+                    .as_mut()
+                    .map(|events| events as &mut (dyn ChangeHandler + 'static)),
+            )
+    }
+}
+
+/// Options set in the configuration that pg-walstream gives no way to honour
+pub(crate) fn unsupported_options(config: &ReplicationConfig) -> Vec<&'static str> {
+    [
+        (config.slot_mode == SlotMode::Temporary, "temporary slots"),
+        (config.parallel_streaming, "parallel streaming"),
+        (config.binary, "binary format"),
+        (config.origin == OriginFilter::None, "origin filtering"),
+        (config.display_timezone.is_some(), "display time zone"),
+        (
+            config.feedback_mode == FeedbackMode::OnRequest,
+            "on-request feedback",
+        ),
+        (
+            config.validation != ValidationMode::Off,
+            "stream validation",
+        ),
+        (config.dump_raw, "raw message dumps"),
+        (
+            config.subscription_stats_interval_secs > 0,
+            "subscription statistics",
+        ),
+        (config.transaction_buffer.is_some(), "transaction buffering"),
+        (config.relation_cache_size.is_some(), "relation cache size"),
+        (config.toast_cache_rows.is_some(), "TOAST cache"),
+        (config.transaction_limits.is_some(), "transaction limits"),
+        (config.rate_limits.is_some(), "rate limits"),
+        (config.control_connection, "control connection"),
+        (
+            config.target_connection_string.is_some(),
+            "applying to a target",
+        ),
+        (config.checkpoint_path.is_some(), "checkpoint file"),
+        (config.commit_log_path.is_some(), "commit log"),
+        (config.record_path.is_some(), "recording"),
+        (config.init_sync.is_some(), "initial sync"),
+        (
+            config
+                .alerts
+                .as_ref()
+                .is_some_and(|alerts| alerts.max_bytes_behind.is_some()),
+            "bytes-behind alert",
+        ),
+        (
+            config
+                .alerts
+                .as_ref()
+                .is_some_and(|alerts| alerts.max_wal_keep_percent.is_some()),
+            "WAL keep alert",
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(set, option)| set.then_some(option))
+    .collect()
+}
+
+/// Convert a row change event into change records, one per affected table
+///
+/// With `ids` set, each record is numbered within its transaction, which is keyed by its xid
+/// since pg-walstream does not report the commit LSN at Begin; truncated tables have no OID
+fn change_records(
+    event: &ChangeEvent,
+    xid: Option<Xid>,
+    config: &ReplicationConfig,
+    mut ids: Option<&mut EventIds>,
+) -> Vec<ChangeRecord> {
+    let lsn = event.lsn.map(|lsn| Lsn(lsn.value()).to_string());
+    let publications = &config.publication_names;
+    let mut record = |schema: &str, table: &str, op: ChangeKind, relation_oid: u32| ChangeRecord {
+        schema_version: config.event_schema.field(),
+        id: ids.as_mut().and_then(|ids| ids.next(xid, relation_oid)),
+        source: config.source.clone(),
+        op,
+        schema: schema.to_string(),
+        table: table.to_string(),
+        lsn: lsn.clone(),
+        xid,
+        publications: publications.to_vec(),
+        transaction: None,
+        old: None,
+        new: None,
+    };
+    let row = Row::from_json;
+
+    match &event.event_type {
+        EventType::Insert {
+            schema,
+            table,
+            relation_oid,
+            data,
+        } => vec![ChangeRecord {
+            new: Some(row(data)),
+            ..record(schema, table, ChangeKind::Insert, *relation_oid)
+        }],
+        EventType::Update {
+            schema,
+            table,
+            relation_oid,
+            old_data,
+            new_data,
+            ..
+        } => vec![ChangeRecord {
+            old: old_data.as_ref().map(row),
+            new: Some(row(new_data)),
+            ..record(schema, table, ChangeKind::Update, *relation_oid)
+        }],
+        EventType::Delete {
+            schema,
+            table,
+            relation_oid,
+            old_data,
+            ..
+        } => vec![ChangeRecord {
+            old: Some(row(old_data)),
+            ..record(schema, table, ChangeKind::Delete, *relation_oid)
+        }],
+        EventType::Truncate(tables) => tables
+            .iter()
+            .map(|name| {
+                let (schema, table) = name.split_once('.').unwrap_or(("public", name));
+                record(schema, table, ChangeKind::Truncate, 0)
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Cut string values longer than `max` bytes, returning how many were cut
+///
+/// pg-walstream has already decoded the whole value, so this bounds what reaches the sinks
+/// rather than what is held while decoding
+fn truncate_values(record: &mut ChangeRecord, max: usize) -> usize {
+    let mut truncated = 0;
+    for row in record.old.iter_mut().chain(record.new.iter_mut()) {
+        for column in row.columns_mut() {
+            if let RowValue::Text(text) = &mut column.value {
+                if text.len() > max {
+                    let prefix = String::from_utf8_lossy(utf8_prefix(text.as_bytes(), max));
+                    *text = truncated_text(&prefix, text.len());
+                    truncated += 1;
+                }
+            }
+        }
+    }
+    truncated
+}
+
+/// Replace the values of masked columns before the record is logged or written
+fn mask_record(config: &ReplicationConfig, record: &mut ChangeRecord) {
+    if config.column_mask.is_empty() {
+        return;
+    }
+    for row in record.old.iter_mut().chain(record.new.iter_mut()) {
+        row.mask(&config.column_mask, &record.schema, &record.table);
+    }
+}
+
+/// pg-walstream retries establishing the connection; its attempt count includes the first one
+fn retry_config(policy: &ReconnectPolicy) -> RetryConfig {
+    RetryConfig {
+        max_attempts: policy.max_retries + 1,
+        initial_delay: policy.initial_backoff,
+        max_delay: policy.max_backoff,
+        jitter: policy.jitter > 0.0,
+        ..RetryConfig::default()
+    }
+}
+
+/// pg-walstream wraps the publication name in double quotes inside a string literal without
+/// escaping it, so hand it the quoted list without its outermost quotes and with single
+/// quotes doubled
+fn walstream_publication_names(names: &[String]) -> String {
+    let option = publication_names_option(names).replace('\'', "''");
+    option
+        .strip_prefix('"')
+        .and_then(|option| option.strip_suffix('"'))
+        .unwrap_or(&option)
+        .to_string()
+}
@@ -0,0 +1,147 @@
+//! Tail/watch mode for quick "is replication flowing right now?" checks
+//! Streams for a bounded duration and summarizes per-table change counts and commit lag
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Kind of row change
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+    Truncate,
+}
+
+/// Change counts for a single table
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TableCounts {
+    pub inserts: u64,
+    pub updates: u64,
+    pub deletes: u64,
+    pub truncates: u64,
+}
+
+impl TableCounts {
+    pub fn record(&mut self, kind: ChangeKind) {
+        match kind {
+            ChangeKind::Insert => self.inserts += 1,
+            ChangeKind::Update => self.updates += 1,
+            ChangeKind::Delete => self.deletes += 1,
+            ChangeKind::Truncate => self.truncates += 1,
+        }
+    }
+}
+
+/// Aggregated observations collected while watching the stream
+#[derive(Debug)]
+pub struct WatchSummary {
+    started: Instant,
+    tables: BTreeMap<String, TableCounts>,
+    transactions: u64,
+    lag_samples: u64,
+    lag_min: Duration,
+    lag_max: Duration,
+    lag_total: Duration,
+}
+
+impl WatchSummary {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            tables: BTreeMap::new(),
+            transactions: 0,
+            lag_samples: 0,
+            lag_min: Duration::MAX,
+            lag_max: Duration::ZERO,
+            lag_total: Duration::ZERO,
+        }
+    }
+
+    /// Count a change for the given schema-qualified table name
+    pub fn record_change(&mut self, table: &str, kind: ChangeKind) {
+        self.tables
+            .entry(table.to_string())
+            .or_default()
+            .record(kind);
+    }
+
+    /// Record a commit and sample the delay between the commit time and now
+    pub fn record_commit(&mut self, commit_time: DateTime<Utc>) {
+        self.transactions += 1;
+
+        let lag = (Utc::now() - commit_time)
+            .to_std()
+            .unwrap_or(Duration::ZERO);
+        self.lag_samples += 1;
+        self.lag_min = self.lag_min.min(lag);
+        self.lag_max = self.lag_max.max(lag);
+        self.lag_total = self.lag_total.saturating_add(lag);
+    }
+
+    /// Log the summary of everything observed since the watch started
+    pub fn log_summary(&self) {
+        info!(
+            "Watch summary: {} transactions in {:.1}s",
+            self.transactions,
+            self.started.elapsed().as_secs_f64()
+        );
+
+        if self.tables.is_empty() {
+            info!("No table changes observed");
+        }
+        for (table, counts) in &self.tables {
+            info!(
+                "  {}: {} inserts, {} updates, {} deletes, {} truncates",
+                table, counts.inserts, counts.updates, counts.deletes, counts.truncates
+            );
+        }
+
+        if self.lag_samples > 0 {
+            let avg_ms = self.lag_total.as_nanos() / self.lag_samples as u128 / 1_000_000;
+            info!(
+                "Commit lag over {} samples: min {}ms, avg {}ms, max {}ms",
+                self.lag_samples,
+                self.lag_min.as_millis(),
+                avg_ms,
+                self.lag_max.as_millis()
+            );
+        }
+    }
+}
+
+impl Default for WatchSummary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parse a duration such as "30s", "5m", "1h" or "2d"; a bare number means seconds
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .with_context(|| format!("Invalid duration: {}", value))?;
+
+    let multiplier = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(anyhow::anyhow!("Invalid duration unit: {}", unit)),
+    };
+
+    let secs = amount
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow::anyhow!("Duration is too long: {}", value))?;
+    Ok(Duration::from_secs(secs))
+}
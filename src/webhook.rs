@@ -0,0 +1,109 @@
+//! Webhook sink for change events
+//! POSTs batches of change records as a JSON array to an HTTP or HTTPS endpoint, retrying with
+//! backoff. The webhook runs as an http sink of the `[[sink]]` fan-out, on a thread of its own
+//! so a slow endpoint or a retry never holds up the stream and its keepalives. A transaction is
+//! only acknowledged once all its events got a 2xx response, and the server never confirms WAL
+//! past it, so delivery is at-least-once.
+
+use crate::errors::{is_retryable_status, ReplicationError, Result};
+use crate::reconnect::ReconnectPolicy;
+use crate::sink::SinkOptions;
+use std::time::Duration;
+use ureq::tls::{RootCerts, TlsConfig};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Destination and batching of the webhook sink
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// `http://` or `https://` endpoint receiving the batches
+    pub url: String,
+    /// Events per request; a batch is sent as soon as it is full
+    pub batch_size: usize,
+    /// Longest an event waits before a partial batch is sent
+    pub flush_interval: Duration,
+    /// Retries of a failed request before the run stops
+    pub retry: ReconnectPolicy,
+}
+
+impl WebhookConfig {
+    /// Batching and retries of the sink delivering to the webhook
+    pub fn sink_options(&self) -> SinkOptions {
+        let mut options = SinkOptions::new("webhook");
+        options.batch_size = self.batch_size;
+        options.flush_interval = self.flush_interval;
+        options.retry = self.retry;
+        options
+    }
+}
+
+/// An `http://` or `https://` URL requests are POSTed to
+#[derive(Debug, Clone)]
+pub(crate) struct Endpoint {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl Endpoint {
+    pub(crate) fn parse(url: &str) -> Result<Self> {
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            return Err(ReplicationError::config(format!(
+                "Unsupported webhook URL {}: use an http:// or https:// URL",
+                url
+            )));
+        }
+        let uri: ureq::http::Uri = url
+            .parse()
+            .map_err(|e| ReplicationError::config(format!("Invalid webhook URL {}: {}", url, e)))?;
+        if uri.host().is_none_or(str::is_empty) {
+            return Err(ReplicationError::config(format!(
+                "Webhook URL {} has no host",
+                url
+            )));
+        }
+
+        // Statuses are classified below rather than turned into errors. Certificates are
+        // verified against the system CA store, which SSL_CERT_FILE and SSL_CERT_DIR override
+        let tls = TlsConfig::builder()
+            .root_certs(RootCerts::PlatformVerifier)
+            .build();
+        let agent = ureq::Agent::config_builder()
+            .timeout_global(Some(REQUEST_TIMEOUT))
+            .tls_config(tls)
+            .http_status_as_error(false)
+            .build()
+            .into();
+        Ok(Self {
+            url: url.to_string(),
+            agent,
+        })
+    }
+
+    /// POST the body and wait for a 2xx response
+    pub(crate) fn post(&self, body: &[u8]) -> Result<()> {
+        let response = self
+            .agent
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .send(body)
+            .map_err(|e| {
+                ReplicationError::webhook_retryable(format!(
+                    "Request to {} failed: {}",
+                    self.url, e
+                ))
+            })?;
+
+        match response.status().as_u16() {
+            200..=299 => Ok(()),
+            // Client errors other than timeouts and throttling fail the same way when resent
+            status if !is_retryable_status(status) => Err(ReplicationError::webhook(format!(
+                "{} responded with status {}",
+                self.url, status
+            ))),
+            status => Err(ReplicationError::webhook_retryable(format!(
+                "{} responded with status {}",
+                self.url, status
+            ))),
+        }
+    }
+}
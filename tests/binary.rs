@@ -0,0 +1,215 @@
+//! Text rendering of values sent in binary format
+//! Each case is a value's send-format bytes and the text PostgreSQL 15 writes for it, both taken
+//! from the server with `TimeZone` UTC and `DateStyle` ISO: `encode(<type>_send(v), 'hex')` and
+//! `v::text`.
+
+use replication_checker::binary::to_text;
+
+const BOOL: u32 = 16;
+const BYTEA: u32 = 17;
+const CHAR: u32 = 18;
+const NAME: u32 = 19;
+const INT8: u32 = 20;
+const INT2: u32 = 21;
+const INT4: u32 = 23;
+const TEXT: u32 = 25;
+const OID: u32 = 26;
+const JSON: u32 = 114;
+const FLOAT4: u32 = 700;
+const FLOAT8: u32 = 701;
+const BPCHAR: u32 = 1042;
+const VARCHAR: u32 = 1043;
+const DATE: u32 = 1082;
+const TIME: u32 = 1083;
+const TIMESTAMP: u32 = 1114;
+const TIMESTAMPTZ: u32 = 1184;
+const NUMERIC: u32 = 1700;
+const UUID: u32 = 2950;
+const JSONB: u32 = 3802;
+
+fn bytes(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("Test data is hex"))
+        .collect()
+}
+
+fn check(type_oid: u32, cases: &[(&str, &str)]) {
+    for (hex, expected) in cases {
+        assert_eq!(
+            to_text(type_oid, &bytes(hex)),
+            *expected,
+            "type {} bytes {}",
+            type_oid,
+            hex
+        );
+    }
+}
+
+#[test]
+fn booleans_and_integers() {
+    check(BOOL, &[("01", "t"), ("00", "f")]);
+    check(INT2, &[("8000", "-32768")]);
+    check(INT4, &[("7fffffff", "2147483647")]);
+    check(INT8, &[("8000000000000000", "-9223372036854775808")]);
+    check(OID, &[("ffffffff", "4294967295")]);
+}
+
+#[test]
+fn float4_uses_its_own_shortest_digits() {
+    check(
+        FLOAT4,
+        &[
+            ("3f8ccccd", "1.1"),
+            ("3dcccccd", "0.1"),
+            ("7f7fffff", "3.4028235e+38"),
+            ("3727c5ac", "1e-05"),
+            ("47f12000", "123456"),
+            ("4996b438", "1.234567e+06"),
+            ("80000000", "-0"),
+            ("7fc00000", "NaN"),
+            ("ff800000", "-Infinity"),
+        ],
+    );
+}
+
+#[test]
+fn float8_switches_to_scientific_like_the_server() {
+    check(
+        FLOAT8,
+        &[
+            ("3fb999999999999a", "0.1"),
+            ("430c6bf526340000", "1e+15"),
+            ("42d6bcc41e900000", "100000000000000"),
+            ("42dc12218377de66", "123456789012345.6"),
+            ("3ee4f8b588e368f1", "1e-05"),
+            ("3f1a36e2eb1c432d", "0.0001"),
+            ("81b01297d23ab683", "-1.5e-300"),
+            ("0010000000000000", "2.2250738585072014e-308"),
+            ("7ff0000000000000", "Infinity"),
+        ],
+    );
+}
+
+#[test]
+fn numerics_keep_their_scale() {
+    check(
+        NUMERIC,
+        &[
+            ("0000000000000000", "0"),
+            ("0002000000000002007b1194", "123.45"),
+            ("0002ffff40000005000107d0", "-0.00012"),
+            ("00010002000000000001", "100000000"),
+            ("00010000000000030001", "1.000"),
+            (
+                "000800040000000904d2162e23340d801ed204d2162e2328",
+                "12345678901234567890.123456789",
+            ),
+            ("00000000c0000000", "NaN"),
+            ("00000000d0000020", "Infinity"),
+            ("00000000f0000020", "-Infinity"),
+        ],
+    );
+}
+
+#[test]
+fn uuids_are_grouped() {
+    check(
+        UUID,
+        &[(
+            "a0eebc999c0b4ef8bb6d6bb9bd380a11",
+            "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11",
+        )],
+    );
+}
+
+#[test]
+fn dates_cover_the_server_range_and_eras() {
+    check(
+        DATE,
+        &[
+            ("00002279", "2024-02-29"),
+            ("fff49d7b", "0044-03-15 BC"),
+            ("fff4da8b", "0001-01-01 BC"),
+            ("ffda97cd", "4713-01-01 BC"),
+            ("002c95d4", "10000-01-01"),
+            ("7fda970c", "5874897-12-31"),
+            ("7fffffff", "infinity"),
+            ("80000000", "-infinity"),
+        ],
+    );
+}
+
+#[test]
+fn times_drop_trailing_zeros() {
+    check(
+        TIME,
+        &[
+            ("0000000000000000", "00:00:00"),
+            ("000000141dd75fff", "23:59:59.999999"),
+            ("0000000a8be1bd20", "12:34:56.5"),
+            ("000000141dd76000", "24:00:00"),
+        ],
+    );
+}
+
+#[test]
+fn timestamps_cover_the_server_range_and_eras() {
+    check(
+        TIMESTAMP,
+        &[
+            ("0002b58341728608", "2024-02-29 12:34:56.789"),
+            ("fffffffffff0bdc1", "1999-12-31 23:59:59.000001"),
+            ("ff1af9e8fb46d000", "0044-03-15 12:00:00 BC"),
+            ("fd0f7fbdaf17e000", "4713-01-01 00:00:00 BC"),
+            ("7fffff5bb3b29fff", "294276-12-31 23:59:59.999999"),
+            ("7fffffffffffffff", "infinity"),
+        ],
+    );
+    check(
+        TIMESTAMPTZ,
+        &[
+            ("0002b581944b3e08", "2024-02-29 10:34:56.789+00"),
+            ("ff1af9e8fb46d000", "0044-03-15 12:00:00+00 BC"),
+            ("8000000000000000", "-infinity"),
+        ],
+    );
+}
+
+#[test]
+fn text_types_are_utf8() {
+    check(TEXT, &[("68c3a96c6c6f", "héllo")]);
+    check(BPCHAR, &[("61622020", "ab  ")]);
+    check(VARCHAR, &[("78", "x")]);
+    check(NAME, &[("736c6f74", "slot")]);
+    check(CHAR, &[("63", "c")]);
+    check(JSON, &[("7b226122203a20317d", "{\"a\" : 1}")]);
+    check(
+        JSONB,
+        &[(
+            "017b2261223a206e756c6c2c202262223a205b312c20325d7d",
+            "{\"a\": null, \"b\": [1, 2]}",
+        )],
+    );
+}
+
+#[test]
+fn bytea_and_unknown_types_are_hex() {
+    check(BYTEA, &[("00ff", "\\x00ff")]);
+    // A point, which is not decoded
+    check(
+        600,
+        &[(
+            "3ff00000000000004000000000000000",
+            "\\x3ff00000000000004000000000000000",
+        )],
+    );
+}
+
+#[test]
+fn wrong_lengths_fall_back_to_hex() {
+    check(INT4, &[("0001", "\\x0001")]);
+    check(UUID, &[("a0ee", "\\xa0ee")]);
+    check(NUMERIC, &[("0001", "\\x0001")]);
+    check(JSONB, &[("027b7d", "\\x027b7d")]);
+}
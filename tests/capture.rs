@@ -0,0 +1,137 @@
+//! Replay of capture files written with `--record`
+//! `tests/data/orders.pgrcap` was recorded from PostgreSQL 15 streaming a publication of
+//! `orders (id int primary key, item text, qty int)` while one transaction inserted two rows,
+//! a second updated one and a third deleted the other.
+
+use replication_checker::capture::{CaptureReader, MAX_MESSAGE_LEN};
+use replication_checker::{
+    ChangeHandler, ChangeRecord, ReplicationConfig, ReplicationServer, Result,
+};
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+fn capture_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/orders.pgrcap")
+}
+
+/// Keeps the changes it is passed as JSON
+#[derive(Clone, Default)]
+struct Recorder {
+    changes: Arc<Mutex<Vec<Value>>>,
+}
+
+impl ChangeHandler for Recorder {
+    fn on_change(&mut self, change: &ChangeRecord) -> Result<()> {
+        let change = serde_json::to_value(change).expect("Change records serialize");
+        self.changes.lock().unwrap().push(change);
+        Ok(())
+    }
+}
+
+#[test]
+fn recorded_messages_read_back_in_order() {
+    let mut reader = CaptureReader::open(&capture_path()).expect("Capture file opens");
+    let mut kinds = Vec::new();
+    let mut previous = None;
+    while let Some(message) = reader.next_message().expect("Message reads") {
+        assert!(previous.is_none_or(|previous| previous <= message.received_at));
+        previous = Some(message.received_at);
+        kinds.push(message.data[0]);
+    }
+
+    assert_eq!(kinds.len(), 17);
+    assert!(kinds.iter().all(|kind| matches!(kind, b'w' | b'k')));
+}
+
+#[test]
+fn replay_emits_the_recorded_changes() {
+    let mut server =
+        ReplicationServer::offline(ReplicationConfig::offline()).expect("Offline server");
+    let recorder = Recorder::default();
+    server.add_handler(recorder.clone());
+    server.replay(&capture_path()).expect("Capture replays");
+
+    let changes = recorder.changes.lock().unwrap();
+    let summary: Vec<(&str, &str, &Value)> = changes
+        .iter()
+        .map(|change| {
+            let op = change["op"].as_str().unwrap();
+            let row = if op == "delete" { "old" } else { "new" };
+            (op, change["table"].as_str().unwrap(), &change[row]["id"])
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("insert", "orders", &Value::from("1")),
+            ("insert", "orders", &Value::from("2")),
+            ("update", "orders", &Value::from("1")),
+            ("delete", "orders", &Value::from("2")),
+        ]
+    );
+    assert_eq!(changes[2]["new"]["qty"], "5");
+}
+
+/// A copy of the capture cut after `len` bytes, or with `tail` appended
+fn altered_capture(name: &str, len: usize, tail: &[u8]) -> PathBuf {
+    let mut data = fs::read(capture_path()).expect("Capture file reads");
+    data.truncate(len);
+    data.extend_from_slice(tail);
+    let path = std::env::temp_dir().join(format!("{}-{}.pgrcap", name, std::process::id()));
+    fs::write(&path, data).expect("Altered capture writes");
+    path
+}
+
+#[test]
+fn truncated_message_is_an_error() {
+    let full = fs::metadata(capture_path()).unwrap().len() as usize;
+    let path = altered_capture("truncated", full - 3, &[]);
+    let mut reader = CaptureReader::open(&path).expect("Capture file opens");
+    let result = loop {
+        match reader.next_message() {
+            Ok(Some(_)) => continue,
+            other => break other,
+        }
+    };
+    fs::remove_file(&path).ok();
+    let error = result.expect_err("A cut message fails");
+    assert!(
+        error.to_string().contains("middle of a message"),
+        "{}",
+        error
+    );
+}
+
+/// Record header of a payload of `len` bytes
+fn record_header(len: u32) -> Vec<u8> {
+    let mut header = 0u64.to_be_bytes().to_vec();
+    header.extend_from_slice(&len.to_be_bytes());
+    header
+}
+
+#[test]
+fn oversized_length_is_rejected_before_reading() {
+    let path = altered_capture("oversized", 8, &record_header(MAX_MESSAGE_LEN + 1));
+    let mut reader = CaptureReader::open(&path).expect("Capture file opens");
+    let result = reader.next_message();
+    fs::remove_file(&path).ok();
+    let error = result.expect_err("An oversized length fails");
+    assert!(error.to_string().contains("exceeds"), "{}", error);
+}
+
+#[test]
+fn length_past_the_end_is_truncated() {
+    // Read as far as the file goes instead of allocating the full length up front
+    let path = altered_capture("unbacked", 8, &record_header(MAX_MESSAGE_LEN));
+    let mut reader = CaptureReader::open(&path).expect("Capture file opens");
+    let result = reader.next_message();
+    fs::remove_file(&path).ok();
+    let error = result.expect_err("A length past the end fails");
+    assert!(
+        error.to_string().contains("middle of a message"),
+        "{}",
+        error
+    );
+}
@@ -0,0 +1,82 @@
+//! Checkpoint file round trips
+//! `CheckpointStore` records the flushed and applied LSN of a slot so a restart resumes where
+//! the last run stopped; a missing file means a fresh start and a corrupt one must not.
+
+use replication_checker::checkpoint::CheckpointStore;
+use replication_checker::{Lsn, ReplicationError};
+use std::fs;
+use std::path::PathBuf;
+
+/// An empty directory of its own for each test
+fn test_directory(name: &str) -> PathBuf {
+    let directory =
+        std::env::temp_dir().join(format!("checkpoint-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&directory).ok();
+    fs::create_dir_all(&directory).expect("Test directory is created");
+    directory
+}
+
+#[test]
+fn save_then_load_returns_the_positions() {
+    let directory = test_directory("roundtrip");
+    let path = directory.join("slot.json");
+
+    let mut store = CheckpointStore::new(path.clone());
+    store
+        .save("orders_slot", Lsn(0x16B3748), Lsn(0x16B3800))
+        .unwrap();
+    store
+        .save("orders_slot", Lsn(0x1_0000_0000), Lsn(0x1_0000_00FF))
+        .unwrap();
+
+    // A fresh store reads what the previous run left behind
+    let checkpoint = CheckpointStore::new(path.clone())
+        .load()
+        .unwrap()
+        .expect("Checkpoint exists after a save");
+    assert_eq!(checkpoint.slot_name, "orders_slot");
+    assert_eq!(checkpoint.flushed_lsn, Lsn(0x1_0000_0000));
+    assert_eq!(checkpoint.applied_lsn, Lsn(0x1_0000_00FF));
+
+    // Only the checkpoint remains, the temporary file was renamed over it
+    let entries: Vec<_> = fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(entries, ["slot.json"]);
+    fs::remove_dir_all(&directory).ok();
+}
+
+#[test]
+fn missing_file_loads_as_none() {
+    let directory = test_directory("missing");
+    let store = CheckpointStore::new(directory.join("slot.json"));
+    assert!(store.load().unwrap().is_none());
+    fs::remove_dir_all(&directory).ok();
+}
+
+#[test]
+fn corrupt_file_is_a_checkpoint_error() {
+    let directory = test_directory("corrupt");
+    let path = directory.join("slot.json");
+    let cases = [
+        "",
+        "{",
+        "not json",
+        r#"{"slot_name": "orders_slot"}"#,
+        r#"{"slot_name": "s", "flushed_lsn": "0/ZZ", "applied_lsn": "0/0", "updated_at": "2024-12-26T18:00:00Z"}"#,
+    ];
+    for contents in cases {
+        fs::write(&path, contents).unwrap();
+        let error = CheckpointStore::new(path.clone())
+            .load()
+            .expect_err("Corrupt checkpoint fails to load");
+        assert!(
+            matches!(error, ReplicationError::Checkpoint { .. }),
+            "{:?}: {}",
+            contents,
+            error
+        );
+    }
+    fs::remove_dir_all(&directory).ok();
+}
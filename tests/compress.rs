@@ -0,0 +1,154 @@
+//! gzip and zstd compression of output files
+//! `tests/data/events.jsonl` is compressed by the `gzip` (1.12) and `zstd` (1.5.7) tools into
+//! the `.gz` and `.zst` files next to it: at their default levels, and as two members or frames
+//! written at different levels and concatenated, the second zstd frame without a checksum.
+
+use replication_checker::compress::{CompressedReader, CompressedWriter, Compression};
+use std::fs;
+use std::io::{BufReader, Read, Write};
+use std::path::PathBuf;
+
+fn data_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data")
+        .join(name)
+}
+
+fn events() -> Vec<u8> {
+    fs::read(data_path("events.jsonl")).expect("Events file reads")
+}
+
+fn decompress(data: &[u8]) -> (Compression, Vec<u8>) {
+    let mut reader = CompressedReader::new(BufReader::new(data)).expect("Reader opens");
+    let compression = reader.compression();
+    let mut output = Vec::new();
+    reader.read_to_end(&mut output).expect("Data decompresses");
+    (compression, output)
+}
+
+fn compress(compression: Compression, data: &[u8], chunk: usize) -> Vec<u8> {
+    let mut writer = CompressedWriter::new(Vec::new(), compression);
+    for piece in data.chunks(chunk.max(1)) {
+        writer.write_all(piece).expect("Write succeeds");
+        writer.flush().expect("Flush succeeds");
+    }
+    writer.finish().expect("Writer finishes")
+}
+
+#[test]
+fn tool_output_decompresses() {
+    let expected = events();
+    for (name, compression) in [
+        ("events.jsonl.gz", Compression::Gzip),
+        ("events.multi.jsonl.gz", Compression::Gzip),
+        ("events.jsonl.zst", Compression::Zstd),
+        ("events.multi.jsonl.zst", Compression::Zstd),
+    ] {
+        let data = fs::read(data_path(name)).expect("Compressed file reads");
+        let (detected, output) = decompress(&data);
+        assert_eq!(detected, compression, "{}", name);
+        assert!(output == expected, "{} decompresses to the events", name);
+    }
+}
+
+/// Input that is empty, incompressible, or longer than any block or window
+fn samples() -> Vec<Vec<u8>> {
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    let noise: Vec<u8> = (0..300_000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let repeated: Vec<u8> = events().iter().cycle().take(1_000_000).copied().collect();
+    vec![
+        Vec::new(),
+        b"x".to_vec(),
+        vec![0; 500_000],
+        noise,
+        events(),
+        repeated,
+    ]
+}
+
+#[test]
+fn round_trip() {
+    for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+        for sample in samples() {
+            for chunk in [1_000_000, 4096, 7] {
+                if chunk == 7 && sample.len() > 100_000 {
+                    continue;
+                }
+                let compressed = compress(compression, &sample, chunk);
+                let (detected, output) = decompress(&compressed);
+                if !sample.is_empty() {
+                    assert_eq!(detected, compression);
+                }
+                assert!(
+                    output == sample,
+                    "{:?} round trip of {} bytes in chunks of {}",
+                    compression,
+                    sample.len(),
+                    chunk
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn dropped_writer_finishes_the_stream() {
+    for compression in [Compression::Gzip, Compression::Zstd] {
+        let mut output = Vec::new();
+        {
+            let mut writer = CompressedWriter::new(&mut output, compression);
+            writer.write_all(&events()).expect("Write succeeds");
+        }
+        assert!(decompress(&output).1 == events(), "{:?}", compression);
+    }
+}
+
+#[test]
+fn appended_streams_read_back_in_order() {
+    for compression in [Compression::Gzip, Compression::Zstd] {
+        let mut data = compress(compression, b"first line\n", 1024);
+        data.extend(compress(compression, b"second line\n", 1024));
+        assert_eq!(decompress(&data).1, b"first line\nsecond line\n");
+    }
+}
+
+#[test]
+fn compresses_like_the_tools() {
+    let events = events();
+    for (compression, tool_output) in [
+        (Compression::Gzip, "events.jsonl.gz"),
+        (Compression::Zstd, "events.jsonl.zst"),
+    ] {
+        let size = compress(compression, &events, 4096).len();
+        let tool_size = fs::metadata(data_path(tool_output)).unwrap().len() as usize;
+        assert!(
+            size * 4 <= tool_size * 5,
+            "{:?} wrote {} bytes, the tool {}",
+            compression,
+            size,
+            tool_size
+        );
+    }
+}
+
+#[test]
+fn truncated_input_is_an_error() {
+    for compression in [Compression::Gzip, Compression::Zstd] {
+        let compressed = compress(compression, &events(), 4096);
+        let cut = &compressed[..compressed.len() - 10];
+        let mut reader = CompressedReader::new(BufReader::new(cut)).expect("Reader opens");
+        let mut output = Vec::new();
+        assert!(
+            reader.read_to_end(&mut output).is_err(),
+            "{:?} stream cut short",
+            compression
+        );
+    }
+}
@@ -0,0 +1,523 @@
+//! Date and time values as the server formats them
+//! Every string was written by PostgreSQL 15 for the same values under each DateStyle, TimeZone
+//! and IntervalStyle, with `SET datestyle`, `SET timezone` and `SET intervalstyle` then
+//! `v::text`. Whatever the style, a value must normalize to the same ISO form.
+
+use replication_checker::datetime::{
+    normalize, parse_timestamptz, DateOrder, DateStyle, DisplayTimeZone,
+};
+
+const DATE: u32 = 1082;
+const TIME: u32 = 1083;
+const TIMESTAMP: u32 = 1114;
+const TIMESTAMPTZ: u32 = 1184;
+const INTERVAL: u32 = 1186;
+
+fn order(style: &str) -> DateOrder {
+    style.parse::<DateStyle>().expect("DateStyle parses").order
+}
+
+fn normalized(type_oid: u32, text: &str, style: &str) -> Option<String> {
+    normalize(type_oid, text, order(style), DisplayTimeZone::Utc)
+}
+
+#[test]
+fn date_styles_read_back_as_shown() {
+    for style in [
+        "ISO, MDY",
+        "ISO, DMY",
+        "ISO, YMD",
+        "SQL, MDY",
+        "SQL, DMY",
+        "Postgres, MDY",
+        "Postgres, DMY",
+        "German, DMY",
+    ] {
+        let parsed: DateStyle = style.parse().unwrap();
+        assert_eq!(parsed.to_string(), style);
+    }
+    assert!("ISO, Unknown".parse::<DateStyle>().is_err());
+}
+
+/// `2024-01-02`, `0044-03-15 BC`, `1997-12-17 07:37:16.5` and `0044-03-15 12:00:00 BC`
+const DATES: [(&str, [&str; 4]); 10] = [
+    (
+        "ISO, MDY",
+        [
+            "2024-01-02",
+            "0044-03-15 BC",
+            "1997-12-17 07:37:16.5",
+            "0044-03-15 12:00:00 BC",
+        ],
+    ),
+    (
+        "ISO, DMY",
+        [
+            "2024-01-02",
+            "0044-03-15 BC",
+            "1997-12-17 07:37:16.5",
+            "0044-03-15 12:00:00 BC",
+        ],
+    ),
+    (
+        "SQL, MDY",
+        [
+            "01/02/2024",
+            "03/15/0044 BC",
+            "12/17/1997 07:37:16.5",
+            "03/15/0044 12:00:00 BC",
+        ],
+    ),
+    (
+        "SQL, DMY",
+        [
+            "02/01/2024",
+            "15/03/0044 BC",
+            "17/12/1997 07:37:16.5",
+            "15/03/0044 12:00:00 BC",
+        ],
+    ),
+    // Under YMD, SQL and Postgres dates are still written month first
+    (
+        "SQL, YMD",
+        [
+            "01/02/2024",
+            "03/15/0044 BC",
+            "12/17/1997 07:37:16.5",
+            "03/15/0044 12:00:00 BC",
+        ],
+    ),
+    (
+        "Postgres, MDY",
+        [
+            "01-02-2024",
+            "03-15-0044 BC",
+            "Wed Dec 17 07:37:16.5 1997",
+            "Fri Mar 15 12:00:00 0044 BC",
+        ],
+    ),
+    (
+        "Postgres, DMY",
+        [
+            "02-01-2024",
+            "15-03-0044 BC",
+            "Wed 17 Dec 07:37:16.5 1997",
+            "Fri 15 Mar 12:00:00 0044 BC",
+        ],
+    ),
+    (
+        "Postgres, YMD",
+        [
+            "01-02-2024",
+            "03-15-0044 BC",
+            "Wed Dec 17 07:37:16.5 1997",
+            "Fri Mar 15 12:00:00 0044 BC",
+        ],
+    ),
+    (
+        "German, DMY",
+        [
+            "02.01.2024",
+            "15.03.0044 BC",
+            "17.12.1997 07:37:16.5",
+            "15.03.0044 12:00:00 BC",
+        ],
+    ),
+    // German dates are day first whatever the order
+    (
+        "German, MDY",
+        [
+            "02.01.2024",
+            "15.03.0044 BC",
+            "17.12.1997 07:37:16.5",
+            "15.03.0044 12:00:00 BC",
+        ],
+    ),
+];
+
+#[test]
+fn dates_and_timestamps_of_every_style() {
+    let expected = [
+        (DATE, "2024-01-02"),
+        (DATE, "0044-03-15 BC"),
+        (TIMESTAMP, "1997-12-17 07:37:16.500"),
+        (TIMESTAMP, "0044-03-15 12:00:00 BC"),
+    ];
+    for (style, values) in DATES {
+        for (text, (type_oid, iso)) in values.iter().zip(expected) {
+            assert_eq!(
+                normalized(type_oid, text, style).as_deref(),
+                Some(iso),
+                "{} in {}",
+                text,
+                style
+            );
+        }
+    }
+}
+
+#[test]
+fn times_are_the_same_in_every_style() {
+    assert_eq!(
+        normalized(TIME, "10:20:30.25", "German, DMY").as_deref(),
+        Some("10:20:30.250")
+    );
+    assert_eq!(
+        normalized(TIME, "23:59:59.999999", "SQL, MDY").as_deref(),
+        Some("23:59:59.999999")
+    );
+}
+
+/// `1997-12-17 15:37:16.5+00`, `1997-07-17 15:37:16+00` and `0044-03-15 12:00:00+00 BC` under
+/// each DateStyle and TimeZone
+const TIMESTAMPTZS: [(&str, &str, [&str; 3]); 25] = [
+    (
+        "ISO, MDY",
+        "UTC",
+        [
+            "1997-12-17 15:37:16.5+00",
+            "1997-07-17 15:37:16+00",
+            "0044-03-15 12:00:00+00 BC",
+        ],
+    ),
+    (
+        "ISO, MDY",
+        "America/Los_Angeles",
+        [
+            "1997-12-17 07:37:16.5-08",
+            "1997-07-17 08:37:16-07",
+            "0044-03-15 04:07:02-07:52:58 BC",
+        ],
+    ),
+    (
+        "ISO, MDY",
+        "Australia/Adelaide",
+        [
+            "1997-12-18 02:07:16.5+10:30",
+            "1997-07-18 01:07:16+09:30",
+            "0044-03-15 21:14:20+09:14:20 BC",
+        ],
+    ),
+    (
+        "ISO, MDY",
+        "Asia/Kolkata",
+        [
+            "1997-12-17 21:07:16.5+05:30",
+            "1997-07-17 21:07:16+05:30",
+            "0044-03-15 17:53:28+05:53:28 BC",
+        ],
+    ),
+    (
+        "ISO, DMY",
+        "<+0330>-03:30",
+        [
+            "1997-12-17 19:07:16.5+03:30",
+            "1997-07-17 19:07:16+03:30",
+            "0044-03-15 15:30:00+03:30 BC",
+        ],
+    ),
+    (
+        "SQL, MDY",
+        "UTC",
+        [
+            "12/17/1997 15:37:16.5 UTC",
+            "07/17/1997 15:37:16 UTC",
+            "03/15/0044 12:00:00 UTC BC",
+        ],
+    ),
+    (
+        "SQL, MDY",
+        "America/Los_Angeles",
+        [
+            "12/17/1997 07:37:16.5 PST",
+            "07/17/1997 08:37:16 PDT",
+            "03/15/0044 04:07:02 LMT BC",
+        ],
+    ),
+    (
+        "SQL, MDY",
+        "Australia/Adelaide",
+        [
+            "12/18/1997 02:07:16.5 ACDT",
+            "07/18/1997 01:07:16 ACST",
+            "03/15/0044 21:14:20 LMT BC",
+        ],
+    ),
+    (
+        "SQL, MDY",
+        "Asia/Kolkata",
+        [
+            "12/17/1997 21:07:16.5 IST",
+            "07/17/1997 21:07:16 IST",
+            "03/15/0044 17:53:28 LMT BC",
+        ],
+    ),
+    (
+        "SQL, MDY",
+        "<+0330>-03:30",
+        [
+            "12/17/1997 19:07:16.5 +0330",
+            "07/17/1997 19:07:16 +0330",
+            "03/15/0044 15:30:00 +0330 BC",
+        ],
+    ),
+    (
+        "SQL, DMY",
+        "UTC",
+        [
+            "17/12/1997 15:37:16.5 UTC",
+            "17/07/1997 15:37:16 UTC",
+            "15/03/0044 12:00:00 UTC BC",
+        ],
+    ),
+    (
+        "SQL, DMY",
+        "America/Los_Angeles",
+        [
+            "17/12/1997 07:37:16.5 PST",
+            "17/07/1997 08:37:16 PDT",
+            "15/03/0044 04:07:02 LMT BC",
+        ],
+    ),
+    (
+        "SQL, DMY",
+        "<+0330>-03:30",
+        [
+            "17/12/1997 19:07:16.5 +0330",
+            "17/07/1997 19:07:16 +0330",
+            "15/03/0044 15:30:00 +0330 BC",
+        ],
+    ),
+    (
+        "Postgres, MDY",
+        "UTC",
+        [
+            "Wed Dec 17 15:37:16.5 1997 UTC",
+            "Thu Jul 17 15:37:16 1997 UTC",
+            "Fri Mar 15 12:00:00 0044 UTC BC",
+        ],
+    ),
+    (
+        "Postgres, MDY",
+        "America/Los_Angeles",
+        [
+            "Wed Dec 17 07:37:16.5 1997 PST",
+            "Thu Jul 17 08:37:16 1997 PDT",
+            "Fri Mar 15 04:07:02 0044 LMT BC",
+        ],
+    ),
+    (
+        "Postgres, MDY",
+        "Australia/Adelaide",
+        [
+            "Thu Dec 18 02:07:16.5 1997 ACDT",
+            "Fri Jul 18 01:07:16 1997 ACST",
+            "Fri Mar 15 21:14:20 0044 LMT BC",
+        ],
+    ),
+    (
+        "Postgres, MDY",
+        "<+0330>-03:30",
+        [
+            "Wed Dec 17 19:07:16.5 1997 +0330",
+            "Thu Jul 17 19:07:16 1997 +0330",
+            "Fri Mar 15 15:30:00 0044 +0330 BC",
+        ],
+    ),
+    (
+        "Postgres, DMY",
+        "UTC",
+        [
+            "Wed 17 Dec 15:37:16.5 1997 UTC",
+            "Thu 17 Jul 15:37:16 1997 UTC",
+            "Fri 15 Mar 12:00:00 0044 UTC BC",
+        ],
+    ),
+    (
+        "Postgres, DMY",
+        "America/Los_Angeles",
+        [
+            "Wed 17 Dec 07:37:16.5 1997 PST",
+            "Thu 17 Jul 08:37:16 1997 PDT",
+            "Fri 15 Mar 04:07:02 0044 LMT BC",
+        ],
+    ),
+    (
+        "Postgres, DMY",
+        "<+0330>-03:30",
+        [
+            "Wed 17 Dec 19:07:16.5 1997 +0330",
+            "Thu 17 Jul 19:07:16 1997 +0330",
+            "Fri 15 Mar 15:30:00 0044 +0330 BC",
+        ],
+    ),
+    (
+        "German, DMY",
+        "UTC",
+        [
+            "17.12.1997 15:37:16.5 UTC",
+            "17.07.1997 15:37:16 UTC",
+            "15.03.0044 12:00:00 UTC BC",
+        ],
+    ),
+    (
+        "German, DMY",
+        "America/Los_Angeles",
+        [
+            "17.12.1997 07:37:16.5 PST",
+            "17.07.1997 08:37:16 PDT",
+            "15.03.0044 04:07:02 LMT BC",
+        ],
+    ),
+    (
+        "German, DMY",
+        "Australia/Adelaide",
+        [
+            "18.12.1997 02:07:16.5 ACDT",
+            "18.07.1997 01:07:16 ACST",
+            "15.03.0044 21:14:20 LMT BC",
+        ],
+    ),
+    (
+        "German, DMY",
+        "Asia/Kolkata",
+        [
+            "17.12.1997 21:07:16.5 IST",
+            "17.07.1997 21:07:16 IST",
+            "15.03.0044 17:53:28 LMT BC",
+        ],
+    ),
+    (
+        "German, DMY",
+        "<+0330>-03:30",
+        [
+            "17.12.1997 19:07:16.5 +0330",
+            "17.07.1997 19:07:16 +0330",
+            "15.03.0044 15:30:00 +0330 BC",
+        ],
+    ),
+];
+
+#[test]
+fn timestamptz_of_every_style_and_zone() {
+    let expected = [
+        "1997-12-17 15:37:16.500+00:00",
+        "1997-07-17 15:37:16+00:00",
+        "0044-03-15 12:00:00+00:00 BC",
+    ];
+    for (style, zone, values) in TIMESTAMPTZS {
+        for (text, iso) in values.iter().zip(expected) {
+            // Local mean time and IST, which is Indian, Irish or Israeli, have no one offset
+            let known = !text.contains("LMT") && !text.contains("IST");
+            assert_eq!(
+                normalized(TIMESTAMPTZ, text, style).as_deref(),
+                known.then_some(iso),
+                "{} in {} and {}",
+                text,
+                style,
+                zone
+            );
+        }
+    }
+}
+
+#[test]
+fn offsets_keep_their_minutes_and_seconds() {
+    let cases = [
+        ("1997-12-17 07:37:16.5-08", -8 * 3600),
+        ("1997-12-18 02:07:16.5+10:30", 10 * 3600 + 30 * 60),
+        (
+            "0044-03-15 04:07:02-07:52:58 BC",
+            -(7 * 3600 + 52 * 60 + 58),
+        ),
+        ("12/17/1997 19:07:16.5 +0330", 3 * 3600 + 30 * 60),
+        ("Thu Dec 18 02:07:16.5 1997 ACDT", 10 * 3600 + 30 * 60),
+        ("17.07.1997 08:37:16 PDT", -7 * 3600),
+    ];
+    for (text, seconds) in cases {
+        let time = parse_timestamptz(text, DateOrder::Mdy).expect(text);
+        assert_eq!(time.offset().local_minus_utc(), seconds, "{}", text);
+    }
+
+    let zone = "+05:30".parse().unwrap();
+    assert_eq!(
+        normalize(
+            TIMESTAMPTZ,
+            "1997-12-17 07:37:16.5-08",
+            DateOrder::Mdy,
+            zone
+        )
+        .as_deref(),
+        Some("1997-12-17 21:07:16.500+05:30")
+    );
+}
+
+/// The same intervals in the `postgres`, `postgres_verbose`, `sql_standard` and `iso_8601`
+/// IntervalStyles
+const INTERVALS: [[&str; 4]; 9] = [
+    [
+        "1 year 2 mons 3 days 04:05:06.5",
+        "@ 1 year 2 mons 3 days 4 hours 5 mins 6.5 secs",
+        "+1-2 +3 +4:05:06.5",
+        "P1Y2M3DT4H5M6.5S",
+    ],
+    [
+        "-1 years -2 mons +3 days -04:05:06",
+        "@ 1 year 2 mons -3 days 4 hours 5 mins 6 secs ago",
+        "-1-2 +3 -4:05:06",
+        "P-1Y-2M3DT-4H-5M-6S",
+    ],
+    [
+        "-1 years -2 mons -3 days -04:05:06.25",
+        "@ 1 year 2 mons 3 days 4 hours 5 mins 6.25 secs ago",
+        "-1-2 -3 -4:05:06.25",
+        "P-1Y-2M-3DT-4H-5M-6.25S",
+    ],
+    ["-1 days", "@ 1 day ago", "-1 0:00:00", "P-1D"],
+    ["00:00:00", "@ 0", "0", "PT0S"],
+    [
+        "-00:00:00.000001",
+        "@ 0.000001 secs ago",
+        "-0:00:00.000001",
+        "PT-0.000001S",
+    ],
+    ["25:00:03", "@ 25 hours 3 secs", "25:00:03", "PT25H3S"],
+    ["1 year 2 mons", "@ 1 year 2 mons", "1-2", "P1Y2M"],
+    [
+        "1 day -00:00:01",
+        "@ 1 day -1 sec",
+        "+0-0 +1 -0:00:01",
+        "P1DT-1S",
+    ],
+];
+
+#[test]
+fn intervals_of_every_style() {
+    for styles in INTERVALS {
+        let iso = styles[3];
+        for text in styles {
+            assert_eq!(
+                normalized(INTERVAL, text, "ISO, MDY").as_deref(),
+                Some(iso),
+                "{}",
+                text
+            );
+        }
+    }
+}
+
+#[test]
+fn infinities_and_other_text_are_left_as_sent() {
+    for (type_oid, text) in [
+        (DATE, "infinity"),
+        (DATE, "-infinity"),
+        (TIMESTAMP, "infinity"),
+        (TIMESTAMPTZ, "-infinity"),
+        (TIME, "24:00:00"),
+        (DATE, "2024-02-30"),
+        (INTERVAL, "P1X"),
+        (25, "2024-01-02"),
+    ] {
+        assert_eq!(normalized(type_oid, text, "ISO, MDY"), None, "{}", text);
+    }
+}
@@ -0,0 +1,78 @@
+//! Durations given to `--for` / `WATCH_DURATION`
+//! `parse_duration` reads "30s", "5m", "1h" or "2d", and `StopConditions::stop_after` turns the
+//! result into a stop time, so values too large for either must be errors rather than panics.
+
+use replication_checker::stop::StopConditions;
+use replication_checker::watch::parse_duration;
+use std::time::Duration;
+
+#[test]
+fn parse_reads_each_unit() {
+    let cases = [
+        ("0", 0),
+        ("45", 45),
+        ("30s", 30),
+        ("5m", 5 * 60),
+        ("1h", 60 * 60),
+        ("2d", 2 * 24 * 60 * 60),
+        (" 10m\n", 10 * 60),
+    ];
+    for (text, secs) in cases {
+        assert_eq!(
+            parse_duration(text).unwrap(),
+            Duration::from_secs(secs),
+            "{:?}",
+            text
+        );
+    }
+}
+
+#[test]
+fn parse_rejects_unknown_units_and_missing_amounts() {
+    for text in ["", "s", "10ms", "5w", "1.5h", "-5m", "10 s", "1h30m", "m5"] {
+        assert!(parse_duration(text).is_err(), "{:?}", text);
+    }
+}
+
+#[test]
+fn parse_rejects_overflowing_values() {
+    // The amount itself does not fit in a u64
+    assert!(parse_duration("18446744073709551616").is_err());
+    // The amount fits, but not once multiplied into seconds
+    for text in [
+        "18446744073709551615m",
+        "307445734561825861h",
+        "99999999999999999d",
+    ] {
+        assert!(parse_duration(text).is_err(), "{:?}", text);
+    }
+    assert_eq!(
+        parse_duration("18446744073709551615").unwrap(),
+        Duration::from_secs(u64::MAX)
+    );
+}
+
+#[test]
+fn stop_after_rejects_durations_past_the_representable_time() {
+    for text in ["99999999999d", "10000000000000", "18446744073709551615"] {
+        let duration = parse_duration(text).unwrap();
+        let mut conditions = StopConditions::default();
+        assert!(conditions.stop_after(duration).is_err(), "{:?}", text);
+        assert!(conditions.until_time.is_none(), "{:?}", text);
+    }
+}
+
+#[test]
+fn stop_after_keeps_the_earlier_stop_time() {
+    let mut conditions = StopConditions::default();
+    conditions.stop_after(Duration::from_secs(60 * 60)).unwrap();
+    let first = conditions.until_time.unwrap();
+
+    conditions
+        .stop_after(Duration::from_secs(24 * 60 * 60))
+        .unwrap();
+    assert_eq!(conditions.until_time, Some(first));
+
+    conditions.stop_after(Duration::from_secs(60)).unwrap();
+    assert!(conditions.until_time.unwrap() < first);
+}
@@ -0,0 +1,316 @@
+//! End-to-end tests against PostgreSQL in a Docker container
+//! Each test starts its own container with `wal_level=logical`, creates a table and publication,
+//! streams with a `ReplicationServer` while running DML through `psql` in the container, and
+//! checks the transactions passed to a recording handler. They need a Docker daemon and are
+//! ignored by default: run them with `cargo test --test e2e -- --ignored`. `PG_TEST_IMAGE`
+//! selects the image (default: postgres:16).
+
+use replication_checker::{
+    ChangeHandler, ChangeRecord, Lsn, ReplicationBackend, ReplicationConfig, ReplicationServer,
+    Result,
+};
+use serde_json::Value;
+use std::process::Command;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest a container may take to accept connections, or a test to see its changes
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Run a docker command, returning its stdout
+fn docker(args: &[&str]) -> String {
+    let output = Command::new("docker")
+        .args(args)
+        .output()
+        .expect("Failed to run docker");
+    assert!(
+        output.status.success(),
+        "docker {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+/// A PostgreSQL container, removed when dropped
+struct Postgres {
+    id: String,
+    port: u16,
+}
+
+impl Postgres {
+    fn start() -> Self {
+        let image = std::env::var("PG_TEST_IMAGE").unwrap_or_else(|_| "postgres:16".to_string());
+        let id = docker(&[
+            "run",
+            "-d",
+            "--rm",
+            "-e",
+            "POSTGRES_PASSWORD=postgres",
+            "-p",
+            "127.0.0.1::5432",
+            &image,
+            "-c",
+            "wal_level=logical",
+            // Small enough that the large transaction test is streamed before it commits
+            "-c",
+            "logical_decoding_work_mem=64kB",
+        ]);
+        let mapping = docker(&["port", &id, "5432/tcp"]);
+        let port = mapping
+            .lines()
+            .next()
+            .and_then(|line| line.rsplit(':').next())
+            .and_then(|port| port.parse().ok())
+            .unwrap_or_else(|| panic!("Unexpected port mapping: {}", mapping));
+        let postgres = Self { id, port };
+
+        // The entrypoint's setup server only listens on the Unix socket, so wait for TCP
+        let deadline = Instant::now() + TIMEOUT;
+        while !Command::new("docker")
+            .args([
+                "exec",
+                &postgres.id,
+                "pg_isready",
+                "-h",
+                "127.0.0.1",
+                "-U",
+                "postgres",
+            ])
+            .output()
+            .is_ok_and(|output| output.status.success())
+        {
+            assert!(Instant::now() < deadline, "PostgreSQL did not start");
+            std::thread::sleep(Duration::from_millis(250));
+        }
+        postgres
+    }
+
+    fn connection_string(&self) -> String {
+        format!(
+            "host=127.0.0.1 port={} user=postgres password=postgres dbname=postgres replication=database",
+            self.port
+        )
+    }
+
+    /// Run each statement as its own transaction
+    fn execute(&self, statements: &[&str]) {
+        let mut args = vec![
+            "exec",
+            &self.id,
+            "psql",
+            "-U",
+            "postgres",
+            "-v",
+            "ON_ERROR_STOP=1",
+        ];
+        for statement in statements {
+            args.extend(["-c", statement]);
+        }
+        docker(&args);
+    }
+}
+
+impl Drop for Postgres {
+    fn drop(&mut self) {
+        let _ = Command::new("docker").args(["rm", "-f", &self.id]).output();
+    }
+}
+
+/// What the handler was called with
+#[derive(Debug, Clone)]
+enum Recorded {
+    Begin(u32),
+    Change(Value),
+    Commit,
+}
+
+/// Records every call, shared with the test
+#[derive(Clone, Default)]
+struct Recorder {
+    calls: Arc<Mutex<Vec<Recorded>>>,
+}
+
+impl Recorder {
+    fn calls(&self) -> Vec<Recorded> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn changes(&self) -> Vec<Value> {
+        self.calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Recorded::Change(change) => Some(change),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn commits(&self) -> usize {
+        self.calls()
+            .iter()
+            .filter(|call| matches!(call, Recorded::Commit))
+            .count()
+    }
+}
+
+impl ChangeHandler for Recorder {
+    fn on_begin(
+        &mut self,
+        xid: u32,
+        _final_lsn: Lsn,
+        _commit_time: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        self.calls.lock().unwrap().push(Recorded::Begin(xid));
+        Ok(())
+    }
+
+    fn on_change(&mut self, change: &ChangeRecord) -> Result<()> {
+        let change = serde_json::to_value(change).expect("Change records serialize");
+        self.calls.lock().unwrap().push(Recorded::Change(change));
+        Ok(())
+    }
+
+    fn on_commit(
+        &mut self,
+        _commit_lsn: Lsn,
+        _end_lsn: Lsn,
+        _commit_time: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        self.calls.lock().unwrap().push(Recorded::Commit);
+        Ok(())
+    }
+}
+
+/// Create the test table and publication, then stream while running `statements`, until
+/// `commits` transactions have been received
+async fn stream_statements(statements: &[&str], commits: usize) -> Recorder {
+    let postgres = Postgres::start();
+    postgres.execute(&[
+        "CREATE TABLE e2e_items (id integer PRIMARY KEY, name text)",
+        "CREATE PUBLICATION e2e_pub FOR TABLE e2e_items",
+    ]);
+
+    let config = ReplicationConfig::builder(postgres.connection_string())
+        .with_publication("e2e_pub")
+        .with_slot_name("e2e_slot")
+        .build()
+        .expect("Valid configuration");
+    let mut server = ReplicationServer::new(config).expect("Server connects");
+    let recorder = Recorder::default();
+    server.add_handler(recorder.clone());
+    let health = server.health_state();
+    let shutdown = server.shutdown_handle();
+
+    let driver = async {
+        let deadline = Instant::now() + TIMEOUT;
+        while !health.report(TIMEOUT).ready {
+            if Instant::now() > deadline {
+                shutdown.store(true, Ordering::Relaxed);
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        postgres.execute(statements);
+        while recorder.commits() < commits && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        shutdown.store(true, Ordering::Relaxed);
+    };
+    // The server is not Send, so it is driven on the test's task
+    let (streamed, ()) = tokio::join!(server.run(), driver);
+    streamed.expect("Streaming succeeds");
+    recorder
+}
+
+fn field<'a>(change: &'a Value, tuple: &str, column: &str) -> &'a Value {
+    &change[tuple][column]
+}
+
+#[tokio::test]
+#[ignore = "needs a Docker daemon"]
+async fn row_changes_arrive_in_their_transactions() {
+    let recorder = stream_statements(
+        &[
+            "INSERT INTO e2e_items VALUES (1, 'alpha')",
+            "UPDATE e2e_items SET name = 'beta' WHERE id = 1",
+            "DELETE FROM e2e_items WHERE id = 1",
+            "TRUNCATE e2e_items",
+        ],
+        4,
+    )
+    .await;
+
+    let calls = recorder.calls();
+    let shape: Vec<&str> = calls
+        .iter()
+        .map(|call| match call {
+            Recorded::Begin(_) => "begin",
+            Recorded::Change(change) => change["op"].as_str().unwrap_or("?"),
+            Recorded::Commit => "commit",
+        })
+        .collect();
+    assert_eq!(
+        shape,
+        [
+            "begin", "insert", "commit", "begin", "update", "commit", "begin", "delete", "commit",
+            "begin", "truncate", "commit",
+        ]
+    );
+
+    let changes = recorder.changes();
+    for change in &changes {
+        assert_eq!(change["schema"], "public");
+        assert_eq!(change["table"], "e2e_items");
+    }
+    assert_eq!(field(&changes[0], "new", "name"), "alpha");
+    assert_eq!(field(&changes[1], "new", "name"), "beta");
+    // Without REPLICA IDENTITY FULL the delete only carries the key
+    assert!(changes[2]["old"].get("id").is_some());
+    assert!(changes[3].get("old").is_none() && changes[3].get("new").is_none());
+
+    // Each transaction has its own xid, and the changes carry it
+    let xids: Vec<u32> = calls
+        .iter()
+        .filter_map(|call| match call {
+            Recorded::Begin(xid) => Some(*xid),
+            _ => None,
+        })
+        .collect();
+    assert!(xids.windows(2).all(|pair| pair[0] < pair[1]));
+    for (change, xid) in changes.iter().zip(&xids) {
+        assert_eq!(change["xid"], *xid);
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs a Docker daemon"]
+async fn large_transactions_are_streamed_before_commit() {
+    let rows = 5000;
+    let insert = format!(
+        "INSERT INTO e2e_items SELECT g, repeat('x', 100) FROM generate_series(1, {}) g",
+        rows
+    );
+    let recorder = stream_statements(&[&insert], 1).await;
+
+    let calls = recorder.calls();
+    // Streamed transactions have no Begin, their changes arrive in blocks before the commit
+    assert!(!calls.iter().any(|call| matches!(call, Recorded::Begin(_))));
+    assert!(matches!(calls.last(), Some(Recorded::Commit)));
+
+    let changes = recorder.changes();
+    assert_eq!(changes.len(), rows);
+    let xid = &changes[0]["xid"];
+    assert!(xid.is_u64());
+    for change in &changes {
+        assert_eq!(change["op"], "insert");
+        assert_eq!(&change["xid"], xid);
+    }
+    assert_eq!(
+        field(&changes[rows - 1], "new", "name")
+            .as_str()
+            .map(str::len),
+        Some(100)
+    );
+}
@@ -0,0 +1,207 @@
+//! Subscriptions of the gRPC server
+//! A client generated from `proto/replication_checker.proto` subscribes to a server listening
+//! on a local port, while `tests/data/orders.pgrcap` (see `capture.rs`) is replayed into its
+//! hub: a subscriber receives the transactions of the tables it selected, with Begin and
+//! Commit around them, and one that falls too far behind has its call ended.
+#![cfg(feature = "grpc")]
+
+use replication_checker::grpc::proto::change_stream_client::ChangeStreamClient;
+use replication_checker::grpc::proto::replication_event::Message;
+use replication_checker::grpc::proto::{ReplicationEvent, SubscribeRequest};
+use replication_checker::grpc::{serve, GrpcHub};
+use replication_checker::watch::ChangeKind;
+use replication_checker::{ChangeHandler, ChangeRecord, ReplicationConfig, ReplicationServer};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tonic::transport::Channel;
+use tonic::{Code, Streaming};
+
+fn capture_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/orders.pgrcap")
+}
+
+/// A server of its own on a free local port, and a client connected to it
+async fn start(hub: &GrpcHub) -> ChangeStreamClient<Channel> {
+    let addr: SocketAddr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    tokio::spawn(serve(addr, hub.clone()));
+    loop {
+        match ChangeStreamClient::connect(format!("http://{}", addr)).await {
+            Ok(client) => return client,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+/// Subscribe to the tables and wait until the hub counts the subscriber
+async fn subscribe(
+    client: &mut ChangeStreamClient<Channel>,
+    hub: &GrpcHub,
+    include_tables: &[&str],
+) -> Streaming<ReplicationEvent> {
+    let subscribers = hub.subscribers();
+    let request = SubscribeRequest {
+        include_tables: include_tables
+            .iter()
+            .map(|table| table.to_string())
+            .collect(),
+        exclude_tables: Vec::new(),
+    };
+    let stream = client
+        .subscribe(request)
+        .await
+        .expect("Subscribe is answered")
+        .into_inner();
+    while hub.subscribers() == subscribers {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    stream
+}
+
+/// Replay the capture into the hub, then stop it as the end of a run does
+fn replay_and_stop(hub: &GrpcHub) {
+    let mut server =
+        ReplicationServer::offline(ReplicationConfig::offline()).expect("Offline server");
+    server.add_handler(hub.publisher(None));
+    server.replay(&capture_path()).expect("Capture replays");
+    hub.shutdown();
+}
+
+/// The kind of each event, with the table of changes, until the call ends with OK
+async fn read_to_end(mut stream: Streaming<ReplicationEvent>) -> Vec<String> {
+    let mut events = Vec::new();
+    while let Some(event) = tokio::time::timeout(Duration::from_secs(5), stream.message())
+        .await
+        .expect("Server answers in time")
+        .expect("Call ends with OK")
+    {
+        events.push(match event.message.expect("Event has a message") {
+            Message::Begin(_) => "begin".to_string(),
+            Message::Commit(_) => "commit".to_string(),
+            Message::Relation(relation) => format!("relation {}", relation.table),
+            Message::Insert(change) => format!("insert {}", change.table),
+            Message::Update(change) => format!("update {}", change.table),
+            Message::Delete(change) => format!("delete {}", change.table),
+            Message::Truncate(change) => format!("truncate {}", change.table),
+        });
+    }
+    events
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn subscribers_receive_the_transactions_of_their_tables() {
+    let hub = GrpcHub::new(64);
+    let mut client = start(&hub).await;
+    let everything = subscribe(&mut client, &hub, &[]).await;
+    let orders = subscribe(&mut client, &hub, &["public.orders"]).await;
+    let other = subscribe(&mut client, &hub, &["public.customers"]).await;
+
+    replay_and_stop(&hub);
+
+    let expected = [
+        "begin",
+        "insert orders",
+        "insert orders",
+        "commit",
+        "begin",
+        "update orders",
+        "commit",
+        "begin",
+        "delete orders",
+        "commit",
+    ];
+    assert_eq!(read_to_end(everything).await, expected);
+    assert_eq!(read_to_end(orders).await, expected);
+    // Transactions touching none of the subscriber's tables are not sent at all
+    assert!(read_to_end(other).await.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn changes_carry_their_rows() {
+    let hub = GrpcHub::new(64);
+    let mut client = start(&hub).await;
+    let mut stream = subscribe(&mut client, &hub, &[]).await;
+
+    replay_and_stop(&hub);
+
+    let mut changes = Vec::new();
+    while let Some(event) = stream.message().await.expect("Call ends with OK") {
+        if let Some(Message::Insert(change) | Message::Update(change)) = event.message {
+            changes.push(change);
+        }
+    }
+    let rows: Vec<Vec<(String, String)>> = changes
+        .iter()
+        .map(|change| {
+            change
+                .new
+                .as_ref()
+                .expect("Inserts and updates have a new row")
+                .columns
+                .iter()
+                .map(|column| {
+                    let value = match &column.value {
+                        Some(replication_checker::grpc::proto::value::Value::Text(text)) => {
+                            text.clone()
+                        }
+                        other => format!("{:?}", other),
+                    };
+                    (column.name.clone(), value)
+                })
+                .collect()
+        })
+        .collect();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(
+        rows[2],
+        [
+            ("id".to_string(), "1".to_string()),
+            ("item".to_string(), "apple".to_string()),
+            ("qty".to_string(), "5".to_string()),
+        ]
+    );
+    assert_eq!(changes[0].schema, "public");
+    assert!(changes.iter().all(|change| change.xid.is_some()));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn subscriber_falling_behind_is_ended_with_resource_exhausted() {
+    let hub = GrpcHub::new(16);
+    let mut client = start(&hub).await;
+    let mut stream = subscribe(&mut client, &hub, &[]).await;
+
+    // Far more than the subscriber buffer and the call's own queues hold while nobody reads
+    let mut publisher = hub.publisher(None);
+    for id in 0..20_000 {
+        let change = ChangeRecord {
+            schema_version: None,
+            id: None,
+            source: None,
+            op: ChangeKind::Truncate,
+            schema: "public".to_string(),
+            table: format!("orders_{}", id),
+            lsn: None,
+            xid: None,
+            publications: Vec::new(),
+            transaction: None,
+            old: None,
+            new: None,
+        };
+        publisher.on_change(&change).unwrap();
+    }
+
+    let status = loop {
+        match tokio::time::timeout(Duration::from_secs(5), stream.message())
+            .await
+            .expect("Server answers in time")
+        {
+            Ok(Some(_)) => continue,
+            Ok(None) => panic!("Call ended with OK"),
+            Err(status) => break status,
+        }
+    };
+    assert_eq!(status.code(), Code::ResourceExhausted, "{}", status);
+    assert!(status.message().contains("behind"), "{}", status);
+}
@@ -0,0 +1,117 @@
+//! Confirmed position while idle
+//! After `tests/data/orders.pgrcap` (see `capture.rs`) is replayed, a keepalive with a later
+//! server position arrives and asks for a reply. Once the event stream or a sink delivered
+//! every transaction, nothing is outstanding, so the keepalive's position is flushed and
+//! saved to the checkpoint like it is without them, and the slot keeps advancing while no
+//! published table changes.
+
+use replication_checker::capture::CaptureWriter;
+use replication_checker::checkpoint::CheckpointStore;
+use replication_checker::compress::Compression;
+use replication_checker::sink::{EventSink, SinkEvent, SinkFuture, SinkOptions};
+use replication_checker::{Lsn, ReplicationConfig, ReplicationServer};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Last end LSN of a transaction in the capture
+const LAST_COMMIT_END: Lsn = Lsn(0x564_54A8);
+/// Server position of the keepalive, past every transaction
+const IDLE_POSITION: Lsn = Lsn(0x564_6000);
+
+fn capture_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/orders.pgrcap")
+}
+
+/// An empty directory of its own for each test
+fn test_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("keepalive-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&directory).ok();
+    fs::create_dir_all(&directory).expect("Test directory is created");
+    directory
+}
+
+/// Capture of a keepalive at `position` with a reply requested
+fn keepalive_capture(directory: &Path, position: Lsn) -> PathBuf {
+    let mut message = vec![b'k'];
+    message.extend_from_slice(&position.value().to_be_bytes());
+    message.extend_from_slice(&0i64.to_be_bytes());
+    message.push(1);
+    let mut writer =
+        CaptureWriter::create(&directory.join("keepalive.pgrcap"), Compression::None).unwrap();
+    writer.write(&message).unwrap();
+    writer.flush().unwrap();
+    writer.path().to_path_buf()
+}
+
+fn offline_server(checkpoint: &Path) -> ReplicationServer {
+    let mut config = ReplicationConfig::offline();
+    config.checkpoint_path = Some(checkpoint.to_path_buf());
+    ReplicationServer::offline(config).expect("Offline server")
+}
+
+fn flushed_lsn(checkpoint: &Path) -> Option<Lsn> {
+    CheckpointStore::new(checkpoint.to_path_buf())
+        .load()
+        .unwrap()
+        .map(|checkpoint| checkpoint.flushed_lsn)
+}
+
+/// Accepts every batch
+struct Discard;
+
+impl EventSink for Discard {
+    fn write<'a>(&'a mut self, _batch: &'a [SinkEvent]) -> SinkFuture<'a> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[tokio::test]
+async fn idle_keepalive_is_flushed_once_the_event_stream_acknowledged_everything() {
+    let directory = test_directory("events");
+    let checkpoint = directory.join("slot.json");
+    let keepalive = keepalive_capture(&directory, IDLE_POSITION);
+    let mut server = offline_server(&checkpoint);
+    let mut stream = server.event_stream(16);
+
+    server.replay(&capture_path()).unwrap();
+    // Not yet acknowledged, so the stream holds the position back
+    server.replay(&keepalive).unwrap();
+    assert_eq!(flushed_lsn(&checkpoint), None);
+
+    let mut last = None;
+    for _ in 0..3 {
+        last = stream.recv().await.map(|event| event.end_lsn);
+    }
+    assert_eq!(last, Some(LAST_COMMIT_END));
+    stream.ack(LAST_COMMIT_END);
+
+    server.replay(&keepalive).unwrap();
+    assert_eq!(flushed_lsn(&checkpoint), Some(IDLE_POSITION));
+    fs::remove_dir_all(&directory).ok();
+}
+
+#[test]
+fn idle_keepalive_is_flushed_once_a_sink_delivered_everything() {
+    let directory = test_directory("sink");
+    let checkpoint = directory.join("slot.json");
+    let keepalive = keepalive_capture(&directory, IDLE_POSITION);
+    let mut server = offline_server(&checkpoint);
+    let mut options = SinkOptions::new("discard");
+    options.flush_interval = Duration::from_millis(10);
+    server.add_sink(options, Discard).unwrap();
+
+    server.replay(&capture_path()).unwrap();
+    // The sink acknowledges on its own thread, so the keepalive repeats until it caught up
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while flushed_lsn(&checkpoint) != Some(IDLE_POSITION) {
+        assert!(
+            Instant::now() < deadline,
+            "Flushed position stayed at {:?}",
+            flushed_lsn(&checkpoint)
+        );
+        std::thread::sleep(Duration::from_millis(10));
+        server.replay(&keepalive).unwrap();
+    }
+    fs::remove_dir_all(&directory).ok();
+}
@@ -0,0 +1,65 @@
+//! WAL positions in PostgreSQL's "X/Y" notation
+//! `Lsn` is parsed from the command line and the server's query results and shown in every log
+//! line, so it must read and write the same text as pg_lsn.
+
+use replication_checker::Lsn;
+
+#[test]
+fn display_matches_pg_lsn() {
+    let cases = [
+        (0, "0/0"),
+        (0x16B3748, "0/16B3748"),
+        (0x1_0000_0000, "1/0"),
+        (0xA_0000_00FF, "A/FF"),
+        (u64::MAX, "FFFFFFFF/FFFFFFFF"),
+    ];
+    for (value, text) in cases {
+        assert_eq!(Lsn(value).to_string(), text);
+        assert_eq!(text.parse::<Lsn>().unwrap(), Lsn(value), "{}", text);
+    }
+}
+
+#[test]
+fn parse_accepts_lowercase_and_surrounding_whitespace() {
+    assert_eq!("0/16b3748".parse::<Lsn>().unwrap(), Lsn(0x16B3748));
+    assert_eq!(" 2/a \n".parse::<Lsn>().unwrap(), Lsn(0x2_0000_000A));
+    // pg_lsn output never has leading zeros, but input may
+    assert_eq!(
+        "00000001/00000002".parse::<Lsn>().unwrap(),
+        Lsn(0x1_0000_0002)
+    );
+}
+
+#[test]
+fn parse_rejects_malformed_positions() {
+    for text in [
+        "",
+        "16B3748",
+        "0/",
+        "/0",
+        "G/0",
+        "0/X",
+        "1/2/3",
+        "+1/0",
+        "0/-1",
+        "0 /1",
+        "100000000/0",
+        "0/100000000",
+    ] {
+        let error = text.parse::<Lsn>().expect_err(text);
+        assert!(error.to_string().contains("Invalid LSN"), "{}", error);
+    }
+}
+
+#[test]
+fn positions_order_and_measure_wal_bytes() {
+    let earlier: Lsn = "0/FFFFFFFF".parse().unwrap();
+    let later: Lsn = "1/10".parse().unwrap();
+    assert!(earlier < later);
+    assert_eq!(later.bytes_since(earlier), 0x11);
+    assert_eq!(earlier.bytes_since(later), 0);
+    assert_eq!(earlier.advance(0x11), later);
+    assert_eq!(Lsn(u64::MAX).advance(1), Lsn(u64::MAX));
+    assert!(!Lsn::INVALID.is_valid());
+    assert!(later.is_valid());
+}
@@ -0,0 +1,64 @@
+//! Parser regression tests over the fuzzing corpus in `fuzz/corpus/parse_wal_message`
+//! Files named `valid-*` must parse outside a streamed transaction and `stream-*` inside one,
+//! while `malformed-*` must be rejected either way; nothing in the corpus may panic. Inputs
+//! found by `cargo fuzz run parse_wal_message` can be added under those names.
+
+use replication_checker::parser::{MAX_COLUMNS, MAX_TRUNCATE_RELATIONS};
+use replication_checker::MessageParser;
+use std::fs;
+use std::path::PathBuf;
+
+fn corpus() -> Vec<(String, Vec<u8>)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/parse_wal_message");
+    let mut files: Vec<(String, Vec<u8>)> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("Failed to read {}: {}", dir.display(), e))
+        .map(|entry| {
+            let path = entry.expect("Failed to read corpus entry").path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, fs::read(&path).expect("Failed to read corpus file"))
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn corpus_parses_as_named() {
+    let files = corpus();
+    assert!(!files.is_empty(), "The corpus is empty");
+
+    for (name, data) in &files {
+        let outside = MessageParser::parse_wal_message(data, false);
+        let inside = MessageParser::parse_wal_message(data, true);
+        if name.starts_with("valid-") {
+            assert!(outside.is_ok(), "{}: {:?}", name, outside.err());
+        } else if name.starts_with("stream-") {
+            assert!(inside.is_ok(), "{}: {:?}", name, inside.err());
+        } else if name.starts_with("malformed-") {
+            assert!(outside.is_err(), "{} parsed: {:?}", name, outside);
+            assert!(inside.is_err(), "{} parsed: {:?}", name, inside);
+        }
+    }
+}
+
+#[test]
+fn counts_are_limited_before_allocating() {
+    // A table at the column limit whose columns are all NULL is accepted
+    let mut insert = b"I\x00\x00\x40\x00N".to_vec();
+    insert.extend_from_slice(&(MAX_COLUMNS as i16).to_be_bytes());
+    insert.extend(std::iter::repeat_n(b'n', MAX_COLUMNS));
+    assert!(MessageParser::parse_wal_message(&insert, false).is_ok());
+
+    // One column more is refused, even though the message holds it
+    let mut insert = b"I\x00\x00\x40\x00N".to_vec();
+    insert.extend_from_slice(&(MAX_COLUMNS as i16 + 1).to_be_bytes());
+    insert.extend(std::iter::repeat_n(b'n', MAX_COLUMNS + 1));
+    assert!(MessageParser::parse_wal_message(&insert, false).is_err());
+
+    // The relation count of a truncate is checked against the limit and the message size
+    let mut truncate = b"T".to_vec();
+    truncate.extend_from_slice(&(MAX_TRUNCATE_RELATIONS as u32 + 1).to_be_bytes());
+    truncate.push(0);
+    truncate.extend(std::iter::repeat_n(0u8, (MAX_TRUNCATE_RELATIONS + 1) * 4));
+    assert!(MessageParser::parse_wal_message(&truncate, false).is_err());
+}
@@ -0,0 +1,222 @@
+//! Files of the Parquet sink read back with the parquet crate
+//! Replays `tests/data/orders.pgrcap` (see `capture.rs`) into the sink and checks that the
+//! record reader accepts the files and finds the schema, row groups and rows that were written.
+#![cfg(feature = "parquet")]
+
+use chrono::{TimeZone, Utc};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use replication_checker::mask::ColumnMask;
+use replication_checker::parquet::{ParquetConfig, ParquetSink};
+use replication_checker::types::{ColumnInfo, Lsn, RelationInfo};
+use replication_checker::watch::ChangeKind;
+use replication_checker::{ChangeHandler, ChangeRecord, ReplicationConfig, ReplicationServer, Row};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+fn capture_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/orders.pgrcap")
+}
+
+/// Every `.parquet` file below `directory`
+fn parquet_files(directory: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(directory).expect("Directory reads") {
+        let path = entry.expect("Directory entry reads").path();
+        if path.is_dir() {
+            files.extend(parquet_files(&path));
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension == "parquet")
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+#[test]
+fn replayed_changes_read_back_with_the_parquet_crate() {
+    let directory = std::env::temp_dir().join(format!("parquet-sink-{}", std::process::id()));
+    let mut config = ReplicationConfig::offline();
+    config.parquet = Some(ParquetConfig {
+        directory: directory.clone(),
+        // Small enough that the rows span several row groups
+        row_group_size: 3,
+    });
+    let mut server = ReplicationServer::offline(config).expect("Offline server");
+    server.replay(&capture_path()).expect("Capture replays");
+    drop(server);
+
+    let files = parquet_files(&directory);
+    assert_eq!(files.len(), 1, "{:?}", files);
+    assert!(files[0].starts_with(directory.join("public.orders")));
+
+    let reader = SerializedFileReader::new(File::open(&files[0]).expect("File opens"))
+        .expect("The parquet crate reads the file");
+    let metadata = reader.metadata();
+    assert_eq!(metadata.file_metadata().num_rows(), 4);
+    assert_eq!(metadata.num_row_groups(), 2);
+    let columns: Vec<&str> = metadata
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .map(|column| column.name())
+        .collect();
+    assert_eq!(
+        columns,
+        ["_op", "_lsn", "_xid", "_commit_time", "id", "item", "qty"]
+    );
+
+    let rows: Vec<Vec<(String, Field)>> = reader
+        .get_row_iter(None)
+        .expect("Rows read")
+        .map(|row| {
+            row.expect("Row reads")
+                .get_column_iter()
+                .map(|(name, field)| (name.clone(), field.clone()))
+                .collect()
+        })
+        .collect();
+    let field = |row: usize, name: &str| {
+        rows[row]
+            .iter()
+            .find(|(column, _)| column == name)
+            .map(|(_, field)| field.clone())
+            .expect("Column is present")
+    };
+
+    let ops: Vec<Field> = (0..4).map(|row| field(row, "_op")).collect();
+    assert_eq!(
+        ops,
+        ["insert", "insert", "update", "delete"].map(|op| Field::Str(op.to_string()))
+    );
+    let ids: Vec<Field> = (0..4).map(|row| field(row, "id")).collect();
+    assert_eq!(ids, [1, 2, 1, 2].map(Field::Int));
+    assert_eq!(field(2, "qty"), Field::Int(5));
+    assert!(matches!(field(0, "item"), Field::Str(_)));
+    assert!(matches!(field(0, "_xid"), Field::Long(_)));
+    assert!(matches!(
+        field(0, "_commit_time"),
+        Field::TimestampMicros(_)
+    ));
+
+    fs::remove_dir_all(&directory).ok();
+}
+
+fn column(name: &str, type_oid: u32) -> ColumnInfo {
+    ColumnInfo {
+        key_flag: 0,
+        column_name: name.to_string(),
+        column_type: type_oid,
+        atttypmod: -1,
+        type_name: None,
+    }
+}
+
+fn insert(values: Value) -> ChangeRecord {
+    let values: HashMap<String, Value> = serde_json::from_value(values).expect("Values are a map");
+    ChangeRecord {
+        schema_version: None,
+        id: None,
+        source: None,
+        op: ChangeKind::Insert,
+        schema: "public".to_string(),
+        table: "readings".to_string(),
+        lsn: Some("0/16B3748".to_string()),
+        xid: Some(740),
+        publications: Vec::new(),
+        transaction: None,
+        old: None,
+        new: Some(Row::from_json(&values)),
+    }
+}
+
+#[test]
+fn typed_columns_and_nulls_read_back() {
+    let directory = std::env::temp_dir().join(format!("parquet-types-{}", std::process::id()));
+    let config = ParquetConfig {
+        directory: directory.clone(),
+        row_group_size: 100,
+    };
+    let mut sink = ParquetSink::new(config, ColumnMask::default()).expect("Sink opens");
+    sink.register_relation(&RelationInfo {
+        oid: 16384,
+        namespace: "public".to_string(),
+        relation_name: "readings".to_string(),
+        replica_identity: 'd',
+        column_count: 6,
+        columns: vec![
+            column("ok", 16),
+            column("small", 21),
+            column("big", 20),
+            column("ratio", 700),
+            column("value", 701),
+            column("note", 25),
+        ],
+        published_mask: None,
+    })
+    .expect("Relation registers");
+
+    let commit_time = Utc.with_ymd_and_hms(2024, 2, 29, 12, 0, 0).unwrap();
+    sink.on_begin(740, Lsn(0x16B3748), commit_time)
+        .expect("Begin");
+    sink.on_change(&insert(json!({
+        "ok": "t", "small": "-7", "big": "9007199254740993", "ratio": "1.5",
+        "value": "-0.25", "note": "héllo"
+    })))
+    .expect("Insert");
+    // NULL, and text that does not fit the column type, are written as nulls
+    sink.on_change(&insert(json!({
+        "ok": null, "small": "seven", "big": null, "ratio": "NaN", "value": null, "note": null
+    })))
+    .expect("Insert");
+    sink.on_commit(Lsn(0x16B3748), Lsn(0x16B3778), commit_time)
+        .expect("Commit");
+    sink.close().expect("Sink closes");
+
+    let files = parquet_files(&directory);
+    assert_eq!(files.len(), 1, "{:?}", files);
+    assert!(files[0].starts_with(directory.join("public.readings").join("date=2024-02-29")));
+    let reader = SerializedFileReader::new(File::open(&files[0]).expect("File opens"))
+        .expect("The parquet crate reads the file");
+    let rows: Vec<Vec<Field>> = reader
+        .get_row_iter(None)
+        .expect("Rows read")
+        .map(|row| {
+            row.expect("Row reads")
+                .get_column_iter()
+                .skip(4)
+                .map(|(_, field)| field.clone())
+                .collect()
+        })
+        .collect();
+    fs::remove_dir_all(&directory).ok();
+
+    assert_eq!(
+        rows[0],
+        [
+            Field::Bool(true),
+            Field::Int(-7),
+            Field::Long(9_007_199_254_740_993),
+            Field::Float(1.5),
+            Field::Double(-0.25),
+            Field::Str("héllo".to_string()),
+        ]
+    );
+    assert!(matches!(rows[1][3], Field::Float(ratio) if ratio.is_nan()));
+    assert_eq!(
+        [&rows[1][..3], &rows[1][4..]].concat(),
+        [
+            Field::Null,
+            Field::Null,
+            Field::Null,
+            Field::Null,
+            Field::Null
+        ]
+    );
+}
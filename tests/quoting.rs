@@ -0,0 +1,87 @@
+//! Quoting of names and values interpolated into SQL and replication commands
+//! Names with quotes, spaces and non-ASCII characters must come out as a single token that
+//! PostgreSQL reads back as the original name.
+
+use replication_checker::quote::{
+    publication_names_option, quote_command_literal, quote_ident, quote_literal, quote_qualified,
+};
+
+#[test]
+fn identifiers_are_always_quoted() {
+    assert_eq!(quote_ident("orders"), "\"orders\"");
+    assert_eq!(quote_ident("Orders"), "\"Orders\"");
+    assert_eq!(quote_ident(""), "\"\"");
+}
+
+#[test]
+fn identifiers_double_embedded_quotes() {
+    assert_eq!(quote_ident("my\"slot"), "\"my\"\"slot\"");
+    assert_eq!(quote_ident("\""), "\"\"\"\"");
+    assert_eq!(quote_ident("it's"), "\"it's\"");
+}
+
+#[test]
+fn identifiers_keep_spaces_and_unicode() {
+    assert_eq!(quote_ident("order items"), "\"order items\"");
+    assert_eq!(
+        quote_ident("Bestellungen über 100€"),
+        "\"Bestellungen über 100€\""
+    );
+    assert_eq!(quote_ident("注文\"表"), "\"注文\"\"表\"");
+}
+
+#[test]
+fn qualified_names_quote_each_part() {
+    assert_eq!(quote_qualified("public", "t"), "\"public\".\"t\"");
+    assert_eq!(
+        quote_qualified("my.schema", "a \"b\""),
+        "\"my.schema\".\"a \"\"b\"\"\""
+    );
+}
+
+#[test]
+fn literals_double_embedded_quotes() {
+    assert_eq!(quote_literal("snapshot"), "'snapshot'");
+    assert_eq!(quote_literal("it's"), "'it''s'");
+    assert_eq!(quote_literal("''"), "''''''");
+    assert_eq!(quote_literal(""), "''");
+}
+
+#[test]
+fn literals_with_backslashes_are_escape_strings() {
+    assert_eq!(quote_literal("a\\b"), "E'a\\\\b'");
+    assert_eq!(quote_literal("\\'"), "E'\\\\'''");
+}
+
+#[test]
+fn literals_keep_spaces_and_unicode() {
+    assert_eq!(quote_literal("slot name"), "'slot name'");
+    assert_eq!(quote_literal("café ☕"), "'café ☕'");
+    assert_eq!(quote_literal("l'été"), "'l''été'");
+}
+
+#[test]
+fn command_literals_keep_backslashes() {
+    assert_eq!(quote_command_literal("it's"), "'it''s'");
+    assert_eq!(quote_command_literal("a\\b"), "'a\\b'");
+    assert_eq!(quote_command_literal("naïve"), "'naïve'");
+}
+
+#[test]
+fn publication_names_are_quoted_identifiers() {
+    let names = vec![
+        "pub".to_string(),
+        "Sales Pub".to_string(),
+        "o'brien \"q\"".to_string(),
+        "出版".to_string(),
+    ];
+    let option = publication_names_option(&names);
+    assert_eq!(
+        option,
+        "\"pub\",\"Sales Pub\",\"o'brien \"\"q\"\"\",\"出版\""
+    );
+    assert_eq!(
+        quote_command_literal(&option),
+        "'\"pub\",\"Sales Pub\",\"o''brien \"\"q\"\"\",\"出版\"'"
+    );
+}
@@ -0,0 +1,208 @@
+//! Parsing and evaluation of row filters
+//! Column values are looked up as the text the server sends, None standing for NULL, and a row
+//! only passes when its predicate is true rather than false or unknown.
+
+use replication_checker::rowfilter::{RowFilter, RowPredicate};
+use std::collections::HashMap;
+
+fn predicate(source: &str) -> RowPredicate {
+    source
+        .parse()
+        .unwrap_or_else(|e| panic!("{} should parse: {}", source, e))
+}
+
+fn parse_error(source: &str) -> String {
+    match source.parse::<RowPredicate>() {
+        Ok(predicate) => panic!("{} should not parse, got {:?}", source, predicate),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Whether a row with the given column values passes; absent columns are NULL
+fn matches(source: &str, row: &[(&str, &str)]) -> bool {
+    let row: HashMap<&str, &str> = row.iter().copied().collect();
+    predicate(source).matches(&|column| row.get(column).map(|value| value.to_string()))
+}
+
+#[test]
+fn predicates_name_their_table() {
+    let unqualified = predicate("orders.status = 'paid'");
+    assert!(unqualified.applies_to("public", "orders"));
+    assert!(unqualified.applies_to("sales", "orders"));
+    assert!(!unqualified.applies_to("public", "order_items"));
+
+    let qualified = predicate("sales.orders.id > 10 AND orders.total < 5");
+    assert!(qualified.applies_to("sales", "orders"));
+    assert!(!qualified.applies_to("public", "orders"));
+
+    let quoted = predicate("\"My Schema\".\"Order\"\"s\".\"Id\" = 1");
+    assert!(quoted.applies_to("My Schema", "Order\"s"));
+    assert_eq!(
+        quoted.to_string(),
+        "\"My Schema\".\"Order\"\"s\".\"Id\" = 1"
+    );
+}
+
+#[test]
+fn malformed_predicates_are_rejected() {
+    for (source, detail) in [
+        ("orders.status = 'paid", "unterminated quote"),
+        ("(orders.id = 1", "missing ')'"),
+        ("orders.id ! 1", "'!' must be followed by '='"),
+        ("orders.id = 1 AND items.id = 2", "same table"),
+        ("a.orders.id = 1 AND b.orders.id = 2", "same table"),
+        ("id = 1", "table.column"),
+        ("orders.id IS 1", "expected NULL after IS"),
+        ("orders.id NOT 1", "expected IN after NOT"),
+        ("orders.id IN (1 2)", "expected ',' or ')'"),
+        ("orders.id = 1.2.3", "invalid number"),
+        (
+            "orders.id = orders.total",
+            "expected a string, number or boolean",
+        ),
+        ("orders.id = 1 orders", "unexpected"),
+        ("orders.id = 1 # 2", "unexpected character '#'"),
+    ] {
+        let error = parse_error(source);
+        assert!(error.contains(detail), "{}: {}", source, error);
+    }
+}
+
+#[test]
+fn bigints_compare_exactly() {
+    // 2^53 + 1 rounds to 2^53 as a double
+    assert!(!matches(
+        "t.id = 9007199254740992",
+        &[("id", "9007199254740993")]
+    ));
+    assert!(matches(
+        "t.id > 9007199254740992",
+        &[("id", "9007199254740993")]
+    ));
+    assert!(matches(
+        "t.id = 9223372036854775807",
+        &[("id", "9223372036854775807")]
+    ));
+    assert!(!matches(
+        "t.id < 9223372036854775806",
+        &[("id", "9223372036854775807")]
+    ));
+    assert!(matches(
+        "t.id < -9223372036854775807",
+        &[("id", "-9223372036854775808")]
+    ));
+    assert!(matches(
+        "t.id IN (1, 9007199254740993)",
+        &[("id", "9007199254740993")]
+    ));
+    assert!(!matches(
+        "t.id IN (1, 9007199254740992)",
+        &[("id", "9007199254740993")]
+    ));
+}
+
+#[test]
+fn numerics_compare_exactly_whatever_their_scale() {
+    assert!(matches("t.price = 0.1", &[("price", "0.10")]));
+    assert!(matches("t.price = 10", &[("price", "10.000")]));
+    assert!(matches("t.price = 0", &[("price", "-0.00")]));
+    assert!(matches(
+        "t.price > 0.1",
+        &[("price", "0.10000000000000000001")]
+    ));
+    assert!(matches("t.price < 0.05", &[("price", "0.049")]));
+    assert!(matches("t.price < 100", &[("price", "99.999")]));
+    assert!(matches("t.price > -1.5", &[("price", "-1.49")]));
+    assert!(matches("t.price < -1.5", &[("price", "-10")]));
+    assert!(matches("t.price >= 007", &[("price", "7")]));
+    assert!(matches(
+        "t.price = 123456789012345678901234567890.5",
+        &[("price", "123456789012345678901234567890.50")]
+    ));
+}
+
+#[test]
+fn floats_in_exponent_notation_compare_as_doubles() {
+    assert!(matches("t.reading > 1000", &[("reading", "1e+20")]));
+    assert!(matches("t.reading < 0.001", &[("reading", "1e-05")]));
+    assert!(matches("t.reading > 1000", &[("reading", "Infinity")]));
+    // NaN is not ordered, so the comparison is unknown
+    assert!(!matches("t.reading > 1", &[("reading", "NaN")]));
+    assert!(!matches("t.reading <= 1", &[("reading", "NaN")]));
+    // Text that is not a number does not compare with a number
+    assert!(!matches("t.reading = 1", &[("reading", "one")]));
+    assert!(!matches("t.reading <> 1", &[("reading", "one")]));
+}
+
+#[test]
+fn text_and_boolean_comparisons() {
+    assert!(matches("t.status = 'paid'", &[("status", "paid")]));
+    assert!(matches("t.status <> 'paid'", &[("status", "open")]));
+    assert!(matches("t.status != 'paid'", &[("status", "open")]));
+    assert!(matches("t.name = 'O''Brien'", &[("name", "O'Brien")]));
+    assert!(matches("t.name < 'b'", &[("name", "abc")]));
+    assert!(matches(
+        "t.status NOT IN ('paid', 'void')",
+        &[("status", "open")]
+    ));
+    assert!(!matches(
+        "t.status IN ('paid', 'void')",
+        &[("status", "open")]
+    ));
+    assert!(matches("t.active = true", &[("active", "t")]));
+    assert!(matches("t.active = FALSE", &[("active", "f")]));
+    assert!(!matches("t.active = true", &[("active", "yes")]));
+}
+
+#[test]
+fn nulls_are_unknown() {
+    assert!(!matches("t.id = 1", &[]));
+    assert!(!matches("t.id <> 1", &[]));
+    assert!(!matches("NOT t.id = 1", &[]));
+    assert!(!matches("t.id IN (1, 2)", &[]));
+    assert!(!matches("t.id NOT IN (1, 2)", &[]));
+    assert!(matches("t.id IS NULL", &[]));
+    assert!(!matches("t.id IS NOT NULL", &[]));
+    assert!(matches("t.id IS NOT NULL", &[("id", "1")]));
+    // Unknown OR true is true, unknown AND false is false, so NOT of it is true
+    assert!(matches("t.id = 1 OR t.kind = 'a'", &[("kind", "a")]));
+    assert!(!matches("t.id = 1 OR t.kind = 'b'", &[("kind", "a")]));
+    assert!(matches("NOT (t.id = 1 AND t.kind = 'b')", &[("kind", "a")]));
+    assert!(!matches(
+        "NOT (t.id = 1 AND t.kind = 'a')",
+        &[("kind", "a")]
+    ));
+}
+
+#[test]
+fn and_binds_tighter_than_or() {
+    let source = "t.a = 1 OR t.b = 1 AND t.c = 1";
+    assert!(matches(source, &[("a", "1"), ("b", "0"), ("c", "0")]));
+    assert!(!matches(source, &[("a", "0"), ("b", "1"), ("c", "0")]));
+    let grouped = "(t.a = 1 OR t.b = 1) AND t.c = 1";
+    assert!(!matches(grouped, &[("a", "1"), ("b", "0"), ("c", "0")]));
+    assert!(matches(grouped, &[("a", "0"), ("b", "1"), ("c", "1")]));
+}
+
+#[test]
+fn filters_require_every_predicate_of_the_table() {
+    let filter = RowFilter::new(vec![
+        predicate("orders.total >= 100"),
+        predicate("orders.status = 'paid'"),
+        predicate("users.active = true"),
+    ]);
+    let row = |total: &'static str, status: &'static str| {
+        move |column: &str| match column {
+            "total" => Some(total.to_string()),
+            "status" => Some(status.to_string()),
+            _ => None,
+        }
+    };
+    assert!(filter.applies_to("public", "orders"));
+    assert!(!filter.applies_to("public", "items"));
+    assert!(filter.matches("public", "orders", &row("100", "paid")));
+    assert!(!filter.matches("public", "orders", &row("99.99", "paid")));
+    assert!(!filter.matches("public", "orders", &row("100", "open")));
+    // Tables without predicates pass
+    assert!(filter.matches("public", "items", &row("0", "open")));
+}
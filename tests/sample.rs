@@ -0,0 +1,82 @@
+//! Sample rates of `SAMPLE_TABLES`
+//! `SampleRule` parses `table=1/N` and `table=P%`, and `SampleRate::keeps` decides which
+//! change of a table is emitted: the first always, then evenly spread so every run of
+//! changes keeps the rate, including percentages that do not divide 100.
+
+use replication_checker::sample::{SampleRate, SampleRule};
+
+/// Positions, from 0, of the changes a rate keeps out of the first `count`
+fn kept(rate: SampleRate, count: u64) -> Vec<u64> {
+    (0..count).filter(|&seen| rate.keeps(seen)).collect()
+}
+
+#[test]
+fn one_in_n_keeps_every_nth_change_from_the_first() {
+    assert_eq!(kept(SampleRate::OneIn(1), 5), [0, 1, 2, 3, 4]);
+    assert_eq!(kept(SampleRate::OneIn(3), 10), [0, 3, 6, 9]);
+    assert_eq!(kept(SampleRate::OneIn(100), 250), [0, 100, 200]);
+}
+
+#[test]
+fn percent_keeps_the_first_change_and_spreads_the_rest() {
+    assert_eq!(kept(SampleRate::Percent(100.0), 5), [0, 1, 2, 3, 4]);
+    assert_eq!(kept(SampleRate::Percent(50.0), 6), [0, 2, 4]);
+    assert_eq!(kept(SampleRate::Percent(5.0), 60), [0, 20, 40]);
+    assert_eq!(kept(SampleRate::Percent(40.0), 10), [0, 2, 5, 7]);
+}
+
+#[test]
+fn percent_keeps_the_rounded_up_share_of_any_count() {
+    for percent in [0.1, 1.0, 7.0, 12.5, 33.3, 66.7, 99.9] {
+        let rate = SampleRate::Percent(percent);
+        let mut emitted = 0;
+        for seen in 0..10_000u64 {
+            if rate.keeps(seen) {
+                emitted += 1;
+            }
+            let expected = ((seen + 1) as f64 * percent / 100.0).ceil() as u64;
+            assert_eq!(emitted, expected, "{}% after {} changes", percent, seen + 1);
+        }
+    }
+}
+
+#[test]
+fn rules_parse_the_table_and_rate() {
+    let cases = [
+        ("orders=1/100", "orders", SampleRate::OneIn(100)),
+        (
+            "public.events*=5%",
+            "public.events*",
+            SampleRate::Percent(5.0),
+        ),
+        (" orders = 1 / 7 ", "orders", SampleRate::OneIn(7)),
+        ("logs=0.5%", "logs", SampleRate::Percent(0.5)),
+        ("logs=100%", "logs", SampleRate::Percent(100.0)),
+    ];
+    for (text, pattern, rate) in cases {
+        let rule: SampleRule = text.parse().unwrap_or_else(|e| panic!("{}: {}", text, e));
+        assert_eq!(rule.pattern(), pattern, "{}", text);
+        assert_eq!(rule.rate(), rate, "{}", text);
+    }
+}
+
+#[test]
+fn rules_reject_missing_tables_and_invalid_rates() {
+    for text in [
+        "orders",
+        "=1/10",
+        "orders=",
+        "orders=1/0",
+        "orders=2/10",
+        "orders=1/-5",
+        "orders=1/x",
+        "orders=0%",
+        "orders=-5%",
+        "orders=100.5%",
+        "orders=NaN%",
+        "orders=5",
+        "a=b=1/2",
+    ] {
+        assert!(text.parse::<SampleRule>().is_err(), "{}", text);
+    }
+}
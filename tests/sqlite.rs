@@ -0,0 +1,116 @@
+//! Audit trail of the SQLite sink
+//! Replays `tests/data/orders.pgrcap` (see `capture.rs`) into a SQLite sink and reads the
+//! `events` table back: one row per change with its operation and row images, and with event
+//! IDs enabled a replay of the same capture adds nothing.
+#![cfg(feature = "sqlite")]
+
+use replication_checker::sink::SinkOptions;
+use replication_checker::sqlite::SqliteSink;
+use replication_checker::{ReplicationConfig, ReplicationServer};
+use rusqlite::Connection;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn capture_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/orders.pgrcap")
+}
+
+/// An empty directory of its own for each test
+fn test_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("sqlite-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&directory).ok();
+    fs::create_dir_all(&directory).expect("Test directory is created");
+    directory
+}
+
+/// Replay the capture into a sink writing to the database, delivering everything before
+/// returning
+fn replay(database: &Path, event_ids: bool) {
+    let mut config = ReplicationConfig::offline();
+    config.source = Some("orders-db".to_string());
+    config.event_ids = event_ids;
+    let mut server = ReplicationServer::offline(config).expect("Offline server");
+    let sink = SqliteSink::open(database).expect("Database opens");
+    server.add_sink(SinkOptions::new("audit"), sink).unwrap();
+    server.replay(&capture_path()).expect("Capture replays");
+}
+
+/// Operation, table and row images of each recorded event, in order
+fn recorded(database: &Path) -> Vec<(String, String, Option<Value>, Option<Value>)> {
+    let connection = Connection::open(database).expect("Database opens for reading");
+    let mut select = connection
+        .prepare("SELECT op, table_name, old, new FROM events ORDER BY seq")
+        .unwrap();
+    let image = |text: Option<String>| {
+        text.map(|text| serde_json::from_str(&text).expect("Row images are JSON"))
+    };
+    select
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                image(row.get(2)?),
+                image(row.get(3)?),
+            ))
+        })
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap()
+}
+
+#[test]
+fn replayed_changes_are_recorded_in_order() {
+    let directory = test_directory("recorded");
+    let database = directory.join("audit.db");
+    replay(&database, false);
+
+    let events = recorded(&database);
+    let kinds: Vec<(&str, &str)> = events
+        .iter()
+        .map(|(op, table, _, _)| (op.as_str(), table.as_str()))
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            ("insert", "orders"),
+            ("insert", "orders"),
+            ("update", "orders"),
+            ("delete", "orders"),
+        ]
+    );
+    let (_, _, old, new) = &events[0];
+    assert_eq!(old, &None);
+    assert!(new.is_some(), "{:?}", events[0]);
+    let (_, _, old, new) = &events[3];
+    assert!(old.is_some(), "{:?}", events[3]);
+    assert_eq!(new, &None);
+
+    let connection = Connection::open(&database).unwrap();
+    let (source, xids): (String, i64) = connection
+        .query_row(
+            "SELECT min(source), count(DISTINCT xid) FROM events",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(source, "orders-db");
+    assert_eq!(xids, 3);
+    fs::remove_dir_all(&directory).ok();
+}
+
+#[test]
+fn redelivered_events_with_ids_are_recorded_once() {
+    let directory = test_directory("redelivered");
+    let database = directory.join("audit.db");
+    replay(&database, true);
+    replay(&database, true);
+    assert_eq!(recorded(&database).len(), 4);
+
+    // Without IDs there is nothing to tell a redelivered event apart
+    let database = directory.join("without-ids.db");
+    replay(&database, false);
+    replay(&database, false);
+    assert_eq!(recorded(&database).len(), 8);
+    fs::remove_dir_all(&directory).ok();
+}
@@ -0,0 +1,50 @@
+//! Conversion of PostgreSQL timestamps, microseconds since 2000-01-01
+//! PostgreSQL accepts years up to 294276 and infinite timestamps, beyond what chrono represents,
+//! so out-of-range values must be reported rather than panic.
+
+use replication_checker::utils::{
+    format_timestamp_from_pg, pg_commit_time, pg_timestamp_to_datetime,
+};
+
+/// 294276-12-31 23:59:59.999999, the latest timestamp PostgreSQL accepts
+const PG_MAX_TIMESTAMP: i64 = 9_223_371_331_199_999_999;
+
+#[test]
+fn epoch_and_negative_values_convert() {
+    let epoch = pg_timestamp_to_datetime(0).unwrap();
+    assert_eq!(epoch.to_rfc3339(), "2000-01-01T00:00:00+00:00");
+
+    let before = pg_timestamp_to_datetime(-1).unwrap();
+    assert_eq!(
+        before.format("%Y-%m-%d %H:%M:%S%.6f").to_string(),
+        "1999-12-31 23:59:59.999999"
+    );
+    assert_eq!(
+        format_timestamp_from_pg(86_400_000_000 + 1_500),
+        "2000-01-02 00:00:00.001 UTC"
+    );
+}
+
+#[test]
+fn values_past_chrono_are_none() {
+    assert!(pg_timestamp_to_datetime(PG_MAX_TIMESTAMP).is_none());
+    assert!(pg_timestamp_to_datetime(i64::MAX).is_none());
+    assert!(pg_timestamp_to_datetime(i64::MIN).is_none());
+}
+
+#[test]
+fn out_of_range_values_format_without_panicking() {
+    assert_eq!(format_timestamp_from_pg(i64::MAX), "infinity");
+    assert_eq!(format_timestamp_from_pg(i64::MIN), "-infinity");
+    assert_eq!(
+        format_timestamp_from_pg(PG_MAX_TIMESTAMP),
+        format!("{} (out of range)", PG_MAX_TIMESTAMP)
+    );
+}
+
+#[test]
+fn out_of_range_commit_times_are_protocol_errors() {
+    assert!(pg_commit_time(0).is_ok());
+    let error = pg_commit_time(PG_MAX_TIMESTAMP).unwrap_err();
+    assert!(error.to_string().contains("out of range"), "{}", error);
+}